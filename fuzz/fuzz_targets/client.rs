@@ -0,0 +1,29 @@
+//! Feeds arbitrary packets from the server and a peer to a queued client.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use mirai_core::transport::{MockNetwork, Packet, Transport};
+use mirai_core::v1::{CLIENT_PORT, SERVER_PORT};
+use mirai_matchmaking_client::{Client, ClientError};
+use std::net::SocketAddr;
+
+fuzz_target!(|data: &[u8]| {
+    let ip = "127.0.0.1".parse().unwrap();
+    let addr = SocketAddr::new(ip, CLIENT_PORT);
+    let network = MockNetwork::new();
+    let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+    let peer = network.transport("127.0.0.2:1".parse().unwrap());
+    let mut client = Client::with_transport(ip, network.transport(addr));
+    let _ = client.queue();
+    // the first byte of each chunk picks the sender, the rest is the payload
+    for chunk in data.split(|&b| b == 0xff) {
+        if let Some((&sender, payload)) = chunk.split_first() {
+            let from = if sender % 2 == 0 { &server } else { &peer };
+            let _ = from.send(Packet::unreliable(addr, payload.to_vec()));
+        }
+    }
+    network.deliver_all();
+    if let Err(ClientError::HandlerStopped) = client.close() {
+        panic!("the client handler panicked");
+    }
+});
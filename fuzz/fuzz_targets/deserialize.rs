@@ -0,0 +1,12 @@
+//! Deserializes arbitrary bytes as each of the protocol messages.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use mirai_core::v1::{ClientToServer, ServerToClient};
+use mirai_matchmaking_client::ClientToClient;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = bincode::deserialize::<ClientToServer>(data);
+    let _ = bincode::deserialize::<ServerToClient>(data);
+    let _ = bincode::deserialize::<ClientToClient>(data);
+});
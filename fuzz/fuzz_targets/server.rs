@@ -0,0 +1,20 @@
+//! Feeds arbitrary packets from a few addresses to the server.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use mirai_core::transport::{MockNetwork, Packet, TransportEvent};
+use mirai_matchmaking_server::Server;
+
+fuzz_target!(|data: &[u8]| {
+    let network = MockNetwork::new();
+    let mut server = Server::new(network.transport("127.0.0.1:9000".parse().unwrap()));
+    // the first byte of each chunk picks the sender, the rest is the payload
+    for chunk in data.split(|&b| b == 0xff) {
+        if let Some((&sender, payload)) = chunk.split_first() {
+            let addr = format!("127.0.0.2:{}", sender % 4 + 1).parse().unwrap();
+            let packet = Packet::unreliable(addr, payload.to_vec());
+            let _ = server.handle_event(TransportEvent::Packet(packet));
+        }
+    }
+    let _ = server.flush();
+});
@@ -0,0 +1,395 @@
+//! A matchmaking client driven from the terminal, for debugging server deployments without the game.
+//!
+//! Reads commands from stdin, one per line:
+//!     queue: queues, or requeues after a match
+//!     dequeue: leaves the queue
+//!     peers: prints the peers and their latencies
+//!     challenge ADDR, accept ADDR, decline ADDR: challenges, accepts or declines the peer at ADDR
+//!     quit: closes the client
+//! and prints the events on stdout, the peers with their latencies whenever they change, incoming
+//! challenges, the queue status and the confirmed match.
+//!
+//! Run using cargo run server_ip [local_addr] [--json], e.g. cargo run 127.0.0.1 127.0.0.1:0 --json
+//! The client binds to an unused port on all interfaces unless a local address is given.
+//! With --json, each event is printed as a JSON object on a line of its own.
+
+use log::error;
+use mirai_core::transport::LaminarTransport;
+use mirai_matchmaking_client::{
+    Client, ClientError, IncomingChallenge, Peer, PeerStatus, QueueStatus,
+};
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+use std::collections::HashSet;
+use std::io::{self, BufRead};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+use std::{env, thread};
+
+/// How often the peers are printed at most while their latencies change.
+const PEERS_INTERVAL_MILLIS: u64 = 1000;
+const POLL_INTERVAL_MILLIS: u64 = 10;
+
+fn main() {
+    env_logger::init();
+    if let Err(e) = run() {
+        error!("{}", e);
+    }
+}
+
+fn run() -> Result<(), CliError> {
+    let mut args: Vec<_> = env::args().skip(1).collect();
+    let json = match args.iter().position(|arg| arg == "--json") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    let server_ip = args.first().ok_or(CliError::MissingIp)?;
+    let server_ip = server_ip.parse().context(InvalidIp { ip: server_ip })?;
+    let local_addr = match args.get(1) {
+        Some(addr) => addr.parse().context(InvalidIp { ip: addr })?,
+        None => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+    };
+
+    let commands = read_commands();
+    let transport = LaminarTransport::bind(local_addr).context(BindError)?;
+    let mut session = Session::new(server_ip, transport, json);
+    loop {
+        match commands.try_recv() {
+            Ok(line) => match Command::parse(&line) {
+                Ok(Some(Command::Quit)) => break,
+                Ok(Some(Command::Queue)) if session.matched => {
+                    session = session.requeue()?;
+                }
+                Ok(Some(command)) => {
+                    if let Err(e) = session.execute(command) {
+                        eprintln!("{}", e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("{}", e),
+            },
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => break,
+        }
+        session.poll()?;
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+    }
+    session.client.close().context(ClientErr)?;
+    Ok(())
+}
+
+/// Reads stdin on a thread of its own so that the events keep being printed while it waits.
+fn read_commands() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    receiver
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Queue,
+    Dequeue,
+    Peers,
+    Challenge(SocketAddr),
+    Accept(SocketAddr),
+    Decline(SocketAddr),
+    Quit,
+}
+
+impl Command {
+    /// Parses a line of input, which may be empty.
+    fn parse(line: &str) -> Result<Option<Self>, CliError> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        let mut addr = || {
+            let addr = words.next().ok_or_else(|| CliError::MissingAddr {
+                command: command.to_string(),
+            })?;
+            addr.parse().context(InvalidIp { ip: addr })
+        };
+        let command = match command {
+            "queue" => Self::Queue,
+            "dequeue" => Self::Dequeue,
+            "peers" => Self::Peers,
+            "challenge" => Self::Challenge(addr()?),
+            "accept" => Self::Accept(addr()?),
+            "decline" => Self::Decline(addr()?),
+            "quit" | "exit" => Self::Quit,
+            _ => {
+                return Err(CliError::UnknownCommand {
+                    command: command.to_string(),
+                })
+            }
+        };
+        Ok(Some(command))
+    }
+}
+
+/// What the client saw happen.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Peers {
+        peers: Vec<PeerInfo>,
+    },
+    Challenged {
+        from: SocketAddr,
+    },
+    QueueStatus {
+        position: u32,
+        estimated_wait_secs: Option<f64>,
+    },
+    Matched {
+        opponent: SocketAddr,
+        relayed: bool,
+    },
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct PeerInfo {
+    addr: SocketAddr,
+    latency_millis: Option<f64>,
+    status: &'static str,
+}
+
+impl From<&Peer> for PeerInfo {
+    fn from(peer: &Peer) -> Self {
+        Self {
+            addr: peer.addr(),
+            latency_millis: peer.latency().map(|latency| latency.as_secs_f64() * 1000.0),
+            status: match peer.status() {
+                PeerStatus::None => "none",
+                PeerStatus::OutgoingChallenge => "challenged",
+                PeerStatus::IncomingChallenge => "challenging",
+                PeerStatus::Confirmed => "confirmed",
+            },
+        }
+    }
+}
+
+impl From<QueueStatus> for Event {
+    fn from(status: QueueStatus) -> Self {
+        Self::QueueStatus {
+            position: status.position,
+            estimated_wait_secs: status.estimated_wait.map(|wait| wait.as_secs_f64()),
+        }
+    }
+}
+
+impl Event {
+    fn print(&self, json: bool) {
+        if json {
+            match serde_json::to_string(self) {
+                Ok(line) => println!("{}", line),
+                Err(e) => error!("failed to serialize {:?}: {}", self, e),
+            }
+            return;
+        }
+        match self {
+            Self::Peers { peers } => {
+                println!("{} peers", peers.len());
+                for peer in peers {
+                    match peer.latency_millis {
+                        Some(latency) => {
+                            println!("  {} {:.1} ms {}", peer.addr, latency, peer.status)
+                        }
+                        None => println!("  {} - {}", peer.addr, peer.status),
+                    }
+                }
+            }
+            Self::Challenged { from } => println!("challenged by {}", from),
+            Self::QueueStatus {
+                position,
+                estimated_wait_secs,
+            } => match estimated_wait_secs {
+                Some(wait) => println!("queue position {}, about {:.0} s to go", position, wait),
+                None => println!("queue position {}", position),
+            },
+            Self::Matched { opponent, relayed } => {
+                let relayed = if *relayed { " through the relay" } else { "" };
+                println!("matched with {}{}", opponent, relayed)
+            }
+        }
+    }
+}
+
+/// The client and what has already been printed about it.
+struct Session {
+    client: Client<LaminarTransport>,
+    server_ip: IpAddr,
+    json: bool,
+    generation: u64,
+    peers_printed: Option<Instant>,
+    challenged_by: HashSet<SocketAddr>,
+    matched: bool,
+}
+
+impl Session {
+    fn new(server_ip: IpAddr, transport: LaminarTransport, json: bool) -> Self {
+        Self {
+            client: Client::with_transport(server_ip, transport),
+            server_ip,
+            json,
+            generation: 0,
+            peers_printed: None,
+            challenged_by: HashSet::new(),
+            matched: false,
+        }
+    }
+
+    fn execute(&mut self, command: Command) -> Result<(), CliError> {
+        match command {
+            Command::Queue => self.client.queue().context(ClientErr)?,
+            Command::Dequeue => self.client.dequeue().context(ClientErr)?,
+            Command::Peers => self.print_peers(self.client.peers().context(ClientErr)?),
+            Command::Challenge(addr) => {
+                let mut peer = self.peer(addr)?;
+                self.client.challenge(&mut peer).context(ClientErr)?;
+            }
+            Command::Accept(addr) => {
+                let mut peer = self.peer(addr)?;
+                self.client.accept(&mut peer).context(ClientErr)?;
+            }
+            Command::Decline(addr) => self.client.decline(addr).context(ClientErr)?,
+            Command::Quit => {}
+        }
+        Ok(())
+    }
+
+    /// A matched client is done, so queues a new one on the same socket.
+    fn requeue(self) -> Result<Self, CliError> {
+        let transport = self.client.close().context(ClientErr)?;
+        let mut session = Self::new(self.server_ip, transport, self.json);
+        session.client.queue().context(ClientErr)?;
+        Ok(session)
+    }
+
+    fn peer(&self, addr: SocketAddr) -> Result<Peer, CliError> {
+        let peers = self.client.peers().context(ClientErr)?;
+        peers
+            .into_iter()
+            .find(|peer| peer.addr() == addr)
+            .ok_or(CliError::UnknownPeer { addr })
+    }
+
+    /// Prints what has happened since the last poll.
+    fn poll(&mut self) -> Result<(), CliError> {
+        if self.matched {
+            return Ok(());
+        }
+        let interval = Duration::from_millis(PEERS_INTERVAL_MILLIS);
+        if self
+            .peers_printed
+            .is_none_or(|printed| printed.elapsed() >= interval)
+        {
+            if let Some((generation, peers)) = self
+                .client
+                .peers_if_changed(self.generation)
+                .context(ClientErr)?
+            {
+                self.generation = generation;
+                self.print_peers(peers);
+            }
+        }
+        let incoming: HashSet<_> = self
+            .client
+            .incoming_challenges()
+            .context(ClientErr)?
+            .iter()
+            .map(IncomingChallenge::peer)
+            .collect();
+        for &from in incoming.difference(&self.challenged_by) {
+            Event::Challenged { from }.print(self.json);
+        }
+        self.challenged_by = incoming;
+        for status in self.client.queue_status_updates().context(ClientErr)? {
+            Event::from(status).print(self.json);
+        }
+        if let Some(opponent) = self.client.check_match().context(ClientErr)? {
+            let relayed = self.client.is_relayed().context(ClientErr)?;
+            Event::Matched { opponent, relayed }.print(self.json);
+            self.matched = true;
+        }
+        Ok(())
+    }
+
+    fn print_peers(&mut self, peers: HashSet<Peer>) {
+        let mut peers: Vec<PeerInfo> = peers.iter().map(PeerInfo::from).collect();
+        peers.sort_by_key(|peer| peer.addr);
+        Event::Peers { peers }.print(self.json);
+        self.peers_printed = Some(Instant::now());
+    }
+}
+
+#[derive(Debug, Snafu)]
+enum CliError {
+    #[snafu(display("missing IP parameter"))]
+    MissingIp,
+    #[snafu(display("invalid address '{}': {}", ip, source))]
+    InvalidIp {
+        ip: String,
+        source: std::net::AddrParseError,
+    },
+    #[snafu(display("unknown command '{}'", command))]
+    UnknownCommand { command: String },
+    #[snafu(display("{} needs the peer's address", command))]
+    MissingAddr { command: String },
+    #[snafu(display("{} is not a peer", addr))]
+    UnknownPeer { addr: SocketAddr },
+    #[snafu(display("binding error: {}", source))]
+    BindError { source: laminar::ErrorKind },
+    #[snafu(display("client error: {:?}", source))]
+    ClientErr { source: ClientError },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed() {
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        assert_eq!(Command::parse("queue").unwrap(), Some(Command::Queue));
+        assert_eq!(
+            Command::parse("  challenge 127.0.0.1:5000 ").unwrap(),
+            Some(Command::Challenge(addr))
+        );
+        assert_eq!(Command::parse("").unwrap(), None);
+        assert!(Command::parse("accept").is_err());
+        assert!(Command::parse("accept 127.0.0.1").is_err());
+        assert!(Command::parse("shout").is_err());
+    }
+
+    #[test]
+    fn events_are_printed_as_json() {
+        let event = Event::Peers {
+            peers: vec![PeerInfo {
+                addr: "127.0.0.1:5000".parse().unwrap(),
+                latency_millis: Some(12.5),
+                status: "none",
+            }],
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"peers","peers":[{"addr":"127.0.0.1:5000","latency_millis":12.5,"status":"none"}]}"#
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! The source of the current time for the clients' timers and timeouts.
+//!
+//! The clients read the time from a `Clock`, which is the `SystemClock` unless one is given.
+//! A `ManualClock` only moves when it's advanced, so tests can skip past a timeout instead of sleeping
+//! through it. Its clones share the same time, so a test can keep one and give the other to a client.
+
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Tells the time with `Instant::now`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until it's advanced.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    /// Creates a clock that starts at the current time.
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Moves the clock and its clones forward.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(PoisonError::into_inner);
+        *now += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manual_clocks_move_together() {
+        let clock = ManualClock::new();
+        let shared = clock.clone();
+        let start = shared.now();
+        assert_eq!(shared.now(), start);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(shared.now(), start + Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,129 @@
+//! The cryptography that encrypts the traffic between the clients and the server,
+//! see `transport::secure`.
+//!
+//! The server has a static keypair whose public key is given to the clients out of band,
+//! e.g. in their configuration. Clients perform a Noise NK handshake with the server, which
+//! authenticates the server and agrees on keys for the session. The handshake is done by `snow`
+//! and the primitives come from the RustCrypto and dalek crates.
+
+pub(crate) mod noise;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io;
+use x25519_dalek::X25519_BASEPOINT_BYTES;
+
+pub type PublicKey = [u8; 32];
+
+/// A static or ephemeral X25519 keypair.
+#[derive(Clone)]
+pub struct Keypair {
+    secret: [u8; 32],
+    public: PublicKey,
+}
+
+impl Keypair {
+    /// Generates a keypair with the operating system's random number generator.
+    /// # Errors
+    /// If no randomness is available.
+    pub fn generate() -> io::Result<Self> {
+        let mut secret = [0; 32];
+        getrandom::getrandom(&mut secret)?;
+        Ok(Self::from_secret(secret))
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        Self {
+            secret,
+            public: x25519_dalek::x25519(secret, X25519_BASEPOINT_BYTES),
+        }
+    }
+
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+
+    pub fn secret(&self) -> [u8; 32] {
+        self.secret
+    }
+}
+
+/// HMAC-SHA-256 of the concatenation of the parts, e.g. to sign data with a key shared out of band.
+pub fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("any key length is valid");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// The public handle of the player with the given ID, which peers are told instead of the ID itself.
+/// Peers can recognize a player by its handle, but can't recover the ID from it to impersonate the player.
+pub fn player_handle(id: &str) -> String {
+    let digest = hmac(b"mirai player handle", &[id.as_bytes()]);
+    key_to_hex(&digest)[..16].to_string()
+}
+
+/// Formats a key as hex, e.g. to publish the server's public key.
+pub fn key_to_hex(key: &[u8; 32]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses a key formatted by `key_to_hex`.
+pub fn key_from_hex(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hmac_covers_every_part() {
+        // RFC 4231, test case 2
+        let mac = hmac(b"Jefe", &[b"what do ya want ", b"for nothing?"]);
+        assert_eq!(
+            key_to_hex(&mac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn handles_hide_the_player_id() {
+        let handle = player_handle("alice");
+        assert_eq!(handle.len(), 16);
+        assert_eq!(handle, player_handle("alice"));
+        assert_ne!(handle, player_handle("bob"));
+        assert!(!handle.contains("alice"));
+    }
+
+    #[test]
+    fn public_keys_are_derived_from_the_secret() {
+        // RFC 7748, section 6.1
+        let secret =
+            key_from_hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a")
+                .unwrap();
+        assert_eq!(
+            key_to_hex(&Keypair::from_secret(secret).public()),
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"
+        );
+    }
+
+    #[test]
+    fn keys_round_trip_through_hex() {
+        let keypair = Keypair::generate().unwrap();
+        let hex = key_to_hex(&keypair.public());
+        assert_eq!(key_from_hex(&hex), Some(keypair.public()));
+        assert_eq!(key_from_hex(&hex[1..]), None);
+        assert_eq!(key_from_hex(&"g".repeat(64)), None);
+    }
+}
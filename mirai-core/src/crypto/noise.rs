@@ -0,0 +1,151 @@
+//! The Noise_NK_25519_ChaChaPoly_SHA256 handshake, see noiseprotocol.org.
+//!
+//! The client knows the server's static public key, so the server is authenticated
+//! while the client stays anonymous:
+//! ```text
+//! <- s
+//! ...
+//! -> e, es
+//! <- e, ee
+//! ```
+//! Both handshake messages carry an empty payload. Transport messages carry their nonce explicitly
+//! because packets may be lost or reordered, so the sessions use snow's stateless transport mode.
+
+use super::{Keypair, PublicKey};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+
+const PATTERN: &str = "Noise_NK_25519_ChaChaPoly_SHA256";
+const TAG_SIZE: usize = 16;
+/// The size of both handshake messages: an ephemeral public key and the tag of the empty payload.
+pub const HANDSHAKE_SIZE: usize = 32 + TAG_SIZE;
+
+fn builder() -> Builder<'static> {
+    Builder::new(PATTERN.parse().expect("the pattern is valid"))
+}
+
+/// Encrypts the transport messages to the other side of a handshake and decrypts the ones from it.
+pub struct Cipher {
+    state: StatelessTransportState,
+    nonce: u64,
+}
+
+impl Cipher {
+    fn new(handshake: HandshakeState) -> Option<Self> {
+        let state = handshake.into_stateless_transport_mode().ok()?;
+        Some(Self { state, nonce: 0 })
+    }
+
+    /// Encrypts the plaintext with the next nonce, which is returned with the ciphertext.
+    /// Returns None once the nonces have run out.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Option<(u64, Vec<u8>)> {
+        // the last nonce is reserved by Noise
+        if self.nonce == u64::MAX {
+            return None;
+        }
+        let nonce = self.nonce;
+        let mut ciphertext = vec![0; plaintext.len() + TAG_SIZE];
+        let len = self
+            .state
+            .write_message(nonce, plaintext, &mut ciphertext)
+            .ok()?;
+        ciphertext.truncate(len);
+        self.nonce += 1;
+        Some((nonce, ciphertext))
+    }
+
+    /// Decrypts a ciphertext sent with the given nonce, or returns None if it has been tampered with.
+    pub fn decrypt(&self, nonce: u64, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let mut plaintext = vec![0; ciphertext.len()];
+        let len = self
+            .state
+            .read_message(nonce, ciphertext, &mut plaintext)
+            .ok()?;
+        plaintext.truncate(len);
+        Some(plaintext)
+    }
+}
+
+/// The client's side of a handshake in progress.
+pub struct Initiator {
+    handshake: HandshakeState,
+}
+
+impl Initiator {
+    /// Starts a handshake with the server, returning the first handshake message.
+    /// # Errors
+    /// If no randomness is available for the ephemeral key.
+    pub fn new(server_public: &PublicKey) -> Result<(Self, Vec<u8>), snow::Error> {
+        let mut handshake = builder()
+            .remote_public_key(server_public)
+            .build_initiator()?;
+        let mut message = vec![0; HANDSHAKE_SIZE];
+        let len = handshake.write_message(&[], &mut message)?;
+        message.truncate(len);
+        Ok((Self { handshake }, message))
+    }
+
+    /// Reads the server's handshake message, returning the cipher for the session.
+    /// Returns None if the message wasn't sent by the server.
+    pub fn finish(mut self, message: &[u8]) -> Option<Cipher> {
+        if message.len() != HANDSHAKE_SIZE {
+            return None;
+        }
+        self.handshake.read_message(message, &mut []).ok()?;
+        Cipher::new(self.handshake)
+    }
+}
+
+/// Answers a client's first handshake message, returning the response and the cipher for the session.
+/// Returns None if the message is malformed or meant for another key, or if no randomness
+/// is available for the ephemeral key.
+pub fn respond(keypair: &Keypair, message: &[u8]) -> Option<(Vec<u8>, Cipher)> {
+    if message.len() != HANDSHAKE_SIZE {
+        return None;
+    }
+    let secret = keypair.secret();
+    let mut handshake = builder()
+        .local_private_key(&secret)
+        .build_responder()
+        .ok()?;
+    handshake.read_message(message, &mut []).ok()?;
+    let mut response = vec![0; HANDSHAKE_SIZE];
+    let len = handshake.write_message(&[], &mut response).ok()?;
+    response.truncate(len);
+    Some((response, Cipher::new(handshake)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn handshake_agrees_on_keys() {
+        let server = Keypair::generate().unwrap();
+        let (initiator, message) = Initiator::new(&server.public()).unwrap();
+        let (response, mut server_cipher) = respond(&server, &message).unwrap();
+        let mut client_cipher = initiator.finish(&response).unwrap();
+
+        let (nonce, ciphertext) = client_cipher.encrypt(b"queue").unwrap();
+        assert_eq!(server_cipher.decrypt(nonce, &ciphertext).unwrap(), b"queue");
+        let (nonce, ciphertext) = server_cipher.encrypt(b"peers").unwrap();
+        assert_eq!(nonce, 0);
+        assert_eq!(client_cipher.decrypt(nonce, &ciphertext).unwrap(), b"peers");
+        // the nonce is authenticated
+        assert_eq!(client_cipher.decrypt(nonce + 1, &ciphertext), None);
+        // and each direction has its own key
+        assert_eq!(server_cipher.decrypt(nonce, &ciphertext), None);
+    }
+
+    #[test]
+    fn only_the_server_can_answer() {
+        let server = Keypair::generate().unwrap();
+        let impostor = Keypair::generate().unwrap();
+        let (initiator, message) = Initiator::new(&server.public()).unwrap();
+        assert!(respond(&impostor, &message).is_none());
+
+        // an impostor that makes up its own response can't complete the handshake
+        let (_, other_message) = Initiator::new(&impostor.public()).unwrap();
+        let (response, _) = respond(&impostor, &other_message).unwrap();
+        assert!(initiator.finish(&response).is_none());
+    }
+}
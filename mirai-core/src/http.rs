@@ -0,0 +1,95 @@
+//! A minimal HTTP client for POSTing JSON to other services, e.g. the server's webhooks.
+//!
+//! Only `http://` URLs are supported, endpoints that require TLS can be reached through a proxy.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Where the requests are sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    // host and port
+    authority: String,
+    path: String,
+}
+
+impl Endpoint {
+    /// Parses an `http://host[:port][/path]` URL, or returns None if it's something else.
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return None;
+        }
+        let authority = if authority.contains(':') {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+        Some(Self {
+            authority,
+            path: path.to_string(),
+        })
+    }
+
+    /// The host and port the requests are sent to.
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    /// POSTs the JSON body and returns the response's status code.
+    /// # Errors
+    /// If the host can't be reached within the timeout or the response isn't HTTP.
+    pub fn post_json(&self, body: &[u8], timeout: Duration) -> io::Result<u16> {
+        let addr =
+            self.authority.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no address for the host")
+            })?;
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.authority,
+            body.len()
+        );
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        // only the status line is needed
+        let mut response = [0; 32];
+        let mut read = 0;
+        while read < response.len() {
+            match stream.read(&mut response[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        std::str::from_utf8(&response[..read])
+            .ok()
+            .and_then(|status_line| status_line.split(' ').nth(1))
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_http_urls_are_supported() {
+        let endpoint = Endpoint::parse("http://example.com").unwrap();
+        assert_eq!(endpoint.authority(), "example.com:80");
+        assert_eq!(endpoint.path, "/");
+        let endpoint = Endpoint::parse("http://127.0.0.1:8080/hooks/mirai").unwrap();
+        assert_eq!(endpoint.authority(), "127.0.0.1:8080");
+        assert_eq!(endpoint.path, "/hooks/mirai");
+        assert!(Endpoint::parse("https://example.com/hook").is_none());
+        assert!(Endpoint::parse("http:///hook").is_none());
+    }
+}
@@ -1,25 +1,338 @@
+pub mod clock;
+pub mod crypto;
+pub mod http;
+pub mod logging;
+pub mod transport;
+
 pub mod v1 {
     // types used by the client and the server
     pub use serde::{Deserialize, Serialize};
-    use std::{collections::HashSet, net::SocketAddr};
+    use std::{collections::HashSet, net::SocketAddr, time::Duration};
 
     pub const SERVER_PORT: u16 = 44444;
     pub const CLIENT_PORT: u16 = 44445;
 
-    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+    /// The streams packets are ordered or sequenced on, one for each class of message,
+    /// so that a lost packet only holds up the packets of its own class.
+    pub mod streams {
+        use crate::transport::StreamId;
+
+        /// Matchmaking messages between the server and the clients and challenges between clients, reliable and ordered.
+        pub const CONTROL: StreamId = 0;
+        /// Pings between clients, unreliable and sequenced.
+        pub const PING: StreamId = 1;
+        /// Inputs during a match, unreliable and sequenced.
+        pub const INPUT: StreamId = 2;
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
     pub enum ClientToServer {
         StatusCheck,
         Queue,
+        // queues the client, whose router forwards the given port to it
+        QueueMapped(u16),
+        // echoes the cookie sent by the server to prove the client receives at its address
+        Cookie(u64),
         Dequeue,
         Heartbeat,
+        // asks the server to relay packets between the client and the peer, which has to ask as well
+        RequestRelay(SocketAddr),
+        // relayed to a peer both clients requested relaying with, with the packet's delivery
+        Relay { to: SocketAddr, payload: Vec<u8> },
+        // lists a room hosted by the queued client, replacing its earlier listing
+        OpenRoom(Room),
+        CloseRoom,
+        ListRooms,
+        // a replay written by the game client, stored if the server keeps replays
+        UploadReplay(Vec<u8>),
+        FetchReplay(u64),
+        // the ID the queued client's reserved matches are scheduled with
+        Identify(String),
+        // queues the client together with the clients that joined with the same secret token
+        JoinParty(u64),
+        LeaveParty,
+        // the criteria the queued client is matched by
+        Profile(Profile),
+        // the queued client's match with the peers was confirmed, so the server can tell how long it waited
+        Matched(Vec<SocketAddr>),
+        // how the identified client's match went, kept in its match history if the server keeps one
+        MatchEnded(MatchReport),
+        // the recent matches of the player with the given ID
+        FetchHistory(String),
+        Leaderboard(LeaderboardQuery),
+        // sent by a federated server in another region, ignored from anyone else
+        QueueSummary(QueueSummary),
+        // sent periodically by a queued client with its resume token, so the server follows it
+        // if its address changes
+        Resume(u64),
+        // the resume token of a previous session of the client, e.g. one that crashed, which is dequeued
+        // so the client can queue again with its player ID
+        Supersede(u64),
+        // the queued peer at the address left the client's challenge unanswered
+        Unresponsive(SocketAddr),
+        // the idle client is back, e.g. it answered or sent a challenge
+        Active,
+        // joins the room of the host at the address, e.g. one offered with Backfill
+        JoinRoom(SocketAddr),
+        // the player IDs of the identified client's friends, which it shares its presence with
+        Friends(Vec<String>),
+        // asks the server to introduce the client to the online friend with the ID, to challenge it
+        ChallengeFriend(String),
+        // asks for a code that invites another client to challenge this one, replacing its earlier one
+        CreateInvite,
+        // asks the server to introduce the client to the one that created the invite, to challenge it
+        RedeemInvite(String),
+        // asks the queued client's server for its peers again, after it missed an update of the list
+        Resync,
     }
 
     #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
     pub enum ServerToClient {
         Alive,
-        Peers(HashSet<SocketAddr>),
-        Queued(SocketAddr),
-        Dequeued(SocketAddr),
+        // the full peer list, which starts a new sequence of updates
+        Peers(PeerListSeq, HashSet<SocketAddr>),
+        // the clients that queued since the last batch
+        Queued(PeerListSeq, HashSet<SocketAddr>),
+        Dequeued(PeerListSeq, SocketAddr),
+        // the client has to echo the cookie before it can queue
+        Cookie(u64),
+        // the server relays packets between the client and the peer
+        RelayReady(SocketAddr),
+        // the server doesn't relay packets
+        RelayUnavailable(SocketAddr),
+        Relayed {
+            from: SocketAddr,
+            payload: Vec<u8>,
+        },
+        // the open rooms and the addresses of their hosts
+        Rooms(Vec<(SocketAddr, Room)>),
+        // the ID the uploaded replay is fetched with
+        ReplayStored(u64),
+        // the server doesn't keep replays, or the replay is too large
+        ReplayRejected,
+        Replay {
+            id: u64,
+            replay: Option<Vec<u8>>,
+        },
+        // the reserved match with the peer has started
+        Scheduled(SocketAddr),
+        // the parties among the peers, with all of their queued members
+        Parties(Vec<Vec<SocketAddr>>),
+        // the queued members of the client's party besides the client
+        Party(Vec<SocketAddr>),
+        // sent to queued clients periodically
+        QueueStatus(QueueStatus),
+        // the player's recent matches, the latest first, empty if the server doesn't keep a history
+        History {
+            player: String,
+            matches: Vec<MatchRecord>,
+        },
+        // the page of the leaderboard that was queried, empty if the server doesn't rate players
+        Leaderboard(Vec<Standing>),
+        // the servers of other regions with players queued, offered to a client nobody here matches
+        OtherRegions(Vec<RegionSummary>),
+        // the address the server receives the client's packets from, sent once the client is verified
+        YourAddr(SocketAddr),
+        // the secret token that moves the client's queue entry to the address a Resume comes from
+        ResumeToken(u64),
+        // a queued client the recipient may know has moved to a new address
+        PeerMoved {
+            from: SocketAddr,
+            to: SocketAddr,
+        },
+        // the client left its peers' challenges unanswered, so it's no longer proposed to others
+        // until it sends Active, or it was dequeued if the server dequeues idle clients
+        Idle {
+            dequeued: bool,
+        },
+        // the rooms with free places the queued client matches, hosted by the clients at the addresses
+        Backfill(Vec<(SocketAddr, Room)>),
+        // the client joined the room of the host, whose other members are at the addresses
+        JoinedRoom {
+            host: SocketAddr,
+            members: Vec<SocketAddr>,
+        },
+        // a player joined the room of the host the recipient hosts or is a member of
+        RoomJoined {
+            host: SocketAddr,
+            player: SocketAddr,
+        },
+        // the room of the host is full, closed or doesn't match the client
+        RoomFull(SocketAddr),
+        // the priority of the peers and how long they have waited, sent after Peers and Queued
+        Standings(Vec<(SocketAddr, QueueStanding)>),
+        // the public handles of the identified peers, sent after Peers and Queued,
+        // see crypto::player_handle
+        PeerHandles(Vec<(SocketAddr, String)>),
+        // the presence of the client's friends, all of them after it sends Friends
+        // and then the ones whose presence changed
+        Presence(Vec<(String, Presence)>),
+        // the address of the friend the client asked to challenge
+        FriendAddr {
+            friend: String,
+            addr: SocketAddr,
+        },
+        // the friend at the address is about to challenge the client
+        FriendChallenge {
+            friend: String,
+            addr: SocketAddr,
+        },
+        // the code that invites another client to challenge this one
+        Invite(String),
+        // the client at the address redeemed the client's invite and is about to challenge it
+        InviteRedeemed(SocketAddr),
+        // the address of the client that created the invite the client redeemed
+        InviteHost {
+            code: String,
+            addr: SocketAddr,
+        },
+        // the invite code is unknown, expired or already redeemed
+        InviteInvalid(String),
+        // the server didn't queue the client
+        Rejected(RejectReason),
+    }
+
+    /// Where an update of a client's peer list stands among the updates the server sent it.
+    /// Peers starts a new sequence at 0, and each Queued and Dequeued after it is numbered one higher
+    /// than the last, so a client that sees a gap knows it missed an update and can ask for the list again.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+    pub struct PeerListSeq {
+        /// Picked at random when the server starts, so the updates of a restarted server don't continue
+        /// the sequences of the previous one.
+        pub epoch: u64,
+        pub sequence: u64,
+    }
+
+    /// Why the server didn't queue a client.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum RejectReason {
+        /// The server is about to shut down, e.g. for a deployment, and only lets the queued clients finish
+        /// matching. Queue on another server or try again later.
+        Draining,
+        /// The queue is as long as the server allows. Queue on another server or try again later.
+        QueueFull,
+    }
+
+    /// What a queued client is matched by, missing values match anything.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+    pub struct Profile {
+        pub rating: Option<u32>,
+        pub region: Option<String>,
+    }
+
+    /// How a queued client is doing in the queue.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+    pub struct QueueStatus {
+        /// The client's place in the queue, 1 for the client with the highest priority that has waited the longest.
+        pub position: u32,
+        /// How much longer the client is expected to wait, if the server has seen players like it matched.
+        pub estimated_wait: Option<Duration>,
+    }
+
+    /// Whether a player is connected to the server, and whether it's queued.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Presence {
+        Offline,
+        Online,
+        Queued,
+    }
+
+    /// Where a queued peer stands in the queue. Peers with a higher priority, and then the ones that
+    /// have waited longer, should be considered first.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+    pub struct QueueStanding {
+        pub priority: u8,
+        pub waited: Duration,
+    }
+
+    /// What a federated server tells the servers of other regions about its queue.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    pub struct QueueSummary {
+        pub region: String,
+        pub queued: u32,
+        /// When the summary was sent, in milliseconds on the sender's clock.
+        pub sent: u64,
+        /// The `sent` of the last summary the sender received from the recipient, and how many
+        /// milliseconds the sender held it for, so the recipient can measure the round trip.
+        pub echo: Option<(u64, u64)>,
+    }
+
+    /// The queue of another region's server, which a client could queue on instead.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    pub struct RegionSummary {
+        /// The address of the other server.
+        pub server: SocketAddr,
+        pub region: String,
+        pub queued: u32,
+        /// The round trip between the servers. A client's round trip to the other server is usually
+        /// around its round trip to this one plus this, so it shows how much worse the connection
+        /// to the other region's players is likely to be.
+        pub server_rtt: Option<Duration>,
+    }
+
+    /// How a match ended for the player.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Outcome {
+        Win,
+        Loss,
+        Draw,
+    }
+
+    /// A client's account of a match it played, sent to the server once the match ends.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    pub struct MatchReport {
+        /// The addresses the opponents were proposed at.
+        pub opponents: Vec<SocketAddr>,
+        pub outcome: Outcome,
+        pub duration: Duration,
+        /// Whether the packets went through the server's relay.
+        pub relayed: bool,
+        pub average_rtt: Option<Duration>,
+        /// The frames the game rolled back and simulated again because of late inputs, if it rolls back.
+        pub rollback_frames: Option<u32>,
+    }
+
+    /// A match in a player's history, as reported by the player.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    pub struct MatchRecord {
+        pub player: String,
+        /// The IDs of the opponents that had identified themselves.
+        pub opponents: Vec<String>,
+        pub outcome: Outcome,
+        pub duration: Duration,
+        pub relayed: bool,
+        pub average_rtt: Option<Duration>,
+        /// How well the connection held up, from 0 to 100, if the player reported its round trip
+        /// or rollbacks.
+        pub quality: Option<u8>,
+        /// When the match was reported, in seconds since the Unix epoch.
+        pub ended: u64,
+    }
+
+    /// A page of the leaderboard.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    pub enum LeaderboardQuery {
+        /// The players from the given rank on, where the best player is ranked 1.
+        Top { from: u32, count: u32 },
+        /// The players ranked around the player with the given ID.
+        Around { player: String, count: u32 },
+    }
+
+    /// A player's place on the leaderboard.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    pub struct Standing {
+        pub rank: u32,
+        pub player: String,
+        pub rating: u32,
+    }
+
+    /// A lobby hosted by a queued client, listed to the clients browsing for a game.
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+    pub struct Room {
+        pub name: String,
+        pub region: String,
+        pub players: u16,
+        pub max_players: u16,
     }
 
     #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
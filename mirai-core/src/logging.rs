@@ -0,0 +1,40 @@
+//! The logging facade the workspace crates emit through: `log` by default, or `tracing` with the
+//! `tracing` feature, for games that already collect their diagnostics with a tracing subscriber.
+//! The crates log with the macros re-exported here instead of using either crate directly.
+//! Without a subscriber, the tracing events are emitted as `log` records, so e.g. the server binary's
+//! env_logger keeps working either way.
+//!
+//! The chatty subsystems log under their own targets, see `targets`, so that e.g. the pings between
+//! clients can be silenced without losing the challenges, with `RUST_LOG=info,mirai::client::ping=off`
+//! for env_logger or the same directive in tracing-subscriber's `EnvFilter`.
+//! Everything else is logged under its module path as usual.
+//!
+//! `set_max_level` changes how verbose the logs are at runtime, e.g. when the server's configuration
+//! is reloaded.
+
+pub use log::LevelFilter;
+#[cfg(not(feature = "tracing"))]
+pub use log::{debug, error, info, trace, warn};
+#[cfg(feature = "tracing")]
+pub use tracing::{debug, error, info, trace, warn};
+
+/// Sets the most verbose level that is logged. The logger's own filter still applies on top,
+/// e.g. env_logger's `RUST_LOG`. With the `tracing` feature, only the events tracing emits
+/// as `log` records are affected, the subscriber's filter is left to the game.
+pub fn set_max_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// The targets of the subsystems that log under their own.
+pub mod targets {
+    /// The pings between clients and their responses.
+    pub const CLIENT_PING: &str = "mirai::client::ping";
+    /// The challenges between clients and the matches they start.
+    pub const CLIENT_CHALLENGE: &str = "mirai::client::challenge";
+    /// The connectivity checks between clients, and the relay they fall back to.
+    pub const CLIENT_CONNECTIVITY: &str = "mirai::client::connectivity";
+    /// The clients joining and leaving the server's queue, and the batches announcing them.
+    pub const SERVER_QUEUE: &str = "mirai::server::queue";
+    /// The packets the server relays between clients.
+    pub const SERVER_RELAY: &str = "mirai::server::relay";
+}
@@ -0,0 +1,662 @@
+//! The transport carries packets between the clients and the server.
+//!
+//! The matchmaking client, the server and the game client are generic over `Transport`,
+//! so alternative transports can be plugged in without changing their handler loops.
+//! Incoming packets and connection events are delivered through a channel, which lets the
+//! handlers wait on the transport and their own messages at the same time.
+//!
+//! `LaminarTransport` sends packets over UDP with laminar and is enabled by the `laminar` feature.
+//! Payloads larger than a packet are split into chunks by `Chunks`, so the size of a message is
+//! not limited by laminar's fragmentation, and ordered packets are ordered by `Ordering` rather than laminar.
+//! `ChannelTransport` is a pair of channels, useful for tests and for wrapping another transport.
+//! `MockNetwork` creates in-memory transports whose packets are delivered when and in the order the test decides.
+//! `impair` wraps a transport to simulate a bad network, in tests or at runtime through `MIRAI_IMPAIRMENT`.
+//! `TcpTransport` is a fallback for networks that block UDP, and `MultiTransport` lets the server
+//! accept clients over several transports at once.
+//! `RelayTransport` sends the packets to peers that can't be reached directly through the server's relay.
+//! `PeerConnection` is the transport handed to the game once a match is confirmed, scoped to the matched peers.
+//! `SecureTransport` encrypts the traffic between the clients and the server.
+//!
+//! A packet handed to a transport hasn't necessarily left the machine, e.g. laminar only sends it once
+//! its socket is polled. Transports that can tell report with a `TransportEvent::Health` when their packets
+//! stop getting out and when they recover, so the handlers can tell the player about the degraded connection.
+
+pub mod chunk;
+pub mod impair;
+pub mod mock;
+pub mod order;
+pub mod peer;
+pub mod relay;
+pub mod secure;
+pub mod tcp;
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use snafu::Snafu;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub use self::chunk::Chunks;
+pub use self::impair::{impair, Impairment, LatencyDistribution};
+#[cfg(feature = "laminar")]
+pub use self::laminar_transport::LaminarTransport;
+pub use self::mock::{MockNetwork, MockTransport};
+pub use self::order::Ordering;
+pub use self::peer::{PeerConnection, PeerConnectionError, PeerEvent};
+pub use self::relay::RelayTransport;
+pub use self::secure::SecureTransport;
+pub use self::tcp::TcpTransport;
+
+/// Identifies a stream of packets that are ordered or sequenced relative to each other.
+/// The streams used by Mirai are defined in `v1::streams`.
+pub type StreamId = u8;
+
+/// Whether a packet has to arrive, and in which order.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Delivery {
+    /// The packet may be lost.
+    Unreliable,
+    /// The packet may be lost, and is dropped if a newer packet on the stream has already arrived.
+    UnreliableSequenced(StreamId),
+    /// The packet is resent until it arrives, but may arrive out of order.
+    ReliableUnordered,
+    /// The packet is resent until it arrives, and arrives after the packets sent before it on the stream.
+    ReliableOrdered(StreamId),
+}
+
+/// A packet sent to or received from a remote address.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Packet {
+    addr: SocketAddr,
+    payload: Vec<u8>,
+    delivery: Delivery,
+}
+
+impl Packet {
+    pub fn new(addr: SocketAddr, payload: Vec<u8>, delivery: Delivery) -> Self {
+        Self {
+            addr,
+            payload,
+            delivery,
+        }
+    }
+
+    pub fn unreliable(addr: SocketAddr, payload: Vec<u8>) -> Self {
+        Self {
+            addr,
+            payload,
+            delivery: Delivery::Unreliable,
+        }
+    }
+
+    pub fn unreliable_sequenced(addr: SocketAddr, payload: Vec<u8>, stream: StreamId) -> Self {
+        Self {
+            addr,
+            payload,
+            delivery: Delivery::UnreliableSequenced(stream),
+        }
+    }
+
+    pub fn reliable_unordered(addr: SocketAddr, payload: Vec<u8>) -> Self {
+        Self {
+            addr,
+            payload,
+            delivery: Delivery::ReliableUnordered,
+        }
+    }
+
+    pub fn reliable_ordered(addr: SocketAddr, payload: Vec<u8>, stream: StreamId) -> Self {
+        Self {
+            addr,
+            payload,
+            delivery: Delivery::ReliableOrdered(stream),
+        }
+    }
+
+    /// The address the packet is sent to or was received from.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn delivery(&self) -> Delivery {
+        self.delivery
+    }
+}
+
+/// Something that happened on the transport.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TransportEvent {
+    Packet(Packet),
+    /// A packet was received from a new address.
+    Connect(SocketAddr),
+    /// Nothing has been received from the address for a while.
+    Timeout(SocketAddr),
+    /// How well the packets are getting out changed.
+    Health(SendHealth),
+}
+
+/// How well a transport is getting the packets it was given out, see `TransportEvent::Health`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SendHealth {
+    Healthy,
+    /// Packets are waiting to be sent faster than they are sent, so they arrive late if at all.
+    Backlogged {
+        queued: usize,
+    },
+    /// The transport stopped sending packets, e.g. because the thread polling its socket died.
+    Stopped,
+}
+
+/// Sends and receives packets.
+pub trait Transport: Send + Sync + 'static {
+    /// Queues the packet to be sent.
+    /// # Errors
+    /// If the transport has been closed.
+    fn send(&self, packet: Packet) -> Result<(), TransportError>;
+
+    /// Returns the channel incoming packets and connection events are delivered to.
+    fn events(&self) -> &Receiver<TransportEvent>;
+
+    /// The address packets are sent from, if the transport has a single one.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Carries the state kept for the peer at `from`, e.g. the order of its streams, over to `to`,
+    /// once the peer is known to have moved there, e.g. because its NAT rebound its port.
+    fn migrate(&self, _from: SocketAddr, _to: SocketAddr) {}
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send(&self, packet: Packet) -> Result<(), TransportError> {
+        (**self).send(packet)
+    }
+
+    fn events(&self) -> &Receiver<TransportEvent> {
+        (**self).events()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn migrate(&self, from: SocketAddr, to: SocketAddr) {
+        (**self).migrate(from, to)
+    }
+}
+
+/// A transport made of a pair of channels: events are received from one and packets are sent to the other.
+pub struct ChannelTransport {
+    events: Receiver<TransportEvent>,
+    packets: Sender<Packet>,
+}
+
+impl ChannelTransport {
+    pub fn new(events: Receiver<TransportEvent>, packets: Sender<Packet>) -> Self {
+        Self { events, packets }
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&self, packet: Packet) -> Result<(), TransportError> {
+        self.packets
+            .send(packet)
+            .map_err(|_| TransportError::Closed)
+    }
+
+    fn events(&self) -> &Receiver<TransportEvent> {
+        &self.events
+    }
+}
+
+/// Combines several transports into one.
+/// Packets are sent through the transport the address was last heard from, or the first one
+/// if nothing has been received from the address yet.
+pub struct MultiTransport {
+    transports: Vec<Box<dyn Transport>>,
+    routes: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+    events: Receiver<TransportEvent>,
+}
+
+impl MultiTransport {
+    /// Starts up a thread per transport that forwards its events.
+    pub fn new(transports: Vec<Box<dyn Transport>>) -> Self {
+        let routes = Arc::new(Mutex::new(HashMap::new()));
+        let (event_sender, events) = unbounded();
+        for (i, transport) in transports.iter().enumerate() {
+            let transport_events = transport.events().clone();
+            let event_sender = event_sender.clone();
+            let routes = Arc::clone(&routes);
+            thread::spawn(move || {
+                for event in transport_events {
+                    let addr = match &event {
+                        TransportEvent::Packet(packet) => packet.addr(),
+                        TransportEvent::Connect(addr) | TransportEvent::Timeout(addr) => *addr,
+                        TransportEvent::Health(_) => {
+                            if event_sender.send(event).is_err() {
+                                return;
+                            }
+                            continue;
+                        }
+                    };
+                    routes.lock().expect("routes poisoned").insert(addr, i);
+                    if event_sender.send(event).is_err() {
+                        // the transport was dropped
+                        return;
+                    }
+                }
+            });
+        }
+        Self {
+            transports,
+            routes,
+            events,
+        }
+    }
+}
+
+impl Transport for MultiTransport {
+    fn send(&self, packet: Packet) -> Result<(), TransportError> {
+        let route = self
+            .routes
+            .lock()
+            .map_err(|_| TransportError::Closed)?
+            .get(&packet.addr())
+            .copied()
+            .unwrap_or(0);
+        self.transports
+            .get(route)
+            .ok_or(TransportError::Closed)?
+            .send(packet)
+    }
+
+    fn events(&self) -> &Receiver<TransportEvent> {
+        &self.events
+    }
+
+    fn migrate(&self, from: SocketAddr, to: SocketAddr) {
+        for transport in &self.transports {
+            transport.migrate(from, to);
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum TransportError {
+    #[snafu(display("the transport has been closed"))]
+    Closed,
+    #[snafu(display("the payload of {} bytes is too large to be sent", size))]
+    TooLarge { size: usize },
+    #[snafu(display("could not start a handshake: {}", source))]
+    Handshake { source: snow::Error },
+}
+
+#[cfg(feature = "laminar")]
+mod laminar_transport {
+    use super::{
+        Chunks, Delivery, Ordering, Packet, SendHealth, Transport, TransportError, TransportEvent,
+    };
+    use crossbeam_channel::{unbounded, Receiver, Sender};
+    use laminar::{Config, ErrorKind, Socket, SocketEvent};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{self, AtomicBool, AtomicU64};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // how long packets may wait without the socket being polled before the transport is backlogged
+    const STALL_MILLIS: u64 = 500;
+    // how long the socket is left between polls, as in laminar's start_polling
+    const POLL_SLEEP_MILLIS: u64 = 1;
+
+    /// Sends packets over UDP with laminar.
+    /// The socket is closed once the transport is dropped.
+    /// Payloads that don't fit in a single fragment are split into chunks.
+    /// Ordered packets are sent as reliable, unordered laminar packets and ordered by `Ordering`.
+    /// The packets are only sent when the socket is polled, so the transport reports
+    /// `SendHealth::Backlogged` when they wait for a poll for long, and `SendHealth::Stopped`
+    /// if the polling thread dies.
+    pub struct LaminarTransport {
+        local_addr: SocketAddr,
+        events: Receiver<TransportEvent>,
+        packets: Sender<laminar::Packet>,
+        chunks: Arc<Mutex<Chunks>>,
+        ordering: Arc<Mutex<Ordering>>,
+        polling: Arc<AtomicBool>,
+        health: Arc<PollHealth>,
+    }
+
+    // tracks whether the polling thread keeps up with the packets
+    struct PollHealth {
+        started: Instant,
+        // when the last poll finished, in milliseconds since the start
+        last_poll: AtomicU64,
+        backlogged: AtomicBool,
+        events: Sender<TransportEvent>,
+    }
+
+    impl PollHealth {
+        fn since_last_poll(&self) -> Duration {
+            let last_poll = Duration::from_millis(self.last_poll.load(atomic::Ordering::Relaxed));
+            self.started.elapsed().saturating_sub(last_poll)
+        }
+
+        // called after queueing packets, reports a backlog if they have waited for a poll for long
+        fn queued(&self, queued: usize) {
+            let stalled = self.since_last_poll() >= Duration::from_millis(STALL_MILLIS);
+            if queued > 0 && stalled && !self.backlogged.swap(true, atomic::Ordering::Relaxed) {
+                let _ = self
+                    .events
+                    .send(TransportEvent::Health(SendHealth::Backlogged { queued }));
+            }
+        }
+
+        // called by the polling thread after each poll, reports a recovery once a poll is quick again
+        fn polled(&self, took: Duration) {
+            let now = self.started.elapsed().as_millis() as u64;
+            self.last_poll.store(now, atomic::Ordering::Relaxed);
+            if took < Duration::from_millis(STALL_MILLIS)
+                && self.backlogged.swap(false, atomic::Ordering::Relaxed)
+            {
+                let _ = self
+                    .events
+                    .send(TransportEvent::Health(SendHealth::Healthy));
+            }
+        }
+    }
+
+    impl LaminarTransport {
+        /// Binds a socket to the given address.
+        /// Starts up threads that poll the socket and forward its events.
+        /// # Errors
+        /// If binding the socket fails.
+        pub fn bind(addr: SocketAddr) -> Result<Self, ErrorKind> {
+            Self::new(Socket::bind(addr)?)
+        }
+
+        /// Binds a socket with the given configuration to the given address.
+        /// Chunks are sized to fit in a single fragment.
+        /// Starts up threads that poll the socket and forward its events.
+        /// # Errors
+        /// If binding the socket fails.
+        pub fn bind_with_config(addr: SocketAddr, config: Config) -> Result<Self, ErrorKind> {
+            let chunk_size = config.fragment_size as usize;
+            Self::start(Socket::bind_with_config(addr, config)?, chunk_size)
+        }
+
+        /// Uses the given socket, which is expected to have laminar's default fragment size.
+        /// Starts up threads that poll the socket and forward its events.
+        /// # Errors
+        /// If the socket's local address cannot be read.
+        pub fn new(socket: Socket) -> Result<Self, ErrorKind> {
+            let chunk_size = Config::default().fragment_size as usize;
+            Self::start(socket, chunk_size)
+        }
+
+        fn start(mut socket: Socket, chunk_size: usize) -> Result<Self, ErrorKind> {
+            let local_addr = socket.local_addr()?;
+            let packets = socket.get_packet_sender();
+            let socket_events = socket.get_event_receiver();
+            let polling = Arc::new(AtomicBool::new(true));
+            let thread_polling = Arc::clone(&polling);
+            let (event_sender, events) = unbounded();
+            let health = Arc::new(PollHealth {
+                started: Instant::now(),
+                last_poll: AtomicU64::new(0),
+                backlogged: AtomicBool::new(false),
+                events: event_sender.clone(),
+            });
+            let thread_health = Arc::clone(&health);
+            // like laminar's start_polling_with_duration, but stops when the transport is dropped
+            thread::spawn(move || {
+                while thread_polling.load(atomic::Ordering::Relaxed) {
+                    let start = Instant::now();
+                    socket.manual_poll(start);
+                    thread_health.polled(start.elapsed());
+                    thread::sleep(Duration::from_millis(POLL_SLEEP_MILLIS));
+                }
+            });
+            let chunks = Arc::new(Mutex::new(Chunks::new(chunk_size)));
+            let thread_chunks = Arc::clone(&chunks);
+            let ordering = Arc::new(Mutex::new(Ordering::default()));
+            let thread_ordering = Arc::clone(&ordering);
+            let thread_polling = Arc::clone(&polling);
+            thread::spawn(move || {
+                for event in socket_events {
+                    let events = match event {
+                        SocketEvent::Packet(packet) => {
+                            let delivery = delivery(&packet);
+                            let packet = Packet {
+                                addr: packet.addr(),
+                                payload: packet.payload().to_vec(),
+                                delivery,
+                            };
+                            let mut chunks = match thread_chunks.lock() {
+                                Ok(chunks) => chunks,
+                                Err(_) => return,
+                            };
+                            let packet = match chunks.receive(packet) {
+                                Some(packet) => packet,
+                                // waiting for the rest of the chunks, or malformed
+                                None => continue,
+                            };
+                            let mut ordering = match thread_ordering.lock() {
+                                Ok(ordering) => ordering,
+                                Err(_) => return,
+                            };
+                            ordering
+                                .receive(packet)
+                                .into_iter()
+                                .map(TransportEvent::Packet)
+                                .collect()
+                        }
+                        SocketEvent::Connect(addr) => vec![TransportEvent::Connect(addr)],
+                        SocketEvent::Timeout(addr) => {
+                            match thread_ordering.lock() {
+                                Ok(mut ordering) => ordering.forget(addr),
+                                Err(_) => return,
+                            }
+                            vec![TransportEvent::Timeout(addr)]
+                        }
+                    };
+                    for event in events {
+                        if event_sender.send(event).is_err() {
+                            // the transport was dropped
+                            return;
+                        }
+                    }
+                }
+                // the socket was dropped, which only happens on purpose once the transport is dropped
+                if thread_polling.load(atomic::Ordering::Relaxed) {
+                    let _ = event_sender.send(TransportEvent::Health(SendHealth::Stopped));
+                }
+            });
+            Ok(Self {
+                local_addr,
+                events,
+                packets,
+                chunks,
+                ordering,
+                polling,
+                health,
+            })
+        }
+
+        pub fn local_addr(&self) -> SocketAddr {
+            self.local_addr
+        }
+    }
+
+    impl Drop for LaminarTransport {
+        fn drop(&mut self) {
+            self.polling.store(false, atomic::Ordering::Relaxed);
+        }
+    }
+
+    impl Transport for LaminarTransport {
+        fn send(&self, packet: Packet) -> Result<(), TransportError> {
+            let packet = self
+                .ordering
+                .lock()
+                .map_err(|_| TransportError::Closed)?
+                .wrap(packet);
+            let chunks = self
+                .chunks
+                .lock()
+                .map_err(|_| TransportError::Closed)?
+                .split(packet)?;
+            for chunk in chunks {
+                let Packet {
+                    addr,
+                    payload,
+                    delivery,
+                } = chunk;
+                let packet = match delivery {
+                    Delivery::Unreliable => laminar::Packet::unreliable(addr, payload),
+                    Delivery::UnreliableSequenced(stream) => {
+                        laminar::Packet::unreliable_sequenced(addr, payload, Some(stream))
+                    }
+                    // the header added by the ordering tells ordered payloads apart
+                    Delivery::ReliableUnordered | Delivery::ReliableOrdered(_) => {
+                        laminar::Packet::reliable_unordered(addr, payload)
+                    }
+                };
+                self.packets
+                    .send(packet)
+                    .map_err(|_| TransportError::Closed)?;
+            }
+            self.health.queued(self.packets.len());
+            Ok(())
+        }
+
+        fn events(&self) -> &Receiver<TransportEvent> {
+            &self.events
+        }
+
+        fn local_addr(&self) -> Option<SocketAddr> {
+            Some(self.local_addr)
+        }
+
+        fn migrate(&self, from: SocketAddr, to: SocketAddr) {
+            if let Ok(mut ordering) = self.ordering.lock() {
+                ordering.migrate(from, to);
+            }
+        }
+    }
+
+    fn delivery(packet: &laminar::Packet) -> Delivery {
+        use laminar::{DeliveryGuarantee, OrderingGuarantee};
+        // laminar only leaves the stream out for packets sent without one, which Mirai doesn't send
+        match (packet.delivery_guarantee(), packet.order_guarantee()) {
+            (DeliveryGuarantee::Unreliable, OrderingGuarantee::Sequenced(stream)) => {
+                Delivery::UnreliableSequenced(stream.unwrap_or_default())
+            }
+            (DeliveryGuarantee::Unreliable, _) => Delivery::Unreliable,
+            // ordered packets are told apart by the ordering
+            (DeliveryGuarantee::Reliable, _) => Delivery::ReliableUnordered,
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use std::time::Duration;
+
+        #[test]
+        fn reports_a_backlog_until_polled() {
+            let (events, received) = unbounded();
+            let health = PollHealth {
+                // the socket hasn't been polled since the start
+                started: Instant::now() - Duration::from_millis(STALL_MILLIS),
+                last_poll: AtomicU64::new(0),
+                backlogged: AtomicBool::new(false),
+                events,
+            };
+            health.queued(0);
+            assert!(received.try_recv().is_err());
+            health.queued(3);
+            health.queued(4);
+            assert_eq!(
+                received.try_iter().collect::<Vec<_>>(),
+                vec![TransportEvent::Health(SendHealth::Backlogged { queued: 3 })]
+            );
+            // a slow poll doesn't clear the backlog
+            health.polled(Duration::from_millis(STALL_MILLIS));
+            assert!(received.try_recv().is_err());
+            health.polled(Duration::from_millis(1));
+            health.queued(1);
+            assert_eq!(
+                received.try_iter().collect::<Vec<_>>(),
+                vec![TransportEvent::Health(SendHealth::Healthy)]
+            );
+        }
+
+        #[test]
+        fn keeps_the_delivery() {
+            let a = LaminarTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let b = LaminarTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let sent = vec![
+                Packet::unreliable(b.local_addr(), vec![0]),
+                Packet::unreliable_sequenced(b.local_addr(), vec![1], 1),
+                Packet::reliable_unordered(b.local_addr(), vec![2]),
+                Packet::reliable_ordered(b.local_addr(), vec![3], 2),
+            ];
+            for packet in &sent {
+                a.send(packet.clone()).unwrap();
+            }
+            let mut received = vec![];
+            while received.len() < sent.len() {
+                let event = b.events().recv_timeout(Duration::from_secs(5)).unwrap();
+                if let TransportEvent::Packet(packet) = event {
+                    received.push(packet);
+                }
+            }
+            received.sort_by_key(|packet| packet.payload().to_vec());
+            for (sent, received) in sent.iter().zip(&received) {
+                assert_eq!(sent.payload(), received.payload());
+                assert_eq!(sent.delivery(), received.delivery());
+                assert_eq!(received.addr(), a.local_addr());
+            }
+        }
+
+        #[test]
+        fn orders_packets_to_peers_that_have_not_answered() {
+            let a = LaminarTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let b = LaminarTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            for i in 0..3 {
+                a.send(Packet::reliable_ordered(b.local_addr(), vec![i], 0))
+                    .unwrap();
+            }
+            let mut received = vec![];
+            while received.len() < 3 {
+                let event = b.events().recv_timeout(Duration::from_secs(5)).unwrap();
+                if let TransportEvent::Packet(packet) = event {
+                    received.push(packet.payload()[0]);
+                }
+            }
+            assert_eq!(received, vec![0, 1, 2]);
+        }
+
+        #[test]
+        fn sends_payloads_larger_than_a_packet() {
+            let a = LaminarTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let b = LaminarTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            // larger than laminar's maximum packet size
+            let payload: Vec<u8> = (0..30_000).map(|i| i as u8).collect();
+            a.send(Packet::reliable_unordered(b.local_addr(), payload.clone()))
+                .unwrap();
+            loop {
+                let event = b.events().recv_timeout(Duration::from_secs(5)).unwrap();
+                if let TransportEvent::Packet(packet) = event {
+                    assert_eq!(packet.payload(), &payload[..]);
+                    break;
+                }
+            }
+        }
+    }
+}
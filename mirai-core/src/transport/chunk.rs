@@ -0,0 +1,233 @@
+//! Splits payloads that don't fit in a single packet into chunks and reassembles them on arrival.
+//!
+//! Every packet starts with a small header that carries the largest packet its sender accepts,
+//! so each side learns how large the packets it sends to a peer can be. Until a peer has been
+//! heard from, packets are kept to `DEFAULT_CHUNK_SIZE`. The chunks of a payload are sent with
+//! the payload's delivery, and a payload whose chunks don't all arrive in time,
+//! e.g. because an unreliable one was lost, is discarded.
+
+use super::{Packet, TransportError};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The chunk size assumed for peers that haven't announced theirs, small enough for common MTUs.
+pub const DEFAULT_CHUNK_SIZE: usize = 1200;
+/// The most chunks a payload can be split into.
+pub const MAX_CHUNKS: usize = 1024;
+const REASSEMBLY_TIMEOUT_MILLIS: u64 = 5000;
+
+const WHOLE: u8 = 0;
+const CHUNK: u8 = 1;
+// kind and chunk size
+const WHOLE_HEADER: usize = 3;
+// kind, chunk size, message, index and count
+const CHUNK_HEADER: usize = 11;
+
+/// A payload some of whose chunks have arrived.
+struct Partial {
+    parts: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    started: Instant,
+}
+
+/// The chunking state of one endpoint.
+pub struct Chunks {
+    chunk_size: usize,
+    peer_chunk_sizes: HashMap<SocketAddr, usize>,
+    next_message: u32,
+    partial: HashMap<(SocketAddr, u32), Partial>,
+}
+
+impl Chunks {
+    /// `chunk_size` is the largest packet, header included, that is sent and accepted by this endpoint.
+    pub fn new(chunk_size: usize) -> Self {
+        Self {
+            chunk_size: chunk_size.max(CHUNK_HEADER + 1).min(u16::MAX as usize),
+            peer_chunk_sizes: HashMap::new(),
+            next_message: 0,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// The largest packet that is sent to the peer.
+    pub fn chunk_size(&self, addr: SocketAddr) -> usize {
+        let peer = self.peer_chunk_sizes.get(&addr).copied();
+        self.chunk_size.min(peer.unwrap_or(DEFAULT_CHUNK_SIZE))
+    }
+
+    /// Adds the header to the packet, splitting it into chunks if it doesn't fit the peer's chunk size.
+    /// # Errors
+    /// If the payload needs more than `MAX_CHUNKS` chunks.
+    pub fn split(&mut self, packet: Packet) -> Result<Vec<Packet>, TransportError> {
+        let chunk_size = self.chunk_size(packet.addr);
+        // the announced size always fits, see new
+        let announced = (self.chunk_size as u16).to_be_bytes();
+        if packet.payload.len() + WHOLE_HEADER <= chunk_size {
+            let mut payload = Vec::with_capacity(WHOLE_HEADER + packet.payload.len());
+            payload.push(WHOLE);
+            payload.extend_from_slice(&announced);
+            payload.extend_from_slice(&packet.payload);
+            return Ok(vec![Packet { payload, ..packet }]);
+        }
+
+        let data_size = chunk_size - CHUNK_HEADER;
+        let count = packet.payload.len().div_ceil(data_size);
+        if count > MAX_CHUNKS {
+            return Err(TransportError::TooLarge {
+                size: packet.payload.len(),
+            });
+        }
+        let message = self.next_message;
+        self.next_message = self.next_message.wrapping_add(1);
+        let chunks = packet
+            .payload
+            .chunks(data_size)
+            .enumerate()
+            .map(|(index, data)| {
+                let mut payload = Vec::with_capacity(CHUNK_HEADER + data.len());
+                payload.push(CHUNK);
+                payload.extend_from_slice(&announced);
+                payload.extend_from_slice(&message.to_be_bytes());
+                payload.extend_from_slice(&(index as u16).to_be_bytes());
+                payload.extend_from_slice(&(count as u16).to_be_bytes());
+                payload.extend_from_slice(data);
+                Packet {
+                    addr: packet.addr,
+                    payload,
+                    delivery: packet.delivery,
+                }
+            })
+            .collect();
+        Ok(chunks)
+    }
+
+    /// Reads the header of a received packet and remembers the sender's chunk size.
+    /// Returns the packet with the whole payload once all of its chunks have arrived.
+    /// Malformed packets are discarded.
+    pub fn receive(&mut self, packet: Packet) -> Option<Packet> {
+        let now = Instant::now();
+        let timeout = Duration::from_millis(REASSEMBLY_TIMEOUT_MILLIS);
+        self.partial
+            .retain(|_, partial| now.duration_since(partial.started) < timeout);
+
+        let payload = &packet.payload;
+        let kind = *payload.first()?;
+        let announced = u16::from_be_bytes(<[u8; 2]>::try_from(payload.get(1..3)?).ok()?);
+        self.peer_chunk_sizes
+            .insert(packet.addr, (announced as usize).max(CHUNK_HEADER + 1));
+        match kind {
+            WHOLE => Some(Packet {
+                payload: payload[WHOLE_HEADER..].to_vec(),
+                ..packet
+            }),
+            CHUNK => {
+                let read_u16 = |i: usize| -> Option<usize> {
+                    let bytes = <[u8; 2]>::try_from(payload.get(i..i + 2)?).ok()?;
+                    Some(u16::from_be_bytes(bytes) as usize)
+                };
+                let message = u32::from_be_bytes(<[u8; 4]>::try_from(payload.get(3..7)?).ok()?);
+                let index = read_u16(7)?;
+                let count = read_u16(9)?;
+                if index >= count || count > MAX_CHUNKS {
+                    return None;
+                }
+                let partial = self
+                    .partial
+                    .entry((packet.addr, message))
+                    .or_insert_with(|| Partial {
+                        parts: vec![None; count],
+                        missing: count,
+                        started: now,
+                    });
+                if partial.parts.len() != count {
+                    return None;
+                }
+                if partial.parts[index].is_none() {
+                    partial.parts[index] = Some(payload[CHUNK_HEADER..].to_vec());
+                    partial.missing -= 1;
+                }
+                if partial.missing > 0 {
+                    return None;
+                }
+                let partial = self.partial.remove(&(packet.addr, message))?;
+                let payload = partial.parts.into_iter().flatten().flatten().collect();
+                Some(Packet { payload, ..packet })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::Delivery;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:1".parse().unwrap()
+    }
+
+    #[test]
+    fn reassembles_chunks_in_any_order() {
+        let mut sender = Chunks::new(100);
+        let mut receiver = Chunks::new(100);
+        let payload: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut chunks = sender
+            .split(Packet::reliable_unordered(addr(), payload.clone()))
+            .unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.payload().len() <= 100));
+
+        chunks.reverse();
+        let last = chunks.pop().unwrap();
+        for chunk in chunks.clone() {
+            assert_eq!(receiver.receive(chunk), None);
+        }
+        // repeated chunks are ignored
+        assert_eq!(receiver.receive(chunks[0].clone()), None);
+        let packet = receiver.receive(last).unwrap();
+        assert_eq!(packet.payload(), &payload[..]);
+        assert_eq!(packet.delivery(), Delivery::ReliableUnordered);
+    }
+
+    #[test]
+    fn small_payloads_are_not_chunked() {
+        let mut sender = Chunks::new(100);
+        let mut receiver = Chunks::new(100);
+        let packet = Packet::unreliable(addr(), vec![1, 2, 3]);
+        let chunks = sender.split(packet.clone()).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(receiver.receive(chunks[0].clone()), Some(packet));
+    }
+
+    #[test]
+    fn uses_the_smaller_chunk_size() {
+        let mut large = Chunks::new(2000);
+        let mut small = Chunks::new(100);
+        assert_eq!(large.chunk_size(addr()), DEFAULT_CHUNK_SIZE);
+
+        let announcement = small.split(Packet::unreliable(addr(), vec![])).unwrap();
+        large.receive(announcement[0].clone());
+        assert_eq!(large.chunk_size(addr()), 100);
+        let chunks = large
+            .split(Packet::unreliable(addr(), vec![0; 1000]))
+            .unwrap();
+        assert!(chunks.iter().all(|chunk| chunk.payload().len() <= 100));
+    }
+
+    #[test]
+    fn rejects_oversized_and_malformed_packets() {
+        let mut chunks = Chunks::new(100);
+        let too_large = vec![0; 100 * MAX_CHUNKS];
+        assert!(chunks.split(Packet::unreliable(addr(), too_large)).is_err());
+        for payload in [vec![], vec![CHUNK, 0, 100, 0], vec![7, 0, 100]] {
+            assert_eq!(chunks.receive(Packet::unreliable(addr(), payload)), None);
+        }
+        // the index must be within the count
+        let mut chunk = vec![CHUNK, 0, 100, 0, 0, 0, 0, 0, 2, 0, 2];
+        chunk.push(0);
+        assert_eq!(chunks.receive(Packet::unreliable(addr(), chunk)), None);
+    }
+}
@@ -0,0 +1,368 @@
+//! Injects latency, jitter, reordering, duplication and loss into a transport.
+//!
+//! The impairment applies to both directions: packets are delayed and dropped on their way out
+//! as well as on their way in. Connection events are passed through as they are.
+//! Random decisions come from a seeded generator, so a run can be repeated with the same seed.
+//!
+//! The impairment can be read from the `MIRAI_IMPAIRMENT` environment variable as a comma-separated
+//! list of settings, e.g. `latency=20..80,loss=0.05,duplicate=0.01,seed=1`. Durations are in milliseconds
+//! and the latency is either a constant `50`, a uniform range `20..80` or a normal distribution `50~10`.
+
+use super::{ChannelTransport, Packet, Transport, TransportEvent};
+use crossbeam_channel::{never, select, unbounded};
+use snafu::Snafu;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The environment variable the impairment is read from.
+pub const IMPAIRMENT_VAR: &str = "MIRAI_IMPAIRMENT";
+
+/// How the one-way latency of each packet is chosen.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LatencyDistribution {
+    Constant(Duration),
+    Uniform { min: Duration, max: Duration },
+    Normal { mean: Duration, std_dev: Duration },
+}
+
+impl Default for LatencyDistribution {
+    fn default() -> Self {
+        LatencyDistribution::Constant(Duration::from_millis(0))
+    }
+}
+
+/// The network conditions to simulate. Chances are between 0 and 1.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Impairment {
+    pub latency: LatencyDistribution,
+    /// Each packet is delayed by a random extra amount up to this.
+    pub jitter: Duration,
+    /// The chance for a packet to be held back by `reorder_delay`, letting the packets after it overtake it.
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    /// The chance for a packet to be delivered twice.
+    pub duplicate: f64,
+    /// The chance for a packet to be lost.
+    pub loss: f64,
+    /// The seed for the random decisions, or a seed based on the current time if none.
+    pub seed: Option<u64>,
+}
+
+impl Impairment {
+    /// Reads the impairment from `MIRAI_IMPAIRMENT`, if it is set.
+    /// # Errors
+    /// If the variable is set but cannot be parsed.
+    pub fn from_env() -> Result<Option<Self>, ImpairmentError> {
+        match std::env::var(IMPAIRMENT_VAR) {
+            Ok(impairment) => impairment.parse().map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl FromStr for Impairment {
+    type Err = ImpairmentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut impairment = Self::default();
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let invalid = || ImpairmentError::InvalidSetting {
+                setting: setting.to_string(),
+            };
+            let mut parts = setting.splitn(2, '=');
+            let key = parts.next().ok_or_else(invalid)?.trim();
+            let value = parts.next().ok_or_else(invalid)?.trim();
+            let millis = |value: &str| {
+                value
+                    .trim()
+                    .parse()
+                    .map(Duration::from_millis)
+                    .map_err(|_| invalid())
+            };
+            let chance = |value: &str| match value.parse::<f64>() {
+                Ok(chance) if (0.0..=1.0).contains(&chance) => Ok(chance),
+                _ => Err(invalid()),
+            };
+            match key {
+                "latency" => {
+                    impairment.latency = if let Some(i) = value.find("..") {
+                        LatencyDistribution::Uniform {
+                            min: millis(&value[..i])?,
+                            max: millis(&value[i + 2..])?,
+                        }
+                    } else if let Some(i) = value.find('~') {
+                        LatencyDistribution::Normal {
+                            mean: millis(&value[..i])?,
+                            std_dev: millis(&value[i + 1..])?,
+                        }
+                    } else {
+                        LatencyDistribution::Constant(millis(value)?)
+                    }
+                }
+                "jitter" => impairment.jitter = millis(value)?,
+                "reorder" => impairment.reorder = chance(value)?,
+                "reorder_delay" => impairment.reorder_delay = millis(value)?,
+                "duplicate" => impairment.duplicate = chance(value)?,
+                "loss" => impairment.loss = chance(value)?,
+                "seed" => impairment.seed = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(impairment)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum ImpairmentError {
+    #[snafu(display("invalid impairment setting '{}'", setting))]
+    InvalidSetting { setting: String },
+}
+
+// xorshift, good enough for simulating a network and reproducible from the seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // the state must not be zero
+        Self(seed.max(1))
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, chance: f64) -> bool {
+        self.next_f64() < chance
+    }
+
+    fn duration(&mut self, max: Duration) -> Duration {
+        max.mul_f64(self.next_f64())
+    }
+}
+
+impl Impairment {
+    fn delay(&self, rng: &mut Rng) -> Duration {
+        let latency = match self.latency {
+            LatencyDistribution::Constant(latency) => latency,
+            LatencyDistribution::Uniform { min, max } => {
+                min + rng.duration(max.checked_sub(min).unwrap_or_default())
+            }
+            LatencyDistribution::Normal { mean, std_dev } => {
+                // box-muller transform
+                let u1 = rng.next_f64().max(f64::MIN_POSITIVE);
+                let u2 = rng.next_f64();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                let millis = mean.as_secs_f64() * 1000.0 + z * std_dev.as_secs_f64() * 1000.0;
+                Duration::from_secs_f64(millis.max(0.0) / 1000.0)
+            }
+        };
+        let mut delay = latency + rng.duration(self.jitter);
+        if rng.chance(self.reorder) {
+            delay += self.reorder_delay;
+        }
+        delay
+    }
+}
+
+enum Delayed {
+    Incoming(Packet),
+    Outgoing(Packet),
+}
+
+struct Scheduled {
+    due: Instant,
+    // keeps packets due at the same time in the order they were scheduled
+    order: u64,
+    delayed: Delayed,
+}
+
+// ordered so that the binary heap pops the earliest due item first
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.due, other.order).cmp(&(self.due, self.order))
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+/// Wraps the transport, returning one that behaves like the original under the given impairment.
+/// Starts up a thread that delays the packets.
+pub fn impair<T: Transport>(transport: T, impairment: Impairment) -> ChannelTransport {
+    let (delayed_event_sender, delayed_event_receiver) = unbounded();
+    let (delayed_packet_sender, delayed_packet_receiver) = unbounded::<Packet>();
+    let seed = impairment.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or(1)
+    });
+    thread::spawn(move || {
+        let mut rng = Rng::new(seed);
+        let mut scheduled = BinaryHeap::<Scheduled>::new();
+        let mut order = 0;
+        let mut events = transport.events().clone();
+        let mut packets = delayed_packet_receiver;
+        let mut events_open = true;
+        let mut packets_open = true;
+        let mut schedule = |packet: Packet, incoming: bool, scheduled: &mut BinaryHeap<_>| {
+            if rng.chance(impairment.loss) {
+                return;
+            }
+            let copies = if rng.chance(impairment.duplicate) {
+                2
+            } else {
+                1
+            };
+            for _ in 0..copies {
+                let delayed = if incoming {
+                    Delayed::Incoming(packet.clone())
+                } else {
+                    Delayed::Outgoing(packet.clone())
+                };
+                order += 1;
+                scheduled.push(Scheduled {
+                    due: Instant::now() + impairment.delay(&mut rng),
+                    order,
+                    delayed,
+                });
+            }
+        };
+        loop {
+            let timeout = match scheduled.peek() {
+                Some(next) => next.due.saturating_duration_since(Instant::now()),
+                None => Duration::from_millis(100),
+            };
+            select! {
+                recv(events) -> event => match event {
+                    Ok(TransportEvent::Packet(packet)) => schedule(packet, true, &mut scheduled),
+                    Ok(event) => {
+                        if delayed_event_sender.send(event).is_err() {
+                            return;
+                        }
+                    }
+                    Err(_) => {
+                        events = never();
+                        events_open = false;
+                    }
+                },
+                recv(packets) -> packet => match packet {
+                    Ok(packet) => schedule(packet, false, &mut scheduled),
+                    Err(_) => {
+                        packets = never();
+                        packets_open = false;
+                    }
+                },
+                default(timeout) => {}
+            }
+            if !events_open && !packets_open && scheduled.is_empty() {
+                return;
+            }
+            while scheduled
+                .peek()
+                .is_some_and(|next| next.due <= Instant::now())
+            {
+                let sent = match scheduled.pop().expect("scheduled item disappeared").delayed {
+                    Delayed::Incoming(packet) => delayed_event_sender
+                        .send(TransportEvent::Packet(packet))
+                        .is_ok(),
+                    Delayed::Outgoing(packet) => transport.send(packet).is_ok(),
+                };
+                if !sent {
+                    return;
+                }
+            }
+        }
+    });
+    ChannelTransport::new(delayed_event_receiver, delayed_packet_sender)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crossbeam_channel::Receiver;
+
+    #[test]
+    fn parses_settings() {
+        let impairment: Impairment = "latency=20..80, jitter=5, loss=0.1, duplicate=0.01, seed=3"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            impairment.latency,
+            LatencyDistribution::Uniform {
+                min: Duration::from_millis(20),
+                max: Duration::from_millis(80),
+            }
+        );
+        assert_eq!(impairment.jitter, Duration::from_millis(5));
+        assert_eq!(impairment.seed, Some(3));
+        assert_eq!(
+            "latency=50~10".parse::<Impairment>().unwrap().latency,
+            LatencyDistribution::Normal {
+                mean: Duration::from_millis(50),
+                std_dev: Duration::from_millis(10),
+            }
+        );
+        assert!("loss=2".parse::<Impairment>().is_err());
+        assert!("bandwidth=1".parse::<Impairment>().is_err());
+    }
+
+    fn impaired(impairment: Impairment) -> (ChannelTransport, Receiver<Packet>) {
+        let (_event_sender, event_receiver) = unbounded();
+        let (packet_sender, packet_receiver) = unbounded();
+        let transport = ChannelTransport::new(event_receiver, packet_sender);
+        (impair(transport, impairment), packet_receiver)
+    }
+
+    #[test]
+    fn packets_are_lost_and_duplicated() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let timeout = Duration::from_millis(100);
+
+        let (lossy, sent) = impaired(Impairment {
+            loss: 1.0,
+            ..Impairment::default()
+        });
+        lossy.send(Packet::unreliable(addr, vec![1])).unwrap();
+        assert!(sent.recv_timeout(timeout).is_err());
+
+        let (duplicating, sent) = impaired(Impairment {
+            duplicate: 1.0,
+            ..Impairment::default()
+        });
+        duplicating.send(Packet::unreliable(addr, vec![1])).unwrap();
+        assert!(sent.recv_timeout(timeout).is_ok());
+        assert!(sent.recv_timeout(timeout).is_ok());
+    }
+
+    #[test]
+    fn packets_are_delayed() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let latency = Duration::from_millis(50);
+        let (delayed, sent) = impaired(Impairment {
+            latency: LatencyDistribution::Constant(latency),
+            ..Impairment::default()
+        });
+        let start = Instant::now();
+        delayed.send(Packet::unreliable(addr, vec![1])).unwrap();
+        sent.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(start.elapsed() >= latency);
+    }
+}
@@ -0,0 +1,301 @@
+//! An in-memory network for deterministic tests.
+//!
+//! Packets sent through a `MockTransport` are held by the `MockNetwork` until the test delivers them,
+//! either one by one in any order with `deliver`, or by advancing the network with `step`.
+//! Each link between two addresses can delay its packets by a number of steps or drop them entirely.
+//! Like laminar, an endpoint receives a connect event the first time a packet arrives from a new address.
+
+use super::{Packet, Transport, TransportError, TransportEvent};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// The conditions on the link from one address to another.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Link {
+    /// The amount of steps a packet stays in flight before `step` delivers it.
+    pub delay: u32,
+    /// Whether every packet sent over the link is lost.
+    pub drop: bool,
+}
+
+/// A packet that has been sent but not delivered yet.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct InFlight {
+    from: SocketAddr,
+    packet: Packet,
+    remaining: u32,
+}
+
+impl InFlight {
+    /// The address the packet was sent from.
+    pub fn from(&self) -> SocketAddr {
+        self.from
+    }
+
+    /// The packet, addressed to its recipient.
+    pub fn packet(&self) -> &Packet {
+        &self.packet
+    }
+}
+
+struct Endpoint {
+    events: Sender<TransportEvent>,
+    connected: HashSet<SocketAddr>,
+}
+
+#[derive(Default)]
+struct State {
+    endpoints: HashMap<SocketAddr, Endpoint>,
+    links: HashMap<(SocketAddr, SocketAddr), Link>,
+    in_flight: Vec<InFlight>,
+}
+
+impl State {
+    // packets to addresses without an endpoint are lost
+    fn deliver(&mut self, in_flight: InFlight) {
+        if let Some(endpoint) = self.endpoints.get_mut(&in_flight.packet.addr) {
+            if endpoint.connected.insert(in_flight.from) {
+                let _ = endpoint
+                    .events
+                    .send(TransportEvent::Connect(in_flight.from));
+            }
+            let packet = Packet {
+                addr: in_flight.from,
+                ..in_flight.packet
+            };
+            let _ = endpoint.events.send(TransportEvent::Packet(packet));
+        }
+    }
+}
+
+/// Connects the mock transports created from it.
+#[derive(Clone, Default)]
+pub struct MockNetwork {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a transport with the given address, replacing any previous one.
+    pub fn transport(&self, addr: SocketAddr) -> MockTransport {
+        let (events_sender, events) = unbounded();
+        let endpoint = Endpoint {
+            events: events_sender,
+            connected: HashSet::new(),
+        };
+        self.state().endpoints.insert(addr, endpoint);
+        MockTransport {
+            addr,
+            events,
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Sets the conditions for packets sent from `from` to `to`.
+    pub fn set_link(&self, from: SocketAddr, to: SocketAddr, link: Link) {
+        self.state().links.insert((from, to), link);
+    }
+
+    /// Returns the packets in flight in the order they were sent.
+    pub fn in_flight(&self) -> Vec<InFlight> {
+        self.state().in_flight.clone()
+    }
+
+    /// Delivers the packet at the given index of `in_flight` regardless of its delay.
+    /// Returns false if there is no such packet.
+    pub fn deliver(&self, index: usize) -> bool {
+        let mut state = self.state();
+        if index < state.in_flight.len() {
+            let in_flight = state.in_flight.remove(index);
+            state.deliver(in_flight);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drops the packet at the given index of `in_flight`.
+    pub fn drop_packet(&self, index: usize) -> Option<InFlight> {
+        let mut state = self.state();
+        if index < state.in_flight.len() {
+            Some(state.in_flight.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Delivers every packet in flight in the order they were sent, regardless of their delay.
+    /// Returns the amount of packets delivered.
+    pub fn deliver_all(&self) -> usize {
+        let mut state = self.state();
+        let in_flight = std::mem::take(&mut state.in_flight);
+        let delivered = in_flight.len();
+        for in_flight in in_flight {
+            state.deliver(in_flight);
+        }
+        delivered
+    }
+
+    /// Delivers the packets whose delay has passed in the order they were sent,
+    /// and brings the rest one step closer to delivery.
+    /// Returns the amount of packets delivered.
+    pub fn step(&self) -> usize {
+        let mut state = self.state();
+        let mut delivered = 0;
+        for mut in_flight in std::mem::take(&mut state.in_flight) {
+            if in_flight.remaining == 0 {
+                state.deliver(in_flight);
+                delivered += 1;
+            } else {
+                in_flight.remaining -= 1;
+                state.in_flight.push(in_flight);
+            }
+        }
+        delivered
+    }
+
+    /// Tells the endpoint at `at` that the connection to `from` has timed out.
+    pub fn timeout(&self, at: SocketAddr, from: SocketAddr) {
+        if let Some(endpoint) = self.state().endpoints.get_mut(&at) {
+            endpoint.connected.remove(&from);
+            let _ = endpoint.events.send(TransportEvent::Timeout(from));
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("mock network poisoned")
+    }
+}
+
+/// A transport on a `MockNetwork`.
+pub struct MockTransport {
+    addr: SocketAddr,
+    events: Receiver<TransportEvent>,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockTransport {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&self, packet: Packet) -> Result<(), TransportError> {
+        let mut state = self.state.lock().map_err(|_| TransportError::Closed)?;
+        let link = state
+            .links
+            .get(&(self.addr, packet.addr))
+            .copied()
+            .unwrap_or_default();
+        if !link.drop {
+            state.in_flight.push(InFlight {
+                from: self.addr,
+                packet,
+                remaining: link.delay,
+            });
+        }
+        Ok(())
+    }
+
+    fn events(&self) -> &Receiver<TransportEvent> {
+        &self.events
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addrs() -> (SocketAddr, SocketAddr) {
+        (
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        )
+    }
+
+    fn received(transport: &MockTransport) -> Vec<u8> {
+        transport
+            .events()
+            .try_iter()
+            .filter_map(|event| match event {
+                TransportEvent::Packet(packet) => Some(packet.payload()[0]),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packets_are_delivered_in_the_scripted_order() {
+        let (addr_1, addr_2) = addrs();
+        let network = MockNetwork::new();
+        let transport_1 = network.transport(addr_1);
+        let transport_2 = network.transport(addr_2);
+        for i in 0..3 {
+            transport_1
+                .send(Packet::unreliable(addr_2, vec![i]))
+                .unwrap();
+        }
+        assert!(received(&transport_2).is_empty());
+
+        assert!(network.deliver(2));
+        assert_eq!(network.drop_packet(0).unwrap().packet().payload(), &[0]);
+        assert_eq!(network.deliver_all(), 1);
+        let events: Vec<_> = transport_2.events().try_iter().collect();
+        assert_eq!(events[0], TransportEvent::Connect(addr_1));
+        assert_eq!(
+            events[1],
+            TransportEvent::Packet(Packet::unreliable(addr_1, vec![2]))
+        );
+        assert_eq!(
+            events[2],
+            TransportEvent::Packet(Packet::unreliable(addr_1, vec![1]))
+        );
+    }
+
+    #[test]
+    fn links_delay_and_drop_packets() {
+        let (addr_1, addr_2) = addrs();
+        let network = MockNetwork::new();
+        let transport_1 = network.transport(addr_1);
+        let transport_2 = network.transport(addr_2);
+        network.set_link(
+            addr_1,
+            addr_2,
+            Link {
+                delay: 2,
+                drop: false,
+            },
+        );
+        network.set_link(
+            addr_2,
+            addr_1,
+            Link {
+                delay: 0,
+                drop: true,
+            },
+        );
+        transport_1
+            .send(Packet::unreliable(addr_2, vec![1]))
+            .unwrap();
+        transport_2
+            .send(Packet::unreliable(addr_1, vec![2]))
+            .unwrap();
+        assert_eq!(network.in_flight().len(), 1);
+
+        assert_eq!(network.step(), 0);
+        assert_eq!(network.step(), 0);
+        assert_eq!(network.step(), 1);
+        assert_eq!(received(&transport_2), vec![1]);
+        assert!(received(&transport_1).is_empty());
+    }
+}
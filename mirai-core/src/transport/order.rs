@@ -0,0 +1,252 @@
+//! Orders the packets of each stream on top of laminar's reliable, unordered delivery.
+//!
+//! laminar keeps its ordering per connection, and only starts a connection once the receiving side
+//! has sent something back. The packets that arrive before that are delivered outside of the connection,
+//! so the ones after them wait for packets the connection will never see. Instead, every payload starts
+//! with a header that tells whether it is ordered, and ordered payloads also carry their stream,
+//! the sender's session and their index on the stream. Payloads that arrive early are held until
+//! the ones before them have arrived, and repeats are dropped.
+//! The session is random per endpoint, so a sender that restarts on the same address starts its
+//! streams over instead of waiting for indices it won't send again.
+//! A peer that has moved to a new address continues its streams there once `migrate` is called.
+
+use super::{Delivery, Packet, StreamId};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+
+/// The most payloads held per stream while waiting for an earlier one, the rest are dropped.
+pub const MAX_HELD: usize = 1024;
+
+const UNORDERED: u8 = 0;
+const ORDERED: u8 = 1;
+// kind
+const UNORDERED_HEADER: usize = 1;
+// kind, stream, session and index
+const ORDERED_HEADER: usize = 10;
+
+/// The payloads received on one of a peer's streams.
+struct Incoming {
+    session: u32,
+    expected: u32,
+    held: BTreeMap<u32, Vec<u8>>,
+}
+
+/// The ordering state of one endpoint.
+pub struct Ordering {
+    session: u32,
+    next: HashMap<(SocketAddr, StreamId), u32>,
+    incoming: HashMap<(SocketAddr, StreamId), Incoming>,
+}
+
+impl Default for Ordering {
+    fn default() -> Self {
+        Self::new(RandomState::new().build_hasher().finish() as u32)
+    }
+}
+
+impl Ordering {
+    /// `session` tells this endpoint's streams apart from those of an earlier endpoint on the same address.
+    pub fn new(session: u32) -> Self {
+        Self {
+            session,
+            next: HashMap::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    /// Adds the header to the packet's payload.
+    pub fn wrap(&mut self, packet: Packet) -> Packet {
+        let mut payload;
+        if let Delivery::ReliableOrdered(stream) = packet.delivery {
+            let next = self.next.entry((packet.addr, stream)).or_insert(0);
+            let index = *next;
+            *next = next.wrapping_add(1);
+            payload = Vec::with_capacity(ORDERED_HEADER + packet.payload.len());
+            payload.push(ORDERED);
+            payload.push(stream);
+            payload.extend_from_slice(&self.session.to_be_bytes());
+            payload.extend_from_slice(&index.to_be_bytes());
+        } else {
+            payload = Vec::with_capacity(UNORDERED_HEADER + packet.payload.len());
+            payload.push(UNORDERED);
+        }
+        payload.extend_from_slice(&packet.payload);
+        Packet { payload, ..packet }
+    }
+
+    /// Reads the header of a received packet.
+    /// Returns the packets that can be delivered in order, which are the received packet and any
+    /// held packets it was holding up, or none if it arrived early or was a repeat.
+    /// Malformed packets are discarded.
+    pub fn receive(&mut self, packet: Packet) -> Vec<Packet> {
+        let payload = &packet.payload;
+        match payload.first() {
+            Some(&UNORDERED) => vec![Packet {
+                payload: payload[UNORDERED_HEADER..].to_vec(),
+                ..packet
+            }],
+            Some(&ORDERED) if payload.len() >= ORDERED_HEADER => {
+                let read_u32 = |i: usize| {
+                    u32::from_be_bytes(<[u8; 4]>::try_from(&payload[i..i + 4]).unwrap_or_default())
+                };
+                let stream = payload[1];
+                let session = read_u32(2);
+                let index = read_u32(6);
+                let incoming = self
+                    .incoming
+                    .entry((packet.addr, stream))
+                    .or_insert_with(|| Incoming {
+                        session,
+                        expected: 0,
+                        held: BTreeMap::new(),
+                    });
+                if incoming.session != session {
+                    // the sender restarted
+                    *incoming = Incoming {
+                        session,
+                        expected: 0,
+                        held: BTreeMap::new(),
+                    };
+                }
+                let ahead = index.wrapping_sub(incoming.expected);
+                if ahead > u32::MAX / 2 {
+                    // already delivered
+                    return vec![];
+                }
+                if ahead > 0 {
+                    if incoming.held.len() < MAX_HELD {
+                        incoming
+                            .held
+                            .insert(index, payload[ORDERED_HEADER..].to_vec());
+                    }
+                    return vec![];
+                }
+                let delivery = Delivery::ReliableOrdered(stream);
+                let mut ready = vec![Packet {
+                    addr: packet.addr,
+                    payload: payload[ORDERED_HEADER..].to_vec(),
+                    delivery,
+                }];
+                incoming.expected = incoming.expected.wrapping_add(1);
+                while let Some(payload) = incoming.held.remove(&incoming.expected) {
+                    ready.push(Packet {
+                        addr: packet.addr,
+                        payload,
+                        delivery,
+                    });
+                    incoming.expected = incoming.expected.wrapping_add(1);
+                }
+                ready
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Continues the streams to and from the peer at its new address.
+    /// Anything already received from the new address is dropped, since the peer's streams
+    /// continue from where they were at the old one.
+    pub fn migrate(&mut self, from: SocketAddr, to: SocketAddr) {
+        self.forget(to);
+        move_streams(&mut self.next, from, to);
+        move_streams(&mut self.incoming, from, to);
+    }
+
+    /// Forgets the streams to and from the peer, e.g. once its connection has timed out.
+    pub fn forget(&mut self, addr: SocketAddr) {
+        self.next.retain(|(peer, _), _| *peer != addr);
+        self.incoming.retain(|(peer, _), _| *peer != addr);
+    }
+}
+
+// moves the streams of the peer to its new address
+fn move_streams<V>(map: &mut HashMap<(SocketAddr, StreamId), V>, from: SocketAddr, to: SocketAddr) {
+    let streams: Vec<_> = map
+        .keys()
+        .filter(|(peer, _)| *peer == from)
+        .map(|&(_, stream)| stream)
+        .collect();
+    for stream in streams {
+        if let Some(value) = map.remove(&(from, stream)) {
+            map.insert((to, stream), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delivers_each_stream_in_order_once() {
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let mut sender = Ordering::new(1);
+        let mut receiver = Ordering::new(2);
+        let sent: Vec<_> = (0..3)
+            .map(|i| sender.wrap(Packet::reliable_ordered(b, vec![i], 0)))
+            .map(|packet| Packet { addr: a, ..packet })
+            .collect();
+        let other = sender.wrap(Packet::reliable_ordered(b, vec![9], 1));
+        let unordered = sender.wrap(Packet::unreliable(b, vec![8]));
+
+        assert!(receiver.receive(sent[2].clone()).is_empty());
+        assert!(receiver.receive(sent[1].clone()).is_empty());
+        // other streams and unordered packets aren't held up
+        let other = receiver.receive(Packet { addr: a, ..other });
+        assert_eq!(other[0].payload(), &[9]);
+        assert_eq!(other[0].delivery(), Delivery::ReliableOrdered(1));
+        let unordered = receiver.receive(Packet {
+            addr: a,
+            ..unordered
+        });
+        assert_eq!(unordered, vec![Packet::unreliable(a, vec![8])]);
+
+        let ready = receiver.receive(sent[0].clone());
+        let payloads: Vec<_> = ready.iter().map(|packet| packet.payload()[0]).collect();
+        assert_eq!(payloads, vec![0, 1, 2]);
+        assert!(ready
+            .iter()
+            .all(|packet| packet.delivery() == Delivery::ReliableOrdered(0)));
+        assert!(receiver.receive(sent[1].clone()).is_empty());
+
+        // a restarted sender starts over
+        let mut restarted = Ordering::new(3);
+        let packet = restarted.wrap(Packet::reliable_ordered(b, vec![7], 0));
+        let ready = receiver.receive(Packet { addr: a, ..packet });
+        assert_eq!(ready.len(), 1);
+        assert!(receiver
+            .receive(Packet::unreliable(a, vec![ORDERED]))
+            .is_empty());
+    }
+
+    #[test]
+    fn streams_continue_at_the_new_address() {
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let moved: SocketAddr = "127.0.0.1:10".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let mut sender = Ordering::new(1);
+        let mut receiver = Ordering::new(2);
+        let mut send = |payload| {
+            let packet = sender.wrap(Packet::reliable_ordered(b, vec![payload], 0));
+            Packet {
+                addr: moved,
+                ..packet
+            }
+        };
+        let first = Packet { addr: a, ..send(0) };
+        assert_eq!(receiver.receive(first).len(), 1);
+        let second = send(1);
+
+        receiver.migrate(a, moved);
+        let ready = receiver.receive(second);
+        assert_eq!(ready, vec![Packet::reliable_ordered(moved, vec![1], 0)]);
+        // and the stream to the peer continues as well
+        receiver.wrap(Packet::reliable_ordered(a, vec![], 0));
+        receiver.migrate(a, moved);
+        let packet = receiver.wrap(Packet::reliable_ordered(moved, vec![], 0));
+        assert_eq!(&packet.payload[6..10], &1u32.to_be_bytes());
+    }
+}
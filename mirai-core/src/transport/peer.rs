@@ -0,0 +1,211 @@
+//! The transport handed from the matchmaking client to the game once a match is confirmed.
+//!
+//! When the matchmaking client is closed, packets from the matchmaking server and the other queued peers
+//! may still be in flight. `PeerConnection` drops the events from those addresses, so the game only sees
+//! the traffic of the peers it was matched with. Events from addresses the matchmaking client didn't know
+//! are let through, since a peer that reconnects from a new address can only be recognized by the game.
+//!
+//! The connection is a `Transport` the game client can take over directly, and it can also send and receive
+//! serializable messages with `send` and `recv`. Both receive from the same events, so one connection
+//! should only be used in one of these ways.
+
+use super::{Delivery, Packet, SendHealth, Transport, TransportError, TransportEvent};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+/// Something that happened on a peer connection, with the received messages deserialized.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PeerEvent<M> {
+    Message { from: SocketAddr, message: M },
+    Connect(SocketAddr),
+    Timeout(SocketAddr),
+    Health(SendHealth),
+}
+
+/// A transport scoped to the peers of a confirmed match.
+pub struct PeerConnection<T: Transport> {
+    transport: T,
+    peers: Vec<SocketAddr>,
+    events: Receiver<TransportEvent>,
+}
+
+impl<T: Transport> PeerConnection<T> {
+    /// Starts up a thread that drops the events from the `others`, e.g. the matchmaking server
+    /// and the peers that weren't matched.
+    pub fn new(transport: T, peers: Vec<SocketAddr>, others: HashSet<SocketAddr>) -> Self {
+        let (event_sender, events) = unbounded();
+        let transport_events = transport.events().clone();
+        thread::spawn(move || {
+            for event in transport_events {
+                let from = match &event {
+                    TransportEvent::Packet(packet) => Some(packet.addr()),
+                    TransportEvent::Connect(addr) | TransportEvent::Timeout(addr) => Some(*addr),
+                    TransportEvent::Health(_) => None,
+                };
+                if from.is_some_and(|from| others.contains(&from)) {
+                    continue;
+                }
+                if event_sender.send(event).is_err() {
+                    // the connection was dropped
+                    return;
+                }
+            }
+        });
+        Self {
+            transport,
+            peers,
+            events,
+        }
+    }
+
+    /// The addresses of the matched peers, as they were when the match was confirmed.
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    /// Serializes the message and sends it to the peer.
+    /// # Errors
+    /// If the message can't be serialized or the transport can't send it.
+    pub fn send<M: Serialize>(
+        &self,
+        to: SocketAddr,
+        message: &M,
+        delivery: Delivery,
+    ) -> Result<(), PeerConnectionError> {
+        let payload = bincode::serialize(message).context(SerializeError)?;
+        self.transport
+            .send(Packet::new(to, payload, delivery))
+            .context(SendError)
+    }
+
+    /// Waits up to `timeout` for the next event, returning `None` if there wasn't one.
+    /// # Errors
+    /// If the transport has been closed or a peer sent a message that can't be deserialized.
+    pub fn recv<M: DeserializeOwned>(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<PeerEvent<M>>, PeerConnectionError> {
+        let event = match self.events.recv_timeout(timeout) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => return Ok(None),
+            Err(RecvTimeoutError::Disconnected) => return Err(PeerConnectionError::Closed),
+        };
+        let event = match event {
+            TransportEvent::Packet(packet) => {
+                let from = packet.addr();
+                let message =
+                    bincode::deserialize(packet.payload()).context(DeserializeError { from })?;
+                PeerEvent::Message { from, message }
+            }
+            TransportEvent::Connect(addr) => PeerEvent::Connect(addr),
+            TransportEvent::Timeout(addr) => PeerEvent::Timeout(addr),
+            TransportEvent::Health(health) => PeerEvent::Health(health),
+        };
+        Ok(Some(event))
+    }
+}
+
+impl<T: Transport> Transport for PeerConnection<T> {
+    fn send(&self, packet: Packet) -> Result<(), TransportError> {
+        self.transport.send(packet)
+    }
+
+    fn events(&self) -> &Receiver<TransportEvent> {
+        &self.events
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    fn migrate(&self, from: SocketAddr, to: SocketAddr) {
+        self.transport.migrate(from, to)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum PeerConnectionError {
+    #[snafu(display("the connection has been closed"))]
+    Closed,
+    #[snafu(display("could not serialize the message: {}", source))]
+    SerializeError { source: bincode::Error },
+    #[snafu(display("invalid message from {}: {}", from, source))]
+    DeserializeError {
+        from: SocketAddr,
+        source: bincode::Error,
+    },
+    #[snafu(display("could not send the message: {}", source))]
+    SendError { source: TransportError },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::MockNetwork;
+
+    fn next_message(connection: &PeerConnection<impl Transport>) -> Option<(SocketAddr, String)> {
+        loop {
+            match connection.recv(Duration::from_millis(100)).unwrap()? {
+                PeerEvent::Message { from, message } => return Some((from, message)),
+                _ => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_matched_peers_are_heard() {
+        let network = MockNetwork::new();
+        let server = network.transport("127.0.0.1:1".parse().unwrap());
+        let peer = network.transport("127.0.0.1:2".parse().unwrap());
+        let unmatched = network.transport("127.0.0.1:3".parse().unwrap());
+        let reconnected = network.transport("127.0.0.1:4".parse().unwrap());
+        let client = network.transport("127.0.0.1:5".parse().unwrap());
+        let client_addr = client.addr();
+        let others = vec![server.addr(), unmatched.addr()].into_iter().collect();
+        let connection = PeerConnection::new(client, vec![peer.addr()], others);
+        assert_eq!(connection.peers(), [peer.addr()]);
+
+        let hello = |from: &dyn Transport, text: &str| {
+            let payload = bincode::serialize(&text.to_string()).unwrap();
+            from.send(Packet::reliable_unordered(client_addr, payload))
+                .unwrap();
+        };
+        hello(&server, "queue status");
+        hello(&unmatched, "challenge");
+        hello(&peer, "hello");
+        hello(&reconnected, "hello again");
+        network.deliver_all();
+        assert_eq!(
+            next_message(&connection),
+            Some((peer.addr(), "hello".to_string()))
+        );
+        assert_eq!(
+            next_message(&connection),
+            Some((reconnected.addr(), "hello again".to_string()))
+        );
+        assert_eq!(next_message(&connection), None);
+
+        connection
+            .send(
+                peer.addr(),
+                &"reply".to_string(),
+                Delivery::ReliableUnordered,
+            )
+            .unwrap();
+        network.deliver_all();
+        let reply = loop {
+            match peer.events().recv_timeout(Duration::from_secs(1)).unwrap() {
+                TransportEvent::Packet(packet) => break packet,
+                _ => continue,
+            }
+        };
+        let reply: String = bincode::deserialize(reply.payload()).unwrap();
+        assert_eq!(reply, "reply");
+    }
+}
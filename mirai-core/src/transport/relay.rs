@@ -0,0 +1,141 @@
+//! Sends the packets to some peers through the matchmaking server's relay.
+//!
+//! When two matched clients can't reach each other directly, both ask the server to relay their
+//! packets. `RelayTransport` then wraps the packets to those peers in `Relay` messages to the server
+//! and unwraps the `Relayed` messages it receives, so the game client sees them as regular packets
+//! from the peer. Packets to other addresses are sent directly.
+
+use super::{Packet, Transport, TransportError, TransportEvent};
+use crate::v1::{client::FromServer, client::ToServer};
+use crossbeam_channel::{unbounded, Receiver};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::thread;
+
+/// Relays the packets to the given peers through the server.
+pub struct RelayTransport<T: Transport> {
+    transport: T,
+    server_addr: SocketAddr,
+    relayed: HashSet<SocketAddr>,
+    events: Receiver<TransportEvent>,
+}
+
+impl<T: Transport> RelayTransport<T> {
+    /// Starts up a thread that unwraps the packets relayed by the server.
+    pub fn new(transport: T, server_addr: SocketAddr, relayed: HashSet<SocketAddr>) -> Self {
+        let (event_sender, events) = unbounded();
+        let transport_events = transport.events().clone();
+        thread::spawn(move || {
+            for event in transport_events {
+                let event = match event {
+                    TransportEvent::Packet(packet) if packet.addr() == server_addr => {
+                        match bincode::deserialize(packet.payload()) {
+                            Ok(FromServer::Relayed { from, payload }) => TransportEvent::Packet(
+                                Packet::new(from, payload, packet.delivery()),
+                            ),
+                            _ => TransportEvent::Packet(packet),
+                        }
+                    }
+                    event => event,
+                };
+                if event_sender.send(event).is_err() {
+                    // the transport was dropped
+                    return;
+                }
+            }
+        });
+        Self {
+            transport,
+            server_addr,
+            relayed,
+            events,
+        }
+    }
+}
+
+impl<T: Transport> Transport for RelayTransport<T> {
+    fn send(&self, packet: Packet) -> Result<(), TransportError> {
+        if !self.relayed.contains(&packet.addr()) {
+            return self.transport.send(packet);
+        }
+        let delivery = packet.delivery();
+        let msg = ToServer::Relay {
+            to: packet.addr(),
+            payload: packet.payload,
+        };
+        let payload = bincode::serialize(&msg).expect("failed to serialize relayed packet");
+        self.transport
+            .send(Packet::new(self.server_addr, payload, delivery))
+    }
+
+    fn events(&self) -> &Receiver<TransportEvent> {
+        &self.events
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    fn migrate(&self, from: SocketAddr, to: SocketAddr) {
+        self.transport.migrate(from, to)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::{Delivery, MockNetwork};
+    use std::time::Duration;
+
+    fn next_packet(events: &Receiver<TransportEvent>) -> Packet {
+        loop {
+            match events.recv_timeout(Duration::from_secs(1)).unwrap() {
+                TransportEvent::Packet(packet) => return packet,
+                _ => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn wraps_packets_to_relayed_peers() {
+        let network = MockNetwork::new();
+        let server = network.transport("127.0.0.1:1".parse().unwrap());
+        let client = network.transport("127.0.0.1:2".parse().unwrap());
+        let direct = network.transport("127.0.0.1:3".parse().unwrap());
+        let peer: SocketAddr = "10.0.0.1:4".parse().unwrap();
+        let relay = RelayTransport::new(client, server.addr(), vec![peer].into_iter().collect());
+
+        relay
+            .send(Packet::reliable_ordered(peer, vec![1, 2], 3))
+            .unwrap();
+        relay
+            .send(Packet::unreliable(direct.addr(), vec![3]))
+            .unwrap();
+        network.deliver_all();
+        let packet = next_packet(server.events());
+        assert_eq!(packet.delivery(), Delivery::ReliableOrdered(3));
+        let msg: ToServer = bincode::deserialize(packet.payload()).unwrap();
+        assert_eq!(
+            msg,
+            ToServer::Relay {
+                to: peer,
+                payload: vec![1, 2]
+            }
+        );
+        assert_eq!(next_packet(direct.events()).payload(), [3]);
+
+        let relayed = FromServer::Relayed {
+            from: peer,
+            payload: vec![4],
+        };
+        let relayed = bincode::serialize(&relayed).unwrap();
+        server
+            .send(Packet::unreliable(relay.local_addr().unwrap(), relayed))
+            .unwrap();
+        network.deliver_all();
+        assert_eq!(
+            next_packet(relay.events()),
+            Packet::unreliable(peer, vec![4])
+        );
+    }
+}
@@ -0,0 +1,369 @@
+//! Encrypts the traffic between the clients and the server.
+//!
+//! A client performs the Noise handshake of `crypto::noise` with the server the first time it
+//! sends it a packet. The packets to the server that are sent before the handshake has completed
+//! are held back until it has. Packets to other addresses, i.e. to peers, are sent as they are.
+//! The server drops the packets of clients that haven't completed a handshake.
+//! Encrypted packets carry their nonce, and a packet whose nonce has already been received
+//! or is more than `REPLAY_WINDOW` behind the newest one is dropped as a replay.
+//! A session ends when the connection times out, and the client handshakes again when it next
+//! sends something to the server.
+
+use super::{Packet, Transport, TransportError, TransportEvent};
+use crate::crypto::noise::{self, Cipher, Initiator};
+use crate::crypto::{Keypair, PublicKey};
+use crate::logging::{debug, warn};
+use crate::v1::streams;
+use crossbeam_channel::{unbounded, Receiver};
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How far behind the newest received nonce a packet may be before it is treated as a replay.
+pub const REPLAY_WINDOW: u64 = 1024;
+
+const HANDSHAKE: u8 = 0;
+const HANDSHAKE_RESPONSE: u8 = 1;
+const DATA: u8 = 2;
+// kind and nonce
+const DATA_HEADER: usize = 9;
+
+#[derive(Default)]
+struct ReplayWindow {
+    newest: Option<u64>,
+    received: BTreeSet<u64>,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, nonce: u64) -> bool {
+        let too_old = self
+            .newest
+            .is_some_and(|newest| nonce.saturating_add(REPLAY_WINDOW) <= newest);
+        !too_old && !self.received.contains(&nonce)
+    }
+
+    fn mark(&mut self, nonce: u64) {
+        self.received.insert(nonce);
+        if self.newest.is_none_or(|newest| nonce > newest) {
+            self.newest = Some(nonce);
+            self.received = self
+                .received
+                .split_off(&nonce.saturating_sub(REPLAY_WINDOW));
+        }
+    }
+}
+
+struct Session {
+    cipher: Cipher,
+    replays: ReplayWindow,
+}
+
+impl Session {
+    fn new(cipher: Cipher) -> Self {
+        Self {
+            cipher,
+            replays: ReplayWindow::default(),
+        }
+    }
+
+    fn encrypt(&mut self, packet: Packet) -> Result<Packet, TransportError> {
+        let (nonce, ciphertext) = self
+            .cipher
+            .encrypt(&packet.payload)
+            .ok_or(TransportError::Closed)?;
+        let mut payload = Vec::with_capacity(DATA_HEADER + ciphertext.len());
+        payload.push(DATA);
+        payload.extend_from_slice(&nonce.to_be_bytes());
+        payload.extend(ciphertext);
+        Ok(Packet { payload, ..packet })
+    }
+
+    fn decrypt(&mut self, packet: Packet) -> Option<Packet> {
+        let nonce =
+            u64::from_be_bytes(<[u8; 8]>::try_from(packet.payload.get(1..DATA_HEADER)?).ok()?);
+        if !self.replays.is_fresh(nonce) {
+            return None;
+        }
+        let payload = self.cipher.decrypt(nonce, &packet.payload[DATA_HEADER..])?;
+        self.replays.mark(nonce);
+        Some(Packet { payload, ..packet })
+    }
+}
+
+enum Role {
+    Client {
+        server_addr: SocketAddr,
+        server_public: PublicKey,
+    },
+    Server {
+        keypair: Keypair,
+    },
+}
+
+#[derive(Default)]
+struct State {
+    sessions: HashMap<SocketAddr, Session>,
+    // the client's handshake in progress and the packets waiting for it
+    handshake: Option<Initiator>,
+    held: Vec<Packet>,
+}
+
+/// Encrypts the packets between the clients and the server.
+pub struct SecureTransport<T: Transport> {
+    transport: Arc<T>,
+    role: Arc<Role>,
+    state: Arc<Mutex<State>>,
+    events: Receiver<TransportEvent>,
+}
+
+impl<T: Transport> SecureTransport<T> {
+    /// Encrypts the packets to the server, which is authenticated by its public key.
+    /// Starts up a thread that decrypts the incoming packets.
+    pub fn client(transport: T, server_addr: SocketAddr, server_public: PublicKey) -> Self {
+        Self::new(
+            transport,
+            Role::Client {
+                server_addr,
+                server_public,
+            },
+        )
+    }
+
+    /// Encrypts the packets to every client with the server's static keypair.
+    /// Starts up a thread that answers handshakes and decrypts the incoming packets.
+    pub fn server(transport: T, keypair: Keypair) -> Self {
+        Self::new(transport, Role::Server { keypair })
+    }
+
+    fn new(transport: T, role: Role) -> Self {
+        let transport = Arc::new(transport);
+        let role = Arc::new(role);
+        let state = Arc::new(Mutex::new(State::default()));
+        let (event_sender, events) = unbounded();
+        let transport_events = transport.events().clone();
+        let thread_transport = Arc::clone(&transport);
+        let thread_role = Arc::clone(&role);
+        let thread_state = Arc::clone(&state);
+        thread::spawn(move || {
+            for event in transport_events {
+                let mut state = match thread_state.lock() {
+                    Ok(state) => state,
+                    Err(_) => return,
+                };
+                let event = match event {
+                    TransportEvent::Packet(packet) => {
+                        match receive(&*thread_transport, &thread_role, &mut state, packet) {
+                            Some(packet) => TransportEvent::Packet(packet),
+                            None => continue,
+                        }
+                    }
+                    TransportEvent::Timeout(addr) => {
+                        state.sessions.remove(&addr);
+                        if let Role::Client { server_addr, .. } = *thread_role {
+                            if addr == server_addr {
+                                state.handshake = None;
+                                state.held.clear();
+                            }
+                        }
+                        TransportEvent::Timeout(addr)
+                    }
+                    event => event,
+                };
+                drop(state);
+                if event_sender.send(event).is_err() {
+                    // the transport was dropped
+                    return;
+                }
+            }
+        });
+        Self {
+            transport,
+            role,
+            state,
+            events,
+        }
+    }
+}
+
+// handles a received packet, returning it decrypted if it's meant for the user of the transport
+fn receive(
+    transport: &impl Transport,
+    role: &Role,
+    state: &mut State,
+    packet: Packet,
+) -> Option<Packet> {
+    let addr = packet.addr;
+    match role {
+        Role::Client { server_addr, .. } if addr != *server_addr => return Some(packet),
+        _ => {}
+    }
+    match (packet.payload.first()?, role) {
+        (&DATA, _) => state.sessions.get_mut(&addr)?.decrypt(packet),
+        (&HANDSHAKE, Role::Server { keypair }) => {
+            let (response, cipher) = noise::respond(keypair, &packet.payload[1..])?;
+            debug!("completed a handshake with {}", addr);
+            state.sessions.insert(addr, Session::new(cipher));
+            let mut payload = vec![HANDSHAKE_RESPONSE];
+            payload.extend(response);
+            let response = Packet::reliable_ordered(addr, payload, streams::CONTROL);
+            if let Err(e) = transport.send(response) {
+                warn!("could not answer a handshake: {}", e);
+            }
+            None
+        }
+        (&HANDSHAKE_RESPONSE, Role::Client { .. }) => {
+            let cipher = state.handshake.take()?.finish(&packet.payload[1..])?;
+            debug!("completed a handshake with the server");
+            let mut session = Session::new(cipher);
+            for held in state.held.drain(..) {
+                let sent = session
+                    .encrypt(held)
+                    .and_then(|packet| transport.send(packet));
+                if let Err(e) = sent {
+                    warn!("could not send a held packet: {}", e);
+                }
+            }
+            state.sessions.insert(addr, session);
+            None
+        }
+        _ => None,
+    }
+}
+
+impl<T: Transport> Transport for SecureTransport<T> {
+    fn send(&self, packet: Packet) -> Result<(), TransportError> {
+        let mut state = self.state.lock().map_err(|_| TransportError::Closed)?;
+        if let Some(session) = state.sessions.get_mut(&packet.addr) {
+            let packet = session.encrypt(packet)?;
+            return self.transport.send(packet);
+        }
+        match &*self.role {
+            Role::Client {
+                server_addr,
+                server_public,
+            } if packet.addr == *server_addr => {
+                state.held.push(packet);
+                if state.handshake.is_none() {
+                    let (handshake, message) = Initiator::new(server_public)
+                        .map_err(|source| TransportError::Handshake { source })?;
+                    state.handshake = Some(handshake);
+                    let mut payload = vec![HANDSHAKE];
+                    payload.extend(message);
+                    let packet = Packet::reliable_ordered(*server_addr, payload, streams::CONTROL);
+                    self.transport.send(packet)?;
+                }
+                Ok(())
+            }
+            Role::Client { .. } => self.transport.send(packet),
+            // clients without a session can't read the packet, so it's lost
+            Role::Server { .. } => Ok(()),
+        }
+    }
+
+    fn events(&self) -> &Receiver<TransportEvent> {
+        &self.events
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    fn migrate(&self, from: SocketAddr, to: SocketAddr) {
+        if let Ok(mut state) = self.state.lock() {
+            if let Some(session) = state.sessions.remove(&from) {
+                state.sessions.insert(to, session);
+            }
+        }
+        self.transport.migrate(from, to);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::MockNetwork;
+    use std::time::{Duration, Instant};
+
+    fn next_packet(network: &MockNetwork, events: &Receiver<TransportEvent>) -> Packet {
+        let start = Instant::now();
+        loop {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            network.deliver_all();
+            if let Ok(TransportEvent::Packet(packet)) = events.try_recv() {
+                return packet;
+            }
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn encrypts_the_traffic_with_the_server() {
+        let network = MockNetwork::new();
+        let server_addr = "127.0.0.1:1".parse().unwrap();
+        let client_addr = "127.0.0.1:2".parse().unwrap();
+        let keypair = Keypair::generate().unwrap();
+        let public = keypair.public();
+        let server = SecureTransport::server(network.transport(server_addr), keypair);
+        let client = SecureTransport::client(network.transport(client_addr), server_addr, public);
+        let observer = network.transport("127.0.0.1:3".parse().unwrap());
+
+        let queue = b"queue".to_vec();
+        client
+            .send(Packet::reliable_ordered(server_addr, queue.clone(), 0))
+            .unwrap();
+        let received = next_packet(&network, server.events());
+        assert_eq!(
+            received,
+            Packet::reliable_ordered(client_addr, queue.clone(), 0)
+        );
+        server
+            .send(Packet::unreliable(client_addr, b"peers".to_vec()))
+            .unwrap();
+        assert_eq!(next_packet(&network, client.events()).payload(), b"peers");
+
+        // on the wire the payload is encrypted
+        client
+            .send(Packet::unreliable(server_addr, queue.clone()))
+            .unwrap();
+        let wire = network.in_flight();
+        assert_eq!(wire.len(), 1);
+        let payload = wire[0].packet().payload();
+        assert_eq!(payload[0], DATA);
+        assert!(!payload
+            .windows(queue.len())
+            .any(|window| window == &queue[..]));
+        assert_eq!(next_packet(&network, server.events()).payload(), &queue[..]);
+
+        // unencrypted packets are dropped by the server but sent to peers as they are
+        observer
+            .send(Packet::unreliable(server_addr, queue.clone()))
+            .unwrap();
+        client
+            .send(Packet::unreliable(observer.addr(), queue.clone()))
+            .unwrap();
+        assert_eq!(
+            next_packet(&network, observer.events()).payload(),
+            &queue[..]
+        );
+        client
+            .send(Packet::unreliable(server_addr, b"dequeue".to_vec()))
+            .unwrap();
+        assert_eq!(next_packet(&network, server.events()).payload(), b"dequeue");
+    }
+
+    #[test]
+    fn replays_are_dropped() {
+        let mut replays = ReplayWindow::default();
+        for &nonce in &[0, 5, 3, REPLAY_WINDOW + 4] {
+            assert!(replays.is_fresh(nonce));
+            replays.mark(nonce);
+        }
+        assert!(!replays.is_fresh(3));
+        assert!(!replays.is_fresh(REPLAY_WINDOW + 4));
+        // too far behind the newest one to be remembered
+        assert!(!replays.is_fresh(4));
+        assert!(replays.is_fresh(6));
+    }
+}
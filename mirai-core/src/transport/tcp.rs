@@ -0,0 +1,272 @@
+//! A transport over TCP for networks that block UDP.
+//!
+//! Each packet is sent as a frame: its length as a big-endian `u32` followed by the payload.
+//! A connection is opened the first time a packet is sent to an address, and every connection
+//! has its own writer thread so sending never blocks the caller.
+//! Packets to addresses that cannot be reached are dropped like lost UDP packets.
+//! A closed connection is reported as a timeout.
+
+use super::{Packet, Transport, TransportError, TransportEvent};
+use crate::logging::debug;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const CONNECT_TIMEOUT_MILLIS: u64 = 5000;
+/// Frames larger than this are treated as a protocol error and close the connection.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+type Connections = Arc<Mutex<HashMap<SocketAddr, Sender<Vec<u8>>>>>;
+
+/// Sends packets over TCP connections.
+pub struct TcpTransport {
+    local_addr: Option<SocketAddr>,
+    events: Receiver<TransportEvent>,
+    event_sender: Sender<TransportEvent>,
+    connections: Connections,
+}
+
+impl TcpTransport {
+    /// Creates a transport that only opens outgoing connections.
+    pub fn new() -> Self {
+        let (event_sender, events) = unbounded();
+        Self {
+            local_addr: None,
+            events,
+            event_sender,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Creates a transport that also accepts connections on the given address.
+    /// Starts up a thread that accepts incoming connections.
+    /// # Errors
+    /// If binding the listener fails.
+    pub fn listen(addr: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let mut transport = Self::new();
+        transport.local_addr = Some(listener.local_addr()?);
+        let event_sender = transport.event_sender.clone();
+        let connections = Arc::clone(&transport.connections);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!("failed to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                let addr = match stream.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(_) => continue,
+                };
+                let (frame_sender, frame_receiver) = unbounded();
+                connections
+                    .lock()
+                    .expect("connections poisoned")
+                    .insert(addr, frame_sender);
+                let event_sender = event_sender.clone();
+                let connections = Arc::clone(&connections);
+                thread::spawn(move || {
+                    run_connection(addr, stream, frame_receiver, event_sender, connections)
+                });
+            }
+        });
+        Ok(transport)
+    }
+
+    /// Returns the address connections are accepted on, if any.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+}
+
+impl Default for TcpTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, packet: Packet) -> Result<(), TransportError> {
+        let addr = packet.addr();
+        let mut connections = self
+            .connections
+            .lock()
+            .map_err(|_| TransportError::Closed)?;
+        let frame_sender = connections.entry(addr).or_insert_with(|| {
+            let (frame_sender, frame_receiver) = unbounded();
+            let event_sender = self.event_sender.clone();
+            let connections = Arc::clone(&self.connections);
+            thread::spawn(move || {
+                let timeout = Duration::from_millis(CONNECT_TIMEOUT_MILLIS);
+                match TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(stream) => {
+                        run_connection(addr, stream, frame_receiver, event_sender, connections)
+                    }
+                    Err(e) => {
+                        debug!("failed to connect to {}: {}", addr, e);
+                        connections
+                            .lock()
+                            .expect("connections poisoned")
+                            .remove(&addr);
+                    }
+                }
+            });
+            frame_sender
+        });
+        // the connection may have just closed, in which case the packet is lost
+        let _ = frame_sender.send(packet.payload().to_vec());
+        Ok(())
+    }
+
+    fn events(&self) -> &Receiver<TransportEvent> {
+        &self.events
+    }
+}
+
+// writes the queued frames to the stream while a reader thread forwards the incoming ones
+fn run_connection(
+    addr: SocketAddr,
+    stream: TcpStream,
+    frame_receiver: Receiver<Vec<u8>>,
+    event_sender: Sender<TransportEvent>,
+    connections: Connections,
+) {
+    let _ = stream.set_nodelay(true);
+    let _ = event_sender.send(TransportEvent::Connect(addr));
+    if let Ok(reader) = stream.try_clone() {
+        let event_sender = event_sender.clone();
+        let connections = Arc::clone(&connections);
+        thread::spawn(move || {
+            read_frames(addr, reader, &event_sender);
+            connections
+                .lock()
+                .expect("connections poisoned")
+                .remove(&addr);
+            let _ = event_sender.send(TransportEvent::Timeout(addr));
+        });
+    }
+    let mut writer = stream;
+    for frame in frame_receiver {
+        if let Err(e) = write_frame(&mut writer, &frame) {
+            debug!("failed to write to {}: {}", addr, e);
+            break;
+        }
+    }
+    // wakes up the reader so the connection is cleaned up
+    let _ = writer.shutdown(std::net::Shutdown::Both);
+}
+
+fn read_frames(addr: SocketAddr, mut reader: TcpStream, event_sender: &Sender<TransportEvent>) {
+    loop {
+        match read_frame(&mut reader) {
+            Ok(payload) => {
+                let packet = Packet::reliable_unordered(addr, payload);
+                if event_sender.send(TransportEvent::Packet(packet)).is_err() {
+                    // the transport was dropped
+                    return;
+                }
+            }
+            Err(e) => {
+                debug!("connection to {} closed: {}", addr, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Writes the payload as a single frame.
+/// # Errors
+/// If the payload is larger than `MAX_FRAME_SIZE` or writing fails.
+pub fn write_frame(writer: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "frame too large",
+        ));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// Reads a single frame and returns its payload.
+/// # Errors
+/// If the frame is larger than `MAX_FRAME_SIZE` or reading fails.
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frames_round_trip() {
+        let mut buffer = vec![];
+        write_frame(&mut buffer, &[1, 2, 3]).unwrap();
+        write_frame(&mut buffer, &[]).unwrap();
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(read_frame(&mut reader).unwrap(), vec![1, 2, 3]);
+        assert_eq!(read_frame(&mut reader).unwrap(), Vec::<u8>::new());
+        assert!(read_frame(&mut reader).is_err());
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let len = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        assert!(read_frame(&mut Cursor::new(len.to_vec())).is_err());
+        assert!(write_frame(&mut vec![], &vec![0; MAX_FRAME_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn packets_are_sent_both_ways() {
+        let server = TcpTransport::listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = TcpTransport::new();
+        let timeout = Duration::from_secs(5);
+
+        client
+            .send(Packet::unreliable(server_addr, vec![1]))
+            .unwrap();
+        let client_addr = loop {
+            match server.events().recv_timeout(timeout).unwrap() {
+                TransportEvent::Packet(packet) => {
+                    assert_eq!(packet.payload(), &[1]);
+                    break packet.addr();
+                }
+                TransportEvent::Connect(_) | TransportEvent::Health(_) => {}
+                TransportEvent::Timeout(addr) => panic!("{} timed out", addr),
+            }
+        };
+
+        server
+            .send(Packet::unreliable(client_addr, vec![2]))
+            .unwrap();
+        loop {
+            if let TransportEvent::Packet(packet) = client.events().recv_timeout(timeout).unwrap() {
+                assert_eq!(packet.addr(), server_addr);
+                assert_eq!(packet.payload(), &[2]);
+                break;
+            }
+        }
+    }
+}
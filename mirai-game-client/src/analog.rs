@@ -0,0 +1,166 @@
+//! Quantized analog inputs.
+//!
+//! Raw analog readings jitter from frame to frame, which would make every prediction of a remote
+//! input wrong. Quantizing sticks and triggers to a limited amount of steps before they are used
+//! keeps the inputs stable, compact on the wire and exactly comparable on every peer.
+
+use serde::{Deserialize, Serialize};
+
+/// The resolution of a quantized value.
+const MAX_STEPS: u8 = 127;
+
+/// Decides how analog readings are converted into quantized values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantization {
+    steps: u8,
+    deadzone: f32,
+}
+
+impl Quantization {
+    /// Quantizes values to `steps` evenly spaced steps in each direction,
+    /// treating readings closer to zero than `deadzone` as zero.
+    /// # Panics
+    /// If `steps` is zero or larger than 127, or `deadzone` is not in the range 0..1.
+    pub fn new(steps: u8, deadzone: f32) -> Self {
+        assert!(
+            steps > 0 && steps <= MAX_STEPS,
+            "steps must be in the range 1..=127"
+        );
+        assert!(
+            (0.0..1.0).contains(&deadzone),
+            "deadzone must be in the range 0..1"
+        );
+        Self { steps, deadzone }
+    }
+
+    /// Quantizes a stick axis reading in the range -1..=1.
+    pub fn axis(&self, value: f32) -> Axis {
+        let magnitude = self.quantize(value.abs());
+        Axis(if value < 0.0 { -magnitude } else { magnitude })
+    }
+
+    /// Quantizes a trigger reading in the range 0..=1.
+    pub fn trigger(&self, value: f32) -> Trigger {
+        Trigger(self.quantize(value) as u8)
+    }
+
+    // maps 0..=1 to 0..=MAX_STEPS, snapped to the configured steps
+    fn quantize(&self, value: f32) -> i8 {
+        if value.is_nan() || value < self.deadzone {
+            return 0;
+        }
+        let value = value.min(1.0);
+        let step = (value * f32::from(self.steps)).round();
+        (step / f32::from(self.steps) * f32::from(MAX_STEPS)).round() as i8
+    }
+}
+
+impl Default for Quantization {
+    /// 32 steps in each direction with a deadzone of 0.1.
+    fn default() -> Self {
+        Self::new(32, 0.1)
+    }
+}
+
+/// A quantized stick axis.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Axis(pub i8);
+
+impl Axis {
+    /// Returns the axis as a value in the range -1..=1.
+    pub fn value(self) -> f32 {
+        f32::from(self.0) / f32::from(MAX_STEPS)
+    }
+}
+
+/// A quantized trigger.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Trigger(pub u8);
+
+impl Trigger {
+    /// Returns the trigger as a value in the range 0..=1.
+    pub fn value(self) -> f32 {
+        f32::from(self.0) / f32::from(MAX_STEPS)
+    }
+}
+
+/// Up to 16 digital buttons packed into a bit set.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Buttons(pub u16);
+
+impl Buttons {
+    /// Checks whether the given button is pressed.
+    /// # Panics
+    /// If `button` is 16 or larger.
+    pub fn pressed(self, button: u8) -> bool {
+        assert!(button < 16, "only 16 buttons are supported");
+        self.0 & (1 << button) != 0
+    }
+
+    /// Sets whether the given button is pressed.
+    /// # Panics
+    /// If `button` is 16 or larger.
+    pub fn set(&mut self, button: u8, pressed: bool) {
+        assert!(button < 16, "only 16 buttons are supported");
+        if pressed {
+            self.0 |= 1 << button;
+        } else {
+            self.0 &= !(1 << button);
+        }
+    }
+}
+
+/// The input of a typical gamepad, usable as the input of a session.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct GamepadInput {
+    pub buttons: Buttons,
+    /// The x and y axes of the left stick.
+    pub left_stick: [Axis; 2],
+    /// The x and y axes of the right stick.
+    pub right_stick: [Axis; 2],
+    /// The left and right triggers.
+    pub triggers: [Trigger; 2],
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn readings_are_quantized() {
+        let quantization = Quantization::new(4, 0.1);
+        assert_eq!(quantization.axis(0.05), Axis(0));
+        assert_eq!(quantization.axis(1.5), Axis(127));
+        assert_eq!(quantization.axis(-0.5), Axis(-64));
+        // nearby readings map to the same step
+        assert_eq!(quantization.axis(0.49), quantization.axis(0.51));
+        assert_eq!(quantization.trigger(0.26), Trigger(32));
+        assert_eq!(quantization.trigger(-1.0), Trigger(0));
+        assert!((Axis(-64).value() + 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn buttons() {
+        let mut buttons = Buttons::default();
+        buttons.set(3, true);
+        buttons.set(15, true);
+        buttons.set(15, false);
+        assert!(buttons.pressed(3));
+        assert!(!buttons.pressed(15));
+        assert_eq!(buttons, Buttons(0b1000));
+    }
+
+    #[test]
+    fn gamepad_inputs_are_compact() {
+        let quantization = Quantization::default();
+        let input = GamepadInput {
+            buttons: Buttons(0b101),
+            left_stick: [quantization.axis(-0.3), quantization.axis(1.0)],
+            right_stick: [Axis(0), Axis(0)],
+            triggers: [quantization.trigger(0.8), Trigger(0)],
+        };
+        let bytes = bincode::serialize(&input).unwrap();
+        assert_eq!(bytes.len(), 8);
+        assert_eq!(bincode::deserialize::<GamepadInput>(&bytes).unwrap(), input);
+    }
+}
@@ -0,0 +1,167 @@
+//! Fixed-size storage for per-frame inputs.
+
+/// The default amount of frames of input history kept.
+pub const DEFAULT_HISTORY_DEPTH: usize = 128;
+
+/// A ring buffer of inputs indexed by frame.
+/// Holds at most `depth` consecutive frames starting from the oldest retained frame.
+#[derive(Debug, Clone)]
+pub struct InputBuffer<I> {
+    slots: Vec<Option<I>>,
+    // the oldest frame that can be stored
+    start: u32,
+    // the latest input before start
+    pruned: Option<I>,
+}
+
+impl<I: Copy> InputBuffer<I> {
+    /// Creates an empty buffer holding up to `depth` frames.
+    /// # Panics
+    /// If `depth` is zero.
+    pub fn new(depth: usize) -> Self {
+        assert!(depth > 0, "input history depth must be positive");
+        Self {
+            slots: vec![None; depth],
+            start: 0,
+            pruned: None,
+        }
+    }
+
+    /// The amount of frames the buffer can hold.
+    pub fn depth(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// The oldest frame that can be stored or fetched.
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    fn index(&self, frame: u32) -> Option<usize> {
+        let offset = frame.checked_sub(self.start)? as usize;
+        if offset < self.slots.len() {
+            Some(frame as usize % self.slots.len())
+        } else {
+            None
+        }
+    }
+
+    /// Stores the input for the given frame if it is not already set.
+    /// Returns false if the frame is outside of the buffer's range.
+    pub fn insert(&mut self, frame: u32, input: I) -> bool {
+        match self.index(frame) {
+            Some(index) => {
+                if self.slots[index].is_none() {
+                    self.slots[index] = Some(input);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stores the input for the given frame, replacing any previous input.
+    /// Returns false if the frame is outside of the buffer's range.
+    pub fn set(&mut self, frame: u32, input: I) -> bool {
+        match self.index(frame) {
+            Some(index) => {
+                self.slots[index] = Some(input);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the input for the given frame, if it has been stored.
+    pub fn get(&self, frame: u32) -> Option<I> {
+        self.index(frame).and_then(|index| self.slots[index])
+    }
+
+    /// Checks whether the input for the given frame has been stored.
+    pub fn contains(&self, frame: u32) -> bool {
+        self.get(frame).is_some()
+    }
+
+    /// Returns the input for the given frame, if not available then the latest input before that frame.
+    pub fn latest_at(&self, frame: u32) -> Option<I> {
+        let end = std::cmp::min(
+            frame,
+            self.start.saturating_add(self.slots.len() as u32 - 1),
+        );
+        (self.start..=end)
+            .rev()
+            .find_map(|frame| self.get(frame))
+            .or(self.pruned)
+    }
+
+    /// Returns up to `count` inputs ending at `frame`, in reverse order:
+    /// [input for frame, input for frame - 1, ...]
+    /// Stops at the first missing input.
+    pub fn recent(&self, frame: u32, count: usize) -> Vec<I> {
+        self.recent_iter(frame, count).collect()
+    }
+
+    /// Like `recent`, but lets the caller choose where the inputs are collected.
+    pub fn recent_iter(&self, frame: u32, count: usize) -> impl Iterator<Item = I> + '_ {
+        (0..=frame)
+            .rev()
+            .take(count)
+            .map_while(move |frame| self.get(frame))
+    }
+
+    /// Discards all inputs before the given frame.
+    /// The latest discarded input is remembered so that it can still be used for prediction.
+    pub fn prune(&mut self, frame: u32) {
+        if frame <= self.start {
+            return;
+        }
+        self.pruned = self.latest_at(frame - 1);
+        let end = std::cmp::min(frame, self.start.saturating_add(self.slots.len() as u32));
+        for discarded in self.start..end {
+            let index = discarded as usize % self.slots.len();
+            self.slots[index] = None;
+        }
+        self.start = frame;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inserts_within_depth() {
+        let mut buffer = InputBuffer::new(4);
+        assert!(buffer.insert(0, 0));
+        assert!(buffer.insert(3, 3));
+        assert!(!buffer.insert(4, 4));
+        assert_eq!(buffer.get(0), Some(0));
+        assert_eq!(buffer.get(3), Some(3));
+        assert_eq!(buffer.get(4), None);
+    }
+
+    #[test]
+    fn prune_frees_space() {
+        let mut buffer = InputBuffer::new(4);
+        for frame in 0..4 {
+            buffer.insert(frame, frame);
+        }
+        buffer.prune(2);
+        assert_eq!(buffer.get(1), None);
+        assert_eq!(buffer.get(2), Some(2));
+        assert!(buffer.insert(5, 5));
+        assert_eq!(buffer.get(5), Some(5));
+        assert_eq!(buffer.recent(3, 8), vec![3, 2]);
+    }
+
+    #[test]
+    fn latest_at_falls_back_to_previous_input() {
+        let mut buffer = InputBuffer::new(8);
+        buffer.insert(0, 0);
+        buffer.insert(2, 2);
+        assert_eq!(buffer.latest_at(1), Some(0));
+        assert_eq!(buffer.latest_at(100), Some(2));
+        buffer.prune(5);
+        assert_eq!(buffer.latest_at(6), Some(2));
+    }
+}
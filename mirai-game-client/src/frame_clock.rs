@@ -0,0 +1,172 @@
+//! A fixed-timestep driver for games that do not get one from their engine.
+//!
+//! Frames are scheduled relative to a fixed starting point rather than the time of the previous frame,
+//! so rounding errors and late wakeups do not accumulate into drift. If the game falls too far
+//! behind, e.g. because the process was suspended, the schedule is reset instead of simulating
+//! a burst of frames. Frames can also be stalled, which is used to let a peer that is behind catch up.
+
+use std::time::{Duration, Instant};
+
+/// The maximum amount of frames reported as due in a single catch-up.
+const MAX_CATCH_UP_FRAMES: u32 = 8;
+
+/// Decides when the game should advance to the next frame.
+#[derive(Debug, Clone)]
+pub struct FrameClock {
+    frame_duration: Duration,
+    // the frames are due at start + n * frame_duration
+    start: Instant,
+    // the amount of frames elapsed since start, including stalled ones
+    elapsed_frames: u32,
+    // the amount of frames returned by tick
+    frames: u32,
+    stalled_frames: u32,
+}
+
+impl FrameClock {
+    /// Creates a clock ticking the given amount of times per second, starting now.
+    /// # Panics
+    /// If `frames_per_second` is zero.
+    pub fn new(frames_per_second: u32) -> Self {
+        Self::starting_at(frames_per_second, Instant::now())
+    }
+
+    /// Creates a clock ticking the given amount of times per second, starting at the given time.
+    /// # Panics
+    /// If `frames_per_second` is zero.
+    pub fn starting_at(frames_per_second: u32, start: Instant) -> Self {
+        assert!(frames_per_second > 0, "frames per second must be positive");
+        Self {
+            frame_duration: Duration::from_secs(1) / frames_per_second,
+            start,
+            elapsed_frames: 0,
+            frames: 0,
+            stalled_frames: 0,
+        }
+    }
+
+    /// Returns the duration of a single frame.
+    pub fn frame_duration(&self) -> Duration {
+        self.frame_duration
+    }
+
+    /// Returns the amount of frames the game has been told to advance.
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Skips the next `frames` frames that become due.
+    /// Used to slow down when running ahead of the other peers.
+    pub fn stall(&mut self, frames: u32) {
+        self.stalled_frames += frames;
+    }
+
+    /// Returns the amount of frames still to be skipped.
+    pub fn stalled_frames(&self) -> u32 {
+        self.stalled_frames
+    }
+
+    /// Checks whether the game should advance a frame. Like ggez's `timer::check_update_time`,
+    /// this should be called in a loop until it returns false.
+    pub fn tick(&mut self) -> bool {
+        self.tick_at(Instant::now())
+    }
+
+    /// Checks whether the game should advance a frame at the given time.
+    pub fn tick_at(&mut self, now: Instant) -> bool {
+        loop {
+            let due = self.due_frames(now);
+            if due > MAX_CATCH_UP_FRAMES {
+                // too far behind to catch up, continue the schedule from now
+                self.start = now;
+                self.elapsed_frames = 0;
+            }
+            if self.due_frames(now) == 0 {
+                return false;
+            }
+            self.elapsed_frames += 1;
+            if self.stalled_frames > 0 {
+                self.stalled_frames -= 1;
+                continue;
+            }
+            self.frames += 1;
+            return true;
+        }
+    }
+
+    /// Returns the time left until the next frame is due, or zero if it is already due.
+    /// Useful for sleeping between frames.
+    pub fn time_until_next_frame(&self) -> Duration {
+        self.time_until_next_frame_at(Instant::now())
+    }
+
+    /// Returns the time left at the given time until the next frame is due.
+    pub fn time_until_next_frame_at(&self, now: Instant) -> Duration {
+        let next = self.start + self.frame_duration * (self.elapsed_frames + 1);
+        next.saturating_duration_since(now)
+    }
+
+    fn due_frames(&self, now: Instant) -> u32 {
+        let since_start = now.saturating_duration_since(self.start);
+        let total = (since_start.as_nanos() / self.frame_duration.as_nanos()) as u32;
+        total.saturating_sub(self.elapsed_frames)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ticks(clock: &mut FrameClock, now: Instant) -> u32 {
+        let mut ticks = 0;
+        while clock.tick_at(now) {
+            ticks += 1;
+        }
+        ticks
+    }
+
+    #[test]
+    fn ticks_on_schedule() {
+        let start = Instant::now();
+        let mut clock = FrameClock::starting_at(10, start);
+        assert_eq!(ticks(&mut clock, start), 0);
+        assert_eq!(ticks(&mut clock, start + Duration::from_millis(99)), 0);
+        assert_eq!(ticks(&mut clock, start + Duration::from_millis(100)), 1);
+        assert_eq!(ticks(&mut clock, start + Duration::from_millis(350)), 2);
+        assert_eq!(
+            clock.time_until_next_frame_at(start + Duration::from_millis(350)),
+            Duration::from_millis(50)
+        );
+    }
+
+    #[test]
+    fn late_checks_do_not_drift() {
+        let start = Instant::now();
+        let mut clock = FrameClock::starting_at(10, start);
+        for i in 1..=50 {
+            // always checked slightly late
+            let now = start + Duration::from_millis(i * 100 + 30);
+            assert_eq!(ticks(&mut clock, now), 1);
+        }
+        assert_eq!(clock.frames(), 50);
+    }
+
+    #[test]
+    fn long_pauses_reset_the_schedule() {
+        let start = Instant::now();
+        let mut clock = FrameClock::starting_at(10, start);
+        let now = start + Duration::from_secs(60);
+        assert_eq!(ticks(&mut clock, now), 0);
+        assert_eq!(ticks(&mut clock, now + Duration::from_millis(100)), 1);
+    }
+
+    #[test]
+    fn stalls_skip_frames() {
+        let start = Instant::now();
+        let mut clock = FrameClock::starting_at(10, start);
+        clock.stall(2);
+        assert_eq!(ticks(&mut clock, start + Duration::from_millis(300)), 1);
+        assert_eq!(clock.stalled_frames(), 0);
+        assert_eq!(clock.frames(), 1);
+    }
+}
@@ -1,94 +1,1189 @@
-use crossbeam_channel::{Receiver, Sender};
-use laminar::{Packet, SocketEvent};
-use std::collections::BTreeMap;
+//! The Mirai game client exchanges inputs with the confirmed peers of a match after
+//! the matchmaking client has handed over its socket.
+//!
+//! Local inputs are sent unreliably to every peer together with a window of the preceding inputs,
+//! so a single lost packet does not stall the others. Each packet carries a sequence number
+//! and packets that arrive duplicated or out of order are discarded.
+//!
+//! A frame is fully confirmed once the inputs of every remote peer have arrived for it.
+//!
+//! Every packet carries a token identifying its sender. If a peer's packets start arriving
+//! from a new address with the same token, e.g. after its connection dropped and came back
+//! with a different port, the peer is moved to the new address. Peers that have fallen behind
+//! can ask each other to send the inputs they missed again.
+//!
+//! Each packet also acknowledges the latest frame up to which the receiver's inputs have been confirmed.
+//! Inputs the receiver has acknowledged are no longer included in the redundancy window sent to it,
+//! so on a good connection each packet only carries the inputs that are actually still missing.
+//! The time between sending an input and it being acknowledged is used to estimate the round trip time to each peer.
+//!
+//! Once a match is over, the peers can agree to a rematch. Each peer sends the token it will use in the rematch
+//! along with its answer, so packets still in flight from the previous match are not mistaken for the new one.
+//! If the rematch is declined, the socket can be handed back to the matchmaking client.
+//!
+//! The confirmed inputs of a match can be signed and streamed to a file or the tournament organizer's server
+//! for reviewing disputed matches, see `InputRecorder`. Spectators can be kept behind the match
+//! with `TimeShift`, so they can't pass on what the players are doing as it happens.
+//!
+//! At the start of a match the peers offer each other the tick rates they support and run the match
+//! at the fastest one they all do, see `TickRate`.
+//!
+//! Any serializable type can be used as an input. Games with analog controls can quantize them
+//! with `Quantization` or use `GamepadInput` directly.
+
+mod analog;
+mod buffer;
+mod frame_clock;
+mod metrics;
+mod prediction;
+mod recording;
+mod replay;
+mod sequence;
+mod session;
+mod spectator;
+mod states;
+mod synctest;
+mod tick_rate;
+
+pub use analog::{Axis, Buttons, GamepadInput, Quantization, Trigger};
+pub use buffer::{InputBuffer, DEFAULT_HISTORY_DEPTH};
+pub use frame_clock::FrameClock;
+pub use metrics::{MetricsCallback, SessionMetrics};
+pub use prediction::{Neutral, Predict, RepeatLast};
+pub use recording::{
+    read_records, verify_records, FileSink, InputRecord, InputRecorder, InputSink, RecordingError,
+    UploadSink, UPLOAD_BATCH_FRAMES, UPLOAD_TIMEOUT_MILLIS,
+};
+pub use replay::{Replay, ReplayError};
+pub use sequence::{ReplayWindow, Sequence, SequenceCheck};
+pub use session::{RematchStatus, Session, SessionConfig, SessionEvent, SessionState};
+pub use spectator::{TimeShift, DEFAULT_SPECTATOR_DELAY};
+pub use states::SavedStates;
+pub use synctest::{SyncTest, SyncTestError, SyncTestGame};
+pub use tick_rate::{TickRate, TickRateStatus};
+
+use crossbeam_channel::{select, unbounded, Receiver, SendError, Sender};
+use mirai_core::logging::{debug, trace};
+use mirai_core::transport::{Packet, Transport, TransportError, TransportEvent};
+use mirai_core::v1::streams;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use smallvec::SmallVec;
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The maximum amount of inputs sent in a single packet when resynchronizing.
+const RESYNC_CHUNK_SIZE: usize = 32;
+
+/// The amount of inputs an `InputWindow` holds without allocating.
+pub const INPUT_WINDOW: usize = 8;
+
+/// The inputs sent or received in a single packet, in reverse order: [input for frame, input for frame - 1, ...]
+/// Kept inline up to `INPUT_WINDOW` inputs, so the per-frame send and receive paths don't allocate for them.
+pub type InputWindow<I> = SmallVec<[I; INPUT_WINDOW]>;
+
+type ArMu<T> = Arc<Mutex<T>>;
+
+fn armu<T>(t: T) -> ArMu<T> {
+    Arc::new(Mutex::new(t))
+}
+
+/// Types that can be used as inputs by the game client.
+pub trait NetInput:
+    Copy + Default + PartialEq + Serialize + DeserializeOwned + Send + 'static
+{
+}
+
+impl<T> NetInput for T where
+    T: Copy + Default + PartialEq + Serialize + DeserializeOwned + Send + 'static
+{
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+struct NetworkInput<I> {
+    sequence: Sequence,
+    // the latest frame up to which the sender has received all of the receiver's inputs
+    ack: u32,
+    frame: u32,
+    inputs: InputWindow<I>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+enum NetworkMessage<I> {
+    Inputs(NetworkInput<I>),
+    // asks the receiver to send its inputs starting from the given frame again
+    Resync(u32),
+    // the sender's answer to a rematch and the token it will use if the rematch happens
+    Rematch { accept: bool, token: u64 },
+    // the tick rates the sender supports
+    TickRates(Vec<TickRate>),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+struct Envelope<I> {
+    // identifies the sender even if its address changes
+    token: u64,
+    message: NetworkMessage<I>,
+}
+
+// serialized like an Envelope with NetworkMessage::Inputs, but borrows the inputs instead of copying them
+#[derive(Serialize)]
+struct InputsEnvelope<'a, I> {
+    token: u64,
+    message: InputsMessage<'a, I>,
+}
+
+#[derive(Serialize)]
+enum InputsMessage<'a, I> {
+    Inputs {
+        sequence: Sequence,
+        ack: u32,
+        frame: u32,
+        inputs: &'a [I],
+    },
+}
+
+enum Message<I> {
+    Inputs(u32, InputWindow<I>),
+    Resync,
+    Rematch(bool),
+    TickRates(Vec<TickRate>),
+}
+
+fn generate_token() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(time) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(time.as_nanos());
+    }
+    hasher.finish()
+}
+
+/// Received inputs and the latest frame up to which all of them have arrived.
+struct RemoteInputs<I> {
+    inputs: InputBuffer<I>,
+    latest_fully_confirmed: u32,
+}
+
+impl<I: NetInput> RemoteInputs<I> {
+    fn new(history_depth: usize) -> Self {
+        let mut inputs = InputBuffer::new(history_depth);
+        inputs.insert(0, I::default());
+        Self {
+            inputs,
+            latest_fully_confirmed: 0,
+        }
+    }
+
+    fn insert(&mut self, frame: u32, inputs: impl IntoIterator<Item = I>) {
+        for (offset, input) in inputs.into_iter().enumerate() {
+            let frame = match frame.checked_sub(offset as u32) {
+                Some(frame) => frame,
+                None => break,
+            };
+            if !self.inputs.insert(frame, input) && frame >= self.inputs.start() {
+                trace!("input for {} does not fit in the history", frame);
+            }
+        }
+        while self.inputs.contains(self.latest_fully_confirmed + 1) {
+            self.latest_fully_confirmed += 1;
+        }
+    }
+
+    fn prune(&mut self, frame: u32) {
+        // unconfirmed frames are never discarded
+        self.inputs
+            .prune(std::cmp::min(frame, self.latest_fully_confirmed));
+    }
+}
 
-enum Message {}
+/// The state kept for each remote peer.
+struct RemotePeer<I> {
+    // the address the peer is currently reachable at
+    addr: SocketAddr,
+    // learned from the first packet received from the peer
+    token: Option<u64>,
+    // the latest frame up to which the peer has acknowledged receiving all local inputs
+    acked: u32,
+    // smoothed estimate, known once the peer has acknowledged an input
+    rtt: Option<Duration>,
+    // the peer's answer to a rematch and the token it will use in it
+    rematch: Option<(bool, u64)>,
+    // the tick rates the peer supports
+    tick_rates: Option<Vec<TickRate>>,
+    replay_window: ReplayWindow,
+    inputs: RemoteInputs<I>,
+}
 
-enum Input {}
+impl<I> RemotePeer<I> {
+    // weighs new samples by 1/8 like TCP does, so a single delayed acknowledgement does not skew the estimate
+    fn add_rtt_sample(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt * 7 / 8 + sample / 8,
+            None => sample,
+        });
+    }
+}
 
-pub struct Client {
-    opp_addr: SocketAddr,
+/// Sends local inputs to the peers of a match and collects their inputs.
+/// Peers are identified by the address they had at the start of the match, even if they later reconnect
+/// from a different one.
+pub struct Client<I, T: Transport> {
+    peers: Vec<SocketAddr>,
+    token: u64,
+    // the token used in a rematch
+    next_token: u64,
+    history_depth: usize,
+    message_sender: Sender<Message<I>>,
+    reconnect_receiver: Receiver<SocketAddr>,
+    remote: ArMu<HashMap<SocketAddr, RemotePeer<I>>>,
+    handle: JoinHandle<Result<T, ClientError>>,
 }
 
-impl Client {
-    pub fn new(
-        opp_addr: SocketAddr,
-        receiver: Receiver<SocketEvent>,
-        sender: Sender<Packet>,
+impl<I: NetInput, T: Transport> Client<I, T> {
+    /// Creates a new Client for a match against the given peers using the transport
+    /// handed over by the matchmaking client.
+    /// Starts up a thread that handles network traffic.
+    pub fn new(peers: Vec<SocketAddr>, transport: T) -> Self {
+        Self::with_history_depth(peers, transport, DEFAULT_HISTORY_DEPTH)
+    }
+
+    /// Creates a new Client that keeps at most `history_depth` frames of each peer's inputs.
+    /// Starts up a thread that handles network traffic.
+    /// # Panics
+    /// If `history_depth` is zero.
+    pub fn with_history_depth(peers: Vec<SocketAddr>, transport: T, history_depth: usize) -> Self {
+        Self::start(
+            peers,
+            transport,
+            history_depth,
+            generate_token(),
+            HashMap::new(),
+        )
+    }
+
+    // the peers' tokens are learned from their first packets unless they are already known
+    fn start(
+        peers: Vec<SocketAddr>,
+        transport: T,
+        history_depth: usize,
+        token: u64,
+        peer_tokens: HashMap<SocketAddr, u64>,
     ) -> Self {
-        Self { opp_addr }
+        let next_token = generate_token();
+        let (message_sender, message_receiver) = unbounded();
+        let (reconnect_sender, reconnect_receiver) = unbounded();
+        let remote = peers
+            .iter()
+            .map(|&addr| {
+                let peer = RemotePeer {
+                    addr,
+                    token: peer_tokens.get(&addr).copied(),
+                    acked: 0,
+                    rtt: None,
+                    rematch: None,
+                    tick_rates: None,
+                    replay_window: ReplayWindow::new(),
+                    inputs: RemoteInputs::new(history_depth),
+                };
+                (addr, peer)
+            })
+            .collect();
+        let remote = armu(remote);
+        let thread_remote = Arc::clone(&remote);
+        let handler = Handler {
+            token,
+            next_token,
+            transport,
+            reconnect_sender,
+            remote: thread_remote,
+            sequence: Sequence::default(),
+            local: InputBuffer::new(history_depth),
+            latest_local: 0,
+            send_times: VecDeque::new(),
+            targets: Vec::new(),
+        };
+        let handle = thread::spawn(move || handler.handle_packets(message_receiver));
+        Self {
+            peers,
+            token,
+            next_token,
+            history_depth,
+            message_sender,
+            reconnect_receiver,
+            remote,
+            handle,
+        }
     }
 
-    fn handle_packets(
-        opp_addr: SocketAddr,
-        packet_sender: Sender<Packet>,
-        event_receiver: Receiver<SocketEvent>,
-        receiver: Receiver<Message>,
-        inputs: Arc<Mutex<BTreeMap<u32, Input>>>,
-        latest_fully_confirmed: Arc<Mutex<u32>>,
-    ) {
+    /// Sends the local inputs for the given frame to every peer.
+    /// The inputs are given in reverse order: [input for frame, input for frame - 1, ...]
+    /// # Errors
+    /// If the handler thread has stopped.
+    pub fn send(&self, frame: u32, inputs: &[I]) -> Result<(), ClientError> {
+        let inputs = InputWindow::from_slice(inputs);
+        self.message_sender.send(Message::Inputs(frame, inputs))?;
+        Ok(())
+    }
+
+    /// Asks every peer whose inputs have not been confirmed up to the latest received frame
+    /// to send the missing inputs again.
+    /// # Errors
+    /// If the handler thread has stopped.
+    pub fn request_resync(&self) -> Result<(), ClientError> {
+        self.message_sender.send(Message::Resync)?;
+        Ok(())
+    }
+
+    /// Sends the answer to a rematch to every peer.
+    /// # Errors
+    /// If the handler thread has stopped.
+    pub fn offer_rematch(&self, accept: bool) -> Result<(), ClientError> {
+        self.message_sender.send(Message::Rematch(accept))?;
+        Ok(())
+    }
+
+    /// Returns the given peer's answer to a rematch, if it has answered.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn rematch_answer(&self, peer: SocketAddr) -> Result<Option<bool>, ClientError> {
+        let remote = self.remote.lock()?;
+        let peer = remote.get(&peer).context(UnknownPeer { addr: peer })?;
+        Ok(peer.rematch.map(|(accept, _)| accept))
+    }
+
+    /// Sends the tick rates the local build supports to every peer.
+    /// # Errors
+    /// If the handler thread has stopped.
+    pub fn offer_tick_rates(&self, rates: &[TickRate]) -> Result<(), ClientError> {
+        self.message_sender
+            .send(Message::TickRates(rates.to_vec()))?;
+        Ok(())
+    }
+
+    /// Returns the tick rates the given peer supports, if it has offered them.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn peer_tick_rates(&self, peer: SocketAddr) -> Result<Option<Vec<TickRate>>, ClientError> {
+        let remote = self.remote.lock()?;
+        let peer = remote.get(&peer).context(UnknownPeer { addr: peer })?;
+        Ok(peer.tick_rates.clone())
+    }
+
+    /// Closes the client and creates a new one for a rematch against the same peers on the same socket.
+    /// # Errors
+    /// If a peer has not accepted the rematch, or the handler thread encountered an error or panicked.
+    pub fn rematch(self) -> Result<Self, ClientError> {
+        let mut peer_tokens = HashMap::new();
+        for (&addr, peer) in self.remote.lock()?.iter() {
+            match peer.rematch {
+                Some((true, token)) => {
+                    peer_tokens.insert(addr, token);
+                }
+                _ => return Err(ClientError::RematchNotAccepted),
+            }
+        }
+        let peers = self.peers.clone();
+        let history_depth = self.history_depth;
+        let token = self.next_token;
+        let transport = self.into_socket()?;
+        Ok(Self::start(
+            peers,
+            transport,
+            history_depth,
+            token,
+            peer_tokens,
+        ))
+    }
+
+    /// Returns the peers that have reconnected from a new address since the last call.
+    pub fn reconnected_peers(&self) -> Vec<SocketAddr> {
+        self.reconnect_receiver.try_iter().collect()
+    }
+
+    /// Returns the token identifying this client to its peers.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// Returns the latest frame up to which the given peer has acknowledged receiving all local inputs.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn latest_acked_by(&self, peer: SocketAddr) -> Result<u32, ClientError> {
+        let remote = self.remote.lock()?;
+        let peer = remote.get(&peer).context(UnknownPeer { addr: peer })?;
+        Ok(peer.acked)
+    }
+
+    /// Returns the estimated round trip time to the given peer, if it has acknowledged any inputs yet.
+    /// Includes the time the peer takes to send its next packet, so on the order of a frame longer than the ping.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn round_trip_time(&self, peer: SocketAddr) -> Result<Option<Duration>, ClientError> {
+        let remote = self.remote.lock()?;
+        let peer = remote.get(&peer).context(UnknownPeer { addr: peer })?;
+        Ok(peer.rtt)
+    }
+
+    /// Returns the address the given peer is currently reachable at.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn current_addr(&self, peer: SocketAddr) -> Result<SocketAddr, ClientError> {
+        let remote = self.remote.lock()?;
+        let peer = remote.get(&peer).context(UnknownPeer { addr: peer })?;
+        Ok(peer.addr)
+    }
+
+    /// Returns the given peer's input for the given frame, if not available then
+    /// the latest input received before that frame.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn input_for(&self, peer: SocketAddr, frame: u32) -> Result<I, ClientError> {
+        Ok(self.latest_received(peer, frame)?.unwrap_or_default())
+    }
+
+    /// Returns the given peer's input for the given frame if it has been received.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn received_input(&self, peer: SocketAddr, frame: u32) -> Result<Option<I>, ClientError> {
+        let remote = self.remote.lock()?;
+        let peer = remote.get(&peer).context(UnknownPeer { addr: peer })?;
+        Ok(peer.inputs.inputs.get(frame))
+    }
+
+    /// Returns the latest input received from the given peer for the given frame or any frame before it.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn latest_received(&self, peer: SocketAddr, frame: u32) -> Result<Option<I>, ClientError> {
+        let remote = self.remote.lock()?;
+        let peer = remote.get(&peer).context(UnknownPeer { addr: peer })?;
+        Ok(peer.inputs.inputs.latest_at(frame))
+    }
+
+    /// Returns the largest frame f where the inputs for 0..=f have been received from every peer.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn latest_fully_confirmed(&self) -> Result<u32, ClientError> {
+        let remote = self.remote.lock()?;
+        let latest = remote
+            .values()
+            .map(|peer| peer.inputs.latest_fully_confirmed)
+            .min()
+            .unwrap_or(0);
+        Ok(latest)
+    }
+
+    /// Returns the largest frame f where the given peer's inputs for 0..=f have all been received.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn latest_confirmed_for(&self, peer: SocketAddr) -> Result<u32, ClientError> {
+        let remote = self.remote.lock()?;
+        let peer = remote.get(&peer).context(UnknownPeer { addr: peer })?;
+        Ok(peer.inputs.latest_fully_confirmed)
+    }
+
+    /// Discards the peers' inputs before the given frame.
+    /// Inputs that have not been confirmed yet are kept.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn prune(&self, frame: u32) -> Result<(), ClientError> {
+        for peer in self.remote.lock()?.values_mut() {
+            peer.inputs.prune(frame);
+        }
+        Ok(())
+    }
+
+    pub fn check_time_until_start(&self) -> Option<u8> {
+        None
+    }
+
+    /// Returns the remote peers of the match.
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    /// Stops the handler thread.
+    /// # Errors
+    /// If the handler thread encountered an error or panicked.
+    pub fn close(self) -> Result<(), ClientError> {
+        self.into_socket()?;
+        Ok(())
+    }
+
+    /// Stops the handler thread and returns the underlying transport,
+    /// e.g. to hand it back to the matchmaking client.
+    /// # Errors
+    /// If the handler thread encountered an error or panicked.
+    pub fn into_socket(self) -> Result<T, ClientError> {
+        drop(self.message_sender);
+        self.handle.join()?
+    }
+}
+
+/// The state owned by the handler thread.
+struct Handler<I, T> {
+    token: u64,
+    next_token: u64,
+    transport: T,
+    reconnect_sender: Sender<SocketAddr>,
+    remote: ArMu<HashMap<SocketAddr, RemotePeer<I>>>,
+    sequence: Sequence,
+    // the local inputs sent so far, kept for resynchronizing peers
+    local: InputBuffer<I>,
+    latest_local: u32,
+    // when each of the latest frames was first sent, for measuring the round trip time
+    send_times: VecDeque<(u32, Instant)>,
+    // reused for every batch of inputs sent
+    targets: Vec<Target>,
+}
+
+impl<I: NetInput, T: Transport> Handler<I, T> {
+    fn handle_packets(mut self, message_receiver: Receiver<Message<I>>) -> Result<T, ClientError> {
+        debug!("starting handler for {} peers", self.remote.lock()?.len());
+        let event_receiver = self.transport.events().clone();
         loop {
-            /*
-            while let Ok(event) = event_receiver.try_recv() {
-                match event {
-                    SocketEvent::Packet(packet) => {
-                        // try to deserialize incoming packet
-                        match bincode::deserialize::<NetworkInput>(&packet.payload()) {
-                            Ok(input) => {
-                                let mut frame = input.frame;
-                                let mut inputs =
-                                    inputs.lock().expect("failed to get lock for inputs in evr");
-                                println!("received {} inputs for {}", input.inputs.len(), frame);
-                                frame += 1;
-                                for input in input.inputs {
-                                    // reverse inputs, the opponent is playing as p1 but on our side they are p2
-                                    let input = Input {
-                                        left: input.right,
-                                        right: input.left,
-                                        ..input
-                                    };
-                                    frame -= 1;
-                                    inputs.insert(frame, input);
-                                }
-                                // update latest fully confirmed
-                                let mut latest_fully_confirmed = latest_fully_confirmed
-                                    .lock()
-                                    .expect("failed to get lock for confirm in evr");
-                                for f in *latest_fully_confirmed + 1.. {
-                                    if !inputs.contains_key(&f) {
-                                        *latest_fully_confirmed = f - 1;
-                                        break;
-                                    }
-                                }
-                            }
-                            _ => {}
-                        }
+            select! {
+                recv(event_receiver) -> event => match event {
+                    Ok(TransportEvent::Packet(packet)) => self.handle_packet(packet)?,
+                    Ok(_) => {}
+                    Err(_) => return Ok(self.transport),
+                },
+                recv(message_receiver) -> message => match message {
+                    Ok(Message::Inputs(frame, inputs)) => {
+                        self.record_local(frame, &inputs);
+                        self.record_send_time(frame);
+                        let mut targets = std::mem::take(&mut self.targets);
+                        self.fill_targets(&mut targets)?;
+                        let sent = self.send_inputs(&targets, frame, &inputs);
+                        self.targets = targets;
+                        sent?;
                     }
-                    _ => {}
+                    Ok(Message::Resync) => self.request_resync()?,
+                    Ok(Message::Rematch(accept)) => self.send_rematch(accept)?,
+                    Ok(Message::TickRates(rates)) => self.send_control(NetworkMessage::TickRates(rates))?,
+                    // the client was dropped
+                    Err(_) => return Ok(self.transport),
+                },
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, packet: Packet) -> Result<(), ClientError> {
+        let envelope = match bincode::deserialize::<Envelope<I>>(packet.payload()) {
+            Ok(envelope) => envelope,
+            Err(_) => return Ok(()),
+        };
+        let mut remote = self.remote.lock()?;
+        let id = match remote.iter().find(|(_, peer)| peer.addr == packet.addr()) {
+            Some((&id, _)) => id,
+            None => match remote
+                .iter()
+                .find(|(_, peer)| peer.token == Some(envelope.token))
+            {
+                Some((&id, _)) => {
+                    debug!("peer {} reconnected from {}", id, packet.addr());
+                    remote.get_mut(&id).expect("peer disappeared").addr = packet.addr();
+                    // the receiver is only dropped along with the client
+                    let _ = self.reconnect_sender.send(id);
+                    id
                 }
+                None => return Ok(()),
+            },
+        };
+        let peer = remote.get_mut(&id).expect("peer disappeared");
+        match peer.token {
+            Some(token) if token != envelope.token => {
+                trace!(
+                    "discarding packet from {} with the wrong token",
+                    packet.addr()
+                );
+                return Ok(());
             }
-            while let Ok(msg) = receiver.try_recv() {
-                match msg {
-                    Message::Inputs(frame, inputs) => {
-                        let msg = bincode::serialize(&NetworkInput { frame, inputs })
-                            .expect("failed to serialize ni");
-                        packet_sender
-                            .send(Packet::unreliable(opp_addr, msg))
-                            .expect("failed to send packet");
+            Some(_) => {}
+            None => peer.token = Some(envelope.token),
+        }
+        match envelope.message {
+            NetworkMessage::Inputs(input) => match peer.replay_window.check(input.sequence) {
+                SequenceCheck::Fresh => {
+                    trace!(
+                        "received {} inputs for {} from {}",
+                        input.inputs.len(),
+                        input.frame,
+                        packet.addr()
+                    );
+                    if input.ack > peer.acked {
+                        peer.acked = input.ack;
+                        if let Some((_, sent)) = self
+                            .send_times
+                            .iter()
+                            .find(|(frame, _)| *frame == input.ack)
+                        {
+                            peer.add_rtt_sample(sent.elapsed());
+                        }
                     }
+                    peer.inputs.insert(input.frame, input.inputs);
                 }
+                check => trace!("discarding packet {:?}: {:?}", input.sequence, check),
+            },
+            NetworkMessage::Rematch { accept, token } => {
+                debug!("{} answered {} to a rematch", packet.addr(), accept);
+                peer.rematch = Some((accept, token));
+            }
+            NetworkMessage::TickRates(rates) => {
+                debug!("{} supports the tick rates {:?}", packet.addr(), rates);
+                peer.tick_rates = Some(rates);
+            }
+            NetworkMessage::Resync(frame) => {
+                debug!("{} requested inputs from {}", packet.addr(), frame);
+                drop(remote);
+                self.resync(packet.addr(), frame)?;
             }
-            */
         }
+        Ok(())
     }
 
-    pub fn check_time_until_start(&self) -> Option<u8> {
-        None
+    fn record_local(&mut self, frame: u32, inputs: &[I]) {
+        let depth = self.local.depth() as u32;
+        if frame >= self.local.start() + depth {
+            // only the latest inputs are kept
+            self.local.prune(frame + 1 - depth);
+        }
+        for (offset, &input) in inputs.iter().enumerate() {
+            match frame.checked_sub(offset as u32) {
+                Some(frame) => self.local.insert(frame, input),
+                None => break,
+            };
+        }
+        self.latest_local = std::cmp::max(self.latest_local, frame);
+    }
+
+    fn record_send_time(&mut self, frame: u32) {
+        if self.send_times.len() == self.local.depth() {
+            self.send_times.pop_front();
+        }
+        self.send_times.push_back((frame, Instant::now()));
+    }
+
+    // sends the local inputs starting from the given frame to the given address
+    fn resync(&mut self, addr: SocketAddr, from: u32) -> Result<(), ClientError> {
+        // the requested inputs are sent regardless of what the peer has acknowledged
+        let targets = self
+            .targets()?
+            .into_iter()
+            .filter(|target| target.addr == addr)
+            .map(|target| Target { acked: 0, ..target })
+            .collect::<Vec<_>>();
+        let mut start = std::cmp::max(from, self.local.start());
+        while start <= self.latest_local {
+            let end = std::cmp::min(start + RESYNC_CHUNK_SIZE as u32 - 1, self.latest_local);
+            let inputs = self.local.recent(end, (end - start + 1) as usize);
+            self.send_inputs(&targets, end, &inputs)?;
+            start = end + 1;
+        }
+        Ok(())
+    }
+
+    fn request_resync(&mut self) -> Result<(), ClientError> {
+        let requests = self
+            .remote
+            .lock()?
+            .values()
+            .map(|peer| (peer.addr, peer.inputs.latest_fully_confirmed + 1))
+            .collect::<Vec<_>>();
+        for (addr, frame) in requests {
+            trace!("requesting inputs from {} starting at {}", addr, frame);
+            self.send(addr, NetworkMessage::Resync(frame))?;
+        }
+        Ok(())
+    }
+
+    fn send_rematch(&mut self, accept: bool) -> Result<(), ClientError> {
+        self.send_control(NetworkMessage::Rematch {
+            accept,
+            token: self.next_token,
+        })
+    }
+
+    // sends the message reliably to every peer
+    fn send_control(&mut self, message: NetworkMessage<I>) -> Result<(), ClientError> {
+        let payload = self.serialize(message)?;
+        for target in self.targets()? {
+            self.transport.send(Packet::reliable_ordered(
+                target.addr,
+                payload.clone(),
+                streams::CONTROL,
+            ))?;
+        }
+        Ok(())
+    }
+
+    // sends the inputs to each target, leaving out the ones the target has already acknowledged
+    fn send_inputs(
+        &mut self,
+        targets: &[Target],
+        frame: u32,
+        inputs: &[I],
+    ) -> Result<(), ClientError> {
+        for target in targets {
+            let unacked = frame.saturating_sub(target.acked) as usize;
+            let count = std::cmp::min(std::cmp::max(unacked, 1), inputs.len());
+            let payload = bincode::serialize(&InputsEnvelope {
+                token: self.token,
+                message: InputsMessage::Inputs {
+                    sequence: self.sequence,
+                    ack: target.ack,
+                    frame,
+                    inputs: &inputs[..count],
+                },
+            })
+            .context(SerializeError)?;
+            self.transport.send(Packet::unreliable_sequenced(
+                target.addr,
+                payload,
+                streams::INPUT,
+            ))?;
+        }
+        self.sequence = self.sequence.next();
+        Ok(())
+    }
+
+    fn send(&self, addr: SocketAddr, message: NetworkMessage<I>) -> Result<(), ClientError> {
+        let payload = self.serialize(message)?;
+        self.transport.send(Packet::unreliable(addr, payload))?;
+        Ok(())
+    }
+
+    fn serialize(&self, message: NetworkMessage<I>) -> Result<Vec<u8>, ClientError> {
+        bincode::serialize(&Envelope {
+            token: self.token,
+            message,
+        })
+        .context(SerializeError)
+    }
+
+    fn targets(&self) -> Result<Vec<Target>, ClientError> {
+        let mut targets = Vec::new();
+        self.fill_targets(&mut targets)?;
+        Ok(targets)
+    }
+
+    // replaces the contents of the given buffer with the current targets
+    fn fill_targets(&self, targets: &mut Vec<Target>) -> Result<(), ClientError> {
+        targets.clear();
+        targets.extend(self.remote.lock()?.values().map(|peer| Target {
+            addr: peer.addr,
+            ack: peer.inputs.latest_fully_confirmed,
+            acked: peer.acked,
+        }));
+        Ok(())
+    }
+}
+
+/// A peer inputs are sent to.
+struct Target {
+    addr: SocketAddr,
+    // the acknowledgement sent to the peer
+    ack: u32,
+    // the acknowledgement received from the peer
+    acked: u32,
+}
+
+#[derive(Debug, Snafu)]
+pub enum ClientError {
+    MutexError,
+    SenderError,
+    SerializeError { source: Box<bincode::ErrorKind> },
+    ThreadError,
+    UnknownPeer { addr: SocketAddr },
+    HistoryFull { frame: u32 },
+    RematchNotAccepted,
+    RecordingFailed { source: RecordingError },
+}
+
+impl<T> From<PoisonError<T>> for ClientError {
+    fn from(_: PoisonError<T>) -> Self {
+        ClientError::MutexError
+    }
+}
+
+impl<T> From<SendError<T>> for ClientError {
+    fn from(_: SendError<T>) -> Self {
+        ClientError::SenderError
+    }
+}
+
+impl From<TransportError> for ClientError {
+    fn from(_: TransportError) -> Self {
+        ClientError::SenderError
+    }
+}
+
+impl From<Box<dyn std::any::Any + Send>> for ClientError {
+    fn from(_: Box<dyn std::any::Any + Send>) -> Self {
+        ClientError::ThreadError
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mirai_core::transport::ChannelTransport;
+    use smallvec::smallvec;
+
+    fn network_input(sequence: u16, frame: u32, inputs: Vec<u8>) -> Vec<u8> {
+        envelope(
+            0,
+            NetworkMessage::Inputs(NetworkInput {
+                sequence: Sequence(sequence),
+                ack: 0,
+                frame,
+                inputs: inputs.into(),
+            }),
+        )
+    }
+
+    fn envelope(token: u64, message: NetworkMessage<u8>) -> Vec<u8> {
+        bincode::serialize(&Envelope { token, message }).unwrap()
+    }
+
+    #[test]
+    fn input_window_fits_inline() {
+        let mut buffer = InputBuffer::new(DEFAULT_HISTORY_DEPTH);
+        for frame in 1..20 {
+            buffer.insert(frame, frame as u8);
+        }
+        let window: InputWindow<u8> = buffer.recent_iter(19, INPUT_WINDOW).collect();
+        assert_eq!(window.as_slice(), &[19, 18, 17, 16, 15, 14, 13, 12]);
+        assert!(!window.spilled());
+    }
+
+    #[test]
+    fn borrowed_inputs_serialize_like_network_inputs() {
+        let inputs = vec![3, 2, 1];
+        let borrowed = bincode::serialize(&InputsEnvelope {
+            token: 7,
+            message: InputsMessage::Inputs {
+                sequence: Sequence(4),
+                ack: 5,
+                frame: 6,
+                inputs: &inputs[..],
+            },
+        })
+        .unwrap();
+        let owned = envelope(
+            7,
+            NetworkMessage::Inputs(NetworkInput {
+                sequence: Sequence(4),
+                ack: 5,
+                frame: 6,
+                inputs: inputs.into(),
+            }),
+        );
+        assert_eq!(borrowed, owned);
     }
 
-    pub fn addr(&self) -> SocketAddr {
-        self.opp_addr
+    fn sent_message(packet: Packet) -> NetworkMessage<u8> {
+        bincode::deserialize::<Envelope<u8>>(packet.payload())
+            .unwrap()
+            .message
+    }
+
+    fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+        let now = Instant::now();
+        while now.elapsed() < Duration::from_millis(500) {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    #[test]
+    fn frames_are_confirmed_by_every_peer() {
+        let addr_1 = "127.0.0.1:1".parse().unwrap();
+        let addr_2 = "127.0.0.1:2".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let client = Client::<u8, _>::new(
+            vec![addr_1, addr_2],
+            ChannelTransport::new(event_receiver, packet_sender),
+        );
+
+        let payload = network_input(0, 2, vec![2, 1]);
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(addr_1, payload)))
+            .unwrap();
+        assert!(wait_until(
+            || client.latest_confirmed_for(addr_1).unwrap() == 2
+        ));
+        assert_eq!(client.latest_confirmed_for(addr_2).unwrap(), 0);
+        assert_eq!(client.latest_fully_confirmed().unwrap(), 0);
+
+        let payload = network_input(0, 1, vec![1]);
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(addr_2, payload)))
+            .unwrap();
+        assert!(wait_until(|| client.latest_fully_confirmed().unwrap() == 1));
+        assert_eq!(client.input_for(addr_2, 5).unwrap(), 1);
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn redundant_inputs_fill_gaps() {
+        let mut remote = RemoteInputs::<u8>::new(DEFAULT_HISTORY_DEPTH);
+        remote.insert(3, vec![3, 2]);
+        assert_eq!(remote.latest_fully_confirmed, 0);
+        remote.insert(4, vec![4, 3, 2, 1]);
+        assert_eq!(remote.latest_fully_confirmed, 4);
+    }
+
+    #[test]
+    fn received_inputs_are_not_overwritten() {
+        let mut remote = RemoteInputs::<u8>::new(DEFAULT_HISTORY_DEPTH);
+        remote.insert(2, vec![2, 1]);
+        remote.insert(2, vec![9, 9]);
+        assert_eq!(remote.inputs.get(2), Some(2));
+        assert_eq!(remote.inputs.get(1), Some(1));
+    }
+
+    #[test]
+    fn peers_reconnect_from_new_addresses() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let new_addr = "127.0.0.1:2".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, packet_receiver) = unbounded();
+        let client = Client::<u8, _>::new(
+            vec![addr],
+            ChannelTransport::new(event_receiver, packet_sender),
+        );
+
+        let payload = envelope(
+            7,
+            NetworkMessage::Inputs(NetworkInput {
+                sequence: Sequence(0),
+                ack: 0,
+                frame: 1,
+                inputs: smallvec![1],
+            }),
+        );
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(addr, payload)))
+            .unwrap();
+        assert!(wait_until(
+            || client.latest_confirmed_for(addr).unwrap() == 1
+        ));
+
+        // packets with a different token are not accepted from new addresses
+        let payload = envelope(8, NetworkMessage::Resync(1));
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(
+                new_addr, payload,
+            )))
+            .unwrap();
+        let payload = envelope(
+            7,
+            NetworkMessage::Inputs(NetworkInput {
+                sequence: Sequence(1),
+                ack: 0,
+                frame: 2,
+                inputs: smallvec![2],
+            }),
+        );
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(
+                new_addr, payload,
+            )))
+            .unwrap();
+        assert!(wait_until(
+            || client.latest_confirmed_for(addr).unwrap() == 2
+        ));
+        assert_eq!(client.reconnected_peers(), vec![addr]);
+        assert_eq!(client.current_addr(addr).unwrap(), new_addr);
+        assert!(packet_receiver.try_recv().is_err());
+
+        client.send(1, &[1]).unwrap();
+        let packet = packet_receiver
+            .recv_timeout(Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(packet.addr(), new_addr);
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn missed_inputs_are_sent_again() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, packet_receiver) = unbounded();
+        let client = Client::<u8, _>::new(
+            vec![addr],
+            ChannelTransport::new(event_receiver, packet_sender),
+        );
+
+        for frame in 1..=40 {
+            client.send(frame, &[frame as u8]).unwrap();
+            packet_receiver
+                .recv_timeout(Duration::from_millis(500))
+                .unwrap();
+        }
+        let payload = envelope(7, NetworkMessage::Resync(2));
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(addr, payload)))
+            .unwrap();
+        let mut resent = vec![];
+        while resent.len() < 39 {
+            let packet = packet_receiver
+                .recv_timeout(Duration::from_millis(500))
+                .unwrap();
+            match sent_message(packet) {
+                NetworkMessage::Inputs(input) => resent.extend(input.inputs.into_iter().rev()),
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+        assert_eq!(resent, (2..=40).collect::<Vec<u8>>());
+
+        client.request_resync().unwrap();
+        let packet = packet_receiver
+            .recv_timeout(Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(sent_message(packet), NetworkMessage::Resync(1));
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn acknowledged_inputs_are_not_resent() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, packet_receiver) = unbounded();
+        let client = Client::<u8, _>::new(
+            vec![addr],
+            ChannelTransport::new(event_receiver, packet_sender),
+        );
+
+        let payload = envelope(
+            7,
+            NetworkMessage::Inputs(NetworkInput {
+                sequence: Sequence(0),
+                ack: 3,
+                frame: 2,
+                inputs: smallvec![2, 1],
+            }),
+        );
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(addr, payload)))
+            .unwrap();
+        assert!(wait_until(|| client.latest_acked_by(addr).unwrap() == 3));
+
+        client.send(5, &[5, 4, 3, 2, 1]).unwrap();
+        let packet = packet_receiver
+            .recv_timeout(Duration::from_millis(500))
+            .unwrap();
+        let expected = NetworkInput {
+            sequence: Sequence(0),
+            ack: 2,
+            frame: 5,
+            inputs: smallvec![5, 4],
+        };
+        assert_eq!(sent_message(packet), NetworkMessage::Inputs(expected));
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn acknowledgements_measure_round_trip_time() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, packet_receiver) = unbounded();
+        let client = Client::<u8, _>::new(
+            vec![addr],
+            ChannelTransport::new(event_receiver, packet_sender),
+        );
+
+        client.send(1, &[1]).unwrap();
+        packet_receiver
+            .recv_timeout(Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(client.round_trip_time(addr).unwrap(), None);
+        thread::sleep(Duration::from_millis(20));
+
+        let payload = envelope(
+            7,
+            NetworkMessage::Inputs(NetworkInput {
+                sequence: Sequence(0),
+                ack: 1,
+                frame: 1,
+                inputs: smallvec![1],
+            }),
+        );
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(addr, payload)))
+            .unwrap();
+        assert!(wait_until(|| client.latest_acked_by(addr).unwrap() == 1));
+        let rtt = client.round_trip_time(addr).unwrap().unwrap();
+        assert!(rtt >= Duration::from_millis(20));
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn rematch_uses_the_announced_tokens() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, packet_receiver) = unbounded();
+        let client = Client::<u8, _>::new(
+            vec![addr],
+            ChannelTransport::new(event_receiver, packet_sender),
+        );
+
+        let payload = envelope(
+            7,
+            NetworkMessage::Rematch {
+                accept: true,
+                token: 9,
+            },
+        );
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(addr, payload)))
+            .unwrap();
+        assert!(wait_until(
+            || client.rematch_answer(addr).unwrap() == Some(true)
+        ));
+
+        client.offer_rematch(true).unwrap();
+        let packet = packet_receiver
+            .recv_timeout(Duration::from_millis(500))
+            .unwrap();
+        let next_token = match sent_message(packet) {
+            NetworkMessage::Rematch {
+                accept: true,
+                token,
+            } => token,
+            message => panic!("unexpected message {:?}", message),
+        };
+        let client = client.rematch().unwrap();
+        assert_eq!(client.token(), next_token);
+
+        // inputs still in flight from the previous match are discarded
+        for &(token, input) in &[(7, 5), (9, 1)] {
+            let payload = envelope(
+                token,
+                NetworkMessage::Inputs(NetworkInput {
+                    sequence: Sequence(0),
+                    ack: 0,
+                    frame: 1,
+                    inputs: smallvec![input],
+                }),
+            );
+            event_sender
+                .send(TransportEvent::Packet(Packet::unreliable(addr, payload)))
+                .unwrap();
+        }
+        assert!(wait_until(
+            || client.latest_confirmed_for(addr).unwrap() == 1
+        ));
+        assert_eq!(client.received_input(addr, 1).unwrap(), Some(1));
+        client.close().unwrap();
     }
 }
@@ -0,0 +1,21 @@
+//! Counters describing how well the netcode is doing during a session.
+
+use std::time::Duration;
+
+/// A snapshot of a session's netcode counters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SessionMetrics {
+    /// The amount of times the game was told to roll back.
+    pub rollbacks: u32,
+    /// The total amount of frames simulated again because of rollbacks.
+    pub frames_resimulated: u32,
+    /// The amount of remote inputs that turned out different from their predictions.
+    pub mispredicted_inputs: u32,
+    /// The amount of times the session was interrupted.
+    pub interruptions: u32,
+    /// The total time spent interrupted, including the current interruption.
+    pub stall_time: Duration,
+}
+
+/// Called with the latest metrics whenever they change due to a rollback or the end of an interruption.
+pub type MetricsCallback = Box<dyn FnMut(&SessionMetrics) + Send>;
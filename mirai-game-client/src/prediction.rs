@@ -0,0 +1,60 @@
+//! Strategies for predicting remote inputs that have not arrived yet.
+//!
+//! The game simulates ahead using predicted inputs and rolls back if a prediction turns out wrong,
+//! so a good strategy for the game in question directly reduces the amount of rollbacks.
+
+/// Decides what to use in place of a remote input that has not arrived yet.
+///
+/// Implemented for closures, so games can supply their own heuristics, e.g. repeating held
+/// directions while predicting that momentary buttons are released.
+pub trait Predict<I>: Send {
+    /// Predicts the input for `frame`, given the latest input received before it, if any.
+    fn predict(&self, previous: Option<I>, frame: u32) -> I;
+}
+
+/// Predicts that the previous input is still being held. The default strategy.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RepeatLast;
+
+impl<I: Default> Predict<I> for RepeatLast {
+    fn predict(&self, previous: Option<I>, _frame: u32) -> I {
+        previous.unwrap_or_default()
+    }
+}
+
+/// Predicts that no buttons are pressed, i.e. the default input.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Neutral;
+
+impl<I: Default> Predict<I> for Neutral {
+    fn predict(&self, _previous: Option<I>, _frame: u32) -> I {
+        I::default()
+    }
+}
+
+impl<I, F> Predict<I> for F
+where
+    F: Fn(Option<I>, u32) -> I + Send,
+{
+    fn predict(&self, previous: Option<I>, frame: u32) -> I {
+        self(previous, frame)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builtin_strategies() {
+        assert_eq!(RepeatLast.predict(Some(3u8), 1), 3);
+        assert_eq!(RepeatLast.predict(None::<u8>, 1), 0);
+        assert_eq!(Neutral.predict(Some(3u8), 1), 0);
+    }
+
+    #[test]
+    fn closures_are_strategies() {
+        let prediction = |previous: Option<u8>, _frame| previous.map_or(0, |p| p & 1);
+        assert_eq!(prediction.predict(Some(3), 1), 1);
+    }
+}
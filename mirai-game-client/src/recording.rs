@@ -0,0 +1,353 @@
+//! Signed logs of the confirmed inputs of a match, so tournament organizers can review disputed matches.
+//!
+//! A session with an `InputRecorder` hands it the inputs of every player once they are confirmed,
+//! see `Session::set_input_recorder`. Each frame is signed with HMAC-SHA-256 under a key the organizer
+//! hands out, chained to the signature of the frame before it, so a log cannot be altered, reordered
+//! or have frames left out of it without the key, which `verify_records` checks. It can still end early.
+//!
+//! The records are streamed to an `InputSink`, e.g. a `FileSink`, or an `UploadSink` that POSTs them
+//! to the organizer's server in batches. Like the server's webhooks, only `http://` URLs are supported,
+//! see `mirai_core::http`.
+
+use crossbeam_channel::{unbounded, Sender};
+use mirai_core::crypto::hmac;
+use mirai_core::http::Endpoint;
+use mirai_core::logging::{trace, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+use std::io::{self, Read, Write};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The version of the file format written by `FileSink`, stored at the start of each file.
+const FORMAT_VERSION: u32 = 1;
+
+/// The amount of frames an `UploadSink` sends in a single request.
+pub const UPLOAD_BATCH_FRAMES: usize = 600;
+
+/// How long an upload may take before it's given up on.
+pub const UPLOAD_TIMEOUT_MILLIS: u64 = 5000;
+
+/// The confirmed inputs of every player for a frame, starting from frame 1.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputRecord<I> {
+    pub frame: u32,
+    /// The local player's input first, then the peers' in the order of `Session::peers`.
+    pub inputs: Vec<I>,
+    /// HMAC-SHA-256 of the previous record's signature, the frame and the inputs.
+    pub signature: [u8; 32],
+}
+
+// the signature of the first record is chained to zeroes
+fn sign<I: Serialize>(
+    key: &[u8],
+    previous: &[u8; 32],
+    frame: u32,
+    inputs: &[I],
+) -> Result<[u8; 32], RecordingError> {
+    let inputs = bincode::serialize(inputs).context(SerializeError)?;
+    Ok(hmac(key, &[previous, &frame.to_le_bytes(), &inputs]))
+}
+
+/// Checks that the records are the log of a match from frame 1 on, signed with the given key.
+/// # Errors
+/// If a record was altered, moved or left out, or the log was signed with another key.
+pub fn verify_records<I: Serialize>(
+    key: &[u8],
+    records: &[InputRecord<I>],
+) -> Result<(), RecordingError> {
+    let mut previous = [0; 32];
+    for (expected, record) in (1..).zip(records) {
+        let signature = sign(key, &previous, record.frame, &record.inputs)?;
+        ensure!(
+            record.frame == expected && signature == record.signature,
+            BadSignature {
+                frame: record.frame
+            }
+        );
+        previous = record.signature;
+    }
+    Ok(())
+}
+
+/// Where the records of a match are streamed to.
+pub trait InputSink<I>: Send {
+    /// Takes the record of the next frame.
+    /// # Errors
+    /// If the record could not be stored or sent.
+    fn record(&mut self, record: &InputRecord<I>) -> Result<(), RecordingError>;
+
+    /// Called after the last record, once the match is over.
+    /// # Errors
+    /// If the records could not be stored or sent.
+    fn finish(&mut self) -> Result<(), RecordingError> {
+        Ok(())
+    }
+}
+
+/// Writes the records to a file or any other writer, to be read back with `read_records`.
+pub struct FileSink<W> {
+    writer: W,
+}
+
+impl<W: Write> FileSink<W> {
+    /// Writes the header of the log to the writer.
+    /// # Errors
+    /// If serializing or writing fails.
+    pub fn new(mut writer: W) -> Result<Self, RecordingError> {
+        bincode::serialize_into(&mut writer, &FORMAT_VERSION).context(SerializeError)?;
+        Ok(Self { writer })
+    }
+}
+
+impl<I: Serialize, W: Write + Send> InputSink<I> for FileSink<W> {
+    fn record(&mut self, record: &InputRecord<I>) -> Result<(), RecordingError> {
+        bincode::serialize_into(&mut self.writer, record).context(SerializeError)
+    }
+
+    fn finish(&mut self) -> Result<(), RecordingError> {
+        self.writer.flush().context(IoError)
+    }
+}
+
+/// Reads the records written by a `FileSink`.
+/// # Errors
+/// If reading or deserializing fails, or the log was written in an unsupported format.
+pub fn read_records<I: DeserializeOwned, R: Read>(
+    mut reader: R,
+) -> Result<Vec<InputRecord<I>>, RecordingError> {
+    let version: u32 = bincode::deserialize_from(&mut reader).context(DeserializeError)?;
+    ensure!(version == FORMAT_VERSION, UnsupportedVersion { version });
+    let mut records = vec![];
+    loop {
+        match bincode::deserialize_from(&mut reader) {
+            Ok(record) => records.push(record),
+            // the log ends where the reader does
+            Err(e) if is_eof(&e) => return Ok(records),
+            Err(e) => return Err(e).context(DeserializeError),
+        }
+    }
+}
+
+fn is_eof(e: &bincode::ErrorKind) -> bool {
+    matches!(e, bincode::ErrorKind::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+/// POSTs the records as JSON arrays of up to `UPLOAD_BATCH_FRAMES` records on a thread of its own.
+/// A failed upload isn't retried, but is reported when the sink is finished.
+pub struct UploadSink<I> {
+    batch: Vec<InputRecord<I>>,
+    sender: Option<Sender<Vec<InputRecord<I>>>>,
+    // returns the amount of batches that failed to upload
+    uploader: Option<JoinHandle<u32>>,
+}
+
+impl<I: Serialize + Send + 'static> UploadSink<I> {
+    /// Uploads the records to the given `http://` URL, e.g. one that identifies the match,
+    /// or returns None if it's something else.
+    pub fn new(url: &str) -> Option<Self> {
+        let endpoint = Endpoint::parse(url)?;
+        let (sender, receiver) = unbounded::<Vec<InputRecord<I>>>();
+        let uploader = thread::spawn(move || {
+            let timeout = Duration::from_millis(UPLOAD_TIMEOUT_MILLIS);
+            let mut failed = 0;
+            for batch in receiver {
+                let body = match serde_json::to_vec(&batch) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        warn!("could not serialize the input records: {}", e);
+                        failed += 1;
+                        continue;
+                    }
+                };
+                match endpoint.post_json(&body, timeout) {
+                    Ok(status) if (200..300).contains(&status) => {
+                        trace!("uploaded {} input records", batch.len())
+                    }
+                    Ok(status) => {
+                        warn!("the input record endpoint answered {}", status);
+                        failed += 1;
+                    }
+                    Err(e) => {
+                        warn!("failed to upload input records: {}", e);
+                        failed += 1;
+                    }
+                }
+            }
+            failed
+        });
+        Some(Self {
+            batch: vec![],
+            sender: Some(sender),
+            uploader: Some(uploader),
+        })
+    }
+
+    fn send_batch(&mut self) -> Result<(), RecordingError> {
+        let batch = std::mem::take(&mut self.batch);
+        match &self.sender {
+            Some(sender) if !batch.is_empty() => sender
+                .send(batch)
+                .map_err(|_| RecordingError::UploaderStopped),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<I: Clone + Serialize + Send + 'static> InputSink<I> for UploadSink<I> {
+    fn record(&mut self, record: &InputRecord<I>) -> Result<(), RecordingError> {
+        self.batch.push(record.clone());
+        if self.batch.len() >= UPLOAD_BATCH_FRAMES {
+            self.send_batch()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), RecordingError> {
+        self.send_batch()?;
+        self.sender = None;
+        let failed = match self.uploader.take() {
+            Some(uploader) => uploader
+                .join()
+                .map_err(|_| RecordingError::UploaderStopped)?,
+            None => 0,
+        };
+        ensure!(failed == 0, UploadFailed { batches: failed });
+        Ok(())
+    }
+}
+
+/// Signs the confirmed inputs of a match and streams them to a sink.
+pub struct InputRecorder<I> {
+    key: Vec<u8>,
+    sink: Box<dyn InputSink<I>>,
+    previous: [u8; 32],
+    recorded: u32,
+}
+
+impl<I: Serialize> InputRecorder<I> {
+    /// Creates a recorder that signs the records with the key the organizer handed out.
+    pub fn new<S: InputSink<I> + 'static>(key: &[u8], sink: S) -> Self {
+        Self {
+            key: key.to_vec(),
+            sink: Box::new(sink),
+            previous: [0; 32],
+            recorded: 0,
+        }
+    }
+
+    /// Returns the latest recorded frame.
+    pub fn recorded(&self) -> u32 {
+        self.recorded
+    }
+
+    /// Signs the inputs of every player for the next frame and passes them to the sink.
+    /// # Errors
+    /// If serializing the inputs fails or the sink could not take the record.
+    pub fn record(&mut self, inputs: Vec<I>) -> Result<(), RecordingError> {
+        let frame = self.recorded + 1;
+        let signature = sign(&self.key, &self.previous, frame, &inputs)?;
+        let record = InputRecord {
+            frame,
+            inputs,
+            signature,
+        };
+        self.sink.record(&record)?;
+        self.previous = signature;
+        self.recorded = frame;
+        Ok(())
+    }
+
+    /// Lets the sink know the match is over.
+    /// # Errors
+    /// If the sink could not store or send the records.
+    pub fn finish(mut self) -> Result<(), RecordingError> {
+        self.sink.finish()
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum RecordingError {
+    IoError { source: io::Error },
+    SerializeError { source: Box<bincode::ErrorKind> },
+    DeserializeError { source: Box<bincode::ErrorKind> },
+    UnsupportedVersion { version: u32 },
+    BadSignature { frame: u32 },
+    UploaderStopped,
+    UploadFailed { batches: u32 },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const KEY: &[u8] = b"organizer key";
+
+    // keeps the records where the test can see them
+    struct Collect(Arc<Mutex<Vec<InputRecord<u8>>>>);
+
+    impl InputSink<u8> for Collect {
+        fn record(&mut self, record: &InputRecord<u8>) -> Result<(), RecordingError> {
+            self.0.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    fn recorded(frames: &[[u8; 2]]) -> Vec<InputRecord<u8>> {
+        let records = Arc::new(Mutex::new(vec![]));
+        let mut recorder = InputRecorder::new(KEY, Collect(Arc::clone(&records)));
+        for inputs in frames {
+            recorder.record(inputs.to_vec()).unwrap();
+        }
+        assert_eq!(recorder.recorded(), frames.len() as u32);
+        recorder.finish().unwrap();
+        let records = records.lock().unwrap().clone();
+        records
+    }
+
+    #[test]
+    fn tampered_logs_fail_verification() {
+        let records = recorded(&[[1, 2], [3, 4], [5, 6]]);
+        assert!(verify_records(KEY, &records).is_ok());
+        assert!(matches!(
+            verify_records(b"another key", &records),
+            Err(RecordingError::BadSignature { frame: 1 })
+        ));
+
+        let mut altered = records.clone();
+        altered[1].inputs[0] = 9;
+        assert!(matches!(
+            verify_records(KEY, &altered),
+            Err(RecordingError::BadSignature { frame: 2 })
+        ));
+
+        let mut cut = records.clone();
+        cut.remove(1);
+        assert!(matches!(
+            verify_records(KEY, &cut),
+            Err(RecordingError::BadSignature { frame: 3 })
+        ));
+
+        // a log that ends early is still a valid log
+        assert!(verify_records(KEY, &records[..2]).is_ok());
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let records = recorded(&[[1, 2], [3, 4]]);
+        let mut bytes = vec![];
+        let mut sink = FileSink::new(&mut bytes).unwrap();
+        for record in &records {
+            sink.record(record).unwrap();
+        }
+        InputSink::<u8>::finish(&mut sink).unwrap();
+        assert_eq!(read_records::<u8, _>(bytes.as_slice()).unwrap(), records);
+
+        bytes[0] = 2;
+        assert!(matches!(
+            read_records::<u8, _>(bytes.as_slice()),
+            Err(RecordingError::UnsupportedVersion { version: 2 })
+        ));
+    }
+}
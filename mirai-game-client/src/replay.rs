@@ -0,0 +1,113 @@
+//! Recordings of matches.
+//!
+//! Since the simulation is deterministic, the confirmed inputs of every player are enough
+//! to play a match back exactly as it happened.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+use std::io::{Read, Write};
+
+/// The version of the replay file format, stored at the start of each file.
+const FORMAT_VERSION: u32 = 1;
+
+/// The inputs of every player of a match, starting from frame 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Replay<I> {
+    players: usize,
+    // inputs[frame - 1][player]
+    inputs: Vec<Vec<I>>,
+}
+
+impl<I: Copy + Serialize + DeserializeOwned> Replay<I> {
+    /// Creates an empty replay for the given amount of players.
+    pub fn new(players: usize) -> Self {
+        Self {
+            players,
+            inputs: vec![],
+        }
+    }
+
+    /// Records the inputs of every player for the next frame.
+    /// # Panics
+    /// If there is not exactly one input per player.
+    pub fn record(&mut self, inputs: Vec<I>) {
+        assert_eq!(inputs.len(), self.players, "expected one input per player");
+        self.inputs.push(inputs);
+    }
+
+    /// Returns the amount of players.
+    pub fn players(&self) -> usize {
+        self.players
+    }
+
+    /// Returns the latest recorded frame.
+    pub fn frames(&self) -> u32 {
+        self.inputs.len() as u32
+    }
+
+    /// Returns the inputs of every player for the given frame, if it has been recorded.
+    pub fn inputs_for(&self, frame: u32) -> Option<&[I]> {
+        let index = frame.checked_sub(1)? as usize;
+        self.inputs.get(index).map(Vec::as_slice)
+    }
+
+    /// Returns the inputs of the given player, starting from frame 1.
+    pub fn player_inputs(&self, player: usize) -> Vec<I> {
+        self.inputs.iter().map(|inputs| inputs[player]).collect()
+    }
+
+    /// Writes the replay to the given writer.
+    /// # Errors
+    /// If serializing or writing fails.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), ReplayError> {
+        bincode::serialize_into(&mut writer, &FORMAT_VERSION).context(SerializeError)?;
+        bincode::serialize_into(&mut writer, self).context(SerializeError)
+    }
+
+    /// Reads a replay written by `write`.
+    /// # Errors
+    /// If reading or deserializing fails, or the replay was written in an unsupported format.
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, ReplayError> {
+        let version: u32 = bincode::deserialize_from(&mut reader).context(DeserializeError)?;
+        ensure!(version == FORMAT_VERSION, UnsupportedVersion { version });
+        bincode::deserialize_from(&mut reader).context(DeserializeError)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum ReplayError {
+    SerializeError { source: Box<bincode::ErrorKind> },
+    DeserializeError { source: Box<bincode::ErrorKind> },
+    UnsupportedVersion { version: u32 },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_inputs_per_frame() {
+        let mut replay = Replay::new(2);
+        replay.record(vec![1u8, 2]);
+        replay.record(vec![3, 4]);
+        assert_eq!(replay.frames(), 2);
+        assert_eq!(replay.inputs_for(0), None);
+        assert_eq!(replay.inputs_for(2), Some(&[3, 4][..]));
+        assert_eq!(replay.player_inputs(1), vec![2, 4]);
+    }
+
+    #[test]
+    fn replays_round_trip() {
+        let mut replay = Replay::new(2);
+        replay.record(vec![1u8, 2]);
+        let mut bytes = vec![];
+        replay.write(&mut bytes).unwrap();
+        assert_eq!(Replay::read(bytes.as_slice()).unwrap(), replay);
+
+        bytes[0] = 2;
+        match Replay::<u8>::read(bytes.as_slice()) {
+            Err(ReplayError::UnsupportedVersion { version: 2 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
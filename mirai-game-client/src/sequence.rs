@@ -0,0 +1,153 @@
+//! Sequence numbers for unreliable input packets.
+//!
+//! Every input packet is tagged with a wrapping sequence number by the sender.
+//! The receiver keeps a replay window over the most recent sequence numbers so that
+//! duplicated and out-of-order packets are discarded instead of being applied
+//! on top of newer data.
+
+use serde::{Deserialize, Serialize};
+
+/// The amount of sequence numbers behind the latest one that are remembered.
+const WINDOW_SIZE: u16 = 64;
+
+/// A wrapping sequence number attached to each outgoing input packet.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub struct Sequence(pub u16);
+
+impl Sequence {
+    /// Returns the sequence number following this one.
+    pub fn next(self) -> Self {
+        Sequence(self.0.wrapping_add(1))
+    }
+
+    /// Checks whether this sequence number is more recent than the other one, taking wraparound into account.
+    pub fn is_newer_than(self, other: Sequence) -> bool {
+        let diff = self.0.wrapping_sub(other.0);
+        diff != 0 && diff <= u16::MAX / 2
+    }
+}
+
+/// The result of checking a received sequence number against the replay window.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SequenceCheck {
+    /// The packet is newer than anything received so far and should be processed.
+    Fresh,
+    /// The packet has already been received.
+    Duplicate,
+    /// The packet arrived after a newer one and should be discarded.
+    Stale,
+}
+
+/// Tracks received sequence numbers for one direction of traffic.
+#[derive(Debug, Default, Clone)]
+pub struct ReplayWindow {
+    latest: Option<Sequence>,
+    // bit n is set if latest - n - 1 has been received
+    received: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the most recent sequence number accepted by the window.
+    pub fn latest(&self) -> Option<Sequence> {
+        self.latest
+    }
+
+    /// Checks the given sequence number and records it as received.
+    /// Only `SequenceCheck::Fresh` packets should be processed further.
+    pub fn check(&mut self, sequence: Sequence) -> SequenceCheck {
+        let latest = match self.latest {
+            Some(latest) => latest,
+            None => {
+                self.latest = Some(sequence);
+                return SequenceCheck::Fresh;
+            }
+        };
+        if sequence == latest {
+            SequenceCheck::Duplicate
+        } else if sequence.is_newer_than(latest) {
+            let shift = sequence.0.wrapping_sub(latest.0);
+            self.received = if shift > WINDOW_SIZE {
+                0
+            } else {
+                // the previous latest is now shift behind
+                self.received.checked_shl(u32::from(shift)).unwrap_or(0) | (1 << (shift - 1))
+            };
+            self.latest = Some(sequence);
+            SequenceCheck::Fresh
+        } else {
+            let behind = latest.0.wrapping_sub(sequence.0);
+            if behind > WINDOW_SIZE {
+                return SequenceCheck::Stale;
+            }
+            let bit = 1 << (behind - 1);
+            if self.received & bit != 0 {
+                SequenceCheck::Duplicate
+            } else {
+                self.received |= bit;
+                SequenceCheck::Stale
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn newer_wraps_around() {
+        assert!(Sequence(1).is_newer_than(Sequence(0)));
+        assert!(!Sequence(0).is_newer_than(Sequence(1)));
+        assert!(!Sequence(5).is_newer_than(Sequence(5)));
+        assert!(Sequence(0).is_newer_than(Sequence(u16::MAX)));
+        assert!(Sequence(10).is_newer_than(Sequence(u16::MAX - 10)));
+        assert_eq!(Sequence(u16::MAX).next(), Sequence(0));
+    }
+
+    #[test]
+    fn in_order_packets_are_fresh() {
+        let mut window = ReplayWindow::new();
+        for i in 0..200 {
+            assert_eq!(window.check(Sequence(i)), SequenceCheck::Fresh);
+        }
+        assert_eq!(window.latest(), Some(Sequence(199)));
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let mut window = ReplayWindow::new();
+        assert_eq!(window.check(Sequence(3)), SequenceCheck::Fresh);
+        assert_eq!(window.check(Sequence(3)), SequenceCheck::Duplicate);
+        assert_eq!(window.check(Sequence(4)), SequenceCheck::Fresh);
+        assert_eq!(window.check(Sequence(3)), SequenceCheck::Duplicate);
+    }
+
+    #[test]
+    fn late_packets_are_stale() {
+        let mut window = ReplayWindow::new();
+        assert_eq!(window.check(Sequence(1)), SequenceCheck::Fresh);
+        assert_eq!(window.check(Sequence(3)), SequenceCheck::Fresh);
+        assert_eq!(window.check(Sequence(2)), SequenceCheck::Stale);
+        // the late packet is remembered
+        assert_eq!(window.check(Sequence(2)), SequenceCheck::Duplicate);
+        // too far behind the window to tell
+        assert_eq!(window.check(Sequence(100)), SequenceCheck::Fresh);
+        assert_eq!(window.check(Sequence(3)), SequenceCheck::Stale);
+    }
+
+    #[test]
+    fn window_survives_wraparound() {
+        let mut window = ReplayWindow::new();
+        assert_eq!(window.check(Sequence(u16::MAX - 1)), SequenceCheck::Fresh);
+        assert_eq!(window.check(Sequence(1)), SequenceCheck::Fresh);
+        assert_eq!(window.check(Sequence(u16::MAX)), SequenceCheck::Stale);
+        assert_eq!(
+            window.check(Sequence(u16::MAX - 1)),
+            SequenceCheck::Duplicate
+        );
+    }
+}
@@ -0,0 +1,1113 @@
+//! The session keeps track of the local player's inputs during a match and
+//! exchanges them with the other peers through the game client.
+//!
+//! Input history is kept in fixed-size buffers. Once the game has saved its state
+//! for some frame, the inputs before it are no longer needed for rollback and are discarded.
+//!
+//! When the game uses a remote input that has not arrived yet, the session remembers the prediction.
+//! Once the real input arrives and differs from the prediction, the game is told to roll back
+//! to the latest state it saved before the mispredicted frame. States are saved every
+//! `save_interval` frames, so the game never has to re-simulate from further back than that.
+//!
+//! The game may only run ahead of the latest fully confirmed frame by a limited amount of frames.
+//! If the peers fall further behind, the session is interrupted and the game should stall
+//! until the missing inputs arrive. While interrupted, the peers are asked to send the inputs
+//! they have missed again, so the match can continue after a brief disconnection.
+//! If the interruption lasts longer than the disconnect timeout, the session is disconnected for good.
+//!
+//! Rollbacks, mispredictions and interruptions are counted in `SessionMetrics`.
+//!
+//! With an `InputRecorder`, the inputs of every player are signed and recorded as soon as
+//! every peer's inputs have been confirmed for the frame, before they are discarded.
+//! If the recorder fails, the match goes on without it and `SessionEvent::RecordingFailed` is reported.
+//!
+//! Before the match starts, the session can agree on a tick rate with the peers. The local offer is sent again
+//! every `TICK_RATE_RESEND_INTERVAL` until every peer's offer has arrived, in case a peer's session wasn't
+//! listening yet. Once they agree, the local inputs are only sent at the agreed input rate.
+//!
+//! Once the match is over, the session can negotiate a rematch with the peers. A rematch starts a new session
+//! on the same socket, otherwise the socket can be handed back to the matchmaking client.
+
+use crate::{
+    Client, ClientError, InputBuffer, InputRecorder, InputWindow, MetricsCallback, NetInput,
+    Predict, RecordingFailed, RepeatLast, SessionMetrics, TickRate, TickRateStatus,
+    DEFAULT_HISTORY_DEPTH, INPUT_WINDOW,
+};
+use mirai_core::clock::{Clock, SystemClock};
+use mirai_core::logging::{debug, info, warn};
+use mirai_core::transport::Transport;
+use snafu::ResultExt;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The amount of previous inputs sent along with each new input.
+const INPUT_REDUNDANCY: usize = INPUT_WINDOW;
+/// The default maximum amount of frames the game may roll back.
+const DEFAULT_MAX_ROLLBACK_DEPTH: u32 = 8;
+/// By default the game state is saved every frame.
+const DEFAULT_SAVE_INTERVAL: u32 = 1;
+/// By default peers have ten seconds to reconnect.
+const DEFAULT_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the peers' tick rates before offering the local ones again.
+const TICK_RATE_RESEND_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Parameters for a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionConfig {
+    /// The amount of frames of input history kept for the local player and for each peer.
+    /// Should comfortably exceed `max_rollback_depth`.
+    pub history_depth: usize,
+    /// The maximum amount of frames the local frame may be ahead of the latest fully confirmed frame.
+    pub max_rollback_depth: u32,
+    /// The game state should be saved on every frame divisible by this.
+    /// Larger values mean fewer saves but longer re-simulations after a misprediction.
+    pub save_interval: u32,
+    /// How long the session may stay interrupted before it is disconnected.
+    pub disconnect_timeout: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            history_depth: DEFAULT_HISTORY_DEPTH,
+            max_rollback_depth: DEFAULT_MAX_ROLLBACK_DEPTH,
+            save_interval: DEFAULT_SAVE_INTERVAL,
+            disconnect_timeout: DEFAULT_DISCONNECT_TIMEOUT,
+        }
+    }
+}
+
+/// Whether the game can keep advancing.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SessionState {
+    Running,
+    /// The peers have fallen too far behind, the game should not advance until they catch up.
+    Interrupted,
+    /// The peers did not catch up before the disconnect timeout, the match cannot continue.
+    Disconnected,
+}
+
+/// Notable changes in the session.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SessionEvent {
+    /// Confirmation fell behind the local frame by more than the maximum rollback depth.
+    ConnectionInterrupted {
+        local_frame: u32,
+        confirmed_frame: u32,
+    },
+    /// Confirmation caught up after an interruption.
+    ConnectionResumed,
+    /// The interruption lasted longer than the disconnect timeout.
+    Disconnected,
+    /// The peer's packets started arriving from a new address.
+    PeerReconnected { peer: SocketAddr },
+    /// The input recorder could not record the frame, the rest of the match is not recorded.
+    RecordingFailed { frame: u32 },
+}
+
+/// Whether a rematch is going to happen.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RematchStatus {
+    /// Waiting for the local player or some of the peers to answer.
+    Pending,
+    /// Everyone accepted, the rematch can be started with `rematch`.
+    Accepted,
+    /// Someone declined.
+    Declined,
+}
+
+/// A match in progress.
+pub struct Session<I, T: Transport> {
+    client: Client<I, T>,
+    config: SessionConfig,
+    local_inputs: InputBuffer<I>,
+    latest_local: u32,
+    // remote inputs handed out to the game before they were confirmed
+    predictions: HashMap<SocketAddr, InputBuffer<I>>,
+    // the latest confirmed frame checked against the predictions for each peer
+    checked: HashMap<SocketAddr, u32>,
+    saved_frames: VecDeque<u32>,
+    prediction: Box<dyn Predict<I>>,
+    state: SessionState,
+    events: VecDeque<SessionEvent>,
+    metrics: SessionMetrics,
+    metrics_callback: Option<MetricsCallback>,
+    recorder: Option<InputRecorder<I>>,
+    clock: Box<dyn Clock>,
+    interrupted_since: Option<Instant>,
+    // the local answer to a rematch
+    rematch_answer: Option<bool>,
+    // the tick rates offered to the peers
+    tick_rates: Option<Vec<TickRate>>,
+    // when the tick rates were last offered
+    tick_rates_offered: Option<Instant>,
+    // the rate the peers agreed on, which the local inputs are sent at
+    tick_rate: Option<TickRate>,
+}
+
+impl<I: NetInput, T: Transport> Session<I, T> {
+    /// Starts a session against the given peers using the transport handed over by the matchmaking client.
+    /// # Panics
+    /// If `config.history_depth` or `config.save_interval` is zero.
+    pub fn new(peers: Vec<SocketAddr>, transport: T, config: SessionConfig) -> Self {
+        assert!(config.save_interval > 0, "save interval must be positive");
+        let client = Client::with_history_depth(peers, transport, config.history_depth);
+        Self::with_client(client, config)
+    }
+
+    fn with_client(client: Client<I, T>, config: SessionConfig) -> Self {
+        let peers = client.peers();
+        let predictions = peers
+            .iter()
+            .map(|&peer| (peer, InputBuffer::new(config.history_depth)))
+            .collect();
+        let checked = peers.iter().map(|&peer| (peer, 0)).collect();
+        let mut local_inputs = InputBuffer::new(config.history_depth);
+        local_inputs.insert(0, I::default());
+        let mut saved_frames = VecDeque::new();
+        // the initial state is always known
+        saved_frames.push_back(0);
+        Self {
+            client,
+            config,
+            local_inputs,
+            latest_local: 0,
+            predictions,
+            checked,
+            saved_frames,
+            prediction: Box::new(RepeatLast),
+            state: SessionState::Running,
+            events: VecDeque::new(),
+            metrics: SessionMetrics::default(),
+            metrics_callback: None,
+            recorder: None,
+            clock: Box::new(SystemClock),
+            interrupted_since: None,
+            rematch_answer: None,
+            tick_rates: None,
+            tick_rates_offered: None,
+            tick_rate: None,
+        }
+    }
+
+    /// Records the local input for the given frame and sends it to every peer.
+    /// # Errors
+    /// If the frame does not fit in the input history because the game has not saved its state
+    /// recently enough, or if the handler thread has stopped.
+    pub fn add_local_input(&mut self, frame: u32, input: I) -> Result<(), ClientError> {
+        if !self.local_inputs.insert(frame, input) {
+            return Err(ClientError::HistoryFull { frame });
+        }
+        self.latest_local = std::cmp::max(self.latest_local, frame);
+        if !frame.is_multiple_of(self.input_interval()) {
+            // sent along with a later frame's input
+            return Ok(());
+        }
+        let inputs: InputWindow<I> = self
+            .local_inputs
+            .recent_iter(frame, INPUT_REDUNDANCY)
+            .collect();
+        self.client.send(frame, &inputs)
+    }
+
+    /// Checks how far behind confirmation is and updates the session state accordingly.
+    /// While interrupted, the latest local inputs are sent again in case they were lost.
+    /// Likewise, the local answer to a rematch is sent again until the rematch is settled,
+    /// and the local tick rates every `TICK_RATE_RESEND_INTERVAL` until the peers' offers arrive.
+    /// Should be called every frame before advancing the game.
+    /// # Errors
+    /// If the handler thread has stopped.
+    pub fn update(&mut self) -> Result<SessionState, ClientError> {
+        for peer in self.client.reconnected_peers() {
+            info!("{} reconnected", peer);
+            self.events
+                .push_back(SessionEvent::PeerReconnected { peer });
+        }
+        self.record_confirmed()?;
+        if let SessionState::Disconnected = self.state {
+            return Ok(self.state);
+        }
+        if let (Some(accept), RematchStatus::Pending) =
+            (self.rematch_answer, self.rematch_status()?)
+        {
+            self.client.offer_rematch(accept)?;
+        }
+        if self.tick_rates.is_some() && self.tick_rate.is_none() {
+            match self.tick_rate_status()? {
+                TickRateStatus::Pending => self.resend_tick_rates()?,
+                TickRateStatus::Agreed(rate) => {
+                    info!("agreed on {:?}", rate);
+                    self.tick_rate = Some(rate);
+                }
+                TickRateStatus::NoCommonRate => {}
+            }
+        }
+        let confirmed_frame = self.latest_fully_confirmed()?;
+        let behind = self.latest_local.saturating_sub(confirmed_frame);
+        match self.state {
+            SessionState::Running if behind >= self.config.max_rollback_depth => {
+                info!(
+                    "connection interrupted at frame {}, confirmed {}",
+                    self.latest_local, confirmed_frame
+                );
+                self.state = SessionState::Interrupted;
+                self.events.push_back(SessionEvent::ConnectionInterrupted {
+                    local_frame: self.latest_local,
+                    confirmed_frame,
+                });
+                self.metrics.interruptions += 1;
+                self.interrupted_since = Some(self.clock.now());
+            }
+            SessionState::Interrupted if behind < self.config.max_rollback_depth => {
+                info!("connection resumed");
+                self.state = SessionState::Running;
+                self.events.push_back(SessionEvent::ConnectionResumed);
+                if let Some(since) = self.interrupted_since.take() {
+                    self.metrics.stall_time += self.clock.now().saturating_duration_since(since);
+                }
+                self.report_metrics();
+            }
+            SessionState::Interrupted
+                if self.interrupted_since.is_some_and(|since| {
+                    self.clock.now().saturating_duration_since(since)
+                        >= self.config.disconnect_timeout
+                }) =>
+            {
+                info!("disconnected at frame {}", self.latest_local);
+                self.state = SessionState::Disconnected;
+                self.events.push_back(SessionEvent::Disconnected);
+                return Ok(self.state);
+            }
+            _ => {}
+        }
+        if let SessionState::Interrupted = self.state {
+            debug!("resending inputs for {}", self.latest_local);
+            let inputs: InputWindow<I> = self
+                .local_inputs
+                .recent_iter(self.latest_local, INPUT_REDUNDANCY)
+                .collect();
+            self.client.send(self.latest_local, &inputs)?;
+            self.client.request_resync()?;
+        }
+        Ok(self.state)
+    }
+
+    /// Returns the current state of the session.
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Returns the events that have occurred since the last call.
+    pub fn events(&mut self) -> Vec<SessionEvent> {
+        self.events.drain(..).collect()
+    }
+
+    /// Returns a snapshot of the session's netcode counters.
+    pub fn metrics(&self) -> SessionMetrics {
+        let mut metrics = self.metrics;
+        if let Some(since) = self.interrupted_since {
+            metrics.stall_time += self.clock.now().saturating_duration_since(since);
+        }
+        metrics
+    }
+
+    /// Sets a callback that is called with the latest metrics after every rollback
+    /// and at the end of every interruption.
+    pub fn set_metrics_callback<F: FnMut(&SessionMetrics) + Send + 'static>(
+        &mut self,
+        callback: F,
+    ) {
+        self.metrics_callback = Some(Box::new(callback));
+    }
+
+    fn report_metrics(&mut self) {
+        let metrics = self.metrics();
+        if let Some(callback) = &mut self.metrics_callback {
+            callback(&metrics);
+        }
+    }
+
+    /// Sets the recorder the confirmed inputs of the match are signed and streamed with.
+    /// The recorder is finished when the session is closed or rematched, a rematch has to be given a new one.
+    pub fn set_input_recorder(&mut self, recorder: InputRecorder<I>) {
+        self.recorder = Some(recorder);
+    }
+
+    // records the frames confirmed since the last call, the local input first and then the peers'
+    fn record_confirmed(&mut self) -> Result<(), ClientError> {
+        let recorder = match &mut self.recorder {
+            Some(recorder) => recorder,
+            None => return Ok(()),
+        };
+        let confirmed = std::cmp::min(self.client.latest_fully_confirmed()?, self.latest_local);
+        for frame in recorder.recorded() + 1..=confirmed {
+            let mut inputs = vec![self.local_inputs.latest_at(frame).unwrap_or_default()];
+            for &peer in self.client.peers() {
+                inputs.push(self.client.input_for(peer, frame)?);
+            }
+            if let Err(e) = recorder.record(inputs) {
+                // the match goes on without the recording
+                warn!("could not record the inputs of frame {}: {}", frame, e);
+                self.recorder = None;
+                self.events
+                    .push_back(SessionEvent::RecordingFailed { frame });
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the rest of the confirmed frames and lets the sink know the match is over.
+    /// Closing, rematching or taking the socket back finishes the recording too, but only logs the errors.
+    /// # Errors
+    /// If the sink could not store or send the records, or the handler thread has panicked.
+    pub fn finish_recording(&mut self) -> Result<(), ClientError> {
+        self.record_confirmed()?;
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish().context(RecordingFailed),
+            None => Ok(()),
+        }
+    }
+
+    fn finish_recording_or_warn(&mut self) {
+        if let Err(e) = self.finish_recording() {
+            warn!("could not finish recording the inputs: {}", e);
+        }
+    }
+
+    /// Returns the local input for the given frame, if not available then the latest input before it.
+    pub fn local_input_for(&self, frame: u32) -> I {
+        self.local_inputs.latest_at(frame).unwrap_or_default()
+    }
+
+    /// Returns the given peer's input for the given frame, if not available then
+    /// an input predicted with the session's prediction strategy.
+    /// Predicted inputs are remembered so that they can be checked once the real input arrives.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn remote_input_for(&mut self, peer: SocketAddr, frame: u32) -> Result<I, ClientError> {
+        let input = match self.client.received_input(peer, frame)? {
+            Some(input) => input,
+            None => {
+                let previous = self.client.latest_received(peer, frame)?;
+                self.prediction.predict(previous, frame)
+            }
+        };
+        if frame > self.client.latest_confirmed_for(peer)? {
+            if let Some(predictions) = self.predictions.get_mut(&peer) {
+                predictions.set(frame, input);
+            }
+        }
+        Ok(input)
+    }
+
+    /// Sets the strategy used to predict remote inputs that have not arrived yet.
+    /// Defaults to `RepeatLast`.
+    pub fn set_prediction<P: Predict<I> + 'static>(&mut self, prediction: P) {
+        self.prediction = Box::new(prediction);
+    }
+
+    /// Sets the clock the interruptions and tick rate offers are timed with, e.g. a `ManualClock`
+    /// that a test advances past the disconnect timeout. Defaults to `SystemClock`.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Checks the predicted inputs against the inputs confirmed since the last call.
+    /// Returns the earliest frame that was simulated with a mispredicted input, if any.
+    /// The game should then load the latest state saved before that frame and simulate forward again.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn rollback_frame(&mut self) -> Result<Option<u32>, ClientError> {
+        let mut rollback_frame = None;
+        for (&peer, predictions) in &mut self.predictions {
+            let checked = self.checked.get_mut(&peer).expect("peer without check");
+            let confirmed = self.client.latest_confirmed_for(peer)?;
+            for frame in *checked + 1..=confirmed {
+                if let Some(predicted) = predictions.get(frame) {
+                    if predicted != self.client.input_for(peer, frame)? {
+                        debug!("mispredicted input from {} for {}", peer, frame);
+                        self.metrics.mispredicted_inputs += 1;
+                        rollback_frame = Some(match rollback_frame {
+                            Some(earlier) if earlier < frame => earlier,
+                            _ => frame,
+                        });
+                    }
+                }
+            }
+            *checked = std::cmp::max(*checked, confirmed);
+            predictions.prune(*checked + 1);
+        }
+        if let Some(frame) = rollback_frame {
+            // states saved after the misprediction are no longer valid
+            while let Some(&saved) = self.saved_frames.back() {
+                if saved < frame || self.saved_frames.len() == 1 {
+                    break;
+                }
+                self.saved_frames.pop_back();
+            }
+            self.metrics.rollbacks += 1;
+            self.metrics.frames_resimulated +=
+                self.latest_local.saturating_sub(self.latest_saved());
+            self.report_metrics();
+        }
+        Ok(rollback_frame)
+    }
+
+    /// Checks whether the game should save its state after simulating the given frame.
+    pub fn should_save(&self, frame: u32) -> bool {
+        frame.is_multiple_of(self.config.save_interval)
+    }
+
+    /// Returns the largest frame f where the inputs for 0..=f have been received from every peer.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn latest_fully_confirmed(&self) -> Result<u32, ClientError> {
+        self.client.latest_fully_confirmed()
+    }
+
+    /// Lets the session know the game has saved its state at the given frame.
+    /// Inputs older than the restore point are discarded.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn state_saved(&mut self, frame: u32) -> Result<(), ClientError> {
+        self.record_confirmed()?;
+        while let Some(&saved) = self.saved_frames.back() {
+            if saved < frame {
+                break;
+            }
+            self.saved_frames.pop_back();
+        }
+        self.saved_frames.push_back(frame);
+
+        let confirmed = self.latest_fully_confirmed()?;
+        while self.saved_frames.len() > 1 && self.saved_frames[1] <= confirmed {
+            self.saved_frames.pop_front();
+        }
+        let restore_point = self.restore_point();
+        self.local_inputs.prune(restore_point);
+        self.client.prune(restore_point)?;
+        Ok(())
+    }
+
+    /// Returns the frame of the latest saved state that can never be rolled past,
+    /// i.e. the latest state saved at or before the latest fully confirmed frame.
+    /// States saved before it are no longer needed.
+    pub fn restore_point(&self) -> u32 {
+        *self.saved_frames.front().expect("no saved states")
+    }
+
+    /// Returns the frame of the latest saved state.
+    pub fn latest_saved(&self) -> u32 {
+        *self.saved_frames.back().expect("no saved states")
+    }
+
+    /// Returns the estimated round trip time to the given peer, if it has acknowledged any inputs yet.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn round_trip_time(&self, peer: SocketAddr) -> Result<Option<Duration>, ClientError> {
+        self.client.round_trip_time(peer)
+    }
+
+    /// Returns how many frames the local player is ahead of the latest confirmed input from the given peer.
+    /// A large advantage means the game is running ahead of the peer and predicting more of its inputs.
+    /// # Errors
+    /// If the peer is not part of the match or the handler thread has panicked.
+    pub fn frame_advantage(&self, peer: SocketAddr) -> Result<i64, ClientError> {
+        let confirmed = self.client.latest_confirmed_for(peer)?;
+        Ok(i64::from(self.latest_local) - i64::from(confirmed))
+    }
+
+    /// Offers the tick rates the local build supports to every peer.
+    /// The game should wait for `tick_rate_status` to agree on one before it starts simulating.
+    /// # Errors
+    /// If the handler thread has stopped.
+    pub fn offer_tick_rates(&mut self, rates: &[TickRate]) -> Result<(), ClientError> {
+        self.tick_rates = Some(rates.to_vec());
+        self.tick_rates_offered = Some(self.clock.now());
+        self.client.offer_tick_rates(rates)
+    }
+
+    fn resend_tick_rates(&mut self) -> Result<(), ClientError> {
+        let now = self.clock.now();
+        let due = self.tick_rates_offered.is_none_or(|offered| {
+            now.saturating_duration_since(offered) >= TICK_RATE_RESEND_INTERVAL
+        });
+        if let (true, Some(rates)) = (due, &self.tick_rates) {
+            self.client.offer_tick_rates(rates)?;
+            self.tick_rates_offered = Some(now);
+        }
+        Ok(())
+    }
+
+    // how many frames apart the local inputs are sent, every frame until a tick rate is agreed on
+    // and never further apart than the inputs sent along with each one cover
+    fn input_interval(&self) -> u32 {
+        self.tick_rate.map_or(1, |rate| {
+            (rate.simulation / rate.input.max(1)).clamp(1, INPUT_REDUNDANCY as u32)
+        })
+    }
+
+    /// Checks whether every peer has offered its tick rates, and which rate they all support.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn tick_rate_status(&self) -> Result<TickRateStatus, ClientError> {
+        let local = match &self.tick_rates {
+            Some(rates) => rates,
+            None => return Ok(TickRateStatus::Pending),
+        };
+        let mut offers = vec![];
+        for &peer in self.peers() {
+            match self.client.peer_tick_rates(peer)? {
+                Some(rates) => offers.push(rates),
+                None => return Ok(TickRateStatus::Pending),
+            }
+        }
+        let agreed = crate::tick_rate::agree(local, offers.iter().map(Vec::as_slice));
+        Ok(match agreed {
+            Some(rate) => TickRateStatus::Agreed(rate),
+            None => TickRateStatus::NoCommonRate,
+        })
+    }
+
+    /// Answers a rematch. The answer is sent to every peer.
+    /// # Errors
+    /// If the handler thread has stopped.
+    pub fn offer_rematch(&mut self, accept: bool) -> Result<(), ClientError> {
+        self.rematch_answer = Some(accept);
+        self.client.offer_rematch(accept)
+    }
+
+    /// Checks whether everyone has accepted a rematch or someone has declined it.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn rematch_status(&self) -> Result<RematchStatus, ClientError> {
+        if self.rematch_answer == Some(false) {
+            return Ok(RematchStatus::Declined);
+        }
+        let mut status = match self.rematch_answer {
+            Some(_) => RematchStatus::Accepted,
+            None => RematchStatus::Pending,
+        };
+        for &peer in self.peers() {
+            match self.client.rematch_answer(peer)? {
+                Some(false) => return Ok(RematchStatus::Declined),
+                Some(true) => {}
+                None => status = RematchStatus::Pending,
+            }
+        }
+        Ok(status)
+    }
+
+    /// Starts a new session against the same peers with the same configuration, prediction and clock.
+    /// The tick rates are offered to the peers again.
+    /// # Errors
+    /// If a peer has not accepted the rematch, or the handler thread encountered an error or panicked.
+    pub fn rematch(mut self) -> Result<Self, ClientError> {
+        if self.rematch_status()? != RematchStatus::Accepted {
+            return Err(ClientError::RematchNotAccepted);
+        }
+        self.finish_recording_or_warn();
+        let client = self.client.rematch()?;
+        let mut session = Self::with_client(client, self.config);
+        session.prediction = self.prediction;
+        session.metrics_callback = self.metrics_callback;
+        session.clock = self.clock;
+        session.tick_rates = self.tick_rates;
+        Ok(session)
+    }
+
+    /// Ends the session and returns the underlying transport,
+    /// e.g. to hand it back to the matchmaking client.
+    /// # Errors
+    /// If the handler thread encountered an error or panicked.
+    pub fn into_socket(mut self) -> Result<T, ClientError> {
+        self.finish_recording_or_warn();
+        self.client.into_socket()
+    }
+
+    pub fn check_time_until_start(&self) -> Option<u8> {
+        self.client.check_time_until_start()
+    }
+
+    /// Returns the remote peers of the match.
+    pub fn peers(&self) -> &[SocketAddr] {
+        self.client.peers()
+    }
+
+    /// Ends the session.
+    /// # Errors
+    /// If the handler thread encountered an error or panicked.
+    pub fn close(mut self) -> Result<(), ClientError> {
+        self.finish_recording_or_warn();
+        self.client.close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        verify_records, Envelope, InputRecord, InputSink, NetworkInput, NetworkMessage,
+        RecordingError, Sequence,
+    };
+    use crossbeam_channel::{unbounded, Receiver, Sender};
+    use mirai_core::clock::ManualClock;
+    use mirai_core::transport::{ChannelTransport, Packet, TransportEvent};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn receive(
+        event_sender: &Sender<TransportEvent>,
+        peer: SocketAddr,
+        frame: u32,
+        inputs: Vec<u8>,
+    ) {
+        let payload = bincode::serialize(&Envelope {
+            token: 0,
+            message: NetworkMessage::Inputs(NetworkInput {
+                sequence: Sequence(frame as u16),
+                ack: 0,
+                frame,
+                inputs: inputs.into(),
+            }),
+        })
+        .unwrap();
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(peer, payload)))
+            .unwrap();
+    }
+
+    fn session(
+        peers: Vec<SocketAddr>,
+        event_receiver: Receiver<TransportEvent>,
+        packet_sender: Sender<Packet>,
+        config: SessionConfig,
+    ) -> Session<u8, ChannelTransport> {
+        Session::new(
+            peers,
+            ChannelTransport::new(event_receiver, packet_sender),
+            config,
+        )
+    }
+
+    fn wait_for_confirmation(session: &Session<u8, ChannelTransport>, frame: u32) {
+        let now = Instant::now();
+        while session.latest_fully_confirmed().unwrap() < frame {
+            assert!(now.elapsed() < Duration::from_millis(500));
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn detects_mispredictions() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let mut session = session(
+            vec![peer],
+            event_receiver,
+            packet_sender,
+            Default::default(),
+        );
+
+        receive(&event_sender, peer, 1, vec![1]);
+        wait_for_confirmation(&session, 1);
+        for frame in 2..=4 {
+            // predicted to repeat the last input
+            assert_eq!(session.remote_input_for(peer, frame).unwrap(), 1);
+        }
+        receive(&event_sender, peer, 3, vec![1, 1]);
+        wait_for_confirmation(&session, 3);
+        assert_eq!(session.rollback_frame().unwrap(), None);
+        receive(&event_sender, peer, 4, vec![2, 1]);
+        wait_for_confirmation(&session, 4);
+        assert_eq!(session.rollback_frame().unwrap(), Some(4));
+        assert_eq!(session.rollback_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn counts_rollbacks() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let mut session = session(
+            vec![peer],
+            event_receiver,
+            packet_sender,
+            Default::default(),
+        );
+        let (metrics_sender, metrics_receiver) = unbounded();
+        session.set_metrics_callback(move |metrics| metrics_sender.send(*metrics).unwrap());
+
+        for frame in 1..=4 {
+            session.add_local_input(frame, 0).unwrap();
+            session.remote_input_for(peer, frame).unwrap();
+            session.state_saved(frame).unwrap();
+        }
+        // frames 2 and 4 were mispredicted
+        receive(&event_sender, peer, 4, vec![1, 0, 1, 0]);
+        wait_for_confirmation(&session, 4);
+        assert_eq!(session.rollback_frame().unwrap(), Some(2));
+        let metrics = metrics_receiver.try_recv().unwrap();
+        assert_eq!(metrics, session.metrics());
+        assert_eq!(metrics.rollbacks, 1);
+        assert_eq!(metrics.mispredicted_inputs, 2);
+        // rolled back to the state saved at 1 and simulated 2..=4 again
+        assert_eq!(metrics.frames_resimulated, 3);
+    }
+
+    impl InputSink<u8> for Sender<InputRecord<u8>> {
+        fn record(&mut self, record: &InputRecord<u8>) -> Result<(), RecordingError> {
+            self.send(record.clone()).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records_confirmed_inputs() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let mut session = session(
+            vec![peer],
+            event_receiver,
+            packet_sender,
+            Default::default(),
+        );
+        let (record_sender, record_receiver) = unbounded();
+        session.set_input_recorder(InputRecorder::new(b"key", record_sender));
+
+        for frame in 1..=3 {
+            session.add_local_input(frame, frame as u8).unwrap();
+        }
+        receive(&event_sender, peer, 2, vec![20, 10]);
+        wait_for_confirmation(&session, 2);
+        // the records are taken before the confirmed inputs are discarded
+        session.state_saved(2).unwrap();
+        let records: Vec<_> = record_receiver.try_iter().collect();
+        let inputs: Vec<_> = records.iter().map(|r| r.inputs.clone()).collect();
+        assert_eq!(inputs, vec![vec![1, 10], vec![2, 20]]);
+
+        receive(&event_sender, peer, 3, vec![30]);
+        wait_for_confirmation(&session, 3);
+        session.finish_recording().unwrap();
+        let records: Vec<_> = records
+            .into_iter()
+            .chain(record_receiver.try_iter())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2].inputs, vec![3, 30]);
+        assert!(verify_records(b"key", &records).is_ok());
+    }
+
+    struct FailingSink;
+
+    impl InputSink<u8> for FailingSink {
+        fn record(&mut self, _record: &InputRecord<u8>) -> Result<(), RecordingError> {
+            Err(RecordingError::UploaderStopped)
+        }
+    }
+
+    #[test]
+    fn recording_failures_are_reported_as_events() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let mut session = session(
+            vec![peer],
+            event_receiver,
+            packet_sender,
+            Default::default(),
+        );
+        session.set_input_recorder(InputRecorder::new(b"key", FailingSink));
+
+        session.add_local_input(1, 1).unwrap();
+        receive(&event_sender, peer, 1, vec![10]);
+        wait_for_confirmation(&session, 1);
+        // the match goes on
+        assert_eq!(session.update().unwrap(), SessionState::Running);
+        assert_eq!(
+            session.events(),
+            vec![SessionEvent::RecordingFailed { frame: 1 }]
+        );
+        session.add_local_input(2, 2).unwrap();
+        receive(&event_sender, peer, 2, vec![20, 10]);
+        wait_for_confirmation(&session, 2);
+        assert_eq!(session.update().unwrap(), SessionState::Running);
+        assert!(session.events().is_empty());
+    }
+
+    #[test]
+    fn uses_configured_prediction() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let mut session = session(
+            vec![peer],
+            event_receiver,
+            packet_sender,
+            Default::default(),
+        );
+        session.set_prediction(crate::Neutral);
+
+        receive(&event_sender, peer, 1, vec![1]);
+        wait_for_confirmation(&session, 1);
+        assert_eq!(session.remote_input_for(peer, 1).unwrap(), 1);
+        assert_eq!(session.remote_input_for(peer, 2).unwrap(), 0);
+    }
+
+    #[test]
+    fn restore_point_follows_confirmation() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let config = SessionConfig {
+            save_interval: 2,
+            ..SessionConfig::default()
+        };
+        let mut session = session(vec![peer], event_receiver, packet_sender, config);
+        assert!(!session.should_save(1));
+        assert!(session.should_save(2));
+
+        session.state_saved(2).unwrap();
+        session.state_saved(4).unwrap();
+        assert_eq!(session.restore_point(), 0);
+        receive(&event_sender, peer, 3, vec![0, 0, 0]);
+        wait_for_confirmation(&session, 3);
+        session.state_saved(6).unwrap();
+        assert_eq!(session.restore_point(), 2);
+        assert_eq!(session.latest_saved(), 6);
+    }
+
+    #[test]
+    fn interrupts_when_confirmation_falls_behind() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let config = SessionConfig {
+            max_rollback_depth: 4,
+            ..SessionConfig::default()
+        };
+        let mut session = session(vec![peer], event_receiver, packet_sender, config);
+
+        for frame in 1..4 {
+            session.add_local_input(frame, 0).unwrap();
+            assert_eq!(session.update().unwrap(), SessionState::Running);
+        }
+        session.add_local_input(4, 0).unwrap();
+        assert_eq!(session.update().unwrap(), SessionState::Interrupted);
+        assert_eq!(
+            session.events(),
+            vec![SessionEvent::ConnectionInterrupted {
+                local_frame: 4,
+                confirmed_frame: 0
+            }]
+        );
+
+        receive(&event_sender, peer, 2, vec![0, 0]);
+        wait_for_confirmation(&session, 2);
+        assert_eq!(session.update().unwrap(), SessionState::Running);
+        assert_eq!(session.events(), vec![SessionEvent::ConnectionResumed]);
+    }
+
+    #[test]
+    fn disconnects_after_timeout() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (_event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let config = SessionConfig {
+            max_rollback_depth: 1,
+            disconnect_timeout: Duration::from_millis(0),
+            ..SessionConfig::default()
+        };
+        let mut session = session(vec![peer], event_receiver, packet_sender, config);
+
+        session.add_local_input(1, 0).unwrap();
+        assert_eq!(session.update().unwrap(), SessionState::Interrupted);
+        assert_eq!(session.update().unwrap(), SessionState::Disconnected);
+        assert_eq!(session.update().unwrap(), SessionState::Disconnected);
+        assert_eq!(
+            session.events(),
+            vec![
+                SessionEvent::ConnectionInterrupted {
+                    local_frame: 1,
+                    confirmed_frame: 0
+                },
+                SessionEvent::Disconnected
+            ]
+        );
+    }
+
+    #[test]
+    fn interruptions_are_timed_with_the_clock() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (_event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let config = SessionConfig {
+            max_rollback_depth: 1,
+            disconnect_timeout: Duration::from_secs(10),
+            ..SessionConfig::default()
+        };
+        let mut session = session(vec![peer], event_receiver, packet_sender, config);
+        let clock = ManualClock::new();
+        session.set_clock(clock.clone());
+
+        session.add_local_input(1, 0).unwrap();
+        assert_eq!(session.update().unwrap(), SessionState::Interrupted);
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(session.update().unwrap(), SessionState::Interrupted);
+        assert_eq!(session.metrics().stall_time, Duration::from_secs(9));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(session.update().unwrap(), SessionState::Disconnected);
+    }
+
+    #[test]
+    fn rematch_requires_everyone_to_accept() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let mut session = session(
+            vec![peer],
+            event_receiver,
+            packet_sender,
+            Default::default(),
+        );
+
+        session.offer_rematch(true).unwrap();
+        assert_eq!(session.rematch_status().unwrap(), RematchStatus::Pending);
+        let payload = bincode::serialize(&Envelope::<u8> {
+            token: 0,
+            message: NetworkMessage::Rematch {
+                accept: false,
+                token: 1,
+            },
+        })
+        .unwrap();
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(peer, payload)))
+            .unwrap();
+        let now = Instant::now();
+        while session.rematch_status().unwrap() == RematchStatus::Pending {
+            assert!(now.elapsed() < Duration::from_millis(500));
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(session.rematch_status().unwrap(), RematchStatus::Declined);
+        assert!(session.rematch().is_err());
+    }
+
+    #[test]
+    fn agrees_on_a_tick_rate_with_the_peers() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, packet_receiver) = unbounded();
+        let mut session = session(
+            vec![peer],
+            event_receiver,
+            packet_sender,
+            Default::default(),
+        );
+        let clock = ManualClock::new();
+        session.set_clock(clock.clone());
+        let offered = |packet_receiver: &Receiver<Packet>| {
+            let packet = packet_receiver
+                .recv_timeout(Duration::from_millis(500))
+                .unwrap();
+            let envelope: Envelope<u8> = bincode::deserialize(packet.payload()).unwrap();
+            envelope.message
+        };
+
+        let rates = [TickRate::new(60), TickRate::new(120)];
+        session.offer_tick_rates(&rates).unwrap();
+        assert_eq!(
+            offered(&packet_receiver),
+            NetworkMessage::TickRates(rates.to_vec())
+        );
+        // the offer isn't sent on every update
+        session.update().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(packet_receiver.try_recv().is_err());
+        // but again once the peer's hasn't arrived for a while
+        clock.advance(TICK_RATE_RESEND_INTERVAL);
+        session.update().unwrap();
+        assert_eq!(
+            offered(&packet_receiver),
+            NetworkMessage::TickRates(rates.to_vec())
+        );
+        assert_eq!(session.tick_rate_status().unwrap(), TickRateStatus::Pending);
+
+        let payload = bincode::serialize(&Envelope::<u8> {
+            token: 0,
+            message: NetworkMessage::TickRates(vec![TickRate::new(30), TickRate::new(60)]),
+        })
+        .unwrap();
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(peer, payload)))
+            .unwrap();
+        let now = Instant::now();
+        while session.tick_rate_status().unwrap() == TickRateStatus::Pending {
+            assert!(now.elapsed() < Duration::from_millis(500));
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(
+            session.tick_rate_status().unwrap(),
+            TickRateStatus::Agreed(TickRate::new(60))
+        );
+        clock.advance(TICK_RATE_RESEND_INTERVAL);
+        session.update().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(packet_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn inputs_are_sent_at_the_agreed_input_rate() {
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, packet_receiver) = unbounded();
+        let mut session = session(
+            vec![peer],
+            event_receiver,
+            packet_sender,
+            Default::default(),
+        );
+        let half = TickRate {
+            simulation: 60,
+            input: 30,
+        };
+        session.offer_tick_rates(&[half]).unwrap();
+        let payload = bincode::serialize(&Envelope::<u8> {
+            token: 0,
+            message: NetworkMessage::TickRates(vec![half]),
+        })
+        .unwrap();
+        event_sender
+            .send(TransportEvent::Packet(Packet::unreliable(peer, payload)))
+            .unwrap();
+        let now = Instant::now();
+        while session.tick_rate_status().unwrap() == TickRateStatus::Pending {
+            assert!(now.elapsed() < Duration::from_millis(500));
+            thread::sleep(Duration::from_millis(5));
+        }
+        session.update().unwrap();
+
+        for frame in 1..=4 {
+            session.add_local_input(frame, frame as u8).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        let sent: Vec<_> = packet_receiver
+            .try_iter()
+            .filter_map(|packet| {
+                match bincode::deserialize::<Envelope<u8>>(packet.payload())
+                    .unwrap()
+                    .message
+                {
+                    NetworkMessage::Inputs(input) => Some((input.frame, input.inputs.to_vec())),
+                    _ => None,
+                }
+            })
+            .collect();
+        // every other frame, along with the frames in between
+        assert_eq!(sent, vec![(2, vec![2, 1]), (4, vec![4, 3, 2, 1])]);
+    }
+}
@@ -0,0 +1,101 @@
+//! A delay between a match and its spectators.
+//!
+//! A spectator that simulated the confirmed inputs as soon as they arrived could tell a player's opponent,
+//! e.g. through their coach, what the player is doing in real time. `TimeShift` holds each frame's inputs
+//! back for a delay after they arrive before handing them to the spectator's simulation.
+//! The inputs can come from any stream of confirmed inputs, e.g. the records of an `InputRecorder`.
+
+use mirai_core::clock::{Clock, SystemClock};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// By default spectators are ten seconds behind the match.
+pub const DEFAULT_SPECTATOR_DELAY: Duration = Duration::from_secs(10);
+
+/// Holds the confirmed inputs of a match back from a spectator, starting from frame 1.
+pub struct TimeShift<I> {
+    delay: Duration,
+    // the inputs of every player for each frame that hasn't been handed out, and when they arrived
+    held: VecDeque<(Instant, Vec<I>)>,
+    // the frame of the next inputs handed out
+    next_frame: u32,
+    clock: Box<dyn Clock>,
+}
+
+impl<I> Default for TimeShift<I> {
+    fn default() -> Self {
+        Self::new(DEFAULT_SPECTATOR_DELAY)
+    }
+}
+
+impl<I> TimeShift<I> {
+    /// Creates a buffer that holds the inputs back for the given delay.
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            held: VecDeque::new(),
+            next_frame: 1,
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    /// Sets the clock the delay is timed with. Defaults to `SystemClock`.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Returns how long the inputs are held back.
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Holds the confirmed inputs of every player for the next frame back until the delay has passed.
+    pub fn push(&mut self, inputs: Vec<I>) {
+        self.held.push_back((self.clock.now(), inputs));
+    }
+
+    /// Returns the next frame and the inputs of every player for it,
+    /// if they arrived at least the delay ago.
+    pub fn pop(&mut self) -> Option<(u32, Vec<I>)> {
+        let (arrived, _) = self.held.front()?;
+        if self.clock.now().saturating_duration_since(*arrived) < self.delay {
+            return None;
+        }
+        let (_, inputs) = self.held.pop_front()?;
+        let frame = self.next_frame;
+        self.next_frame += 1;
+        Some((frame, inputs))
+    }
+
+    /// Returns the amount of frames that are held back.
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mirai_core::clock::ManualClock;
+
+    #[test]
+    fn holds_inputs_back_for_the_delay() {
+        let clock = ManualClock::new();
+        let mut shift = TimeShift::new(Duration::from_secs(10));
+        shift.set_clock(clock.clone());
+
+        shift.push(vec![1u8, 2]);
+        clock.advance(Duration::from_secs(4));
+        shift.push(vec![3, 4]);
+        assert_eq!(shift.pop(), None);
+
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(shift.pop(), Some((1, vec![1, 2])));
+        assert_eq!(shift.pop(), None);
+        assert_eq!(shift.held(), 1);
+
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(shift.pop(), Some((2, vec![3, 4])));
+        assert_eq!(shift.held(), 0);
+    }
+}
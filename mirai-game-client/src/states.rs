@@ -0,0 +1,104 @@
+//! Storage for game state snapshots used for rolling back.
+
+use std::collections::VecDeque;
+
+/// Game states saved at various frames, ordered by frame.
+/// A state saved at frame f is the state after frame f has been simulated.
+#[derive(Debug, Clone)]
+pub struct SavedStates<S> {
+    states: VecDeque<(u32, S)>,
+}
+
+impl<S: Clone> SavedStates<S> {
+    /// Creates a store holding the initial state of the game at frame 0.
+    pub fn new(initial: S) -> Self {
+        let mut states = VecDeque::new();
+        states.push_back((0, initial));
+        Self { states }
+    }
+
+    /// Saves the state for the given frame, replacing any states saved for that frame or later.
+    pub fn save(&mut self, frame: u32, state: S) {
+        while let Some(&(saved_frame, _)) = self.states.back() {
+            if saved_frame < frame {
+                break;
+            }
+            self.states.pop_back();
+        }
+        self.states.push_back((frame, state));
+    }
+
+    /// Returns the latest state saved before the given frame together with its frame,
+    /// discarding the states saved at that frame or later since they are no longer valid.
+    /// Used when the inputs for `frame` turn out to have been mispredicted.
+    pub fn load_before(&mut self, frame: u32) -> Option<(u32, S)> {
+        while let Some(&(saved_frame, _)) = self.states.back() {
+            if saved_frame < frame {
+                break;
+            }
+            self.states.pop_back();
+        }
+        self.states.back().cloned()
+    }
+
+    /// Discards the states saved before the given frame.
+    /// The state at the given frame is kept if it exists.
+    pub fn discard_before(&mut self, frame: u32) {
+        while let Some(&(saved_frame, _)) = self.states.front() {
+            if saved_frame >= frame || self.states.len() == 1 {
+                break;
+            }
+            self.states.pop_front();
+        }
+    }
+
+    /// Returns the frame of the oldest saved state.
+    pub fn oldest_frame(&self) -> Option<u32> {
+        self.states.front().map(|&(frame, _)| frame)
+    }
+
+    /// Returns the amount of states saved.
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Checks whether there are no states saved.
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn loads_latest_state_before_misprediction() {
+        let mut states = SavedStates::new(0);
+        states.save(4, 4);
+        states.save(8, 8);
+        assert_eq!(states.load_before(8), Some((4, 4)));
+        assert_eq!(states.len(), 2);
+        assert_eq!(states.load_before(3), Some((0, 0)));
+    }
+
+    #[test]
+    fn resaving_replaces_later_states() {
+        let mut states = SavedStates::new(0);
+        states.save(4, 4);
+        states.save(8, 8);
+        states.save(4, 40);
+        assert_eq!(states.load_before(100), Some((4, 40)));
+    }
+
+    #[test]
+    fn discards_old_states() {
+        let mut states = SavedStates::new(0);
+        states.save(4, 4);
+        states.save(8, 8);
+        states.discard_before(5);
+        assert_eq!(states.len(), 1);
+        states.discard_before(100);
+        assert_eq!(states.len(), 1);
+    }
+}
@@ -0,0 +1,191 @@
+//! Sync testing runs the game locally while rolling back and re-simulating every frame,
+//! comparing the checksums of the re-simulated states to the original ones.
+//!
+//! Any difference means the game's simulation is not deterministic given the same
+//! starting state and inputs, which would cause peers to desync in online play.
+
+use crate::SavedStates;
+use snafu::Snafu;
+use std::collections::VecDeque;
+
+/// A game that can be driven by a sync test.
+pub trait SyncTestGame {
+    type Input: Copy;
+    type State: Clone;
+
+    /// Returns a copy of the current game state.
+    fn save_state(&self) -> Self::State;
+
+    /// Replaces the current game state.
+    fn load_state(&mut self, state: Self::State);
+
+    /// Simulates a single frame with one input per player.
+    fn advance_frame(&mut self, inputs: &[Self::Input]);
+
+    /// Returns a checksum of the current game state.
+    fn checksum(&self) -> u64;
+}
+
+/// Drives a game one frame at a time, rolling back `check_distance` frames after each one.
+pub struct SyncTest<G: SyncTestGame> {
+    check_distance: u32,
+    frame: u32,
+    states: SavedStates<G::State>,
+    // inputs and checksums for the frames since the oldest saved state, oldest first
+    inputs: VecDeque<Vec<G::Input>>,
+    checksums: VecDeque<u64>,
+}
+
+impl<G: SyncTestGame> SyncTest<G> {
+    /// Starts a sync test from the current state of the game.
+    /// # Panics
+    /// If `check_distance` is zero.
+    pub fn new(game: &G, check_distance: u32) -> Self {
+        assert!(check_distance > 0, "check distance must be positive");
+        Self {
+            check_distance,
+            frame: 0,
+            states: SavedStates::new(game.save_state()),
+            inputs: VecDeque::new(),
+            checksums: VecDeque::new(),
+        }
+    }
+
+    /// Returns the latest simulated frame.
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// Simulates the next frame with the given inputs, then rolls back up to `check_distance` frames
+    /// and simulates them again, checking that the resulting checksums match.
+    /// # Errors
+    /// If a re-simulated frame has a different checksum than the first time it was simulated.
+    pub fn advance_frame(
+        &mut self,
+        game: &mut G,
+        inputs: Vec<G::Input>,
+    ) -> Result<(), SyncTestError> {
+        game.advance_frame(&inputs);
+        self.frame += 1;
+        self.inputs.push_back(inputs);
+        self.checksums.push_back(game.checksum());
+
+        let restore_point = self.frame.saturating_sub(self.check_distance);
+        let (start, state) = self
+            .states
+            .load_before(restore_point + 1)
+            .expect("no saved state to roll back to");
+        game.load_state(state);
+        let first_kept = self.frame + 1 - self.inputs.len() as u32;
+        for frame in start + 1..=self.frame {
+            let index = (frame - first_kept) as usize;
+            game.advance_frame(&self.inputs[index]);
+            let expected = self.checksums[index];
+            let actual = game.checksum();
+            if expected != actual {
+                return Err(SyncTestError::Desync {
+                    frame,
+                    expected,
+                    actual,
+                });
+            }
+            self.states.save(frame, game.save_state());
+        }
+
+        // the next frame will roll back to at most one frame further
+        let next_restore_point = (self.frame + 1).saturating_sub(self.check_distance);
+        self.states.discard_before(next_restore_point);
+        let oldest = self.states.oldest_frame().expect("no saved states");
+        while !self.inputs.is_empty() && self.frame + 1 - self.inputs.len() as u32 <= oldest {
+            self.inputs.pop_front();
+            self.checksums.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Snafu, PartialEq, Eq)]
+pub enum SyncTestError {
+    #[snafu(display(
+        "desync at frame {}: expected checksum {:x}, got {:x}",
+        frame,
+        expected,
+        actual
+    ))]
+    Desync {
+        frame: u32,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Counter {
+        value: u64,
+        // simulates game code that depends on something outside of the saved state
+        hidden: u64,
+        deterministic: bool,
+    }
+
+    impl SyncTestGame for Counter {
+        type Input = u64;
+        type State = u64;
+
+        fn save_state(&self) -> u64 {
+            self.value
+        }
+
+        fn load_state(&mut self, state: u64) {
+            self.value = state;
+        }
+
+        fn advance_frame(&mut self, inputs: &[u64]) {
+            self.value += inputs.iter().sum::<u64>();
+            if !self.deterministic {
+                self.hidden += 1;
+                self.value += self.hidden;
+            }
+        }
+
+        fn checksum(&self) -> u64 {
+            self.value
+        }
+    }
+
+    #[test]
+    fn deterministic_game_passes() {
+        let mut game = Counter {
+            value: 0,
+            hidden: 0,
+            deterministic: true,
+        };
+        let mut sync_test = SyncTest::new(&game, 4);
+        for frame in 0..20 {
+            sync_test.advance_frame(&mut game, vec![frame, 1]).unwrap();
+        }
+        assert_eq!(sync_test.frame(), 20);
+        assert_eq!(game.value, (0..20).sum::<u64>() + 20);
+    }
+
+    #[test]
+    fn nondeterministic_game_fails() {
+        let mut game = Counter {
+            value: 0,
+            hidden: 0,
+            deterministic: false,
+        };
+        let mut sync_test = SyncTest::new(&game, 2);
+        let error = sync_test.advance_frame(&mut game, vec![1]).unwrap_err();
+        assert_eq!(
+            error,
+            SyncTestError::Desync {
+                frame: 1,
+                expected: 2,
+                actual: 3
+            }
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! The rates a match is simulated and its inputs are sent at, agreed on by the peers at the start of the match.
+//!
+//! Instead of every build hardcoding the same frame rate, each peer offers the rates it supports,
+//! and the match runs at the fastest rate all of them support. Every peer picks from the same offers
+//! the same way, so they agree without another round trip.
+
+use serde::{Deserialize, Serialize};
+
+/// A simulation rate along with the rate inputs are sent at, both per second.
+/// Ordered by the simulation rate first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct TickRate {
+    pub simulation: u32,
+    pub input: u32,
+}
+
+impl TickRate {
+    /// A rate that sends the inputs of every frame.
+    pub fn new(frames_per_second: u32) -> Self {
+        Self {
+            simulation: frames_per_second,
+            input: frames_per_second,
+        }
+    }
+}
+
+/// Whether the peers have agreed on a tick rate.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TickRateStatus {
+    /// Waiting for the local player or some of the peers to offer their rates.
+    Pending,
+    /// The fastest rate everyone supports.
+    Agreed(TickRate),
+    /// The peers don't support any of the same rates, the match cannot be played.
+    NoCommonRate,
+}
+
+/// Picks the fastest rate that is in every offer.
+pub(crate) fn agree<'a>(
+    local: &[TickRate],
+    peers: impl IntoIterator<Item = &'a [TickRate]>,
+) -> Option<TickRate> {
+    let mut common = local.to_vec();
+    for offer in peers {
+        common.retain(|rate| offer.contains(rate));
+    }
+    common.into_iter().max()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn agrees_on_the_fastest_common_rate() {
+        let rates = |rates: &[(u32, u32)]| {
+            rates
+                .iter()
+                .map(|&(simulation, input)| TickRate { simulation, input })
+                .collect::<Vec<_>>()
+        };
+        let local = rates(&[(60, 60), (60, 30), (120, 60)]);
+        let peer = rates(&[(60, 30), (60, 60), (30, 30)]);
+        assert_eq!(
+            agree(&local, vec![peer.as_slice()]),
+            Some(TickRate::new(60))
+        );
+        // the order of the offers doesn't matter
+        assert_eq!(
+            agree(&peer, vec![local.as_slice()]),
+            Some(TickRate::new(60))
+        );
+
+        let other = rates(&[(60, 30)]);
+        assert_eq!(
+            agree(&local, vec![peer.as_slice(), other.as_slice()]),
+            Some(TickRate {
+                simulation: 60,
+                input: 30
+            })
+        );
+        assert_eq!(agree(&local, vec![&rates(&[(30, 30)])[..]]), None);
+    }
+}
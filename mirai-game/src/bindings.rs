@@ -0,0 +1,177 @@
+// key bindings for the local players, loaded from and saved to a toml file
+
+use ggez::event::KeyCode;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+
+pub const DEFAULT_BINDINGS_PATH: &str = "bindings.toml";
+
+// keys are stored by name, e.g. "A" or "Left"
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PlayerBindings {
+    pub left: String,
+    pub right: String,
+    pub attack: String,
+}
+
+impl PlayerBindings {
+    // returns the left, right and attack keys
+    // panics if a key name is not recognized
+    pub fn keys(&self) -> (KeyCode, KeyCode, KeyCode) {
+        let key =
+            |name: &str| key_from_name(name).unwrap_or_else(|| panic!("unknown key {}", name));
+        (key(&self.left), key(&self.right), key(&self.attack))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Bindings {
+    pub p1: PlayerBindings,
+    pub p2: PlayerBindings,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self {
+            p1: PlayerBindings {
+                left: "A".to_string(),
+                right: "D".to_string(),
+                attack: "S".to_string(),
+            },
+            p2: PlayerBindings {
+                left: "Left".to_string(),
+                right: "Right".to_string(),
+                attack: "Down".to_string(),
+            },
+        }
+    }
+}
+
+impl Bindings {
+    // loads the bindings from the given file, or the defaults if it does not exist
+    pub fn load(path: &str) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let contents =
+            toml::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, contents)
+    }
+}
+
+// the bindings in the order they are asked for when rebinding
+const REBINDING_PROMPTS: [&str; 6] = [
+    "player 1 left",
+    "player 1 right",
+    "player 1 attack",
+    "player 2 left",
+    "player 2 right",
+    "player 2 attack",
+];
+
+// asks for each binding in turn
+pub struct Rebinding {
+    step: usize,
+    bindings: Bindings,
+}
+
+impl Rebinding {
+    pub fn new(bindings: Bindings) -> Self {
+        Self { step: 0, bindings }
+    }
+
+    pub fn prompt(&self) -> String {
+        format!("press the key for {}", REBINDING_PROMPTS[self.step])
+    }
+
+    // binds the key to the current prompt, returns the new bindings once every key has been bound
+    pub fn press(&mut self, key: KeyCode) -> Option<Bindings> {
+        let name = format!("{:?}", key);
+        // keys that cannot be read back from the file are ignored
+        key_from_name(&name)?;
+        let binding = match self.step {
+            0 => &mut self.bindings.p1.left,
+            1 => &mut self.bindings.p1.right,
+            2 => &mut self.bindings.p1.attack,
+            3 => &mut self.bindings.p2.left,
+            4 => &mut self.bindings.p2.right,
+            _ => &mut self.bindings.p2.attack,
+        };
+        *binding = name;
+        self.step += 1;
+        if self.step == REBINDING_PROMPTS.len() {
+            Some(self.bindings.clone())
+        } else {
+            None
+        }
+    }
+}
+
+// the keys that can be bound, by the name used in the bindings file
+pub fn key_from_name(name: &str) -> Option<KeyCode> {
+    use KeyCode::*;
+    let key = match name {
+        "A" => A,
+        "B" => B,
+        "C" => C,
+        "D" => D,
+        "E" => E,
+        "F" => F,
+        "G" => G,
+        "H" => H,
+        "I" => I,
+        "J" => J,
+        "K" => K,
+        "L" => L,
+        "M" => M,
+        "N" => N,
+        "O" => O,
+        "P" => P,
+        "Q" => Q,
+        "R" => R,
+        "S" => S,
+        "T" => T,
+        "U" => U,
+        "V" => V,
+        "W" => W,
+        "X" => X,
+        "Y" => Y,
+        "Z" => Z,
+        "Key0" => Key0,
+        "Key1" => Key1,
+        "Key2" => Key2,
+        "Key3" => Key3,
+        "Key4" => Key4,
+        "Key5" => Key5,
+        "Key6" => Key6,
+        "Key7" => Key7,
+        "Key8" => Key8,
+        "Key9" => Key9,
+        "Left" => Left,
+        "Right" => Right,
+        "Up" => Up,
+        "Down" => Down,
+        "Space" => Space,
+        "Return" => Return,
+        "Tab" => Tab,
+        "LShift" => LShift,
+        "RShift" => RShift,
+        "LControl" => LControl,
+        "RControl" => RControl,
+        "LAlt" => LAlt,
+        "RAlt" => RAlt,
+        "Comma" => Comma,
+        "Semicolon" => Semicolon,
+        "Slash" => Slash,
+        _ => return None,
+    };
+    Some(key)
+}
@@ -0,0 +1,32 @@
+// headless mode that finds a match and plays scripted inputs, for soak testing the netcode and the server
+// several bots can be run on the same machine by giving each a different local IP, e.g. 127.0.0.2
+
+use crate::game::Game;
+use crate::inputs::{Input, InputSource, InputSourceKind};
+use crate::netsim::NetworkConditions;
+use mirai_game_client::Replay;
+use std::net::IpAddr;
+use std::thread;
+
+// one minute at 32 frames per second
+const MATCH_FRAMES: u32 = 32 * 60;
+
+// returns the replay of the match
+pub fn run(local_ip: IpAddr, server_ip: IpAddr, conditions: NetworkConditions) -> Replay<Input> {
+    let session = crate::find_match(local_ip, server_ip, conditions);
+    let input_source = InputSource::new(
+        InputSourceKind::scripted(),
+        InputSourceKind::remote(session),
+    );
+    let mut game = Game::new(input_source);
+    while game.current_frame() < MATCH_FRAMES {
+        if game.disconnected() {
+            println!("disconnected at frame {}", game.current_frame());
+            return game.replay().clone();
+        }
+        game.advance(None);
+        thread::sleep(game.time_until_next_frame());
+    }
+    println!("finished the match");
+    game.replay().clone()
+}
@@ -1,7 +1,13 @@
-use crate::inputs::{Input, InputSource};
+use crate::bindings::{Bindings, Rebinding, DEFAULT_BINDINGS_PATH};
+use crate::inputs::{Input, InputSource, InputSourceKind};
 use ggez::graphics::Color;
 use ggez::nalgebra as na;
 use ggez::*;
+use mirai_game_client::{FrameClock, RematchStatus, Replay, SavedStates, SyncTest, SyncTestGame};
+use mirai_session::MatchSession as Session;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
 use PlayerState::*;
 
 const FRAMES_PER_SECOND: u32 = 32;
@@ -11,10 +17,12 @@ const GROUND_LEVEL: i32 = 360;
 const GROUND_SIZE: i32 = 480;
 const GROUND_X_START: i32 = 80;
 const GROUND_X_END: i32 = GROUND_X_START + GROUND_SIZE;
+const SYNC_TEST_CHECK_DISTANCE: u32 = 8;
+// how many frames the pre-rollback positions stay visible
+const GHOST_FRAMES: u32 = 12;
 
 #[derive(Clone)]
-struct State {
-    frame: u32,
+pub struct State {
     p1: Player,
     p2: Player,
 }
@@ -25,27 +33,92 @@ pub struct Game {
     p1: Player,
     p2: Player,
     input_source: InputSource,
-    last_confirmed_state: State,
+    saved_states: SavedStates<State>,
+    sync_test: Option<SyncTest<Game>>,
+    clock: FrameClock,
+    // the confirmed inputs of the match so far
+    replay: Replay<Input>,
+    playback: Option<Playback>,
+    bindings: Bindings,
+    bindings_path: String,
+    rebinding: Option<Rebinding>,
+    show_hud: bool,
+    // when set, mispredicted positions are drawn for a while after each rollback
+    ghosts: Option<Vec<Ghost>>,
+    // the frame an online match ended at, cleared if a rollback undoes it
+    ko_frame: Option<u32>,
+}
+
+// the positions the players were drawn at before a rollback corrected them
+struct Ghost {
+    p1: (i32, i32),
+    p2: (i32, i32),
+    until_frame: u32,
+}
+
+// controls for playing back a replay
+struct Playback {
+    frames: u32,
+    paused: bool,
+    step: bool,
 }
 
 impl Game {
     pub fn new(input_source: InputSource) -> Self {
         let p1 = Player::new(160 - PLAYER_SIZE / 2, GROUND_LEVEL, Side::Left);
         let p2 = Player::new(480 - PLAYER_SIZE / 2, GROUND_LEVEL, Side::Right);
-        let confirmed_state = State {
-            frame: 0,
+        let initial_state = State {
             p1: p1.clone(),
             p2: p2.clone(),
         };
-        let mut s = Game {
+        Game {
             current_frame: 0,
             target_frame: 0,
-            p1: p1,
-            p2: p2,
+            p1,
+            p2,
             input_source,
-            last_confirmed_state: confirmed_state.clone(),
-        };
-        s
+            saved_states: SavedStates::new(initial_state),
+            sync_test: None,
+            clock: FrameClock::new(FRAMES_PER_SECOND),
+            replay: Replay::new(2),
+            playback: None,
+            bindings: Bindings::default(),
+            bindings_path: DEFAULT_BINDINGS_PATH.to_string(),
+            rebinding: None,
+            show_hud: false,
+            ghosts: None,
+            ko_frame: None,
+        }
+    }
+
+    // sets the key bindings in use and the file they are saved to after rebinding
+    pub fn set_bindings(&mut self, bindings: Bindings, path: &str) {
+        self.input_source.set_bindings(&bindings);
+        self.bindings = bindings;
+        self.bindings_path = path.to_string();
+    }
+
+    // creates a game that plays back the given replay
+    // space pauses and unpauses, period advances a single frame
+    pub fn playback(replay: &Replay<Input>) -> Self {
+        let input_source = InputSource::new(
+            InputSourceKind::replayed(replay.player_inputs(0)),
+            InputSourceKind::replayed(replay.player_inputs(1)),
+        );
+        let mut game = Self::new(input_source);
+        game.playback = Some(Playback {
+            frames: replay.frames(),
+            paused: false,
+            step: false,
+        });
+        game
+    }
+
+    // creates a game that rolls back and re-simulates every frame to check that the simulation is deterministic
+    pub fn sync_test(input_source: InputSource) -> Self {
+        let mut game = Self::new(input_source);
+        game.sync_test = Some(SyncTest::new(&game, SYNC_TEST_CHECK_DISTANCE));
+        game
     }
 
     fn reset(&mut self) {
@@ -54,49 +127,116 @@ impl Game {
     }
 
     // saves the current state
-    fn save_state(&mut self) {
-        self.last_confirmed_state = State {
-            frame: self.current_frame,
-            p1: self.p1.clone(),
-            p2: self.p2.clone(),
-        };
+    fn save_current_state(&mut self) {
+        let state = self.save_state();
+        self.saved_states.save(self.current_frame, state);
+        // inputs up to the confirmed frame are final and can be recorded before they are discarded
+        let confirmed_frame = std::cmp::min(
+            self.input_source.confirmed_frame(self.target_frame),
+            self.current_frame,
+        );
+        while self.replay.frames() < confirmed_frame {
+            let frame = self.replay.frames() + 1;
+            let inputs = vec![
+                self.input_source.p1_input_for(frame),
+                self.input_source.p2_input_for(frame),
+            ];
+            self.replay.record(inputs);
+        }
+        // states and inputs before the restore point are no longer needed for rollback
+        let restore_point = self.input_source.state_saved(self.current_frame);
+        self.saved_states.discard_before(restore_point);
     }
 
-    // loads the previous confirmed state
-    fn load_state(&mut self) {
-        let confirmed = &self.last_confirmed_state;
-        self.current_frame = confirmed.frame;
-        self.p1 = confirmed.p1.clone();
-        self.p2 = confirmed.p2.clone();
+    // simulates a single frame with the given inputs
+    fn simulate(&mut self, p1_inputs: &Input, p2_inputs: &Input) {
+        // online matches end at the first KO and the players stay put until the rematch
+        let online = self.input_source.is_online();
+        if online && self.knocked_out() {
+            return;
+        }
+        // orient players, no change on p1.x == p2.x because both players think they are p1 so it's impossible to do consistently
+        if self.p1.x < self.p2.x {
+            self.p1.side = Side::Left;
+            self.p2.side = Side::Right;
+        } else if self.p1.x > self.p2.x {
+            self.p2.side = Side::Left;
+            self.p1.side = Side::Right;
+        }
+        handle_player(&mut self.p1, p1_inputs, &mut self.p2);
+        handle_player(&mut self.p2, p2_inputs, &mut self.p1);
+        check_hitboxes(&mut self.p1, &mut self.p2);
+        check_hitboxes(&mut self.p2, &mut self.p1);
+
+        if self.p1.dead() {
+            println!("player 1 died");
+        }
+        if self.p2.dead() {
+            println!("player 2 died");
+        }
+        if !online && self.knocked_out() {
+            self.reset();
+        }
     }
-}
 
-#[derive(Clone, Copy)]
-enum Side {
-    Left,
-    Right,
-}
+    fn knocked_out(&self) -> bool {
+        self.p1.dead() || self.p2.dead()
+    }
 
-impl event::EventHandler for Game {
-    fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
+    // loads the latest state saved before the given frame
+    fn load_state_before(&mut self, frame: u32) {
+        if let Some((saved_frame, state)) = self.saved_states.load_before(frame) {
+            self.current_frame = saved_frame;
+            self.load_state(state);
+            if !self.knocked_out() {
+                self.ko_frame = None;
+            }
+        }
+    }
+
+    // progresses the game to the current frame, reading keyboard inputs from the context if there is one
+    pub fn advance(&mut self, ctx: Option<&Context>) {
         // fixed timer, controls the game tick rate
-        while timer::check_update_time(ctx, FRAMES_PER_SECOND) {
+        while self.clock.tick() {
+            if let Some(playback) = &mut self.playback {
+                let step = std::mem::replace(&mut playback.step, false);
+                if (playback.paused && !step) || self.target_frame >= playback.frames {
+                    continue;
+                }
+            }
+            if self.input_source.stalled() {
+                // too far ahead of the remote inputs, wait for them to catch up
+                continue;
+            }
             self.target_frame += 1;
             println!("progressed to {}", self.target_frame);
             // lets the input source know we are progressing the frame now
             self.input_source.progress_frame(ctx, self.target_frame);
         }
 
-        // check rollback
-        let latest_fully_confirmed = self.input_source.latest_fully_confirmed();
-        if latest_fully_confirmed + 8 < self.target_frame {
-            panic!("no inputs received for the past 8 frames, giving up");
+        if let Some(mut sync_test) = self.sync_test.take() {
+            while self.current_frame < self.target_frame {
+                self.current_frame += 1;
+                let inputs = vec![
+                    self.input_source.p1_input_for(self.current_frame),
+                    self.input_source.p2_input_for(self.current_frame),
+                ];
+                if let Err(e) = sync_test.advance_frame(self, inputs) {
+                    panic!("sync test failed: {}", e);
+                }
+                // the sync test keeps its own copy of the inputs
+                self.input_source.state_saved(self.current_frame);
+            }
+            self.sync_test = Some(sync_test);
+            return;
         }
-        if latest_fully_confirmed > self.last_confirmed_state.frame
-            && self.current_frame > self.last_confirmed_state.frame
-        {
-            // the input source has confirmed frames further than the game: rollback to sync
-            self.load_state();
+
+        // check rollback
+        let mut pre_rollback = None;
+        if let Some(mispredicted_frame) = self.input_source.rollback_frame() {
+            pre_rollback = Some(((self.p1.x, self.p1.y), (self.p2.x, self.p2.y)));
+            // some frames were simulated with the wrong inputs: rollback to sync
+            self.load_state_before(mispredicted_frame);
         }
 
         // progress game state from current to target frame
@@ -105,37 +245,203 @@ impl event::EventHandler for Game {
             // get inputs for current frame
             let p1_inputs = self.input_source.p1_input_for(self.current_frame);
             let p2_inputs = self.input_source.p2_input_for(self.current_frame);
-
-            // orient players, no change on p1.x == p2.x because both players think they are p1 so it's impossible to do consistently
-            if self.p1.x < self.p2.x {
-                self.p1.side = Side::Left;
-                self.p2.side = Side::Right;
-            } else if self.p1.x > self.p2.x {
-                self.p2.side = Side::Left;
-                self.p1.side = Side::Right;
+            self.simulate(&p1_inputs, &p2_inputs);
+            if self.ko_frame.is_none() && self.knocked_out() {
+                self.ko_frame = Some(self.current_frame);
             }
-            handle_player(&mut self.p1, &p1_inputs, &mut self.p2);
-            handle_player(&mut self.p2, &p2_inputs, &mut self.p1);
-            check_hitboxes(&mut self.p1, &mut self.p2);
-            check_hitboxes(&mut self.p2, &mut self.p1);
-
-            if self.p1.dead() {
-                println!("player 1 died");
-                self.reset();
+
+            if self.input_source.should_save(self.current_frame) {
+                self.save_current_state();
             }
-            if self.p2.dead() {
-                println!("player 2 died");
-                self.reset();
+        }
+
+        if let Some(ghosts) = &mut self.ghosts {
+            let current_frame = self.current_frame;
+            ghosts.retain(|ghost| ghost.until_frame >= current_frame);
+            if let Some((p1, p2)) = pre_rollback {
+                // rollbacks that did not change the outcome are not interesting
+                if p1 != (self.p1.x, self.p1.y) || p2 != (self.p2.x, self.p2.y) {
+                    ghosts.push(Ghost {
+                        p1,
+                        p2,
+                        until_frame: current_frame + GHOST_FRAMES,
+                    });
+                }
             }
+        }
+    }
+
+    pub fn current_frame(&self) -> u32 {
+        self.current_frame
+    }
+
+    pub fn replay(&self) -> &Replay<Input> {
+        &self.replay
+    }
+
+    pub fn time_until_next_frame(&self) -> Duration {
+        self.clock.time_until_next_frame()
+    }
 
-            if self.current_frame == latest_fully_confirmed {
-                // reached latest fully confirmed frame, saving state
-                self.save_state();
+    // the text of the debug overlay
+    fn hud_text(&self) -> String {
+        let confirmed_frame = std::cmp::min(
+            self.input_source.confirmed_frame(self.target_frame),
+            self.current_frame,
+        );
+        let mut text = format!(
+            "frame {}\nconfirmed {}",
+            self.current_frame, confirmed_frame
+        );
+        match self.input_source.network_stats() {
+            Some(stats) => {
+                let rtt = match stats.rtt {
+                    Some(rtt) => format!("{} ms", rtt.as_millis()),
+                    None => "-".to_string(),
+                };
+                text += &format!(
+                    "\nrtt {}\nrollbacks {}\nframe advantage {}",
+                    rtt, stats.rollbacks, stats.frame_advantage
+                );
             }
+            None => text += "\nlocal match",
         }
+        text
+    }
+
+    // checks whether an online match has ended in a KO that can no longer be rolled back
+    pub fn match_over(&self) -> bool {
+        self.ko_frame
+            .is_some_and(|frame| frame <= self.input_source.confirmed_frame(self.target_frame))
+    }
+
+    // whether the players have agreed to a rematch, once the match is over
+    pub fn rematch_status(&self) -> Option<RematchStatus> {
+        if self.match_over() {
+            self.input_source.rematch_status()
+        } else {
+            None
+        }
+    }
+
+    // ends the game, returning the session of an online match so it can be reused
+    pub fn into_session(self) -> Option<Session<Input>> {
+        self.input_source.into_session()
+    }
+
+    // checks whether the match can no longer continue
+    pub fn disconnected(&self) -> bool {
+        self.input_source.disconnected()
+    }
+}
+
+#[derive(Clone, Copy, Hash)]
+enum Side {
+    Left,
+    Right,
+}
+
+impl SyncTestGame for Game {
+    type Input = Input;
+    type State = State;
+
+    fn save_state(&self) -> State {
+        State {
+            p1: self.p1.clone(),
+            p2: self.p2.clone(),
+        }
+    }
+
+    fn load_state(&mut self, state: State) {
+        self.p1 = state.p1;
+        self.p2 = state.p2;
+    }
+
+    fn advance_frame(&mut self, inputs: &[Input]) {
+        self.simulate(&inputs[0], &inputs[1]);
+    }
+
+    fn checksum(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.p1.hash(&mut hasher);
+        self.p2.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl event::EventHandler for Game {
+    fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
+        self.advance(Some(&*ctx));
         Ok(())
     }
 
+    fn key_down_event(
+        &mut self,
+        ctx: &mut Context,
+        keycode: event::KeyCode,
+        _keymods: event::KeyMods,
+        repeat: bool,
+    ) {
+        if let Some(rebinding) = &mut self.rebinding {
+            if !repeat {
+                if let Some(bindings) = rebinding.press(keycode) {
+                    self.rebinding = None;
+                    if let Err(e) = bindings.save(&self.bindings_path) {
+                        println!("failed to save bindings: {}", e);
+                    }
+                    let path = self.bindings_path.clone();
+                    self.set_bindings(bindings, &path);
+                }
+            }
+            return;
+        }
+        if self.match_over() && !repeat {
+            match keycode {
+                event::KeyCode::Y => self.input_source.offer_rematch(true),
+                event::KeyCode::N => self.input_source.offer_rematch(false),
+                _ => {}
+            }
+        }
+        match keycode {
+            // rebinds every key in turn
+            event::KeyCode::F1 => self.rebinding = Some(Rebinding::new(self.bindings.clone())),
+            // toggles the network debug overlay
+            event::KeyCode::F3 if !repeat => self.show_hud = !self.show_hud,
+            // toggles drawing the positions corrected by rollbacks
+            event::KeyCode::F4 if !repeat => {
+                self.ghosts = match self.ghosts {
+                    Some(_) => None,
+                    None => Some(vec![]),
+                }
+            }
+            // records the training dummy's inputs, pressing again loops them
+            event::KeyCode::F5 if !repeat => match self.input_source.toggle_dummy_recording() {
+                Some(true) => println!("recording the dummy"),
+                Some(false) => println!("looping the dummy's inputs"),
+                None => {}
+            },
+            event::KeyCode::Escape => event::quit(ctx),
+            event::KeyCode::Space if !repeat => {
+                if let Some(playback) = &mut self.playback {
+                    playback.paused = !playback.paused;
+                }
+            }
+            event::KeyCode::Period => {
+                if let Some(playback) = &mut self.playback {
+                    playback.paused = true;
+                    playback.step = true;
+                    // useful for comparing against another run when looking for a desync
+                    println!(
+                        "frame {} checksum {:x}",
+                        self.current_frame,
+                        self.checksum()
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
     fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
         graphics::clear(ctx, graphics::WHITE);
 
@@ -178,6 +484,22 @@ impl event::EventHandler for Game {
             (na::Point2::new(self.p2.x as f32, self.p2.y as f32),),
         )?;
 
+        // draw the mispredicted positions, fading out as they age
+        for ghost in self.ghosts.iter().flatten() {
+            let age =
+                GHOST_FRAMES.saturating_sub(ghost.until_frame.saturating_sub(self.current_frame));
+            let alpha = 0.5 * (1.0 - age as f32 / GHOST_FRAMES as f32);
+            for &(x, y) in &[ghost.p1, ghost.p2] {
+                let outline = graphics::Mesh::new_rectangle(
+                    ctx,
+                    graphics::DrawMode::stroke(2.0),
+                    graphics::Rect::new(0.0, 0.0, 32.0, 32.0),
+                    Color::new(0.5, 0.5, 0.5, alpha),
+                )?;
+                graphics::draw(ctx, &outline, (na::Point2::new(x as f32, y as f32),))?;
+            }
+        }
+
         // draw hurtboxes
         for hurtbox in self.p1.hurtboxes.iter().chain(self.p2.hurtboxes.iter()) {
             let square = graphics::Mesh::new_rectangle(
@@ -207,6 +529,25 @@ impl event::EventHandler for Game {
             )?;
         }
 
+        if self.show_hud {
+            let hud = graphics::Text::new(self.hud_text());
+            let x = graphics::drawable_size(ctx).0 - hud.width(ctx) as f32 - 16.0;
+            graphics::draw(ctx, &hud, (na::Point2::new(x, 16.0), graphics::BLACK))?;
+        }
+        if let Some(status) = self.rematch_status() {
+            let prompt = match status {
+                RematchStatus::Pending => "KO! rematch? y/n",
+                RematchStatus::Accepted => "starting the rematch",
+                RematchStatus::Declined => "returning to the lobby",
+            };
+            let prompt = graphics::Text::new(prompt);
+            graphics::draw(ctx, &prompt, (na::Point2::new(16.0, 48.0), graphics::BLACK))?;
+        }
+        if let Some(rebinding) = &self.rebinding {
+            let prompt = graphics::Text::new(rebinding.prompt());
+            graphics::draw(ctx, &prompt, (na::Point2::new(16.0, 16.0), graphics::BLACK))?;
+        }
+
         graphics::present(ctx)
     }
 }
@@ -293,7 +634,7 @@ fn handle_player(p: &mut Player, inp: &Input, opp: &Player) {
     p.hurtboxes.push(Hitbox::for_player(&p));
 }
 
-#[derive(Clone)]
+#[derive(Clone, Hash)]
 struct Player {
     x: i32,
     y: i32,
@@ -326,7 +667,7 @@ impl Player {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Hash)]
 struct Hitbox {
     x: i32,
     y: i32,
@@ -356,14 +697,14 @@ impl Hitbox {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Hash)]
 enum PlayerState {
     Standing,
     Midair(Momentum),
     Attacking(u32),
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Hash)]
 struct Momentum {
     x: i32,
     y: i32,
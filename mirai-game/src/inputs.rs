@@ -1,21 +1,19 @@
-use bincode;
-use crossbeam_channel::{Receiver as CrossReceiver, Sender as CrossSender};
+use crate::bindings::{Bindings, PlayerBindings};
 use ggez::{event::KeyCode, input, Context};
-use laminar::{Packet, SocketEvent};
-use mirai_game_client::Client;
-use serde::{Deserialize, Serialize};
-use std::{
-    collections::BTreeMap,
-    iter::DoubleEndedIterator,
-    net::SocketAddr,
-    sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc, Mutex,
-    },
+use gilrs::{Axis, Button, Gilrs};
+use mirai_game_client::{
+    InputBuffer, Quantization, RematchStatus, SessionState, DEFAULT_HISTORY_DEPTH,
 };
+use mirai_session::MatchSession as Session;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use InputSourceKind::*;
 
+// how far the stick needs to be tilted to count as a direction
+const STICK_DEADZONE: f32 = 0.5;
+
 // top level abstraction for dealing with inputs
 pub struct InputSource {
     p1: InputSourceKind,
@@ -27,174 +25,492 @@ impl InputSource {
         Self { p1, p2 }
     }
 
-    // returns the latest fully confirmed frame = the largest frame f where 0..=f are all confirmed
-    pub fn latest_fully_confirmed(&self) -> u32 {
-        std::cmp::min(
-            self.p1.latest_fully_confirmed(),
-            self.p2.latest_fully_confirmed(),
-        )
+    // returns the earliest frame simulated with a mispredicted remote input, if any
+    pub fn rollback_frame(&mut self) -> Option<u32> {
+        let p1_frame = self.p1.rollback_frame();
+        let p2_frame = self.p2.rollback_frame();
+        match (p1_frame, p2_frame) {
+            (Some(p1), Some(p2)) => Some(std::cmp::min(p1, p2)),
+            (frame, None) | (None, frame) => frame,
+        }
+    }
+
+    // checks whether the game state should be saved after the given frame
+    pub fn should_save(&self, frame: u32) -> bool {
+        match (&self.p1, &self.p2) {
+            (Remote(is), _) | (_, Remote(is)) => is.session.should_save(frame),
+            // local inputs are never mispredicted, only the latest state is needed
+            _ => true,
+        }
     }
 
     // locks the current local inputs for the target frame and sends them to each remote source
-    pub fn progress_frame(&mut self, ctx: &mut Context, frame: u32) {
+    // keyboard inputs are read from the context, without one no keys are pressed
+    pub fn progress_frame(&mut self, ctx: Option<&Context>, frame: u32) {
         if let Local(local_source) = &mut self.p1 {
-            local_source.progress_frame(ctx);
-            if let Remote(remote_source) = &self.p2 {
-                let lower_bound = if frame > 8 { frame as usize - 7 } else { 0 };
-                let mut inputs = local_source.inputs[lower_bound..=frame as usize].to_vec();
-                inputs.reverse();
-                remote_source.send(frame, inputs);
+            let input = local_source.progress_frame(ctx);
+            if let Remote(remote_source) = &mut self.p2 {
+                remote_source.send(frame, input);
             }
         }
         if let Local(local_source) = &mut self.p2 {
-            local_source.progress_frame(ctx);
-            if let Remote(remote_source) = &self.p1 {
-                let lower_bound = if frame > 8 { frame as usize - 8 } else { 0 };
-                let mut inputs = local_source.inputs[lower_bound..=frame as usize].to_vec();
-                inputs.reverse();
-                remote_source.send(frame, inputs);
+            let input = local_source.progress_frame(ctx);
+            if let Remote(remote_source) = &mut self.p1 {
+                remote_source.send(frame, input);
             }
         }
+        // the dummy is only used in local games
+        if let Playback(dummy) = &mut self.p2 {
+            dummy.progress_frame(ctx);
+        }
+    }
+
+    // checks whether a remote source has fallen too far behind for the game to advance
+    pub fn stalled(&mut self) -> bool {
+        let p1_stalled = self.p1.stalled();
+        let p2_stalled = self.p2.stalled();
+        p1_stalled || p2_stalled
+    }
+
+    // updates the keys used by the keyboard controlled players
+    pub fn set_bindings(&mut self, bindings: &Bindings) {
+        if let Local(is) = &mut self.p1 {
+            is.set_bindings(&bindings.p1);
+        }
+        match &mut self.p2 {
+            Local(is) => is.set_bindings(&bindings.p2),
+            Playback(is) => is.local.set_bindings(&bindings.p2),
+            _ => {}
+        }
+    }
+
+    // starts recording the dummy's inputs, or starts looping them if they were being recorded
+    // returns whether the dummy is now recording, or None if there is no dummy
+    pub fn toggle_dummy_recording(&mut self) -> Option<bool> {
+        match &mut self.p2 {
+            Playback(dummy) => Some(dummy.toggle_recording()),
+            _ => None,
+        }
+    }
+
+    // returns the latest frame up to which the inputs of every source are final
+    pub fn confirmed_frame(&self, target_frame: u32) -> u32 {
+        match (&self.p1, &self.p2) {
+            (Remote(is), _) | (_, Remote(is)) => is
+                .session
+                .latest_fully_confirmed()
+                .expect("failed to check confirmed frame"),
+            _ => target_frame,
+        }
+    }
+
+    // returns the state of the connection to the remote source, if there is one
+    pub fn network_stats(&self) -> Option<NetworkStats> {
+        match (&self.p1, &self.p2) {
+            (Remote(is), _) | (_, Remote(is)) => Some(is.network_stats()),
+            _ => None,
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        matches!((&self.p1, &self.p2), (Remote(_), _) | (_, Remote(_)))
+    }
+
+    // answers a rematch, does nothing in local games
+    pub fn offer_rematch(&mut self, accept: bool) {
+        if let (Remote(is), _) | (_, Remote(is)) = (&mut self.p1, &mut self.p2) {
+            is.session
+                .offer_rematch(accept)
+                .expect("failed to answer the rematch");
+        }
+    }
+
+    pub fn rematch_status(&self) -> Option<RematchStatus> {
+        match (&self.p1, &self.p2) {
+            (Remote(is), _) | (_, Remote(is)) => Some(
+                is.session
+                    .rematch_status()
+                    .expect("failed to check the rematch"),
+            ),
+            _ => None,
+        }
+    }
+
+    pub fn into_session(self) -> Option<Session<Input>> {
+        match (self.p1, self.p2) {
+            (Remote(is), _) | (_, Remote(is)) => Some(is.session),
+            _ => None,
+        }
+    }
+
+    // checks whether a remote source has been disconnected for good
+    pub fn disconnected(&self) -> bool {
+        match (&self.p1, &self.p2) {
+            (Remote(is), _) | (_, Remote(is)) => is.disconnected,
+            _ => false,
+        }
+    }
+
+    // lets the input sources know the game state was saved at the given frame, so older inputs can be discarded
+    // returns the frame of the oldest state that may still be needed for rollback
+    pub fn state_saved(&mut self, frame: u32) -> u32 {
+        let restore_point = std::cmp::min(self.p1.state_saved(frame), self.p2.state_saved(frame));
+        if let Local(is) = &mut self.p1 {
+            is.inputs.prune(restore_point);
+        }
+        match &mut self.p2 {
+            Local(is) => is.inputs.prune(restore_point),
+            Playback(is) => is.local.inputs.prune(restore_point),
+            _ => {}
+        }
+        restore_point
     }
 
     // fetch input for the given frame for p1
-    pub fn p1_input_for(&self, frame: u32) -> Input {
-        match &self.p1 {
+    pub fn p1_input_for(&mut self, frame: u32) -> Input {
+        match &mut self.p1 {
             Local(is) => is.input_for(frame),
             Remote(is) => is.input_for(frame),
+            Replayed(is) => is.input_for(frame),
+            Playback(is) => is.local.input_for(frame),
         }
     }
 
     // fetch input for the given frame for p2
-    pub fn p2_input_for(&self, frame: u32) -> Input {
-        match &self.p2 {
+    pub fn p2_input_for(&mut self, frame: u32) -> Input {
+        match &mut self.p2 {
             Local(is) => is.input_for(frame),
             Remote(is) => is.input_for(frame),
+            Replayed(is) => is.input_for(frame),
+            Playback(is) => is.local.input_for(frame),
         }
     }
 }
 
-// differentiation between local and remote input sources, i.e. keyboard vs socket
+// differentiation between local, remote and recorded input sources, i.e. keyboard or script vs socket vs replay
 pub enum InputSourceKind {
     Local(LocalInputSource),
     Remote(RemoteInputSource),
+    Replayed(ReplayedInputSource),
+    Playback(PlaybackInputSource),
 }
 
 impl InputSourceKind {
     pub fn local(left_keycode: KeyCode, right_keycode: KeyCode, attack_keycode: KeyCode) -> Self {
-        Self::Local(LocalInputSource::new(
+        Self::Local(LocalInputSource::new(Controls::Keyboard {
             left_keycode,
             right_keycode,
             attack_keycode,
-        ))
+        }))
+    }
+
+    // a local player using the nth connected gamepad
+    pub fn gamepad(index: usize) -> Self {
+        let gilrs = Gilrs::new().expect("failed to initialize gamepad support");
+        Self::Local(LocalInputSource::new(Controls::Gamepad { gilrs, index }))
+    }
+
+    // a local player that plays scripted inputs instead of reading the keyboard
+    pub fn scripted() -> Self {
+        Self::Local(LocalInputSource::new(Controls::Scripted))
+    }
+
+    pub fn remote(session: Session<Input>) -> Self {
+        Self::Remote(RemoteInputSource::new(session))
+    }
+
+    // plays back the given inputs, starting from frame 1
+    pub fn replayed(inputs: Vec<Input>) -> Self {
+        Self::Replayed(ReplayedInputSource { inputs })
+    }
+
+    // a training dummy that loops inputs recorded with the given keys
+    pub fn playback(
+        left_keycode: KeyCode,
+        right_keycode: KeyCode,
+        attack_keycode: KeyCode,
+    ) -> Self {
+        Self::Playback(PlaybackInputSource {
+            local: LocalInputSource::new(Controls::Keyboard {
+                left_keycode,
+                right_keycode,
+                attack_keycode,
+            }),
+            recording: None,
+            inputs_loop: vec![],
+            loop_start: 0,
+        })
+    }
+
+    fn rollback_frame(&mut self) -> Option<u32> {
+        match self {
+            Local(_) | Replayed(_) | Playback(_) => None,
+            Remote(is) => is.rollback_frame(),
+        }
     }
 
-    pub fn remote(client: Client) -> Self {
-        Self::Remote(RemoteInputSource::new(client))
+    fn stalled(&mut self) -> bool {
+        match self {
+            Local(_) | Replayed(_) | Playback(_) => false,
+            Remote(is) => is.stalled(),
+        }
     }
 
-    pub fn latest_fully_confirmed(&self) -> u32 {
+    fn state_saved(&mut self, frame: u32) -> u32 {
         match self {
-            Local(is) => is.target_frame,
-            Remote(is) => is.latest_fully_confirmed(),
+            // local inputs can be discarded once the remote sources are done with them
+            Local(_) | Replayed(_) | Playback(_) => frame,
+            Remote(is) => is.state_saved(frame),
         }
     }
 }
 
-struct LocalInputSource {
-    left_keycode: KeyCode,
-    right_keycode: KeyCode,
-    attack_keycode: KeyCode,
+struct ReplayedInputSource {
     inputs: Vec<Input>,
+}
+
+impl ReplayedInputSource {
+    fn input_for(&self, frame: u32) -> Input {
+        match frame.checked_sub(1) {
+            Some(index) => self.inputs.get(index as usize).copied().unwrap_or_default(),
+            None => Input::default(),
+        }
+    }
+}
+
+// loops a recorded sequence of inputs, stands still until something has been recorded
+// while recording, the dummy is controlled with its keys instead
+struct PlaybackInputSource {
+    local: LocalInputSource,
+    recording: Option<Vec<Input>>,
+    inputs_loop: Vec<Input>,
+    // the frame the loop was started at
+    loop_start: u32,
+}
+
+impl PlaybackInputSource {
+    fn progress_frame(&mut self, ctx: Option<&Context>) {
+        let input = self.local.progress_frame(ctx);
+        let frame = self.local.target_frame;
+        match &mut self.recording {
+            Some(recording) => recording.push(input),
+            None => {
+                let input = if self.inputs_loop.is_empty() {
+                    Input::default()
+                } else {
+                    let index = frame.saturating_sub(self.loop_start) as usize;
+                    self.inputs_loop[index % self.inputs_loop.len()]
+                };
+                self.local.inputs.set(frame, input);
+            }
+        }
+    }
+
+    fn toggle_recording(&mut self) -> bool {
+        match self.recording.take() {
+            Some(recording) => {
+                self.inputs_loop = recording;
+                self.loop_start = self.local.target_frame + 1;
+                false
+            }
+            None => {
+                self.recording = Some(vec![]);
+                true
+            }
+        }
+    }
+}
+
+enum Controls {
+    Keyboard {
+        left_keycode: KeyCode,
+        right_keycode: KeyCode,
+        attack_keycode: KeyCode,
+    },
+    Gamepad {
+        gilrs: Gilrs,
+        index: usize,
+    },
+    Scripted,
+}
+
+struct LocalInputSource {
+    controls: Controls,
+    inputs: InputBuffer<Input>,
     target_frame: u32,
 }
 
 impl LocalInputSource {
-    fn new(left_keycode: KeyCode, right_keycode: KeyCode, attack_keycode: KeyCode) -> Self {
-        let inputs = vec![Input::default()];
+    fn new(controls: Controls) -> Self {
+        let mut inputs = InputBuffer::new(DEFAULT_HISTORY_DEPTH);
+        inputs.insert(0, Input::default());
         LocalInputSource {
-            left_keycode,
-            right_keycode,
-            attack_keycode,
+            controls,
             inputs,
             target_frame: 0,
         }
     }
     fn input_for(&self, frame: u32) -> Input {
-        self.inputs[frame as usize]
+        self.inputs.latest_at(frame).unwrap_or_default()
+    }
+    fn set_bindings(&mut self, bindings: &PlayerBindings) {
+        if let Controls::Keyboard {
+            left_keycode,
+            right_keycode,
+            attack_keycode,
+        } = &mut self.controls
+        {
+            let (left, right, attack) = bindings.keys();
+            *left_keycode = left;
+            *right_keycode = right;
+            *attack_keycode = attack;
+        }
     }
     // progress target_frame, set the current input for that frame
-    fn progress_frame(&mut self, ctx: &mut Context) {
+    fn progress_frame(&mut self, ctx: Option<&Context>) -> Input {
         self.target_frame += 1;
-        let current_input = Input {
-            left: input::keyboard::is_key_pressed(ctx, self.left_keycode),
-            right: input::keyboard::is_key_pressed(ctx, self.right_keycode),
-            attack: input::keyboard::is_key_pressed(ctx, self.attack_keycode),
+        let current_input = match (&mut self.controls, ctx) {
+            (
+                Controls::Keyboard {
+                    left_keycode,
+                    right_keycode,
+                    attack_keycode,
+                },
+                Some(ctx),
+            ) => Input {
+                left: input::keyboard::is_key_pressed(ctx, *left_keycode),
+                right: input::keyboard::is_key_pressed(ctx, *right_keycode),
+                attack: input::keyboard::is_key_pressed(ctx, *attack_keycode),
+            },
+            (Controls::Keyboard { .. }, None) => Input::default(),
+            (Controls::Gamepad { gilrs, index }, _) => gamepad_input(gilrs, *index),
+            (Controls::Scripted, _) => scripted_input(self.target_frame),
         };
-        self.inputs.push(current_input);
+        self.inputs.insert(self.target_frame, current_input);
+        current_input
     }
 }
 
-enum Message {
-    Inputs(u32, Vec<Input>),
+// reads the state of the nth connected gamepad, if it is not connected no buttons are pressed
+fn gamepad_input(gilrs: &mut Gilrs, index: usize) -> Input {
+    // the gamepad state is only updated when its events are processed
+    while gilrs.next_event().is_some() {}
+    let gamepad = match gilrs.gamepads().nth(index) {
+        Some((_, gamepad)) => gamepad,
+        None => return Input::default(),
+    };
+    // the stick is treated as a digital direction
+    let stick = Quantization::new(1, STICK_DEADZONE).axis(gamepad.value(Axis::LeftStickX));
+    Input {
+        left: gamepad.is_pressed(Button::DPadLeft) || stick.0 < 0,
+        right: gamepad.is_pressed(Button::DPadRight) || stick.0 > 0,
+        attack: gamepad.is_pressed(Button::South),
+    }
+}
+
+// walks back and forth, attacking every now and then
+fn scripted_input(frame: u32) -> Input {
+    let walking_right = (frame / 48).is_multiple_of(2);
+    Input {
+        left: !walking_right,
+        right: walking_right,
+        attack: frame.is_multiple_of(20),
+    }
 }
 
 struct RemoteInputSource {
-    client: Client,
-    sender: Sender<Message>,
-    inputs: Arc<Mutex<BTreeMap<u32, Input>>>,
-    latest_fully_confirmed: Arc<Mutex<u32>>,
+    session: Session<Input>,
+    opp_addr: SocketAddr,
+    disconnected: bool,
 }
 
 impl RemoteInputSource {
-    fn new(client: Client) -> Self {
-        // start thread
-        let mut inputs = BTreeMap::new();
-        inputs.insert(0, Input::default());
-        let inputs = Arc::new(Mutex::new(inputs));
-        let thread_inputs = Arc::clone(&inputs);
-        let latest_fully_confirmed = Arc::new(Mutex::new(0));
-        let thread_latest_fully_confirmed = Arc::clone(&latest_fully_confirmed);
-        let (sender, receiver) = channel();
+    fn new(mut session: Session<Input>) -> Self {
+        // the demo is played against a single opponent
+        let opp_addr = session.peers()[0];
+        session.set_prediction(predict_input);
+        session.set_metrics_callback(|metrics| println!("{:?}", metrics));
         Self {
-            client,
-            sender,
-            inputs,
-            latest_fully_confirmed,
+            session,
+            opp_addr,
+            disconnected: false,
         }
     }
 
     // fetch input for the given frame, if not available then fetch latest input we have before that frame
-    fn input_for(&self, frame: u32) -> Input {
-        let inputs = self.inputs.lock().expect("failed to get lock for inputs");
-        if let Some(&input) = inputs.get(&frame) {
-            input
-        } else {
-            *inputs.range(0..frame).next_back().expect("empty range").1
+    fn input_for(&mut self, frame: u32) -> Input {
+        let input = self
+            .session
+            .remote_input_for(self.opp_addr, frame)
+            .expect("failed to get remote input");
+        // reverse inputs, the opponent is playing as p1 but on our side they are p2
+        Input {
+            left: input.right,
+            right: input.left,
+            ..input
         }
     }
 
-    fn send(&self, frame: u32, inputs: Vec<Input>) {
-        self.sender
-            .send(Message::Inputs(frame, inputs))
+    fn send(&mut self, frame: u32, input: Input) {
+        self.session
+            .add_local_input(frame, input)
             .expect("failed to send inputs");
     }
 
-    pub fn latest_fully_confirmed(&self) -> u32 {
-        *self
-            .latest_fully_confirmed
-            .lock()
-            .expect("failed to get lock for confirm")
+    fn stalled(&mut self) -> bool {
+        let state = self.session.update().expect("failed to update session");
+        for event in self.session.events() {
+            println!("{:?}", event);
+        }
+        // a disconnected match never resumes, the game stays frozen
+        self.disconnected = state == SessionState::Disconnected;
+        state != SessionState::Running
+    }
+
+    fn network_stats(&self) -> NetworkStats {
+        NetworkStats {
+            rtt: self
+                .session
+                .round_trip_time(self.opp_addr)
+                .expect("failed to check round trip time"),
+            rollbacks: self.session.metrics().rollbacks,
+            frame_advantage: self
+                .session
+                .frame_advantage(self.opp_addr)
+                .expect("failed to check frame advantage"),
+        }
+    }
+
+    fn rollback_frame(&mut self) -> Option<u32> {
+        self.session
+            .rollback_frame()
+            .expect("failed to check predictions")
+    }
+
+    fn state_saved(&mut self, frame: u32) -> u32 {
+        self.session
+            .state_saved(frame)
+            .expect("failed to discard old inputs");
+        self.session.restore_point()
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct NetworkInput {
-    frame: u32,
-    inputs: Vec<Input>, // stored in reverse order: [input for frame, input for frame - 1, ...]
+// shown in the debug overlay
+pub struct NetworkStats {
+    pub rtt: Option<Duration>,
+    pub rollbacks: u32,
+    pub frame_advantage: i64,
+}
+
+// directions tend to be held for a while, attacks are single presses
+fn predict_input(previous: Option<Input>, _frame: u32) -> Input {
+    Input {
+        attack: false,
+        ..previous.unwrap_or_default()
+    }
 }
 
-#[derive(Copy, Clone, Default, Serialize, Deserialize)]
+#[derive(Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Input {
     pub left: bool,
     pub right: bool,
@@ -1,14 +1,23 @@
+mod bindings;
+mod bot;
 mod game;
 mod inputs;
+mod menu;
+mod netsim;
 
+use bindings::{Bindings, DEFAULT_BINDINGS_PATH};
 use game::Game;
-use ggez::event::KeyCode;
 use ggez::*;
 use inputs::*;
-use mirai_game_client::Client as GameClient;
-use mirai_matchmaking_client::{Client, PeerStatus};
+use menu::{App, Menu, Setup};
+use mirai_game_client::Replay;
+use mirai_session::{Config, MatchSession as Session, Phase, Session as MiraiSession};
+use netsim::NetworkConditions;
 use std::env;
-use std::io::Result;
+use std::fs::File;
+use std::io::{BufWriter, Result};
+use std::net::IpAddr;
+use std::time::Duration;
 
 const LOCAL_IP: &str = "127.0.0.1";
 
@@ -16,70 +25,104 @@ fn main() -> Result<()> {
     let args: Vec<_> = env::args().collect();
     let server_ip = &args.get(1).expect("missing server IP");
     let server_ip = server_ip.parse().expect("invalid format for server IP");
-    let p1_input = InputSourceKind::local(KeyCode::A, KeyCode::D, KeyCode::S);
-    let p2_input;
-
-    let single_player;
-    let mut s = String::new();
-    println!("input 1 for single player, 2 for multi");
-    std::io::stdin().read_line(&mut s).unwrap();
-    match s.trim().parse() {
-        Ok(1) => single_player = true,
-        Ok(2) => single_player = false,
-        _ => panic!("invalid value"),
+    let local_ip = flag_value(&args, "--local-ip")
+        .unwrap_or(LOCAL_IP)
+        .parse()
+        .expect("invalid format for local IP");
+    // simulated network conditions, e.g. --latency 120 --jitter 10 --loss 5
+    let conditions = NetworkConditions {
+        latency: Duration::from_millis(
+            flag_value(&args, "--latency").map_or(0, |ms| ms.parse().expect("invalid latency")),
+        ),
+        jitter: Duration::from_millis(
+            flag_value(&args, "--jitter").map_or(0, |ms| ms.parse().expect("invalid jitter")),
+        ),
+        loss: flag_value(&args, "--loss").map_or(0.0, |percent| {
+            percent.parse::<f64>().expect("invalid loss") / 100.0
+        }),
+    };
+    // the confirmed inputs of the match are written to this file at exit
+    let record_path = flag_value(&args, "--record");
+    if args.iter().any(|arg| arg == "--bot") {
+        let replay = bot::run(local_ip, server_ip, conditions);
+        if let Some(path) = record_path {
+            save_replay(&replay, path)?;
+        }
+        return Ok(());
     }
 
-    if single_player {
-        p2_input = InputSourceKind::local(KeyCode::Left, KeyCode::Right, KeyCode::Down);
-    } else {
-        // matchmaking
-
-        let mut client = Client::new(LOCAL_IP.parse().unwrap(), server_ip).unwrap();
-        // matchmaking
-        client.dequeue().unwrap();
-        client.queue().unwrap();
-        let opp = 'ret: loop {
-            let matches = client.peers().unwrap();
-            for mut peer in matches {
-                if let Some(l) = peer.latency() {
-                    match peer.status() {
-                        PeerStatus::Confirmed => {
-                            println!("confirmed");
-                            break 'ret peer;
-                        }
-                        PeerStatus::IncomingChallenge => {
-                            println!("accepting");
-                            client.accept(&mut peer).unwrap();
-                        }
-                        PeerStatus::None => {
-                            println!("challenging");
-                            client.challenge(&mut peer).unwrap();
-                        }
-                        _ => {}
-                    }
-                }
-            }
-            std::thread::sleep(std::time::Duration::from_secs(1));
-        };
-
-        client.dequeue().unwrap();
-        let (receiver, sender) = client.close().unwrap();
-        let client = GameClient::new(opp.addr(), receiver, sender);
-        while let Some(_) = client.check_time_until_start() {}
-        p2_input = InputSourceKind::remote(client);
-    }
+    // key bindings for the local players, rebound in game with F1
+    let bindings_path = flag_value(&args, "--bindings").unwrap_or(DEFAULT_BINDINGS_PATH);
+    let bindings = Bindings::load(bindings_path).expect("failed to load key bindings");
 
-    let input_source = InputSource::new(p1_input, p2_input);
-    let mut my_game = Game::new(input_source);
+    let menu = Menu::new(Setup {
+        local_ip,
+        server_ip,
+        conditions,
+        bindings,
+        bindings_path: bindings_path.to_string(),
+        // --gamepad lets player 1 use the first connected gamepad instead of the keyboard
+        use_gamepad: args.iter().any(|arg| arg == "--gamepad"),
+    });
+    let game = match flag_value(&args, "--replay") {
+        Some(path) => {
+            let replay = Replay::read(File::open(path)?).expect("failed to read replay");
+            Some(Game::playback(&replay))
+        }
+        None => None,
+    };
+    let mut app = App::new(menu, game);
 
     let (mut ctx, mut event_loop) = ContextBuilder::new("gemu", "Heliozoa")
         .window_mode(conf::WindowMode::default().dimensions(640.0, 480.0))
         .build()
         .unwrap();
 
-    match event::run(&mut ctx, &mut event_loop, &mut my_game) {
+    match event::run(&mut ctx, &mut event_loop, &mut app) {
         Ok(_) => println!("Exited cleanly."),
         Err(e) => println!("Error occured: {}", e),
     }
+    if let (Some(path), Some(game)) = (record_path, app.game()) {
+        save_replay(game.replay(), path)?;
+    }
+    Ok(())
+}
+
+fn save_replay(replay: &Replay<Input>, path: &str) -> Result<()> {
+    replay
+        .write(BufWriter::new(File::create(path)?))
+        .expect("failed to write replay");
+    println!("saved the replay to {}", path);
     Ok(())
 }
+
+// returns the value following the given flag in the arguments
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let i = args.iter().position(|arg| arg == flag)?;
+    let value = args
+        .get(i + 1)
+        .unwrap_or_else(|| panic!("missing value for {}", flag));
+    Some(value)
+}
+
+// queues with the server and waits until a match starts, used by the headless bot
+fn find_match(
+    local_ip: IpAddr,
+    server_ip: IpAddr,
+    conditions: NetworkConditions,
+) -> Session<Input> {
+    let mut session = MiraiSession::new(local_ip, server_ip, Config::default()).unwrap();
+    if !conditions.is_perfect() {
+        session.set_socket_wrapper(move |socket| netsim::simulate(conditions, socket));
+    }
+    let mut phase = Phase::Searching;
+    while phase != Phase::InMatch {
+        let next = session.poll().unwrap();
+        if next != phase {
+            println!("{:?}", next);
+            phase = next;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    session.into_game().unwrap()
+}
@@ -0,0 +1,410 @@
+// the menus shown before a match: choosing the game mode and picking an opponent from the matchmaking lobby
+
+use crate::bindings::Bindings;
+use crate::game::Game;
+use crate::inputs::{Input, InputSource, InputSourceKind};
+use crate::netsim::{self, NetworkConditions};
+use ggez::event::{self, EventHandler};
+use ggez::graphics;
+use ggez::nalgebra as na;
+use ggez::{Context, GameResult};
+use mirai_game_client::{RematchStatus, SessionConfig};
+use mirai_matchmaking_client::{
+    bind_transport, secure_transport, Client, ClientError, Peer, PeerStatus,
+};
+use mirai_session::{MatchSession as Session, Socket};
+use std::net::{IpAddr, SocketAddr};
+
+const MODES: [&str; 4] = ["single player", "multiplayer", "sync test", "training"];
+
+// everything needed to set up a game, given on the command line
+pub struct Setup {
+    pub local_ip: IpAddr,
+    pub server_ip: IpAddr,
+    pub conditions: NetworkConditions,
+    pub bindings: Bindings,
+    pub bindings_path: String,
+    pub use_gamepad: bool,
+}
+
+// shows the menu until a game starts, then the game
+// after an online match the players either start a rematch or return to the lobby
+pub struct App {
+    menu: Menu,
+    game: Option<Game>,
+}
+
+impl App {
+    pub fn new(menu: Menu, game: Option<Game>) -> Self {
+        Self { menu, game }
+    }
+
+    // the game, if one was started
+    pub fn game(&self) -> Option<&Game> {
+        self.game.as_ref()
+    }
+}
+
+impl EventHandler for App {
+    fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let game = match &mut self.game {
+            Some(game) => game,
+            None => {
+                self.game = self.menu.update();
+                return Ok(());
+            }
+        };
+        game.update(ctx)?;
+        match game.rematch_status() {
+            Some(RematchStatus::Accepted) => {
+                let session = self.take_session();
+                self.game = self.menu.rematch(session);
+            }
+            Some(RematchStatus::Declined) => {
+                let session = self.take_session();
+                self.menu.requeue(session);
+            }
+            Some(RematchStatus::Pending) | None => {}
+        }
+        Ok(())
+    }
+
+    fn key_down_event(
+        &mut self,
+        ctx: &mut Context,
+        keycode: event::KeyCode,
+        keymods: event::KeyMods,
+        repeat: bool,
+    ) {
+        match &mut self.game {
+            Some(game) => game.key_down_event(ctx, keycode, keymods, repeat),
+            None => self.game = self.menu.key_down(ctx, keycode, repeat),
+        }
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
+        match &mut self.game {
+            Some(game) => game.draw(ctx),
+            None => self.menu.draw(ctx),
+        }
+    }
+}
+
+impl App {
+    fn take_session(&mut self) -> Session<Input> {
+        self.game
+            .take()
+            .and_then(Game::into_session)
+            .expect("rematch without a session")
+    }
+}
+
+enum Screen {
+    Modes { selected: usize },
+    Lobby(Lobby),
+}
+
+// queued with the server, showing the peers it has sent
+struct Lobby {
+    client: Client<Socket>,
+    // sorted by address so that the selection stays put as the list is refreshed
+    peers: Vec<Peer>,
+    // the generation of the peers above, they're only copied from the client when it changes
+    peers_generation: u64,
+    selected: usize,
+    // set when the socket was handed back after a match and already goes through the simulated network
+    simulated: bool,
+}
+
+pub struct Menu {
+    setup: Setup,
+    screen: Screen,
+    // the latest error, shown until the next action
+    message: Option<String>,
+}
+
+impl Menu {
+    pub fn new(setup: Setup) -> Self {
+        Self {
+            setup,
+            screen: Screen::Modes { selected: 0 },
+            message: None,
+        }
+    }
+
+    // refreshes the lobby, returns the game once a match has been confirmed
+    fn update(&mut self) -> Option<Game> {
+        let lobby = match &mut self.screen {
+            Screen::Lobby(lobby) => lobby,
+            Screen::Modes { .. } => return None,
+        };
+        match lobby.client.check_group_match() {
+            Ok(Some(peers)) => {
+                let lobby = match std::mem::replace(&mut self.screen, Screen::Modes { selected: 1 })
+                {
+                    Screen::Lobby(lobby) => lobby,
+                    Screen::Modes { .. } => unreachable!("left the lobby while starting a match"),
+                };
+                return match self.start_match(lobby, peers) {
+                    Ok(game) => Some(game),
+                    Err(e) => {
+                        self.message = Some(format!("failed to start the match: {}", e));
+                        None
+                    }
+                };
+            }
+            Ok(None) => {}
+            Err(e) => self.message = Some(format!("matchmaking failed: {}", e)),
+        }
+        if let Ok(Some((generation, peers))) = lobby.client.peers_if_changed(lobby.peers_generation)
+        {
+            lobby.peers_generation = generation;
+            let mut peers = peers.into_iter().collect::<Vec<_>>();
+            peers.sort_by_key(|peer| peer.addr());
+            lobby.selected = std::cmp::min(lobby.selected, peers.len().saturating_sub(1));
+            lobby.peers = peers;
+        }
+        None
+    }
+
+    // up and down move the selection, return picks the mode
+    // in the lobby c challenges the selected peer, a accepts and d declines its challenge and r requeues
+    fn key_down(
+        &mut self,
+        ctx: &mut Context,
+        keycode: event::KeyCode,
+        repeat: bool,
+    ) -> Option<Game> {
+        if repeat {
+            return None;
+        }
+        self.message = None;
+        if let (Screen::Lobby(_), event::KeyCode::Escape) = (&self.screen, keycode) {
+            self.leave_lobby();
+            return None;
+        }
+        match &mut self.screen {
+            Screen::Modes { selected } => match keycode {
+                event::KeyCode::Up => *selected = selected.saturating_sub(1),
+                event::KeyCode::Down => *selected = std::cmp::min(*selected + 1, MODES.len() - 1),
+                event::KeyCode::Return => {
+                    let selected = *selected;
+                    return self.select_mode(selected);
+                }
+                event::KeyCode::Escape => event::quit(ctx),
+                _ => {}
+            },
+            Screen::Lobby(lobby) => {
+                let result = match (keycode, lobby.peers.get_mut(lobby.selected)) {
+                    (event::KeyCode::Up, _) => {
+                        lobby.selected = lobby.selected.saturating_sub(1);
+                        Ok(())
+                    }
+                    (event::KeyCode::Down, _) => {
+                        lobby.selected =
+                            std::cmp::min(lobby.selected + 1, lobby.peers.len().saturating_sub(1));
+                        Ok(())
+                    }
+                    (event::KeyCode::C, Some(peer)) => lobby.client.challenge(peer),
+                    (event::KeyCode::A, Some(peer)) => lobby.client.accept(peer),
+                    (event::KeyCode::D, Some(peer)) => lobby.client.decline(peer.addr()),
+                    (event::KeyCode::R, _) => requeue(&mut lobby.client),
+                    _ => Ok(()),
+                };
+                if let Err(e) = result {
+                    self.message = Some(format!("matchmaking failed: {}", e));
+                }
+            }
+        }
+        None
+    }
+
+    fn select_mode(&mut self, selected: usize) -> Option<Game> {
+        match selected {
+            0 => {
+                let (left, right, attack) = self.setup.bindings.p2.keys();
+                Some(self.start_local(InputSourceKind::local(left, right, attack), false))
+            }
+            1 => {
+                match self.join_lobby() {
+                    Ok(lobby) => self.screen = Screen::Lobby(lobby),
+                    Err(e) => self.message = Some(e),
+                }
+                None
+            }
+            2 => {
+                let (left, right, attack) = self.setup.bindings.p2.keys();
+                Some(self.start_local(InputSourceKind::local(left, right, attack), true))
+            }
+            // player 2 is a dummy that is recorded with player 2's keys, see F5 in game
+            _ => {
+                let (left, right, attack) = self.setup.bindings.p2.keys();
+                Some(self.start_local(InputSourceKind::playback(left, right, attack), false))
+            }
+        }
+    }
+
+    fn join_lobby(&self) -> Result<Lobby, String> {
+        let transport = bind_transport(self.setup.local_ip)
+            .and_then(|transport| secure_transport(transport, self.setup.server_ip))
+            .map_err(|e| format!("failed to create the client: {}", e))?;
+        let mut client = Client::with_transport(self.setup.server_ip, transport);
+        client
+            .queue()
+            .map_err(|e| format!("failed to queue: {}", e))?;
+        Ok(Lobby {
+            client,
+            peers: vec![],
+            peers_generation: 0,
+            selected: 0,
+            simulated: false,
+        })
+    }
+
+    // hands the socket of a finished match back to the matchmaking client and queues again
+    fn requeue(&mut self, session: Session<Input>) {
+        let socket = match session.into_socket() {
+            Ok(socket) => socket,
+            Err(e) => {
+                self.message = Some(format!("failed to end the match: {}", e));
+                return;
+            }
+        };
+        let mut client = Client::with_transport(self.setup.server_ip, socket);
+        match client.queue() {
+            Ok(()) => {
+                self.screen = Screen::Lobby(Lobby {
+                    client,
+                    peers: vec![],
+                    peers_generation: 0,
+                    selected: 0,
+                    simulated: !self.setup.conditions.is_perfect(),
+                })
+            }
+            Err(e) => self.message = Some(format!("failed to queue: {}", e)),
+        }
+    }
+
+    fn rematch(&mut self, session: Session<Input>) -> Option<Game> {
+        match session.rematch() {
+            Ok(session) => {
+                let input_source =
+                    InputSource::new(self.p1_input(), InputSourceKind::remote(session));
+                Some(self.with_bindings(Game::new(input_source)))
+            }
+            Err(e) => {
+                self.message = Some(format!("failed to start the rematch: {}", e));
+                None
+            }
+        }
+    }
+
+    fn leave_lobby(&mut self) {
+        if let Screen::Lobby(lobby) =
+            std::mem::replace(&mut self.screen, Screen::Modes { selected: 1 })
+        {
+            let client = lobby.client;
+            if let Err(e) = client.dequeue().and_then(|_| client.close()) {
+                self.message = Some(format!("failed to leave the queue: {}", e));
+            }
+        }
+    }
+
+    fn p1_input(&self) -> InputSourceKind {
+        if self.setup.use_gamepad {
+            InputSourceKind::gamepad(0)
+        } else {
+            let (left, right, attack) = self.setup.bindings.p1.keys();
+            InputSourceKind::local(left, right, attack)
+        }
+    }
+
+    fn start_local(&self, p2_input: InputSourceKind, sync_test: bool) -> Game {
+        let input_source = InputSource::new(self.p1_input(), p2_input);
+        let game = if sync_test {
+            Game::sync_test(input_source)
+        } else {
+            Game::new(input_source)
+        };
+        self.with_bindings(game)
+    }
+
+    // hands the socket over to the game client
+    fn start_match(&self, lobby: Lobby, peers: Vec<SocketAddr>) -> Result<Game, ClientError> {
+        lobby.client.dequeue()?;
+        // relayed through the server if the peers couldn't reach each other
+        let mut socket: Socket = Box::new(lobby.client.into_peer_connection()?);
+        if !lobby.simulated && !self.setup.conditions.is_perfect() {
+            socket = netsim::simulate(self.setup.conditions, socket);
+        }
+        let session = Session::new(peers, socket, SessionConfig::default());
+        let input_source = InputSource::new(self.p1_input(), InputSourceKind::remote(session));
+        Ok(self.with_bindings(Game::new(input_source)))
+    }
+
+    fn with_bindings(&self, mut game: Game) -> Game {
+        game.set_bindings(self.setup.bindings.clone(), &self.setup.bindings_path);
+        game
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
+        graphics::clear(ctx, graphics::WHITE);
+        let mut lines = vec![];
+        match &self.screen {
+            Screen::Modes { selected } => {
+                lines.push("choose a mode with the arrow keys and return".to_string());
+                for (i, mode) in MODES.iter().enumerate() {
+                    lines.push(format!("{} {}", cursor(i == *selected), mode));
+                }
+            }
+            Screen::Lobby(lobby) => {
+                lines.push(
+                    "c: challenge, a: accept, d: decline, r: requeue, escape: back".to_string(),
+                );
+                if lobby.peers.is_empty() {
+                    lines.push("waiting for peers...".to_string());
+                }
+                for (i, peer) in lobby.peers.iter().enumerate() {
+                    let latency = match peer.latency() {
+                        Some(latency) => format!("{} ms", latency.as_millis()),
+                        None => "-".to_string(),
+                    };
+                    let status = match peer.status() {
+                        PeerStatus::None => "",
+                        PeerStatus::OutgoingChallenge => "challenged",
+                        PeerStatus::IncomingChallenge => "challenges you",
+                        PeerStatus::Confirmed => "confirmed",
+                    };
+                    lines.push(format!(
+                        "{} {} {} {}",
+                        cursor(i == lobby.selected),
+                        peer.addr(),
+                        latency,
+                        status
+                    ));
+                }
+            }
+        }
+        if let Some(message) = &self.message {
+            lines.push(String::new());
+            lines.push(message.clone());
+        }
+        let text = graphics::Text::new(lines.join("\n"));
+        graphics::draw(ctx, &text, (na::Point2::new(16.0, 16.0), graphics::BLACK))?;
+        graphics::present(ctx)
+    }
+}
+
+// asks the server for a new set of peers
+fn requeue(client: &mut Client<Socket>) -> Result<(), ClientError> {
+    client.dequeue()?;
+    client.queue()
+}
+
+fn cursor(selected: bool) -> &'static str {
+    if selected {
+        ">"
+    } else {
+        " "
+    }
+}
@@ -0,0 +1,34 @@
+// simulates a worse network by delaying and dropping packets between the transport and the game client
+
+use mirai_core::transport::{impair, Impairment, LatencyDistribution};
+use mirai_session::Socket;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Default)]
+pub struct NetworkConditions {
+    // added to the round trip time, split evenly between both directions
+    pub latency: Duration,
+    // each packet is delayed by a random extra amount up to this
+    pub jitter: Duration,
+    // the chance for each packet to be dropped in each direction, 0..=1
+    pub loss: f64,
+}
+
+impl NetworkConditions {
+    pub fn is_perfect(&self) -> bool {
+        self.latency == Duration::from_millis(0)
+            && self.jitter == Duration::from_millis(0)
+            && self.loss <= 0.0
+    }
+}
+
+// wraps the transport, returning one that behaves like the original under the given conditions
+pub fn simulate(conditions: NetworkConditions, transport: Socket) -> Socket {
+    let impairment = Impairment {
+        latency: LatencyDistribution::Constant(conditions.latency / 2),
+        jitter: conditions.jitter,
+        loss: conditions.loss,
+        ..Impairment::default()
+    };
+    Box::new(impair(transport, impairment))
+}
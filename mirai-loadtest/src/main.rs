@@ -0,0 +1,248 @@
+//! Load tests a matchmaking server with simulated clients.
+//!
+//! Half of the clients challenge the other half, which accept every challenge. Matched clients requeue
+//! right away, and clients that haven't been matched in a while requeue as well so a lost race
+//! doesn't stall them. Prints the number of matches, the time it took to get matched and the
+//! latency between the matched clients.
+//!
+//! Run using cargo run server_ip [clients] [seconds] [local_ip], e.g. cargo run 127.0.0.1 100 30
+//! The clients bind to the server's IP unless a local IP is given.
+
+use log::{debug, error, info};
+use mirai_core::transport::LaminarTransport;
+use mirai_matchmaking_client::{Client, ClientError, Peer};
+use snafu::{ResultExt, Snafu};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use std::{env, thread};
+
+const DEFAULT_CLIENTS: usize = 100;
+const DEFAULT_SECONDS: u64 = 30;
+const MATCH_TIMEOUT_MILLIS: u64 = 5000;
+const POLL_INTERVAL_MILLIS: u64 = 10;
+
+fn main() {
+    env_logger::init();
+    if let Err(e) = run() {
+        error!("{}", e);
+    }
+}
+
+fn run() -> Result<(), LoadTestError> {
+    let args: Vec<_> = env::args().collect();
+    let server_ip = args.get(1).ok_or(LoadTestError::MissingIp)?;
+    let server_ip = server_ip.parse().context(InvalidIp { ip: server_ip })?;
+    let clients = match args.get(2) {
+        Some(clients) => clients.parse().context(InvalidNumber { arg: clients })?,
+        None => DEFAULT_CLIENTS,
+    };
+    let seconds = match args.get(3) {
+        Some(seconds) => seconds.parse().context(InvalidNumber { arg: seconds })?,
+        None => DEFAULT_SECONDS,
+    };
+    let local_ip = match args.get(4) {
+        Some(local_ip) => local_ip.parse().context(InvalidIp { ip: local_ip })?,
+        None => server_ip,
+    };
+
+    info!("starting {} clients against {}", clients, server_ip);
+    let mut simulated = Vec::with_capacity(clients);
+    for i in 0..clients {
+        let transport = LaminarTransport::bind(SocketAddr::new(local_ip, 0)).context(BindError)?;
+        simulated.push(SimulatedClient::new(server_ip, transport, i % 2 == 0)?);
+    }
+    let accepting: HashSet<_> = simulated
+        .iter()
+        .filter(|client| !client.challenger)
+        .map(|client| client.addr)
+        .collect();
+
+    let mut stats = Stats::default();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(seconds) {
+        simulated = simulated
+            .into_iter()
+            .map(|client| client.step(server_ip, &accepting, &mut stats))
+            .collect::<Result<_, _>>()?;
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+    }
+    stats.report(start.elapsed());
+    for client in simulated {
+        client.client.close().context(ClientErr)?;
+    }
+    Ok(())
+}
+
+/// A client that queues, challenges or accepts, and requeues after it's matched.
+struct SimulatedClient {
+    client: Client<LaminarTransport>,
+    addr: SocketAddr,
+    challenger: bool,
+    queued_at: Instant,
+    // the peers challenged or accepted by this client
+    handled: HashSet<SocketAddr>,
+}
+
+impl SimulatedClient {
+    fn new(
+        server_ip: IpAddr,
+        transport: LaminarTransport,
+        challenger: bool,
+    ) -> Result<Self, LoadTestError> {
+        let addr = transport.local_addr();
+        let mut client = Client::with_transport(server_ip, transport);
+        client.queue().context(ClientErr)?;
+        Ok(Self {
+            client,
+            addr,
+            challenger,
+            queued_at: Instant::now(),
+            handled: HashSet::new(),
+        })
+    }
+
+    fn step(
+        mut self,
+        server_ip: IpAddr,
+        accepting: &HashSet<SocketAddr>,
+        stats: &mut Stats,
+    ) -> Result<Self, LoadTestError> {
+        if let Some(opponent) = self.client.check_match().context(ClientErr)? {
+            // both sides see the match, only count it once
+            if self.challenger {
+                stats.match_times.push(self.queued_at.elapsed());
+                let peers = self.client.peers().context(ClientErr)?;
+                if let Some(latency) = peers
+                    .iter()
+                    .find(|peer| peer.addr() == opponent)
+                    .and_then(|peer| peer.latency())
+                {
+                    stats.latencies.push(latency);
+                }
+            }
+            return self.requeue(server_ip);
+        }
+        if self.queued_at.elapsed() > Duration::from_millis(MATCH_TIMEOUT_MILLIS) {
+            debug!("{} timed out", self.addr);
+            stats.timeouts += 1;
+            return self.requeue(server_ip);
+        }
+
+        if self.challenger {
+            // challenge one accepting peer at a time
+            if self
+                .client
+                .outgoing_challenges()
+                .context(ClientErr)?
+                .is_empty()
+            {
+                let peers = self.client.peers().context(ClientErr)?;
+                if let Some(mut peer) = peers.into_iter().find(|peer| {
+                    accepting.contains(&peer.addr()) && !self.handled.contains(&peer.addr())
+                }) {
+                    self.client.challenge(&mut peer).context(ClientErr)?;
+                    self.handled.insert(peer.addr());
+                } else {
+                    // everyone has been challenged once, start over
+                    self.handled.clear();
+                }
+            }
+        } else {
+            for challenge in self.client.incoming_challenges().context(ClientErr)? {
+                let addr = challenge.peer();
+                if self.handled.insert(addr) {
+                    self.client
+                        .accept(&mut Peer::new(addr))
+                        .context(ClientErr)?;
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    /// Closes the client and queues a new one on the same transport.
+    fn requeue(self, server_ip: IpAddr) -> Result<Self, LoadTestError> {
+        let transport = self.client.close().context(ClientErr)?;
+        Self::new(server_ip, transport, self.challenger)
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    match_times: Vec<Duration>,
+    latencies: Vec<Duration>,
+    timeouts: usize,
+}
+
+impl Stats {
+    fn report(mut self, elapsed: Duration) {
+        let matches = self.match_times.len();
+        println!(
+            "{} matches in {:.1} s, {:.1} matches/s, {} timeouts",
+            matches,
+            elapsed.as_secs_f64(),
+            matches as f64 / elapsed.as_secs_f64(),
+            self.timeouts
+        );
+        self.match_times.sort();
+        self.latencies.sort();
+        for (name, durations) in &[
+            ("time to match", &self.match_times),
+            ("peer latency", &self.latencies),
+        ] {
+            println!(
+                "{}: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+                name,
+                percentile(durations, 50),
+                percentile(durations, 90),
+                percentile(durations, 99),
+                percentile(durations, 100),
+            );
+        }
+    }
+}
+
+/// Returns the value below which the given percentage of the sorted values fall.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let index = (sorted.len() * percent).div_ceil(100).max(1) - 1;
+    sorted[index]
+}
+
+#[derive(Debug, Snafu)]
+enum LoadTestError {
+    #[snafu(display("missing IP parameter"))]
+    MissingIp,
+    #[snafu(display("invalid IP '{}': {}", ip, source))]
+    InvalidIp {
+        ip: String,
+        source: std::net::AddrParseError,
+    },
+    #[snafu(display("invalid number '{}': {}", arg, source))]
+    InvalidNumber {
+        arg: String,
+        source: std::num::ParseIntError,
+    },
+    #[snafu(display("binding error: {}", source))]
+    BindError { source: laminar::ErrorKind },
+    #[snafu(display("client error: {:?}", source))]
+    ClientErr { source: ClientError },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let sorted: Vec<_> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50), Duration::from_millis(5));
+        assert_eq!(percentile(&sorted, 90), Duration::from_millis(9));
+        assert_eq!(percentile(&sorted, 99), Duration::from_millis(10));
+        assert_eq!(percentile(&sorted, 0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50), Duration::default());
+    }
+}
@@ -0,0 +1,469 @@
+//! Tracks the challenge between the client and each of its peers.
+//!
+//! Each peer's challenge is in one `ChallengeState`, which the player's answers and the peer's control
+//! messages move along. Events that don't apply to the state are ignored, so e.g. an `Accept` from a peer
+//! that was never challenged or a `Start` from a peer whose challenge was declined can't start a match.
+//! Once its challenge is accepted, the challenger sends `Start`, and the challenged client confirms
+//! the match by answering with a `Start` of its own. Matches with more than two players start the same way,
+//! except that the host answers each acceptance with `GroupStart`, which a member only acts on
+//! if it accepted the host's challenge.
+//! A challenge is cancelled when the server says the peer left the queue.
+//! Incoming challenges carry metadata for the player to decide by, e.g. the game mode, which is kept
+//! with the time the challenge was first received for as long as it's incoming.
+//!
+//! Incoming challenges are rate limited per peer, see `ChallengeLimit`, so a hostile peer can't flood
+//! the player with them. The excess challenges are declined, and a peer that keeps sending them
+//! is blocked for a while.
+
+use crate::CancelledChallenge;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How many challenges a peer may send before the rest are declined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChallengeLimit {
+    /// The most challenges accepted from a peer within the window.
+    pub max_challenges: u32,
+    pub window: Duration,
+    /// A peer that sends this many challenges within the window is blocked, if set.
+    pub block_after: Option<u32>,
+    /// How long a peer is blocked for.
+    pub block_for: Duration,
+}
+
+impl Default for ChallengeLimit {
+    fn default() -> Self {
+        Self {
+            max_challenges: 3,
+            window: Duration::from_secs(10),
+            block_after: Some(10),
+            block_for: Duration::from_secs(300),
+        }
+    }
+}
+
+/// What to do with a challenge received from a peer, see `Challenges::limit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Limited {
+    Allowed,
+    /// The peer has sent too many challenges, or is blocked.
+    Declined,
+    /// The peer has sent so many challenges it's now blocked until the given time.
+    Blocked(Instant),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChallengeState {
+    /// Neither has challenged the other.
+    Idle,
+    /// The client challenged the peer.
+    Outgoing,
+    /// The peer challenged the client.
+    Incoming,
+    /// Both challenged each other.
+    Mutual,
+    /// The client accepted the peer's challenge and waits for it to start the match.
+    Accepted,
+    /// The peer accepted the client's challenge and was sent `Start`.
+    Starting,
+    /// Both sent `Start`.
+    Matched,
+}
+
+/// What moves a challenge along, either done by the client or received from the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChallengeEvent {
+    Challenge,
+    Accept,
+    Decline,
+    ReceivedChallenge,
+    ReceivedAccept,
+    ReceivedDecline,
+    ReceivedStart,
+    ReceivedGroupStart,
+}
+
+impl ChallengeState {
+    /// The state after the event, or None if the event doesn't apply.
+    pub(crate) fn next(self, event: ChallengeEvent) -> Option<Self> {
+        use ChallengeEvent::*;
+        use ChallengeState::*;
+
+        let next = match (self, event) {
+            (Idle, Challenge) | (Outgoing, Challenge) => Outgoing,
+            (Incoming, Challenge) | (Mutual, Challenge) => Mutual,
+            (Incoming, Accept) | (Mutual, Accept) | (Accepted, Accept) => Accepted,
+            (Incoming, Decline) | (Accepted, Decline) => Idle,
+            (Mutual, Decline) => Outgoing,
+            (Idle, ReceivedChallenge) | (Incoming, ReceivedChallenge) => Incoming,
+            (Outgoing, ReceivedChallenge) | (Mutual, ReceivedChallenge) => Mutual,
+            (Accepted, ReceivedChallenge) => Accepted,
+            // when both challenged each other, either challenge can be accepted
+            (Outgoing, ReceivedAccept) | (Mutual, ReceivedAccept) | (Accepted, ReceivedAccept) => {
+                Starting
+            }
+            (Outgoing, ReceivedDecline) | (Starting, ReceivedDecline) => Idle,
+            (Mutual, ReceivedDecline) => Incoming,
+            (Accepted, ReceivedStart) | (Starting, ReceivedStart) => Matched,
+            (Accepted, ReceivedGroupStart) => Matched,
+            _ => return None,
+        };
+        Some(next)
+    }
+
+    fn is_incoming(self) -> bool {
+        matches!(
+            self,
+            ChallengeState::Incoming | ChallengeState::Mutual | ChallengeState::Accepted
+        )
+    }
+
+    fn is_outgoing(self) -> bool {
+        matches!(
+            self,
+            ChallengeState::Outgoing | ChallengeState::Mutual | ChallengeState::Starting
+        )
+    }
+}
+
+/// The challenges with each peer, keyed by the peer's address. Peers without one are idle.
+#[derive(Default)]
+pub(crate) struct Challenges {
+    states: HashMap<SocketAddr, ChallengeState>,
+    // when each incoming challenge was first received, and the metadata it was last received with
+    received: HashMap<SocketAddr, (Instant, Vec<u8>)>,
+    limit: ChallengeLimit,
+    // when each peer's recent challenges were received, within the limit's window
+    recent: HashMap<SocketAddr, VecDeque<Instant>>,
+    // the peers blocked for sending too many challenges, until when
+    blocked: HashMap<SocketAddr, Instant>,
+}
+
+impl Challenges {
+    pub(crate) fn state(&self, peer: SocketAddr) -> ChallengeState {
+        self.states
+            .get(&peer)
+            .copied()
+            .unwrap_or(ChallengeState::Idle)
+    }
+
+    /// Moves the challenge with the peer along, returning whether the event applied to it.
+    pub(crate) fn handle(&mut self, peer: SocketAddr, event: ChallengeEvent) -> bool {
+        let next = match self.state(peer).next(event) {
+            Some(next) => next,
+            None => return false,
+        };
+        if next == ChallengeState::Idle {
+            self.states.remove(&peer);
+        } else {
+            self.states.insert(peer, next);
+        }
+        if !next.is_incoming() {
+            self.received.remove(&peer);
+        }
+        true
+    }
+
+    /// Moves the challenge with the peer along with a challenge received from it,
+    /// returning whether it applied. Repeated challenges replace the metadata.
+    pub(crate) fn receive(&mut self, peer: SocketAddr, metadata: Vec<u8>, now: Instant) -> bool {
+        if !self.handle(peer, ChallengeEvent::ReceivedChallenge) {
+            return false;
+        }
+        self.received.entry(peer).or_insert((now, Vec::new())).1 = metadata;
+        true
+    }
+
+    pub(crate) fn set_limit(&mut self, limit: ChallengeLimit) {
+        self.limit = limit;
+    }
+
+    /// Counts a challenge received from the peer against the limit. A declined challenge also declines
+    /// the peer's incoming challenge, since the peer is sent Decline.
+    pub(crate) fn limit(&mut self, peer: SocketAddr, now: Instant) -> Limited {
+        self.prune(now);
+        if self.blocked.contains_key(&peer) {
+            return Limited::Declined;
+        }
+        let window = self.limit.window;
+        let recent = self.recent.entry(peer).or_default();
+        while recent
+            .front()
+            .is_some_and(|&at| now.duration_since(at) >= window)
+        {
+            recent.pop_front();
+        }
+        recent.push_back(now);
+        let count = recent.len() as u32;
+        if self.limit.block_after.is_some_and(|after| count >= after) {
+            self.recent.remove(&peer);
+            let until = now + self.limit.block_for;
+            self.blocked.insert(peer, until);
+            self.handle(peer, ChallengeEvent::Decline);
+            Limited::Blocked(until)
+        } else if count > self.limit.max_challenges {
+            self.handle(peer, ChallengeEvent::Decline);
+            Limited::Declined
+        } else {
+            Limited::Allowed
+        }
+    }
+
+    // forgets the blocks that have run out and the peers with no challenges left in the window,
+    // so peers that stop challenging aren't kept around
+    fn prune(&mut self, now: Instant) {
+        let window = self.limit.window;
+        self.blocked.retain(|_, &mut until| now < until);
+        self.recent.retain(|_, recent| {
+            recent
+                .back()
+                .is_some_and(|&at| now.duration_since(at) < window)
+        });
+    }
+
+    /// The incoming challenges with when they were received and their metadata.
+    pub(crate) fn received(&self) -> impl Iterator<Item = (SocketAddr, Instant, &[u8])> {
+        self.received
+            .iter()
+            .map(|(&peer, (at, metadata))| (peer, *at, metadata.as_slice()))
+    }
+
+    /// The peers the client challenged, including those that accepted.
+    pub(crate) fn outgoing(&self) -> HashSet<SocketAddr> {
+        self.peers(ChallengeState::is_outgoing)
+    }
+
+    fn peers(&self, filter: fn(ChallengeState) -> bool) -> HashSet<SocketAddr> {
+        self.states
+            .iter()
+            .filter(|(_, &state)| filter(state))
+            .map(|(&peer, _)| peer)
+            .collect()
+    }
+
+    /// Forgets the challenge with the peer, returning how far it had got unless it was idle.
+    pub(crate) fn cancel(&mut self, peer: SocketAddr) -> Option<CancelledChallenge> {
+        self.received.remove(&peer);
+        let cancelled = match self.states.remove(&peer)? {
+            ChallengeState::Idle => return None,
+            ChallengeState::Outgoing => CancelledChallenge::Outgoing(peer),
+            ChallengeState::Incoming => CancelledChallenge::Incoming(peer),
+            ChallengeState::Mutual => CancelledChallenge::Mutual(peer),
+            ChallengeState::Accepted | ChallengeState::Starting | ChallengeState::Matched => {
+                CancelledChallenge::Accepted(peer)
+            }
+        };
+        Some(cancelled)
+    }
+
+    /// Continues the challenge with the peer at its new address.
+    pub(crate) fn migrate(&mut self, from: SocketAddr, to: SocketAddr) {
+        if let Some(state) = self.states.remove(&from) {
+            self.states.insert(to, state);
+        }
+        if let Some(received) = self.received.remove(&from) {
+            self.received.insert(to, received);
+        }
+        if let Some(recent) = self.recent.remove(&from) {
+            self.recent.insert(to, recent);
+        }
+        if let Some(until) = self.blocked.remove(&from) {
+            self.blocked.insert(to, until);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.states.clear();
+        self.received.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ChallengeEvent::*;
+    use ChallengeState::*;
+
+    const STATES: [ChallengeState; 7] = [
+        Idle, Outgoing, Incoming, Mutual, Accepted, Starting, Matched,
+    ];
+    const EVENTS: [ChallengeEvent; 8] = [
+        Challenge,
+        Accept,
+        Decline,
+        ReceivedChallenge,
+        ReceivedAccept,
+        ReceivedDecline,
+        ReceivedStart,
+        ReceivedGroupStart,
+    ];
+
+    #[test]
+    fn every_transition() {
+        let transitions = [
+            (Idle, Challenge, Outgoing),
+            (Idle, ReceivedChallenge, Incoming),
+            (Outgoing, Challenge, Outgoing),
+            (Outgoing, ReceivedChallenge, Mutual),
+            (Outgoing, ReceivedAccept, Starting),
+            (Outgoing, ReceivedDecline, Idle),
+            (Incoming, Challenge, Mutual),
+            (Incoming, Accept, Accepted),
+            (Incoming, Decline, Idle),
+            (Incoming, ReceivedChallenge, Incoming),
+            (Mutual, Challenge, Mutual),
+            (Mutual, Accept, Accepted),
+            (Mutual, Decline, Outgoing),
+            (Mutual, ReceivedChallenge, Mutual),
+            (Mutual, ReceivedAccept, Starting),
+            (Mutual, ReceivedDecline, Incoming),
+            (Accepted, Accept, Accepted),
+            (Accepted, Decline, Idle),
+            (Accepted, ReceivedChallenge, Accepted),
+            (Accepted, ReceivedAccept, Starting),
+            (Accepted, ReceivedStart, Matched),
+            (Accepted, ReceivedGroupStart, Matched),
+            (Starting, ReceivedDecline, Idle),
+            (Starting, ReceivedStart, Matched),
+        ];
+        for &state in &STATES {
+            for &event in &EVENTS {
+                let expected = transitions
+                    .iter()
+                    .find(|&&(from, on, _)| from == state && on == event)
+                    .map(|&(_, _, to)| to);
+                assert_eq!(state.next(event), expected, "{:?} on {:?}", state, event);
+            }
+        }
+    }
+
+    #[test]
+    fn ignored_events_leave_the_challenge_as_it_is() {
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let moved: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let now = Instant::now();
+        let mut challenges = Challenges::default();
+        let incoming = |challenges: &Challenges| -> HashSet<SocketAddr> {
+            challenges.received().map(|(peer, _, _)| peer).collect()
+        };
+        // an accept or start without a challenge
+        assert!(!challenges.handle(peer, ReceivedAccept));
+        assert!(!challenges.handle(peer, ReceivedStart));
+        assert_eq!(challenges.state(peer), Idle);
+
+        // a start from a peer whose challenge was declined
+        assert!(challenges.receive(peer, vec![], now));
+        assert_eq!(incoming(&challenges), vec![peer].into_iter().collect());
+        assert!(challenges.handle(peer, Decline));
+        assert!(!challenges.handle(peer, ReceivedStart));
+        assert!(incoming(&challenges).is_empty());
+
+        // or not yet accepted
+        assert!(challenges.receive(peer, vec![], now));
+        assert!(!challenges.handle(peer, ReceivedStart));
+        assert!(challenges.handle(peer, Challenge));
+        assert_eq!(challenges.outgoing(), vec![peer].into_iter().collect());
+        assert_eq!(incoming(&challenges), vec![peer].into_iter().collect());
+
+        challenges.migrate(peer, moved);
+        assert_eq!(challenges.state(peer), Idle);
+        assert_eq!(challenges.cancel(peer), None);
+        assert!(challenges.handle(moved, ReceivedAccept));
+        assert!(challenges.handle(moved, ReceivedStart));
+        assert_eq!(challenges.state(moved), Matched);
+        challenges.clear();
+        assert_eq!(challenges.state(moved), Idle);
+
+        assert!(challenges.handle(moved, ReceivedChallenge));
+        assert!(challenges.handle(moved, Accept));
+        assert_eq!(
+            challenges.cancel(moved),
+            Some(CancelledChallenge::Accepted(moved))
+        );
+        assert_eq!(challenges.state(moved), Idle);
+    }
+
+    #[test]
+    fn incoming_challenges_keep_their_metadata() {
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let first = Instant::now();
+        let later = first + std::time::Duration::from_secs(1);
+        let mut challenges = Challenges::default();
+        let received = |challenges: &Challenges| -> Vec<(SocketAddr, Instant, Vec<u8>)> {
+            challenges
+                .received()
+                .map(|(peer, at, metadata)| (peer, at, metadata.to_vec()))
+                .collect()
+        };
+
+        assert!(challenges.receive(peer, b"ranked".to_vec(), first));
+        // a repeated challenge keeps when it was first received
+        assert!(challenges.receive(peer, b"casual".to_vec(), later));
+        assert_eq!(
+            received(&challenges),
+            vec![(peer, first, b"casual".to_vec())]
+        );
+        assert!(challenges.handle(peer, Accept));
+        assert_eq!(received(&challenges).len(), 1);
+
+        assert!(challenges.handle(peer, Decline));
+        assert!(received(&challenges).is_empty());
+        assert!(challenges.receive(peer, vec![], later));
+        assert!(challenges.cancel(peer).is_some());
+        assert!(received(&challenges).is_empty());
+    }
+
+    #[test]
+    fn challenge_floods_are_declined_then_blocked() {
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let start = Instant::now();
+        let limit = ChallengeLimit::default();
+        let mut challenges = Challenges::default();
+
+        for i in 0..limit.max_challenges {
+            let at = start + Duration::from_millis(u64::from(i));
+            assert_eq!(challenges.limit(peer, at), Limited::Allowed);
+            assert!(challenges.receive(peer, vec![], at));
+        }
+        let excess = start + Duration::from_millis(u64::from(limit.max_challenges));
+        assert_eq!(challenges.limit(peer, excess), Limited::Declined);
+        assert_eq!(challenges.state(peer), Idle);
+        // other peers have their own limit
+        assert_eq!(challenges.limit(other, excess), Limited::Allowed);
+        // and the window moves on
+        let later = excess + limit.window;
+        assert_eq!(challenges.limit(peer, later), Limited::Allowed);
+
+        let block_after = limit.block_after.unwrap();
+        assert!(challenges.receive(peer, vec![], later));
+        let blocked = (0..block_after)
+            .map(|_| challenges.limit(peer, later))
+            .find(|limited| matches!(limited, Limited::Blocked(_)))
+            .unwrap();
+        assert_eq!(blocked, Limited::Blocked(later + limit.block_for));
+        // the flooder's challenge is no longer incoming
+        assert_eq!(challenges.state(peer), Idle);
+        let after_window = later + limit.window;
+        assert_eq!(challenges.limit(peer, after_window), Limited::Declined);
+        let unblocked = later + limit.block_for;
+        assert_eq!(challenges.limit(peer, unblocked), Limited::Allowed);
+        // peers that stopped challenging are forgotten when another peer challenges
+        let quiet = unblocked + limit.block_for + limit.window;
+        assert_eq!(challenges.limit(other, quiet), Limited::Allowed);
+        assert!(challenges.blocked.is_empty());
+        assert_eq!(challenges.recent.keys().collect::<Vec<_>>(), vec![&other]);
+
+        challenges.set_limit(ChallengeLimit {
+            block_after: None,
+            ..limit
+        });
+        for _ in 0..block_after {
+            assert_ne!(
+                challenges.limit(other, unblocked),
+                Limited::Blocked(unblocked + limit.block_for)
+            );
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Finds a working path to a peer once a challenge has been accepted.
+//!
+//! The address the server observed for a peer isn't always reachable, e.g. when both clients are
+//! behind the same NAT. Once a challenge is accepted, both clients send the other their candidate
+//! addresses. The client with the larger control session is controlling: it probes every candidate
+//! and the peer's observed address in parallel, and nominates the first one that responds.
+//! The controlled client uses the address the nominated probe came from,
+//! so both ends agree on the path.
+//! If nothing is nominated within `CONNECTIVITY_TIMEOUT_MILLIS`, the controlling client tells the
+//! peer to use the server's relay, and both ask the server to relay their packets once the match is
+//! confirmed, since the server counts the match as formed when both have asked. The match then
+//! uses the observed address through the relay, or directly if the server doesn't relay or
+//! doesn't answer within `RELAY_TIMEOUT_MILLIS`.
+//! Once the path is known, the client reports the match to the server with Matched, unless it's relayed:
+//! the server records a relayed match itself when both clients ask it to relay.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long the connectivity checks may take before falling back to the observed address.
+pub const CONNECTIVITY_TIMEOUT_MILLIS: u64 = 2000;
+/// How long after the checks started the observed address is used even if the relay isn't ready.
+pub const RELAY_TIMEOUT_MILLIS: u64 = 2 * CONNECTIVITY_TIMEOUT_MILLIS;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Relay {
+    None,
+    // needed, but not requested from the server until the match is confirmed
+    Wanted,
+    Requested,
+    Ready,
+    Unavailable,
+}
+
+struct Check {
+    started: Instant,
+    // the candidates probed by the controlling client, by nonce
+    probes: HashMap<u64, SocketAddr>,
+    // the addresses the probes of the controlling peer came from, by nonce
+    probe_sources: HashMap<u64, SocketAddr>,
+    selected: Option<SocketAddr>,
+    // whether this client probes the peer's candidates
+    controlling: bool,
+    relay: Relay,
+}
+
+/// The connectivity checks with each peer, keyed by the peer's observed address.
+#[derive(Default)]
+pub(crate) struct Checks {
+    checks: HashMap<SocketAddr, Check>,
+}
+
+impl Checks {
+    /// Starts checking the connectivity to the peer, unless already started.
+    pub(crate) fn start(&mut self, peer: SocketAddr, now: Instant) {
+        self.checks.entry(peer).or_insert_with(|| Check {
+            started: now,
+            probes: HashMap::new(),
+            probe_sources: HashMap::new(),
+            selected: None,
+            controlling: false,
+            relay: Relay::None,
+        });
+    }
+
+    /// Probes the peer's observed address and its candidates, each with its own nonce,
+    /// if the connectivity to the peer is being checked.
+    pub(crate) fn probe(
+        &mut self,
+        peer: SocketAddr,
+        mut candidates: Vec<SocketAddr>,
+        mut nonce: impl FnMut() -> u64,
+    ) {
+        if let Some(check) = self.checks.get_mut(&peer) {
+            candidates.push(peer);
+            candidates.sort();
+            candidates.dedup();
+            check.probes = candidates.into_iter().map(|c| (nonce(), c)).collect();
+            check.controlling = true;
+        }
+    }
+
+    /// The probes to send until a candidate has responded.
+    pub(crate) fn pending_probes(&self, now: Instant) -> Vec<(SocketAddr, u64)> {
+        let timeout = Duration::from_millis(CONNECTIVITY_TIMEOUT_MILLIS);
+        self.checks
+            .values()
+            .filter(|check| {
+                check.selected.is_none() && now.saturating_duration_since(check.started) < timeout
+            })
+            .flat_map(|check| {
+                check
+                    .probes
+                    .iter()
+                    .map(|(&nonce, &candidate)| (candidate, nonce))
+            })
+            .collect()
+    }
+
+    /// Selects the address a probe was answered from, if it's the first answer.
+    /// Returns the peer the nonce is to be nominated to.
+    pub(crate) fn answered(&mut self, nonce: u64, from: SocketAddr) -> Option<SocketAddr> {
+        let (&peer, check) = self
+            .checks
+            .iter_mut()
+            .find(|(_, check)| check.probes.contains_key(&nonce))?;
+        if check.selected.is_some() {
+            return None;
+        }
+        check.selected = Some(from);
+        Some(peer)
+    }
+
+    /// Remembers where a probe from the controlling peer came from.
+    pub(crate) fn probed(&mut self, peer: SocketAddr, nonce: u64, from: SocketAddr) {
+        if let Some(check) = self.checks.get_mut(&peer) {
+            check.probe_sources.insert(nonce, from);
+        }
+    }
+
+    /// Selects the address the nominated probe came from.
+    pub(crate) fn nominated(&mut self, peer: SocketAddr, nonce: u64) {
+        if let Some(check) = self.checks.get_mut(&peer) {
+            if let Some(&source) = check.probe_sources.get(&nonce) {
+                check.selected = Some(source);
+            }
+        }
+    }
+
+    /// The peers whose checks this client controls and that have timed out without an answer.
+    /// Their relay is marked as wanted, so each peer is only returned once.
+    pub(crate) fn relay_needed(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let timeout = Duration::from_millis(CONNECTIVITY_TIMEOUT_MILLIS);
+        self.checks
+            .iter_mut()
+            .filter(|(_, check)| {
+                check.controlling
+                    && check.selected.is_none()
+                    && check.relay == Relay::None
+                    && now.saturating_duration_since(check.started) >= timeout
+            })
+            .map(|(&peer, check)| {
+                check.relay = Relay::Wanted;
+                peer
+            })
+            .collect()
+    }
+
+    /// Marks the relay to the peer as wanted if the peer asked for it and no path was found.
+    pub(crate) fn relay_asked(&mut self, peer: SocketAddr) {
+        if let Some(check) = self.checks.get_mut(&peer) {
+            if check.selected.is_none() && check.relay == Relay::None {
+                check.relay = Relay::Wanted;
+            }
+        }
+    }
+
+    /// Marks the wanted relay to the peer as requested once the match with the peer is confirmed.
+    /// Returns whether the relay should be requested from the server.
+    pub(crate) fn relay_confirmed(&mut self, peer: SocketAddr) -> bool {
+        match self.checks.get_mut(&peer) {
+            Some(check) if check.relay == Relay::Wanted => {
+                check.relay = Relay::Requested;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records the server's answer to a relay request.
+    pub(crate) fn relay_answered(&mut self, peer: SocketAddr, ready: bool) {
+        if let Some(check) = self.checks.get_mut(&peer) {
+            if check.relay == Relay::Requested {
+                check.relay = if ready {
+                    Relay::Ready
+                } else {
+                    Relay::Unavailable
+                };
+            }
+        }
+    }
+
+    /// Whether the packets to the peer go through the server's relay.
+    pub(crate) fn is_relayed(&self, peer: SocketAddr) -> bool {
+        self.checks
+            .get(&peer)
+            .is_some_and(|check| check.selected.is_none() && check.relay == Relay::Ready)
+    }
+
+    /// Whether a probe reached the peer, or None if its connectivity wasn't checked.
+    #[cfg(feature = "telemetry")]
+    pub(crate) fn punched(&self, peer: SocketAddr) -> Option<bool> {
+        self.checks.get(&peer).map(|check| check.selected.is_some())
+    }
+
+    /// The address to reach the peer at, or None while the checks are still running.
+    pub(crate) fn path(&self, peer: SocketAddr, now: Instant) -> Option<SocketAddr> {
+        let timeout = Duration::from_millis(RELAY_TIMEOUT_MILLIS);
+        match self.checks.get(&peer) {
+            Some(check) => match (check.selected, check.relay) {
+                (Some(selected), _) => Some(selected),
+                (None, Relay::Ready) | (None, Relay::Unavailable) => Some(peer),
+                (None, _) if now.saturating_duration_since(check.started) >= timeout => Some(peer),
+                (None, _) => None,
+            },
+            // the match wasn't preceded by a challenge, e.g. a group match
+            None => Some(peer),
+        }
+    }
+
+    /// Stops checking the connectivity to the peer, e.g. when it left.
+    pub(crate) fn stop(&mut self, peer: SocketAddr) {
+        self.checks.remove(&peer);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.checks.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn controlling_and_controlled_agree_on_the_path() {
+        let peer_a = addr("1.1.1.1:1");
+        let peer_b = addr("2.2.2.2:2");
+        let lan_b = addr("192.168.0.2:2");
+        let lan_a = addr("192.168.0.1:1");
+        let now = Instant::now();
+        let mut controlling = Checks::default();
+        let mut controlled = Checks::default();
+        controlled.start(peer_a, now);
+        let mut nonces = 0..;
+        controlling.start(peer_b, now);
+        controlling.probe(peer_b, vec![lan_b], || nonces.next().unwrap());
+        assert_eq!(controlling.path(peer_b, now), None);
+
+        let probes = controlling.pending_probes(now);
+        assert_eq!(probes.len(), 2);
+        let &(_, nonce) = probes.iter().find(|(c, _)| *c == lan_b).unwrap();
+        // the probe to the LAN address arrives from the peer's LAN address
+        controlled.probed(peer_a, nonce, lan_a);
+        assert_eq!(controlling.answered(nonce, lan_b), Some(peer_b));
+        // later answers don't change the selection
+        let &(_, other) = probes.iter().find(|(c, _)| *c == peer_b).unwrap();
+        assert_eq!(controlling.answered(other, peer_b), None);
+        assert!(controlling.pending_probes(now).is_empty());
+        assert_eq!(controlling.path(peer_b, now), Some(lan_b));
+
+        assert_eq!(controlled.path(peer_a, now), None);
+        controlled.nominated(peer_a, nonce);
+        assert_eq!(controlled.path(peer_a, now), Some(lan_a));
+    }
+
+    #[test]
+    fn peers_that_cannot_be_reached_are_relayed() {
+        let peer = addr("1.1.1.1:1");
+        let now = Instant::now();
+        let mut controlling = Checks::default();
+        let mut controlled = Checks::default();
+        controlling.start(peer, now);
+        controlling.probe(peer, vec![], || 0);
+        controlled.start(peer, now);
+        assert!(controlling.relay_needed(now).is_empty());
+
+        // no probe was answered in time
+        let now = now + Duration::from_millis(CONNECTIVITY_TIMEOUT_MILLIS);
+        assert_eq!(controlling.relay_needed(now), vec![peer]);
+        assert!(controlling.relay_needed(now).is_empty());
+        controlled.relay_asked(peer);
+        assert_eq!(controlling.path(peer, now), None);
+        // the relay is only requested once the match is confirmed, and only once
+        for checks in &mut [&mut controlling, &mut controlled] {
+            assert!(checks.relay_confirmed(peer));
+            assert!(!checks.relay_confirmed(peer));
+        }
+
+        controlling.relay_answered(peer, true);
+        controlled.relay_answered(peer, false);
+        assert_eq!(controlling.path(peer, now), Some(peer));
+        assert!(controlling.is_relayed(peer));
+        assert_eq!(controlled.path(peer, now), Some(peer));
+        assert!(!controlled.is_relayed(peer));
+    }
+
+    #[test]
+    fn unchecked_peers_use_the_observed_address() {
+        let checks = Checks::default();
+        let peer = addr("1.1.1.1:1");
+        assert_eq!(checks.path(peer, Instant::now()), Some(peer));
+    }
+}
@@ -0,0 +1,396 @@
+//! A self-test the game can run at startup or from a "test my connection" button, see `Client::diagnose`.
+//!
+//! The client port is bound and released to find out whether it's free, and the server is sent a
+//! status check from an ephemeral port, so the client can be created right after.
+//!
+//! The NAT is classified with STUN binding requests (RFC 5389) to the STUN servers the game provides,
+//! sent from one socket: if the servers see it at the same public address, the NAT maps the socket to
+//! the same address for every destination and hole punching should work. If they see different
+//! addresses, the NAT is symmetric and matches will likely fall back to the relay. At least two servers
+//! are needed to tell the two apart.
+//!
+//! The path MTU is estimated by padding the binding requests to the sizes in `PAYLOAD_PROBES`,
+//! largest first, until the first STUN server answers one. Any answer counts, since servers that don't
+//! support the PADDING attribute still answer with an error.
+
+use mirai_core::logging::debug;
+use mirai_core::transport::LaminarTransport;
+use mirai_core::v1::{CLIENT_PORT, SERVER_PORT};
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// How long the server has to answer the status check.
+pub const STATUS_TIMEOUT_MILLIS: u64 = 2000;
+/// How long a STUN server has to answer a binding request before it's sent again.
+pub const STUN_TIMEOUT_MILLIS: u64 = 500;
+/// How many times a binding request is sent before the server is considered unreachable.
+pub const STUN_ATTEMPTS: u32 = 2;
+/// The UDP payload sizes the path MTU is probed with: a 1500 byte Ethernet frame over IPv4 and IPv6,
+/// common tunnels and VPNs, the IPv6 minimum MTU and the IPv4 minimum reassembly size.
+pub const PAYLOAD_PROBES: [usize; 5] = [1472, 1452, 1400, 1232, 548];
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LEN: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const PADDING: u16 = 0x0026;
+
+/// The results of `Client::diagnose`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Diagnosis {
+    /// Why the client port couldn't be bound, e.g. because another client is already using it.
+    pub bind_error: Option<String>,
+    /// The round trip of a status check to the server, or None if it didn't answer.
+    pub server_rtt: Option<Duration>,
+    /// The address the STUN servers saw the client at.
+    pub public_addr: Option<SocketAddr>,
+    pub nat: NatType,
+    /// The largest of `PAYLOAD_PROBES` that reached a STUN server, or None if none answered.
+    pub max_payload: Option<usize>,
+}
+
+/// How the client's NAT maps its address, as far as the STUN servers could tell.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// The client isn't behind a NAT: the STUN servers saw its own address.
+    Open,
+    /// The NAT maps the socket to the same public address for every destination, so hole punching should work.
+    EndpointIndependent,
+    /// The NAT maps the socket to a different public address for each destination,
+    /// so peers likely can't reach the client directly and matches fall back to the relay.
+    Symmetric,
+    /// None of the STUN servers answered, so UDP may be blocked.
+    Blocked,
+    /// No STUN servers were given, or only one answered and saw a translated address.
+    Unknown,
+}
+
+impl NatType {
+    /// Whether peers should be able to reach the client directly.
+    pub fn allows_hole_punching(self) -> bool {
+        matches!(self, NatType::Open | NatType::EndpointIndependent)
+    }
+}
+
+pub(crate) fn diagnose(addr: IpAddr, server_ip: IpAddr, stun_servers: &[SocketAddr]) -> Diagnosis {
+    // the socket is closed right away, so the port is free again for the client
+    let bind_error = UdpSocket::bind(SocketAddr::new(addr, CLIENT_PORT))
+        .err()
+        .map(|e| e.to_string());
+    let server_addr = SocketAddr::new(server_ip, SERVER_PORT);
+    let server_rtt = match LaminarTransport::bind(SocketAddr::new(addr, 0)) {
+        Ok(transport) => {
+            let timeout = Duration::from_millis(STATUS_TIMEOUT_MILLIS);
+            crate::probe_servers(&transport, &[server_addr], timeout)
+                .first()
+                .map(|&(_, rtt)| rtt)
+        }
+        Err(e) => {
+            debug!("could not bind a socket to check the server: {}", e);
+            None
+        }
+    };
+    let (public_addr, nat, max_payload) = match UdpSocket::bind(SocketAddr::new(addr, 0)) {
+        Ok(socket) => stun(
+            &socket,
+            stun_servers,
+            Duration::from_millis(STUN_TIMEOUT_MILLIS),
+        ),
+        Err(e) => {
+            debug!("could not bind a socket for STUN: {}", e);
+            (None, NatType::Unknown, None)
+        }
+    };
+    Diagnosis {
+        bind_error,
+        server_rtt,
+        public_addr,
+        nat,
+        max_payload,
+    }
+}
+
+// the public address, NAT type and largest payload that got through, as seen by the STUN servers
+fn stun(
+    socket: &UdpSocket,
+    stun_servers: &[SocketAddr],
+    timeout: Duration,
+) -> (Option<SocketAddr>, NatType, Option<usize>) {
+    if stun_servers.is_empty() {
+        return (None, NatType::Unknown, None);
+    }
+    let mut answering = None;
+    let mut mapped = vec![];
+    for &server in stun_servers {
+        if let Some(answer) = query(socket, server, HEADER_LEN, timeout) {
+            answering.get_or_insert(server);
+            mapped.extend(answer);
+        }
+    }
+    let local = route_addr(socket, stun_servers[0]);
+    let nat = match answering {
+        Some(_) => classify(local, &mapped),
+        None => NatType::Blocked,
+    };
+    let max_payload = answering.and_then(|server| {
+        PAYLOAD_PROBES
+            .iter()
+            .copied()
+            .find(|&size| query(socket, server, size, timeout).is_some())
+    });
+    debug!(
+        "STUN servers saw {:?} behind {:?}, payloads of {:?} bytes got through",
+        mapped, nat, max_payload
+    );
+    (mapped.first().copied(), nat, max_payload)
+}
+
+fn classify(local: Option<SocketAddr>, mapped: &[SocketAddr]) -> NatType {
+    match mapped.split_first() {
+        None => NatType::Unknown,
+        Some((first, rest)) if rest.iter().any(|addr| addr != first) => NatType::Symmetric,
+        Some((first, _)) if Some(*first) == local => NatType::Open,
+        Some((_, [])) => NatType::Unknown,
+        Some(_) => NatType::EndpointIndependent,
+    }
+}
+
+// the address the socket sends from towards the destination, which its local address
+// doesn't tell if it's bound to the unspecified address
+fn route_addr(socket: &UdpSocket, toward: SocketAddr) -> Option<SocketAddr> {
+    let local = socket.local_addr().ok()?;
+    if !local.ip().is_unspecified() {
+        return Some(local);
+    }
+    // connecting a UDP socket sends nothing, it only picks the route
+    let probe = UdpSocket::bind(SocketAddr::new(local.ip(), 0)).ok()?;
+    probe.connect(toward).ok()?;
+    Some(SocketAddr::new(probe.local_addr().ok()?.ip(), local.port()))
+}
+
+// sends a binding request padded to the size and waits for the answer, which carries
+// the mapped address unless the server answered with an error
+fn query(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    size: usize,
+    timeout: Duration,
+) -> Option<Option<SocketAddr>> {
+    let transaction = transaction_id();
+    let request = request(&transaction, size);
+    let mut buf = [0; 1500];
+    for _ in 0..STUN_ATTEMPTS {
+        if let Err(e) = socket.send_to(&request, server) {
+            // e.g. a payload larger than the interface's MTU
+            debug!(
+                "could not send {} bytes to {}: {}",
+                request.len(),
+                server,
+                e
+            );
+            return None;
+        }
+        let deadline = Instant::now() + timeout;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            if socket.set_read_timeout(Some(remaining)).is_err() {
+                return None;
+            }
+            match socket.recv_from(&mut buf) {
+                Ok((len, from)) if from == server => {
+                    if let Some(answer) = parse_response(&buf[..len], &transaction) {
+                        return Some(answer);
+                    }
+                }
+                // a late answer to an earlier request, or some other sender
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    }
+    None
+}
+
+// the hasher's keys are random, which is enough to tell the requests apart
+fn transaction_id() -> [u8; 12] {
+    let mut id = [0; 12];
+    for chunk in id.chunks_mut(4) {
+        let random = RandomState::new().build_hasher().finish();
+        chunk.copy_from_slice(&random.to_be_bytes()[..4]);
+    }
+    id
+}
+
+// a binding request, padded to the size rounded down to the 4 byte alignment of the attributes
+fn request(transaction: &[u8; 12], size: usize) -> Vec<u8> {
+    let padding = size.saturating_sub(HEADER_LEN + 4) / 4 * 4;
+    let attributes_len = if padding > 0 { 4 + padding } else { 0 };
+    let mut request = Vec::with_capacity(HEADER_LEN + attributes_len);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&(attributes_len as u16).to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(transaction);
+    if padding > 0 {
+        request.extend_from_slice(&PADDING.to_be_bytes());
+        request.extend_from_slice(&(padding as u16).to_be_bytes());
+        request.resize(HEADER_LEN + attributes_len, 0);
+    }
+    request
+}
+
+// None if it isn't an answer to the transaction
+fn parse_response(response: &[u8], transaction: &[u8; 12]) -> Option<Option<SocketAddr>> {
+    if response.len() < HEADER_LEN
+        || response[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || response[8..HEADER_LEN] != transaction[..]
+    {
+        return None;
+    }
+    match u16::from_be_bytes([response[0], response[1]]) {
+        BINDING_SUCCESS => {}
+        BINDING_ERROR => return Some(None),
+        _ => return None,
+    }
+    let mut attributes = &response[HEADER_LEN..];
+    let mut mapped = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = usize::from(u16::from_be_bytes([attributes[2], attributes[3]]));
+        let value = attributes.get(4..4 + len)?;
+        match kind {
+            XOR_MAPPED_ADDRESS => return Some(address(value, Some(transaction))),
+            // older servers only send the plain address
+            MAPPED_ADDRESS => mapped = address(value, None),
+            _ => {}
+        }
+        let padded_len = len.div_ceil(4) * 4;
+        attributes = attributes.get(4 + padded_len..).unwrap_or_default();
+    }
+    Some(mapped)
+}
+
+// decodes a MAPPED-ADDRESS, or an XOR-MAPPED-ADDRESS if the transaction is given
+fn address(value: &[u8], xor: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let mut mask = [0; 16];
+    if let Some(transaction) = xor {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction);
+    }
+    let port = u16::from_be_bytes([value.get(2)? ^ mask[0], value.get(3)? ^ mask[1]]);
+    let ip_len = match value.get(1)? {
+        1 => 4,
+        2 => 16,
+        _ => return None,
+    };
+    let mut ip = [0; 16];
+    for (i, byte) in value.get(4..4 + ip_len)?.iter().enumerate() {
+        ip[i] = byte ^ mask[i];
+    }
+    let ip: IpAddr = if ip_len == 4 {
+        [ip[0], ip[1], ip[2], ip[3]].into()
+    } else {
+        ip.into()
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    // answers binding requests of up to max_size bytes with the sender's address, shifted by the port offset
+    fn stun_server(max_size: usize, port_offset: u16) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 2048];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                if len > max_size {
+                    continue;
+                }
+                let ip = match from.ip() {
+                    IpAddr::V4(ip) => u32::from(ip) ^ MAGIC_COOKIE,
+                    IpAddr::V6(_) => unreachable!(),
+                };
+                let port = (from.port() + port_offset) ^ (MAGIC_COOKIE >> 16) as u16;
+                // an unknown attribute whose value is padded, then the address
+                let mut attributes = vec![0x80, 0x22, 0, 3, b'm', b'i', b'r', 0];
+                attributes.extend_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+                attributes.extend_from_slice(&[0, 8, 0, 1]);
+                attributes.extend_from_slice(&port.to_be_bytes());
+                attributes.extend_from_slice(&ip.to_be_bytes());
+                let mut response = BINDING_SUCCESS.to_be_bytes().to_vec();
+                response.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+                response.extend_from_slice(&buf[4..HEADER_LEN]);
+                response.extend_from_slice(&attributes);
+                let _ = socket.send_to(&response, from);
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn requests_are_padded_to_the_probed_size() {
+        let transaction = transaction_id();
+        assert_eq!(request(&transaction, HEADER_LEN).len(), HEADER_LEN);
+        for &size in &PAYLOAD_PROBES {
+            let request = request(&transaction, size);
+            assert_eq!(request.len(), size);
+            assert_eq!(
+                usize::from(u16::from_be_bytes([request[2], request[3]])),
+                size - HEADER_LEN
+            );
+        }
+    }
+
+    #[test]
+    fn nat_types_are_told_apart_by_the_mapped_addresses() {
+        let local: SocketAddr = "192.168.1.2:4000".parse().unwrap();
+        let public: SocketAddr = "203.0.113.5:4000".parse().unwrap();
+        let other_port: SocketAddr = "203.0.113.5:4001".parse().unwrap();
+        assert_eq!(classify(Some(local), &[local, local]), NatType::Open);
+        assert_eq!(classify(Some(local), &[local]), NatType::Open);
+        assert_eq!(
+            classify(Some(local), &[public, public]),
+            NatType::EndpointIndependent
+        );
+        assert_eq!(
+            classify(Some(local), &[public, other_port]),
+            NatType::Symmetric
+        );
+        assert_eq!(classify(Some(local), &[public]), NatType::Unknown);
+        assert_eq!(classify(None, &[]), NatType::Unknown);
+    }
+
+    #[test]
+    fn stun_servers_see_the_address_and_the_largest_payload() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let local = socket.local_addr().unwrap();
+        let timeout = Duration::from_millis(50);
+        let servers = [stun_server(1300, 0), stun_server(1300, 0)];
+        assert_eq!(
+            stun(&socket, &servers, timeout),
+            (Some(local), NatType::Open, Some(1232))
+        );
+
+        let servers = [stun_server(1500, 0), stun_server(1500, 1)];
+        assert_eq!(
+            stun(&socket, &servers, timeout),
+            (Some(local), NatType::Symmetric, Some(1472))
+        );
+
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let servers = [silent.local_addr().unwrap()];
+        assert_eq!(
+            stun(&socket, &servers, timeout),
+            (None, NatType::Blocked, None)
+        );
+        assert_eq!(stun(&socket, &[], timeout), (None, NatType::Unknown, None));
+    }
+}
@@ -0,0 +1,118 @@
+//! Remembers the identified peers across queue sessions.
+//!
+//! The server tells the client the public handles of its identified peers' player IDs. When a `PeerStore`
+//! is given to the client, it keeps the latest latency to each of them, whether the player blocked them
+//! and how often they've been met, so a peer met again starts out with the latency measured last time.
+//! Blocked peers are left out of the candidates and their challenges are declined.
+//! The store is kept in memory unless it's opened from a file, which `save` then writes it to.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What's known about a peer from earlier queue sessions.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct KnownPeer {
+    /// The latest latency to the peer.
+    pub latency: Option<Duration>,
+    pub blocked: bool,
+    /// How many queue sessions the peer has been met in.
+    pub encounters: u32,
+}
+
+/// The known peers, by their handles.
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    peers: HashMap<String, KnownPeer>,
+    path: Option<PathBuf>,
+}
+
+impl PeerStore {
+    /// Creates an empty store that is kept in memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the store from the file, or starts an empty one if it doesn't exist yet.
+    /// # Errors
+    /// If the file can't be read or contains something other than a store.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let peers = match fs::read(&path) {
+            Ok(bytes) => bincode::deserialize(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            peers,
+            path: Some(path),
+        })
+    }
+
+    /// Writes the store to the file it was opened from, if any.
+    /// # Errors
+    /// If the file can't be written.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let bytes = bincode::serialize(&self.peers)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            fs::write(path, bytes)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, handle: &str) -> Option<&KnownPeer> {
+        self.peers.get(handle)
+    }
+
+    /// The peer, which is added if it isn't known yet.
+    pub fn entry(&mut self, handle: &str) -> &mut KnownPeer {
+        self.peers.entry(handle.to_string()).or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stores_are_saved_and_opened() {
+        let path = std::env::temp_dir().join(format!("mirai-peers-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut store = PeerStore::open(&path).unwrap();
+        assert!(store.is_empty());
+        store.entry("rival").latency = Some(Duration::from_millis(30));
+        store.entry("rival").encounters += 1;
+        store.entry("troll").blocked = true;
+        store.save().unwrap();
+
+        let store = PeerStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(
+            store.get("rival"),
+            Some(&KnownPeer {
+                latency: Some(Duration::from_millis(30)),
+                blocked: false,
+                encounters: 1,
+            })
+        );
+        assert!(store.get("troll").unwrap().blocked);
+        assert_eq!(store.get("stranger"), None);
+
+        fs::write(&path, b"garbage").unwrap();
+        assert!(PeerStore::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
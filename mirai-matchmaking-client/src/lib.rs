@@ -5,30 +5,146 @@
 //! some set of peers that the server has selected for it. The client can challenge
 //! peers, and accept or decline challenges. The client may receive further
 //! peers from the server or request a new set by requeueing.
+//! Challenges can carry metadata, e.g. the game mode, and `Client::incoming_challenges` lists them
+//! with the challengers' IDs and latencies and when they expire, so a UI can show them as they are.
 //!
 //! Meanwhile, the clients are evaluating the connection quality to each of its peers
 //! by sending ping messages back and forth.
 //!
+//! Once a challenge is accepted, the clients check which of each other's addresses they can reach,
+//! see `connectivity`.
+//!
+//! Matches with more than two players are started by one of the clients, which challenges each peer
+//! and sends the other members of the group to those that accept. The match is confirmed once every
+//! peer has responded. `Client::into_peer_connection` then hands the client's transport over to the game,
+//! relayed through the server if needed and scoped to the matched peers.
+//!
+//! On networks that block UDP, `Client::with_fallback` connects to the server over TCP instead.
+//! `Client::diagnose` checks the client port, the server, the NAT and the path MTU before the client
+//! is created, e.g. to tell the player why matches keep falling back to the relay, see `diagnose`.
+//!
+//! A queued client regularly sends the server the resume token it was given, so that the server keeps
+//! its place in the queue if its address changes, e.g. when its NAT rebinds its port, and tells its
+//! peers where it went.
+//!
+//! When servers run in several regions, `probe_servers` measures the round trip to each before queueing,
+//! so the client can queue on the closest one, or on several of them at once with `MultiQueue`, see `multi`.
+//!
+//! A server that is draining before a deployment, or whose queue is full, rejects new queue requests, which `Client::check_match`
+//! returns as `ClientError::QueueRejected`, so the game can queue on another server.
+//!
+//! Peers that leave a challenge unanswered for `CHALLENGE_TIMEOUT_MILLIS` are reported to the server,
+//! which stops proposing players that several peers have reported, see `Client::is_idle`.
+//! The client checks the round trip to the server every second, see `Client::server_latency`.
+//! If the transport reports that the client's packets aren't getting out, e.g. because laminar's polling
+//! thread died, `Client::send_health` says so, rather than the client silently sending into the void.
+//! When a peer leaves the queue, e.g. because its connection to the server timed out, the server sends
+//! Dequeued with it, and the challenge with it is cancelled, see `Client::cancelled_challenges`.
+//! A peer that sends too many challenges has the rest declined, and is blocked for a while if it keeps
+//! sending them, see `Client::challenge_blocks` and `ChallengeLimit`.
+//! The server numbers the updates of the peer list, and a client that missed one, e.g. because the server
+//! restarted, asks for the whole list again and forgets the peers that are missing from it.
+//!
+//! With the `port-mapping` feature, `Client::new` asks the router to forward the client port
+//! and queues with the forwarded port, so that peers behind other NATs can reach the client.
+//!
+//! If the server's public key is given in `MIRAI_SERVER_KEY`, `secure_transport` encrypts the
+//! traffic with the server, see `mirai_core::transport::secure`.
+//!
+//! Queued clients can list the rooms they host on the server, and other clients can browse them
+//! with their hosts' latencies, like a classic server browser.
+//! While queued, clients are also offered the compatible rooms that are missing players, see
+//! `Client::backfill_offers`. Joining one with `Client::join_room` leaves the queue, and the room's host
+//! and members are told about the newcomer, see `Client::room_events`.
+//! If the server prioritizes players, e.g. an event's participants, the peers carry their priority tier
+//! and how long they have waited, and `Client::candidates` lists them in the order to consider them in.
+//! The peers that identified themselves carry the public handles of their player IDs, and with
+//! `Client::keep_peers` the client remembers their latencies and whether the player blocked them across
+//! queue sessions, see `known`. The IDs themselves stay on the server.
+//! If a peer's address turns up with another handle, e.g. because a NAT gave it to someone else,
+//! the peer starts over as a new one, see `Client::replaced_peers`.
+//! Clients that share a party token with `Client::join_party` queue as a group: the server proposes
+//! the whole party to its opponents, e.g. for team games.
+//!
+//! Leagues can reserve matches between player IDs on the server. A client that has set its ID with
+//! `Client::identify` is held out of the queue during its reserved match until the opponent has
+//! queued as well, and both are then given each other's address.
+//! A player ID is queued from one address at a time. A client restarted after a crash takes its ID back
+//! from the stale session once the server hasn't heard from that session for a few seconds, or right away
+//! by passing the resume token it kept from `Client::resume_token` to `Client::supersede`.
+//! An identified client can share its presence with its friends with `Client::set_friends`, and follows
+//! the presence of those that listed it as well, see `Client::friends`. An online friend can be challenged
+//! through the server with `Client::challenge_friend`, even if neither of them is queued.
+//! Any client can be invited the same way with a code from `Client::create_invite`, which the invited
+//! client redeems with `Client::redeem_invite`.
+//!
+//! With the `telemetry` feature, `Client::enable_telemetry` uploads anonymized metrics about how
+//! matchmaking went, such as the time to a match and how often hole punching succeeded, see `telemetry`.
+//!
+//! Replays written by the game client can be uploaded to the server and fetched by the ID it
+//! stored them as, if the server keeps replays.
+//!
+
+mod challenge;
+mod connectivity;
+mod diagnose;
+mod known;
+pub mod multi;
+#[cfg(feature = "port-mapping")]
+pub mod port_mapping;
+mod server_link;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
 
 use self::ClientToClient as ToClient;
 use self::ClientToClient as FromClient;
+pub use challenge::ChallengeLimit;
+use challenge::{ChallengeEvent, ChallengeState, Challenges, Limited};
+use connectivity::Checks;
 use crossbeam_channel::SendError;
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use laminar::{Packet, Socket, SocketEvent};
-use log::{debug, error, info, trace, warn};
-use mirai_core::v1::{client::*, CLIENT_PORT, SERVER_PORT};
+use crossbeam_channel::{select, tick, unbounded, Receiver, Sender};
+pub use diagnose::{Diagnosis, NatType};
+pub use known::{KnownPeer, PeerStore};
+use mirai_core::clock::{Clock, SystemClock};
+use mirai_core::crypto::{key_from_hex, player_handle};
+use mirai_core::logging::targets::{CLIENT_CHALLENGE, CLIENT_CONNECTIVITY, CLIENT_PING};
+use mirai_core::logging::{debug, info, trace, warn};
+use mirai_core::transport::impair::{impair, Impairment, ImpairmentError};
+pub use mirai_core::transport::SendHealth;
+use mirai_core::transport::{
+    LaminarTransport, Packet, PeerConnection, RelayTransport, SecureTransport, TcpTransport,
+    Transport, TransportError, TransportEvent,
+};
+use mirai_core::v1::{client::*, streams, PeerListSeq, CLIENT_PORT, SERVER_PORT};
+pub use mirai_core::v1::{
+    LeaderboardQuery, MatchRecord, MatchReport, Outcome, Presence, Profile, QueueStanding,
+    QueueStatus, RegionSummary, RejectReason, Room, Standing,
+};
+pub use multi::MultiQueue;
+#[cfg(feature = "port-mapping")]
+use port_mapping::PortMapping;
 use serde::{Deserialize, Serialize};
+use server_link::ServerLink;
 use snafu::{ResultExt, Snafu};
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
 use std::collections::{HashMap, HashSet};
-use std::convert::From;
-use std::hash::{Hash, Hasher};
-use std::net::{IpAddr, SocketAddr};
+use std::convert::{From, TryFrom};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::sync::PoisonError;
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 const PING_TIMER_MILLIS: u64 = 100;
+const CONNECT_TIMEOUT_MILLIS: u64 = 5000;
+// well within the connection timeout, so the server follows a client whose address changed
+// before the old address times out
+const RESUME_INTERVAL_MILLIS: u64 = 1000;
+// how long a challenged peer has to answer before it's reported to the server as unresponsive
+const CHALLENGE_TIMEOUT_MILLIS: u64 = 30_000;
+const FALLBACK_TIMEOUT_MILLIS: u64 = 2000;
 
 type ArMu<T> = Arc<Mutex<T>>;
 
@@ -36,14 +152,244 @@ fn armu<T>(t: T) -> ArMu<T> {
     Arc::new(Mutex::new(t))
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub enum ClientToClient {
     Ping(u128),
     PingResponse(u128),
-    Challenge,
+    /// Checks whether the peer can be reached at the address, see `connectivity`.
+    Probe {
+        session: u64,
+        nonce: u64,
+    },
+    ProbeResponse(u64),
+    /// A message that changes the state of a challenge or a match.
+    /// Numbered per peer so that repeated and stale messages are discarded.
+    Control {
+        session: u64,
+        sequence: u32,
+        message: Control,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum Control {
+    /// Challenges the peer, with metadata for its player to decide by, e.g. the game mode.
+    Challenge(Vec<u8>),
     Accept,
     Decline,
     Start(u128),
+    /// Starts a match with the sender and the given other members of the group.
+    GroupStart(Vec<SocketAddr>),
+    /// The addresses the sender may be reachable at besides the one the server observed.
+    Candidates(Vec<SocketAddr>),
+    /// The probe whose path the match uses.
+    Nominate(u64),
+    /// No probe was answered, so the match goes through the server's relay.
+    UseRelay,
+}
+
+fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(time) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(time.as_nanos());
+    }
+    hasher.finish()
+}
+
+/// The local IP address packets to the given address are sent from.
+pub(crate) fn local_ip_towards(addr: SocketAddr) -> std::io::Result<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(addr)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// The addresses besides the observed one that peers may reach the transport at.
+fn candidates(transport: &impl Transport, server_addr: SocketAddr) -> Vec<SocketAddr> {
+    let mut local_addr = match transport.local_addr() {
+        Some(local_addr) => local_addr,
+        None => return vec![],
+    };
+    if local_addr.ip().is_unspecified() {
+        match local_ip_towards(server_addr) {
+            Ok(ip) => local_addr.set_ip(ip),
+            Err(_) => return vec![],
+        }
+    }
+    vec![local_addr]
+}
+
+/// Numbers the control messages sent to each peer and remembers the latest one received from each.
+/// The session is random per client, so a peer that was recreated e.g. to requeue is not mistaken
+/// for repeating its old messages when its numbering starts over.
+struct ControlSequences {
+    session: u64,
+    outgoing: HashMap<SocketAddr, u32>,
+    incoming: HashMap<SocketAddr, (u64, u32)>,
+}
+
+impl ControlSequences {
+    fn new() -> Self {
+        Self {
+            session: random_u64(),
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    /// Numbers the message with the next sequence number for the peer.
+    fn next(&mut self, addr: SocketAddr, message: Control) -> ClientToClient {
+        let sequence = self.outgoing.entry(addr).or_insert(0);
+        let msg = ClientToClient::Control {
+            session: self.session,
+            sequence: *sequence,
+            message,
+        };
+        *sequence += 1;
+        msg
+    }
+
+    /// Continues the numbering with the peer at its new address.
+    fn migrate(&mut self, from: SocketAddr, to: SocketAddr) {
+        if let Some(sequence) = self.outgoing.remove(&from) {
+            self.outgoing.insert(to, sequence);
+        }
+        if let Some(latest) = self.incoming.remove(&from) {
+            self.incoming.insert(to, latest);
+        }
+    }
+
+    /// Whether this client controls the connectivity checks with the peer, see `connectivity`.
+    fn controls(&self, addr: SocketAddr) -> bool {
+        match self.incoming.get(&addr) {
+            Some(&(session, _)) => self.session > session,
+            None => false,
+        }
+    }
+
+    /// The peer whose messages carry the given session.
+    fn peer_with_session(&self, session: u64) -> Option<SocketAddr> {
+        self.incoming
+            .iter()
+            .find(|(_, &(s, _))| s == session)
+            .map(|(&addr, _)| addr)
+    }
+
+    /// Checks whether the message is newer than the ones already received from the peer,
+    /// and if so remembers it as the latest.
+    fn is_new(&mut self, addr: SocketAddr, session: u64, sequence: u32) -> bool {
+        match self.incoming.get(&addr) {
+            Some(&(latest_session, latest)) if latest_session == session && sequence <= latest => {
+                false
+            }
+            _ => {
+                self.incoming.insert(addr, (session, sequence));
+                true
+            }
+        }
+    }
+}
+
+/// Asks the server to queue the client, announcing the port forwarded to it if there is one.
+/// The player ID, party and profile are sent first, so the server knows them once queued.
+fn send_queue_request(
+    transport: &impl Transport,
+    server_addr: SocketAddr,
+    port: Option<u16>,
+    preamble: Vec<ToServer>,
+) -> Result<(), ClientError> {
+    for msg in &preamble {
+        send_to_server(transport, server_addr, msg)?;
+    }
+    let msg = match port {
+        Some(port) => ToServer::QueueMapped(port),
+        None => ToServer::Queue,
+    };
+    let msg = bincode::serialize(&msg).context(SerializeError { message: "Queue" })?;
+    transport.send(Packet::reliable_ordered(server_addr, msg, streams::CONTROL))?;
+    Ok(())
+}
+
+/// Asks the server to relay the packets to the peer.
+fn request_relay(
+    transport: &impl Transport,
+    server_addr: SocketAddr,
+    peer: SocketAddr,
+) -> Result<(), ClientError> {
+    let msg = bincode::serialize(&ToServer::RequestRelay(peer)).context(SerializeError {
+        message: "RequestRelay",
+    })?;
+    transport.send(Packet::reliable_ordered(server_addr, msg, streams::CONTROL))?;
+    Ok(())
+}
+
+/// Sends the message to the server on the control stream.
+fn send_to_server(
+    transport: &impl Transport,
+    server_addr: SocketAddr,
+    msg: &ToServer,
+) -> Result<(), ClientError> {
+    let msg = bincode::serialize(msg).context(SerializeError {
+        message: "a request to the server",
+    })?;
+    transport.send(Packet::reliable_ordered(server_addr, msg, streams::CONTROL))?;
+    Ok(())
+}
+
+/// Numbers the control message and sends it to the peer.
+fn send_control(
+    transport: &impl Transport,
+    sequences: &ArMu<ControlSequences>,
+    addr: SocketAddr,
+    message: Control,
+) -> Result<(), ClientError> {
+    let msg = sequences.lock()?.next(addr, message);
+    let msg = bincode::serialize(&msg).context(SerializeError {
+        message: "a control message",
+    })?;
+    transport.send(Packet::reliable_ordered(addr, msg, streams::CONTROL))?;
+    Ok(())
+}
+
+// forgets a peer that left the queue, cancelling the challenge with it unless their match has started
+fn forget_peer(
+    addr: SocketAddr,
+    peers: &ArMu<Peers>,
+    status: &ArMu<Status>,
+    checks: &ArMu<Checks>,
+    challenges: &ArMu<Challenges>,
+    requests: &ArMu<Requests>,
+) -> Result<(), ClientError> {
+    peers.lock()?.changed().remove(&addr);
+    let cancelled = {
+        let mut status = status.lock()?;
+        if *status == Status::MatchPending(addr) {
+            *status = Status::Queued;
+        }
+        // a match that has already started isn't called off
+        if *status == Status::MatchConfirmed(addr) {
+            None
+        } else {
+            checks.lock()?.stop(addr);
+            challenges.lock()?.cancel(addr)
+        }
+    };
+    let mut requests = requests.lock()?;
+    requests.unanswered.remove(&addr);
+    if let Some(cancelled) = cancelled {
+        info!(target: CLIENT_CHALLENGE, "{} left during a challenge", addr);
+        requests.cancelled.push(cancelled);
+    }
+    Ok(())
+}
+
+// lets an idle client accept challenges as if it was queued, without contacting the server
+fn connect_directly(status: &ArMu<Status>, checks: &ArMu<Checks>) -> Result<(), ClientError> {
+    let mut status = status.lock()?;
+    if let Status::Idle = *status {
+        *status = Status::Queued;
+        checks.lock()?.clear();
+    }
+    Ok(())
 }
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -58,9 +404,16 @@ pub enum PeerStatus {
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct Peer {
     addr: SocketAddr,
-    latency: Option<u128>,
+    latency: Option<Duration>,
     ping_count: u32,
     status: PeerStatus,
+    // the other members of the peer's party
+    party: Vec<SocketAddr>,
+    priority: u8,
+    // when the peer queued, if the server has told
+    queued_since: Option<Instant>,
+    handle: Option<String>,
+    blocked: bool,
 }
 
 impl Peer {
@@ -70,10 +423,15 @@ impl Peer {
             latency: None,
             ping_count: 0,
             status: PeerStatus::None,
+            party: Vec::new(),
+            priority: 0,
+            queued_since: None,
+            handle: None,
+            blocked: false,
         }
     }
 
-    pub fn add_ping(&mut self, ping_latency: u128) {
+    pub fn add_ping(&mut self, ping_latency: Duration) {
         self.ping_count += 1;
         match self.latency {
             Some(latency) => self.latency = Some(latency / 2 + ping_latency / 2),
@@ -85,13 +443,44 @@ impl Peer {
         self.addr
     }
 
-    pub fn latency(&self) -> Option<u128> {
+    /// The average one-way latency to the peer, once it has responded to a ping.
+    pub fn latency(&self) -> Option<Duration> {
         self.latency
     }
 
+    /// The latency in nanoseconds, as it was reported before it was a `Duration`.
+    pub fn latency_nanos(&self) -> Option<u128> {
+        self.latency.map(|latency| latency.as_nanos())
+    }
+
     pub fn status(&self) -> PeerStatus {
         self.status
     }
+
+    /// The other members of the peer's party, who are proposed as a group with it.
+    pub fn party(&self) -> &[SocketAddr] {
+        &self.party
+    }
+
+    /// The peer's priority tier on the server, higher tiers should be considered first.
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// How long the peer has been queued, if the server prioritizes players and has told.
+    pub fn waited(&self) -> Option<Duration> {
+        self.queued_since.map(|queued| queued.elapsed())
+    }
+
+    /// The public handle of the peer's player ID, if it identified itself, see `mirai_core::crypto::player_handle`.
+    pub fn handle(&self) -> Option<&str> {
+        self.handle.as_deref()
+    }
+
+    /// Whether the player blocked the peer, see `Client::block`.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
 }
 
 impl Hash for Peer {
@@ -100,20 +489,312 @@ impl Hash for Peer {
     }
 }
 
+/// A room listed by the server, with the latency to its host.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct RoomListing {
+    room: Room,
+    host: Peer,
+}
+
+impl RoomListing {
+    pub fn room(&self) -> &Room {
+        &self.room
+    }
+
+    pub fn host(&self) -> SocketAddr {
+        self.host.addr
+    }
+
+    pub fn latency(&self) -> Option<Duration> {
+        self.host.latency()
+    }
+
+    /// The latency in nanoseconds, as it was reported before it was a `Duration`.
+    pub fn latency_nanos(&self) -> Option<u128> {
+        self.host.latency_nanos()
+    }
+}
+
+/// What happened to a room the client joined or hosts, see `Client::room_events`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum RoomEvent {
+    /// The client joined the host's room and left the queue. The room's other members are at the addresses.
+    Joined {
+        host: SocketAddr,
+        members: Vec<SocketAddr>,
+    },
+    /// A player joined the room of the host, which is the client or one whose room it joined.
+    PlayerJoined {
+        host: SocketAddr,
+        player: SocketAddr,
+    },
+    /// The host's room is full, closed or doesn't match the client.
+    Full(SocketAddr),
+}
+
+/// A challenge with a peer that left the queue, e.g. because its connection to the server timed out,
+/// see `Client::cancelled_challenges`. The peer is no longer listed, and a pending match with it is
+/// called off.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CancelledChallenge {
+    /// The client had challenged the peer.
+    Outgoing(SocketAddr),
+    /// The peer had challenged the client.
+    Incoming(SocketAddr),
+    /// Both had challenged each other.
+    Mutual(SocketAddr),
+    /// One had accepted the other's challenge and the match was starting.
+    Accepted(SocketAddr),
+}
+
+impl CancelledChallenge {
+    pub fn peer(&self) -> SocketAddr {
+        match *self {
+            CancelledChallenge::Outgoing(peer)
+            | CancelledChallenge::Incoming(peer)
+            | CancelledChallenge::Mutual(peer)
+            | CancelledChallenge::Accepted(peer) => peer,
+        }
+    }
+}
+
+/// A peer's address now belongs to another player, e.g. because a NAT gave the previous player's address
+/// to someone else, see `Client::replaced_peers`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct PeerReplaced {
+    addr: SocketAddr,
+    previous: String,
+    current: String,
+}
+
+impl PeerReplaced {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The handle of the player that used to be at the address.
+    pub fn previous(&self) -> &str {
+        &self.previous
+    }
+
+    /// The handle of the player now at the address.
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+}
+
+/// A challenge from a peer, see `Client::incoming_challenges`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct IncomingChallenge {
+    peer: SocketAddr,
+    handle: Option<String>,
+    metadata: Vec<u8>,
+    latency: Option<Duration>,
+    received_at: Instant,
+    expires_at: Instant,
+}
+
+impl IncomingChallenge {
+    /// The challenger's address, to accept or decline the challenge with.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// The handle of the challenger's player ID, if it identified itself.
+    pub fn handle(&self) -> Option<&str> {
+        self.handle.as_deref()
+    }
+
+    /// What the challenger sent with the challenge, see `Client::challenge_with`.
+    pub fn metadata(&self) -> &[u8] {
+        &self.metadata
+    }
+
+    /// The average one-way latency to the challenger, once it has responded to a ping.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// When the challenge was first received.
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    /// When the challenger gives up on the challenge and reports the client as unresponsive
+    /// to the server, unless it has been answered.
+    pub fn expires_at(&self) -> Instant {
+        self.expires_at
+    }
+}
+
+// the time since a ping sent at the given nanoseconds after the start,
+// or none if the response echoed a time that wasn't sent by us, e.g. one from the future
+fn round_trip(start_time: Instant, now: Instant, sent: u128) -> Option<Duration> {
+    let sent = Duration::from_nanos(u64::try_from(sent).ok()?);
+    now.saturating_duration_since(start_time).checked_sub(sent)
+}
+
+// the rooms with their hosts, keeping the latency of hosts we already know
+fn listings(listed: Vec<(SocketAddr, Room)>, known: &[RoomListing]) -> Vec<RoomListing> {
+    listed
+        .into_iter()
+        .map(|(host, room)| {
+            let host = known
+                .iter()
+                .find(|listing| listing.host.addr == host)
+                .map(|listing| listing.host.clone())
+                .unwrap_or_else(|| Peer::new(host));
+            RoomListing { room, host }
+        })
+        .collect()
+}
+
+/// The server's answer to a replay upload or fetch.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum ReplayResponse {
+    Stored(u64),
+    /// The server doesn't keep replays, or the replay is too large.
+    Rejected,
+    Fetched {
+        id: u64,
+        replay: Option<Vec<u8>>,
+    },
+}
+
+/// The requests answered by the server outside of the queue.
+#[derive(Default)]
+struct Requests {
+    // sent before the client was verified, repeated once the server's cookie has been echoed
+    unverified: Vec<ToServer>,
+    rooms: Vec<RoomListing>,
+    // the rooms with free places the server last offered the queued client
+    backfill: Vec<RoomListing>,
+    room_events: Vec<RoomEvent>,
+    // the challenges cancelled since they were last taken
+    cancelled: Vec<CancelledChallenge>,
+    // the peers whose address was taken over by another player since they were last taken
+    replaced: Vec<PeerReplaced>,
+    // the peers blocked for flooding the client with challenges since they were last taken, until when
+    challenge_blocks: Vec<(SocketAddr, Instant)>,
+    replays: Vec<ReplayResponse>,
+    // the players and their recent matches
+    histories: Vec<(String, Vec<MatchRecord>)>,
+    leaderboards: Vec<Vec<Standing>>,
+    // the player ID reserved matches are scheduled with
+    identity: Option<String>,
+    // the resume token of a previous session the client takes over from when it queues
+    superseded: Option<u64>,
+    // the opponents of started reserved matches
+    scheduled: Vec<SocketAddr>,
+    // the client's friends and their presence, as last sent by the server
+    friends: Vec<(String, Presence)>,
+    // the code the server last gave the client to invite others with, until it's redeemed
+    invite: Option<String>,
+    // the invite codes the server rejected since they were last taken
+    invalid_invites: Vec<String>,
+    party: Option<u64>,
+    teammates: Vec<SocketAddr>,
+    profile: Option<Profile>,
+    // the latest queue status, and those received since they were last taken
+    queue_status: Option<QueueStatus>,
+    queue_statuses: Vec<QueueStatus>,
+    // the other regions the server offered since they were last taken
+    other_regions: Vec<RegionSummary>,
+    // the address the server receives the client's packets from
+    public_addr: Option<SocketAddr>,
+    // moves the client's queue entry if its address changes
+    resume_token: Option<u64>,
+    // when the peers that haven't answered the client's challenges were challenged
+    unanswered: HashMap<SocketAddr, Instant>,
+    // the server stopped proposing the client because it left challenges unanswered
+    idle: bool,
+    // why the server didn't queue the client, until the game is told
+    rejected: Option<RejectReason>,
+    // how well the packets are getting out, if the transport has reported it
+    send_health: Option<SendHealth>,
+    // whether the confirmed match has been reported, or left for the server to see through its relay
+    match_reported: bool,
+    // the latency and loss of the status checks sent to the server
+    server_link: ServerLink,
+    // the last update of the peer list, and whether the client asked for the whole list after missing one
+    peer_list: Option<PeerListSeq>,
+    resyncing: bool,
+    // what's known about the identified peers, if the client keeps them
+    known: Option<PeerStore>,
+    #[cfg(feature = "telemetry")]
+    telemetry: Option<telemetry::Telemetry>,
+}
+
+impl Requests {
+    /// The messages sent before each queue request.
+    fn queue_preamble(&self) -> Vec<ToServer> {
+        // the previous session has to be superseded before its player ID can be taken
+        let superseded = self.superseded.map(ToServer::Supersede);
+        let identity = self.identity.clone().map(ToServer::Identify);
+        let party = self.party.map(ToServer::JoinParty);
+        let profile = self.profile.clone().map(ToServer::Profile);
+        superseded
+            .into_iter()
+            .chain(identity)
+            .chain(party)
+            .chain(profile)
+            .collect()
+    }
+
+    /// Records a Queued or Dequeued, returning whether the client missed an update before it
+    /// and hasn't asked for the whole list yet.
+    fn peer_list_updated(&mut self, seq: PeerListSeq) -> bool {
+        let missed = self
+            .peer_list
+            .is_some_and(|last| last.epoch != seq.epoch || last.sequence + 1 != seq.sequence);
+        self.peer_list = Some(seq);
+        missed && !std::mem::replace(&mut self.resyncing, true)
+    }
+
+    /// Records a Peers, returning whether it's the whole list the client asked for after missing an update.
+    fn peer_list_received(&mut self, seq: PeerListSeq) -> bool {
+        self.peer_list = Some(seq);
+        std::mem::take(&mut self.resyncing)
+    }
+}
+
+/// The potential opponents, with a generation that is incremented whenever they change.
+#[derive(Default)]
+struct Peers {
+    map: HashMap<SocketAddr, Peer>,
+    generation: u64,
+}
+
+impl Peers {
+    /// Returns the peers to be modified and increments the generation.
+    fn changed(&mut self) -> &mut HashMap<SocketAddr, Peer> {
+        self.generation += 1;
+        &mut self.map
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 enum ServerConnection {
     Connected,
     Disconnected,
     Connecting(Instant),
+    // the server didn't answer while connecting
+    Unreachable,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 enum Status {
     Idle,
-    QueuePending,
+    // with the port forwarded to the client, which is repeated if the server sends a cookie
+    QueuePending(Option<u16>),
     Queued,
     MatchPending(SocketAddr),
     MatchConfirmed(SocketAddr),
+    GroupPending {
+        members: HashSet<SocketAddr>,
+        confirmed: HashSet<SocketAddr>,
+    },
+    GroupConfirmed(Vec<SocketAddr>),
 }
 
 enum Message {
@@ -121,20 +802,28 @@ enum Message {
 }
 
 /// The primary struct of the crate.
-pub struct Client {
+/// Generic over the transport used to communicate with the server and the peers.
+pub struct Client<T: Transport> {
     status: ArMu<Status>,
     server_addr: SocketAddr,
     server_connection: ArMu<ServerConnection>,
     message_sender: Sender<Message>,
-    packet_sender: Sender<Packet>,
-    peers: ArMu<HashMap<SocketAddr, Peer>>,
-    incoming_challenges: ArMu<HashSet<SocketAddr>>,
-    outgoing_challenges: ArMu<HashSet<SocketAddr>>,
-    handle: JoinHandle<Result<(Receiver<SocketEvent>, Sender<Packet>), ClientError>>,
+    transport: Arc<T>,
+    peers: ArMu<Peers>,
+    challenges: ArMu<Challenges>,
+    requests: ArMu<Requests>,
+    control_sequences: ArMu<ControlSequences>,
+    checks: ArMu<Checks>,
+    clock: Arc<dyn Clock>,
+    #[cfg(feature = "port-mapping")]
+    port_mapping: Option<PortMapping>,
+    handle: JoinHandle<Result<(), ClientError>>,
 }
 
-impl Client {
-    /// Creates a new Client. Starts up a thread that handles network traffic.
+impl Client<LaminarTransport> {
+    /// Creates a new Client using laminar. Starts up a thread that handles network traffic.
+    /// With the `port-mapping` feature, also asks the router to forward the client port,
+    /// which may take a few seconds if the router doesn't respond.
     /// # Errors
     /// If binding a socket to the given addr fails.
     pub fn new(addr: IpAddr, server_ip: IpAddr) -> Result<Self, CreateError> {
@@ -143,19 +832,165 @@ impl Client {
             addr, CLIENT_PORT, server_ip, SERVER_PORT
         );
         let socket_addr = SocketAddr::new(addr, CLIENT_PORT);
+        let transport = LaminarTransport::bind(socket_addr).context(BindError)?;
+        #[allow(unused_mut)]
+        let mut client = Self::with_transport(server_ip, transport);
+        #[cfg(feature = "port-mapping")]
+        match PortMapping::new(CLIENT_PORT) {
+            Ok(mapping) => {
+                info!("router forwards port {}", mapping.external_port());
+                client.port_mapping = Some(mapping);
+            }
+            Err(e) => info!("could not map the client port: {}", e),
+        }
+        Ok(client)
+    }
+
+    /// Checks whether the client port is free, whether the server answers a status check, what kind of NAT
+    /// the client is behind according to the STUN servers, e.g. public ones the game trusts, and how large
+    /// a UDP payload gets through to them. Binding requests are sent to at least two STUN servers to classify
+    /// the NAT. Takes a few seconds if the server or the STUN servers don't answer.
+    /// Nothing is left bound, so the client can be created with the same addresses afterwards.
+    pub fn diagnose(addr: IpAddr, server_ip: IpAddr, stun_servers: &[SocketAddr]) -> Diagnosis {
+        diagnose::diagnose(addr, server_ip, stun_servers)
+    }
+}
+
+impl Client<Box<dyn Transport>> {
+    /// Creates a new Client, falling back to TCP if the server does not respond over UDP.
+    /// Starts up a thread that handles network traffic.
+    /// # Errors
+    /// If binding a socket to the given addr fails.
+    pub fn with_fallback(addr: IpAddr, server_ip: IpAddr) -> Result<Self, CreateError> {
+        let server_addr = SocketAddr::new(server_ip, SERVER_PORT);
+        let udp = LaminarTransport::bind(SocketAddr::new(addr, CLIENT_PORT)).context(BindError)?;
+        let timeout = Duration::from_millis(FALLBACK_TIMEOUT_MILLIS);
+        let transport: Box<dyn Transport> = if server_responds(&udp, server_addr, timeout) {
+            Box::new(udp)
+        } else {
+            info!("server unreachable over UDP, falling back to TCP");
+            Box::new(TcpTransport::new())
+        };
+        Ok(Self::with_transport(server_ip, transport))
+    }
+}
+
+/// Encrypts the traffic to the server with `SecureTransport` if the server's public key
+/// is given in `MIRAI_SERVER_KEY`, otherwise returns the transport as it is.
+/// # Errors
+/// If `MIRAI_SERVER_KEY` is not a key of 64 hex digits.
+pub fn secure_transport(
+    transport: Box<dyn Transport>,
+    server_ip: IpAddr,
+) -> Result<Box<dyn Transport>, CreateError> {
+    match std::env::var("MIRAI_SERVER_KEY") {
+        Ok(key) => {
+            let key = key_from_hex(&key).ok_or(CreateError::InvalidServerKey)?;
+            let server_addr = SocketAddr::new(server_ip, SERVER_PORT);
+            info!("encrypting traffic with the server");
+            Ok(Box::new(SecureTransport::client(
+                transport,
+                server_addr,
+                key,
+            )))
+        }
+        Err(_) => Ok(transport),
+    }
+}
+
+/// Binds a UDP transport to the client port of the given address.
+/// The transport is impaired according to `MIRAI_IMPAIRMENT` if it is set.
+/// # Errors
+/// If binding the socket fails or `MIRAI_IMPAIRMENT` cannot be parsed.
+pub fn bind_transport(addr: IpAddr) -> Result<Box<dyn Transport>, CreateError> {
+    let transport =
+        LaminarTransport::bind(SocketAddr::new(addr, CLIENT_PORT)).context(BindError)?;
+    match Impairment::from_env().context(InvalidImpairment)? {
+        Some(impairment) => {
+            info!("impairing the network: {:?}", impairment);
+            Ok(Box::new(impair(transport, impairment)))
+        }
+        None => Ok(Box::new(transport)),
+    }
+}
+
+// sends a status check and waits for the server to respond
+fn server_responds(transport: &impl Transport, server_addr: SocketAddr, timeout: Duration) -> bool {
+    !probe_servers(transport, &[server_addr], timeout).is_empty()
+}
+
+/// Sends a status check to each of the servers, e.g. those of different regions, and waits up to
+/// `timeout` for them to respond. Returns the servers that responded with their round trip times,
+/// lowest first, so the first one is the server to queue on. Probe with the transport the client
+/// will be created with, since the round trip depends on the route from its address.
+pub fn probe_servers(
+    transport: &impl Transport,
+    servers: &[SocketAddr],
+    timeout: Duration,
+) -> Vec<(SocketAddr, Duration)> {
+    let msg = match bincode::serialize(&ToServer::StatusCheck) {
+        Ok(msg) => msg,
+        Err(_) => return vec![],
+    };
+    let mut pending = HashMap::new();
+    for &server in servers {
+        let packet = Packet::reliable_ordered(server, msg.clone(), streams::CONTROL);
+        if transport.send(packet).is_ok() {
+            pending.insert(server, Instant::now());
+        }
+    }
+    let mut responded = vec![];
+    let deadline = Instant::now() + timeout;
+    while !pending.is_empty() {
+        let remaining = match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) => remaining,
+            None => break,
+        };
+        match transport.events().recv_timeout(remaining) {
+            Ok(TransportEvent::Packet(packet)) if pending.contains_key(&packet.addr()) => {
+                if let Ok(FromServer::Alive) = bincode::deserialize(packet.payload()) {
+                    if let Some(sent) = pending.remove(&packet.addr()) {
+                        debug!("{} responded in {:?}", packet.addr(), sent.elapsed());
+                        responded.push((packet.addr(), sent.elapsed()));
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    responded.sort_by_key(|&(_, rtt)| rtt);
+    responded
+}
+
+impl<T: Transport> Client<T> {
+    /// Creates a new Client using the given transport,
+    /// e.g. one handed back by the game client to requeue after a match.
+    /// Starts up a thread that handles network traffic.
+    pub fn with_transport(server_ip: IpAddr, transport: T) -> Self {
+        Self::with_clock(server_ip, transport, SystemClock)
+    }
+
+    /// Creates a new Client whose timers and timeouts follow the given clock,
+    /// e.g. a `ManualClock` that a test advances past a challenge's timeout.
+    /// Starts up a thread that handles network traffic.
+    pub fn with_clock(server_ip: IpAddr, transport: T, clock: impl Clock + 'static) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+        let thread_clock = Arc::clone(&clock);
         let server_addr = SocketAddr::new(server_ip, SERVER_PORT);
-        let mut socket = Socket::bind(socket_addr).context(BindError)?;
-        let event_receiver = socket.get_event_receiver();
-        let packet_sender = socket.get_packet_sender();
-        let thread_packet_sender = socket.get_packet_sender();
-        let _handle = thread::spawn(move || socket.start_polling());
-
-        let peers = armu(HashMap::new());
-        let incoming_challenges = armu(HashSet::new());
-        let outgoing_challenges = armu(HashSet::new());
+        let transport = Arc::new(transport);
+        let thread_transport = Arc::clone(&transport);
+
+        let peers = armu(Peers::default());
+        let challenges = armu(Challenges::default());
         let thread_peers = Arc::clone(&peers);
-        let thread_incoming_challenges = Arc::clone(&incoming_challenges);
-        let thread_outgoing_challenges = Arc::clone(&outgoing_challenges);
+        let thread_challenges = Arc::clone(&challenges);
+        let requests = armu(Requests::default());
+        let thread_requests = Arc::clone(&requests);
+        let control_sequences = armu(ControlSequences::new());
+        let thread_control_sequences = Arc::clone(&control_sequences);
+        let checks = armu(Checks::default());
+        let thread_checks = Arc::clone(&checks);
 
         let (message_sender, message_receiver) = unbounded();
         let status = armu(Status::Idle);
@@ -165,213 +1000,921 @@ impl Client {
         let handle = thread::spawn(move || {
             Self::handler(
                 server_addr,
-                thread_packet_sender,
-                event_receiver,
+                &*thread_transport,
                 message_receiver,
                 thread_peers,
-                thread_outgoing_challenges,
-                thread_incoming_challenges,
+                thread_challenges,
+                thread_requests,
+                thread_control_sequences,
+                thread_checks,
                 thread_status,
                 thread_server_connection,
+                thread_clock,
             )
         });
-        Ok(Self {
+        Self {
             status,
             server_addr,
             server_connection,
             message_sender,
-            packet_sender,
+            transport,
             peers,
-            outgoing_challenges,
-            incoming_challenges,
+            challenges,
+            requests,
+            control_sequences,
+            checks,
+            clock,
+            #[cfg(feature = "port-mapping")]
+            port_mapping: None,
             handle,
-        })
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn handler(
         server_addr: SocketAddr,
-        packet_sender: Sender<Packet>,
-        event_receiver: Receiver<SocketEvent>,
+        transport: &T,
         message_receiver: Receiver<Message>,
-        peers: ArMu<HashMap<SocketAddr, Peer>>,
-        outgoing_challenges: ArMu<HashSet<SocketAddr>>,
-        incoming_challenges: ArMu<HashSet<SocketAddr>>,
+        peers: ArMu<Peers>,
+        challenges: ArMu<Challenges>,
+        requests: ArMu<Requests>,
+        control_sequences: ArMu<ControlSequences>,
+        checks: ArMu<Checks>,
         status: ArMu<Status>,
         server_connection: ArMu<ServerConnection>,
-    ) -> Result<(Receiver<SocketEvent>, Sender<Packet>), ClientError> {
-        let start_time = Instant::now();
-        let mut ping_timer = Instant::now() - Duration::from_millis(PING_TIMER_MILLIS);
+        clock: Arc<dyn Clock>,
+    ) -> Result<(), ClientError> {
+        let start_time = clock.now();
+        let ticker = tick(Duration::from_millis(PING_TIMER_MILLIS));
+        // the ping is serialized once per tick, reusing the buffer
+        let mut ping = Vec::new();
+        let mut last_resume = start_time;
         debug!("starting handler");
         loop {
-            match event_receiver.try_recv() {
-                Ok(SocketEvent::Packet(packet)) => {
-                    trace!("received packet");
-                    if packet.addr() != server_addr {
-                        trace!("received packet from client");
-                        match bincode::deserialize::<FromClient>(packet.payload()) {
-                            Ok(FromClient::Challenge) => {
-                                debug!("received challenge");
-                                incoming_challenges.lock()?.insert(packet.addr());
-                            }
-                            Ok(FromClient::Accept) => {
-                                debug!("received accept");
-                                let mut status = status.lock()?;
-                                if let Status::Queued = *status {
-                                    if outgoing_challenges.lock()?.contains(&packet.addr()) {
-                                        let msg = bincode::serialize(&ToClient::Start(0))
-                                            .context(SerializeError)?;
-                                        packet_sender
-                                            .send(Packet::reliable_unordered(packet.addr(), msg))?;
-                                        *status = Status::MatchPending(packet.addr());
+            // blocks until there's something to do so an idle client doesn't use the CPU
+            select! {
+                recv(transport.events()) -> event => match event {
+                    Ok(TransportEvent::Packet(packet)) => {
+                        trace!("received packet");
+                        if packet.addr() != server_addr {
+                            trace!("received packet from client");
+                            match bincode::deserialize::<FromClient>(packet.payload()) {
+                                Ok(FromClient::Control {
+                                    session,
+                                    sequence,
+                                    message,
+                                }) => {
+                                    let addr = packet.addr();
+                                    if control_sequences.lock()?.is_new(addr, session, sequence) {
+                                        // whatever the peer sent, it's at its keyboard
+                                        requests.lock()?.unanswered.remove(&addr);
+                                        let blocked = matches!(message, Control::Challenge(_))
+                                            && peers
+                                                .lock()?
+                                                .map
+                                                .get(&addr)
+                                                .is_some_and(|peer| peer.blocked);
+                                        let limited = if let Control::Challenge(_) = message {
+                                            challenges.lock()?.limit(addr, clock.now())
+                                        } else {
+                                            Limited::Allowed
+                                        };
+                                        if let Limited::Blocked(until) = limited {
+                                            warn!(target: CLIENT_CHALLENGE, "blocking {} for flooding the client with challenges", addr);
+                                            requests.lock()?.challenge_blocks.push((addr, until));
+                                        }
+                                        if blocked || limited != Limited::Allowed {
+                                            debug!(target: CLIENT_CHALLENGE, "declining the challenge of {}", addr);
+                                            let decline = Control::Decline;
+                                            send_control(transport, &control_sequences, addr, decline)?;
+                                            continue;
+                                        }
+                                        #[cfg(feature = "telemetry")]
+                                        if matches!(message, Control::Decline)
+                                            && challenges.lock()?.outgoing().contains(&addr)
+                                        {
+                                            if let Some(telemetry) = &mut requests.lock()?.telemetry {
+                                                telemetry.declined();
+                                            }
+                                        }
+                                        Self::handle_control(
+                                            addr,
+                                            message,
+                                            transport,
+                                            server_addr,
+                                            &control_sequences,
+                                            &checks,
+                                            &challenges,
+                                            &peers,
+                                            &status,
+                                            clock.now(),
+                                        )?;
+                                    } else {
+                                        debug!("discarding repeated control message");
                                     }
                                 }
-                            }
-                            Ok(FromClient::Decline) => {
-                                debug!("received decline");
-                                outgoing_challenges.lock()?.remove(&packet.addr());
-                                let mut status = status.lock()?;
-                                if let Status::MatchPending(addr) = *status {
-                                    if addr == packet.addr() {
-                                        // got declined by someone we sent Start to
-                                        *status = Status::Queued;
+                                Ok(FromClient::Ping(remote_time)) => {
+                                    trace!(target: CLIENT_PING, "received ping");
+                                    let msg =
+                                        bincode::serialize(&ToClient::PingResponse(remote_time))
+                                            .context(SerializeError { message: "PingResponse" })?;
+                                    let response = Packet::unreliable_sequenced(
+                                        packet.addr(),
+                                        msg,
+                                        streams::PING,
+                                    );
+                                    transport.send(response)?;
+                                }
+                                Ok(FromClient::Probe { session, nonce }) => {
+                                    trace!(target: CLIENT_CONNECTIVITY, "received probe");
+                                    let msg = bincode::serialize(&ToClient::ProbeResponse(nonce))
+                                        .context(SerializeError { message: "ProbeResponse" })?;
+                                    transport.send(Packet::unreliable(packet.addr(), msg))?;
+                                    let peer = control_sequences.lock()?.peer_with_session(session);
+                                    if let Some(peer) = peer {
+                                        checks.lock()?.probed(peer, nonce, packet.addr());
                                     }
                                 }
-                            }
-                            Ok(FromClient::Start(time)) => {
-                                debug!("received start");
-                                let mut status = status.lock()?;
-                                if let Status::Queued = *status {
-                                    // they are match pending
-                                    let msg = bincode::serialize(&ToClient::Start(0))
-                                        .context(SerializeError)?;
-                                    packet_sender
-                                        .send(Packet::reliable_unordered(packet.addr(), msg))?;
-                                    incoming_challenges.lock()?.clear();
-                                    outgoing_challenges.lock()?.clear();
-                                    *status = Status::MatchConfirmed(packet.addr());
-                                } else if let Status::MatchPending(addr) = *status {
-                                    if addr == packet.addr() {
-                                        // pending match confirmed
-                                        *status = Status::MatchConfirmed(packet.addr());
+                                Ok(FromClient::ProbeResponse(nonce)) => {
+                                    trace!(target: CLIENT_CONNECTIVITY, "received probe response");
+                                    let peer = checks.lock()?.answered(nonce, packet.addr());
+                                    if let Some(peer) = peer {
+                                        debug!(target: CLIENT_CONNECTIVITY, "reached {} at {}", peer, packet.addr());
+                                        let message = Control::Nominate(nonce);
+                                        send_control(transport, &control_sequences, peer, message)?;
                                     }
                                 }
-                            }
-                            Ok(FromClient::Ping(remote_time)) => {
-                                trace!("received ping");
-                                let msg = bincode::serialize(&ToClient::PingResponse(remote_time))
-                                    .context(SerializeError)?;
-                                packet_sender.send(Packet::unreliable(packet.addr(), msg))?;
-                            }
-                            Ok(FromClient::PingResponse(past_local_time)) => {
-                                trace!("received pingresponse");
-                                let mut peers = peers.lock()?;
-                                if let Some(peer) = peers.get_mut(&packet.addr()) {
-                                    let local_time = start_time.elapsed().as_nanos();
-                                    let latency = (local_time - past_local_time) / 2;
-                                    peer.add_ping(latency);
+                                Ok(FromClient::PingResponse(past_local_time)) => {
+                                    trace!(target: CLIENT_PING, "received pingresponse");
+                                    if let Some(round_trip) =
+                                        round_trip(start_time, clock.now(), past_local_time)
+                                    {
+                                        let peers = &mut *peers.lock()?;
+                                        if let Some(peer) = peers.map.get_mut(&packet.addr()) {
+                                            peer.add_ping(round_trip / 2);
+                                            peers.generation += 1;
+                                        }
+                                        let requests = &mut *requests.lock()?;
+                                        for listing in
+                                            requests.rooms.iter_mut().chain(&mut requests.backfill)
+                                        {
+                                            if listing.host.addr == packet.addr() {
+                                                listing.host.add_ping(round_trip / 2);
+                                            }
+                                        }
+                                        let peer = peers.map.get(&packet.addr());
+                                        if let (Some(peer), Some(known)) = (peer, &mut requests.known) {
+                                            if let Some(handle) = &peer.handle {
+                                                known.entry(handle).latency = peer.latency;
+                                            }
+                                        }
+                                    }
                                 }
+                                Err(_) => {}
                             }
-                            Err(_) => {}
-                        }
-                    } else {
-                        trace!("received packet from server");
-                        match bincode::deserialize::<FromServer>(packet.payload()) {
-                            Ok(FromServer::Peers(new_peers)) => {
-                                debug!("received peers");
-                                let mut peers = peers.lock()?;
-                                for peer in new_peers {
-                                    peers.insert(peer, Peer::new(peer));
-                                }
-
-                                let mut status = status.lock()?;
-                                if let Status::QueuePending = *status {
-                                    *status = Status::Queued;
+                        } else {
+                            trace!("received packet from server");
+                            {
+                                let mut server_connection = server_connection.lock()?;
+                                if let ServerConnection::Connecting(_) = *server_connection {
+                                    *server_connection = ServerConnection::Connected;
                                 }
                             }
-                            Ok(FromServer::Queued(addr)) => {
-                                debug!("received queued");
-                                peers.lock()?.insert(addr, Peer::new(addr));
-                            }
-                            Ok(FromServer::Dequeued(addr)) => {
-                                debug!("received dequeued");
-                                peers.lock()?.remove(&addr);
-                            }
-                            _ => {
-                                warn!("unknown packet from server");
-                            }
-                        }
-                    }
-                }
-                Ok(SocketEvent::Connect(addr)) => {
-                    trace!("connected");
-                    if addr == server_addr {
-                        info!("connected to server");
-                        *server_connection.lock()? = ServerConnection::Connected;
-                    }
-                }
-                Ok(SocketEvent::Timeout(addr)) => {
-                    trace!("disconnected");
-                    if addr == server_addr {
-                        info!("disconnected from server");
-                        *server_connection.lock()? = ServerConnection::Disconnected;
-                    }
-                }
-                Err(_) => {}
-            }
-            match message_receiver.try_recv() {
-                Ok(Message::Quit) => return Ok((event_receiver, packet_sender)),
-                Err(_) => {}
-            }
-            if ping_timer.elapsed() > Duration::from_millis(PING_TIMER_MILLIS) {
-                for peer in peers.lock()?.values() {
-                    let msg = bincode::serialize(&ToClient::Ping(start_time.elapsed().as_nanos()))
-                        .context(SerializeError)?;
-                    packet_sender.send(Packet::unreliable(peer.addr, msg))?;
-                }
-                ping_timer = Instant::now();
-            }
-            let mut server_connection = server_connection.lock()?;
-            if let ServerConnection::Connecting(time_limit) = *server_connection {
-                if Instant::now() > time_limit {
-                    *server_connection = ServerConnection::Disconnected;
-                }
-            }
-        }
-    }
-
-    /// Queues the client.
-    /// # Errors
-    /// If there is an issue serializing or sending the message, or
-    /// if the handler thread has panicked.
-    pub fn queue(&mut self) -> Result<(), ClientError> {
-        debug!("queueing");
-        let mut status = self.status.lock()?;
-        if let Status::Idle = *status {
-            let msg = bincode::serialize(&ToServer::Queue).context(SerializeError)?;
-            self.packet_sender
-                .send(Packet::reliable_unordered(self.server_addr, msg))?;
-            let mut server_connection = self.server_connection.lock()?;
-            if let ServerConnection::Disconnected = *server_connection {
-                debug!("asd");
-            }
-            *status = Status::QueuePending;
-        }
-        Ok(())
-    }
+                            // the peer the server introduced the client to, and whether the client
+                            // asked to challenge it
+                            let mut introduced = None;
+                            match bincode::deserialize::<FromServer>(packet.payload()) {
+                                Ok(FromServer::Peers(seq, new_peers)) => {
+                                    debug!("received peers");
+                                    let resynced = requests.lock()?.peer_list_received(seq);
+                                    // the peers whose Dequeued was missed
+                                    let stale: Vec<_> = if resynced {
+                                        let peers = peers.lock()?;
+                                        peers
+                                            .map
+                                            .keys()
+                                            .filter(|addr| !new_peers.contains(addr))
+                                            .copied()
+                                            .collect()
+                                    } else {
+                                        vec![]
+                                    };
+                                    for addr in stale {
+                                        debug!("{} left the queue unnoticed", addr);
+                                        forget_peer(addr, &peers, &status, &checks, &challenges, &requests)?;
+                                    }
+                                    let mut peers = peers.lock()?;
+                                    let peers = peers.changed();
+                                    for peer in new_peers {
+                                        peers.insert(peer, Peer::new(peer));
+                                    }
 
-    /// Dequeues the client.
-    /// # Errors
-    /// If there is an issue serializing or sending the message, or
-    /// if the handler thread has panicked.
-    pub fn dequeue(&self) -> Result<(), ClientError> {
-        let mut status = self.status.lock()?;
-        if let Status::QueuePending | Status::Queued = *status {
-            let msg = bincode::serialize(&ToServer::Dequeue).context(SerializeError)?;
-            self.packet_sender
-                .send(Packet::reliable_unordered(self.server_addr, msg))?;
-            *status = Status::Idle;
-            *self.server_connection.lock()? = ServerConnection::Disconnected;
-        }
+                                    let mut status = status.lock()?;
+                                    if let Status::QueuePending(_) = *status {
+                                        *status = Status::Queued;
+                                    }
+                                    #[cfg(feature = "telemetry")]
+                                    if let Some(telemetry) = &mut requests.lock()?.telemetry {
+                                        telemetry.peers_received(clock.now());
+                                    }
+                                }
+                                Ok(FromServer::Standings(standings)) => {
+                                    debug!("received {} standings", standings.len());
+                                    let now = Instant::now();
+                                    let mut peers = peers.lock()?;
+                                    let peers = peers.changed();
+                                    for (addr, standing) in standings {
+                                        if let Some(peer) = peers.get_mut(&addr) {
+                                            peer.priority = standing.priority;
+                                            peer.queued_since = now.checked_sub(standing.waited);
+                                        }
+                                    }
+                                }
+                                Ok(FromServer::PeerHandles(handles)) => {
+                                    debug!("received {} peer handles", handles.len());
+                                    let mut peers = peers.lock()?;
+                                    let peers = peers.changed();
+                                    let mut status = status.lock()?;
+                                    let mut requests = requests.lock()?;
+                                    for (addr, handle) in handles {
+                                        if let Some(peer) = peers.get_mut(&addr) {
+                                            let previous = peer.handle.clone().filter(|previous| *previous != handle);
+                                            if let Some(previous) = previous {
+                                                // another player behind the same address, nothing known about
+                                                // the previous one applies to it
+                                                info!("{} is now {} instead of {}", addr, handle, previous);
+                                                *peer = Peer::new(addr);
+                                                if *status == Status::MatchPending(addr) {
+                                                    *status = Status::Queued;
+                                                }
+                                                // a match that has already started isn't called off
+                                                if *status != Status::MatchConfirmed(addr) {
+                                                    checks.lock()?.stop(addr);
+                                                    if let Some(cancelled) = challenges.lock()?.cancel(addr) {
+                                                        requests.cancelled.push(cancelled);
+                                                    }
+                                                }
+                                                requests.unanswered.remove(&addr);
+                                                requests.replaced.push(PeerReplaced {
+                                                    addr,
+                                                    previous,
+                                                    current: handle.clone(),
+                                                });
+                                            }
+                                            if let Some(known) = &mut requests.known {
+                                                let known = known.entry(&handle);
+                                                if peer.handle.is_none() {
+                                                    known.encounters += 1;
+                                                }
+                                                peer.blocked = known.blocked;
+                                                // until the peer answers a ping
+                                                if peer.latency.is_none() {
+                                                    peer.latency = known.latency;
+                                                }
+                                            }
+                                            peer.handle = Some(handle);
+                                        }
+                                    }
+                                }
+                                Ok(FromServer::Queued(seq, addrs)) => {
+                                    debug!("received queued");
+                                    if requests.lock()?.peer_list_updated(seq) {
+                                        info!("missed an update of the peers, asking for them again");
+                                        send_to_server(transport, server_addr, &ToServer::Resync)?;
+                                    }
+                                    let mut peers = peers.lock()?;
+                                    let peers = peers.changed();
+                                    for addr in addrs {
+                                        // keep the latency of peers we already know
+                                        peers.entry(addr).or_insert_with(|| Peer::new(addr));
+                                    }
+                                }
+                                Ok(FromServer::Cookie(cookie)) => {
+                                    debug!("received cookie");
+                                    let queue_pending = match *status.lock()? {
+                                        Status::QueuePending(port) => Some(port),
+                                        _ => None,
+                                    };
+                                    let unverified =
+                                        std::mem::take(&mut requests.lock()?.unverified);
+                                    if queue_pending.is_some() || !unverified.is_empty() {
+                                        let msg = bincode::serialize(&ToServer::Cookie(cookie))
+                                            .context(SerializeError { message: "Cookie" })?;
+                                        transport.send(Packet::reliable_ordered(
+                                            server_addr,
+                                            msg,
+                                            streams::CONTROL,
+                                        ))?;
+                                    }
+                                    if let Some(port) = queue_pending {
+                                        let preamble = requests.lock()?.queue_preamble();
+                                        send_queue_request(
+                                            transport,
+                                            server_addr,
+                                            port,
+                                            preamble,
+                                        )?;
+                                    }
+                                    for request in &unverified {
+                                        send_to_server(transport, server_addr, request)?;
+                                    }
+                                }
+                                Ok(FromServer::Dequeued(seq, addr)) => {
+                                    debug!("received dequeued");
+                                    if requests.lock()?.peer_list_updated(seq) {
+                                        info!("missed an update of the peers, asking for them again");
+                                        send_to_server(transport, server_addr, &ToServer::Resync)?;
+                                    }
+                                    forget_peer(addr, &peers, &status, &checks, &challenges, &requests)?;
+                                }
+                                Ok(FromServer::RelayReady(peer)) => {
+                                    debug!(target: CLIENT_CONNECTIVITY, "the server relays to {}", peer);
+                                    checks.lock()?.relay_answered(peer, true);
+                                }
+                                Ok(FromServer::RelayUnavailable(peer)) => {
+                                    debug!(target: CLIENT_CONNECTIVITY, "the server can't relay to {}", peer);
+                                    checks.lock()?.relay_answered(peer, false);
+                                }
+                                Ok(FromServer::Rooms(listed)) => {
+                                    debug!("received {} rooms", listed.len());
+                                    let mut requests = requests.lock()?;
+                                    requests.unverified.clear();
+                                    let known = std::mem::take(&mut requests.rooms);
+                                    requests.rooms = listings(listed, &known);
+                                }
+                                Ok(FromServer::Backfill(offered)) => {
+                                    debug!("offered {} rooms to backfill", offered.len());
+                                    let mut requests = requests.lock()?;
+                                    let known = std::mem::take(&mut requests.backfill);
+                                    requests.backfill = listings(offered, &known);
+                                }
+                                Ok(FromServer::JoinedRoom { host, members }) => {
+                                    info!("joined the room of {}", host);
+                                    let mut status = status.lock()?;
+                                    if let Status::QueuePending(_) | Status::Queued = *status {
+                                        *status = Status::Idle;
+                                        *server_connection.lock()? = ServerConnection::Disconnected;
+                                    }
+                                    let mut requests = requests.lock()?;
+                                    requests.backfill.clear();
+                                    requests.queue_status = None;
+                                    requests.resume_token = None;
+                                    requests.room_events.push(RoomEvent::Joined { host, members });
+                                }
+                                Ok(FromServer::RoomJoined { host, player }) => {
+                                    debug!("{} joined the room of {}", player, host);
+                                    let event = RoomEvent::PlayerJoined { host, player };
+                                    requests.lock()?.room_events.push(event);
+                                }
+                                Ok(FromServer::RoomFull(host)) => {
+                                    debug!("could not join the room of {}", host);
+                                    let mut requests = requests.lock()?;
+                                    requests.backfill.retain(|listing| listing.host.addr != host);
+                                    requests.room_events.push(RoomEvent::Full(host));
+                                }
+                                Ok(FromServer::ReplayStored(id)) => {
+                                    debug!("the server stored the replay as {}", id);
+                                    let mut requests = requests.lock()?;
+                                    requests.unverified.clear();
+                                    requests.replays.push(ReplayResponse::Stored(id));
+                                }
+                                Ok(FromServer::ReplayRejected) => {
+                                    debug!("the server rejected the replay");
+                                    let mut requests = requests.lock()?;
+                                    requests.unverified.clear();
+                                    requests.replays.push(ReplayResponse::Rejected);
+                                }
+                                Ok(FromServer::Scheduled(peer)) => {
+                                    info!("the reserved match with {} has started", peer);
+                                    peers
+                                        .lock()?
+                                        .changed()
+                                        .entry(peer)
+                                        .or_insert_with(|| Peer::new(peer));
+                                    requests.lock()?.scheduled.push(peer);
+                                }
+                                Ok(FromServer::Parties(parties)) => {
+                                    debug!("received {} parties", parties.len());
+                                    let mut peers = peers.lock()?;
+                                    let peers = peers.changed();
+                                    for party in parties {
+                                        for member in &party {
+                                            if let Some(peer) = peers.get_mut(member) {
+                                                peer.party = party
+                                                    .iter()
+                                                    .filter(|&m| m != member)
+                                                    .copied()
+                                                    .collect();
+                                            }
+                                        }
+                                    }
+                                }
+                                Ok(FromServer::Party(teammates)) => {
+                                    debug!("the party has {} other members", teammates.len());
+                                    requests.lock()?.teammates = teammates;
+                                }
+                                Ok(FromServer::History { player, matches }) => {
+                                    debug!("received the history of {}", player);
+                                    let mut requests = requests.lock()?;
+                                    requests.unverified.clear();
+                                    requests.histories.push((player, matches));
+                                }
+                                Ok(FromServer::Leaderboard(standings)) => {
+                                    debug!("received {} standings", standings.len());
+                                    let mut requests = requests.lock()?;
+                                    requests.unverified.clear();
+                                    requests.leaderboards.push(standings);
+                                }
+                                Ok(FromServer::QueueStatus(queue_status)) => {
+                                    trace!("received queue status");
+                                    let mut requests = requests.lock()?;
+                                    requests.queue_status = Some(queue_status);
+                                    requests.queue_statuses.push(queue_status);
+                                }
+                                Ok(FromServer::ResumeToken(token)) => {
+                                    trace!("received resume token");
+                                    requests.lock()?.resume_token = Some(token);
+                                }
+                                Ok(FromServer::PeerMoved { from, to }) => {
+                                    let mut peers = peers.lock()?;
+                                    if peers.map.contains_key(&from) {
+                                        debug!("{} moved to {}", from, to);
+                                        let peers = peers.changed();
+                                        if let Some(mut peer) = peers.remove(&from) {
+                                            peer.addr = to;
+                                            peers.insert(to, peer);
+                                        }
+                                        transport.migrate(from, to);
+                                        control_sequences.lock()?.migrate(from, to);
+                                        challenges.lock()?.migrate(from, to);
+                                        let mut requests = requests.lock()?;
+                                        if let Some(challenged) = requests.unanswered.remove(&from) {
+                                            requests.unanswered.insert(to, challenged);
+                                        }
+                                    }
+                                }
+                                Ok(FromServer::Presence(presences)) => {
+                                    debug!("received the presence of {} friends", presences.len());
+                                    let mut requests = requests.lock()?;
+                                    requests.unverified.clear();
+                                    for (id, presence) in presences {
+                                        let friend =
+                                            requests.friends.iter_mut().find(|(f, _)| *f == id);
+                                        if let Some((_, known)) = friend {
+                                            *known = presence;
+                                        }
+                                    }
+                                }
+                                Ok(FromServer::FriendAddr { friend, addr }) => {
+                                    debug!(target: CLIENT_CHALLENGE, "challenging {} at {}", friend, addr);
+                                    introduced = Some((addr, Some(friend), true));
+                                }
+                                Ok(FromServer::FriendChallenge { friend, addr }) => {
+                                    debug!(target: CLIENT_CHALLENGE, "{} at {} is challenging the client", friend, addr);
+                                    introduced = Some((addr, Some(friend), false));
+                                }
+                                Ok(FromServer::Invite(code)) => {
+                                    debug!("received invite {}", code);
+                                    let mut requests = requests.lock()?;
+                                    requests.unverified.clear();
+                                    requests.invite = Some(code);
+                                }
+                                Ok(FromServer::InviteRedeemed(addr)) => {
+                                    debug!("{} redeemed the invite", addr);
+                                    requests.lock()?.invite = None;
+                                    introduced = Some((addr, None, false));
+                                }
+                                Ok(FromServer::InviteHost { code, addr }) => {
+                                    debug!(target: CLIENT_CHALLENGE, "challenging the creator of invite {} at {}", code, addr);
+                                    requests.lock()?.unverified.clear();
+                                    introduced = Some((addr, None, true));
+                                }
+                                Ok(FromServer::InviteInvalid(code)) => {
+                                    info!("invite {} is invalid", code);
+                                    let mut requests = requests.lock()?;
+                                    requests.unverified.clear();
+                                    requests.invalid_invites.push(code);
+                                }
+                                Ok(FromServer::Idle { dequeued }) => {
+                                    info!("the server marked the client idle");
+                                    let mut requests = requests.lock()?;
+                                    requests.idle = true;
+                                    if dequeued {
+                                        let mut status = status.lock()?;
+                                        if let Status::QueuePending(_) | Status::Queued = *status {
+                                            *status = Status::Idle;
+                                            *server_connection.lock()? =
+                                                ServerConnection::Disconnected;
+                                        }
+                                        requests.queue_status = None;
+                                        requests.resume_token = None;
+                                    }
+                                }
+                                Ok(FromServer::Rejected(reason)) => {
+                                    info!("the server rejected the queue request: {:?}", reason);
+                                    if let Status::QueuePending(_) = *status.lock()? {
+                                        requests.lock()?.rejected = Some(reason);
+                                    }
+                                }
+                                Ok(FromServer::YourAddr(addr)) => {
+                                    debug!("server sees the client at {}", addr);
+                                    requests.lock()?.public_addr = Some(addr);
+                                }
+                                Ok(FromServer::OtherRegions(regions)) => {
+                                    debug!("offered {} other regions", regions.len());
+                                    requests.lock()?.other_regions = regions;
+                                }
+                                Ok(FromServer::Alive) => {
+                                    trace!("received alive");
+                                    requests.lock()?.server_link.answered(clock.now());
+                                }
+                                Ok(FromServer::Replay { id, replay }) => {
+                                    debug!("received replay {}", id);
+                                    let mut requests = requests.lock()?;
+                                    requests.unverified.clear();
+                                    requests.replays.push(ReplayResponse::Fetched { id, replay });
+                                }
+                                _ => {
+                                    warn!("unknown packet from server");
+                                }
+                            }
+                            if let Some((addr, friend, challenge)) = introduced {
+                                connect_directly(&status, &checks)?;
+                                let mut peers = peers.lock()?;
+                                let peer = peers
+                                    .changed()
+                                    .entry(addr)
+                                    .or_insert_with(|| Peer::new(addr));
+                                if let Some(friend) = friend {
+                                    peer.handle = Some(player_handle(&friend));
+                                }
+                                if challenge && challenges.lock()?.handle(addr, ChallengeEvent::Challenge) {
+                                    send_control(transport, &control_sequences, addr, Control::Challenge(Vec::new()))?;
+                                    peer.status = PeerStatus::OutgoingChallenge;
+                                    let mut requests = requests.lock()?;
+                                    requests.unanswered.insert(addr, clock.now());
+                                    #[cfg(feature = "telemetry")]
+                                    if let Some(telemetry) = &mut requests.telemetry {
+                                        telemetry.challenged();
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Ok(TransportEvent::Connect(addr)) => {
+                        trace!("connected");
+                        if addr == server_addr {
+                            info!("connected to server");
+                            *server_connection.lock()? = ServerConnection::Connected;
+                        }
+                    }
+                    Ok(TransportEvent::Timeout(addr)) => {
+                        trace!("disconnected");
+                        if addr == server_addr {
+                            info!("disconnected from server");
+                            *server_connection.lock()? = ServerConnection::Disconnected;
+                        }
+                    }
+                    Ok(TransportEvent::Health(health)) => {
+                        match health {
+                            SendHealth::Healthy => info!("the packets are getting out again"),
+                            _ => warn!("the packets aren't getting out: {:?}", health),
+                        }
+                        requests.lock()?.send_health = Some(health);
+                    }
+                    Err(_) => return Ok(()),
+                },
+                recv(message_receiver) -> message => match message {
+                    Ok(Message::Quit) | Err(_) => return Ok(()),
+                },
+                recv(ticker) -> _ => {
+                    let now = clock.now();
+                    ping.clear();
+                    let msg = ToClient::Ping(now.saturating_duration_since(start_time).as_nanos());
+                    bincode::serialize_into(&mut ping, &msg).context(SerializeError { message: "Ping" })?;
+                    for peer in peers.lock()?.map.values() {
+                        // the transport takes ownership of the payload
+                        transport.send(Packet::unreliable_sequenced(
+                            peer.addr,
+                            ping.clone(),
+                            streams::PING,
+                        ))?;
+                    }
+                    {
+                        let requests = requests.lock()?;
+                        for listing in requests.rooms.iter().chain(&requests.backfill) {
+                            transport.send(Packet::unreliable_sequenced(
+                                listing.host.addr,
+                                ping.clone(),
+                                streams::PING,
+                            ))?;
+                        }
+                    }
+                    let session = control_sequences.lock()?.session;
+                    for (candidate, nonce) in checks.lock()?.pending_probes(now) {
+                        let msg = bincode::serialize(&ToClient::Probe { session, nonce })
+                            .context(SerializeError { message: "Probe" })?;
+                        transport.send(Packet::unreliable(candidate, msg))?;
+                    }
+                    let relay_needed = checks.lock()?.relay_needed(now);
+                    for peer in relay_needed {
+                        info!(target: CLIENT_CONNECTIVITY, "could not reach {} directly, using the relay", peer);
+                        send_control(transport, &control_sequences, peer, Control::UseRelay)?;
+                    }
+                    // the server records the match once both players have asked for the relay,
+                    // so it's only asked for once the players have exchanged Start
+                    let confirmed = match &*status.lock()? {
+                        Status::MatchConfirmed(peer) => vec![*peer],
+                        Status::GroupConfirmed(members) => members.clone(),
+                        _ => Vec::new(),
+                    };
+                    for peer in confirmed {
+                        if checks.lock()?.relay_confirmed(peer) {
+                            request_relay(transport, server_addr, peer)?;
+                        }
+                    }
+                    // the match is reported once the path to the peer is known, unless it's relayed,
+                    // since the server sees the match form when both ask it to relay
+                    let unreported = match *status.lock()? {
+                        Status::MatchConfirmed(peer) if !requests.lock()?.match_reported => Some(peer),
+                        _ => None,
+                    };
+                    if let Some(peer) = unreported {
+                        let (found, relayed) = {
+                            let checks = checks.lock()?;
+                            (checks.path(peer, now).is_some(), checks.is_relayed(peer))
+                        };
+                        if found {
+                            requests.lock()?.match_reported = true;
+                            if relayed {
+                                debug!(target: CLIENT_CONNECTIVITY, "the server saw the match through the relay");
+                            } else {
+                                send_to_server(transport, server_addr, &ToServer::Matched(vec![peer]))?;
+                            }
+                        }
+                    }
+                    let timeout = Duration::from_millis(CHALLENGE_TIMEOUT_MILLIS);
+                    let unresponsive: Vec<_> = {
+                        let unanswered = &mut requests.lock()?.unanswered;
+                        let expired: Vec<_> = unanswered
+                            .iter()
+                            .filter(|(_, &challenged)| now.saturating_duration_since(challenged) >= timeout)
+                            .map(|(&peer, _)| peer)
+                            .collect();
+                        for peer in &expired {
+                            unanswered.remove(peer);
+                        }
+                        expired
+                    };
+                    for peer in unresponsive {
+                        debug!(target: CLIENT_CHALLENGE, "{} left the challenge unanswered", peer);
+                        send_to_server(transport, server_addr, &ToServer::Unresponsive(peer))?;
+                    }
+                    if requests.lock()?.server_link.probe(now) {
+                        let msg = bincode::serialize(&ToServer::StatusCheck)
+                            .context(SerializeError { message: "StatusCheck" })?;
+                        // a lost check counts towards the loss to the server
+                        transport.send(Packet::unreliable(server_addr, msg))?;
+                    }
+                    let resume_interval = Duration::from_millis(RESUME_INTERVAL_MILLIS);
+                    if now.saturating_duration_since(last_resume) >= resume_interval {
+                        last_resume = now;
+                        let queued = matches!(
+                            *status.lock()?,
+                            Status::Queued | Status::MatchPending(_)
+                        );
+                        match requests.lock()?.resume_token {
+                            Some(token) if queued => {
+                                let msg = bincode::serialize(&ToServer::Resume(token))
+                                    .context(SerializeError { message: "Resume" })?;
+                                // a lost resume is replaced by the next one
+                                transport.send(Packet::unreliable(server_addr, msg))?;
+                            }
+                            _ => {}
+                        }
+                    }
+                    #[cfg(feature = "telemetry")]
+                    {
+                        let matched = match *status.lock()? {
+                            Status::MatchConfirmed(peer) => Some(peer),
+                            _ => None,
+                        };
+                        if let Some(telemetry) = &mut requests.lock()?.telemetry {
+                            telemetry.match_status(matched.is_some(), now);
+                            if let Some(peer) = matched {
+                                let checks = checks.lock()?;
+                                if checks.path(peer, now).is_some() {
+                                    telemetry.connected(checks.punched(peer));
+                                }
+                            }
+                            telemetry.upload_if_due(now);
+                        }
+                    }
+                    let mut server_connection = server_connection.lock()?;
+                    if let ServerConnection::Connecting(time_limit) = *server_connection {
+                        if now > time_limit {
+                            warn!("the server did not answer");
+                            *server_connection = ServerConnection::Unreachable;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles a control message that has been checked not to repeat an earlier one.
+    /// Messages that don't apply to the current status or to the challenge with the peer are ignored,
+    /// so e.g. a late Start from a peer whose challenge was declined cannot confirm a match.
+    #[allow(clippy::too_many_arguments)]
+    fn handle_control(
+        addr: SocketAddr,
+        message: Control,
+        transport: &T,
+        server_addr: SocketAddr,
+        control_sequences: &ArMu<ControlSequences>,
+        checks: &ArMu<Checks>,
+        challenges: &ArMu<Challenges>,
+        peers: &ArMu<Peers>,
+        status: &ArMu<Status>,
+        now: Instant,
+    ) -> Result<(), ClientError> {
+        match message {
+            Control::Challenge(metadata) => {
+                debug!(target: CLIENT_CHALLENGE, "received challenge");
+                challenges.lock()?.receive(addr, metadata, now);
+            }
+            Control::Accept => {
+                debug!(target: CLIENT_CHALLENGE, "received accept");
+                let mut status = status.lock()?;
+                let mut challenges = challenges.lock()?;
+                match &*status {
+                    Status::Queued if challenges.handle(addr, ChallengeEvent::ReceivedAccept) => {
+                        send_control(transport, control_sequences, addr, Control::Start(0))?;
+                        *status = Status::MatchPending(addr);
+                        checks.lock()?.start(addr, now);
+                        let candidates = Control::Candidates(candidates(transport, server_addr));
+                        send_control(transport, control_sequences, addr, candidates)?;
+                    }
+                    // a member of the group accepted its challenge
+                    Status::GroupPending { members, .. }
+                        if members.contains(&addr)
+                            && challenges.handle(addr, ChallengeEvent::ReceivedAccept) =>
+                    {
+                        let others = members.iter().copied().filter(|&m| m != addr).collect();
+                        let group_start = Control::GroupStart(others);
+                        send_control(transport, control_sequences, addr, group_start)?;
+                    }
+                    _ => {}
+                }
+            }
+            Control::Decline => {
+                debug!(target: CLIENT_CHALLENGE, "received decline");
+                let mut status = status.lock()?;
+                let declined = challenges
+                    .lock()?
+                    .handle(addr, ChallengeEvent::ReceivedDecline);
+                if let Status::MatchPending(pending) = *status {
+                    if declined && pending == addr {
+                        // got declined by someone we sent Start to
+                        *status = Status::Queued;
+                    }
+                }
+            }
+            Control::Start(_time) => {
+                debug!(target: CLIENT_CHALLENGE, "received start");
+                let mut status = status.lock()?;
+                let mut challenges = challenges.lock()?;
+                let accepted = challenges.state(addr) == ChallengeState::Accepted;
+                match &mut *status {
+                    // they are match pending after we accepted their challenge
+                    Status::Queued if accepted => {
+                        send_control(transport, control_sequences, addr, Control::Start(0))?;
+                        challenges.clear();
+                        *status = Status::MatchConfirmed(addr);
+                    }
+                    // pending match confirmed
+                    Status::MatchPending(pending)
+                        if *pending == addr
+                            && challenges.handle(addr, ChallengeEvent::ReceivedStart) =>
+                    {
+                        challenges.clear();
+                        *status = Status::MatchConfirmed(addr);
+                    }
+                    Status::GroupPending { members, confirmed }
+                        if members.contains(&addr)
+                            && challenges.handle(addr, ChallengeEvent::ReceivedStart) =>
+                    {
+                        confirmed.insert(addr);
+                        if confirmed == members {
+                            // every member has responded
+                            challenges.clear();
+                            let members: Vec<_> = members.iter().cloned().collect();
+                            let matched = ToServer::Matched(members.clone());
+                            *status = Status::GroupConfirmed(members);
+                            send_to_server(transport, server_addr, &matched)?;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Control::Candidates(candidates) => {
+                debug!(target: CLIENT_CONNECTIVITY, "received candidates");
+                if control_sequences.lock()?.controls(addr) {
+                    // only probes peers being checked, so strangers can't aim the probes
+                    checks.lock()?.probe(addr, candidates, random_u64);
+                }
+            }
+            Control::Nominate(nonce) => {
+                debug!(target: CLIENT_CONNECTIVITY, "received nomination");
+                checks.lock()?.nominated(addr, nonce);
+            }
+            Control::UseRelay => {
+                debug!(target: CLIENT_CONNECTIVITY, "received use relay");
+                checks.lock()?.relay_asked(addr);
+            }
+            Control::GroupStart(others) => {
+                debug!(target: CLIENT_CHALLENGE, "received group start");
+                let mut status = status.lock()?;
+                // the other members have to be peers the server proposed as well
+                let known = {
+                    let peers = peers.lock()?;
+                    others.iter().all(|other| peers.map.contains_key(other))
+                };
+                let mut challenges = challenges.lock()?;
+                // only starts a group whose host's challenge was accepted
+                if let Status::Queued = *status {
+                    if !known || !challenges.handle(addr, ChallengeEvent::ReceivedGroupStart) {
+                        debug!(target: CLIENT_CHALLENGE, "ignoring group start from {}", addr);
+                        return Ok(());
+                    }
+                    send_control(transport, control_sequences, addr, Control::Start(0))?;
+                    challenges.clear();
+                    let mut members = others;
+                    members.push(addr);
+                    let matched = ToServer::Matched(members.clone());
+                    *status = Status::GroupConfirmed(members);
+                    send_to_server(transport, server_addr, &matched)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues the client.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn queue(&mut self) -> Result<(), ClientError> {
+        debug!("queueing");
+        let mut status = self.status.lock()?;
+        if let Status::Idle = *status {
+            let port = self.external_port();
+            let preamble = {
+                let mut requests = self.requests.lock()?;
+                requests.queue_status = None;
+                requests.resume_token = None;
+                requests.idle = false;
+                requests.rejected = None;
+                #[cfg(feature = "telemetry")]
+                if let Some(telemetry) = &mut requests.telemetry {
+                    telemetry.queued(self.clock.now());
+                }
+                requests.queue_preamble()
+            };
+            send_queue_request(&*self.transport, self.server_addr, port, preamble)?;
+            let mut server_connection = self.server_connection.lock()?;
+            if let ServerConnection::Disconnected | ServerConnection::Unreachable =
+                *server_connection
+            {
+                let time_limit = self.clock.now() + Duration::from_millis(CONNECT_TIMEOUT_MILLIS);
+                *server_connection = ServerConnection::Connecting(time_limit);
+            }
+            *status = Status::QueuePending(port);
+            self.checks.lock()?.clear();
+        }
+        Ok(())
+    }
+
+    /// Dequeues the client.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn dequeue(&self) -> Result<(), ClientError> {
+        let mut status = self.status.lock()?;
+        if let Status::QueuePending(_) | Status::Queued = *status {
+            let msg = bincode::serialize(&ToServer::Dequeue)
+                .context(SerializeError { message: "Dequeue" })?;
+            self.transport.send(Packet::reliable_ordered(
+                self.server_addr,
+                msg,
+                streams::CONTROL,
+            ))?;
+            *status = Status::Idle;
+            *self.server_connection.lock()? = ServerConnection::Disconnected;
+            let mut requests = self.requests.lock()?;
+            requests.queue_status = None;
+            requests.resume_token = None;
+        }
         Ok(())
     }
 
@@ -380,24 +1923,103 @@ impl Client {
     /// If there is an issue serializing or sending the message, or
     /// if the handler thread has panicked.
     pub fn challenge(&self, peer: &mut Peer) -> Result<(), ClientError> {
-        let msg = bincode::serialize(&ToClient::Challenge).context(SerializeError)?;
-        self.packet_sender
-            .send(Packet::reliable_unordered(peer.addr, msg))?;
+        self.challenge_with(peer, Vec::new())
+    }
+
+    /// Challenges the given peer with metadata for its player to decide by, e.g. the game mode,
+    /// see `IncomingChallenge::metadata`.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn challenge_with(&self, peer: &mut Peer, metadata: Vec<u8>) -> Result<(), ClientError> {
+        self.mark_active()?;
+        if !self
+            .challenges
+            .lock()?
+            .handle(peer.addr, ChallengeEvent::Challenge)
+        {
+            debug!(target: CLIENT_CHALLENGE, "{} already accepted or started a match", peer.addr);
+            return Ok(());
+        }
+        send_control(
+            &*self.transport,
+            &self.control_sequences,
+            peer.addr,
+            Control::Challenge(metadata),
+        )?;
         peer.status = PeerStatus::OutgoingChallenge;
-        self.outgoing_challenges.lock()?.insert(peer.addr);
+        let mut requests = self.requests.lock()?;
+        requests.unanswered.insert(peer.addr, self.clock.now());
+        #[cfg(feature = "telemetry")]
+        if let Some(telemetry) = &mut requests.telemetry {
+            telemetry.challenged();
+        }
         Ok(())
     }
 
+    // tells the server that the client is back if it marked the client idle
+    fn mark_active(&self) -> Result<(), ClientError> {
+        let mut requests = self.requests.lock()?;
+        if requests.idle {
+            requests.idle = false;
+            send_to_server(&*self.transport, self.server_addr, &ToServer::Active)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a peer whose address is already known, bypassing the server.
+    /// The peer is pinged and can be challenged like the peers sent by the server.
+    /// An idle client starts accepting challenges as if it was queued, without contacting the server,
+    /// so both players need to add each other.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn add_peer(&self, addr: SocketAddr) -> Result<Peer, ClientError> {
+        debug!(target: CLIENT_CONNECTIVITY, "connecting directly to {}", addr);
+        // the handler locks the peers before the status
+        connect_directly(&self.status, &self.checks)?;
+        let mut peers = self.peers.lock()?;
+        let peer = peers
+            .changed()
+            .entry(addr)
+            .or_insert_with(|| Peer::new(addr));
+        Ok(peer.clone())
+    }
+
+    /// Adds the peer with `add_peer` and challenges it.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn direct_challenge(&self, addr: SocketAddr) -> Result<(), ClientError> {
+        let mut peer = self.add_peer(addr)?;
+        self.challenge(&mut peer)
+    }
+
     // TODO: change parameter to challenge struct?
     /// Accepts the challenge from the given peer.
     /// # Errors
     /// If there is an issue serializing or sending the message, or
     /// if the handler thread has panicked.
     pub fn accept(&self, peer: &mut Peer) -> Result<(), ClientError> {
-        if self.incoming_challenges.lock()?.contains(&peer.addr) {
-            let msg = bincode::serialize(&ToClient::Accept).context(SerializeError)?;
-            self.packet_sender
-                .send(Packet::reliable_unordered(peer.addr, msg))?;
+        self.mark_active()?;
+        if self
+            .challenges
+            .lock()?
+            .handle(peer.addr, ChallengeEvent::Accept)
+        {
+            send_control(
+                &*self.transport,
+                &self.control_sequences,
+                peer.addr,
+                Control::Accept,
+            )?;
+            self.checks.lock()?.start(peer.addr, self.clock.now());
+            let candidates = Control::Candidates(candidates(&*self.transport, self.server_addr));
+            send_control(
+                &*self.transport,
+                &self.control_sequences,
+                peer.addr,
+                candidates,
+            )?;
         }
         Ok(())
     }
@@ -407,144 +2029,2543 @@ impl Client {
     /// If there is an issue serializing or sending the message, or
     /// if the handler thread has panicked.
     pub fn decline(&self, addr: SocketAddr) -> Result<(), ClientError> {
-        if self.incoming_challenges.lock()?.remove(&addr) {
-            let msg = bincode::serialize(&ToClient::Decline).context(SerializeError)?;
-            self.packet_sender
-                .send(Packet::reliable_unordered(addr, msg))?;
+        self.mark_active()?;
+        if self
+            .challenges
+            .lock()?
+            .handle(addr, ChallengeEvent::Decline)
+        {
+            send_control(
+                &*self.transport,
+                &self.control_sequences,
+                addr,
+                Control::Decline,
+            )?;
         }
         Ok(())
     }
 
-    /// Closes the client and returns the underlying receiver and sender.
+    /// Starts a match with all of the given peers.
+    /// Each peer is challenged and sent the other members of the group once it accepts,
+    /// and the match is confirmed once all of them have responded. Does nothing if the client is not queued.
     /// # Errors
-    /// If the handler thread has panicked.
-    pub fn close(self) -> Result<(Receiver<SocketEvent>, Sender<Packet>), ClientError> {
-        self.message_sender.send(Message::Quit)?;
-        self.handle.join()?
+    /// If no peers are given, if there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn start_group(&self, peers: &[SocketAddr]) -> Result<(), ClientError> {
+        if peers.is_empty() {
+            return Err(ClientError::EmptyGroup);
+        }
+        let mut status = self.status.lock()?;
+        if let Status::Queued = *status {
+            let mut challenges = self.challenges.lock()?;
+            for &peer in peers {
+                if challenges.handle(peer, ChallengeEvent::Challenge) {
+                    let message = Control::Challenge(Vec::new());
+                    send_control(&*self.transport, &self.control_sequences, peer, message)?;
+                }
+            }
+            *status = Status::GroupPending {
+                members: peers.iter().cloned().collect(),
+                confirmed: HashSet::new(),
+            };
+        }
+        Ok(())
     }
 
-    /// Returns the potential opponents.
+    /// Lists the room hosted by the client on the server, replacing its earlier listing,
+    /// e.g. to update the player count. The server only lists the rooms of queued clients.
     /// # Errors
-    /// If the handler thread has panicked.
-    pub fn peers(&self) -> Result<HashSet<Peer>, ClientError> {
-        Ok(self.peers.lock()?.values().cloned().collect())
+    /// If there is an issue serializing or sending the message.
+    pub fn open_room(&self, room: Room) -> Result<(), ClientError> {
+        send_to_server(
+            &*self.transport,
+            self.server_addr,
+            &ToServer::OpenRoom(room),
+        )
     }
 
-    /// Returns the incoming challenges.
+    /// Removes the room hosted by the client from the listing.
     /// # Errors
-    /// If the handler thread has panicked.
-    pub fn incoming_challenges(&self) -> Result<HashSet<SocketAddr>, ClientError> {
-        Ok(self.incoming_challenges.lock()?.clone())
+    /// If there is an issue serializing or sending the message.
+    pub fn close_room(&self) -> Result<(), ClientError> {
+        send_to_server(&*self.transport, self.server_addr, &ToServer::CloseRoom)
     }
 
-    /// Returns the outgoing challenges.
+    /// Requests the open rooms from the server, see `rooms`.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn request_rooms(&self) -> Result<(), ClientError> {
+        self.send_verified(ToServer::ListRooms)
+    }
+
+    /// Returns the rooms received after the last `request_rooms`.
+    /// The hosts are pinged to measure their latency as long as they are listed.
     /// # Errors
     /// If the handler thread has panicked.
-    pub fn outgoing_challenges(&self) -> Result<HashSet<SocketAddr>, ClientError> {
-        Ok(self.outgoing_challenges.lock()?.clone())
+    pub fn rooms(&self) -> Result<Vec<RoomListing>, ClientError> {
+        Ok(self.requests.lock()?.rooms.clone())
     }
 
-    /// Checks the match status.
+    /// Returns the rooms with free places the server last offered while the client was queued.
+    /// The hosts are pinged to measure their latency as long as they are offered.
     /// # Errors
     /// If the handler thread has panicked.
-    pub fn check_match(&self) -> Result<Option<SocketAddr>, ClientError> {
-        if let Status::MatchConfirmed(peer) = *self.status.lock()? {
-            Ok(Some(peer))
-        } else {
-            Ok(None)
-        }
+    pub fn backfill_offers(&self) -> Result<Vec<RoomListing>, ClientError> {
+        Ok(self.requests.lock()?.backfill.clone())
     }
-}
 
-#[derive(Debug, Snafu)]
-pub enum CreateError {
-    BindError { source: laminar::ErrorKind },
-}
+    /// Asks the server to join the room of the host, see `room_events` for the result.
+    /// If the client is let in, it leaves the queue.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn join_room(&self, host: SocketAddr) -> Result<(), ClientError> {
+        self.send_verified(ToServer::JoinRoom(host))
+    }
 
-#[derive(Debug, Snafu)]
-pub enum ClientError {
-    MutexError,
-    SenderError,
-    SerializeError { source: Box<bincode::ErrorKind> },
-    ThreadError,
-}
+    /// Returns what happened to the rooms the client joined or hosts since the last call.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn room_events(&self) -> Result<Vec<RoomEvent>, ClientError> {
+        Ok(std::mem::take(&mut self.requests.lock()?.room_events))
+    }
 
-impl<T> From<PoisonError<T>> for ClientError {
-    fn from(_: PoisonError<T>) -> Self {
-        ClientError::MutexError
+    /// Returns the challenges cancelled since the last call because their peers left the queue.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn cancelled_challenges(&self) -> Result<Vec<CancelledChallenge>, ClientError> {
+        Ok(std::mem::take(&mut self.requests.lock()?.cancelled))
     }
-}
 
-impl<T> From<SendError<T>> for ClientError {
-    fn from(_: SendError<T>) -> Self {
-        ClientError::SenderError
+    /// Sets how many challenges a peer may send before the rest are declined, and whether and for how long
+    /// a peer that keeps sending them is blocked, see `ChallengeLimit`.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn set_challenge_limit(&self, limit: ChallengeLimit) -> Result<(), ClientError> {
+        self.challenges.lock()?.set_limit(limit);
+        Ok(())
     }
-}
 
-impl From<Box<dyn std::any::Any + Send>> for ClientError {
-    fn from(_: Box<dyn std::any::Any + Send>) -> Self {
-        ClientError::ThreadError
+    /// Returns the peers blocked since the last call for flooding the client with challenges,
+    /// with when their block ends, so the game can tell the player.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn challenge_blocks(&self) -> Result<Vec<(SocketAddr, Instant)>, ClientError> {
+        Ok(std::mem::take(&mut self.requests.lock()?.challenge_blocks))
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::mem::discriminant;
+    /// Returns the peers whose address was taken over by another player since the last call,
+    /// as told by their handles. What was known about the previous player, e.g. its latency
+    /// and whether it was blocked, is forgotten, and a challenge with it is cancelled.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn replaced_peers(&self) -> Result<Vec<PeerReplaced>, ClientError> {
+        Ok(std::mem::take(&mut self.requests.lock()?.replaced))
+    }
 
-    fn init() {
-        let _ = env_logger::builder().is_test(true).try_init();
+    /// Uploads a replay written by the game client to the server, see `replay_responses`.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn upload_replay(&self, replay: Vec<u8>) -> Result<(), ClientError> {
+        self.send_verified(ToServer::UploadReplay(replay))
     }
 
-    #[test]
-    fn sample_test() {
-        init();
+    /// Fetches the replay with the ID the server stored it as, see `replay_responses`.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn fetch_replay(&self, id: u64) -> Result<(), ClientError> {
+        self.send_verified(ToServer::FetchReplay(id))
+    }
 
-        let ip1 = "127.0.0.1".parse().unwrap();
-        let ip2 = "127.0.0.2".parse().unwrap();
-        let addr1 = SocketAddr::new(ip1, CLIENT_PORT);
-        let addr2 = SocketAddr::new(ip2, CLIENT_PORT);
-        let mut client1 = Client::new(ip1, ip1).unwrap();
-        let mut client2 = Client::new(ip2, ip1).unwrap();
+    /// Returns the server's answers to replay uploads and fetches since the last call.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn replay_responses(&self) -> Result<Vec<ReplayResponse>, ClientError> {
+        Ok(std::mem::take(&mut self.requests.lock()?.replays))
+    }
 
-        let mut server = Socket::bind((ip1, SERVER_PORT)).unwrap();
+    /// Reports how the match went once it has ended, so the server can keep it in the match history
+    /// of the player ID set with `identify`. The round trip and rollbacks let the server score the
+    /// connection, and avoid pairing networks whose matches went poorly.
+    /// # Errors
+    /// If there is an issue serializing or sending the message.
+    pub fn report_match(&self, report: MatchReport) -> Result<(), ClientError> {
+        send_to_server(
+            &*self.transport,
+            self.server_addr,
+            &ToServer::MatchEnded(report),
+        )
+    }
 
-        client1.queue().unwrap();
-        client2.queue().unwrap();
+    /// Fetches the recent matches of the player with the given ID, see `match_histories`.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn fetch_history(&self, player: String) -> Result<(), ClientError> {
+        self.send_verified(ToServer::FetchHistory(player))
+    }
 
-        thread::sleep(Duration::from_millis(100));
-        server.manual_poll(Instant::now());
-        while let Some(event) = server.recv() {
-            if let SocketEvent::Packet(packet) = event {
-                if packet.addr() == addr1 {
-                    let mut peers = HashSet::new();
-                    peers.insert(addr2);
-                    let payload = bincode::serialize(&FromServer::Peers(peers)).unwrap();
-                    let response = Packet::reliable_unordered(packet.addr(), payload);
-                    server.send(response).unwrap();
-                    server.manual_poll(Instant::now());
-                } else {
-                    let mut peers = HashSet::new();
-                    peers.insert(addr1);
-                    let payload = bincode::serialize(&FromServer::Peers(peers)).unwrap();
-                    let response = Packet::reliable_unordered(packet.addr(), payload);
-                    server.send(response).unwrap();
-                    server.manual_poll(Instant::now());
-                }
-            }
+    /// Returns the match histories received since the last call, with the latest matches first.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn match_histories(&self) -> Result<Vec<(String, Vec<MatchRecord>)>, ClientError> {
+        Ok(std::mem::take(&mut self.requests.lock()?.histories))
+    }
+
+    /// Queries a page of the leaderboard of the players rated by the server, see `leaderboards`.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn query_leaderboard(&self, query: LeaderboardQuery) -> Result<(), ClientError> {
+        self.send_verified(ToServer::Leaderboard(query))
+    }
+
+    /// Returns the leaderboard pages received since the last call, in the order they were queried.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn leaderboards(&self) -> Result<Vec<Vec<Standing>>, ClientError> {
+        Ok(std::mem::take(&mut self.requests.lock()?.leaderboards))
+    }
+
+    // sends a request that the server only answers once the client has echoed a cookie,
+    // and remembers it so it's repeated after the cookie
+    fn send_verified(&self, request: ToServer) -> Result<(), ClientError> {
+        send_to_server(&*self.transport, self.server_addr, &request)?;
+        self.requests.lock()?.unverified.push(request);
+        Ok(())
+    }
+
+    /// Sets the player ID sent to the server before queueing, used to schedule reserved matches.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn identify(&self, id: String) -> Result<(), ClientError> {
+        self.requests.lock()?.identity = Some(id);
+        Ok(())
+    }
+
+    /// The resume token of the client's queue entry, if it's queued. A game can keep it so that after a crash,
+    /// the restarted client can take over from the stale session with `supersede`.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn resume_token(&self) -> Result<Option<u64>, ClientError> {
+        Ok(self.requests.lock()?.resume_token)
+    }
+
+    /// Takes over from a previous session of the client, e.g. one that crashed, with the resume token
+    /// it was given. The server dequeues the stale session when the client next queues, so the client
+    /// can queue with its player ID, which the server otherwise leaves to the stale session until it
+    /// stops hearing from it.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn supersede(&self, token: u64) -> Result<(), ClientError> {
+        self.requests.lock()?.superseded = Some(token);
+        Ok(())
+    }
+
+    /// Keeps what's learned about the identified peers in the store, so that a peer met again,
+    /// e.g. after requeueing, starts out with the latency measured last time.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn keep_peers(&self, store: PeerStore) -> Result<(), ClientError> {
+        self.requests.lock()?.known = Some(store);
+        Ok(())
+    }
+
+    /// Blocks or unblocks the player with the given handle, see `Peer::handle`. Blocked players are left out of
+    /// `candidates` and their challenges are declined. Without a store given to `keep_peers`,
+    /// the block is kept in memory.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn block(&self, handle: &str, blocked: bool) -> Result<(), ClientError> {
+        {
+            let mut peers = self.peers.lock()?;
+            for peer in peers.changed().values_mut() {
+                if peer.handle() == Some(handle) {
+                    peer.blocked = blocked;
+                }
+            }
+        }
+        let mut requests = self.requests.lock()?;
+        requests
+            .known
+            .get_or_insert_with(PeerStore::new)
+            .entry(handle)
+            .blocked = blocked;
+        Ok(())
+    }
+
+    /// What's known about the player with the given handle from earlier queue sessions.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn known_peer(&self, handle: &str) -> Result<Option<KnownPeer>, ClientError> {
+        let requests = self.requests.lock()?;
+        Ok(requests
+            .known
+            .as_ref()
+            .and_then(|known| known.get(handle))
+            .cloned())
+    }
+
+    /// Writes the known peers to the file their store was opened from.
+    /// # Errors
+    /// If the file can't be written or the handler thread has panicked.
+    pub fn save_peers(&self) -> Result<(), ClientError> {
+        if let Some(known) = &self.requests.lock()?.known {
+            known.save().context(PeerStoreError)?;
+        }
+        Ok(())
+    }
+
+    /// Starts uploading anonymized matchmaking metrics to the URL, see `telemetry`.
+    /// Telemetry is off until this is called, so the game can ask the player first.
+    /// # Errors
+    /// If the URL isn't an `http://` URL or the handler thread has panicked.
+    #[cfg(feature = "telemetry")]
+    pub fn enable_telemetry(&self, url: &str) -> Result<(), ClientError> {
+        let endpoint =
+            mirai_core::http::Endpoint::parse(url).ok_or_else(|| ClientError::UnsupportedUrl {
+                url: url.to_string(),
+            })?;
+        let telemetry = telemetry::Telemetry::new(endpoint, self.clock.now());
+        self.requests.lock()?.telemetry = Some(telemetry);
+        Ok(())
+    }
+
+    /// Stops uploading telemetry, discarding the metrics not uploaded yet.
+    /// # Errors
+    /// If the handler thread has panicked.
+    #[cfg(feature = "telemetry")]
+    pub fn disable_telemetry(&self) -> Result<(), ClientError> {
+        self.requests.lock()?.telemetry = None;
+        Ok(())
+    }
+
+    /// Sets the rating and region sent to the server before queueing. If the server matches
+    /// players by them, the client is proposed more distant opponents the longer it waits.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn set_profile(&self, profile: Profile) -> Result<(), ClientError> {
+        self.requests.lock()?.profile = Some(profile);
+        Ok(())
+    }
+
+    /// Queues the client with the clients that use the same party token, from the next `queue` on.
+    /// The server doesn't propose party members to each other, and the peers' parties
+    /// are listed with `Peer::party`. The token should be shared only with the other members.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn join_party(&self, token: u64) -> Result<(), ClientError> {
+        self.requests.lock()?.party = Some(token);
+        Ok(())
+    }
+
+    /// Queues the client alone again.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn leave_party(&self) -> Result<(), ClientError> {
+        let mut requests = self.requests.lock()?;
+        if requests.party.take().is_some() {
+            requests.teammates.clear();
+            send_to_server(&*self.transport, self.server_addr, &ToServer::LeaveParty)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the queued members of the client's party besides the client.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn party(&self) -> Result<Vec<SocketAddr>, ClientError> {
+        Ok(self.requests.lock()?.teammates.clone())
+    }
+
+    /// Shares the client's presence with the friends with the given player IDs and follows theirs.
+    /// Only the friends that list the client as well share their presence with it.
+    /// The client has to `identify` first.
+    /// # Errors
+    /// If there is an issue serializing or sending the messages, or
+    /// if the handler thread has panicked.
+    pub fn set_friends(&self, friends: Vec<String>) -> Result<(), ClientError> {
+        let identity = {
+            let mut requests = self.requests.lock()?;
+            requests.friends = friends
+                .iter()
+                .map(|friend| (friend.clone(), Presence::Offline))
+                .collect();
+            requests.identity.clone()
+        };
+        if let Some(id) = identity {
+            self.send_verified(ToServer::Identify(id))?;
+        }
+        self.send_verified(ToServer::Friends(friends))
+    }
+
+    /// Returns the client's friends with their presence, offline until the server says otherwise.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn friends(&self) -> Result<Vec<(String, Presence)>, ClientError> {
+        Ok(self.requests.lock()?.friends.clone())
+    }
+
+    /// Asks the server for the address of the online friend and challenges it, queued or not.
+    /// The friend is added to the peers, and the challenge goes on like any other.
+    /// Returns false without asking if the friend isn't online.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn challenge_friend(&self, friend: &str) -> Result<bool, ClientError> {
+        let online = self
+            .requests
+            .lock()?
+            .friends
+            .iter()
+            .any(|(f, presence)| f == friend && *presence != Presence::Offline);
+        if online {
+            self.mark_active()?;
+            let msg = ToServer::ChallengeFriend(friend.to_string());
+            send_to_server(&*self.transport, self.server_addr, &msg)?;
+        }
+        Ok(online)
+    }
+
+    /// Asks the server for a code that invites another client to challenge this one, e.g. one shared
+    /// with a streamer's viewers, replacing the client's earlier code. The code is returned by `invite`
+    /// once the server sends it, and can be redeemed once before it expires.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn create_invite(&self) -> Result<(), ClientError> {
+        self.requests.lock()?.invite = None;
+        self.send_verified(ToServer::CreateInvite)
+    }
+
+    /// Returns the code created with `create_invite`, or None if the server hasn't sent it yet
+    /// or it has been redeemed. The client that redeems it is added to the peers and challenges
+    /// this one, queued or not.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn invite(&self) -> Result<Option<String>, ClientError> {
+        Ok(self.requests.lock()?.invite.clone())
+    }
+
+    /// Redeems the invite code of another client, which is added to the peers and challenged,
+    /// queued or not, like with `direct_challenge`. Codes the server rejects are returned by
+    /// `invalid_invites`.
+    /// # Errors
+    /// If there is an issue serializing or sending the message, or
+    /// if the handler thread has panicked.
+    pub fn redeem_invite(&self, code: &str) -> Result<(), ClientError> {
+        self.mark_active()?;
+        self.send_verified(ToServer::RedeemInvite(code.to_string()))
+    }
+
+    /// Returns the invite codes the server rejected since the last call, because they were unknown,
+    /// expired or already redeemed.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn invalid_invites(&self) -> Result<Vec<String>, ClientError> {
+        Ok(std::mem::take(&mut self.requests.lock()?.invalid_invites))
+    }
+
+    /// Returns the opponents of the reserved matches that have started since the last call.
+    /// They are added to the peers, so they can be challenged like any other.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn scheduled_matches(&self) -> Result<Vec<SocketAddr>, ClientError> {
+        Ok(std::mem::take(&mut self.requests.lock()?.scheduled))
+    }
+
+    /// Returns the client's place in the queue and how much longer it's expected to wait,
+    /// as last sent by the server, or None if the server hasn't sent it since the client queued.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn queue_status(&self) -> Result<Option<QueueStatus>, ClientError> {
+        Ok(self.requests.lock()?.queue_status)
+    }
+
+    /// Returns the queue statuses received since the last call.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn queue_status_updates(&self) -> Result<Vec<QueueStatus>, ClientError> {
+        Ok(std::mem::take(&mut self.requests.lock()?.queue_statuses))
+    }
+
+    /// Returns the address the server receives the client's packets from, i.e. the client's address
+    /// outside its NAT, or None if the server hasn't sent it yet. The server sends it once it has
+    /// verified the client, e.g. on the first queue request. If the port differs from the client's
+    /// local port, the NAT maps the port, and peers outside the NAT may not reach the client directly.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn public_addr(&self) -> Result<Option<SocketAddr>, ClientError> {
+        Ok(self.requests.lock()?.public_addr)
+    }
+
+    /// Whether the server has marked the client idle because it left its peers' challenges unanswered.
+    /// An idle client isn't proposed to the rest of the queue until it challenges, accepts or declines
+    /// someone, unless the server dequeued it, in which case it has to queue again.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn is_idle(&self) -> Result<bool, ClientError> {
+        Ok(self.requests.lock()?.idle)
+    }
+
+    /// How well the client's packets are getting out, as last reported by the transport.
+    /// While it isn't healthy, the server and the peers receive the client's messages late if at all,
+    /// which the game can tell the player about rather than leave them waiting.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn send_health(&self) -> Result<SendHealth, ClientError> {
+        let health = self.requests.lock()?.send_health;
+        Ok(health.unwrap_or(SendHealth::Healthy))
+    }
+
+    /// The round trip time to the matchmaking server, measured with a status check every
+    /// `SERVER_PROBE_INTERVAL_MILLIS`, once the server has answered one, so a launcher can warn
+    /// the player when the route to matchmaking is already bad, see `server_link`.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn server_latency(&self) -> Result<Option<Duration>, ClientError> {
+        Ok(self.requests.lock()?.server_link.latency())
+    }
+
+    /// The share of the latest status checks the matchmaking server didn't answer, from 0 to 1,
+    /// once one has been answered or lost.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn server_loss(&self) -> Result<Option<f64>, ClientError> {
+        Ok(self.requests.lock()?.server_link.loss())
+    }
+
+    /// Returns the regions the server last offered since the last call, closest first.
+    /// The server offers them once per queueing, if the client has waited long without anyone
+    /// in the queue matching it. The client can then dequeue and queue on one of the other servers,
+    /// expecting its round trip to the players there to be around `RegionSummary::server_rtt` longer.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn other_regions(&self) -> Result<Vec<RegionSummary>, ClientError> {
+        Ok(std::mem::take(&mut self.requests.lock()?.other_regions))
+    }
+
+    /// The port the router forwards to the client, if it was mapped.
+    pub fn external_port(&self) -> Option<u16> {
+        #[cfg(feature = "port-mapping")]
+        return self.port_mapping.as_ref().map(PortMapping::external_port);
+        #[cfg(not(feature = "port-mapping"))]
+        None
+    }
+
+    /// Closes the client and returns the underlying transport.
+    /// The port mapping is removed, if there is one, and the last telemetry report is uploaded.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn close(self) -> Result<T, ClientError> {
+        #[cfg(feature = "port-mapping")]
+        if let Some(mapping) = self.port_mapping {
+            if let Err(e) = mapping.remove() {
+                warn!("could not remove the port mapping: {}", e);
+            }
+        }
+        self.message_sender.send(Message::Quit)?;
+        self.handle.join()??;
+        #[cfg(feature = "telemetry")]
+        if let Some(telemetry) = self.requests.lock()?.telemetry.take() {
+            telemetry.finish();
+        }
+        // the handler's reference was dropped when the thread finished
+        Arc::try_unwrap(self.transport).map_err(|_| ClientError::HandlerStopped)
+    }
+
+    /// Closes the client once the match has been confirmed and hands its transport over for the match,
+    /// wrapped in a `RelayTransport` if the match is relayed and scoped to the matched peers,
+    /// see `mirai_core::transport::peer`.
+    /// # Errors
+    /// If no match has been confirmed yet, see `Client::check_group_match`, or
+    /// if the handler thread has panicked.
+    pub fn into_peer_connection(self) -> Result<PeerConnection<Box<dyn Transport>>, ClientError> {
+        let peers = self.check_group_match()?.ok_or(ClientError::NotMatched)?;
+        let relayed = self.is_relayed()?;
+        let server_addr = self.server_addr;
+        // the packets still in flight from the server and the unmatched peers are dropped
+        let mut others: HashSet<_> = self.peers.lock()?.map.keys().copied().collect();
+        for peer in &peers {
+            others.remove(peer);
+        }
+        others.insert(server_addr);
+        let mut transport: Box<dyn Transport> = Box::new(self.close()?);
+        if relayed {
+            let relayed = peers.iter().copied().collect();
+            transport = Box::new(RelayTransport::new(transport, server_addr, relayed));
+        }
+        Ok(PeerConnection::new(transport, peers, others))
+    }
+
+    /// Returns the potential opponents.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn peers(&self) -> Result<HashSet<Peer>, ClientError> {
+        Ok(self.peers.lock()?.map.values().cloned().collect())
+    }
+
+    /// Returns the potential opponents in the order they should be considered in: those with the highest
+    /// priority first, then those that have waited the longest, then the rest.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn candidates(&self) -> Result<Vec<Peer>, ClientError> {
+        let mut candidates: Vec<_> = self
+            .peers
+            .lock()?
+            .map
+            .values()
+            .filter(|peer| !peer.blocked)
+            .cloned()
+            .collect();
+        candidates.sort_by_key(|peer| {
+            (
+                Reverse(peer.priority),
+                peer.queued_since.is_none(),
+                peer.queued_since,
+            )
+        });
+        Ok(candidates)
+    }
+
+    /// Returns the potential opponents and their generation if they have changed since the given generation,
+    /// so that a caller polling every frame only copies them when needed. The generation starts at 0.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn peers_if_changed(
+        &self,
+        generation: u64,
+    ) -> Result<Option<(u64, HashSet<Peer>)>, ClientError> {
+        let peers = self.peers.lock()?;
+        if peers.generation == generation {
+            Ok(None)
+        } else {
+            let map = peers.map.values().cloned().collect();
+            Ok(Some((peers.generation, map)))
+        }
+    }
+
+    /// Returns the incoming challenges, the oldest first, with what's known about the challengers.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn incoming_challenges(&self) -> Result<Vec<IncomingChallenge>, ClientError> {
+        let timeout = Duration::from_millis(CHALLENGE_TIMEOUT_MILLIS);
+        // the handler locks the peers before the challenges
+        let peers = self.peers.lock()?;
+        let mut incoming: Vec<_> = self
+            .challenges
+            .lock()?
+            .received()
+            .map(|(addr, received_at, metadata)| {
+                let peer = peers.map.get(&addr);
+                IncomingChallenge {
+                    peer: addr,
+                    handle: peer.and_then(|peer| peer.handle.clone()),
+                    metadata: metadata.to_vec(),
+                    latency: peer.and_then(Peer::latency),
+                    received_at,
+                    expires_at: received_at + timeout,
+                }
+            })
+            .collect();
+        incoming.sort_by_key(|challenge| challenge.received_at);
+        Ok(incoming)
+    }
+
+    /// Returns the outgoing challenges.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn outgoing_challenges(&self) -> Result<HashSet<SocketAddr>, ClientError> {
+        Ok(self.challenges.lock()?.outgoing())
+    }
+
+    /// Checks the match status.
+    /// Returns the address to reach the opponent at once the match has been confirmed
+    /// and the connectivity checks have finished.
+    /// # Errors
+    /// If the server didn't answer or rejected the queue request, in which case the client stops queueing, or
+    /// if the handler thread has panicked.
+    pub fn check_match(&self) -> Result<Option<SocketAddr>, ClientError> {
+        let mut status = self.status.lock()?;
+        match *status {
+            Status::MatchConfirmed(peer) => Ok(self.checks.lock()?.path(peer, self.clock.now())),
+            Status::QueuePending(_) => {
+                if let Some(reason) = self.requests.lock()?.rejected.take() {
+                    *status = Status::Idle;
+                    *self.server_connection.lock()? = ServerConnection::Disconnected;
+                    return Err(ClientError::QueueRejected { reason });
+                }
+                let mut server_connection = self.server_connection.lock()?;
+                if let ServerConnection::Unreachable = *server_connection {
+                    *status = Status::Idle;
+                    *server_connection = ServerConnection::Disconnected;
+                    return Err(ClientError::ServerUnreachable {
+                        addr: self.server_addr,
+                    });
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether the confirmed match goes through the server's relay because the peers couldn't
+    /// reach each other directly, in which case the game's transport has to be wrapped
+    /// in a `RelayTransport` to the server, as `Client::into_peer_connection` does.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn is_relayed(&self) -> Result<bool, ClientError> {
+        if let Status::MatchConfirmed(peer) = *self.status.lock()? {
+            Ok(self.checks.lock()?.is_relayed(peer))
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// The address of the matchmaking server, which also relays packets.
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    /// Checks the match status, including matches with more than two players.
+    /// Returns every other member of the match once it has been confirmed.
+    /// # Errors
+    /// If the handler thread has panicked.
+    pub fn check_group_match(&self) -> Result<Option<Vec<SocketAddr>>, ClientError> {
+        match &*self.status.lock()? {
+            Status::MatchConfirmed(peer) => Ok(self
+                .checks
+                .lock()?
+                .path(*peer, self.clock.now())
+                .map(|p| vec![p])),
+            Status::GroupConfirmed(members) => Ok(Some(members.clone())),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum CreateError {
+    BindError { source: laminar::ErrorKind },
+    InvalidImpairment { source: ImpairmentError },
+    InvalidServerKey,
+}
+
+/// What went wrong in a call to the client or in its handler thread.
+#[derive(Debug, Snafu)]
+#[non_exhaustive]
+pub enum ClientError {
+    /// The handler thread has panicked or stopped, so the client has to be created again.
+    #[snafu(display("the client's handler thread has stopped"))]
+    HandlerStopped,
+    #[snafu(display("could not serialize {}: {}", message, source))]
+    SerializeError {
+        message: &'static str,
+        source: Box<bincode::ErrorKind>,
+    },
+    /// The transport couldn't send a packet, e.g. because it was closed or the packet was too large.
+    #[snafu(display("could not send a packet: {}", source))]
+    SendPacketError { source: TransportError },
+    /// The server didn't answer the queue request in time. The client is no longer queueing
+    /// and can queue again, e.g. after checking the server with `probe_servers`.
+    #[snafu(display("the server at {} did not answer", addr))]
+    ServerUnreachable { addr: SocketAddr },
+    /// The server didn't queue the client, e.g. because it's draining before a deployment.
+    /// The client is no longer queueing and can queue again, e.g. on another server.
+    #[snafu(display("the server rejected the queue request: {:?}", reason))]
+    QueueRejected { reason: RejectReason },
+    /// The transport was asked for before a match was confirmed.
+    #[snafu(display("no match has been confirmed"))]
+    NotMatched,
+    /// A group match was started without any peers, so no one could confirm it.
+    #[snafu(display("a group needs at least one peer"))]
+    EmptyGroup,
+    #[snafu(display("could not save the known peers: {}", source))]
+    PeerStoreError { source: std::io::Error },
+    /// Only `http://` URLs are supported.
+    #[snafu(display("unsupported URL {}", url))]
+    UnsupportedUrl { url: String },
+}
+
+impl ClientError {
+    /// Whether the client can no longer be used and has to be created again,
+    /// as opposed to the call being worth retrying.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, ClientError::HandlerStopped)
+    }
+}
+
+// a poisoned lock means a thread panicked while holding it
+impl<T> From<PoisonError<T>> for ClientError {
+    fn from(_: PoisonError<T>) -> Self {
+        ClientError::HandlerStopped
+    }
+}
+
+// the handler's end of the channel is only dropped once it has stopped
+impl<T> From<SendError<T>> for ClientError {
+    fn from(_: SendError<T>) -> Self {
+        ClientError::HandlerStopped
+    }
+}
+
+impl From<TransportError> for ClientError {
+    fn from(source: TransportError) -> Self {
+        ClientError::SendPacketError { source }
+    }
+}
+
+impl From<Box<dyn std::any::Any + Send>> for ClientError {
+    fn from(_: Box<dyn std::any::Any + Send>) -> Self {
+        ClientError::HandlerStopped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mirai_core::clock::ManualClock;
+    use mirai_core::transport::{ChannelTransport, MockNetwork, MockTransport};
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn degraded_connections_are_reported() {
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, _packet_receiver) = unbounded();
+        let transport = ChannelTransport::new(event_receiver, packet_sender);
+        let client = Client::with_transport("127.0.0.1".parse().unwrap(), transport);
+        assert_eq!(client.send_health().unwrap(), SendHealth::Healthy);
+        for health in [SendHealth::Backlogged { queued: 10 }, SendHealth::Stopped] {
+            event_sender.send(TransportEvent::Health(health)).unwrap();
+            let start = Instant::now();
+            while client.send_health().unwrap() != health {
+                assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+                thread::yield_now();
+            }
+        }
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn probed_servers_are_sorted_by_round_trip() {
+        let servers: Vec<SocketAddr> = ["127.0.0.1:1", "127.0.0.2:1", "127.0.0.3:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let (event_sender, event_receiver) = unbounded();
+        let (packet_sender, packet_receiver) = unbounded::<Packet>();
+        let transport = ChannelTransport::new(event_receiver, packet_sender);
+        // the first server is far away, the second is close and the third is down
+        let far = Duration::from_millis(60);
+        let (first, second) = (servers[0], servers[1]);
+        let responder = thread::spawn(move || {
+            let checked: Vec<_> = packet_receiver.iter().take(3).collect();
+            let start = Instant::now();
+            let alive = bincode::serialize(&FromServer::Alive).unwrap();
+            for (server, delay) in [(second, Duration::from_millis(5)), (first, far)] {
+                assert!(checked.iter().any(|packet| packet.addr() == server));
+                thread::sleep(delay.saturating_sub(start.elapsed()));
+                let packet = Packet::unreliable(server, alive.clone());
+                event_sender.send(TransportEvent::Packet(packet)).unwrap();
+            }
+        });
+
+        let probes = probe_servers(&transport, &servers, Duration::from_millis(500));
+        responder.join().unwrap();
+        let probed: Vec<_> = probes.iter().map(|&(server, _)| server).collect();
+        assert_eq!(probed, vec![servers[1], servers[0]]);
+        assert!(probes[1].1 >= far);
+    }
+
+    #[test]
+    fn falls_back_to_tcp() {
+        init();
+
+        let ip = "127.0.0.3".parse().unwrap();
+        let server = TcpTransport::listen(SocketAddr::new(ip, SERVER_PORT)).unwrap();
+        let mut client = Client::with_fallback(ip, ip).unwrap();
+        client.queue().unwrap();
+
+        loop {
+            let event = server
+                .events()
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap();
+            if let TransportEvent::Packet(packet) = event {
+                let msg = bincode::deserialize::<ToServer>(packet.payload()).unwrap();
+                assert_eq!(msg, ToServer::Queue);
+                break;
+            }
+        }
+    }
+
+    // numbers an update of the peer list in the epoch of the mock servers
+    fn seq(sequence: u64) -> PeerListSeq {
+        PeerListSeq { epoch: 0, sequence }
+    }
+
+    // delivers packets until the condition holds
+    fn run_until(network: &MockNetwork, mut condition: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            network.deliver_all();
+            thread::yield_now();
+        }
+    }
+
+    // responds to queue requests with every other client
+    fn serve(server: &MockTransport, clients: &[SocketAddr]) {
+        for event in server.events().try_iter() {
+            if let TransportEvent::Packet(packet) = event {
+                if let Ok(ToServer::Queue) = bincode::deserialize(packet.payload()) {
+                    let peers = clients
+                        .iter()
+                        .cloned()
+                        .filter(|&c| c != packet.addr())
+                        .collect();
+                    let payload = bincode::serialize(&FromServer::Peers(seq(0), peers)).unwrap();
+                    server
+                        .send(Packet::reliable_unordered(packet.addr(), payload))
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_server_latency_is_measured() {
+        let ip = "127.0.0.1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let client =
+            Client::with_transport(ip, network.transport(SocketAddr::new(ip, CLIENT_PORT)));
+        assert_eq!(client.server_latency().unwrap(), None);
+        run_until(&network, || {
+            for event in server.events().try_iter() {
+                if let TransportEvent::Packet(packet) = event {
+                    if let Ok(ToServer::StatusCheck) = bincode::deserialize(packet.payload()) {
+                        let alive = bincode::serialize(&FromServer::Alive).unwrap();
+                        server
+                            .send(Packet::unreliable(packet.addr(), alive))
+                            .unwrap();
+                    }
+                }
+            }
+            client.server_latency().unwrap().is_some()
+        });
+        assert_eq!(client.server_loss().unwrap(), Some(0.0));
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn malformed_packets_are_ignored() {
+        let ip = "127.0.0.1".parse().unwrap();
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        let server_addr = SocketAddr::new(ip, SERVER_PORT);
+        let peer_addr = "127.0.0.2:1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(server_addr);
+        let peer = network.transport(peer_addr);
+        let mut client = Client::with_transport(ip, network.transport(addr));
+        client.queue().unwrap();
+
+        let peers = bincode::serialize(&FromServer::Peers(
+            seq(0),
+            vec![peer_addr].into_iter().collect(),
+        ))
+        .unwrap();
+        server
+            .send(Packet::reliable_unordered(addr, peers.clone()))
+            .unwrap();
+        run_until(&network, || client.peers().unwrap().len() == 1);
+
+        let forged = bincode::serialize(&ToClient::PingResponse(u128::MAX)).unwrap();
+        for payload in [vec![], vec![0xff; 64], forged] {
+            peer.send(Packet::unreliable(addr, payload)).unwrap();
+        }
+        server
+            .send(Packet::unreliable(addr, peers[..peers.len() - 1].to_vec()))
+            .unwrap();
+        network.deliver_all();
+        // the handler is still running if it responds to a ping
+        let ping = bincode::serialize(&ToClient::Ping(0)).unwrap();
+        peer.send(Packet::unreliable(addr, ping)).unwrap();
+        run_until(&network, || {
+            peer.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => {
+                    bincode::deserialize::<FromClient>(packet.payload()).ok()
+                        == Some(FromClient::PingResponse(0))
+                }
+                _ => false,
+            })
+        });
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn unreachable_servers_are_reported() {
+        let ip = "127.0.0.1".parse().unwrap();
+        let server_addr = SocketAddr::new(ip, SERVER_PORT);
+        let network = MockNetwork::new();
+        let clock = ManualClock::new();
+        let transport = network.transport(SocketAddr::new(ip, CLIENT_PORT));
+        let mut client = Client::with_clock(ip, transport, clock.clone());
+        client.queue().unwrap();
+        thread::sleep(Duration::from_millis(2 * PING_TIMER_MILLIS));
+        assert_eq!(client.check_match().unwrap(), None);
+        // the time limit passes without an answer
+        clock.advance(Duration::from_millis(CONNECT_TIMEOUT_MILLIS + 1));
+
+        let mut error = None;
+        run_until(&network, || match client.check_match() {
+            Ok(_) => false,
+            Err(e) => {
+                error = Some(e);
+                true
+            }
+        });
+        let error = error.unwrap();
+        assert!(matches!(error, ClientError::ServerUnreachable { addr } if addr == server_addr));
+        assert!(!error.is_fatal());
+        // the client stopped queueing and can queue again
+        assert_eq!(client.check_match().unwrap(), None);
+        client.queue().unwrap();
+        assert!(matches!(
+            *client.status.lock().unwrap(),
+            Status::QueuePending(_)
+        ));
+    }
+
+    #[test]
+    fn rejected_queue_requests_are_reported() {
+        let ip = "127.0.0.1".parse().unwrap();
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        let server_addr = SocketAddr::new(ip, SERVER_PORT);
+        let network = MockNetwork::new();
+        let server = network.transport(server_addr);
+        let mut client = Client::with_transport(ip, network.transport(addr));
+        client.queue().unwrap();
+
+        let rejected = bincode::serialize(&FromServer::Rejected(RejectReason::Draining)).unwrap();
+        server
+            .send(Packet::reliable_ordered(addr, rejected, streams::CONTROL))
+            .unwrap();
+        let mut error = None;
+        run_until(&network, || match client.check_match() {
+            Ok(_) => false,
+            Err(e) => {
+                error = Some(e);
+                true
+            }
+        });
+        let error = error.unwrap();
+        assert!(matches!(
+            error,
+            ClientError::QueueRejected {
+                reason: RejectReason::Draining
+            }
+        ));
+        assert!(!error.is_fatal());
+        assert_eq!(client.check_match().unwrap(), None);
+        client.queue().unwrap();
+        assert!(matches!(
+            *client.status.lock().unwrap(),
+            Status::QueuePending(_)
+        ));
+    }
+
+    #[test]
+    fn latencies_are_averaged_and_bogus_times_ignored() {
+        let mut peer = Peer::new("127.0.0.1:1".parse().unwrap());
+        assert_eq!(peer.latency(), None);
+        peer.add_ping(Duration::from_millis(10));
+        peer.add_ping(Duration::from_millis(20));
+        assert_eq!(peer.latency(), Some(Duration::from_millis(15)));
+        assert_eq!(peer.latency_nanos(), Some(15_000_000));
+
+        let start_time = Instant::now();
+        let now = start_time + Duration::from_secs(1);
+        assert_eq!(round_trip(start_time, now, 0), Some(Duration::from_secs(1)));
+        let future = Duration::from_secs(10).as_nanos();
+        assert_eq!(round_trip(start_time, now, future), None);
+        assert_eq!(round_trip(start_time, now, u128::MAX), None);
+    }
+
+    #[test]
+    fn peers_are_only_copied_when_changed() {
+        let ip = "127.0.0.1".parse().unwrap();
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        let server_addr = SocketAddr::new(ip, SERVER_PORT);
+        let peer_addr = "127.0.0.2:1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(server_addr);
+        let mut client = Client::with_transport(ip, network.transport(addr));
+        assert!(client.peers_if_changed(0).unwrap().is_none());
+
+        client.queue().unwrap();
+        let peers = bincode::serialize(&FromServer::Peers(
+            seq(0),
+            vec![peer_addr].into_iter().collect(),
+        ))
+        .unwrap();
+        server
+            .send(Packet::reliable_unordered(addr, peers))
+            .unwrap();
+        run_until(&network, || client.peers_if_changed(0).unwrap().is_some());
+        let (generation, peers) = client.peers_if_changed(0).unwrap().unwrap();
+        assert_eq!(peers, client.peers().unwrap());
+        assert!(client.peers_if_changed(generation).unwrap().is_none());
+
+        let dequeued = bincode::serialize(&FromServer::Dequeued(seq(1), peer_addr)).unwrap();
+        server
+            .send(Packet::reliable_unordered(addr, dequeued))
+            .unwrap();
+        run_until(&network, || {
+            client.peers_if_changed(generation).unwrap().is_some()
+        });
+        assert!(client.peers().unwrap().is_empty());
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn missed_peer_list_updates_are_resynced() {
+        init();
+
+        let (network, client, server, _peer) = queued_with_peer();
+        let client_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), CLIENT_PORT);
+        let other = "127.0.0.3:1".parse().unwrap();
+        let send = |msg: &FromServer| {
+            let msg = bincode::serialize(msg).unwrap();
+            server
+                .send(Packet::reliable_ordered(client_addr, msg, streams::CONTROL))
+                .unwrap();
+        };
+        let resynced = || {
+            server.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => {
+                    bincode::deserialize::<ToServer>(packet.payload()).ok()
+                        == Some(ToServer::Resync)
+                }
+                _ => false,
+            })
+        };
+
+        // the Dequeued of the peer was missed
+        send(&FromServer::Queued(
+            seq(2),
+            vec![other].into_iter().collect(),
+        ));
+        run_until(&network, resynced);
+        assert_eq!(client.peers().unwrap().len(), 2);
+        send(&FromServer::Peers(
+            seq(0),
+            vec![other].into_iter().collect(),
+        ));
+        run_until(&network, || {
+            let peers = client.peers().unwrap();
+            peers.len() == 1 && peers.iter().all(|peer| peer.addr == other)
+        });
+
+        // the updates follow the new sequence
+        send(&FromServer::Dequeued(seq(1), other));
+        run_until(&network, || client.peers().unwrap().is_empty());
+        network.deliver_all();
+        assert!(!resynced());
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn challenges_are_cancelled_when_the_peer_leaves() {
+        init();
+
+        let ip1 = "127.0.0.1".parse().unwrap();
+        let ip2 = "127.0.0.2".parse().unwrap();
+        let addr1 = SocketAddr::new(ip1, CLIENT_PORT);
+        let addr2 = SocketAddr::new(ip2, CLIENT_PORT);
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip1, SERVER_PORT));
+        let mut client1 = Client::with_transport(ip1, network.transport(addr1));
+        let mut client2 = Client::with_transport(ip1, network.transport(addr2));
+
+        client1.queue().unwrap();
+        client2.queue().unwrap();
+        run_until(&network, || {
+            serve(&server, &[addr1, addr2]);
+            client1.peers().unwrap().len() == 1 && client2.peers().unwrap().len() == 1
+        });
+        let mut peer2 = client1.peers().unwrap().into_iter().next().unwrap();
+        client1.challenge(&mut peer2).unwrap();
+        run_until(&network, || {
+            client2
+                .incoming_challenges()
+                .unwrap()
+                .iter()
+                .any(|challenge| challenge.peer() == addr1)
+        });
+
+        // each timed out as far as the server is concerned
+        for &(to, left) in &[(addr1, addr2), (addr2, addr1)] {
+            let dequeued = bincode::serialize(&FromServer::Dequeued(seq(1), left)).unwrap();
+            server
+                .send(Packet::reliable_ordered(to, dequeued, streams::CONTROL))
+                .unwrap();
+        }
+        run_until(&network, || {
+            client1.peers().unwrap().is_empty() && client2.peers().unwrap().is_empty()
+        });
+        assert_eq!(
+            client1.cancelled_challenges().unwrap(),
+            vec![CancelledChallenge::Outgoing(addr2)]
+        );
+        assert_eq!(
+            client2.cancelled_challenges().unwrap(),
+            vec![CancelledChallenge::Incoming(addr1)]
+        );
+        assert!(client2.incoming_challenges().unwrap().is_empty());
+        assert!(client1.cancelled_challenges().unwrap().is_empty());
+    }
+
+    #[test]
+    fn accepted_challenge_confirms_the_match() {
+        init();
+
+        let ip1 = "127.0.0.1".parse().unwrap();
+        let ip2 = "127.0.0.2".parse().unwrap();
+        let addr1 = SocketAddr::new(ip1, CLIENT_PORT);
+        let addr2 = SocketAddr::new(ip2, CLIENT_PORT);
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip1, SERVER_PORT));
+        let mut client1 = Client::with_transport(ip1, network.transport(addr1));
+        let mut client2 = Client::with_transport(ip1, network.transport(addr2));
+
+        client1.queue().unwrap();
+        client2.queue().unwrap();
+        run_until(&network, || {
+            serve(&server, &[addr1, addr2]);
+            client1.peers().unwrap().len() == 1 && client2.peers().unwrap().len() == 1
+        });
+
+        let mut peer2 = client1.peers().unwrap().into_iter().next().unwrap();
+        assert_eq!(peer2.addr(), addr2);
+        client1.challenge(&mut peer2).unwrap();
+        run_until(&network, || {
+            client2
+                .incoming_challenges()
+                .unwrap()
+                .iter()
+                .any(|challenge| challenge.peer() == addr1)
+        });
+
+        let mut peer1 = client2.peers().unwrap().into_iter().next().unwrap();
+        client2.accept(&mut peer1).unwrap();
+        run_until(&network, || {
+            client1.check_match().unwrap().is_some() && client2.check_match().unwrap().is_some()
+        });
+        assert_eq!(client1.check_match().unwrap(), Some(addr2));
+        assert_eq!(client2.check_match().unwrap(), Some(addr1));
+    }
+
+    #[test]
+    fn rooms_are_listed_with_the_latency_to_their_hosts() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let browser_addr = SocketAddr::new(ip, CLIENT_PORT);
+        let host_addr = "127.0.0.2:1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let browser = Client::with_transport(ip, network.transport(browser_addr));
+        // answers the browser's pings
+        let _host = Client::with_transport(ip, network.transport(host_addr));
+        let room = Room {
+            name: "room".to_string(),
+            region: "eu".to_string(),
+            players: 1,
+            max_players: 2,
+        };
+
+        browser.request_rooms().unwrap();
+        let mut verified = false;
+        run_until(&network, || {
+            for event in server.events().try_iter() {
+                if let TransportEvent::Packet(packet) = event {
+                    let reply = match bincode::deserialize(packet.payload()) {
+                        Ok(ToServer::ListRooms) if verified => {
+                            FromServer::Rooms(vec![(host_addr, room.clone())])
+                        }
+                        Ok(ToServer::ListRooms) => FromServer::Cookie(7),
+                        Ok(ToServer::Cookie(7)) => {
+                            verified = true;
+                            continue;
+                        }
+                        _ => continue,
+                    };
+                    let payload = bincode::serialize(&reply).unwrap();
+                    server
+                        .send(Packet::reliable_unordered(packet.addr(), payload))
+                        .unwrap();
+                }
+            }
+            browser
+                .rooms()
+                .unwrap()
+                .iter()
+                .any(|listing| listing.latency().is_some())
+        });
+        let listing = browser.rooms().unwrap().pop().unwrap();
+        assert_eq!(listing.host(), host_addr);
+        assert_eq!(listing.room(), &room);
+    }
+
+    #[test]
+    fn candidates_are_ordered_by_priority_and_wait() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let client_addr = SocketAddr::new(ip, CLIENT_PORT);
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let client = Client::with_transport(ip, network.transport(client_addr));
+        let addrs: Vec<SocketAddr> = (2..6)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let standing = |priority, secs| QueueStanding {
+            priority,
+            waited: Duration::from_secs(secs),
+        };
+        for msg in &[
+            FromServer::Peers(seq(0), addrs.iter().copied().collect()),
+            FromServer::Standings(vec![
+                (addrs[0], standing(0, 10)),
+                (addrs[1], standing(1, 5)),
+                (addrs[2], standing(0, 20)),
+            ]),
+        ] {
+            let payload = bincode::serialize(msg).unwrap();
+            server
+                .send(Packet::reliable_ordered(
+                    client_addr,
+                    payload,
+                    streams::CONTROL,
+                ))
+                .unwrap();
+        }
+        run_until(&network, || {
+            client
+                .peers()
+                .unwrap()
+                .iter()
+                .any(|peer| peer.priority() > 0)
+        });
+        let candidates: Vec<_> = client
+            .candidates()
+            .unwrap()
+            .iter()
+            .map(Peer::addr)
+            .collect();
+        assert_eq!(candidates, vec![addrs[1], addrs[2], addrs[0], addrs[3]]);
+    }
+
+    #[test]
+    fn backfill_rooms_are_offered_and_joined() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let client_addr = SocketAddr::new(ip, CLIENT_PORT);
+        let host_addr = "127.0.0.2:1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let client = Client::with_transport(ip, network.transport(client_addr));
+        // answers the client's pings
+        let _host = Client::with_transport(ip, network.transport(host_addr));
+        let room = Room {
+            name: "room".to_string(),
+            region: "eu".to_string(),
+            players: 2,
+            max_players: 4,
+        };
+        let send = |msg: &FromServer| {
+            let payload = bincode::serialize(msg).unwrap();
+            server
+                .send(Packet::reliable_unordered(client_addr, payload))
+                .unwrap();
+        };
+
+        send(&FromServer::Backfill(vec![(host_addr, room.clone())]));
+        run_until(&network, || {
+            client
+                .backfill_offers()
+                .unwrap()
+                .iter()
+                .any(|listing| listing.latency().is_some())
+        });
+        let offer = client.backfill_offers().unwrap().pop().unwrap();
+        assert_eq!(offer.host(), host_addr);
+        assert_eq!(offer.room(), &room);
+
+        client.join_room(host_addr).unwrap();
+        let mut asked = false;
+        run_until(&network, || {
+            for event in server.events().try_iter() {
+                if let TransportEvent::Packet(packet) = event {
+                    if let Ok(ToServer::JoinRoom(host)) = bincode::deserialize(packet.payload()) {
+                        asked = host == host_addr;
+                    }
+                }
+            }
+            asked
+        });
+        let member = "127.0.0.3:1".parse().unwrap();
+        send(&FromServer::JoinedRoom {
+            host: host_addr,
+            members: vec![member],
+        });
+        let mut events = vec![];
+        run_until(&network, || {
+            events.extend(client.room_events().unwrap());
+            !events.is_empty()
+        });
+        assert_eq!(
+            events,
+            vec![RoomEvent::Joined {
+                host: host_addr,
+                members: vec![member]
+            }]
+        );
+        assert!(client.backfill_offers().unwrap().is_empty());
+    }
+
+    #[test]
+    fn match_history_is_fetched() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let client =
+            Client::with_transport(ip, network.transport(SocketAddr::new(ip, CLIENT_PORT)));
+        let report = MatchReport {
+            opponents: vec!["127.0.0.2:1".parse().unwrap()],
+            outcome: Outcome::Draw,
+            duration: Duration::from_secs(60),
+            relayed: false,
+            average_rtt: None,
+            rollback_frames: Some(12),
+        };
+        let record = MatchRecord {
+            player: "player".to_string(),
+            opponents: vec!["opponent".to_string()],
+            outcome: Outcome::Draw,
+            duration: Duration::from_secs(60),
+            relayed: false,
+            average_rtt: None,
+            quality: Some(99),
+            ended: 0,
+        };
+
+        client.report_match(report.clone()).unwrap();
+        client.fetch_history("player".to_string()).unwrap();
+        let mut reported = None;
+        let mut histories = vec![];
+        run_until(&network, || {
+            for event in server.events().try_iter() {
+                if let TransportEvent::Packet(packet) = event {
+                    let reply = match bincode::deserialize(packet.payload()) {
+                        Ok(ToServer::MatchEnded(report)) => {
+                            reported = Some(report);
+                            continue;
+                        }
+                        Ok(ToServer::FetchHistory(player)) => FromServer::History {
+                            player,
+                            matches: vec![record.clone()],
+                        },
+                        _ => continue,
+                    };
+                    let payload = bincode::serialize(&reply).unwrap();
+                    server
+                        .send(Packet::reliable_unordered(packet.addr(), payload))
+                        .unwrap();
+                }
+            }
+            histories.extend(client.match_histories().unwrap());
+            !histories.is_empty()
+        });
+        assert_eq!(reported, Some(report));
+        assert_eq!(histories, vec![("player".to_string(), vec![record])]);
+    }
+
+    #[test]
+    fn replays_are_uploaded_and_fetched() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let client =
+            Client::with_transport(ip, network.transport(SocketAddr::new(ip, CLIENT_PORT)));
+
+        client.upload_replay(vec![1, 2]).unwrap();
+        client.fetch_replay(0).unwrap();
+        let mut responses = vec![];
+        run_until(&network, || {
+            for event in server.events().try_iter() {
+                if let TransportEvent::Packet(packet) = event {
+                    let reply = match bincode::deserialize(packet.payload()) {
+                        Ok(ToServer::UploadReplay(_)) => FromServer::ReplayStored(0),
+                        Ok(ToServer::FetchReplay(id)) => FromServer::Replay {
+                            id,
+                            replay: Some(vec![1, 2]),
+                        },
+                        _ => continue,
+                    };
+                    let payload = bincode::serialize(&reply).unwrap();
+                    server
+                        .send(Packet::reliable_ordered(packet.addr(), payload, 0))
+                        .unwrap();
+                }
+            }
+            responses.extend(client.replay_responses().unwrap());
+            responses.len() == 2
+        });
+        assert_eq!(
+            responses,
+            vec![
+                ReplayResponse::Stored(0),
+                ReplayResponse::Fetched {
+                    id: 0,
+                    replay: Some(vec![1, 2])
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn party_members_are_listed() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let mut client =
+            Client::with_transport(ip, network.transport(SocketAddr::new(ip, CLIENT_PORT)));
+        let teammate = "127.0.0.2:1".parse().unwrap();
+        let opponents = [
+            "127.0.0.3:1".parse().unwrap(),
+            "127.0.0.4:1".parse().unwrap(),
+        ];
+
+        client.join_party(7).unwrap();
+        client.queue().unwrap();
+        network.deliver_all();
+        let sent: Vec<ToServer> = server
+            .events()
+            .try_iter()
+            .filter_map(|event| match event {
+                TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent, vec![ToServer::JoinParty(7), ToServer::Queue]);
+
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        for msg in &[
+            FromServer::Peers(seq(0), opponents.iter().copied().collect()),
+            FromServer::Parties(vec![opponents.to_vec()]),
+            FromServer::Party(vec![teammate]),
+        ] {
+            let payload = bincode::serialize(msg).unwrap();
+            server
+                .send(Packet::reliable_ordered(addr, payload, 0))
+                .unwrap();
         }
+        run_until(&network, || client.party().unwrap() == vec![teammate]);
+        let peers = client.peers().unwrap();
+        let peer = peers.iter().find(|p| p.addr() == opponents[0]).unwrap();
+        assert_eq!(peer.party(), &opponents[1..]);
+    }
+
+    #[test]
+    fn queue_status_is_kept_until_dequeued() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let mut client =
+            Client::with_transport(ip, network.transport(SocketAddr::new(ip, CLIENT_PORT)));
+        client.queue().unwrap();
+        network.deliver_all();
 
-        thread::sleep(Duration::from_millis(100));
-        for mut peer in client1.peers().unwrap() {
-            client1.challenge(&mut peer).unwrap();
+        let statuses = [
+            QueueStatus {
+                position: 2,
+                estimated_wait: None,
+            },
+            QueueStatus {
+                position: 1,
+                estimated_wait: Some(Duration::from_secs(10)),
+            },
+        ];
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        let msgs = std::iter::once(FromServer::Peers(seq(0), HashSet::new()))
+            .chain(statuses.iter().copied().map(FromServer::QueueStatus));
+        for msg in msgs {
+            let payload = bincode::serialize(&msg).unwrap();
+            server
+                .send(Packet::reliable_ordered(addr, payload, 0))
+                .unwrap();
         }
-        for mut peer in client2.peers().unwrap() {
-            client2.challenge(&mut peer).unwrap();
+        run_until(&network, || {
+            client.queue_status().unwrap() == Some(statuses[1])
+        });
+        assert_eq!(client.queue_status_updates().unwrap(), statuses.to_vec());
+        assert!(client.queue_status_updates().unwrap().is_empty());
+        client.dequeue().unwrap();
+        assert_eq!(client.queue_status().unwrap(), None);
+    }
+
+    #[test]
+    fn queued_client_resumes_and_follows_moved_peers() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let mut client =
+            Client::with_transport(ip, network.transport(SocketAddr::new(ip, CLIENT_PORT)));
+        client.queue().unwrap();
+        network.deliver_all();
+
+        let peer_addr: SocketAddr = "127.0.0.2:2".parse().unwrap();
+        let moved_addr: SocketAddr = "127.0.0.2:20".parse().unwrap();
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        let msgs = vec![
+            FromServer::Peers(seq(0), vec![peer_addr].into_iter().collect()),
+            FromServer::ResumeToken(7),
+            FromServer::PeerMoved {
+                from: peer_addr,
+                to: moved_addr,
+            },
+        ];
+        for msg in msgs {
+            let payload = bincode::serialize(&msg).unwrap();
+            server
+                .send(Packet::reliable_ordered(addr, payload, 0))
+                .unwrap();
         }
+        run_until(&network, || {
+            let peers = client.peers().unwrap();
+            peers.len() == 1 && peers.iter().all(|peer| peer.addr() == moved_addr)
+        });
+
+        // the client keeps telling the server its token
+        let mut resumed = false;
+        run_until(&network, || {
+            for event in server.events().try_iter() {
+                if let TransportEvent::Packet(packet) = event {
+                    resumed |= bincode::deserialize::<ToServer>(packet.payload()).ok()
+                        == Some(ToServer::Resume(7));
+                }
+            }
+            resumed
+        });
+        assert_eq!(client.resume_token().unwrap(), Some(7));
+    }
+
+    #[test]
+    fn stale_sessions_are_superseded_before_identifying() {
+        let requests = Requests {
+            identity: Some("bob".to_string()),
+            superseded: Some(7),
+            ..Requests::default()
+        };
+        assert_eq!(
+            requests.queue_preamble(),
+            vec![
+                ToServer::Supersede(7),
+                ToServer::Identify("bob".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn unanswered_challenges_are_reported() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let clock = ManualClock::new();
+        let transport = network.transport(SocketAddr::new(ip, CLIENT_PORT));
+        let mut client = Client::with_clock(ip, transport, clock.clone());
+        client.queue().unwrap();
+        network.deliver_all();
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        let peer_addr: SocketAddr = "127.0.0.2:2".parse().unwrap();
+        let payload = bincode::serialize(&FromServer::Peers(
+            seq(0),
+            vec![peer_addr].into_iter().collect(),
+        ))
+        .unwrap();
+        server
+            .send(Packet::reliable_ordered(addr, payload, 0))
+            .unwrap();
+        run_until(&network, || client.peers().unwrap().len() == 1);
+
+        let mut peer = client.peers().unwrap().into_iter().next().unwrap();
+        client.challenge(&mut peer).unwrap();
+        // the challenge goes unanswered for the whole timeout
+        clock.advance(Duration::from_millis(CHALLENGE_TIMEOUT_MILLIS));
+        let received = |expected: ToServer| {
+            let mut received = false;
+            run_until(&network, || {
+                for event in server.events().try_iter() {
+                    if let TransportEvent::Packet(packet) = event {
+                        received |= bincode::deserialize::<ToServer>(packet.payload()).ok()
+                            == Some(expected.clone());
+                    }
+                }
+                received
+            });
+        };
+        received(ToServer::Unresponsive(peer_addr));
+
+        // the client is idle until it challenges someone again
+        let payload = bincode::serialize(&FromServer::Idle { dequeued: false }).unwrap();
+        server
+            .send(Packet::reliable_ordered(addr, payload, 0))
+            .unwrap();
+        run_until(&network, || client.is_idle().unwrap());
+        client.challenge(&mut peer).unwrap();
+        assert!(!client.is_idle().unwrap());
+        received(ToServer::Active);
+    }
+
+    #[test]
+    fn public_addr_is_the_one_the_server_sees() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let client =
+            Client::with_transport(ip, network.transport(SocketAddr::new(ip, CLIENT_PORT)));
+        assert_eq!(client.public_addr().unwrap(), None);
+
+        let public_addr = "203.0.113.7:40000".parse().unwrap();
+        let payload = bincode::serialize(&FromServer::YourAddr(public_addr)).unwrap();
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        server
+            .send(Packet::reliable_ordered(addr, payload, 0))
+            .unwrap();
+        run_until(&network, || {
+            client.public_addr().unwrap() == Some(public_addr)
+        });
+    }
+
+    #[test]
+    fn other_regions_are_taken_once() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let mut client =
+            Client::with_transport(ip, network.transport(SocketAddr::new(ip, CLIENT_PORT)));
+        client.queue().unwrap();
+        network.deliver_all();
+
+        let regions = vec![RegionSummary {
+            server: "127.0.0.2:1".parse().unwrap(),
+            region: "eu".to_string(),
+            queued: 12,
+            server_rtt: Some(Duration::from_millis(90)),
+        }];
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        let payload = bincode::serialize(&FromServer::OtherRegions(regions.clone())).unwrap();
+        server
+            .send(Packet::reliable_ordered(addr, payload, 0))
+            .unwrap();
+        let mut offered = vec![];
+        run_until(&network, || {
+            offered.extend(client.other_regions().unwrap());
+            !offered.is_empty()
+        });
+        assert_eq!(offered, regions);
+        assert!(client.other_regions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn direct_challenge_without_server() {
+        init();
+
+        let ip1 = "127.0.0.1".parse().unwrap();
+        let ip2 = "127.0.0.2".parse().unwrap();
+        let addr1 = SocketAddr::new(ip1, CLIENT_PORT);
+        let addr2 = SocketAddr::new(ip2, CLIENT_PORT);
+        // nothing listens at the server address
+        let network = MockNetwork::new();
+        let client1 = Client::with_transport(ip1, network.transport(addr1));
+        let client2 = Client::with_transport(ip1, network.transport(addr2));
+
+        client2.add_peer(addr1).unwrap();
+        client1.direct_challenge(addr2).unwrap();
+        run_until(&network, || {
+            client2
+                .incoming_challenges()
+                .unwrap()
+                .iter()
+                .any(|challenge| challenge.peer() == addr1)
+        });
+        run_until(&network, || {
+            client1
+                .peers()
+                .unwrap()
+                .iter()
+                .any(|peer| peer.latency().is_some())
+        });
+
+        let mut peer1 = client2.peers().unwrap().into_iter().next().unwrap();
+        client2.accept(&mut peer1).unwrap();
+        run_until(&network, || {
+            client1.check_match().unwrap().is_some() && client2.check_match().unwrap().is_some()
+        });
+        assert_eq!(client1.check_match().unwrap(), Some(addr2));
+        assert_eq!(client2.check_match().unwrap(), Some(addr1));
+    }
+
+    // a queued client with a peer whose control messages are written by the test
+    fn queued_with_peer() -> (
+        MockNetwork,
+        Client<MockTransport>,
+        MockTransport,
+        MockTransport,
+    ) {
+        let ip = "127.0.0.1".parse().unwrap();
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        let peer_addr = "127.0.0.2:1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let peer = network.transport(peer_addr);
+        let mut client = Client::with_transport(ip, network.transport(addr));
+        client.queue().unwrap();
+        run_until(&network, || {
+            serve(&server, &[addr, peer_addr]);
+            client.peers().unwrap().len() == 1
+        });
+        (network, client, server, peer)
+    }
+
+    fn send_control(peer: &MockTransport, sequence: u32, message: Control) {
+        let msg = ToClient::Control {
+            session: 1,
+            sequence,
+            message,
+        };
+        let msg = bincode::serialize(&msg).unwrap();
+        let addr = "127.0.0.1".parse().unwrap();
+        peer.send(Packet::reliable_unordered(
+            SocketAddr::new(addr, CLIENT_PORT),
+            msg,
+        ))
+        .unwrap();
+    }
+
+    // waits until the client has handled everything the peer sent,
+    // returning the control messages the client sent in the meantime
+    fn sync(network: &MockNetwork, peer: &MockTransport) -> Vec<Control> {
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), CLIENT_PORT);
+        let ping = bincode::serialize(&ToClient::Ping(0)).unwrap();
+        peer.send(Packet::unreliable(addr, ping)).unwrap();
+        let mut received = vec![];
+        run_until(network, || {
+            peer.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => match bincode::deserialize(packet.payload()) {
+                    Ok(FromClient::Control { message, .. }) => {
+                        received.push(message);
+                        false
+                    }
+                    Ok(FromClient::PingResponse(0)) => true,
+                    _ => false,
+                },
+                _ => false,
+            })
+        });
+        received
+    }
+
+    #[test]
+    fn identified_peers_are_remembered_across_queues() {
+        init();
+
+        let (network, client, server, peer) = queued_with_peer();
+        client.keep_peers(PeerStore::new()).unwrap();
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), CLIENT_PORT);
+        let peer_addr = "127.0.0.2:1".parse().unwrap();
+        let send_peers = || {
+            let peers = FromServer::Peers(seq(0), vec![peer_addr].into_iter().collect());
+            let handles = FromServer::PeerHandles(vec![(peer_addr, "rival".to_string())]);
+            for msg in &[peers, handles] {
+                let msg = bincode::serialize(msg).unwrap();
+                server
+                    .send(Packet::reliable_ordered(addr, msg, streams::CONTROL))
+                    .unwrap();
+            }
+        };
+        send_peers();
+        // the peer answers the client's pings
+        run_until(&network, || {
+            for event in peer.events().try_iter() {
+                if let TransportEvent::Packet(packet) = event {
+                    if let Ok(ToClient::Ping(time)) = bincode::deserialize(packet.payload()) {
+                        let response = bincode::serialize(&ToClient::PingResponse(time)).unwrap();
+                        peer.send(Packet::unreliable(addr, response)).unwrap();
+                    }
+                }
+            }
+            client
+                .known_peer("rival")
+                .unwrap()
+                .is_some_and(|known| known.latency.is_some())
+        });
+        sync(&network, &peer);
+        let latency = client.known_peer("rival").unwrap().unwrap().latency;
+
+        // requeueing replaces the peer, which starts out with the latency measured before
+        send_peers();
+        run_until(&network, || {
+            client.known_peer("rival").unwrap().unwrap().encounters == 2
+        });
+        let rival = client.peers().unwrap().into_iter().next().unwrap();
+        assert_eq!(rival.handle(), Some("rival"));
+        assert_eq!(rival.latency(), latency);
+
+        // blocked peers aren't candidates and their challenges are declined
+        client.block("rival", true).unwrap();
+        assert!(client.peers().unwrap().iter().all(Peer::is_blocked));
+        assert!(client.candidates().unwrap().is_empty());
+        send_control(&peer, 1, Control::Challenge(vec![]));
+        assert_eq!(sync(&network, &peer), vec![Control::Decline]);
+        assert!(client
+            .peers()
+            .unwrap()
+            .iter()
+            .all(|p| p.status() == PeerStatus::None));
+        assert!(client.known_peer("rival").unwrap().unwrap().blocked);
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn another_player_behind_a_peers_address_replaces_it() {
+        init();
+
+        let (network, client, server, peer) = queued_with_peer();
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), CLIENT_PORT);
+        let peer_addr = peer.addr();
+        let send_handle = |handle: &str| {
+            let handles = FromServer::PeerHandles(vec![(peer_addr, handle.to_string())]);
+            let msg = bincode::serialize(&handles).unwrap();
+            server
+                .send(Packet::reliable_ordered(addr, msg, streams::CONTROL))
+                .unwrap();
+        };
+        send_handle("rival");
+        send_control(&peer, 0, Control::Challenge(vec![]));
+        sync(&network, &peer);
+        assert_eq!(client.incoming_challenges().unwrap().len(), 1);
+        // the same player is sent again, e.g. when the client refreshes its peers
+        send_handle("rival");
+        sync(&network, &peer);
+        assert!(client.replaced_peers().unwrap().is_empty());
+
+        send_handle("stranger");
+        run_until(&network, || {
+            client
+                .peers()
+                .unwrap()
+                .iter()
+                .any(|peer| peer.handle() == Some("stranger"))
+        });
+        let replaced = client.replaced_peers().unwrap();
+        assert_eq!(replaced.len(), 1);
+        assert_eq!(replaced[0].addr(), peer_addr);
+        assert_eq!(replaced[0].previous(), "rival");
+        assert_eq!(replaced[0].current(), "stranger");
+        assert!(client.incoming_challenges().unwrap().is_empty());
+        assert_eq!(
+            client.cancelled_challenges().unwrap(),
+            vec![CancelledChallenge::Incoming(peer_addr)]
+        );
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn online_friends_are_challenged_through_the_server() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        let friend_addr = "127.0.0.2:1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let friend = network.transport(friend_addr);
+        let client = Client::with_transport(ip, network.transport(addr));
+        let mut received = vec![];
+        let mut server_receives = |msg: ToServer| {
+            run_until(&network, || {
+                received.extend(server.events().try_iter().filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                }));
+                received.contains(&msg)
+            });
+        };
+        let send = |msg: &FromServer| {
+            let msg = bincode::serialize(msg).unwrap();
+            server
+                .send(Packet::reliable_ordered(addr, msg, streams::CONTROL))
+                .unwrap();
+        };
+
+        client.identify("bob".to_string()).unwrap();
+        let friends = vec!["alice".to_string(), "carol".to_string()];
+        client.set_friends(friends.clone()).unwrap();
+        server_receives(ToServer::Friends(friends));
+        server_receives(ToServer::Identify("bob".to_string()));
+        assert!(client
+            .friends()
+            .unwrap()
+            .iter()
+            .all(|&(_, presence)| presence == Presence::Offline));
+        send(&FromServer::Presence(vec![(
+            "alice".to_string(),
+            Presence::Queued,
+        )]));
+        run_until(&network, || {
+            client.friends().unwrap()[0].1 == Presence::Queued
+        });
+        assert_eq!(
+            client.friends().unwrap(),
+            vec![
+                ("alice".to_string(), Presence::Queued),
+                ("carol".to_string(), Presence::Offline)
+            ]
+        );
+
+        // the client isn't queued, but the friend is challenged once the server tells where it is
+        assert!(!client.challenge_friend("carol").unwrap());
+        assert!(client.challenge_friend("alice").unwrap());
+        server_receives(ToServer::ChallengeFriend("alice".to_string()));
+        send(&FromServer::FriendAddr {
+            friend: "alice".to_string(),
+            addr: friend_addr,
+        });
+        run_until(&network, || {
+            friend.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => matches!(
+                    bincode::deserialize(packet.payload()),
+                    Ok(FromClient::Control {
+                        message: Control::Challenge(_),
+                        ..
+                    })
+                ),
+                _ => false,
+            })
+        });
+        let peers = client.peers().unwrap();
+        let alice = peers.iter().next().unwrap();
+        assert_eq!(alice.handle(), Some(player_handle("alice").as_str()));
+        assert_eq!(alice.status(), PeerStatus::OutgoingChallenge);
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn invites_introduce_the_host_and_the_guest() {
+        init();
+
+        let ip = "127.0.0.1".parse().unwrap();
+        let addr = SocketAddr::new(ip, CLIENT_PORT);
+        let guest_addr = "127.0.0.2:1".parse().unwrap();
+        let host_addr = "127.0.0.3:1".parse().unwrap();
+        let network = MockNetwork::new();
+        let server = network.transport(SocketAddr::new(ip, SERVER_PORT));
+        let host = network.transport(host_addr);
+        let client = Client::with_transport(ip, network.transport(addr));
+        let mut received = vec![];
+        let mut server_receives = |msg: ToServer| {
+            run_until(&network, || {
+                received.extend(server.events().try_iter().filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                }));
+                received.contains(&msg)
+            });
+        };
+        let send = |msg: &FromServer| {
+            let msg = bincode::serialize(msg).unwrap();
+            server
+                .send(Packet::reliable_ordered(addr, msg, streams::CONTROL))
+                .unwrap();
+        };
+
+        client.create_invite().unwrap();
+        server_receives(ToServer::CreateInvite);
+        send(&FromServer::Invite("ABCD2345".to_string()));
+        run_until(&network, || client.invite().unwrap().is_some());
+        assert_eq!(client.invite().unwrap().as_deref(), Some("ABCD2345"));
+        send(&FromServer::InviteRedeemed(guest_addr));
+        run_until(&network, || client.invite().unwrap().is_none());
+        assert!(client
+            .peers()
+            .unwrap()
+            .iter()
+            .any(|p| p.addr() == guest_addr));
+
+        client.redeem_invite("EXPIRED2").unwrap();
+        server_receives(ToServer::RedeemInvite("EXPIRED2".to_string()));
+        send(&FromServer::InviteInvalid("EXPIRED2".to_string()));
+        run_until(&network, || {
+            !client.requests.lock().unwrap().invalid_invites.is_empty()
+        });
+        assert_eq!(
+            client.invalid_invites().unwrap(),
+            vec!["EXPIRED2".to_string()]
+        );
+        assert!(client.invalid_invites().unwrap().is_empty());
+
+        client.redeem_invite("STREAMER").unwrap();
+        server_receives(ToServer::RedeemInvite("STREAMER".to_string()));
+        send(&FromServer::InviteHost {
+            code: "STREAMER".to_string(),
+            addr: host_addr,
+        });
+        run_until(&network, || {
+            host.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => matches!(
+                    bincode::deserialize(packet.payload()),
+                    Ok(FromClient::Control {
+                        message: Control::Challenge(_),
+                        ..
+                    })
+                ),
+                _ => false,
+            })
+        });
+        let peers = client.peers().unwrap();
+        let host = peers.iter().find(|p| p.addr() == host_addr).unwrap();
+        assert_eq!(host.status(), PeerStatus::OutgoingChallenge);
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn incoming_challenges_carry_their_metadata() {
+        init();
+
+        let (network, client, _server, peer) = queued_with_peer();
+        let peer_addr = peer.addr();
+        send_control(&peer, 0, Control::Challenge(b"ranked".to_vec()));
+        sync(&network, &peer);
+        let incoming = client.incoming_challenges().unwrap();
+        assert_eq!(incoming.len(), 1);
+        assert_eq!(incoming[0].peer(), peer_addr);
+        assert_eq!(incoming[0].metadata(), b"ranked");
+        assert_eq!(incoming[0].handle(), None);
+        assert_eq!(
+            incoming[0].expires_at() - incoming[0].received_at(),
+            Duration::from_millis(CHALLENGE_TIMEOUT_MILLIS)
+        );
+
+        let mut peer_entry = client.peers().unwrap().into_iter().next().unwrap();
+        client
+            .challenge_with(&mut peer_entry, b"casual".to_vec())
+            .unwrap();
+        assert_eq!(
+            sync(&network, &peer),
+            vec![Control::Challenge(b"casual".to_vec())]
+        );
+        client.decline(peer_addr).unwrap();
+        sync(&network, &peer);
+        assert!(client.incoming_challenges().unwrap().is_empty());
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn repeated_control_messages_are_discarded() {
+        init();
+
+        let (network, client, server, peer) = queued_with_peer();
+        let peer_addr = peer.addr();
+        send_control(&peer, 0, Control::Challenge(vec![]));
+        sync(&network, &peer);
+        assert!(client
+            .incoming_challenges()
+            .unwrap()
+            .iter()
+            .any(|challenge| challenge.peer() == peer_addr));
+        client.decline(peer_addr).unwrap();
+        assert_eq!(sync(&network, &peer), vec![Control::Decline]);
+
+        // the challenge is delivered again
+        send_control(&peer, 0, Control::Challenge(vec![]));
+        sync(&network, &peer);
+        assert!(client.incoming_challenges().unwrap().is_empty());
+        // but a new challenge is not discarded
+        send_control(&peer, 1, Control::Challenge(vec![]));
+        sync(&network, &peer);
+        assert!(client
+            .incoming_challenges()
+            .unwrap()
+            .iter()
+            .any(|challenge| challenge.peer() == peer_addr));
+
+        let mut peer_entry = client.peers().unwrap().into_iter().next().unwrap();
+        client.challenge(&mut peer_entry).unwrap();
+        sync(&network, &peer);
+        send_control(&peer, 2, Control::Accept);
+        send_control(&peer, 2, Control::Accept);
+        let candidates = Control::Candidates(vec![client.transport.local_addr().unwrap()]);
+        assert_eq!(sync(&network, &peer), vec![Control::Start(0), candidates]);
+        send_control(&peer, 3, Control::Start(0));
+        send_control(&peer, 3, Control::Start(0));
+        assert!(sync(&network, &peer).is_empty());
+        // the client has the larger session, so it probes the peer's observed address
+        send_control(&peer, 4, Control::Candidates(vec![]));
+        let client_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), CLIENT_PORT);
+        run_until(&network, || {
+            peer.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => match bincode::deserialize(packet.payload()) {
+                    Ok(FromClient::Probe { nonce, .. }) => {
+                        let msg = bincode::serialize(&ToClient::ProbeResponse(nonce)).unwrap();
+                        peer.send(Packet::unreliable(client_addr, msg)).unwrap();
+                        true
+                    }
+                    _ => false,
+                },
+                _ => false,
+            })
+        });
+        run_until(&network, || client.check_match().unwrap().is_some());
+        assert_eq!(client.check_match().unwrap(), Some(peer_addr));
+        assert!(!client.is_relayed().unwrap());
+        // the match is reported once the path to the peer is known
+        run_until(&network, || {
+            server.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => {
+                    bincode::deserialize(packet.payload()).ok()
+                        == Some(ToServer::Matched(vec![peer_addr]))
+                }
+                _ => false,
+            })
+        });
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn challenge_floods_are_blocked() {
+        init();
+
+        let (network, client, _server, peer) = queued_with_peer();
+        let peer_addr = peer.addr();
+        client
+            .set_challenge_limit(ChallengeLimit {
+                max_challenges: 1,
+                block_after: Some(3),
+                ..ChallengeLimit::default()
+            })
+            .unwrap();
+        send_control(&peer, 0, Control::Challenge(vec![]));
+        assert!(sync(&network, &peer).is_empty());
+        send_control(&peer, 1, Control::Challenge(vec![]));
+        assert_eq!(sync(&network, &peer), vec![Control::Decline]);
+        assert!(client.incoming_challenges().unwrap().is_empty());
+        assert!(client.challenge_blocks().unwrap().is_empty());
+        send_control(&peer, 2, Control::Challenge(vec![]));
+        assert_eq!(sync(&network, &peer), vec![Control::Decline]);
+        let blocks = client.challenge_blocks().unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0, peer_addr);
+        assert!(client.incoming_challenges().unwrap().is_empty());
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn reordered_control_messages_are_discarded() {
+        init();
+
+        let (network, client, _server, peer) = queued_with_peer();
+        let mut peer_entry = client.peers().unwrap().into_iter().next().unwrap();
+        client.challenge(&mut peer_entry).unwrap();
+        assert_eq!(sync(&network, &peer), vec![Control::Challenge(vec![])]);
+
+        // the peer accepted and then declined, but the accept arrives last
+        send_control(&peer, 1, Control::Decline);
+        send_control(&peer, 0, Control::Accept);
+        assert!(sync(&network, &peer).is_empty());
+        assert!(client.outgoing_challenges().unwrap().is_empty());
+
+        // a late start from a peer whose challenge was never accepted is ignored
+        send_control(&peer, 2, Control::Start(0));
+        assert!(sync(&network, &peer).is_empty());
+        assert_eq!(client.check_match().unwrap(), None);
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn starts_need_an_accepted_challenge() {
+        init();
+
+        let (network, client, _server, peer) = queued_with_peer();
+        let peer_addr = peer.addr();
+        // the client never challenged the peer
+        send_control(&peer, 0, Control::Accept);
+        assert!(sync(&network, &peer).is_empty());
+
+        // nor accepted its challenge yet
+        send_control(&peer, 1, Control::Challenge(vec![]));
+        send_control(&peer, 2, Control::Start(0));
+        assert!(sync(&network, &peer).is_empty());
+        assert!(client
+            .incoming_challenges()
+            .unwrap()
+            .iter()
+            .any(|challenge| challenge.peer() == peer_addr));
+
+        let mut peer_entry = client.peers().unwrap().into_iter().next().unwrap();
+        client.accept(&mut peer_entry).unwrap();
+        sync(&network, &peer);
+        send_control(&peer, 3, Control::Start(0));
+        assert_eq!(sync(&network, &peer), vec![Control::Start(0)]);
+        assert!(client.incoming_challenges().unwrap().is_empty());
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn group_starts_need_an_accepted_challenge() {
+        init();
+
+        let (network, client, _server, peer) = queued_with_peer();
+        let peer_addr = peer.addr();
+        // the peer never challenged the client
+        send_control(&peer, 0, Control::GroupStart(vec![]));
+        assert!(sync(&network, &peer).is_empty());
+        assert_eq!(client.check_group_match().unwrap(), None);
+
+        send_control(&peer, 1, Control::Challenge(vec![]));
+        sync(&network, &peer);
+        let mut peer_entry = client.peers().unwrap().into_iter().next().unwrap();
+        client.accept(&mut peer_entry).unwrap();
+        sync(&network, &peer);
+        // the other members have to be known peers
+        let stranger = "127.0.0.9:1".parse().unwrap();
+        send_control(&peer, 2, Control::GroupStart(vec![stranger]));
+        assert!(sync(&network, &peer).is_empty());
+
+        send_control(&peer, 3, Control::GroupStart(vec![]));
+        assert_eq!(sync(&network, &peer), vec![Control::Start(0)]);
+        assert_eq!(client.check_group_match().unwrap(), Some(vec![peer_addr]));
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn empty_groups_are_rejected() {
+        init();
+
+        let (network, client, _server, peer) = queued_with_peer();
+        assert!(matches!(
+            client.start_group(&[]),
+            Err(ClientError::EmptyGroup)
+        ));
+        // the client is still queued and can start a group with its peers
+        client.start_group(&[peer.addr()]).unwrap();
+        assert_eq!(sync(&network, &peer), vec![Control::Challenge(Vec::new())]);
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn unreachable_peers_are_relayed() {
+        init();
+
+        let (network, client, server, peer) = queued_with_peer();
+        let peer_addr = peer.addr();
+        let mut peer_entry = client.peers().unwrap().into_iter().next().unwrap();
+        client.challenge(&mut peer_entry).unwrap();
+        sync(&network, &peer);
+        send_control(&peer, 0, Control::Accept);
+        send_control(&peer, 1, Control::Start(0));
+        // the client has the larger session, so it probes the peer, which never answers
+        send_control(&peer, 2, Control::Candidates(vec![]));
+        sync(&network, &peer);
+        assert_eq!(client.check_match().unwrap(), None);
+
+        run_until(&network, || {
+            server.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => {
+                    bincode::deserialize(packet.payload()).ok()
+                        == Some(ToServer::RequestRelay(peer_addr))
+                }
+                _ => false,
+            })
+        });
+        assert_eq!(sync(&network, &peer), vec![Control::UseRelay]);
+        let ready = bincode::serialize(&FromServer::RelayReady(peer_addr)).unwrap();
+        let client_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), CLIENT_PORT);
+        server
+            .send(Packet::reliable_unordered(client_addr, ready))
+            .unwrap();
+        run_until(&network, || client.check_match().unwrap().is_some());
+        assert_eq!(client.check_match().unwrap(), Some(peer_addr));
+        assert!(client.is_relayed().unwrap());
+        // the server saw the match form through the relay, so it isn't reported
+        thread::sleep(Duration::from_millis(3 * PING_TIMER_MILLIS));
+        network.deliver_all();
+        assert!(!server.events().try_iter().any(|event| match event {
+            TransportEvent::Packet(packet) => matches!(
+                bincode::deserialize(packet.payload()),
+                Ok(ToServer::Matched(_))
+            ),
+            _ => false,
+        }));
+
+        // the game hears the peer through the relay, but not the server's own messages
+        let connection = client.into_peer_connection().unwrap();
+        assert_eq!(connection.peers(), [peer_addr]);
+        let status = FromServer::QueueStatus(QueueStatus {
+            position: 1,
+            estimated_wait: None,
+        });
+        let status = bincode::serialize(&status).unwrap();
+        server
+            .send(Packet::reliable_unordered(client_addr, status))
+            .unwrap();
+        let relayed = FromServer::Relayed {
+            from: peer_addr,
+            payload: vec![1, 2],
+        };
+        let relayed = bincode::serialize(&relayed).unwrap();
+        server
+            .send(Packet::reliable_unordered(client_addr, relayed))
+            .unwrap();
+        network.deliver_all();
+        let packet = loop {
+            match connection.events().recv_timeout(Duration::from_secs(1)) {
+                Ok(TransportEvent::Packet(packet)) => break packet,
+                Ok(_) => continue,
+                Err(e) => panic!("no packet from the peer: {}", e),
+            }
+        };
+        assert_eq!(packet.addr(), peer_addr);
+        assert_eq!(packet.payload(), [1, 2]);
+    }
+
+    #[test]
+    fn relays_are_only_requested_for_confirmed_matches() {
+        init();
+
+        let (network, client, server, peer) = queued_with_peer();
+        let peer_addr = peer.addr();
+        let requested_relay = || {
+            server.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => {
+                    bincode::deserialize(packet.payload()).ok()
+                        == Some(ToServer::RequestRelay(peer_addr))
+                }
+                _ => false,
+            })
+        };
+        send_control(&peer, 0, Control::Challenge(vec![]));
+        sync(&network, &peer);
+        let mut peer_entry = client.peers().unwrap().into_iter().next().unwrap();
+        client.accept(&mut peer_entry).unwrap();
+        sync(&network, &peer);
+        // the peer can't be reached directly, but its Start never arrives
+        send_control(&peer, 1, Control::UseRelay);
+        sync(&network, &peer);
+        thread::sleep(Duration::from_millis(3 * PING_TIMER_MILLIS));
+        network.deliver_all();
+        assert!(!requested_relay());
+        assert_eq!(client.check_match().unwrap(), None);
+
+        send_control(&peer, 2, Control::Start(0));
+        assert_eq!(sync(&network, &peer), vec![Control::Start(0)]);
+        run_until(&network, requested_relay);
+        assert!(client.close().is_ok());
+    }
+
+    #[test]
+    fn other_queues_are_left_once_matched() {
+        init();
+
+        let (network, client, server, peer) = queued_with_peer();
+        let peer_addr = peer.addr();
+        // another region's server, where the player is queued from another port
+        let other_ip = "127.0.0.3".parse().unwrap();
+        let other_server = network.transport(SocketAddr::new(other_ip, SERVER_PORT));
+        let other_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), CLIENT_PORT + 1);
+        let other_peer = "127.0.0.4:1".parse().unwrap();
+        let mut other = Client::with_transport(other_ip, network.transport(other_addr));
+        other.queue().unwrap();
+        run_until(&network, || {
+            serve(&other_server, &[other_addr, other_peer]);
+            other.peers().unwrap().len() == 1
+        });
+
+        let mut queue = MultiQueue::new(vec![client, other]);
+        assert_eq!(queue.servers(), [server.addr(), other_server.addr()]);
+        let candidates = queue.candidates().unwrap();
+        assert_eq!(candidates.len(), 2);
+        let (found_on, mut peer_entry) = candidates
+            .into_iter()
+            .find(|(_, candidate)| candidate.addr() == peer_addr)
+            .unwrap();
+        assert_eq!(found_on, server.addr());
+        queue
+            .client(found_on)
+            .unwrap()
+            .challenge(&mut peer_entry)
+            .unwrap();
+        sync(&network, &peer);
+        send_control(&peer, 0, Control::Accept);
+        send_control(&peer, 1, Control::Start(0));
+        send_control(&peer, 2, Control::Candidates(vec![]));
+        sync(&network, &peer);
+        assert_eq!(queue.check_match().unwrap(), None);
+
+        // the peer is only reachable through the relay
+        run_until(&network, || {
+            server.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => {
+                    bincode::deserialize(packet.payload()).ok()
+                        == Some(ToServer::RequestRelay(peer_addr))
+                }
+                _ => false,
+            })
+        });
+        let ready = bincode::serialize(&FromServer::RelayReady(peer_addr)).unwrap();
+        let client_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), CLIENT_PORT);
+        server
+            .send(Packet::reliable_unordered(client_addr, ready))
+            .unwrap();
+        run_until(&network, || queue.check_match().unwrap().is_some());
+        assert_eq!(queue.check_match().unwrap(), Some(server.addr()));
+        run_until(&network, || {
+            other_server.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => {
+                    bincode::deserialize(packet.payload()).ok() == Some(ToServer::Dequeue)
+                }
+                _ => false,
+            })
+        });
 
-        thread::sleep(Duration::from_millis(400));
-        println!("{:?}", client1.status.lock());
-        println!("{:?}", client2.status.lock());
-        unimplemented!();
+        let matched = queue.into_matched().unwrap();
+        assert_eq!(matched.server_addr(), server.addr());
+        assert!(matched.close().is_ok());
     }
 }
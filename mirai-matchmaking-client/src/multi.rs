@@ -0,0 +1,148 @@
+//! Queueing on several servers at once, e.g. one per region, to find a match sooner.
+//!
+//! `MultiQueue` holds a client for each server, each with its own transport, and queues them all.
+//! The candidates of every server are merged into one list, and a player queued on several of the servers
+//! is only listed once, on the server with the lowest latency to it. A challenge is sent through the client
+//! of the server the candidate was found on, see `MultiQueue::client`.
+//!
+//! As soon as a match is confirmed on one of the servers, the clients on the others are dequeued,
+//! and `MultiQueue::into_matched` closes them and returns the matched client.
+//! A server that rejects the queue request or doesn't answer is dropped from the set.
+
+use crate::{Client, ClientError, Peer};
+use mirai_core::logging::{info, warn};
+use mirai_core::transport::Transport;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+/// Clients queued on several servers at once.
+pub struct MultiQueue<T: Transport> {
+    clients: Vec<Client<T>>,
+    // the index of the client whose match was confirmed
+    matched: Option<usize>,
+}
+
+impl<T: Transport> MultiQueue<T> {
+    /// Creates a queue from clients of different servers, e.g. those `probe_servers` found responsive.
+    pub fn new(clients: Vec<Client<T>>) -> Self {
+        Self {
+            clients,
+            matched: None,
+        }
+    }
+
+    /// Queues each of the clients.
+    /// # Errors
+    /// If there is an issue serializing or sending the queue request, or
+    /// if a handler thread has panicked.
+    pub fn queue(&mut self) -> Result<(), ClientError> {
+        for client in &mut self.clients {
+            client.queue()?;
+        }
+        Ok(())
+    }
+
+    /// The servers the clients are queued on.
+    pub fn servers(&self) -> Vec<SocketAddr> {
+        self.clients.iter().map(Client::server_addr).collect()
+    }
+
+    /// The client queued on the given server, e.g. to challenge a candidate found on it.
+    pub fn client(&self, server: SocketAddr) -> Option<&Client<T>> {
+        self.clients
+            .iter()
+            .find(|client| client.server_addr() == server)
+    }
+
+    /// Returns the potential opponents of every server with the server they were found on,
+    /// in the order they should be considered in, see `Client::candidates`.
+    /// # Errors
+    /// If a handler thread has panicked.
+    pub fn candidates(&self) -> Result<Vec<(SocketAddr, Peer)>, ClientError> {
+        let mut candidates = vec![];
+        for client in &self.clients {
+            for peer in client.candidates()? {
+                candidates.push((client.server_addr(), peer));
+            }
+        }
+        // a player queued on several servers is kept where it's closest
+        candidates.sort_by_key(|(_, peer)| (peer.latency.is_none(), peer.latency));
+        let mut seen = HashSet::new();
+        candidates.retain(|(_, peer)| match &peer.handle {
+            Some(handle) => seen.insert(handle.clone()),
+            None => true,
+        });
+        candidates.sort_by_key(|(_, peer)| {
+            (
+                Reverse(peer.priority),
+                peer.queued_since.is_none(),
+                peer.queued_since,
+            )
+        });
+        Ok(candidates)
+    }
+
+    /// Checks the match status on every server, see `Client::check_group_match`.
+    /// Returns the server the match was confirmed on, once it has been,
+    /// after dequeueing the clients on the other servers.
+    /// # Errors
+    /// If every server rejected the queue request or didn't answer, or
+    /// if a handler thread has panicked.
+    pub fn check_match(&mut self) -> Result<Option<SocketAddr>, ClientError> {
+        if let Some(matched) = self.matched {
+            return Ok(Some(self.clients[matched].server_addr()));
+        }
+        let mut i = 0;
+        while i < self.clients.len() {
+            match self.clients[i].check_match() {
+                Err(e @ ClientError::QueueRejected { .. })
+                | Err(e @ ClientError::ServerUnreachable { .. }) => {
+                    warn!(
+                        "stopped queueing on {}: {}",
+                        self.clients[i].server_addr(),
+                        e
+                    );
+                    let client = self.clients.remove(i);
+                    if let Err(e) = client.close() {
+                        warn!("could not close the client: {}", e);
+                    }
+                    if self.clients.is_empty() {
+                        return Err(e);
+                    }
+                    continue;
+                }
+                result => {
+                    result?;
+                }
+            }
+            if self.clients[i].check_group_match()?.is_some() {
+                let server = self.clients[i].server_addr();
+                info!("match confirmed on {}, leaving the other queues", server);
+                for (j, client) in self.clients.iter().enumerate() {
+                    if j != i {
+                        client.dequeue()?;
+                    }
+                }
+                self.matched = Some(i);
+                return Ok(Some(server));
+            }
+            i += 1;
+        }
+        Ok(None)
+    }
+
+    /// Closes the clients on the servers the match wasn't confirmed on and returns the matched client,
+    /// e.g. to hand its transport over to the game with `Client::into_peer_connection`.
+    /// # Errors
+    /// If no match has been confirmed yet, see `MultiQueue::check_match`, or
+    /// if a handler thread has panicked.
+    pub fn into_matched(mut self) -> Result<Client<T>, ClientError> {
+        let matched = self.matched.ok_or(ClientError::NotMatched)?;
+        let client = self.clients.remove(matched);
+        for other in self.clients {
+            other.close()?;
+        }
+        Ok(client)
+    }
+}
@@ -0,0 +1,443 @@
+//! Asks the router to forward the client port, so that peers behind other NATs can reach the client directly.
+//!
+//! NAT-PMP is tried first and UPnP after it. Both are best effort, as many routers support neither
+//! or have them disabled, in which case the client works as it would without the mapping.
+//! A mapping that isn't removed expires on its own after `LEASE_SECS`.
+
+use mirai_core::logging::{debug, trace};
+use snafu::{ResultExt, Snafu};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket};
+use std::time::Duration;
+
+/// How long the router keeps a mapping that isn't removed.
+pub const LEASE_SECS: u32 = 7200;
+const NAT_PMP_PORT: u16 = 5351;
+// the first response is waited for this long, doubling on every retry
+const NAT_PMP_TIMEOUT_MILLIS: u64 = 250;
+const NAT_PMP_TRIES: u32 = 3;
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const UPNP_TIMEOUT_MILLIS: u64 = 2000;
+const UPNP_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// A UDP port forwarded by the router.
+#[derive(Debug)]
+pub struct PortMapping {
+    internal_port: u16,
+    external_port: u16,
+    protocol: Protocol,
+}
+
+#[derive(Debug)]
+enum Protocol {
+    NatPmp { gateway: SocketAddr },
+    Upnp { control: Url, service: &'static str },
+}
+
+impl PortMapping {
+    /// Asks the router to forward the same UDP port to the given port on this machine.
+    /// # Errors
+    /// If neither NAT-PMP nor UPnP could map the port.
+    pub fn new(port: u16) -> Result<Self, PortMappingError> {
+        let nat_pmp = default_gateway().and_then(|gateway| {
+            let gateway = SocketAddr::new(IpAddr::V4(gateway), NAT_PMP_PORT);
+            Self::nat_pmp(gateway, port)
+        });
+        match nat_pmp {
+            Ok(mapping) => Ok(mapping),
+            Err(e) => {
+                debug!("NAT-PMP failed: {}", e);
+                Self::upnp(port)
+            }
+        }
+    }
+
+    /// The port peers can reach the client at.
+    pub fn external_port(&self) -> u16 {
+        self.external_port
+    }
+
+    /// Asks the router to remove the mapping.
+    /// # Errors
+    /// If the router can't be reached or refuses.
+    pub fn remove(self) -> Result<(), PortMappingError> {
+        match &self.protocol {
+            Protocol::NatPmp { gateway } => {
+                nat_pmp_request(*gateway, self.internal_port, 0, 0).map(|_| ())
+            }
+            Protocol::Upnp { control, service } => {
+                let arguments = format!(
+                    "<NewRemoteHost></NewRemoteHost>\
+                     <NewExternalPort>{}</NewExternalPort>\
+                     <NewProtocol>UDP</NewProtocol>",
+                    self.external_port
+                );
+                soap_request(control, service, "DeletePortMapping", &arguments)
+            }
+        }
+    }
+
+    fn nat_pmp(gateway: SocketAddr, port: u16) -> Result<Self, PortMappingError> {
+        let external_port = nat_pmp_request(gateway, port, port, LEASE_SECS)?;
+        debug!("mapped port {} to {} with NAT-PMP", port, external_port);
+        Ok(Self {
+            internal_port: port,
+            external_port,
+            protocol: Protocol::NatPmp { gateway },
+        })
+    }
+
+    fn upnp(port: u16) -> Result<Self, PortMappingError> {
+        let location = discover_gateway()?;
+        let description = http_get(&location)?;
+        let (service, control) = control_url(&description, &location)?;
+        let local_ip = crate::local_ip_towards(control.addr).context(IoError)?;
+        let arguments = format!(
+            "<NewRemoteHost></NewRemoteHost>\
+             <NewExternalPort>{port}</NewExternalPort>\
+             <NewProtocol>UDP</NewProtocol>\
+             <NewInternalPort>{port}</NewInternalPort>\
+             <NewInternalClient>{ip}</NewInternalClient>\
+             <NewEnabled>1</NewEnabled>\
+             <NewPortMappingDescription>mirai</NewPortMappingDescription>\
+             <NewLeaseDuration>{lease}</NewLeaseDuration>",
+            port = port,
+            ip = local_ip,
+            lease = LEASE_SECS
+        );
+        soap_request(&control, service, "AddPortMapping", &arguments)?;
+        debug!("mapped port {} with UPnP", port);
+        Ok(Self {
+            internal_port: port,
+            external_port: port,
+            protocol: Protocol::Upnp { control, service },
+        })
+    }
+}
+
+/// Reads the default gateway from the routing table, which is only available on Linux.
+fn default_gateway() -> Result<Ipv4Addr, PortMappingError> {
+    let routes = std::fs::read_to_string("/proc/net/route").context(IoError)?;
+    parse_default_gateway(&routes).ok_or(PortMappingError::NoGateway)
+}
+
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let mut columns = line.split_whitespace().skip(1);
+        let destination = columns.next()?;
+        let gateway = u32::from_str_radix(columns.next()?, 16).ok()?;
+        if destination == "00000000" && gateway != 0 {
+            // the address is printed as it is laid out in memory
+            Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+        } else {
+            None
+        }
+    })
+}
+
+// sends a mapping request and returns the mapped external port, a lifetime of 0 removes the mapping
+fn nat_pmp_request(
+    gateway: SocketAddr,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> Result<u16, PortMappingError> {
+    let socket = UdpSocket::bind("0.0.0.0:0").context(IoError)?;
+    socket.connect(gateway).context(IoError)?;
+    let mut request = vec![0, 1, 0, 0];
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+
+    let mut timeout = Duration::from_millis(NAT_PMP_TIMEOUT_MILLIS);
+    let mut response = [0; 16];
+    for _ in 0..NAT_PMP_TRIES {
+        socket.send(&request).context(IoError)?;
+        socket.set_read_timeout(Some(timeout)).context(IoError)?;
+        match socket.recv(&mut response) {
+            Ok(len) => return parse_nat_pmp_response(&response[..len]),
+            Err(e) => trace!("no NAT-PMP response: {}", e),
+        }
+        timeout *= 2;
+    }
+    Err(PortMappingError::NoResponse)
+}
+
+fn parse_nat_pmp_response(response: &[u8]) -> Result<u16, PortMappingError> {
+    if response.len() < 16 || response[0] != 0 || response[1] != 129 {
+        return Err(PortMappingError::InvalidResponse);
+    }
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        return Err(PortMappingError::Refused {
+            reason: format!("NAT-PMP result code {}", result),
+        });
+    }
+    Ok(u16::from_be_bytes([response[10], response[11]]))
+}
+
+// searches for an internet gateway device and returns the location of its description
+fn discover_gateway() -> Result<Url, PortMappingError> {
+    let socket = UdpSocket::bind("0.0.0.0:0").context(IoError)?;
+    socket
+        .set_read_timeout(Some(Duration::from_millis(UPNP_TIMEOUT_MILLIS)))
+        .context(IoError)?;
+    let search = "M-SEARCH * HTTP/1.1\r\n\
+                  HOST: 239.255.255.250:1900\r\n\
+                  MAN: \"ssdp:discover\"\r\n\
+                  MX: 2\r\n\
+                  ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+    socket
+        .send_to(search.as_bytes(), SSDP_ADDR)
+        .context(IoError)?;
+    let mut buf = [0; 2048];
+    let (len, _) = socket
+        .recv_from(&mut buf)
+        .map_err(|_| PortMappingError::NoResponse)?;
+    let response = String::from_utf8_lossy(&buf[..len]);
+    let location = header(&response, "location").ok_or(PortMappingError::InvalidResponse)?;
+    Url::parse(location)
+}
+
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next()?.trim();
+        if key.eq_ignore_ascii_case(name) {
+            Some(parts.next()?.trim())
+        } else {
+            None
+        }
+    })
+}
+
+// finds the service that can map ports in the device description
+fn control_url(description: &str, location: &Url) -> Result<(&'static str, Url), PortMappingError> {
+    for &service in &UPNP_SERVICES {
+        if let Some(start) = description.find(service) {
+            let rest = &description[start..];
+            let control = rest
+                .find("<controlURL>")
+                .and_then(|i| {
+                    let url = &rest[i + "<controlURL>".len()..];
+                    url.find("</controlURL>").map(|end| url[..end].trim())
+                })
+                .ok_or(PortMappingError::InvalidResponse)?;
+            return Ok((service, location.join(control)?));
+        }
+    }
+    Err(PortMappingError::NoGateway)
+}
+
+fn http_get(url: &Url) -> Result<String, PortMappingError> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        url.path, url.addr
+    );
+    http_request(url.addr, &request)
+}
+
+fn soap_request(
+    control: &Url,
+    service: &str,
+    action: &str,
+    arguments: &str,
+) -> Result<(), PortMappingError> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body>\
+         </s:Envelope>",
+        action = action,
+        service = service,
+        arguments = arguments
+    );
+    let request = format!(
+        "POST {path} HTTP/1.1\r\n\
+         Host: {host}\r\n\
+         Content-Type: text/xml; charset=\"utf-8\"\r\n\
+         Content-Length: {len}\r\n\
+         SOAPAction: \"{service}#{action}\"\r\n\
+         Connection: close\r\n\r\n{body}",
+        path = control.path,
+        host = control.addr,
+        len = body.len(),
+        service = service,
+        action = action,
+        body = body
+    );
+    http_request(control.addr, &request)?;
+    Ok(())
+}
+
+// returns the body of a successful response
+fn http_request(addr: SocketAddr, request: &str) -> Result<String, PortMappingError> {
+    let timeout = Duration::from_millis(UPNP_TIMEOUT_MILLIS);
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).context(IoError)?;
+    stream.set_read_timeout(Some(timeout)).context(IoError)?;
+    stream.write_all(request.as_bytes()).context(IoError)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).context(IoError)?;
+    let status = response.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(PortMappingError::Refused {
+            reason: status.to_string(),
+        });
+    }
+    let body = response.find("\r\n\r\n").map(|i| &response[i + 4..]);
+    Ok(body.unwrap_or_default().to_string())
+}
+
+/// A plain http URL, which is all UPnP devices use.
+#[derive(Debug, PartialEq, Eq)]
+struct Url {
+    addr: SocketAddr,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Self, PortMappingError> {
+        let invalid = || PortMappingError::InvalidUrl {
+            url: url.to_string(),
+        };
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let addr = host
+            .parse()
+            .or_else(|_| format!("{}:80", host).parse())
+            .map_err(|_| invalid())?;
+        Ok(Self {
+            addr,
+            path: path.to_string(),
+        })
+    }
+
+    // resolves a URL found in the description, which may be relative to it
+    fn join(&self, url: &str) -> Result<Self, PortMappingError> {
+        if url.starts_with("http://") {
+            Self::parse(url)
+        } else if url.starts_with('/') {
+            Ok(Self {
+                addr: self.addr,
+                path: url.to_string(),
+            })
+        } else {
+            Ok(Self {
+                addr: self.addr,
+                path: format!("/{}", url),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum PortMappingError {
+    #[snafu(display("no gateway that supports port mapping was found"))]
+    NoGateway,
+    #[snafu(display("the gateway didn't respond"))]
+    NoResponse,
+    #[snafu(display("invalid response from the gateway"))]
+    InvalidResponse,
+    #[snafu(display("invalid URL '{}'", url))]
+    InvalidUrl { url: String },
+    #[snafu(display("the gateway refused: {}", reason))]
+    Refused { reason: String },
+    #[snafu(display("{}", source))]
+    IoError { source: std::io::Error },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn reads_the_default_gateway() {
+        let routes = "Iface\tDestination\tGateway \tFlags\n\
+                      eth0\t000200C0\t00000000\t0001\n\
+                      eth0\t00000000\t010200C0\t0003\n";
+        let gateway = parse_default_gateway(routes);
+        if cfg!(target_endian = "little") {
+            assert_eq!(gateway, Some(Ipv4Addr::new(192, 0, 2, 1)));
+        }
+        assert_eq!(parse_default_gateway("Iface\tDestination\tGateway\n"), None);
+    }
+
+    #[test]
+    fn maps_and_removes_with_nat_pmp() {
+        let gateway = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let gateway_addr = gateway.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut requests = vec![];
+            for _ in 0..2 {
+                let mut buf = [0; 12];
+                let (_, from) = gateway.recv_from(&mut buf).unwrap();
+                let mut response = vec![0, 129, 0, 0, 0, 0, 0, 1];
+                // internal port, external port and lifetime
+                response.extend_from_slice(&buf[4..6]);
+                response.extend_from_slice(&[0xad, 0x9c]);
+                response.extend_from_slice(&buf[8..12]);
+                gateway.send_to(&response, from).unwrap();
+                requests.push(buf);
+            }
+            requests
+        });
+
+        let mapping = PortMapping::nat_pmp(gateway_addr, 44445).unwrap();
+        assert_eq!(mapping.external_port(), 44444);
+        mapping.remove().unwrap();
+        let requests = handle.join().unwrap();
+        assert_eq!(&requests[0][..4], &[0, 1, 0, 0]);
+        assert_eq!(&requests[0][4..6], &44445u16.to_be_bytes());
+        assert_eq!(&requests[0][8..], &LEASE_SECS.to_be_bytes());
+        // removing asks for a lifetime of 0
+        assert_eq!(&requests[1][8..], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn refused_nat_pmp_requests_are_errors() {
+        let response = [0, 129, 0, 3, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(parse_nat_pmp_response(&response).is_err());
+        assert!(parse_nat_pmp_response(&response[..8]).is_err());
+    }
+
+    #[test]
+    fn finds_the_control_url() {
+        let location = Url::parse("http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+        let (service, control) = control_url(description, &location).unwrap();
+        assert_eq!(service, UPNP_SERVICES[0]);
+        assert_eq!(
+            control,
+            Url {
+                addr: "192.168.1.1:5000".parse().unwrap(),
+                path: "/ctl/IPConn".to_string(),
+            }
+        );
+        assert!(control_url("<root></root>", &location).is_err());
+    }
+
+    #[test]
+    fn reads_the_location_header() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                        Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        let location = header(response, "location").unwrap();
+        assert_eq!(
+            Url::parse(location).unwrap().path,
+            "/rootDesc.xml".to_string()
+        );
+        assert!(Url::parse("https://192.168.1.1/").is_err());
+    }
+}
@@ -0,0 +1,115 @@
+//! Measures the route to the matchmaking server, so a launcher can warn the player when it's already bad.
+//!
+//! The client sends the server a StatusCheck every `SERVER_PROBE_INTERVAL_MILLIS`, which the server answers
+//! with Alive. Only one check is outstanding at a time: a check that isn't answered by the time the next one
+//! is sent counts as lost. The latency is smoothed over the answered checks, and the loss is the share
+//! of the last `SERVER_PROBE_WINDOW` checks that went unanswered.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How often the server's latency is checked.
+pub const SERVER_PROBE_INTERVAL_MILLIS: u64 = 1000;
+/// How many of the latest checks the loss is measured over.
+pub const SERVER_PROBE_WINDOW: usize = 20;
+
+#[derive(Default)]
+pub(crate) struct ServerLink {
+    // when the outstanding check was sent
+    pending: Option<Instant>,
+    last_sent: Option<Instant>,
+    // the round trip time, smoothed like the peers' latencies
+    rtt: Option<Duration>,
+    // whether each of the latest checks was answered, the oldest first
+    answered: VecDeque<bool>,
+}
+
+impl ServerLink {
+    /// Whether it's time to send the next check, in which case it's counted as sent.
+    pub(crate) fn probe(&mut self, now: Instant) -> bool {
+        let interval = Duration::from_millis(SERVER_PROBE_INTERVAL_MILLIS);
+        if self
+            .last_sent
+            .is_some_and(|sent| now.saturating_duration_since(sent) < interval)
+        {
+            return false;
+        }
+        if self.pending.is_some() {
+            self.record(false);
+        }
+        self.last_sent = Some(now);
+        self.pending = Some(now);
+        true
+    }
+
+    /// Records the server's answer to the outstanding check.
+    pub(crate) fn answered(&mut self, now: Instant) {
+        if let Some(sent) = self.pending.take() {
+            let rtt = now.saturating_duration_since(sent);
+            self.rtt = Some(match self.rtt {
+                Some(smoothed) => smoothed / 2 + rtt / 2,
+                None => rtt,
+            });
+            self.record(true);
+        }
+    }
+
+    fn record(&mut self, answered: bool) {
+        if self.answered.len() == SERVER_PROBE_WINDOW {
+            self.answered.pop_front();
+        }
+        self.answered.push_back(answered);
+    }
+
+    /// The round trip time to the server, once it has answered a check.
+    pub(crate) fn latency(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// The share of the latest checks the server didn't answer, once one has been answered or lost.
+    pub(crate) fn loss(&self) -> Option<f64> {
+        if self.answered.is_empty() {
+            return None;
+        }
+        let lost = self.answered.iter().filter(|&&answered| !answered).count();
+        Some(lost as f64 / self.answered.len() as f64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latency_and_loss_follow_the_checks() {
+        let interval = Duration::from_millis(SERVER_PROBE_INTERVAL_MILLIS);
+        let start = Instant::now();
+        let mut link = ServerLink::default();
+        assert_eq!(link.latency(), None);
+        assert_eq!(link.loss(), None);
+
+        assert!(link.probe(start));
+        assert!(!link.probe(start + interval / 2));
+        link.answered(start + Duration::from_millis(40));
+        assert_eq!(link.latency(), Some(Duration::from_millis(40)));
+        assert_eq!(link.loss(), Some(0.0));
+        // a repeated answer doesn't count
+        link.answered(start + Duration::from_millis(60));
+        assert_eq!(link.latency(), Some(Duration::from_millis(40)));
+
+        // the next check is lost
+        assert!(link.probe(start + interval));
+        assert!(link.probe(start + 2 * interval));
+        assert_eq!(link.loss(), Some(0.5));
+        link.answered(start + 2 * interval + Duration::from_millis(80));
+        assert_eq!(link.latency(), Some(Duration::from_millis(60)));
+
+        for i in 3..(3 + SERVER_PROBE_WINDOW as u32) {
+            let sent = start + i * interval;
+            assert!(link.probe(sent));
+            link.answered(sent + Duration::from_millis(60));
+        }
+        // the lost check has left the window
+        assert_eq!(link.loss(), Some(0.0));
+    }
+}
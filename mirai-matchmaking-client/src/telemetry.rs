@@ -0,0 +1,301 @@
+//! Anonymized metrics about how matchmaking goes for the player, to help tune the servers.
+//!
+//! Telemetry is off unless the `telemetry` feature is enabled and the game calls
+//! `Client::enable_telemetry` with the URL to send the reports to, e.g. after asking the player.
+//! The client only counts queues, matches and challenges and times how long they take, it doesn't
+//! collect addresses, player IDs or anything else that identifies the player or their peers.
+//! The aggregates are POSTed as a JSON `TelemetryReport` every `UPLOAD_INTERVAL_SECS` and when
+//! the client is closed, and reset after each report. Reports without a queue or a match aren't sent.
+//! Like the server's webhooks, only `http://` URLs are supported, see `mirai_core::http`.
+
+use crossbeam_channel::Sender;
+use mirai_core::http::Endpoint;
+use mirai_core::logging::{trace, warn};
+use serde::Serialize;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the aggregates are uploaded.
+pub const UPLOAD_INTERVAL_SECS: u64 = 10 * 60;
+
+/// How long an upload may take before it's given up on.
+pub const UPLOAD_TIMEOUT_MILLIS: u64 = 5000;
+
+/// The funnel from queueing to a connected match, aggregated since the last report.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct TelemetryReport {
+    pub queues: u32,
+    /// The average time from queueing to the first peer list.
+    pub time_to_peers_millis: Option<u64>,
+    pub matches: u32,
+    /// The average time from queueing to a confirmed match, for the matches that were queued for.
+    pub time_to_match_millis: Option<u64>,
+    /// The challenges the client sent, and how many of them the peers declined.
+    pub challenges: u32,
+    pub declined_challenges: u32,
+    pub decline_rate: Option<f64>,
+    /// The matches whose connectivity checks finished, and how many reached the peer directly
+    /// instead of falling back to the relay or the observed address.
+    pub checked_matches: u32,
+    pub hole_punched_matches: u32,
+    pub hole_punch_success_rate: Option<f64>,
+}
+
+impl TelemetryReport {
+    fn is_empty(&self) -> bool {
+        self.queues == 0 && self.matches == 0
+    }
+}
+
+// a total and how many were added to it
+#[derive(Default)]
+struct Average {
+    total: Duration,
+    count: u32,
+}
+
+impl Average {
+    fn add(&mut self, duration: Duration) {
+        self.total += duration;
+        self.count += 1;
+    }
+
+    fn millis(&self) -> Option<u64> {
+        (self.count > 0).then(|| (self.total / self.count).as_millis() as u64)
+    }
+}
+
+fn rate(part: u32, whole: u32) -> Option<f64> {
+    (whole > 0).then(|| f64::from(part) / f64::from(whole))
+}
+
+/// Aggregates the metrics and uploads them on a thread of its own.
+pub(crate) struct Telemetry {
+    sender: Sender<TelemetryReport>,
+    uploader: JoinHandle<()>,
+    last_upload: Instant,
+    // when the client queued, until it's matched
+    queued_at: Option<Instant>,
+    awaiting_peers: bool,
+    // whether the current match has been counted, and whether its path is yet to be
+    in_match: bool,
+    connecting: bool,
+    queues: u32,
+    time_to_peers: Average,
+    matches: u32,
+    time_to_match: Average,
+    challenges: u32,
+    declined: u32,
+    checked: u32,
+    punched: u32,
+}
+
+impl Telemetry {
+    pub(crate) fn new(endpoint: Endpoint, now: Instant) -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded::<TelemetryReport>();
+        let uploader = thread::spawn(move || {
+            let timeout = Duration::from_millis(UPLOAD_TIMEOUT_MILLIS);
+            for report in receiver {
+                let body = match serde_json::to_vec(&report) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        warn!("could not serialize the telemetry report: {}", e);
+                        continue;
+                    }
+                };
+                match endpoint.post_json(&body, timeout) {
+                    Ok(status) if (200..300).contains(&status) => trace!("uploaded telemetry"),
+                    Ok(status) => warn!("the telemetry endpoint answered {}", status),
+                    Err(e) => warn!("failed to upload telemetry: {}", e),
+                }
+            }
+        });
+        Self {
+            sender,
+            uploader,
+            last_upload: now,
+            queued_at: None,
+            awaiting_peers: false,
+            in_match: false,
+            connecting: false,
+            queues: 0,
+            time_to_peers: Average::default(),
+            matches: 0,
+            time_to_match: Average::default(),
+            challenges: 0,
+            declined: 0,
+            checked: 0,
+            punched: 0,
+        }
+    }
+
+    pub(crate) fn queued(&mut self, now: Instant) {
+        self.queues += 1;
+        self.queued_at = Some(now);
+        self.awaiting_peers = true;
+    }
+
+    pub(crate) fn peers_received(&mut self, now: Instant) {
+        if let (true, Some(queued_at)) = (self.awaiting_peers, self.queued_at) {
+            self.awaiting_peers = false;
+            self.time_to_peers
+                .add(now.saturating_duration_since(queued_at));
+        }
+    }
+
+    pub(crate) fn challenged(&mut self) {
+        self.challenges += 1;
+    }
+
+    pub(crate) fn declined(&mut self) {
+        self.declined += 1;
+    }
+
+    /// Counts a match the first time the client is seen in it.
+    pub(crate) fn match_status(&mut self, matched: bool, now: Instant) {
+        if matched && !self.in_match {
+            self.matches += 1;
+            // direct challenges, e.g. between friends, weren't queued for
+            if let Some(queued_at) = self.queued_at.take() {
+                self.time_to_match
+                    .add(now.saturating_duration_since(queued_at));
+            }
+            self.awaiting_peers = false;
+            self.connecting = true;
+        }
+        self.in_match = matched;
+    }
+
+    /// Counts the outcome of the current match's connectivity checks once they've finished,
+    /// or None if the match wasn't checked, e.g. a group match.
+    pub(crate) fn connected(&mut self, punched: Option<bool>) {
+        if self.connecting {
+            self.connecting = false;
+            if let Some(punched) = punched {
+                self.checked += 1;
+                if punched {
+                    self.punched += 1;
+                }
+            }
+        }
+    }
+
+    /// The aggregates since the last report, which are reset.
+    pub(crate) fn report(&mut self) -> TelemetryReport {
+        let report = TelemetryReport {
+            queues: self.queues,
+            time_to_peers_millis: self.time_to_peers.millis(),
+            matches: self.matches,
+            time_to_match_millis: self.time_to_match.millis(),
+            challenges: self.challenges,
+            declined_challenges: self.declined,
+            decline_rate: rate(self.declined, self.challenges),
+            checked_matches: self.checked,
+            hole_punched_matches: self.punched,
+            hole_punch_success_rate: rate(self.punched, self.checked),
+        };
+        self.queues = 0;
+        self.time_to_peers = Average::default();
+        self.matches = 0;
+        self.time_to_match = Average::default();
+        self.challenges = 0;
+        self.declined = 0;
+        self.checked = 0;
+        self.punched = 0;
+        report
+    }
+
+    /// Uploads the report if `UPLOAD_INTERVAL_SECS` have passed since the last one.
+    pub(crate) fn upload_if_due(&mut self, now: Instant) {
+        let interval = Duration::from_secs(UPLOAD_INTERVAL_SECS);
+        if now.saturating_duration_since(self.last_upload) >= interval {
+            self.last_upload = now;
+            self.upload();
+        }
+    }
+
+    /// Uploads the last report and waits for the uploads to finish.
+    pub(crate) fn finish(mut self) {
+        self.upload();
+        drop(self.sender);
+        if self.uploader.join().is_err() {
+            warn!("the telemetry uploader panicked");
+        }
+    }
+
+    fn upload(&mut self) {
+        let report = self.report();
+        if !report.is_empty() {
+            // the uploader only stops once the sender is dropped
+            let _ = self.sender.send(report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn the_funnel_is_aggregated_and_uploaded() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/telemetry", listener.local_addr().unwrap());
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut telemetry = Telemetry::new(Endpoint::parse(&url).unwrap(), start);
+
+        telemetry.queued(at(0));
+        telemetry.peers_received(at(100));
+        // later peer lists aren't timed
+        telemetry.peers_received(at(900));
+        telemetry.challenged();
+        telemetry.declined();
+        telemetry.challenged();
+        telemetry.match_status(false, at(1000));
+        telemetry.match_status(true, at(1500));
+        telemetry.match_status(true, at(1600));
+        telemetry.connected(Some(true));
+        telemetry.connected(Some(false));
+        telemetry.match_status(false, at(5000));
+        // a direct challenge that wasn't queued for, whose path wasn't checked
+        telemetry.match_status(true, at(6000));
+        telemetry.connected(None);
+
+        let expected = TelemetryReport {
+            queues: 1,
+            time_to_peers_millis: Some(100),
+            matches: 2,
+            time_to_match_millis: Some(1500),
+            challenges: 2,
+            declined_challenges: 1,
+            decline_rate: Some(0.5),
+            checked_matches: 1,
+            hole_punched_matches: 1,
+            hole_punch_success_rate: Some(1.0),
+        };
+        assert_eq!(telemetry.report(), expected);
+        assert!(telemetry.report().is_empty());
+
+        telemetry.queued(at(7000));
+        let uploader = thread::spawn(move || telemetry.finish());
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"}") {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0);
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .unwrap();
+        drop(stream);
+        uploader.join().unwrap();
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /telemetry HTTP/1.1"));
+        assert!(request.contains(r#""queues":1"#));
+        assert!(request.contains(r#""time_to_peers_millis":null"#));
+    }
+}
@@ -0,0 +1,244 @@
+//! Accounts for the bandwidth the server spends relaying packets between matched clients.
+//!
+//! Each pair of clients relaying to each other is a relay, whose bytes are counted from its first
+//! relayed packet until either client times out. A relay can be capped to a rate, allowing bursts of
+//! up to a second's worth, and to a total for the whole match. The packets over either cap are dropped,
+//! which the clients' transports treat like any other loss. The limits are parsed from settings like
+//! `rate=65536,total=104857600`, in bytes per second and bytes.
+//!
+//! So that operators can budget for hosting the relay, the totals are logged and sent to the webhooks
+//! every `RELAY_REPORT_INTERVAL_SECS` while relays are active, and each relay is sent to them once it ends.
+
+use snafu::Snafu;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Instant;
+
+/// How often the relayed bytes are reported while relays are active.
+pub const RELAY_REPORT_INTERVAL_SECS: u64 = 60;
+
+/// The caps of each relay, missing values don't cap anything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayLimits {
+    /// The most bytes relayed per second.
+    pub rate: Option<u64>,
+    /// The most bytes relayed in total.
+    pub total: Option<u64>,
+}
+
+impl FromStr for RelayLimits {
+    type Err = RelayLimitsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = Self::default();
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let invalid = || RelayLimitsError::InvalidSetting {
+                setting: setting.to_string(),
+            };
+            let mut parts = setting.splitn(2, '=');
+            let key = parts.next().ok_or_else(invalid)?.trim();
+            let value = parts.next().ok_or_else(invalid)?.trim();
+            let bytes = value.parse::<u64>().map_err(|_| invalid())?;
+            match key {
+                "rate" => limits.rate = Some(bytes),
+                "total" => limits.total = Some(bytes),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(limits)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum RelayLimitsError {
+    #[snafu(display("invalid relay limit '{}'", setting))]
+    InvalidSetting { setting: String },
+}
+
+/// The bytes relayed between two clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayUsage {
+    /// The clients, lowest address first.
+    pub clients: (SocketAddr, SocketAddr),
+    pub relayed_bytes: u64,
+    /// The bytes dropped for going over a cap.
+    pub dropped_bytes: u64,
+    /// When the first packet was relayed.
+    pub started: Instant,
+}
+
+struct Relay {
+    usage: RelayUsage,
+    // the bytes that may be relayed right now, and when they were last topped up
+    allowance: u64,
+    refilled: Instant,
+}
+
+/// The bytes relayed by each relay and in total since the server started.
+#[derive(Default)]
+pub struct RelayAccounting {
+    limits: RelayLimits,
+    relays: HashMap<(SocketAddr, SocketAddr), Relay>,
+    relayed_bytes: u64,
+    dropped_bytes: u64,
+}
+
+impl RelayAccounting {
+    pub fn new(limits: RelayLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Caps the relays with the given limits from now on, keeping what they have relayed so far.
+    pub fn set_limits(&mut self, limits: RelayLimits) {
+        self.limits = limits;
+    }
+
+    /// Counts a packet of the given size from one client to the other,
+    /// returning whether it's within the relay's caps and should be sent.
+    pub fn relay(&mut self, from: SocketAddr, to: SocketAddr, bytes: usize, now: Instant) -> bool {
+        let rate = self.limits.rate;
+        let clients = ordered(from, to);
+        let relay = self.relays.entry(clients).or_insert_with(|| Relay {
+            usage: RelayUsage {
+                clients,
+                relayed_bytes: 0,
+                dropped_bytes: 0,
+                started: now,
+            },
+            allowance: rate.unwrap_or(0),
+            refilled: now,
+        });
+        let bytes = bytes as u64;
+        if let Some(rate) = rate {
+            let elapsed = now.saturating_duration_since(relay.refilled);
+            let refill = (elapsed.as_secs_f64() * rate as f64) as u64;
+            if refill > 0 {
+                relay.allowance = relay.allowance.saturating_add(refill).min(rate);
+                relay.refilled = now;
+            }
+        }
+        let over_rate = rate.is_some() && bytes > relay.allowance;
+        let over_total = self
+            .limits
+            .total
+            .is_some_and(|total| relay.usage.relayed_bytes + bytes > total);
+        if over_rate || over_total {
+            relay.usage.dropped_bytes += bytes;
+            self.dropped_bytes += bytes;
+            return false;
+        }
+        if rate.is_some() {
+            relay.allowance -= bytes;
+        }
+        relay.usage.relayed_bytes += bytes;
+        self.relayed_bytes += bytes;
+        true
+    }
+
+    /// Ends the relays of the client, e.g. once it has timed out, and returns their usage.
+    pub fn end(&mut self, client: SocketAddr) -> Vec<RelayUsage> {
+        self.relays_of(client)
+            .into_iter()
+            .filter_map(|clients| self.relays.remove(&clients))
+            .map(|relay| relay.usage)
+            .collect()
+    }
+
+    /// Continues the relays of the client at its new address.
+    pub fn migrate(&mut self, from: SocketAddr, to: SocketAddr) {
+        for clients in self.relays_of(from) {
+            if let Some(mut relay) = self.relays.remove(&clients) {
+                let other = if clients.0 == from {
+                    clients.1
+                } else {
+                    clients.0
+                };
+                relay.usage.clients = ordered(to, other);
+                self.relays.insert(relay.usage.clients, relay);
+            }
+        }
+    }
+
+    fn relays_of(&self, client: SocketAddr) -> Vec<(SocketAddr, SocketAddr)> {
+        self.relays
+            .keys()
+            .filter(|&&(a, b)| a == client || b == client)
+            .copied()
+            .collect()
+    }
+
+    /// The usage of the active relays.
+    pub fn usage(&self) -> Vec<RelayUsage> {
+        self.relays.values().map(|relay| relay.usage).collect()
+    }
+
+    /// The bytes relayed since the server started.
+    pub fn relayed_bytes(&self) -> u64 {
+        self.relayed_bytes
+    }
+
+    /// The bytes dropped for going over a cap since the server started.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped_bytes
+    }
+}
+
+fn ordered(a: SocketAddr, b: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn relays_are_capped() {
+        let limits: RelayLimits = "rate=1000, total=2500".parse().unwrap();
+        assert_eq!(
+            limits,
+            RelayLimits {
+                rate: Some(1000),
+                total: Some(2500)
+            }
+        );
+        assert!("rate=fast".parse::<RelayLimits>().is_err());
+
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let c: SocketAddr = "127.0.0.1:3".parse().unwrap();
+        let mut accounting = RelayAccounting::new(limits);
+        let now = Instant::now();
+        // a second's worth can be relayed at once, in either direction
+        assert!(accounting.relay(a, b, 600, now));
+        assert!(accounting.relay(b, a, 400, now));
+        assert!(!accounting.relay(a, b, 1, now));
+        // other relays have caps of their own
+        assert!(accounting.relay(a, c, 1000, now));
+        let later = now + Duration::from_millis(500);
+        assert!(accounting.relay(a, b, 500, later));
+        // the allowance doesn't grow past a second's worth, and the total caps the whole relay
+        let much_later = now + Duration::from_secs(10);
+        assert!(!accounting.relay(a, b, 1001, much_later));
+        assert!(accounting.relay(a, b, 1000, much_later));
+        let even_later = much_later + Duration::from_secs(10);
+        assert!(!accounting.relay(a, b, 1, even_later));
+        assert_eq!(accounting.relayed_bytes(), 3500);
+        assert_eq!(accounting.dropped_bytes(), 1003);
+
+        let ended = accounting.end(b);
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].clients, (a, b));
+        assert_eq!(ended[0].relayed_bytes, 2500);
+        assert_eq!(ended[0].dropped_bytes, 1003);
+        assert_eq!(accounting.usage().len(), 1);
+    }
+}
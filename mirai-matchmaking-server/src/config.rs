@@ -0,0 +1,216 @@
+//! Settings that can be changed while the server runs, without dropping the queue.
+//!
+//! The configuration file has one `key = value` setting per line, and lines starting with `#` are comments:
+//!     log_level: the most verbose level logged, one of off, error, warn, info, debug and trace
+//!     max_queue: the most clients queued at once, the rest are sent Rejected(QueueFull)
+//!     max_refreshes, refresh_window_secs: how often a queued client may refresh its peers, see `MAX_REFRESHES`
+//!     relay_limits: the caps of each relay, e.g. `rate=65536,total=104857600`, see `bandwidth`
+//!     criteria: how the matchmaking windows relax, e.g. `rating=100,growth=10,max=1000,regions=30`,
+//!         see `criteria`
+//!     queue_spike: the queue size announced to the webhooks as a spike
+//! For example:
+//!     log_level = debug
+//!     max_queue = 5000
+//!     criteria = rating=150,growth=20,max=1000,regions=20
+//!
+//! The file is checked for changes every `RELOAD_INTERVAL_MILLIS` and applied when it has changed.
+//! Settings missing from the file are left as they are, e.g. as set by the environment at startup.
+//! A file with an invalid setting is logged and ignored as a whole, so a typo doesn't half apply a change.
+
+use crate::bandwidth::{RelayLimits, RelayLimitsError};
+use crate::criteria::{Relaxation, RelaxationError};
+use mirai_core::logging::{info, warn, LevelFilter};
+use snafu::{ResultExt, Snafu};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the configuration file is checked for changes.
+pub const RELOAD_INTERVAL_MILLIS: u64 = 1000;
+
+/// The settings in a configuration file, missing ones are left as they are.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    pub log_level: Option<LevelFilter>,
+    pub max_queue: Option<usize>,
+    pub max_refreshes: Option<u32>,
+    pub refresh_window: Option<Duration>,
+    pub relay_limits: Option<RelayLimits>,
+    pub criteria: Option<Relaxation>,
+    pub queue_spike: Option<usize>,
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || ConfigError::InvalidSetting {
+                line: line.to_string(),
+            };
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().ok_or_else(invalid)?.trim();
+            let value = parts.next().ok_or_else(invalid)?.trim();
+            match key {
+                "log_level" => config.log_level = Some(value.parse().map_err(|_| invalid())?),
+                "max_queue" => config.max_queue = Some(value.parse().map_err(|_| invalid())?),
+                "max_refreshes" => {
+                    config.max_refreshes = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "refresh_window_secs" => {
+                    let secs = value.parse().map_err(|_| invalid())?;
+                    config.refresh_window = Some(Duration::from_secs(secs));
+                }
+                "relay_limits" => {
+                    config.relay_limits = Some(value.parse().context(RelayLimitsSetting)?)
+                }
+                "criteria" => config.criteria = Some(value.parse().context(CriteriaSetting)?),
+                "queue_spike" => config.queue_spike = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// A configuration file, reloaded whenever it changes.
+pub struct ConfigFile {
+    path: PathBuf,
+    config: Config,
+    modified: Option<SystemTime>,
+    last_checked: Instant,
+}
+
+impl ConfigFile {
+    /// Loads the configuration from the file.
+    /// # Errors
+    /// If the file can't be read or has an invalid setting.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, ConfigError> {
+        let path = path.into();
+        let modified = modified(&path);
+        let config = read(&path)?;
+        Ok(Self {
+            path,
+            config,
+            modified,
+            last_checked: Instant::now(),
+        })
+    }
+
+    /// The configuration as it was last loaded.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Reloads the configuration if the file has changed since it was last checked,
+    /// returning whether it was reloaded.
+    pub fn reload_if_changed(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_checked) < Duration::from_millis(RELOAD_INTERVAL_MILLIS) {
+            return false;
+        }
+        self.last_checked = now;
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        match read(&self.path) {
+            Ok(config) => {
+                info!("reloaded the configuration from {:?}", self.path);
+                self.config = config;
+                true
+            }
+            Err(e) => {
+                warn!("kept the running configuration: {}", e);
+                false
+            }
+        }
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read(path: &PathBuf) -> Result<Config, ConfigError> {
+    fs::read_to_string(path)
+        .context(ReadError { path: path.clone() })?
+        .parse()
+}
+
+#[derive(Debug, Snafu)]
+pub enum ConfigError {
+    #[snafu(display("could not read the configuration {:?}: {}", path, source))]
+    ReadError { path: PathBuf, source: io::Error },
+    #[snafu(display("invalid setting '{}'", line))]
+    InvalidSetting { line: String },
+    #[snafu(display("{}", source))]
+    RelayLimitsSetting { source: RelayLimitsError },
+    #[snafu(display("{}", source))]
+    CriteriaSetting { source: RelaxationError },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn settings_are_parsed_and_reloaded() {
+        let config: Config = "
+            # tuned for the weekend
+            log_level = debug
+            max_queue = 5000
+            refresh_window_secs = 30
+            relay_limits = rate=65536
+            criteria = rating=150, growth=20
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                log_level: Some(LevelFilter::Debug),
+                max_queue: Some(5000),
+                max_refreshes: None,
+                refresh_window: Some(Duration::from_secs(30)),
+                relay_limits: Some(RelayLimits {
+                    rate: Some(65536),
+                    total: None,
+                }),
+                criteria: Some(Relaxation {
+                    rating_window: 150,
+                    rating_growth: 20,
+                    ..Relaxation::default()
+                }),
+                queue_spike: None,
+            }
+        );
+        assert!("max_queue = lots".parse::<Config>().is_err());
+        assert!("max_players = 10".parse::<Config>().is_err());
+        assert!("criteria = rating=wide".parse::<Config>().is_err());
+
+        let path = std::env::temp_dir().join(format!("mirai-config-{}.conf", std::process::id()));
+        fs::write(&path, "max_queue = 10").unwrap();
+        let mut file = ConfigFile::load(&path).unwrap();
+        assert_eq!(file.config().max_queue, Some(10));
+        // an invalid file keeps the running configuration
+        fs::write(&path, "max_queue = ten").unwrap();
+        file.modified = None;
+        file.last_checked -= Duration::from_millis(RELOAD_INTERVAL_MILLIS);
+        assert!(!file.reload_if_changed());
+        assert_eq!(file.config().max_queue, Some(10));
+        fs::write(&path, "max_queue = 20").unwrap();
+        file.modified = None;
+        file.last_checked -= Duration::from_millis(RELOAD_INTERVAL_MILLIS);
+        assert!(file.reload_if_changed());
+        assert_eq!(file.config().max_queue, Some(20));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+//! Which queued players are proposed to each other.
+//!
+//! Players that sent a profile are only proposed opponents whose rating is within their rating window,
+//! and from their own region. The window widens the longer a player waits, and regions stop mattering
+//! after a while, so players still find games when few others are queued. Both players have to accept
+//! each other, so the window of the player that has waited less decides. Missing ratings and regions
+//! match anything.
+
+use mirai_core::v1::Profile;
+use snafu::Snafu;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How the criteria relax over time, parsed from settings like `rating=100,growth=10,max=1000,regions=30`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relaxation {
+    /// How far apart ratings may be right after queueing.
+    pub rating_window: u32,
+    /// How much the rating window widens per second waited.
+    pub rating_growth: u32,
+    /// The widest the rating window gets.
+    pub max_rating_window: u32,
+    /// How long players wait before they are proposed opponents from other regions.
+    pub any_region_after: Duration,
+}
+
+impl Default for Relaxation {
+    fn default() -> Self {
+        Self {
+            rating_window: 100,
+            rating_growth: 10,
+            max_rating_window: 1000,
+            any_region_after: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Relaxation {
+    /// The rating window of a player that has waited for the given time.
+    pub fn rating_window(&self, waited: Duration) -> u32 {
+        let growth = self
+            .rating_growth
+            .saturating_mul(waited.as_secs().min(u64::from(u32::MAX)) as u32);
+        self.rating_window
+            .saturating_add(growth)
+            .min(self.max_rating_window.max(self.rating_window))
+    }
+
+    /// Whether the players are proposed to each other after waiting for the given times.
+    pub fn compatible(
+        &self,
+        a: &Profile,
+        a_waited: Duration,
+        b: &Profile,
+        b_waited: Duration,
+    ) -> bool {
+        let waited = a_waited.min(b_waited);
+        let ratings = match (a.rating, b.rating) {
+            (Some(a), Some(b)) => a.abs_diff(b) <= self.rating_window(waited),
+            _ => true,
+        };
+        let regions = match (&a.region, &b.region) {
+            (Some(a), Some(b)) => a == b || waited >= self.any_region_after,
+            _ => true,
+        };
+        ratings && regions
+    }
+}
+
+impl FromStr for Relaxation {
+    type Err = RelaxationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut relaxation = Self::default();
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let invalid = || RelaxationError::InvalidSetting {
+                setting: setting.to_string(),
+            };
+            let mut parts = setting.splitn(2, '=');
+            let key = parts.next().ok_or_else(invalid)?.trim();
+            let value = parts.next().ok_or_else(invalid)?.trim();
+            let number = |value: &str| value.parse::<u32>().map_err(|_| invalid());
+            match key {
+                "rating" => relaxation.rating_window = number(value)?,
+                "growth" => relaxation.rating_growth = number(value)?,
+                "max" => relaxation.max_rating_window = number(value)?,
+                "regions" => {
+                    relaxation.any_region_after = Duration::from_secs(number(value)?.into())
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(relaxation)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum RelaxationError {
+    #[snafu(display("invalid relaxation setting '{}'", setting))]
+    InvalidSetting { setting: String },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn profile(rating: u32, region: &str) -> Profile {
+        Profile {
+            rating: Some(rating),
+            region: Some(region.to_string()),
+        }
+    }
+
+    #[test]
+    fn criteria_widen_with_waiting() {
+        let relaxation: Relaxation = "rating=100, growth=10, max=300, regions=30"
+            .parse()
+            .unwrap();
+        let secs = Duration::from_secs;
+        let a = profile(1000, "eu");
+        let b = profile(1250, "eu");
+        assert!(!relaxation.compatible(&a, secs(60), &b, secs(0)));
+        assert!(!relaxation.compatible(&a, secs(14), &b, secs(14)));
+        assert!(relaxation.compatible(&a, secs(15), &b, secs(60)));
+        assert_eq!(relaxation.rating_window(secs(600)), 300);
+
+        let c = profile(1000, "na");
+        assert!(!relaxation.compatible(&a, secs(29), &c, secs(40)));
+        assert!(relaxation.compatible(&a, secs(30), &c, secs(40)));
+        assert!(relaxation.compatible(&a, secs(0), &Profile::default(), secs(0)));
+
+        assert!("rating=-1".parse::<Relaxation>().is_err());
+        assert!("speed=1".parse::<Relaxation>().is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! Servers in different regions that tell each other about their queues.
+//!
+//! Every `SUMMARY_INTERVAL_MILLIS`, each server sends the others a summary of its queue. The summaries
+//! echo the last one received from the recipient, which lets each server measure its round trip to the
+//! others without synchronized clocks. A client that has waited `OFFER_AFTER_SECS` without a single
+//! queued player matching it is offered the other regions with players queued, together with the
+//! round trip between the servers, so it can decide whether a worse connection is worth a game.
+//! Servers that haven't sent a summary in `SERVER_TIMEOUT_SECS` aren't offered.
+
+use mirai_core::v1::{QueueSummary, RegionSummary};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often the servers send each other summaries of their queues.
+pub const SUMMARY_INTERVAL_MILLIS: u64 = 5000;
+/// How long a queued client waits without a match before it's offered other regions.
+pub const OFFER_AFTER_SECS: u64 = 60;
+/// How long a server is offered after its last summary.
+pub const SERVER_TIMEOUT_SECS: u64 = 30;
+
+/// What is known about another region's server.
+struct OtherServer {
+    region: Option<String>,
+    queued: u32,
+    rtt: Option<Duration>,
+    // the `sent` of the server's last summary and when it arrived
+    last_summary: Option<(u64, Instant)>,
+    heard: Option<Instant>,
+}
+
+/// The servers of the other regions.
+pub struct Federation {
+    region: String,
+    servers: HashMap<SocketAddr, OtherServer>,
+    last_sent: Option<Instant>,
+}
+
+impl Federation {
+    /// Federates this server, which serves the given region, with the servers at the given addresses.
+    pub fn new(region: String, servers: &[SocketAddr]) -> Self {
+        let servers = servers
+            .iter()
+            .map(|&addr| {
+                let server = OtherServer {
+                    region: None,
+                    queued: 0,
+                    rtt: None,
+                    last_summary: None,
+                    heard: None,
+                };
+                (addr, server)
+            })
+            .collect();
+        Self {
+            region,
+            servers,
+            last_sent: None,
+        }
+    }
+
+    /// Whether the address is one of the federated servers.
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        self.servers.contains_key(&addr)
+    }
+
+    /// Returns the summaries to send to each server if it's time to send them.
+    pub fn summaries(&mut self, queued: usize, now: Instant) -> Vec<(SocketAddr, QueueSummary)> {
+        let interval = Duration::from_millis(SUMMARY_INTERVAL_MILLIS);
+        if self
+            .last_sent
+            .is_some_and(|sent| now.saturating_duration_since(sent) < interval)
+        {
+            return vec![];
+        }
+        self.last_sent = Some(now);
+        let sent = millis_since_epoch();
+        self.servers
+            .iter()
+            .map(|(&addr, server)| {
+                let echo = server.last_summary.map(|(their_sent, received)| {
+                    let held = now.saturating_duration_since(received).as_millis() as u64;
+                    (their_sent, held)
+                });
+                let summary = QueueSummary {
+                    region: self.region.clone(),
+                    queued: queued.min(u32::MAX as usize) as u32,
+                    sent,
+                    echo,
+                };
+                (addr, summary)
+            })
+            .collect()
+    }
+
+    /// Takes in a summary from one of the servers, ignoring anyone else.
+    pub fn receive(&mut self, from: SocketAddr, summary: QueueSummary, now: Instant) {
+        let server = match self.servers.get_mut(&from) {
+            Some(server) => server,
+            None => return,
+        };
+        if let Some((our_sent, held)) = summary.echo {
+            let rtt = millis_since_epoch()
+                .saturating_sub(our_sent)
+                .saturating_sub(held);
+            server.rtt = Some(Duration::from_millis(rtt));
+        }
+        server.region = Some(summary.region);
+        server.queued = summary.queued;
+        server.last_summary = Some((summary.sent, now));
+        server.heard = Some(now);
+    }
+
+    /// The other regions with players queued, closest first.
+    pub fn other_regions(&self, now: Instant) -> Vec<RegionSummary> {
+        let timeout = Duration::from_secs(SERVER_TIMEOUT_SECS);
+        let mut regions: Vec<_> = self
+            .servers
+            .iter()
+            .filter(|(_, server)| {
+                server.queued > 0
+                    && server
+                        .heard
+                        .is_some_and(|heard| now.saturating_duration_since(heard) < timeout)
+            })
+            .filter_map(|(&addr, server)| {
+                Some(RegionSummary {
+                    server: addr,
+                    region: server.region.clone()?,
+                    queued: server.queued,
+                    server_rtt: server.rtt,
+                })
+            })
+            .collect();
+        regions.sort_by_key(|region| (region.server_rtt.is_none(), region.server_rtt));
+        regions
+    }
+}
+
+fn millis_since_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summaries_measure_the_round_trip() {
+        let eu_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let na_addr: SocketAddr = "127.0.0.2:1".parse().unwrap();
+        let mut eu = Federation::new("eu".to_string(), &[na_addr]);
+        let mut na = Federation::new("na".to_string(), &[eu_addr]);
+        let now = Instant::now();
+
+        let (to, summary) = eu.summaries(0, now).remove(0);
+        assert_eq!(to, na_addr);
+        assert_eq!(summary.echo, None);
+        na.receive(eu_addr, summary, now);
+        // nobody is queued in eu, and the round trip isn't known yet
+        assert!(na.other_regions(now).is_empty());
+        assert!(eu.summaries(0, now).is_empty());
+
+        let (_, summary) = na.summaries(3, now).remove(0);
+        assert!(summary.echo.is_some());
+        eu.receive(na_addr, summary, now);
+        let regions = eu.other_regions(now);
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].server, na_addr);
+        assert_eq!(regions[0].region, "na");
+        assert_eq!(regions[0].queued, 3);
+        assert!(regions[0].server_rtt.unwrap() < Duration::from_secs(1));
+
+        // strangers are ignored, and silent servers stop being offered
+        let stranger = QueueSummary {
+            region: "as".to_string(),
+            queued: 10,
+            sent: 0,
+            echo: None,
+        };
+        eu.receive("127.0.0.3:1".parse().unwrap(), stranger, now);
+        assert_eq!(eu.other_regions(now).len(), 1);
+        let later = now + Duration::from_secs(SERVER_TIMEOUT_SECS);
+        assert!(eu.other_regions(later).is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+//! Friend lists and the presence the players on them share with each other.
+//!
+//! An identified client opts in by sending the player IDs of its friends. A player's presence, whether it's
+//! offline, online or queued, is only shared with the friends that listed it as well, so nobody can follow
+//! a player that hasn't added them back. The client is sent the presence of its friends when it sends
+//! its list, and the changes every `BATCH_INTERVAL_MILLIS` after that. A player's list is forgotten
+//! once it goes offline, and sent again when it comes back.
+//!
+//! Friends can challenge each other through the server without either being queued: the server
+//! sends each the other's address, and the challenge continues between the clients as usual.
+
+use mirai_core::v1::Presence;
+use std::collections::{HashMap, HashSet};
+
+/// The most friends kept from a client's list.
+pub const MAX_FRIENDS: usize = 200;
+
+/// The friend lists of the players that shared their presence.
+#[derive(Default)]
+pub struct Friends {
+    lists: HashMap<String, HashSet<String>>,
+    // the presence last sent to each player's friends
+    sent: HashMap<String, Presence>,
+}
+
+impl Friends {
+    /// Replaces the player's friend list, keeping up to `MAX_FRIENDS` of them.
+    pub fn set(&mut self, id: String, friends: Vec<String>) {
+        let friends = friends
+            .into_iter()
+            .filter(|friend| *friend != id)
+            .take(MAX_FRIENDS)
+            .collect();
+        self.lists.insert(id, friends);
+    }
+
+    /// Whether both players listed each other.
+    pub fn are_mutual(&self, a: &str, b: &str) -> bool {
+        let listed = |by: &str, id: &str| self.lists.get(by).is_some_and(|list| list.contains(id));
+        listed(a, b) && listed(b, a)
+    }
+
+    /// The friends of the player that listed it as well.
+    pub fn mutual(&self, id: &str) -> Vec<String> {
+        self.lists.get(id).map_or_else(Vec::new, |list| {
+            list.iter()
+                .filter(|friend| self.are_mutual(id, friend))
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// The players whose presence changed since it was last sent, with their presence and the friends
+    /// to send it to. The players that are offline are forgotten.
+    pub fn changed(
+        &mut self,
+        presence: impl Fn(&str) -> Presence,
+    ) -> Vec<(String, Presence, Vec<String>)> {
+        let mut changed = vec![];
+        for id in self.lists.keys() {
+            let presence = presence(id);
+            let sent = self.sent.get(id).copied().unwrap_or(Presence::Offline);
+            if presence != sent {
+                changed.push((id.clone(), presence, self.mutual(id)));
+            }
+        }
+        for (id, presence, _) in &changed {
+            self.sent.insert(id.clone(), *presence);
+        }
+        let sent = &mut self.sent;
+        self.lists.retain(|id, _| {
+            let online = sent.get(id).is_some_and(|&sent| sent != Presence::Offline);
+            if !online {
+                sent.remove(id);
+            }
+            online
+        });
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn presence_is_only_shared_with_mutual_friends() {
+        let mut friends = Friends::default();
+        friends.set(
+            "alice".to_string(),
+            vec!["bob".to_string(), "carol".to_string()],
+        );
+        friends.set(
+            "bob".to_string(),
+            vec!["alice".to_string(), "bob".to_string()],
+        );
+        friends.set("carol".to_string(), vec![]);
+        assert!(friends.are_mutual("alice", "bob"));
+        assert!(!friends.are_mutual("alice", "carol"));
+        assert_eq!(friends.mutual("alice"), vec!["bob".to_string()]);
+
+        let mut current = HashMap::new();
+        current.insert("alice".to_string(), Presence::Queued);
+        current.insert("bob".to_string(), Presence::Online);
+        current.insert("carol".to_string(), Presence::Online);
+        let presence = |current: &HashMap<_, _>, id: &str| {
+            current.get(id).copied().unwrap_or(Presence::Offline)
+        };
+        let mut changed = friends.changed(|id| presence(&current, id));
+        changed.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            changed,
+            vec![
+                (
+                    "alice".to_string(),
+                    Presence::Queued,
+                    vec!["bob".to_string()]
+                ),
+                (
+                    "bob".to_string(),
+                    Presence::Online,
+                    vec!["alice".to_string()]
+                ),
+                ("carol".to_string(), Presence::Online, vec![]),
+            ]
+        );
+        assert!(friends.changed(|id| presence(&current, id)).is_empty());
+
+        // the friends are told before the list is forgotten
+        current.remove("bob");
+        assert_eq!(
+            friends.changed(|id| presence(&current, id)),
+            vec![(
+                "bob".to_string(),
+                Presence::Offline,
+                vec!["alice".to_string()]
+            )]
+        );
+        assert!(!friends.are_mutual("alice", "bob"));
+        assert!(friends.changed(|id| presence(&current, id)).is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+//! Infers the region of clients that don't declare one from their IP, enabled with the `geoip` feature.
+//!
+//! The location is looked up in a MaxMind country or city database, e.g. GeoLite2-Country.mmdb.
+//! A client is placed in the region its country is mapped to, or else the one its continent is mapped to,
+//! e.g. `US=na-east,CA=na-east,EU=eu`. Locations without a mapping use the continent code in lowercase,
+//! e.g. `eu` or `na`, so clients that declare their region should use the same names.
+
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use snafu::{ResultExt, Snafu};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// The regions countries and continents are placed in, parsed from settings like `US=na-east,EU=eu`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegionMap {
+    // ISO country codes and continent codes to region names
+    regions: HashMap<String, String>,
+}
+
+impl RegionMap {
+    /// The region of a client in the given country and continent.
+    pub fn region(&self, country: Option<&str>, continent: Option<&str>) -> Option<String> {
+        let mapped = |code: Option<&str>| self.regions.get(&code?.to_uppercase()).cloned();
+        mapped(country)
+            .or_else(|| mapped(continent))
+            .or_else(|| continent.map(str::to_lowercase))
+    }
+}
+
+impl FromStr for RegionMap {
+    type Err = GeoIpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut regions = HashMap::new();
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let invalid = || GeoIpError::InvalidMapping {
+                setting: setting.to_string(),
+            };
+            let mut parts = setting.splitn(2, '=');
+            let code = parts.next().map(str::trim).ok_or_else(invalid)?;
+            let region = parts.next().map(str::trim).ok_or_else(invalid)?;
+            if code.is_empty() || region.is_empty() {
+                return Err(invalid());
+            }
+            regions.insert(code.to_uppercase(), region.to_string());
+        }
+        Ok(Self { regions })
+    }
+}
+
+/// A location database and the regions its locations are placed in.
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+    regions: RegionMap,
+}
+
+impl GeoIp {
+    /// Loads the database from the file.
+    /// # Errors
+    /// If the file can't be read or isn't a MaxMind database.
+    pub fn open(path: impl Into<PathBuf>, regions: RegionMap) -> Result<Self, GeoIpError> {
+        let path = path.into();
+        let reader = Reader::open_readfile(&path).context(DatabaseError { path })?;
+        Ok(Self { reader, regions })
+    }
+
+    /// The region of the IP, if the database knows where it is.
+    pub fn region(&self, ip: IpAddr) -> Option<String> {
+        let location: geoip2::Country = self.reader.lookup(ip).ok()?;
+        let country = location.country.and_then(|country| country.iso_code);
+        let continent = location.continent.and_then(|continent| continent.code);
+        self.regions.region(country, continent)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum GeoIpError {
+    #[snafu(display("could not load the location database {:?}: {}", path, source))]
+    DatabaseError {
+        path: PathBuf,
+        source: MaxMindDBError,
+    },
+    #[snafu(display("invalid region mapping '{}', expected CODE=region", setting))]
+    InvalidMapping { setting: String },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn countries_are_mapped_before_continents() {
+        let regions: RegionMap = "US=na-east, ca=na-east,EU=eu".parse().unwrap();
+        assert_eq!(
+            regions.region(Some("US"), Some("NA")),
+            Some("na-east".to_string())
+        );
+        assert_eq!(
+            regions.region(Some("CA"), Some("NA")),
+            Some("na-east".to_string())
+        );
+        assert_eq!(
+            regions.region(Some("FI"), Some("EU")),
+            Some("eu".to_string())
+        );
+        assert_eq!(
+            regions.region(Some("MX"), Some("NA")),
+            Some("na".to_string())
+        );
+        assert_eq!(regions.region(None, None), None);
+        assert_eq!(
+            RegionMap::default().region(None, Some("AS")),
+            Some("as".to_string())
+        );
+        assert!("US".parse::<RegionMap>().is_err());
+        assert!("US=".parse::<RegionMap>().is_err());
+    }
+}
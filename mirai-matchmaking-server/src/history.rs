@@ -0,0 +1,146 @@
+//! Storage for the matches reported by identified clients.
+//!
+//! Each client reports its own side of a match, so a player's history is the matches it reported,
+//! and the outcome is always from the player's point of view. Only the latest matches of each player
+//! are kept.
+
+use mirai_core::v1::MatchRecord;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+
+/// Where the reported matches are kept.
+pub trait HistoryStore: Send {
+    /// Stores the match in its player's history.
+    /// # Errors
+    /// If the match could not be stored.
+    fn record(&mut self, record: MatchRecord) -> io::Result<()>;
+
+    /// Returns up to `count` of the player's latest matches, the latest first.
+    /// # Errors
+    /// If the matches could not be loaded.
+    fn recent(&self, player: &str, count: usize) -> io::Result<Vec<MatchRecord>>;
+}
+
+/// Keeps the latest matches of each player in memory.
+pub struct MemoryHistory {
+    per_player: usize,
+    matches: HashMap<String, VecDeque<MatchRecord>>,
+}
+
+impl MemoryHistory {
+    pub fn new(per_player: usize) -> Self {
+        Self {
+            per_player: per_player.max(1),
+            matches: HashMap::new(),
+        }
+    }
+}
+
+impl HistoryStore for MemoryHistory {
+    fn record(&mut self, record: MatchRecord) -> io::Result<()> {
+        let matches = self.matches.entry(record.player.clone()).or_default();
+        if matches.len() == self.per_player {
+            matches.pop_back();
+        }
+        matches.push_front(record);
+        Ok(())
+    }
+
+    fn recent(&self, player: &str, count: usize) -> io::Result<Vec<MatchRecord>> {
+        let recent = self
+            .matches
+            .get(player)
+            .map(|matches| matches.iter().take(count).cloned().collect())
+            .unwrap_or_default();
+        Ok(recent)
+    }
+}
+
+/// Appends the matches to a file, and keeps the latest ones of each player in memory.
+pub struct FileHistory {
+    file: File,
+    memory: MemoryHistory,
+}
+
+impl FileHistory {
+    /// Creates the file if needed, and loads the matches already in it.
+    /// # Errors
+    /// If the file can't be created or read, or contains something other than matches.
+    pub fn open(path: impl AsRef<Path>, per_player: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut memory = MemoryHistory::new(per_player);
+        let mut reader = BufReader::new(&file);
+        loop {
+            match bincode::deserialize_from::<_, MatchRecord>(&mut reader) {
+                Ok(record) => memory.record(record)?,
+                Err(e) => match *e {
+                    bincode::ErrorKind::Io(ref io) if io.kind() == io::ErrorKind::UnexpectedEof => {
+                        break
+                    }
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                },
+            }
+        }
+        Ok(Self { file, memory })
+    }
+}
+
+impl HistoryStore for FileHistory {
+    fn record(&mut self, record: MatchRecord) -> io::Result<()> {
+        let bytes = bincode::serialize(&record)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.file.write_all(&bytes)?;
+        self.memory.record(record)
+    }
+
+    fn recent(&self, player: &str, count: usize) -> io::Result<Vec<MatchRecord>> {
+        self.memory.recent(player, count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mirai_core::v1::Outcome;
+    use std::time::Duration;
+
+    fn record(player: &str, ended: u64) -> MatchRecord {
+        MatchRecord {
+            player: player.to_string(),
+            opponents: vec!["opponent".to_string()],
+            outcome: Outcome::Win,
+            duration: Duration::from_secs(60),
+            relayed: false,
+            average_rtt: Some(Duration::from_millis(30)),
+            quality: Some(100),
+            ended,
+        }
+    }
+
+    #[test]
+    fn file_keeps_the_latest_matches_after_reopening() {
+        let path = std::env::temp_dir().join(format!("mirai-history-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut history = FileHistory::open(&path, 2).unwrap();
+        for ended in 0..3 {
+            history.record(record("player", ended)).unwrap();
+        }
+        history.record(record("other", 3)).unwrap();
+        let history = FileHistory::open(&path, 2).unwrap();
+        let recent = history.recent("player", 10).unwrap();
+        let ended: Vec<_> = recent.iter().map(|record| record.ended).collect();
+        assert_eq!(ended, vec![2, 1]);
+        assert_eq!(
+            history.recent("other", 10).unwrap(),
+            vec![record("other", 3)]
+        );
+        assert!(history.recent("nobody", 10).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+//! Invite codes for direct matches, e.g. for a streamer to play with one of their viewers.
+//!
+//! A client asks the server for a code and shares it however it likes. The client that redeems the code
+//! is sent the inviter's address and challenges it, and the inviter is told where the challenge will come
+//! from, so the match starts like any other direct challenge. Neither has to be queued. A code can be
+//! redeemed once within `INVITE_SECS`, and each client has one code at a time.
+//! The codes are `CODE_LEN` characters long, leaving out the ones easily mistaken for each other.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long an invite code can be redeemed for.
+pub const INVITE_SECS: u64 = 10 * 60;
+
+/// The length of the invite codes.
+pub const CODE_LEN: usize = 8;
+
+// 32 characters, without 0, O, 1 and I
+const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// The open invites, by their codes.
+#[derive(Default)]
+pub struct Invites {
+    // keys the codes, so they can't be guessed from earlier ones
+    key: RandomState,
+    created: u64,
+    // the inviters and when they created the codes
+    codes: HashMap<String, (SocketAddr, Instant)>,
+}
+
+impl Invites {
+    /// Creates a code that invites others to challenge the inviter, replacing its earlier one.
+    pub fn create(&mut self, inviter: SocketAddr, now: Instant) -> String {
+        self.remove(inviter);
+        self.expire(now);
+        loop {
+            self.created += 1;
+            let mut bits = self.key.hash_one((inviter, self.created));
+            let code: String = (0..CODE_LEN)
+                .map(|_| {
+                    let c = ALPHABET[(bits % ALPHABET.len() as u64) as usize];
+                    bits /= ALPHABET.len() as u64;
+                    char::from(c)
+                })
+                .collect();
+            if !self.codes.contains_key(&code) {
+                self.codes.insert(code.clone(), (inviter, now));
+                return code;
+            }
+        }
+    }
+
+    /// The inviter of the code, if it's open and the client isn't the inviter itself.
+    /// The code can't be redeemed again. Codes are accepted in lower case as well.
+    pub fn redeem(&mut self, code: &str, client: SocketAddr, now: Instant) -> Option<SocketAddr> {
+        self.expire(now);
+        let code = code.to_ascii_uppercase();
+        match self.codes.get(&code) {
+            Some(&(inviter, _)) if inviter != client => {
+                self.codes.remove(&code);
+                Some(inviter)
+            }
+            _ => None,
+        }
+    }
+
+    /// Closes the inviter's invite, e.g. when it times out.
+    pub fn remove(&mut self, inviter: SocketAddr) {
+        self.codes.retain(|_, &mut (i, _)| i != inviter);
+    }
+
+    /// Moves the inviter's invite to its new address.
+    pub fn migrate(&mut self, from: SocketAddr, to: SocketAddr) {
+        for (inviter, _) in self.codes.values_mut() {
+            if *inviter == from {
+                *inviter = to;
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let open = Duration::from_secs(INVITE_SECS);
+        self.codes
+            .retain(|_, &mut (_, created)| now.saturating_duration_since(created) < open);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn invites_are_redeemed_once_before_they_expire() {
+        let mut invites = Invites::default();
+        let inviter = "127.0.0.1:1".parse().unwrap();
+        let client = "127.0.0.2:2".parse().unwrap();
+        let now = Instant::now();
+        let code = invites.create(inviter, now);
+        assert_eq!(code.len(), CODE_LEN);
+        assert!(code.bytes().all(|c| ALPHABET.contains(&c)));
+        assert_eq!(invites.redeem(&code, inviter, now), None);
+        assert_eq!(
+            invites.redeem(&code.to_lowercase(), client, now),
+            Some(inviter)
+        );
+        assert_eq!(invites.redeem(&code, client, now), None);
+
+        // a new code replaces the earlier one
+        let earlier = invites.create(inviter, now);
+        let code = invites.create(inviter, now);
+        assert_ne!(earlier, code);
+        assert_eq!(invites.redeem(&earlier, client, now), None);
+        let later = now + Duration::from_secs(INVITE_SECS);
+        assert_eq!(invites.redeem(&code, client, later), None);
+
+        let moved = "127.0.0.1:3".parse().unwrap();
+        let code = invites.create(inviter, now);
+        invites.migrate(inviter, moved);
+        assert_eq!(invites.redeem(&code, client, now), Some(moved));
+    }
+}
@@ -0,0 +1,5154 @@
+//! The Mirai matchmaking server facilitates peer discovery for Mirai matchmaking clients.
+//! The server can receive the following messages:
+//!     StatusCheck
+//!         returns Alive to signal that it's running
+//!     Queue
+//!         if the client hasn't proven it can receive at its address, returns a Cookie
+//!         if the client is not already in the queue, adds the client to the queue
+//!         and returns a ResumeToken
+//!         selects a set of potential matches (currently the entire queue)
+//!         returns the potential matches to the client
+//!         if players are prioritized, followed by Standings with their priorities and how long they have waited
+//!         if any of them identified themselves, followed by PeerHandles with the public handles of their player IDs
+//!         the client's info is sent to all potential matches in the next batch
+//!         if the client is already queued, the request only refreshes the potential matches: they're returned
+//!         to the client, but the client isn't announced again
+//!         a client is sent at most `MAX_REFRESHES` refreshes per `REFRESH_WINDOW_SECS`, the rest are ignored
+//!         if the client isn't queued and the server is draining or its queue is full, returns Rejected instead
+//!     Resync
+//!         if the client is queued, returns its potential matches like a repeated Queue,
+//!         sent by a client that missed an update of its peer list
+//!         not counted against `MAX_REFRESHES`, since the client can't follow the queue until it's answered
+//!     QueueMapped
+//!         like Queue, but the client is announced at the port its router forwards to it
+//!     Cookie
+//!         if the cookie is the one returned to the client recently, the client is verified
+//!         and sent YourAddr with the address the server sees it at, e.g. for diagnosing its NAT
+//!         the client then repeats its queue request
+//!     Dequeue
+//!         removes the client from the queue
+//!     Heartbeat
+//!         ignored
+//!     RequestRelay
+//!         if the relay is enabled and both the client and the peer are queued, records the request
+//!         once the peer has requested relaying with the client as well, returns RelayReady to both
+//!         and records the match as if both had sent Matched, since clients only ask for a relay once their
+//!         match is confirmed, i.e. they have exchanged Start, and don't report relayed matches themselves
+//!         returns RelayUnavailable if the relay is disabled
+//!     Relay
+//!         if both clients requested relaying with each other, sends the payload to the recipient
+//!         used by matched clients that can't reach each other directly
+//!         the relayed bytes are counted and may be capped, see `bandwidth`
+//!     OpenRoom
+//!         if the client is queued, lists the room it hosts until it dequeues or closes the room
+//!     CloseRoom
+//!         removes the client's room from the listing
+//!     JoinRoom
+//!         if the client is queued and the host's room has a free place and matches the client,
+//!         adds the client to the room and dequeues it
+//!         returns JoinedRoom with the room's other members, and sends RoomJoined to them
+//!         otherwise returns RoomFull
+//!     ListRooms
+//!         if the client hasn't proven it can receive at its address, returns a Cookie
+//!         otherwise returns up to `MAX_LISTED_ROOMS` open rooms
+//!     UploadReplay
+//!         if the client hasn't proven it can receive at its address, returns a Cookie
+//!         if the server keeps replays and the replay is at most `MAX_REPLAY_SIZE` bytes,
+//!         stores it and returns ReplayStored with its ID, otherwise returns ReplayRejected
+//!     FetchReplay
+//!         if the client hasn't proven it can receive at its address, returns a Cookie
+//!         otherwise returns the replay with the given ID, if it's stored, see `replays`
+//!     Identify
+//!         if the client has proven it can receive at its address, remembers the player ID
+//!         its reserved matches are scheduled with, sent before queueing
+//!         a player ID is only queued from one address at a time: while another queued client holds it,
+//!         e.g. the ghost of a client that crashed and restarted, the ID is ignored, unless the server hasn't
+//!         heard from that client for `SUPERSEDE_AFTER_SECS`, in which case the newer client supersedes it
+//!         the same applies when a client that identified while unqueued queues, see Supersede
+//!         the client's peers are sent an opaque public handle of the ID along with its address, so they
+//!         recognize a player they've met before without learning the ID itself
+//!     Supersede
+//!         if the client has proven it can receive at its address and the resume token is the one another
+//!         queued client was sent, the newer client supersedes that stale session right away, sent before
+//!         Identify
+//!         the stale client is dequeued and forgets its ID, and the queue is sent Dequeued with it
+//!     JoinParty
+//!         if the client has proven it can receive at its address, queues it as a party with the clients
+//!         that joined with the same token, sent before queueing
+//!         party members aren't proposed to each other, and are sent Party with the rest of the party
+//!         whenever a member joins the queue
+//!         the peers are followed by Parties with the parties among them
+//!     LeaveParty
+//!         queues the client alone from then on
+//!     Profile
+//!         if the client has proven it can receive at its address, remembers its rating and region,
+//!         sent before queueing
+//!         if the criteria are enabled, the client is only proposed players that match, see `criteria`
+//!     Matched
+//!         records how long the client waited for its match and removes it from the queue
+//!         sent once the client found a direct path to its opponents
+//!         if the server sends webhooks, announces the match with the peers the client was matched with,
+//!         unless one of them already did, see `webhooks`
+//!     MatchEnded
+//!         if the client was matched since its last report, scores the match's connection, and keeps
+//!         the players of networks whose matches went poorly apart for a while, see `quality`
+//!         if the server keeps a match history and the client has identified, stores the match and its
+//!         score in the client's history with the IDs of the opponents that identified, see `history`
+//!         if the server rates players, the client was matched since its last report and its only
+//!         opponent has identified, rates both players once the opponent's report agrees, see `ratings`
+//!     FetchHistory
+//!         if the client hasn't proven it can receive at its address, returns a Cookie
+//!         otherwise returns History with up to `MAX_HISTORY_MATCHES` of the player's latest matches
+//!     Leaderboard
+//!         if the client hasn't proven it can receive at its address, returns a Cookie
+//!         otherwise returns up to `MAX_LEADERBOARD_PAGE` players from the given rank or around the
+//!         given player
+//!     Resume
+//!         if the token is the resume token of a queued client that was at another address, e.g. because
+//!         its NAT rebound its port, moves the client's queue entry to the new address, returns YourAddr,
+//!         and sends PeerMoved to the queued players that may know the client's old address
+//!         the client counts as verified at the new address, since only it was sent the token
+//!         encrypted clients can't be followed, since their packets from the new address can't be decrypted
+//!     Unresponsive
+//!         if both the client and the peer are queued and the peer is proposed to the client, records that
+//!         the peer left the client's challenge unanswered, the record expires after `IDLE_REPORT_WINDOW_SECS`
+//!         once `IDLE_REPORTS` clients have, and at least one in `IDLE_REPORT_SHARE` of the clients it's
+//!         proposed to have, the peer is marked idle: it's sent Idle, the others are sent Dequeued with it, and
+//!         it's no longer proposed to anyone, or dequeued if idle clients are dequeued
+//!     Active
+//!         if the client was marked idle, announces it to the queue again in the next batch
+//!     Friends
+//!         if the client has identified, shares its presence with the friends with the given IDs that
+//!         listed it as well, and returns Presence with theirs, see `friends`
+//!         the friends are sent Presence in the next batch whenever the client queues, dequeues or times out
+//!     ChallengeFriend
+//!         if the friend with the ID listed the client as well and shares its presence, queued or not,
+//!         sends the friend FriendChallenge and returns FriendAddr with each other's addresses
+//!     CreateInvite
+//!         if the client hasn't proven it can receive at its address, returns a Cookie
+//!         otherwise returns Invite with a code that invites another client to challenge it, see `invites`
+//!     RedeemInvite
+//!         if the client hasn't proven it can receive at its address, returns a Cookie
+//!         if the code is open, sends its creator InviteRedeemed and returns InviteHost with each other's
+//!         addresses, queued or not, otherwise returns InviteInvalid
+//!     QueueSummary
+//!         if the server is federated with the sender, remembers how many players are queued in its region
+//!         ignored from anyone else, see `federation`
+//! Clients are dequeued when the connection times out, and the rest of the queue is sent Dequeued with them,
+//! so that their peers can cancel the challenges they had with them.
+//!
+//! Peers, Queued and Dequeued are numbered in a sequence per client, which each Peers starts over,
+//! and tagged with an epoch picked when the server starts, so a client can tell when it missed an update,
+//! e.g. after the server restarted, and asks for the list again with Resync.
+//!
+//! With the `scripting` feature, operators can also filter the proposed players with a script that is
+//! reloaded when it changes, see `policy`.
+//!
+//! Other services can be notified of matches and queue spikes with webhooks, see `webhooks`.
+//!
+//! With the `geoip` feature, clients that don't declare a region are placed in the one their IP is
+//! located in, see `geoip`. Every `REGION_REPORT_INTERVAL_SECS`, the number of queued players per region
+//! is logged and sent to the webhooks, for capacity planning.
+//!
+//! Servers in different regions can be federated to share summaries of their queues. Clients that have
+//! waited long without a single player here matching them are sent OtherRegions with the regions that
+//! have players queued and the round trip to their servers, see `federation`.
+//!
+//! Players can be given priority tiers by a roster of their player IDs, and players that return after timing out while
+//! queued can be given one as well, see `priority`. Higher tiers are placed ahead in the queue, and clients
+//! are sent the tiers of the players they are proposed with Standings.
+//!
+//! A league can reserve a match between two player IDs, see `Server::reserve`. Once the match starts,
+//! both players are held out of the rest of the queue until they have both queued and identified,
+//! and they are then sent Scheduled with each other's address.
+//!
+//! The server doesn't send a large response to a spoofed source address, which would make it
+//! an amplifier for denial of service attacks: the queue, whose peer list grows with the queue,
+//! is only joined after the client has echoed a cookie sent to its address, and the server sends at most
+//! `AMPLIFICATION_LIMIT` times the bytes it has received to a source that hasn't been verified.
+//!
+//! Rooms whose games have already started can be backfilled: every `BACKFILL_INTERVAL_MILLIS`, the queued
+//! clients are sent Backfill with the rooms that have free places and whose hosts they match, and can join
+//! one with JoinRoom, e.g. for casual formats that rotate players.
+//!
+//! The clients that queued are announced to the rest of the queue in batches, every `BATCH_INTERVAL_MILLIS`.
+//! Every `STATUS_INTERVAL_MILLIS`, the queued clients are sent QueueStatus with their place in the queue
+//! and how much longer they are expected to wait, see `wait`.
+//!
+//! Before a deployment, the server can be drained with `Server::drain` or a `DrainHandle` while it runs:
+//! it stops queueing new clients, but the queued ones keep being matched and the relays keep running.
+//! Once the queue has emptied and no relays are active, the server is drained and can be shut down
+//! without disrupting anyone, which is logged and sent to the webhooks.
+//!
+//! The rate limits, the queue cap, the matchmaking windows and the log level can be changed while the
+//! server runs, without dropping the queue, with a configuration file that's reloaded when it changes,
+//! see `config`.
+//!
+//! The queue, the rooms and the ratings can be saved to a snapshot and restored when the server restarts,
+//! so the queued clients don't have to queue again, see `snapshot`.
+//!
+//! The server is driven by a `Server`, which handles the events of any transport one at a time.
+//! When run, the packets are sent on `SEND_WORKERS` threads so that a slow send to one client
+//! doesn't hold up handling the messages of the others.
+
+pub mod bandwidth;
+pub mod config;
+pub mod criteria;
+pub mod federation;
+pub mod friends;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+pub mod history;
+pub mod invites;
+#[cfg(feature = "scripting")]
+pub mod policy;
+pub mod priority;
+pub mod quality;
+pub mod ratings;
+pub mod replays;
+pub mod snapshot;
+pub mod wait;
+pub mod webhooks;
+
+use bandwidth::{RelayAccounting, RelayLimits, RELAY_REPORT_INTERVAL_SECS};
+use config::{Config, ConfigFile};
+use criteria::Relaxation;
+use crossbeam_channel::{select, tick, unbounded, Sender};
+use federation::{Federation, OFFER_AFTER_SECS};
+use friends::Friends;
+use history::HistoryStore;
+use invites::Invites;
+use mirai_core::crypto::player_handle;
+use mirai_core::logging::targets::{SERVER_QUEUE, SERVER_RELAY};
+use mirai_core::logging::{debug, info, set_max_level, trace, warn};
+use mirai_core::transport::{Packet, SendHealth, Transport, TransportError, TransportEvent};
+use mirai_core::v1::server::*;
+use mirai_core::v1::{
+    streams, LeaderboardQuery, MatchRecord, MatchReport, PeerListSeq, Presence, Profile,
+    QueueStanding, QueueStatus, RejectReason, Room,
+};
+use priority::{Disconnected, Priorities};
+use quality::MatchQuality;
+use ratings::{RatingSystem, Ratings};
+use replays::ReplayStore;
+use snafu::{ResultExt, Snafu};
+use snapshot::{QueuedClient, Snapshot, RESTORE_GRACE_SECS, SNAPSHOT_INTERVAL_SECS};
+use std::cmp::Reverse;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+};
+use wait::WaitTimes;
+use webhooks::{Event, Participant, Webhooks};
+
+/// How often the clients that have queued are announced to the rest of the queue.
+pub const BATCH_INTERVAL_MILLIS: u64 = 50;
+
+/// The amount of threads sending packets while the server is running.
+pub const SEND_WORKERS: usize = 4;
+
+/// How many times the bytes received from an unverified source the server may send to it.
+pub const AMPLIFICATION_LIMIT: usize = 3;
+
+/// How long a cookie is valid for, it is accepted until the end of the next period.
+pub const COOKIE_PERIOD_SECS: u64 = 30;
+
+/// How many unverified sources are tracked, the rest aren't answered until some time out.
+pub const MAX_UNVERIFIED: usize = 10_000;
+
+/// How often the queued players that match each other since the criteria relaxed are announced.
+pub const RELAXATION_INTERVAL_MILLIS: u64 = 1000;
+
+/// How often the queued clients are sent their place in the queue and estimated wait.
+pub const STATUS_INTERVAL_MILLIS: u64 = 5000;
+
+/// How often the number of queued players per region is logged and sent to the webhooks.
+pub const REGION_REPORT_INTERVAL_SECS: u64 = 60;
+
+/// How often the queued clients are offered the rooms with free places they match.
+pub const BACKFILL_INTERVAL_MILLIS: u64 = 5000;
+
+/// The most rooms returned to a client browsing for a game.
+pub const MAX_LISTED_ROOMS: usize = 100;
+
+/// The longest room name or region in bytes, longer ones are ignored.
+pub const MAX_ROOM_TEXT_LEN: usize = 64;
+
+/// The largest replay the server stores in bytes.
+/// Replays larger than the transport's packets are uploaded over TCP or a chunking transport.
+pub const MAX_REPLAY_SIZE: usize = 1024 * 1024;
+
+/// How long after its start a reserved match waits for both players to queue.
+pub const RESERVATION_WINDOW_SECS: u64 = 15 * 60;
+
+/// The longest player ID in bytes.
+pub const MAX_PLAYER_ID_LEN: usize = 64;
+
+/// The most matches of a player's history returned to a client.
+pub const MAX_HISTORY_MATCHES: usize = 20;
+
+/// The most opponents in a reported match, reports with more are ignored.
+pub const MAX_REPORTED_OPPONENTS: usize = 16;
+
+/// The most players returned for a leaderboard query.
+pub const MAX_LEADERBOARD_PAGE: u32 = 50;
+
+/// How long the first report of a match is remembered, so the other players' reports aren't
+/// announced to the webhooks as new matches.
+pub const MATCH_CREATED_WINDOW_SECS: u64 = 60;
+
+/// How long the server has to go without hearing from a queued client before a newer client identifying
+/// with the same player ID supersedes it. Queued clients send a resume request every second.
+pub const SUPERSEDE_AFTER_SECS: u64 = 3;
+
+/// How many queued clients have to report a client's challenges unanswered before it's marked idle.
+pub const IDLE_REPORTS: usize = 2;
+
+/// In larger queues, at least one in this many of the clients a client is proposed to have to report it
+/// before it's marked idle.
+pub const IDLE_REPORT_SHARE: usize = 2;
+
+/// How long a report of an unanswered challenge counts towards marking the client idle.
+pub const IDLE_REPORT_WINDOW_SECS: u64 = 60;
+
+/// How many times a queued client may refresh its peers with a queue request per `REFRESH_WINDOW_SECS`,
+/// unless configured otherwise, see `config`.
+pub const MAX_REFRESHES: u32 = 5;
+
+/// How long the window `MAX_REFRESHES` applies to lasts, unless configured otherwise.
+pub const REFRESH_WINDOW_SECS: u64 = 10;
+
+/// A match scheduled between two players, identified by the IDs they send with `Identify`.
+/// The IDs aren't authenticated, so they should be tokens only given to the players.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reservation {
+    pub players: [String; 2],
+    pub start: SystemTime,
+}
+
+impl Reservation {
+    /// Parses a reservation in the form `player player start`,
+    /// where the start is in seconds since the Unix epoch.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.split_whitespace();
+        let first = parts.next()?.to_string();
+        let second = parts.next()?.to_string();
+        let start = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            players: [first, second],
+            start: UNIX_EPOCH + Duration::from_secs(start),
+        })
+    }
+
+    fn is_open(&self, now: SystemTime) -> bool {
+        now >= self.start && now < self.start + Duration::from_secs(RESERVATION_WINDOW_SECS)
+    }
+}
+
+/// Drains a running server from another thread, see `Server::drain_handle`.
+#[derive(Debug, Clone)]
+pub struct DrainHandle {
+    draining: Arc<AtomicBool>,
+}
+
+impl DrainHandle {
+    /// Stops queueing new clients.
+    pub fn drain(&self) {
+        self.draining.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// Whether the server is draining.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// Runs the server on the given transport until the transport is closed.
+/// # Errors
+/// If there is an issue serializing or sending a message.
+pub fn with_transport<T: Transport>(transport: T) -> Result<(), ServerError> {
+    Server::new(transport).run()
+}
+
+/// The matchmaking server.
+pub struct Server<T: Transport> {
+    transport: Arc<T>,
+    // set while running, otherwise packets are sent right away
+    workers: Option<SendWorkers>,
+    queue: HashSet<SocketAddr>,
+    // queued since the last batch
+    joined: HashSet<SocketAddr>,
+    // the ports forwarded to clients by their routers
+    mapped: HashMap<SocketAddr, u16>,
+    relay: bool,
+    // (client, peer) pairs where the client asked for packets to the peer to be relayed
+    relay_requests: HashSet<(SocketAddr, SocketAddr)>,
+    // the peer each relayed packet goes to, keyed by its sender and the name the sender knows the peer by,
+    // for the pairs that both requested relaying
+    relay_routes: HashMap<(SocketAddr, SocketAddr), SocketAddr>,
+    // the addresses the relaying clients know each other by, kept after they dequeue
+    relay_names: HashMap<SocketAddr, SocketAddr>,
+    relays: RelayAccounting,
+    last_relay_report: Instant,
+    // keys the cookies, so they can't be forged without receiving them
+    cookie_key: RandomState,
+    // the sources that have echoed a cookie
+    verified: HashSet<SocketAddr>,
+    // the bytes received from and sent to sources that haven't
+    unverified: HashMap<SocketAddr, (usize, usize)>,
+    // the rooms hosted by queued clients
+    rooms: HashMap<SocketAddr, Room>,
+    // the clients that joined each host's room through the server
+    room_members: HashMap<SocketAddr, Vec<SocketAddr>>,
+    last_backfill: Instant,
+    replays: Option<Box<dyn ReplayStore>>,
+    history: Option<Box<dyn HistoryStore>>,
+    ratings: Option<Ratings>,
+    // the clients that were matched and haven't reported the match yet
+    unreported: HashSet<SocketAddr>,
+    webhooks: Option<Webhooks>,
+    // the queue size announced as a spike, and whether the queue has grown to it
+    queue_spike: usize,
+    spiking: bool,
+    // the IPs of the players of recently created matches
+    created: HashMap<Vec<IpAddr>, Instant>,
+    reservations: Vec<Reservation>,
+    // the player IDs of queued clients
+    identities: HashMap<SocketAddr, String>,
+    // the party tokens of clients
+    parties: HashMap<SocketAddr, u64>,
+    relaxation: Option<Relaxation>,
+    profiles: HashMap<SocketAddr, Profile>,
+    queued_at: HashMap<SocketAddr, Instant>,
+    // when the clients that match since the criteria relaxed were last announced
+    last_relaxed: Instant,
+    #[cfg(feature = "scripting")]
+    policy: Option<policy::ScriptPolicy>,
+    // the IPs of the opponents in the last match each client reported, for the policy
+    #[cfg(feature = "scripting")]
+    last_opponents: HashMap<SocketAddr, Vec<std::net::IpAddr>>,
+    // how long the matched clients waited
+    waits: WaitTimes,
+    last_status: Instant,
+    #[cfg(feature = "geoip")]
+    geoip: Option<geoip::GeoIp>,
+    last_region_report: Instant,
+    federation: Option<Federation>,
+    // the queued clients that have been offered the other regions
+    offered: HashSet<SocketAddr>,
+    // the queued clients by their resume tokens, and the other way around
+    resume_tokens: HashMap<u64, SocketAddr>,
+    resume_token_of: HashMap<SocketAddr, u64>,
+    // when each queued client was last heard from
+    last_heard: HashMap<SocketAddr, Instant>,
+    // the queued clients that left challenges unanswered, and when the clients that reported them did so
+    unresponsive: HashMap<SocketAddr, HashMap<SocketAddr, Instant>>,
+    // the queued clients that are no longer proposed to others until they are active again
+    idle: HashSet<SocketAddr>,
+    dequeue_idle: bool,
+    priorities: Priorities,
+    // the identified players that timed out while queued, and the queued clients that returned since
+    disconnected: Disconnected,
+    returning: HashSet<SocketAddr>,
+    friends: Friends,
+    // the identified clients that sent their friend list, queued or not
+    sharing_presence: HashSet<SocketAddr>,
+    invites: Invites,
+    // the scores of the matches between each pair of networks
+    quality: MatchQuality,
+    // when the current refresh window of each queued client started, and how often it has refreshed in it
+    refreshes: HashMap<SocketAddr, (Instant, u32)>,
+    max_refreshes: u32,
+    refresh_window: Duration,
+    // numbers the updates of the clients' peer lists, see `PeerListSeq`
+    epoch: u64,
+    // the number of each queued client's next update
+    peer_list_seqs: HashMap<SocketAddr, u64>,
+    max_queue: usize,
+    config: Option<ConfigFile>,
+    draining: DrainHandle,
+    // whether the server was announced as drained
+    drained: bool,
+    snapshots: Option<PathBuf>,
+    last_snapshot: Instant,
+    // the clients restored from a snapshot that haven't contacted the server since, and when they were restored
+    restored: HashSet<SocketAddr>,
+    restored_at: Instant,
+}
+
+impl<T: Transport> Server<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport: Arc::new(transport),
+            workers: None,
+            queue: HashSet::new(),
+            joined: HashSet::new(),
+            mapped: HashMap::new(),
+            relay: false,
+            relay_requests: HashSet::new(),
+            relay_routes: HashMap::new(),
+            relay_names: HashMap::new(),
+            relays: RelayAccounting::default(),
+            last_relay_report: Instant::now(),
+            cookie_key: RandomState::new(),
+            verified: HashSet::new(),
+            unverified: HashMap::new(),
+            rooms: HashMap::new(),
+            room_members: HashMap::new(),
+            last_backfill: Instant::now(),
+            replays: None,
+            history: None,
+            ratings: None,
+            unreported: HashSet::new(),
+            webhooks: None,
+            queue_spike: usize::MAX,
+            spiking: false,
+            created: HashMap::new(),
+            reservations: Vec::new(),
+            identities: HashMap::new(),
+            parties: HashMap::new(),
+            relaxation: None,
+            profiles: HashMap::new(),
+            queued_at: HashMap::new(),
+            last_relaxed: Instant::now(),
+            #[cfg(feature = "scripting")]
+            policy: None,
+            #[cfg(feature = "scripting")]
+            last_opponents: HashMap::new(),
+            waits: WaitTimes::default(),
+            last_status: Instant::now(),
+            #[cfg(feature = "geoip")]
+            geoip: None,
+            last_region_report: Instant::now(),
+            federation: None,
+            offered: HashSet::new(),
+            resume_tokens: HashMap::new(),
+            resume_token_of: HashMap::new(),
+            last_heard: HashMap::new(),
+            unresponsive: HashMap::new(),
+            idle: HashSet::new(),
+            dequeue_idle: false,
+            priorities: Priorities::default(),
+            disconnected: Disconnected::default(),
+            returning: HashSet::new(),
+            friends: Friends::default(),
+            sharing_presence: HashSet::new(),
+            invites: Invites::default(),
+            quality: MatchQuality::default(),
+            refreshes: HashMap::new(),
+            max_refreshes: MAX_REFRESHES,
+            refresh_window: Duration::from_secs(REFRESH_WINDOW_SECS),
+            epoch: RandomState::new().build_hasher().finish(),
+            peer_list_seqs: HashMap::new(),
+            max_queue: usize::MAX,
+            config: None,
+            draining: DrainHandle {
+                draining: Arc::new(AtomicBool::new(false)),
+            },
+            drained: false,
+            snapshots: None,
+            last_snapshot: Instant::now(),
+            restored: HashSet::new(),
+            restored_at: Instant::now(),
+        }
+    }
+
+    /// Relays packets between matched clients that request it.
+    pub fn enable_relay(&mut self) {
+        self.relay = true;
+    }
+
+    /// Caps the bandwidth of each relay, see `bandwidth`.
+    pub fn limit_relays(&mut self, limits: RelayLimits) {
+        self.relays = RelayAccounting::new(limits);
+    }
+
+    /// The bytes relayed so far, see `bandwidth`.
+    pub fn relays(&self) -> &RelayAccounting {
+        &self.relays
+    }
+
+    /// Dequeues the clients marked idle instead of only hiding them from the rest of the queue.
+    pub fn dequeue_idle(&mut self) {
+        self.dequeue_idle = true;
+    }
+
+    /// Places the players with higher priority tiers ahead of the others, see `priority`.
+    pub fn prioritize(&mut self, priorities: Priorities) {
+        self.priorities = priorities;
+    }
+
+    /// Stores the replays uploaded by clients in the given store.
+    pub fn store_replays(&mut self, store: impl ReplayStore + 'static) {
+        self.replays = Some(Box::new(store));
+    }
+
+    /// Keeps the matches reported by identified clients in the given store.
+    pub fn store_history(&mut self, store: impl HistoryStore + 'static) {
+        self.history = Some(Box::new(store));
+    }
+
+    /// Only proposes players to each other if the policy script allows it, see `policy`.
+    #[cfg(feature = "scripting")]
+    pub fn apply_policy(&mut self, policy: policy::ScriptPolicy) {
+        self.policy = Some(policy);
+    }
+
+    /// Places the clients that don't declare a region in the one their IP is located in, see `geoip`.
+    #[cfg(feature = "geoip")]
+    pub fn locate_clients(&mut self, geoip: geoip::GeoIp) {
+        self.geoip = Some(geoip);
+    }
+
+    /// Shares summaries of the queue with the servers of other regions, see `federation`.
+    pub fn federate(&mut self, federation: Federation) {
+        self.federation = Some(federation);
+    }
+
+    /// Sends the server's events to the webhooks, announcing a spike when the queue grows
+    /// to the given size, see `webhooks`.
+    pub fn notify_webhooks(&mut self, webhooks: Webhooks, queue_spike: usize) {
+        self.webhooks = Some(webhooks);
+        self.queue_spike = queue_spike.max(1);
+    }
+
+    /// Rates the identified players by the matches they report with the given system,
+    /// and answers leaderboard queries, see `ratings`.
+    pub fn rate_players(&mut self, system: RatingSystem) {
+        self.ratings = Some(Ratings::new(system));
+    }
+
+    /// Schedules a match between the two players, see `Reservation`.
+    pub fn reserve(&mut self, reservation: Reservation) {
+        self.reservations.push(reservation);
+    }
+
+    /// Only proposes players to each other if they match each other's profiles,
+    /// with criteria that relax the longer they wait, see `criteria`.
+    pub fn relax_criteria(&mut self, relaxation: Relaxation) {
+        self.relaxation = Some(relaxation);
+    }
+
+    /// Applies the settings in the configuration, see `config`. The queue is kept as it is,
+    /// e.g. clients over a lowered `max_queue` stay queued, and new ones are rejected until it has shrunk.
+    pub fn configure(&mut self, config: &Config) {
+        if let Some(level) = config.log_level {
+            set_max_level(level);
+        }
+        if let Some(max_queue) = config.max_queue {
+            self.max_queue = max_queue;
+        }
+        if let Some(max_refreshes) = config.max_refreshes {
+            self.max_refreshes = max_refreshes;
+        }
+        if let Some(refresh_window) = config.refresh_window {
+            self.refresh_window = refresh_window;
+        }
+        if let Some(limits) = config.relay_limits {
+            self.relays.set_limits(limits);
+        }
+        if let Some(relaxation) = config.criteria {
+            self.relaxation = Some(relaxation);
+        }
+        if let Some(queue_spike) = config.queue_spike {
+            self.queue_spike = queue_spike.max(1);
+        }
+    }
+
+    /// Applies the configuration in the file, and again whenever the file changes, see `config`.
+    pub fn watch_config(&mut self, file: ConfigFile) {
+        self.configure(file.config());
+        self.config = Some(file);
+    }
+
+    /// Stops queueing new clients, which are sent Rejected(Draining), while the queued ones keep
+    /// being matched, e.g. before a deployment.
+    pub fn drain(&self) {
+        self.draining.drain();
+    }
+
+    /// A handle that drains the server once it runs.
+    pub fn drain_handle(&self) -> DrainHandle {
+        self.draining.clone()
+    }
+
+    /// Whether the server is draining and has no queued clients or active relays left,
+    /// so it can be shut down.
+    pub fn is_drained(&self) -> bool {
+        self.draining.is_draining() && self.queue.is_empty() && self.relays.usage().is_empty()
+    }
+
+    /// The queue, the rooms and the ratings, to be restored after a restart, see `snapshot`.
+    pub fn snapshot(&self) -> Snapshot {
+        let now = Instant::now();
+        let queue = self
+            .queue
+            .iter()
+            .map(|&client| QueuedClient {
+                addr: client,
+                mapped: self.mapped.get(&client).copied(),
+                identity: self.identities.get(&client).cloned(),
+                party: self.parties.get(&client).copied(),
+                profile: self.profiles.get(&client).cloned(),
+                resume_token: self.resume_token_of.get(&client).copied(),
+                waited: self
+                    .queued_at
+                    .get(&client)
+                    .map(|&queued_at| now.saturating_duration_since(queued_at))
+                    .unwrap_or_default(),
+            })
+            .collect();
+        Snapshot {
+            saved_at: SystemTime::now(),
+            queue,
+            rooms: self
+                .rooms
+                .iter()
+                .map(|(&host, room)| (host, room.clone()))
+                .collect(),
+            ratings: self
+                .ratings
+                .as_ref()
+                .map(|ratings| ratings.save(now))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Restores the state saved in the snapshot, see `snapshot`. The ratings are only restored if
+    /// the server rates players, and the queue and rooms only if the snapshot is fresh.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        let now = Instant::now();
+        let age = snapshot.age();
+        let fresh = snapshot.is_fresh();
+        if let Some(ratings) = &mut self.ratings {
+            ratings.restore(snapshot.ratings, age, now);
+        }
+        if !fresh {
+            info!("not restoring the queue of a snapshot saved {:?} ago", age);
+            return;
+        }
+        for client in snapshot.queue {
+            let addr = client.addr;
+            // a client without a resume token couldn't resume, so it would only be dequeued later
+            let resume_token = match client.resume_token {
+                Some(resume_token) => resume_token,
+                None => {
+                    debug!("not restoring {}, which has no resume token", addr);
+                    continue;
+                }
+            };
+            self.queue.insert(addr);
+            self.verified.insert(addr);
+            self.restored.insert(addr);
+            self.last_heard.insert(addr, now);
+            if let Some(port) = client.mapped {
+                self.mapped.insert(addr, port);
+            }
+            if let Some(id) = client.identity {
+                self.identities.insert(addr, id);
+            }
+            if let Some(token) = client.party {
+                self.parties.insert(addr, token);
+            }
+            if let Some(profile) = client.profile {
+                self.profiles.insert(addr, profile);
+            }
+            self.resume_tokens.insert(resume_token, addr);
+            self.resume_token_of.insert(addr, resume_token);
+            let waited = client.waited + age;
+            self.queued_at
+                .insert(addr, now.checked_sub(waited).unwrap_or(now));
+        }
+        for (host, room) in snapshot.rooms {
+            if self.queue.contains(&host) {
+                self.rooms.insert(host, room);
+            }
+        }
+        self.restored_at = now;
+        info!("restored {} queued clients", self.restored.len());
+    }
+
+    /// Saves a snapshot at the path every `SNAPSHOT_INTERVAL_SECS` and once the server stops,
+    /// see `snapshot`.
+    pub fn save_snapshots(&mut self, path: impl Into<PathBuf>) {
+        self.snapshots = Some(path.into());
+    }
+
+    fn save_snapshot(&mut self) {
+        self.last_snapshot = Instant::now();
+        if let Some(path) = &self.snapshots {
+            if let Err(e) = self.snapshot().save(path) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    // dequeues the restored clients that haven't contacted the server in time, as if they timed out
+    fn dequeue_unheard(&mut self) -> Result<(), ServerError> {
+        let grace = Duration::from_secs(RESTORE_GRACE_SECS);
+        if self.restored.is_empty() || self.restored_at.elapsed() < grace {
+            return Ok(());
+        }
+        for client in std::mem::take(&mut self.restored) {
+            if self.queue.contains(&client) {
+                debug!(target: SERVER_QUEUE, "{} didn't return after the restart", client);
+                self.handle_event(TransportEvent::Timeout(client))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles events until the transport is closed.
+    /// # Errors
+    /// If there is an issue serializing or sending a message.
+    pub fn run(mut self) -> Result<(), ServerError> {
+        info!("started server");
+        self.workers = Some(SendWorkers::new(&self.transport, SEND_WORKERS));
+        let events = self.transport.events().clone();
+        let ticker = tick(Duration::from_millis(BATCH_INTERVAL_MILLIS));
+        loop {
+            select! {
+                recv(events) -> event => match event {
+                    Ok(event) => self.handle_event(event)?,
+                    Err(_) => {
+                        self.save_snapshot();
+                        return Ok(());
+                    }
+                },
+                recv(ticker) -> _ => self.flush()?,
+            }
+        }
+    }
+
+    /// Handles a single event from the transport.
+    /// # Errors
+    /// If there is an issue serializing or sending a message.
+    pub fn handle_event(&mut self, event: TransportEvent) -> Result<(), ServerError> {
+        match event {
+            TransportEvent::Packet(packet) => {
+                let source = packet.addr();
+                trace!("received packet from {}", source);
+                self.restored.remove(&source);
+                if let Some(heard) = self.last_heard.get_mut(&source) {
+                    *heard = Instant::now();
+                }
+                let delivery = packet.delivery();
+                let payload = packet.payload();
+                self.received_from(source, payload.len());
+                // try to deserialize the payload
+                match bincode::deserialize::<FromClient>(payload) {
+                    Ok(msg) => match msg {
+                        FromClient::StatusCheck => {
+                            debug!("received status check");
+                            let msg =
+                                bincode::serialize(&ToClient::Alive).context(SerializeError)?;
+                            self.reply(Packet::reliable_ordered(source, msg, streams::CONTROL))?;
+                            trace!("sent response");
+                        }
+                        FromClient::Queue
+                        | FromClient::QueueMapped(_)
+                        | FromClient::ListRooms
+                        | FromClient::UploadReplay(_)
+                        | FromClient::FetchReplay(_)
+                        | FromClient::FetchHistory(_)
+                        | FromClient::Leaderboard(_)
+                        | FromClient::Friends(_)
+                        | FromClient::CreateInvite
+                        | FromClient::RedeemInvite(_)
+                            if !self.verified.contains(&source) =>
+                        {
+                            debug!("received request from unverified source");
+                            let cookie = self.cookie(source, cookie_period());
+                            let msg = bincode::serialize(&ToClient::Cookie(cookie))
+                                .context(SerializeError)?;
+                            self.reply(Packet::reliable_ordered(source, msg, streams::CONTROL))?;
+                        }
+                        FromClient::Queue | FromClient::QueueMapped(_) => {
+                            if let Some(reason) = self.rejection(source) {
+                                debug!(target: SERVER_QUEUE, "rejected queue request: {:?}", reason);
+                                let msg = bincode::serialize(&ToClient::Rejected(reason))
+                                    .context(SerializeError)?;
+                                self.send(Packet::reliable_ordered(source, msg, streams::CONTROL))?;
+                            } else {
+                                if let FromClient::QueueMapped(port) = msg {
+                                    debug!(target: SERVER_QUEUE, "received queue request with port {}", port);
+                                    self.mapped.insert(source, port);
+                                } else {
+                                    debug!(target: SERVER_QUEUE, "received queue request");
+                                    self.mapped.remove(&source);
+                                }
+                                self.queue_client(source)?;
+                            }
+                        }
+                        FromClient::Cookie(cookie) => {
+                            let period = cookie_period();
+                            let valid = cookie == self.cookie(source, period)
+                                || cookie == self.cookie(source, period.wrapping_sub(1));
+                            if valid && self.verified.insert(source) {
+                                debug!("verified {}", source);
+                                self.unverified.remove(&source);
+                                let msg = bincode::serialize(&ToClient::YourAddr(source))
+                                    .context(SerializeError)?;
+                                self.send(Packet::reliable_ordered(source, msg, streams::CONTROL))?;
+                            }
+                        }
+                        FromClient::Dequeue => {
+                            debug!(target: SERVER_QUEUE, "received dequeue request");
+                            self.dequeue_client(source);
+                        }
+                        FromClient::Heartbeat => { /* heartbeat, ignore */ }
+                        FromClient::RequestRelay(peer) => {
+                            debug!(target: SERVER_RELAY, "received relay request for {}", peer);
+                            self.request_relay(source, peer)?;
+                        }
+                        FromClient::Relay { to, payload } => {
+                            trace!(target: SERVER_RELAY, "received packet to relay to {}", to);
+                            if let Some(&recipient) = self.relay_routes.get(&(source, to)) {
+                                let msg = bincode::serialize(&ToClient::Relayed {
+                                    from: self.relay_name(source),
+                                    payload,
+                                })
+                                .context(SerializeError)?;
+                                let now = Instant::now();
+                                if self.relays.relay(source, recipient, msg.len(), now) {
+                                    self.send(Packet::new(recipient, msg, delivery))?;
+                                } else {
+                                    trace!(target: SERVER_RELAY, "dropped a packet over the relay's cap");
+                                }
+                            }
+                        }
+                        FromClient::OpenRoom(room) => {
+                            let valid = room.name.len() <= MAX_ROOM_TEXT_LEN
+                                && room.region.len() <= MAX_ROOM_TEXT_LEN
+                                && room.players <= room.max_players;
+                            if valid && self.queue.contains(&source) {
+                                debug!("{} opened room {}", source, room.name);
+                                self.rooms.insert(source, room);
+                            }
+                        }
+                        FromClient::CloseRoom => {
+                            debug!("{} closed its room", source);
+                            self.rooms.remove(&source);
+                            self.room_members.remove(&source);
+                        }
+                        FromClient::JoinRoom(host) => {
+                            debug!("{} asked to join the room of {}", source, host);
+                            self.join_room(source, host)?;
+                        }
+                        FromClient::ListRooms => {
+                            debug!("received room list request");
+                            let rooms = self
+                                .rooms
+                                .iter()
+                                .take(MAX_LISTED_ROOMS)
+                                .map(|(&host, room)| (self.advertised(host), room.clone()))
+                                .collect();
+                            let msg = bincode::serialize(&ToClient::Rooms(rooms))
+                                .context(SerializeError)?;
+                            self.send(Packet::reliable_ordered(source, msg, streams::CONTROL))?;
+                        }
+                        FromClient::UploadReplay(replay) => {
+                            debug!("received replay of {} bytes", replay.len());
+                            let stored = match &mut self.replays {
+                                Some(store) if replay.len() <= MAX_REPLAY_SIZE => {
+                                    match store.store(&replay) {
+                                        Ok(id) => Some(id),
+                                        Err(e) => {
+                                            warn!("failed to store replay: {}", e);
+                                            None
+                                        }
+                                    }
+                                }
+                                _ => None,
+                            };
+                            let msg = match stored {
+                                Some(id) => ToClient::ReplayStored(id),
+                                None => ToClient::ReplayRejected,
+                            };
+                            let msg = bincode::serialize(&msg).context(SerializeError)?;
+                            self.send(Packet::reliable_ordered(source, msg, streams::CONTROL))?;
+                        }
+                        FromClient::FetchReplay(id) => {
+                            debug!("received request for replay {}", id);
+                            let replay = match &self.replays {
+                                Some(store) => store.load(id).unwrap_or_else(|e| {
+                                    warn!("failed to load replay {}: {}", id, e);
+                                    None
+                                }),
+                                None => None,
+                            };
+                            let msg = bincode::serialize(&ToClient::Replay { id, replay })
+                                .context(SerializeError)?;
+                            self.send(Packet::reliable_ordered(source, msg, streams::CONTROL))?;
+                        }
+                        FromClient::Identify(id) => {
+                            if self.verified.contains(&source)
+                                && id.len() <= MAX_PLAYER_ID_LEN
+                                && self.may_hold(source, &id)?
+                            {
+                                debug!(target: SERVER_QUEUE, "{} identified as {}", source, id);
+                                self.identities.insert(source, id);
+                            }
+                        }
+                        FromClient::Supersede(token) => {
+                            // the token proves the stale session was the client's
+                            let stale = self.resume_tokens.get(&token).copied();
+                            if let (true, Some(stale)) = (self.verified.contains(&source), stale) {
+                                if stale != source {
+                                    self.supersede(source, stale)?;
+                                }
+                            }
+                        }
+                        FromClient::Friends(friends) => {
+                            if let Some(id) = self.identities.get(&source).cloned() {
+                                debug!(
+                                    "{} shared its presence with {} friends",
+                                    source,
+                                    friends.len()
+                                );
+                                self.friends.set(id.clone(), friends);
+                                self.sharing_presence.insert(source);
+                                let presences = self.presences();
+                                let friends = self
+                                    .friends
+                                    .mutual(&id)
+                                    .into_iter()
+                                    .map(|friend| {
+                                        let presence = presences
+                                            .get(&friend)
+                                            .map_or(Presence::Offline, |&(presence, _)| presence);
+                                        (friend, presence)
+                                    })
+                                    .collect();
+                                let msg = bincode::serialize(&ToClient::Presence(friends))
+                                    .context(SerializeError)?;
+                                self.send(Packet::reliable_ordered(source, msg, streams::CONTROL))?;
+                            }
+                        }
+                        FromClient::ChallengeFriend(friend) => {
+                            self.challenge_friend(source, friend)?;
+                        }
+                        FromClient::CreateInvite => {
+                            debug!("{} created an invite", source);
+                            let code = self.invites.create(source, Instant::now());
+                            let msg = bincode::serialize(&ToClient::Invite(code))
+                                .context(SerializeError)?;
+                            self.send(Packet::reliable_ordered(source, msg, streams::CONTROL))?;
+                        }
+                        FromClient::RedeemInvite(code) => {
+                            self.redeem_invite(source, code)?;
+                        }
+                        FromClient::JoinParty(token) => {
+                            if self.verified.contains(&source) {
+                                debug!("{} joined a party", source);
+                                self.parties.insert(source, token);
+                            }
+                        }
+                        FromClient::LeaveParty => {
+                            self.parties.remove(&source);
+                        }
+                        FromClient::Profile(profile) => {
+                            let region_len = profile.region.as_ref().map_or(0, String::len);
+                            if self.verified.contains(&source) && region_len <= MAX_ROOM_TEXT_LEN {
+                                debug!("{} sent its profile", source);
+                                self.profiles.insert(source, profile);
+                                #[cfg(feature = "geoip")]
+                                self.locate(source);
+                            }
+                        }
+                        FromClient::Matched(opponents) => self.matched(source, &opponents),
+                        FromClient::MatchEnded(report) => {
+                            debug!("received the report of a match");
+                            #[cfg(feature = "scripting")]
+                            {
+                                let ips = report.opponents.iter().map(SocketAddr::ip);
+                                let ips = ips.take(MAX_REPORTED_OPPONENTS).collect();
+                                self.last_opponents.insert(source, ips);
+                            }
+                            if report.opponents.len() <= MAX_REPORTED_OPPONENTS {
+                                // each match is only rated and scored once
+                                let first = self.unreported.remove(&source);
+                                let score = quality::score(&report);
+                                if let (true, Some(score)) = (first, score) {
+                                    for opponent in &report.opponents {
+                                        self.quality.record(source.ip(), opponent.ip(), score);
+                                    }
+                                }
+                                if let Some(webhooks) = &self.webhooks {
+                                    webhooks.notify(&Event::MatchCompleted {
+                                        player: self.participant(source),
+                                        opponents: report
+                                            .opponents
+                                            .iter()
+                                            .filter_map(|&opponent| self.identity_of(opponent))
+                                            .collect(),
+                                        outcome: report.outcome,
+                                        duration_secs: report.duration.as_secs_f64(),
+                                        relayed: report.relayed,
+                                        average_rtt_millis: report
+                                            .average_rtt
+                                            .map(|rtt| rtt.as_secs_f64() * 1000.0),
+                                        quality: score,
+                                    });
+                                }
+                                self.record_match(source, report, first, score);
+                            }
+                        }
+                        FromClient::FetchHistory(player) => {
+                            debug!("received request for the history of {}", player);
+                            let matches = match &self.history {
+                                Some(store) => store
+                                    .recent(&player, MAX_HISTORY_MATCHES)
+                                    .unwrap_or_else(|e| {
+                                        warn!("failed to load the history of {}: {}", player, e);
+                                        vec![]
+                                    }),
+                                None => vec![],
+                            };
+                            let msg = bincode::serialize(&ToClient::History { player, matches })
+                                .context(SerializeError)?;
+                            self.send(Packet::reliable_ordered(source, msg, streams::CONTROL))?;
+                        }
+                        FromClient::Leaderboard(query) => {
+                            debug!("received leaderboard query");
+                            let standings = match &self.ratings {
+                                Some(ratings) => match query {
+                                    LeaderboardQuery::Top { from, count } => {
+                                        let count = count.min(MAX_LEADERBOARD_PAGE);
+                                        ratings.top(from, count, Instant::now())
+                                    }
+                                    LeaderboardQuery::Around { player, count } => {
+                                        let count = count.min(MAX_LEADERBOARD_PAGE);
+                                        ratings.around(&player, count, Instant::now())
+                                    }
+                                },
+                                None => vec![],
+                            };
+                            let msg = bincode::serialize(&ToClient::Leaderboard(standings))
+                                .context(SerializeError)?;
+                            self.send(Packet::reliable_ordered(source, msg, streams::CONTROL))?;
+                        }
+                        FromClient::Resume(token) => match self.resume_tokens.get(&token) {
+                            Some(&client) if client != source => {
+                                info!("{} moved to {}", client, source);
+                                self.migrate(client, source)?;
+                            }
+                            _ => { /* still at the same address, or not queued */ }
+                        },
+                        FromClient::Unresponsive(peer) => {
+                            let (now, wall_clock) = (Instant::now(), SystemTime::now());
+                            // only the clients the peer was proposed to had a reason to challenge it
+                            let reported = self
+                                .queue
+                                .iter()
+                                .find(|&&c| {
+                                    self.advertised(c) == peer
+                                        && self.proposes(source, c, now, wall_clock)
+                                })
+                                .copied();
+                            if let (true, Some(reported)) = (self.queue.contains(&source), reported)
+                            {
+                                debug!(target: SERVER_QUEUE, "{} left a challenge from {} unanswered", reported, source);
+                                self.report_unresponsive(reported, source, now)?;
+                            }
+                        }
+                        FromClient::Resync => {
+                            if self.queue.contains(&source) {
+                                debug!(target: SERVER_QUEUE, "{} missed an update of its peers", source);
+                                self.send_peers(source, Instant::now())?;
+                            }
+                        }
+                        FromClient::Active => {
+                            if self.idle.remove(&source) {
+                                debug!(target: SERVER_QUEUE, "{} is active again", source);
+                                self.unresponsive.remove(&source);
+                                self.joined.insert(source);
+                            }
+                        }
+                        FromClient::QueueSummary(summary) => {
+                            if let Some(federation) = &mut self.federation {
+                                trace!("received queue summary from {}", source);
+                                federation.receive(source, summary, Instant::now());
+                            }
+                        }
+                    },
+                    Err(_) => { /* invalid message */ }
+                }
+            }
+            TransportEvent::Connect(_connect_addr) => {}
+            TransportEvent::Health(health) => match health {
+                SendHealth::Healthy => info!("the packets are getting out again"),
+                _ => warn!("the packets aren't getting out: {:?}", health),
+            },
+            TransportEvent::Timeout(timeout_addr) => {
+                self.end_relays(timeout_addr);
+                let queued = self.queue.contains(&timeout_addr);
+                if let (true, Some(id)) = (queued, self.identities.get(&timeout_addr)) {
+                    self.disconnected.insert(id.clone(), Instant::now());
+                }
+                let advertised = self.advertised(timeout_addr);
+                self.dequeue_client(timeout_addr);
+                if queued {
+                    self.send_dequeued(advertised)?;
+                }
+                self.verified.remove(&timeout_addr);
+                self.unverified.remove(&timeout_addr);
+                self.relay_requests
+                    .retain(|&(client, peer)| client != timeout_addr && peer != timeout_addr);
+                self.relay_routes.retain(|&(client, _), &mut peer| {
+                    client != timeout_addr && peer != timeout_addr
+                });
+                self.relay_names.remove(&timeout_addr);
+                for members in self.room_members.values_mut() {
+                    members.retain(|&member| member != timeout_addr);
+                }
+                self.identities.remove(&timeout_addr);
+                self.sharing_presence.remove(&timeout_addr);
+                self.invites.remove(timeout_addr);
+                self.parties.remove(&timeout_addr);
+                self.profiles.remove(&timeout_addr);
+                self.unreported.remove(&timeout_addr);
+                #[cfg(feature = "scripting")]
+                self.last_opponents.remove(&timeout_addr);
+            }
+        }
+        Ok(())
+    }
+
+    // sends the client the rest of the queue and adds it to the next batch,
+    // or only refreshes its peers if it's already queued
+    fn queue_client(&mut self, client: SocketAddr) -> Result<(), ServerError> {
+        if self.queue.contains(&client) {
+            return self.refresh(client);
+        }
+        if let Some(id) = self.identities.get(&client).cloned() {
+            // the client identified before another client queued with the same ID
+            if !self.may_hold(client, &id)? {
+                self.identities.remove(&client);
+            }
+        }
+        let instant = Instant::now();
+        self.queued_at.entry(client).or_insert(instant);
+        self.last_heard.insert(client, instant);
+        #[cfg(feature = "geoip")]
+        self.locate(client);
+        self.send_peers(client, instant)?;
+        trace!(target: SERVER_QUEUE, "sent response");
+        self.queue.insert(client);
+        let returned = match self.identities.get(&client) {
+            Some(id) => self.disconnected.returned(id, instant),
+            None => false,
+        };
+        if returned {
+            debug!(target: SERVER_QUEUE, "{} returned after a disconnect", client);
+            self.returning.insert(client);
+        }
+        let token = RandomState::new().build_hasher().finish();
+        self.resume_tokens.insert(token, client);
+        self.resume_token_of.insert(client, token);
+        let msg = bincode::serialize(&ToClient::ResumeToken(token)).context(SerializeError)?;
+        self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))?;
+        if self.queue.len() >= self.queue_spike && !self.spiking {
+            self.spiking = true;
+            if let Some(webhooks) = &self.webhooks {
+                webhooks.notify(&Event::QueueSpike {
+                    queued: self.queue.len(),
+                });
+            }
+        }
+        self.joined.insert(client);
+        trace!(target: SERVER_QUEUE, "added to queue");
+        if let Some(&token) = self.parties.get(&client) {
+            let members = self.party_members(token);
+            for &member in &members {
+                let teammates = members
+                    .iter()
+                    .filter(|&&m| m != member)
+                    .map(|&m| self.advertised(m))
+                    .collect();
+                let msg =
+                    bincode::serialize(&ToClient::Party(teammates)).context(SerializeError)?;
+                self.send(Packet::reliable_ordered(member, msg, streams::CONTROL))?;
+            }
+        }
+        Ok(())
+    }
+
+    // why the client's queue request is rejected, if it is
+    fn rejection(&self, client: SocketAddr) -> Option<RejectReason> {
+        if self.queue.contains(&client) {
+            None
+        } else if self.draining.is_draining() {
+            Some(RejectReason::Draining)
+        } else if self.queue.len() >= self.max_queue {
+            Some(RejectReason::QueueFull)
+        } else {
+            None
+        }
+    }
+
+    // sends a queued client its peers again, unless it has refreshed too often lately
+    fn refresh(&mut self, client: SocketAddr) -> Result<(), ServerError> {
+        let now = Instant::now();
+        let (start, count) = self.refreshes.entry(client).or_insert((now, 0));
+        if now.duration_since(*start) >= self.refresh_window {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        if *count > self.max_refreshes {
+            debug!(target: SERVER_QUEUE, "{} refreshed too often, ignoring", client);
+            return Ok(());
+        }
+        trace!(target: SERVER_QUEUE, "refreshing the peers of {}", client);
+        self.send_peers(client, now)
+    }
+
+    // sends the client the queued clients it may be matched with, along with their parties,
+    // standings and player IDs
+    fn send_peers(&mut self, client: SocketAddr, instant: Instant) -> Result<(), ServerError> {
+        let now = SystemTime::now();
+        let peers: Vec<_> = self
+            .queue
+            .iter()
+            .filter(|&&c| self.proposes(client, c, instant, now))
+            .copied()
+            .collect();
+        let parties = self.parties_of(&peers);
+        let advertised = peers.iter().map(|&c| self.advertised(c)).collect();
+        let seq = self.peer_list_seq(client, true);
+        let msg = bincode::serialize(&ToClient::Peers(seq, advertised)).context(SerializeError)?;
+        self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))?;
+        if !parties.is_empty() {
+            let parties = parties.into_iter().map(|(_, members)| members).collect();
+            let msg = bincode::serialize(&ToClient::Parties(parties)).context(SerializeError)?;
+            self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))?;
+        }
+        self.send_standings(client, &peers)?;
+        self.send_peer_handles(client, &peers)
+    }
+
+    // adds the client to the room of the host and tells the room's members about it
+    fn join_room(&mut self, client: SocketAddr, host_name: SocketAddr) -> Result<(), ServerError> {
+        let now = Instant::now();
+        let host = self
+            .rooms
+            .iter()
+            .find(|(&host, room)| {
+                self.advertised(host) == host_name
+                    && host != client
+                    && room.players < room.max_players
+                    && self.compatible(host, client, now)
+            })
+            .map(|(&host, _)| host);
+        let host = match host {
+            Some(host) if self.queue.contains(&client) => host,
+            _ => {
+                let msg =
+                    bincode::serialize(&ToClient::RoomFull(host_name)).context(SerializeError)?;
+                return self.send(Packet::reliable_ordered(client, msg, streams::CONTROL));
+            }
+        };
+        info!("{} joined the room of {}", client, host);
+        let player = self.advertised(client);
+        let members = self.room_members.entry(host).or_default().clone();
+        let msg = bincode::serialize(&ToClient::JoinedRoom {
+            host: host_name,
+            members: members.iter().map(|&m| self.advertised(m)).collect(),
+        })
+        .context(SerializeError)?;
+        self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))?;
+        let msg = bincode::serialize(&ToClient::RoomJoined {
+            host: host_name,
+            player,
+        })
+        .context(SerializeError)?;
+        for &member in std::iter::once(&host).chain(&members) {
+            self.send(Packet::reliable_ordered(
+                member,
+                msg.clone(),
+                streams::CONTROL,
+            ))?;
+        }
+        if let Some(room) = self.rooms.get_mut(&host) {
+            room.players += 1;
+        }
+        if let Some(members) = self.room_members.get_mut(&host) {
+            members.push(client);
+        }
+        self.dequeue_client(client);
+        Ok(())
+    }
+
+    // whether the queued peer is proposed to the client
+    fn proposes(
+        &self,
+        client: SocketAddr,
+        peer: SocketAddr,
+        instant: Instant,
+        now: SystemTime,
+    ) -> bool {
+        peer != client
+            && !self.held(client, now)
+            && !self.held(peer, now)
+            && !self.idle.contains(&peer)
+            && !self.are_teammates(peer, client)
+            && self.compatible(peer, client, instant)
+    }
+
+    fn are_teammates(&self, a: SocketAddr, b: SocketAddr) -> bool {
+        match (self.parties.get(&a), self.parties.get(&b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    // the queued members of the party, sorted
+    fn party_members(&self, token: u64) -> Vec<SocketAddr> {
+        let mut members: Vec<_> = self
+            .queue
+            .iter()
+            .filter(|&c| self.parties.get(c) == Some(&token))
+            .copied()
+            .collect();
+        members.sort();
+        members
+    }
+
+    // the parties of the given clients with the advertised addresses of all of their queued members,
+    // sorted by the members
+    fn parties_of(&self, clients: &[SocketAddr]) -> Vec<(u64, Vec<SocketAddr>)> {
+        let tokens: HashSet<_> = clients.iter().filter_map(|c| self.parties.get(c)).collect();
+        let mut parties: Vec<_> = tokens
+            .into_iter()
+            .map(|&token| {
+                let members = self.party_members(token);
+                (
+                    token,
+                    members
+                        .into_iter()
+                        .map(|m| self.advertised(m))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect();
+        parties.sort_by(|a, b| a.1.cmp(&b.1));
+        parties
+    }
+
+    // whether the client may hold the player ID, which another queued client may hold already:
+    // the other client keeps it, unless it has gone unheard long enough to be superseded
+    fn may_hold(&mut self, client: SocketAddr, id: &str) -> Result<bool, ServerError> {
+        let holder = self
+            .queue
+            .iter()
+            .find(|&&c| c != client && self.identities.get(&c).map(String::as_str) == Some(id))
+            .copied();
+        let holder = match holder {
+            Some(holder) => holder,
+            None => return Ok(true),
+        };
+        let unheard = self
+            .last_heard
+            .get(&holder)
+            .map_or(Duration::MAX, |heard| heard.elapsed());
+        if unheard < Duration::from_secs(SUPERSEDE_AFTER_SECS) {
+            debug!(target: SERVER_QUEUE, "{} identified as {}, which {} holds", client, id, holder);
+            return Ok(false);
+        }
+        self.supersede(client, holder)?;
+        Ok(true)
+    }
+
+    // dequeues the stale session of the client, whose resume token it sent
+    // or whose player ID it identified with after the stale session went unheard
+    fn supersede(&mut self, client: SocketAddr, stale: SocketAddr) -> Result<(), ServerError> {
+        info!(target: SERVER_QUEUE, "{} superseded {}", client, stale);
+        let advertised = self.advertised(stale);
+        self.dequeue_client(stale);
+        if let Some(id) = self.identities.remove(&stale) {
+            // the player is back after losing its previous session
+            self.disconnected.insert(id, Instant::now());
+        }
+        self.send_dequeued(advertised)
+    }
+
+    // tells the queue that the client at the advertised address left it
+    fn send_dequeued(&mut self, advertised: SocketAddr) -> Result<(), ServerError> {
+        let queue: Vec<_> = self.queue.iter().copied().collect();
+        for other in queue {
+            let seq = self.peer_list_seq(other, false);
+            let msg =
+                bincode::serialize(&ToClient::Dequeued(seq, advertised)).context(SerializeError)?;
+            self.send(Packet::reliable_ordered(other, msg, streams::CONTROL))?;
+        }
+        Ok(())
+    }
+
+    // numbers the next update of the client's peer list, a full list starting a new sequence
+    fn peer_list_seq(&mut self, client: SocketAddr, full: bool) -> PeerListSeq {
+        let next = self.peer_list_seqs.entry(client).or_insert(0);
+        if full {
+            *next = 0;
+        }
+        let sequence = *next;
+        *next += 1;
+        PeerListSeq {
+            epoch: self.epoch,
+            sequence,
+        }
+    }
+
+    fn dequeue_client(&mut self, client: SocketAddr) {
+        self.queue.remove(&client);
+        self.joined.remove(&client);
+        self.mapped.remove(&client);
+        self.rooms.remove(&client);
+        self.room_members.remove(&client);
+        self.queued_at.remove(&client);
+        self.last_heard.remove(&client);
+        self.offered.remove(&client);
+        self.unresponsive.remove(&client);
+        self.idle.remove(&client);
+        self.returning.remove(&client);
+        self.refreshes.remove(&client);
+        self.peer_list_seqs.remove(&client);
+        if let Some(token) = self.resume_token_of.remove(&client) {
+            self.resume_tokens.remove(&token);
+        }
+        if self.queue.len() <= self.queue_spike / 2 {
+            self.spiking = false;
+        }
+    }
+
+    // records how long the queued client waited for its match and dequeues it
+    fn matched(&mut self, client: SocketAddr, opponents: &[SocketAddr]) {
+        if let Some(queued) = self.queued_at.get(&client) {
+            debug!(target: SERVER_QUEUE, "{} was matched", client);
+            let rating = self.profiles.get(&client).and_then(|p| p.rating);
+            self.waits.record(rating, queued.elapsed());
+            self.dequeue_client(client);
+            self.unreported.insert(client);
+            self.match_created(client, opponents);
+        }
+    }
+
+    // announces the match to the webhooks, unless another player has already reported it
+    fn match_created(&mut self, client: SocketAddr, opponents: &[SocketAddr]) {
+        let webhooks = match &self.webhooks {
+            Some(webhooks) => webhooks,
+            None => return,
+        };
+        let now = Instant::now();
+        let window = Duration::from_secs(MATCH_CREATED_WINDOW_SECS);
+        self.created
+            .retain(|_, &mut created| now.duration_since(created) < window);
+        let mut ips: Vec<_> = std::iter::once(client)
+            .chain(opponents.iter().copied())
+            .map(|c| c.ip())
+            .collect();
+        ips.sort();
+        if self.created.contains_key(&ips) || opponents.len() > MAX_REPORTED_OPPONENTS {
+            return;
+        }
+        let players = std::iter::once(self.participant(client))
+            .chain(opponents.iter().map(|&opponent| Participant {
+                address: opponent,
+                id: self.identity_of(opponent),
+            }))
+            .collect();
+        webhooks.notify(&Event::MatchCreated { players });
+        self.created.insert(ips, now);
+    }
+
+    // the client as described to the webhooks
+    fn participant(&self, client: SocketAddr) -> Participant {
+        Participant {
+            address: self.advertised(client),
+            id: self.identities.get(&client).cloned(),
+        }
+    }
+
+    // relays between the client and the peer once both have asked for it
+    fn request_relay(&mut self, client: SocketAddr, peer: SocketAddr) -> Result<(), ServerError> {
+        if !self.relay {
+            let msg =
+                bincode::serialize(&ToClient::RelayUnavailable(peer)).context(SerializeError)?;
+            return self.reply(Packet::reliable_ordered(client, msg, streams::CONTROL));
+        }
+        // only relay between queued clients so the server can't be used to reach arbitrary addresses
+        let peer_name = peer;
+        let peer = match self
+            .queue
+            .iter()
+            .find(|&&c| self.advertised(c) == peer_name)
+        {
+            Some(&peer) if self.queue.contains(&client) => peer,
+            _ => return Ok(()),
+        };
+        self.relay_names.insert(client, self.advertised(client));
+        self.relay_requests.insert((client, peer));
+        if self.relay_requests.contains(&(peer, client)) {
+            debug!(target: SERVER_RELAY, "relaying between {} and {}", client, peer);
+            self.relay_routes
+                .insert((client, self.relay_name(peer)), peer);
+            self.relay_routes
+                .insert((peer, self.relay_name(client)), client);
+            for &(to, other) in &[(client, peer), (peer, client)] {
+                let msg = bincode::serialize(&ToClient::RelayReady(self.relay_name(other)))
+                    .context(SerializeError)?;
+                self.send(Packet::reliable_ordered(to, msg, streams::CONTROL))?;
+            }
+            // the clients only ask for a relay once their match is confirmed, i.e. they've exchanged Start,
+            // so they are matched and don't report it themselves
+            debug!(target: SERVER_RELAY, "{} and {} matched through the relay", client, peer);
+            self.matched(client, &[self.advertised(peer)]);
+            self.matched(peer, &[self.advertised(client)]);
+        }
+        Ok(())
+    }
+
+    // moves the queued client to its new address and tells the players that may know its old one
+    fn migrate(&mut self, from: SocketAddr, to: SocketAddr) -> Result<(), ServerError> {
+        let old = self.advertised(from);
+        self.transport.migrate(from, to);
+        // anything the new address was used for before is forgotten
+        self.dequeue_client(to);
+        self.unverified.remove(&to);
+        self.verified.insert(to);
+        for set in &mut [
+            &mut self.queue,
+            &mut self.joined,
+            &mut self.offered,
+            &mut self.unreported,
+            &mut self.idle,
+            &mut self.returning,
+            &mut self.sharing_presence,
+        ] {
+            if set.remove(&from) {
+                set.insert(to);
+            }
+        }
+        move_key(&mut self.mapped, from, to);
+        move_key(&mut self.rooms, from, to);
+        move_key(&mut self.room_members, from, to);
+        for members in self.room_members.values_mut() {
+            for member in members.iter_mut().filter(|member| **member == from) {
+                *member = to;
+            }
+        }
+        move_key(&mut self.identities, from, to);
+        move_key(&mut self.parties, from, to);
+        move_key(&mut self.profiles, from, to);
+        move_key(&mut self.queued_at, from, to);
+        move_key(&mut self.last_heard, from, to);
+        move_key(&mut self.resume_token_of, from, to);
+        move_key(&mut self.unresponsive, from, to);
+        move_key(&mut self.refreshes, from, to);
+        move_key(&mut self.peer_list_seqs, from, to);
+        self.relays.migrate(from, to);
+        self.invites.migrate(from, to);
+        for reporters in self.unresponsive.values_mut() {
+            move_key(reporters, from, to);
+        }
+        #[cfg(feature = "scripting")]
+        move_key(&mut self.last_opponents, from, to);
+        if let Some(&token) = self.resume_token_of.get(&to) {
+            self.resume_tokens.insert(token, to);
+        }
+        let new = self.advertised(to);
+        if self.relay_names.remove(&from).is_some() {
+            self.relay_names.insert(to, new);
+        }
+        self.relay_requests = self
+            .relay_requests
+            .iter()
+            .map(|&(client, peer)| {
+                let moved = |c| if c == from { to } else { c };
+                (moved(client), moved(peer))
+            })
+            .collect();
+        self.relay_routes = self
+            .relay_requests
+            .iter()
+            .filter(|&&(client, peer)| self.relay_requests.contains(&(peer, client)))
+            .map(|&(client, peer)| ((client, self.relay_name(peer)), peer))
+            .collect();
+
+        let msg = bincode::serialize(&ToClient::YourAddr(to)).context(SerializeError)?;
+        self.send(Packet::reliable_ordered(to, msg, streams::CONTROL))?;
+        if new == old {
+            // e.g. a mapped client whose router still forwards the same port
+            return Ok(());
+        }
+        let now = Instant::now();
+        let msg = bincode::serialize(&ToClient::PeerMoved { from: old, to: new })
+            .context(SerializeError)?;
+        for &client in self
+            .queue
+            .iter()
+            .filter(|&&c| c != to && (self.are_teammates(c, to) || self.compatible(c, to, now)))
+        {
+            self.send(Packet::reliable_ordered(
+                client,
+                msg.clone(),
+                streams::CONTROL,
+            ))?;
+        }
+        Ok(())
+    }
+
+    // the presence of the players sharing it, and the clients they share it from
+    fn presences(&self) -> HashMap<String, (Presence, Vec<SocketAddr>)> {
+        let mut presences: HashMap<String, (Presence, Vec<SocketAddr>)> = HashMap::new();
+        for &client in &self.sharing_presence {
+            if let Some(id) = self.identities.get(&client) {
+                let (presence, clients) = presences
+                    .entry(id.clone())
+                    .or_insert((Presence::Online, vec![]));
+                if self.queue.contains(&client) {
+                    *presence = Presence::Queued;
+                }
+                clients.push(client);
+            }
+        }
+        presences
+    }
+
+    // sends the players whose friends' presence changed since the last batch the changes
+    fn send_presence(&mut self) -> Result<(), ServerError> {
+        let presences = self.presences();
+        let changed = self.friends.changed(|id| {
+            presences
+                .get(id)
+                .map_or(Presence::Offline, |&(presence, _)| presence)
+        });
+        let mut updates: HashMap<SocketAddr, Vec<(String, Presence)>> = HashMap::new();
+        for (id, presence, friends) in changed {
+            for friend in friends {
+                for &client in presences
+                    .get(&friend)
+                    .map_or(&[][..], |(_, clients)| clients)
+                {
+                    updates
+                        .entry(client)
+                        .or_default()
+                        .push((id.clone(), presence));
+                }
+            }
+        }
+        for (client, presences) in updates {
+            let msg = bincode::serialize(&ToClient::Presence(presences)).context(SerializeError)?;
+            self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))?;
+        }
+        Ok(())
+    }
+
+    // sends the client and its online friend each other's address, so it can challenge the friend
+    fn challenge_friend(&mut self, client: SocketAddr, friend: String) -> Result<(), ServerError> {
+        let id = match self.identities.get(&client) {
+            Some(id) if self.friends.are_mutual(id, &friend) => id.clone(),
+            _ => return Ok(()),
+        };
+        let target = self
+            .sharing_presence
+            .iter()
+            .find(|&&c| c != client && self.identities.get(&c) == Some(&friend))
+            .copied();
+        if let Some(target) = target {
+            debug!("{} challenged its friend {}", client, friend);
+            let challenge = ToClient::FriendChallenge {
+                friend: id,
+                addr: self.advertised(client),
+            };
+            let addr = ToClient::FriendAddr {
+                friend,
+                addr: self.advertised(target),
+            };
+            for (to, msg) in [(target, challenge), (client, addr)] {
+                let msg = bincode::serialize(&msg).context(SerializeError)?;
+                self.send(Packet::reliable_ordered(to, msg, streams::CONTROL))?;
+            }
+        }
+        Ok(())
+    }
+
+    // sends the client and the creator of the invite each other's address, so the client can challenge it
+    fn redeem_invite(&mut self, client: SocketAddr, code: String) -> Result<(), ServerError> {
+        let messages = match self.invites.redeem(&code, client, Instant::now()) {
+            Some(inviter) => {
+                debug!("{} redeemed the invite of {}", client, inviter);
+                let redeemed = ToClient::InviteRedeemed(self.advertised(client));
+                let host = ToClient::InviteHost {
+                    code,
+                    addr: self.advertised(inviter),
+                };
+                vec![(inviter, redeemed), (client, host)]
+            }
+            None => vec![(client, ToClient::InviteInvalid(code))],
+        };
+        for (to, msg) in messages {
+            let msg = bincode::serialize(&msg).context(SerializeError)?;
+            self.send(Packet::reliable_ordered(to, msg, streams::CONTROL))?;
+        }
+        Ok(())
+    }
+
+    // records the report and marks the client idle once enough of the clients it's proposed to
+    // have recently reported it
+    fn report_unresponsive(
+        &mut self,
+        client: SocketAddr,
+        reporter: SocketAddr,
+        now: Instant,
+    ) -> Result<(), ServerError> {
+        let wall_clock = SystemTime::now();
+        let proposed_to = self
+            .queue
+            .iter()
+            .filter(|&&c| self.proposes(c, client, now, wall_clock))
+            .count();
+        let required = IDLE_REPORTS.max(proposed_to / IDLE_REPORT_SHARE);
+        let window = Duration::from_secs(IDLE_REPORT_WINDOW_SECS);
+        let reporters = self.unresponsive.entry(client).or_default();
+        reporters.retain(|_, &mut reported| now.saturating_duration_since(reported) < window);
+        reporters.insert(reporter, now);
+        if reporters.len() < required || !self.idle.insert(client) {
+            return Ok(());
+        }
+        info!(target: SERVER_QUEUE, "{} is idle", client);
+        let dequeued = self.dequeue_idle;
+        let msg = bincode::serialize(&ToClient::Idle { dequeued }).context(SerializeError)?;
+        self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))?;
+        let advertised = self.advertised(client);
+        let others: Vec<_> = self
+            .queue
+            .iter()
+            .filter(|&&c| c != client)
+            .copied()
+            .collect();
+        for other in others {
+            let seq = self.peer_list_seq(other, false);
+            let msg =
+                bincode::serialize(&ToClient::Dequeued(seq, advertised)).context(SerializeError)?;
+            self.send(Packet::reliable_ordered(other, msg, streams::CONTROL))?;
+        }
+        if dequeued {
+            self.dequeue_client(client);
+        }
+        Ok(())
+    }
+
+    // sends the usage of the client's relays to the webhooks
+    fn end_relays(&mut self, client: SocketAddr) {
+        for usage in self.relays.end(client) {
+            let (a, b) = usage.clients;
+            info!(target: SERVER_RELAY,
+                "relayed {} bytes between {} and {}, dropped {}",
+                usage.relayed_bytes, a, b, usage.dropped_bytes
+            );
+            if let Some(webhooks) = &self.webhooks {
+                let relay_participant = |c| Participant {
+                    address: self.relay_name(c),
+                    id: self.identities.get(&c).cloned(),
+                };
+                webhooks.notify(&Event::RelayCompleted {
+                    players: vec![relay_participant(a), relay_participant(b)],
+                    relayed_bytes: usage.relayed_bytes,
+                    dropped_bytes: usage.dropped_bytes,
+                    duration_secs: usage.started.elapsed().as_secs_f64(),
+                });
+            }
+        }
+    }
+
+    fn relay_name(&self, client: SocketAddr) -> SocketAddr {
+        self.relay_names.get(&client).copied().unwrap_or(client)
+    }
+
+    // stores the match in the history of the identified client, and if it's the first report since the
+    // client was matched against a single identified opponent, hands it to the ratings to check against
+    // the opponent's report
+    fn record_match(
+        &mut self,
+        client: SocketAddr,
+        report: MatchReport,
+        first: bool,
+        quality: Option<u8>,
+    ) {
+        let player = match self.identities.get(&client) {
+            Some(player) => player.clone(),
+            None => return,
+        };
+        let opponents: Vec<_> = report
+            .opponents
+            .iter()
+            .filter_map(|&opponent| self.identity_of(opponent))
+            .collect();
+        if let (true, [opponent], [_], Some(ratings)) = (
+            first,
+            &opponents[..],
+            &report.opponents[..],
+            &mut self.ratings,
+        ) {
+            ratings.report(&player, opponent, report.outcome, Instant::now());
+        }
+        let ended = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+        let record = MatchRecord {
+            player,
+            opponents,
+            outcome: report.outcome,
+            duration: report.duration,
+            relayed: report.relayed,
+            average_rtt: report.average_rtt,
+            quality,
+            ended,
+        };
+        if let Some(store) = &mut self.history {
+            if let Err(e) = store.record(record) {
+                warn!("failed to store match: {}", e);
+            }
+        }
+    }
+
+    // the priority tier of the client, 0 unless it has identified
+    fn priority(&self, client: SocketAddr) -> u8 {
+        self.identities.get(&client).map_or(0, |id| {
+            self.priorities.tier(id, self.returning.contains(&client))
+        })
+    }
+
+    // sends the client the standings of the peers, if there are any and players are prioritized
+    fn send_standings(&self, client: SocketAddr, peers: &[SocketAddr]) -> Result<(), ServerError> {
+        if peers.is_empty() || self.priorities.is_empty() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let standings = peers
+            .iter()
+            .map(|&c| {
+                let standing = QueueStanding {
+                    priority: self.priority(c),
+                    waited: self
+                        .queued_at
+                        .get(&c)
+                        .map(|&queued| now.saturating_duration_since(queued))
+                        .unwrap_or_default(),
+                };
+                (self.advertised(c), standing)
+            })
+            .collect();
+        let msg = bincode::serialize(&ToClient::Standings(standings)).context(SerializeError)?;
+        self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))
+    }
+
+    // the player IDs stay on the server, peers only learn their handles
+    fn send_peer_handles(
+        &self,
+        client: SocketAddr,
+        peers: &[SocketAddr],
+    ) -> Result<(), ServerError> {
+        let handles: Vec<_> = peers
+            .iter()
+            .filter_map(|c| Some((self.advertised(*c), player_handle(self.identities.get(c)?))))
+            .collect();
+        if handles.is_empty() {
+            return Ok(());
+        }
+        let msg = bincode::serialize(&ToClient::PeerHandles(handles)).context(SerializeError)?;
+        self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))
+    }
+
+    // the player ID of the client other clients reach at the address
+    fn identity_of(&self, advertised: SocketAddr) -> Option<String> {
+        self.identities
+            .iter()
+            .find(|(&c, _)| c == advertised || self.advertised(c) == advertised)
+            .map(|(_, id)| id.clone())
+    }
+
+    /// The address other clients reach the client at,
+    /// which uses the port forwarded by its router if it reported one.
+    pub fn advertised(&self, client: SocketAddr) -> SocketAddr {
+        match self.mapped.get(&client) {
+            Some(&port) => SocketAddr::new(client.ip(), port),
+            None => client,
+        }
+    }
+
+    /// Announces the clients that have queued since the last batch to the rest of the queue.
+    /// The message is serialized once for every client that isn't part of the batch.
+    /// Then sends the queued clients their status and starts the reserved matches whose players
+    /// have both queued.
+    /// # Errors
+    /// If there is an issue serializing or sending a message.
+    pub fn flush(&mut self) -> Result<(), ServerError> {
+        if let Some(file) = &mut self.config {
+            if file.reload_if_changed() {
+                let config = file.config().clone();
+                self.configure(&config);
+            }
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(policy) = &mut self.policy {
+            policy.reload_if_changed();
+        }
+        if self.last_snapshot.elapsed() >= Duration::from_secs(SNAPSHOT_INTERVAL_SECS) {
+            self.save_snapshot();
+        }
+        self.dequeue_unheard()?;
+        self.report_regions();
+        self.report_relays();
+        self.announce_drained();
+        self.send_presence()?;
+        self.share_queue()?;
+        self.offer_backfill()?;
+        let now = SystemTime::now();
+        let instant = Instant::now();
+        let joined: HashSet<_> = std::mem::take(&mut self.joined)
+            .into_iter()
+            .filter(|&c| !self.held(c, now) && !self.idle.contains(&c))
+            .collect();
+        if joined.is_empty() {
+            self.announce_relaxed()?;
+            self.send_queue_status()?;
+            return self.start_reservations();
+        }
+        let parties = self.parties_of(&joined.iter().copied().collect::<Vec<_>>());
+        let parties_msg = if parties.is_empty() {
+            None
+        } else {
+            let all = parties.iter().map(|(_, members)| members.clone()).collect();
+            Some(bincode::serialize(&ToClient::Parties(all)).context(SerializeError)?)
+        };
+        let queue: Vec<_> = self
+            .queue
+            .iter()
+            .filter(|&&c| !self.held(c, now))
+            .copied()
+            .collect();
+        for client in queue {
+            let token = self.parties.get(&client).copied();
+            let teammate_joined = parties.iter().any(|(t, _)| Some(*t) == token);
+            // the client already knows about those that queued before it, but not about itself,
+            // and neither its teammates nor the players it doesn't match are its opponents
+            let others: Vec<_> = joined
+                .iter()
+                .filter(|&&c| {
+                    c != client
+                        && !self.are_teammates(c, client)
+                        && self.compatible(c, client, instant)
+                })
+                .copied()
+                .collect();
+            if others.is_empty() {
+                continue;
+            }
+            let announced = others.iter().map(|&c| self.advertised(c)).collect();
+            let seq = self.peer_list_seq(client, false);
+            let msg =
+                bincode::serialize(&ToClient::Queued(seq, announced)).context(SerializeError)?;
+            self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))?;
+            self.send_peer_handles(client, &others)?;
+            // the players that just joined haven't waited, so only their priority matters
+            let prioritized: Vec<_> = others
+                .into_iter()
+                .filter(|&c| self.priority(c) > 0)
+                .collect();
+            self.send_standings(client, &prioritized)?;
+            let parties_msg = if teammate_joined {
+                let others: Vec<_> = parties
+                    .iter()
+                    .filter(|(t, _)| Some(*t) != token)
+                    .map(|(_, members)| members.clone())
+                    .collect();
+                if others.is_empty() {
+                    continue;
+                }
+                bincode::serialize(&ToClient::Parties(others)).context(SerializeError)?
+            } else {
+                match &parties_msg {
+                    Some(msg) => msg.clone(),
+                    None => continue,
+                }
+            };
+            self.send(Packet::reliable_ordered(
+                client,
+                parties_msg,
+                streams::CONTROL,
+            ))?;
+        }
+        trace!(target: SERVER_QUEUE, "announced {} clients", joined.len());
+        self.announce_relaxed()?;
+        self.send_queue_status()?;
+        self.start_reservations()
+    }
+
+    // offers the queued clients the rooms with free places whose hosts they match
+    fn offer_backfill(&mut self) -> Result<(), ServerError> {
+        let now = Instant::now();
+        let interval = Duration::from_millis(BACKFILL_INTERVAL_MILLIS);
+        if now.duration_since(self.last_backfill) < interval {
+            return Ok(());
+        }
+        self.last_backfill = now;
+        let system_now = SystemTime::now();
+        let open: Vec<_> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.players < room.max_players)
+            .collect();
+        if open.is_empty() {
+            return Ok(());
+        }
+        for &client in self.queue.iter().filter(|&c| {
+            !self.rooms.contains_key(c) && !self.held(*c, system_now) && !self.idle.contains(c)
+        }) {
+            let rooms: Vec<_> = open
+                .iter()
+                .filter(|(&host, _)| {
+                    !self.are_teammates(host, client) && self.compatible(host, client, now)
+                })
+                .take(MAX_LISTED_ROOMS)
+                .map(|(&host, room)| (self.advertised(host), (*room).clone()))
+                .collect();
+            if !rooms.is_empty() {
+                let msg = bincode::serialize(&ToClient::Backfill(rooms)).context(SerializeError)?;
+                self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))?;
+            }
+        }
+        Ok(())
+    }
+
+    // sends the queued clients their place in the queue and how much longer they are expected to wait
+    fn send_queue_status(&mut self) -> Result<(), ServerError> {
+        let now = Instant::now();
+        let interval = Duration::from_millis(STATUS_INTERVAL_MILLIS);
+        if now.duration_since(self.last_status) < interval {
+            return Ok(());
+        }
+        self.last_status = now;
+        let system_now = SystemTime::now();
+        let mut waiting: Vec<_> = self
+            .queue
+            .iter()
+            .filter(|&&c| !self.held(c, system_now))
+            .filter_map(|&c| self.queued_at.get(&c).map(|&queued| (queued, c)))
+            .collect();
+        waiting.sort_by_key(|&(queued, c)| (Reverse(self.priority(c)), queued));
+        for (position, &(queued, client)) in waiting.iter().enumerate() {
+            let rating = self.profiles.get(&client).and_then(|p| p.rating);
+            let status = QueueStatus {
+                position: position as u32 + 1,
+                estimated_wait: self.waits.estimate(rating, now.duration_since(queued)),
+            };
+            let msg = bincode::serialize(&ToClient::QueueStatus(status)).context(SerializeError)?;
+            self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))?;
+        }
+        self.offer_other_regions(&waiting)
+    }
+
+    // sends the servers of the other regions a summary of the queue
+    fn share_queue(&mut self) -> Result<(), ServerError> {
+        let queued = self.queue.len();
+        let summaries = match &mut self.federation {
+            Some(federation) => federation.summaries(queued, Instant::now()),
+            None => return Ok(()),
+        };
+        for (server, summary) in summaries {
+            let msg =
+                bincode::serialize(&FromClient::QueueSummary(summary)).context(SerializeError)?;
+            // a lost summary is replaced by the next one, and retransmissions would skew the round trip
+            self.send(Packet::unreliable(server, msg))?;
+        }
+        Ok(())
+    }
+
+    // offers the other regions once to the clients that have waited long without anyone here matching them
+    fn offer_other_regions(
+        &mut self,
+        waiting: &[(Instant, SocketAddr)],
+    ) -> Result<(), ServerError> {
+        let now = Instant::now();
+        let regions = match &self.federation {
+            Some(federation) => federation.other_regions(now),
+            None => return Ok(()),
+        };
+        if regions.is_empty() {
+            return Ok(());
+        }
+        let offer_after = Duration::from_secs(OFFER_AFTER_SECS);
+        let msg = bincode::serialize(&ToClient::OtherRegions(regions)).context(SerializeError)?;
+        for &(queued, client) in waiting {
+            if now.duration_since(queued) < offer_after || self.offered.contains(&client) {
+                continue;
+            }
+            let matched = waiting.iter().any(|&(_, c)| {
+                c != client && !self.are_teammates(c, client) && self.compatible(c, client, now)
+            });
+            if !matched {
+                debug!("offering other regions to {}", client);
+                self.offered.insert(client);
+                self.send(Packet::reliable_ordered(
+                    client,
+                    msg.clone(),
+                    streams::CONTROL,
+                ))?;
+            }
+        }
+        Ok(())
+    }
+
+    // announces the queued clients to each other once they match, as the criteria relax over time
+    fn announce_relaxed(&mut self) -> Result<(), ServerError> {
+        let now = Instant::now();
+        let interval = Duration::from_millis(RELAXATION_INTERVAL_MILLIS);
+        let relaxing = self.relaxation.is_some() || self.quality.avoids_any();
+        if !relaxing || now.duration_since(self.last_relaxed) < interval {
+            return Ok(());
+        }
+        let before = std::mem::replace(&mut self.last_relaxed, now);
+        let system_now = SystemTime::now();
+        let queued: Vec<_> = self
+            .queue
+            .iter()
+            .filter(|&&c| !self.held(c, system_now))
+            .copied()
+            .collect();
+        for &client in &queued {
+            // the criteria only widen, so those that matched before have already been announced
+            let matched: Vec<_> = queued
+                .iter()
+                .filter(|&&c| {
+                    c != client
+                        && !self.idle.contains(&c)
+                        && !self.are_teammates(c, client)
+                        && self.compatible(c, client, now)
+                        && !self.compatible(c, client, before)
+                })
+                .copied()
+                .collect();
+            if !matched.is_empty() {
+                let advertised = matched.iter().map(|&c| self.advertised(c)).collect();
+                let seq = self.peer_list_seq(client, false);
+                let msg = bincode::serialize(&ToClient::Queued(seq, advertised))
+                    .context(SerializeError)?;
+                self.send(Packet::reliable_ordered(client, msg, streams::CONTROL))?;
+                self.send_standings(client, &matched)?;
+                self.send_peer_handles(client, &matched)?;
+            }
+        }
+        Ok(())
+    }
+
+    // fills in the region of a client that didn't declare one from its IP
+    #[cfg(feature = "geoip")]
+    fn locate(&mut self, client: SocketAddr) {
+        if let Some(geoip) = &self.geoip {
+            let profile = self.profiles.entry(client).or_default();
+            if profile.region.is_none() {
+                profile.region = geoip.region(client.ip());
+                trace!("located {} in {:?}", client, profile.region);
+            }
+        }
+    }
+
+    /// The number of queued players per region, with the players whose region isn't known
+    /// under `unknown`.
+    pub fn queue_regions(&self) -> BTreeMap<String, usize> {
+        let mut regions = BTreeMap::new();
+        for client in &self.queue {
+            let region = self
+                .profiles
+                .get(client)
+                .and_then(|profile| profile.region.clone())
+                .unwrap_or_else(|| "unknown".to_string());
+            *regions.entry(region).or_insert(0) += 1;
+        }
+        regions
+    }
+
+    // logs the number of queued players per region and sends it to the webhooks
+    fn report_regions(&mut self) {
+        let interval = Duration::from_secs(REGION_REPORT_INTERVAL_SECS);
+        if self.last_region_report.elapsed() < interval || self.queue.is_empty() {
+            return;
+        }
+        self.last_region_report = Instant::now();
+        let regions = self.queue_regions();
+        info!("queued players per region: {:?}", regions);
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(&Event::QueueRegions { regions });
+        }
+    }
+
+    // logs and notifies the webhooks once the server is drained
+    fn announce_drained(&mut self) {
+        if self.drained || !self.is_drained() {
+            return;
+        }
+        self.drained = true;
+        info!("drained, the server can be shut down");
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(&Event::Drained);
+        }
+    }
+
+    // logs the bytes relayed so far and sends them to the webhooks
+    fn report_relays(&mut self) {
+        let interval = Duration::from_secs(RELAY_REPORT_INTERVAL_SECS);
+        let active = self.relays.usage().len();
+        if self.last_relay_report.elapsed() < interval || active == 0 {
+            return;
+        }
+        self.last_relay_report = Instant::now();
+        let relayed_bytes = self.relays.relayed_bytes();
+        let dropped_bytes = self.relays.dropped_bytes();
+        info!(
+            "{} active relays, relayed {} bytes, dropped {}",
+            active, relayed_bytes, dropped_bytes
+        );
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(&Event::RelayUsage {
+                active,
+                relayed_bytes,
+                dropped_bytes,
+            });
+        }
+    }
+
+    // whether the clients match each other's criteria at the given time, see `criteria`,
+    // their networks aren't kept apart, see `quality`, and the policy allows proposing them to each other
+    fn compatible(&self, a: SocketAddr, b: SocketAddr, at: Instant) -> bool {
+        let anything = Profile::default();
+        let profile = |c| self.profiles.get(&c).unwrap_or(&anything);
+        let waited = |c| {
+            self.queued_at
+                .get(&c)
+                .map(|&queued| at.saturating_duration_since(queued))
+                .unwrap_or_default()
+        };
+        if self
+            .quality
+            .avoids(a.ip(), b.ip(), waited(a).min(waited(b)))
+        {
+            return false;
+        }
+        let criteria = match &self.relaxation {
+            Some(relaxation) => relaxation.compatible(profile(a), waited(a), profile(b), waited(b)),
+            None => true,
+        };
+        #[cfg(feature = "scripting")]
+        if let (true, Some(policy)) = (criteria, &self.policy) {
+            let client = |c: SocketAddr| policy::QueuedClient {
+                addr: c,
+                id: self.identities.get(&c).map(String::as_str),
+                rating: profile(c).rating,
+                region: profile(c).region.as_deref(),
+                waited: waited(c),
+                last_opponents: self.last_opponents.get(&c).map_or(&[], Vec::as_slice),
+            };
+            let (a, b) = (client(a), client(b));
+            return policy.allows(&a, &b) && policy.allows(&b, &a);
+        }
+        criteria
+    }
+
+    // whether the client is held out of the queue for a reserved match that has started
+    fn held(&self, client: SocketAddr, now: SystemTime) -> bool {
+        self.identities.get(&client).is_some_and(|id| {
+            self.reservations
+                .iter()
+                .any(|reservation| reservation.is_open(now) && reservation.players.contains(id))
+        })
+    }
+
+    // sends both players of each started reservation each other's address once they have queued,
+    // and forgets the reservations that have ended
+    fn start_reservations(&mut self) -> Result<(), ServerError> {
+        let now = SystemTime::now();
+        let window = Duration::from_secs(RESERVATION_WINDOW_SECS);
+        self.reservations
+            .retain(|reservation| now < reservation.start + window);
+        let queued = |id: &String| {
+            self.queue
+                .iter()
+                .find(|&c| self.identities.get(c) == Some(id))
+                .copied()
+        };
+        let mut started = vec![];
+        for (i, reservation) in self.reservations.iter().enumerate() {
+            if !reservation.is_open(now) {
+                continue;
+            }
+            let [first, second] = &reservation.players;
+            if let (Some(first), Some(second)) = (queued(first), queued(second)) {
+                started.push((i, first, second));
+            }
+        }
+        for &(i, first, second) in started.iter().rev() {
+            debug!("starting the reserved match of {} and {}", first, second);
+            for &(to, other) in &[(first, second), (second, first)] {
+                let msg = bincode::serialize(&ToClient::Scheduled(self.advertised(other)))
+                    .context(SerializeError)?;
+                self.send(Packet::reliable_ordered(to, msg, streams::CONTROL))?;
+            }
+            self.reservations.remove(i);
+        }
+        Ok(())
+    }
+
+    // the cookie for the source in the given period
+    fn cookie(&self, source: SocketAddr, period: u64) -> u64 {
+        let mut hasher = self.cookie_key.build_hasher();
+        source.hash(&mut hasher);
+        period.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // counts the bytes received from a source that hasn't been verified
+    fn received_from(&mut self, source: SocketAddr, bytes: usize) {
+        if self.verified.contains(&source) {
+            return;
+        }
+        let tracked = self.unverified.len();
+        match self.unverified.get_mut(&source) {
+            Some((received, _)) => *received += bytes,
+            None if tracked < MAX_UNVERIFIED => {
+                self.unverified.insert(source, (bytes, 0));
+            }
+            None => {}
+        }
+    }
+
+    // sends a response to a source that may not have been verified,
+    // dropping it if it would exceed the amplification limit
+    fn reply(&mut self, packet: Packet) -> Result<(), ServerError> {
+        let addr = packet.addr();
+        if !self.verified.contains(&addr) {
+            let size = packet.payload().len();
+            match self.unverified.get_mut(&addr) {
+                Some((received, sent)) if *sent + size <= AMPLIFICATION_LIMIT * *received => {
+                    *sent += size;
+                }
+                _ => {
+                    debug!("not answering unverified source {}", addr);
+                    return Ok(());
+                }
+            }
+        }
+        self.send(packet)
+    }
+
+    fn send(&self, packet: Packet) -> Result<(), ServerError> {
+        match &self.workers {
+            Some(workers) => workers.send(packet),
+            None => self.transport.send(packet),
+        }
+        .context(SenderError)
+    }
+
+    /// Returns the queued clients.
+    pub fn queue(&self) -> &HashSet<SocketAddr> {
+        &self.queue
+    }
+}
+
+// sends packets on worker threads, packets to the same address always go through the same worker to keep their order
+struct SendWorkers {
+    senders: Vec<Sender<Packet>>,
+}
+
+impl SendWorkers {
+    // the workers stop once this is dropped
+    fn new<T: Transport>(transport: &Arc<T>, count: usize) -> Self {
+        let senders = (0..count)
+            .map(|_| {
+                let (sender, receiver) = unbounded::<Packet>();
+                let transport = Arc::clone(transport);
+                thread::spawn(move || {
+                    for packet in receiver {
+                        let addr = packet.addr();
+                        if let Err(e) = transport.send(packet) {
+                            warn!("failed to send to {}: {}", addr, e);
+                        }
+                    }
+                });
+                sender
+            })
+            .collect();
+        Self { senders }
+    }
+
+    fn send(&self, packet: Packet) -> Result<(), TransportError> {
+        let worker = worker_for(packet.addr(), self.senders.len());
+        self.senders[worker]
+            .send(packet)
+            .map_err(|_| TransportError::Closed)
+    }
+}
+
+// moves the value of the key to another key
+fn move_key<V>(map: &mut HashMap<SocketAddr, V>, from: SocketAddr, to: SocketAddr) {
+    if let Some(value) = map.remove(&from) {
+        map.insert(to, value);
+    }
+}
+
+// the current cookie period since the epoch
+fn cookie_period() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs() / COOKIE_PERIOD_SECS)
+        .unwrap_or(0)
+}
+
+fn worker_for(addr: SocketAddr, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    addr.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+#[derive(Debug, Snafu)]
+pub enum ServerError {
+    #[snafu(display("error serializing: {}", source))]
+    SerializeError {
+        source: std::boxed::Box<bincode::ErrorKind>,
+    },
+    #[snafu(display("error sending: {}", source))]
+    SenderError { source: TransportError },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use laminar::{Packet, Socket, SocketEvent};
+    use mirai_core::transport::{Chunks, LaminarTransport, MockNetwork, Ordering, TcpTransport};
+    use mirai_core::v1::{Outcome, QueueSummary, RegionSummary};
+    use std::cell::RefCell;
+    use std::collections::{HashMap, VecDeque};
+    use std::time::{Duration, Instant};
+
+    thread_local! {
+        // the ordering of the packets each raw socket receives, and the messages it has yet to return
+        static RECEIVED: RefCell<HashMap<SocketAddr, (Ordering, VecDeque<ToClient>)>> =
+            RefCell::new(HashMap::new());
+    }
+
+    fn start_test_server(socket: Socket) {
+        let transport = LaminarTransport::new(socket).unwrap();
+        std::thread::spawn(move || with_transport(transport));
+    }
+
+    // the laminar transport adds an ordering and a chunk header to each payload,
+    // which the raw sockets have to mimic
+    fn framed(server_addr: SocketAddr, msg: &FromClient) -> Vec<u8> {
+        let msg = bincode::serialize(msg).unwrap();
+        let packet = mirai_core::transport::Packet::reliable_unordered(server_addr, msg);
+        let packet = Ordering::default().wrap(packet);
+        let mut chunks = Chunks::new(1024).split(packet).unwrap();
+        assert_eq!(chunks.len(), 1);
+        chunks.remove(0).payload().to_vec()
+    }
+
+    // the messages that can be returned in order once the packet has arrived at the socket
+    fn unframed(socket: &Socket, packet: &Packet) {
+        let packet =
+            mirai_core::transport::Packet::unreliable(packet.addr(), packet.payload().to_vec());
+        let packet = Chunks::new(1024).receive(packet).unwrap();
+        RECEIVED.with(|received| {
+            let mut received = received.borrow_mut();
+            let (ordering, pending) = received
+                .entry(socket.local_addr().unwrap())
+                .or_insert_with(|| (Ordering::default(), VecDeque::new()));
+            for packet in ordering.receive(packet) {
+                pending.push_back(bincode::deserialize::<ToClient>(packet.payload()).unwrap());
+            }
+        })
+    }
+
+    fn pending(socket: &Socket) -> Option<ToClient> {
+        RECEIVED.with(|received| {
+            let mut received = received.borrow_mut();
+            let (_, pending) = received.get_mut(&socket.local_addr().unwrap())?;
+            pending.pop_front()
+        })
+    }
+
+    fn wait_for_server(server_addr: SocketAddr) {
+        let mut socket = Socket::bind_any().unwrap();
+        loop {
+            let msg = framed(server_addr, &FromClient::StatusCheck);
+            socket
+                .send(Packet::reliable_unordered(server_addr, msg))
+                .unwrap();
+            socket.manual_poll(std::time::Instant::now());
+            if let Some(SocketEvent::Packet(packet)) = socket.recv() {
+                unframed(&socket, &packet);
+            }
+            if let Some(msg) = pending(&socket) {
+                assert_eq!(msg, ToClient::Alive);
+                println!("server is alive");
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+
+    fn send(socket: &mut Socket, msg: FromClient, server_addr: SocketAddr) {
+        let ser = framed(server_addr, &msg);
+        socket
+            .send(Packet::reliable_unordered(server_addr, ser))
+            .unwrap();
+        socket.manual_poll(std::time::Instant::now());
+    }
+
+    fn recv_msg(socket: &mut Socket) -> Option<ToClient> {
+        let timer = Duration::from_millis(500);
+        let now = Instant::now();
+        loop {
+            if let Some(msg) = pending(socket) {
+                return Some(msg);
+            }
+            if now.elapsed() > timer {
+                return None;
+            }
+            socket.manual_poll(std::time::Instant::now());
+            if let Some(SocketEvent::Packet(packet)) = socket.recv() {
+                unframed(socket, &packet);
+            }
+        }
+    }
+
+    fn expect_msg(socket: &mut Socket, msg: ToClient) -> Option<ToClient> {
+        loop {
+            let recvd = recv_msg(socket)?;
+            if std::mem::discriminant(&msg) == std::mem::discriminant(&recvd) {
+                return Some(recvd);
+            }
+        }
+    }
+
+    // queues the socket, echoing the cookie the server answers the first request with
+    fn queue(socket: &mut Socket, server_addr: SocketAddr) {
+        send(socket, FromClient::Queue, server_addr);
+        let cookie = match expect_msg(socket, ToClient::Cookie(0)) {
+            Some(ToClient::Cookie(cookie)) => cookie,
+            msg => panic!("expected a cookie, got {:?}", msg),
+        };
+        send(socket, FromClient::Cookie(cookie), server_addr);
+        send(socket, FromClient::Queue, server_addr);
+    }
+
+    // verifies the client as if it had echoed its cookie
+    fn verify<T: Transport>(server: &mut Server<T>, client: SocketAddr) {
+        let cookie = server.cookie(client, cookie_period());
+        let msg = bincode::serialize(&FromClient::Cookie(cookie)).unwrap();
+        let packet = mirai_core::transport::Packet::unreliable(client, msg);
+        server.handle_event(TransportEvent::Packet(packet)).unwrap();
+    }
+
+    // the resume token the queued client was sent
+    fn resume_token<T: Transport>(server: &Server<T>, client: SocketAddr) -> ToClient {
+        ToClient::ResumeToken(server.resume_token_of[&client])
+    }
+
+    // stands in for the number of an update the test only matches the kind of
+    fn any_seq() -> PeerListSeq {
+        PeerListSeq {
+            epoch: 0,
+            sequence: 0,
+        }
+    }
+
+    // the number of an update of a client's peer list
+    fn seq<T: Transport>(server: &Server<T>, sequence: u64) -> PeerListSeq {
+        PeerListSeq {
+            epoch: server.epoch,
+            sequence,
+        }
+    }
+
+    // waits for a batch that announces the given client
+    fn expect_queued(socket: &mut Socket, addr: SocketAddr) -> bool {
+        while let Some(msg) = expect_msg(socket, ToClient::Queued(any_seq(), HashSet::new())) {
+            if let ToClient::Queued(_, addrs) = msg {
+                if addrs.contains(&addr) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn basic_queue_test() {
+        let server_socket = Socket::bind_any().unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        start_test_server(server_socket);
+        let mut socket_1 = Socket::bind_any().unwrap();
+        let mut socket_2 = Socket::bind_any().unwrap();
+        let mut socket_3 = Socket::bind_any().unwrap();
+        let addr_1 = socket_1.local_addr().unwrap();
+        let addr_2 = socket_2.local_addr().unwrap();
+        let addr_3 = socket_3.local_addr().unwrap();
+        println!("1: {:?}", addr_1);
+        println!("2: {:?}", addr_2);
+        println!("3: {:?}", addr_3);
+        wait_for_server(server_addr);
+
+        queue(&mut socket_1, server_addr);
+        let peers = expect_msg(&mut socket_1, ToClient::Peers(any_seq(), HashSet::new())).unwrap();
+        if let ToClient::Peers(_, peer_list) = peers {
+            assert_eq!(
+                peer_list,
+                HashSet::new(),
+                "first to queue gets an empty peer set"
+            );
+        } else {
+            unreachable!("first to queue did not receive peers")
+        }
+
+        queue(&mut socket_2, server_addr);
+        let peers = expect_msg(&mut socket_2, ToClient::Peers(any_seq(), HashSet::new())).unwrap();
+        if let ToClient::Peers(_, peer_list) = peers {
+            let mut expected = HashSet::new();
+            expected.insert(addr_1);
+            assert_eq!(
+                peer_list, expected,
+                "second to queue gets the first peer in a set"
+            );
+        } else {
+            unreachable!("second to queue did not get peers")
+        }
+
+        assert!(
+            expect_queued(&mut socket_1, addr_2),
+            "first peer is notified of second peer"
+        );
+
+        queue(&mut socket_3, server_addr);
+        let peers = expect_msg(&mut socket_3, ToClient::Peers(any_seq(), HashSet::new())).unwrap();
+        if let ToClient::Peers(_, peer_list) = peers {
+            let mut expected = HashSet::new();
+            expected.insert(addr_1);
+            expected.insert(addr_2);
+            assert_eq!(
+                peer_list, expected,
+                "third to queue receivers both previous peers in a set"
+            );
+        } else {
+            unreachable!("third to queue did not receive peers")
+        }
+
+        assert!(
+            expect_queued(&mut socket_1, addr_3),
+            "first peer is notified of third"
+        );
+
+        assert!(
+            expect_queued(&mut socket_2, addr_3),
+            "second peer is notified of third"
+        );
+    }
+
+    #[test]
+    fn basic_dequeue_test() {
+        let server_socket = Socket::bind_any().unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        start_test_server(server_socket);
+        let mut socket_1 = Socket::bind_any().unwrap();
+        let mut socket_2 = Socket::bind_any().unwrap();
+        wait_for_server(server_addr);
+
+        queue(&mut socket_1, server_addr);
+        send(&mut socket_1, FromClient::Dequeue, server_addr);
+        queue(&mut socket_2, server_addr);
+
+        let peers = expect_msg(&mut socket_2, ToClient::Peers(any_seq(), HashSet::new())).unwrap();
+        if let ToClient::Peers(_, peers) = peers {
+            assert_eq!(
+                peers,
+                HashSet::new(),
+                "second to queue receives empty peer set"
+            );
+        } else {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn tcp_queue_test() {
+        let server = TcpTransport::listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        std::thread::spawn(move || with_transport(server));
+        let client = TcpTransport::new();
+        let send = |msg: &FromClient| {
+            let msg = bincode::serialize(msg).unwrap();
+            client
+                .send(mirai_core::transport::Packet::reliable_unordered(
+                    server_addr,
+                    msg,
+                ))
+                .unwrap();
+        };
+
+        send(&FromClient::Queue);
+        let cookie = loop {
+            let event = client
+                .events()
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap();
+            if let TransportEvent::Packet(packet) = event {
+                match bincode::deserialize::<ToClient>(packet.payload()).unwrap() {
+                    ToClient::Cookie(cookie) => break cookie,
+                    msg => panic!("expected a cookie, got {:?}", msg),
+                }
+            }
+        };
+        send(&FromClient::Cookie(cookie));
+        send(&FromClient::Queue);
+        let mut received = vec![];
+        while received.len() < 2 {
+            let event = client
+                .events()
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap();
+            if let TransportEvent::Packet(packet) = event {
+                received.push(bincode::deserialize::<ToClient>(packet.payload()).unwrap());
+            }
+        }
+        assert!(
+            matches!(
+                &received[..],
+                [ToClient::YourAddr(addr), ToClient::Peers(_, peers)]
+                    if addr.ip() == server_addr.ip() && peers.is_empty()
+            ),
+            "clients can queue over TCP: {:?}",
+            received
+        );
+    }
+
+    #[test]
+    fn malformed_packets_are_ignored() {
+        let network = MockNetwork::new();
+        let server_addr = "127.0.0.1:1".parse().unwrap();
+        let client_addr = "127.0.0.1:2".parse().unwrap();
+        let mut server = Server::new(network.transport(server_addr));
+        let client = network.transport(client_addr);
+        let queue = bincode::serialize(&FromClient::Queue).unwrap();
+        let malformed = vec![vec![], vec![0xff; 64], queue[..queue.len() - 1].to_vec()];
+        for payload in malformed {
+            let packet = mirai_core::transport::Packet::unreliable(client_addr, payload);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+        }
+        assert!(server.queue().is_empty());
+
+        verify(&mut server, client_addr);
+        let packet = mirai_core::transport::Packet::unreliable(client_addr, queue);
+        server.handle_event(TransportEvent::Packet(packet)).unwrap();
+        assert!(server.queue().contains(&client_addr));
+        network.deliver_all();
+        assert_eq!(
+            client.events().try_iter().count(),
+            4,
+            "connect, address, peers and resume token"
+        );
+    }
+
+    #[test]
+    fn queued_clients_are_announced_in_batches() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..5)
+            .map(|port| format!("127.0.0.1:{}", port).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        for &addr in &addrs {
+            verify(&mut server, addr);
+        }
+        let queue = bincode::serialize(&FromClient::Queue).unwrap();
+
+        // the first client was announced in an earlier batch
+        let packet = mirai_core::transport::Packet::unreliable(addrs[0], queue.clone());
+        server.handle_event(TransportEvent::Packet(packet)).unwrap();
+        server.flush().unwrap();
+        for &addr in &addrs[1..] {
+            let packet = mirai_core::transport::Packet::unreliable(addr, queue.clone());
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+        }
+        server.flush().unwrap();
+        server.flush().unwrap();
+        network.deliver_all();
+
+        let queued = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => {
+                        match bincode::deserialize(packet.payload()).unwrap() {
+                            ToClient::Queued(_, addrs) => Some(addrs),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let set = |addrs: &[SocketAddr]| addrs.iter().cloned().collect::<HashSet<_>>();
+        assert_eq!(queued(&clients[0]), vec![set(&addrs[1..])]);
+        assert_eq!(queued(&clients[1]), vec![set(&addrs[2..])]);
+        assert_eq!(queued(&clients[2]), vec![set(&addrs[1..2])]);
+    }
+
+    #[test]
+    fn mapped_clients_are_announced_at_their_mapped_port() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let mapped_addr = "127.0.0.2:2".parse().unwrap();
+        let other_addr = "127.0.0.3:3".parse().unwrap();
+        let mapped = network.transport(mapped_addr);
+        let other = network.transport(other_addr);
+        verify(&mut server, mapped_addr);
+        verify(&mut server, other_addr);
+
+        let queue = bincode::serialize(&FromClient::QueueMapped(44445)).unwrap();
+        let packet = mirai_core::transport::Packet::unreliable(mapped_addr, queue);
+        server.handle_event(TransportEvent::Packet(packet)).unwrap();
+        server.flush().unwrap();
+        let queue = bincode::serialize(&FromClient::Queue).unwrap();
+        let packet = mirai_core::transport::Packet::unreliable(other_addr, queue);
+        server.handle_event(TransportEvent::Packet(packet)).unwrap();
+        server.flush().unwrap();
+        network.deliver_all();
+
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        let advertised = "127.0.0.2:44445".parse().unwrap();
+        assert_eq!(server.advertised(mapped_addr), advertised);
+        let peers = vec![advertised].into_iter().collect();
+        assert_eq!(
+            messages(&other),
+            vec![
+                ToClient::YourAddr(other_addr),
+                ToClient::Peers(seq(&server, 0), peers),
+                resume_token(&server, other_addr)
+            ]
+        );
+        // the mapped client is still sent to at the address it queued from
+        let queued = vec![other_addr].into_iter().collect();
+        assert_eq!(
+            messages(&mapped),
+            vec![
+                ToClient::YourAddr(mapped_addr),
+                ToClient::Peers(seq(&server, 0), HashSet::new()),
+                resume_token(&server, mapped_addr),
+                ToClient::Queued(seq(&server, 1), queued)
+            ]
+        );
+    }
+
+    #[test]
+    fn relays_between_clients_that_both_requested_it() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let a_addr = "127.0.0.2:2".parse().unwrap();
+        let b_addr = "127.0.0.3:3".parse().unwrap();
+        let a = network.transport(a_addr);
+        let b = network.transport(b_addr);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        let relay = |to: SocketAddr| FromClient::Relay {
+            to,
+            payload: vec![1],
+        };
+
+        verify(&mut server, a_addr);
+        verify(&mut server, b_addr);
+        handle(&mut server, a_addr, FromClient::Queue);
+        handle(&mut server, b_addr, FromClient::Queue);
+        messages(&a);
+        messages(&b);
+        handle(&mut server, a_addr, FromClient::RequestRelay(b_addr));
+        assert_eq!(messages(&a), vec![ToClient::RelayUnavailable(b_addr)]);
+
+        server.enable_relay();
+        handle(&mut server, a_addr, FromClient::RequestRelay(b_addr));
+        // nothing is relayed until both clients have asked for it
+        handle(&mut server, a_addr, relay(b_addr));
+        assert!(messages(&a).is_empty());
+        assert!(messages(&b).is_empty());
+        handle(&mut server, b_addr, FromClient::RequestRelay(a_addr));
+        assert_eq!(messages(&a), vec![ToClient::RelayReady(b_addr)]);
+        assert_eq!(messages(&b), vec![ToClient::RelayReady(a_addr)]);
+        // relays are only requested by matched clients, so both are dequeued
+        assert!(server.queue().is_empty());
+
+        // the relay keeps working after the clients dequeue
+        handle(&mut server, a_addr, FromClient::Dequeue);
+        handle(&mut server, b_addr, FromClient::Dequeue);
+        let msg = bincode::serialize(&relay(b_addr)).unwrap();
+        let packet = mirai_core::transport::Packet::unreliable_sequenced(a_addr, msg, 2);
+        server.handle_event(TransportEvent::Packet(packet)).unwrap();
+        network.deliver_all();
+        let relayed = b
+            .events()
+            .try_iter()
+            .find_map(|event| match event {
+                TransportEvent::Packet(packet) => Some(packet),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            relayed.delivery(),
+            mirai_core::transport::Delivery::UnreliableSequenced(2)
+        );
+        assert_eq!(
+            bincode::deserialize::<ToClient>(relayed.payload()).unwrap(),
+            ToClient::Relayed {
+                from: a_addr,
+                payload: vec![1]
+            }
+        );
+
+        server
+            .handle_event(TransportEvent::Timeout(b_addr))
+            .unwrap();
+        let msg = bincode::serialize(&relay(b_addr)).unwrap();
+        let packet = mirai_core::transport::Packet::unreliable(a_addr, msg);
+        server.handle_event(TransportEvent::Packet(packet)).unwrap();
+        network.deliver_all();
+        assert!(messages(&b).is_empty());
+    }
+
+    #[test]
+    fn rooms_of_queued_hosts_are_listed() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let host_addr = "127.0.0.2:2".parse().unwrap();
+        let browser_addr = "127.0.0.3:3".parse().unwrap();
+        let _host = network.transport(host_addr);
+        let browser = network.transport(browser_addr);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        let room = Room {
+            name: "friday night".to_string(),
+            region: "eu".to_string(),
+            players: 1,
+            max_players: 4,
+        };
+
+        // the room is only listed once the host has queued
+        verify(&mut server, host_addr);
+        verify(&mut server, browser_addr);
+        handle(&mut server, host_addr, FromClient::OpenRoom(room.clone()));
+        handle(&mut server, browser_addr, FromClient::ListRooms);
+        assert_eq!(
+            messages(&browser),
+            vec![ToClient::YourAddr(browser_addr), ToClient::Rooms(vec![])]
+        );
+        handle(&mut server, host_addr, FromClient::Queue);
+        handle(&mut server, host_addr, FromClient::OpenRoom(room.clone()));
+        let too_many_players = Room {
+            players: 5,
+            ..room.clone()
+        };
+        handle(
+            &mut server,
+            host_addr,
+            FromClient::OpenRoom(too_many_players),
+        );
+        handle(&mut server, browser_addr, FromClient::ListRooms);
+        assert_eq!(
+            messages(&browser),
+            vec![ToClient::Rooms(vec![(host_addr, room)])]
+        );
+
+        handle(&mut server, host_addr, FromClient::Dequeue);
+        handle(&mut server, browser_addr, FromClient::ListRooms);
+        assert_eq!(messages(&browser), vec![ToClient::Rooms(vec![])]);
+    }
+
+    #[test]
+    fn queued_clients_backfill_rooms_with_free_places() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..6)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let host = addrs[0];
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        let room = Room {
+            name: "rotation".to_string(),
+            region: "eu".to_string(),
+            players: 2,
+            max_players: 4,
+        };
+
+        for &addr in &addrs {
+            verify(&mut server, addr);
+            handle(&mut server, addr, FromClient::Queue);
+        }
+        handle(&mut server, host, FromClient::OpenRoom(room.clone()));
+        server.flush().unwrap();
+        network.deliver_all();
+        for client in &clients {
+            messages(client);
+        }
+
+        // nothing is offered until it's time to
+        server.flush().unwrap();
+        network.deliver_all();
+        assert!(messages(&clients[1]).is_empty());
+        let interval = Duration::from_millis(BACKFILL_INTERVAL_MILLIS);
+        server.last_backfill = Instant::now().checked_sub(interval).unwrap();
+        server.flush().unwrap();
+        network.deliver_all();
+        assert!(messages(&clients[0]).is_empty());
+        for client in &clients[1..] {
+            assert_eq!(
+                messages(client),
+                vec![ToClient::Backfill(vec![(host, room.clone())])]
+            );
+        }
+
+        handle(&mut server, addrs[1], FromClient::JoinRoom(host));
+        assert_eq!(
+            messages(&clients[1]),
+            vec![ToClient::JoinedRoom {
+                host,
+                members: vec![]
+            }]
+        );
+        assert_eq!(
+            messages(&clients[0]),
+            vec![ToClient::RoomJoined {
+                host,
+                player: addrs[1]
+            }]
+        );
+        assert!(!server.queue().contains(&addrs[1]));
+
+        // the members that joined earlier are told about the newcomer as well
+        handle(&mut server, addrs[2], FromClient::JoinRoom(host));
+        assert_eq!(
+            messages(&clients[2]),
+            vec![ToClient::JoinedRoom {
+                host,
+                members: vec![addrs[1]]
+            }]
+        );
+        let joined = ToClient::RoomJoined {
+            host,
+            player: addrs[2],
+        };
+        assert_eq!(messages(&clients[0]), vec![joined.clone()]);
+        assert_eq!(messages(&clients[1]), vec![joined]);
+
+        handle(&mut server, addrs[3], FromClient::JoinRoom(host));
+        assert_eq!(messages(&clients[3]), vec![ToClient::RoomFull(host)]);
+        assert!(server.queue().contains(&addrs[3]));
+    }
+
+    #[test]
+    fn uploaded_replays_are_fetched_by_id() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let client_addr = "127.0.0.2:2".parse().unwrap();
+        let client = network.transport(client_addr);
+        let handle = |server: &mut Server<_>, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(client_addr, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+
+        verify(&mut server, client_addr);
+        assert_eq!(
+            handle(&mut server, FromClient::UploadReplay(vec![1, 2, 3])),
+            vec![ToClient::YourAddr(client_addr), ToClient::ReplayRejected]
+        );
+
+        server.store_replays(replays::MemoryReplays::new(10));
+        assert_eq!(
+            handle(&mut server, FromClient::UploadReplay(vec![1, 2, 3])),
+            vec![ToClient::ReplayStored(0)]
+        );
+        assert_eq!(
+            handle(
+                &mut server,
+                FromClient::UploadReplay(vec![0; MAX_REPLAY_SIZE + 1])
+            ),
+            vec![ToClient::ReplayRejected]
+        );
+        assert_eq!(
+            handle(&mut server, FromClient::FetchReplay(0)),
+            vec![ToClient::Replay {
+                id: 0,
+                replay: Some(vec![1, 2, 3])
+            }]
+        );
+        assert_eq!(
+            handle(&mut server, FromClient::FetchReplay(1)),
+            vec![ToClient::Replay {
+                id: 1,
+                replay: None
+            }]
+        );
+    }
+
+    #[test]
+    fn reserved_players_are_held_until_both_have_queued() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let a_addr = "127.0.0.2:2".parse().unwrap();
+        let b_addr = "127.0.0.3:3".parse().unwrap();
+        let c_addr = "127.0.0.4:4".parse().unwrap();
+        let a = network.transport(a_addr);
+        let b = network.transport(b_addr);
+        let c = network.transport(c_addr);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            server.flush().unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        let start = SystemTime::now() - Duration::from_secs(1);
+        let line = format!(
+            "alice bob {}",
+            start.duration_since(UNIX_EPOCH).unwrap().as_secs()
+        );
+        server.reserve(Reservation::parse(&line).unwrap());
+        assert_eq!(Reservation::parse("alice bob"), None);
+
+        for &addr in &[a_addr, b_addr, c_addr] {
+            verify(&mut server, addr);
+        }
+        handle(&mut server, c_addr, FromClient::Queue);
+        handle(
+            &mut server,
+            a_addr,
+            FromClient::Identify("alice".to_string()),
+        );
+        handle(&mut server, a_addr, FromClient::Queue);
+        assert_eq!(
+            messages(&a),
+            vec![
+                ToClient::YourAddr(a_addr),
+                ToClient::Peers(seq(&server, 0), HashSet::new()),
+                resume_token(&server, a_addr)
+            ]
+        );
+        // the held player isn't announced to the rest of the queue
+        assert_eq!(
+            messages(&c),
+            vec![
+                ToClient::YourAddr(c_addr),
+                ToClient::Peers(seq(&server, 0), HashSet::new()),
+                resume_token(&server, c_addr)
+            ]
+        );
+
+        handle(&mut server, b_addr, FromClient::Identify("bob".to_string()));
+        handle(&mut server, b_addr, FromClient::Queue);
+        assert_eq!(messages(&a), vec![ToClient::Scheduled(b_addr)]);
+        assert_eq!(
+            messages(&b),
+            vec![
+                ToClient::YourAddr(b_addr),
+                ToClient::Peers(seq(&server, 0), HashSet::new()),
+                resume_token(&server, b_addr),
+                ToClient::Scheduled(a_addr)
+            ]
+        );
+        assert!(messages(&c).is_empty());
+    }
+
+    #[test]
+    fn parties_are_proposed_as_a_group() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let a_addr = "127.0.0.2:2".parse().unwrap();
+        let b_addr = "127.0.0.3:3".parse().unwrap();
+        let c_addr = "127.0.0.4:4".parse().unwrap();
+        let a = network.transport(a_addr);
+        let b = network.transport(b_addr);
+        let c = network.transport(c_addr);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            server.flush().unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        let set = |addrs: &[SocketAddr]| addrs.iter().copied().collect::<HashSet<_>>();
+
+        for &addr in &[a_addr, b_addr, c_addr] {
+            verify(&mut server, addr);
+        }
+        handle(&mut server, c_addr, FromClient::Queue);
+        handle(&mut server, a_addr, FromClient::JoinParty(1));
+        handle(&mut server, a_addr, FromClient::Queue);
+        assert_eq!(
+            messages(&a),
+            vec![
+                ToClient::YourAddr(a_addr),
+                ToClient::Peers(seq(&server, 0), set(&[c_addr])),
+                resume_token(&server, a_addr),
+                ToClient::Party(vec![])
+            ]
+        );
+        assert_eq!(
+            messages(&c),
+            vec![
+                ToClient::YourAddr(c_addr),
+                ToClient::Peers(seq(&server, 0), set(&[])),
+                resume_token(&server, c_addr),
+                ToClient::Queued(seq(&server, 1), set(&[a_addr])),
+                ToClient::Parties(vec![vec![a_addr]])
+            ]
+        );
+
+        // teammates aren't proposed to each other
+        handle(&mut server, b_addr, FromClient::JoinParty(1));
+        handle(&mut server, b_addr, FromClient::Queue);
+        assert_eq!(
+            messages(&b),
+            vec![
+                ToClient::YourAddr(b_addr),
+                ToClient::Peers(seq(&server, 0), set(&[c_addr])),
+                resume_token(&server, b_addr),
+                ToClient::Party(vec![a_addr])
+            ]
+        );
+        assert_eq!(messages(&a), vec![ToClient::Party(vec![b_addr])]);
+        assert_eq!(
+            messages(&c),
+            vec![
+                ToClient::Queued(seq(&server, 2), set(&[b_addr])),
+                ToClient::Parties(vec![vec![a_addr, b_addr]])
+            ]
+        );
+    }
+
+    #[test]
+    fn players_are_proposed_as_the_criteria_relax() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        server.relax_criteria("rating=0, growth=10, regions=0".parse().unwrap());
+        let a_addr = "127.0.0.2:2".parse().unwrap();
+        let b_addr = "127.0.0.3:3".parse().unwrap();
+        let a = network.transport(a_addr);
+        let b = network.transport(b_addr);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            server.flush().unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        let profile = |rating| {
+            FromClient::Profile(Profile {
+                rating: Some(rating),
+                region: None,
+            })
+        };
+
+        for &(addr, rating) in &[(a_addr, 1000), (b_addr, 1050)] {
+            verify(&mut server, addr);
+            handle(&mut server, addr, profile(rating));
+            handle(&mut server, addr, FromClient::Queue);
+        }
+        let queued = |server: &Server<_>, addr| {
+            vec![
+                ToClient::YourAddr(addr),
+                ToClient::Peers(seq(server, 0), HashSet::new()),
+                resume_token(server, addr),
+            ]
+        };
+        assert_eq!(messages(&a), queued(&server, a_addr));
+        assert_eq!(messages(&b), queued(&server, b_addr));
+
+        let wait = |server: &mut Server<_>, secs| {
+            let waited = Duration::from_secs(secs);
+            for queued in server.queued_at.values_mut() {
+                *queued -= waited;
+            }
+            server.last_relaxed -= waited;
+            server.flush().unwrap();
+            network.deliver_all();
+        };
+        // after five seconds the rating windows cover the difference
+        wait(&mut server, 5);
+        let first = seq(&server, 1);
+        let announced = |addr| vec![ToClient::Queued(first, std::iter::once(addr).collect())];
+        assert_eq!(messages(&a), announced(b_addr));
+        assert_eq!(messages(&b), announced(a_addr));
+        wait(&mut server, 2);
+        assert!(messages(&a).is_empty());
+    }
+
+    #[test]
+    fn networks_with_poor_matches_are_kept_apart_for_a_while() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let a_addr: SocketAddr = "10.0.1.2:2".parse().unwrap();
+        let b_addr: SocketAddr = "10.0.2.3:3".parse().unwrap();
+        let a = network.transport(a_addr);
+        let b = network.transport(b_addr);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            server.flush().unwrap();
+            network.deliver_all();
+        };
+        // the peers the client was proposed
+        let proposed = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .flat_map(|msg| match msg {
+                    ToClient::Peers(_, peers) | ToClient::Queued(_, peers) => peers,
+                    _ => HashSet::new(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let report = |opponent| {
+            FromClient::MatchEnded(MatchReport {
+                opponents: vec![opponent],
+                outcome: Outcome::Draw,
+                duration: Duration::from_secs(60),
+                relayed: false,
+                average_rtt: Some(Duration::from_millis(300)),
+                rollback_frames: Some(600),
+            })
+        };
+
+        for &addr in &[a_addr, b_addr] {
+            verify(&mut server, addr);
+            handle(&mut server, addr, FromClient::Queue);
+        }
+        assert_eq!(proposed(&a), vec![b_addr]);
+        assert_eq!(proposed(&b), vec![a_addr]);
+        handle(&mut server, a_addr, FromClient::Matched(vec![b_addr]));
+        handle(&mut server, b_addr, FromClient::Matched(vec![a_addr]));
+        handle(&mut server, a_addr, report(b_addr));
+        handle(&mut server, b_addr, report(a_addr));
+        // the match was already scored
+        handle(&mut server, a_addr, report(b_addr));
+        assert_eq!(server.quality.average(a_addr.ip(), b_addr.ip()), Some(0));
+        assert!(!server.quality.avoids_any());
+        server.quality.record(a_addr.ip(), b_addr.ip(), 0);
+
+        for &addr in &[a_addr, b_addr] {
+            handle(&mut server, addr, FromClient::Queue);
+        }
+        assert!(proposed(&a).is_empty());
+        assert!(proposed(&b).is_empty());
+        // until both have waited long enough to be proposed anyone
+        let waited = Duration::from_secs(quality::AVOID_SECS);
+        for queued in server.queued_at.values_mut() {
+            *queued -= waited;
+        }
+        server.last_relaxed -= Duration::from_millis(RELAXATION_INTERVAL_MILLIS);
+        server.flush().unwrap();
+        network.deliver_all();
+        assert_eq!(proposed(&a), vec![b_addr]);
+        assert_eq!(proposed(&b), vec![a_addr]);
+    }
+
+    #[test]
+    fn queue_regions_count_the_queued_players() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+        };
+        let regions = [Some("eu"), Some("eu"), None, Some("na")];
+        for (i, region) in regions.iter().enumerate() {
+            let addr: SocketAddr = format!("127.0.0.{}:{}", i + 2, i + 2).parse().unwrap();
+            verify(&mut server, addr);
+            let profile = Profile {
+                rating: None,
+                region: region.map(str::to_string),
+            };
+            handle(&mut server, addr, FromClient::Profile(profile));
+            // the last client never queues
+            if i < 3 {
+                handle(&mut server, addr, FromClient::Queue);
+            }
+        }
+        let expected: BTreeMap<_, _> = vec![("eu".to_string(), 2), ("unknown".to_string(), 1)]
+            .into_iter()
+            .collect();
+        assert_eq!(server.queue_regions(), expected);
+    }
+
+    #[test]
+    fn unmatched_clients_are_offered_other_regions() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let other_server_addr: SocketAddr = "127.0.0.9:1".parse().unwrap();
+        let other_server = network.transport(other_server_addr);
+        server.federate(Federation::new("eu".to_string(), &[other_server_addr]));
+        server.relax_criteria("regions=3600".parse().unwrap());
+        let addrs: Vec<SocketAddr> = (2..6)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let offers = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .filter_map(|msg| match msg {
+                    ToClient::OtherRegions(regions) => Some(regions),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let flush = |server: &mut Server<_>| {
+            server.last_status -= Duration::from_millis(STATUS_INTERVAL_MILLIS);
+            server.flush().unwrap();
+            network.deliver_all();
+        };
+
+        // the first two clients are in regions nobody else is in, the last two match each other
+        let regions = ["oc", "af", "eu", "eu"];
+        for (&addr, region) in addrs.iter().zip(&regions) {
+            verify(&mut server, addr);
+            let profile = Profile {
+                rating: None,
+                region: Some(region.to_string()),
+            };
+            handle(&mut server, addr, FromClient::Profile(profile));
+            handle(&mut server, addr, FromClient::Queue);
+            *server.queued_at.get_mut(&addr).unwrap() -= Duration::from_secs(OFFER_AFTER_SECS);
+        }
+
+        // the other server is sent a summary, and nothing is offered before it has sent one
+        flush(&mut server);
+        let summaries: Vec<_> = other_server
+            .events()
+            .try_iter()
+            .filter_map(|event| match event {
+                TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                _ => None,
+            })
+            .collect();
+        match &summaries[..] {
+            [FromClient::QueueSummary(summary)] => {
+                assert_eq!(summary.region, "eu");
+                assert_eq!(summary.queued, 4);
+            }
+            msgs => panic!("expected a queue summary, got {:?}", msgs),
+        }
+        assert!(clients.iter().all(|client| offers(client).is_empty()));
+
+        let summary = |region: &str| QueueSummary {
+            region: region.to_string(),
+            queued: 7,
+            sent: 0,
+            echo: None,
+        };
+        // only the federated server's summaries are heard
+        handle(
+            &mut server,
+            "127.0.0.10:1".parse().unwrap(),
+            FromClient::QueueSummary(summary("as")),
+        );
+        handle(
+            &mut server,
+            other_server_addr,
+            FromClient::QueueSummary(summary("na")),
+        );
+        flush(&mut server);
+        let expected = vec![RegionSummary {
+            server: other_server_addr,
+            region: "na".to_string(),
+            queued: 7,
+            server_rtt: None,
+        }];
+        assert_eq!(offers(&clients[0]), vec![expected.clone()]);
+        assert_eq!(offers(&clients[1]), vec![expected]);
+        assert!(offers(&clients[2]).is_empty());
+        assert!(offers(&clients[3]).is_empty());
+
+        // the offer isn't repeated until the client queues again
+        flush(&mut server);
+        assert!(offers(&clients[0]).is_empty());
+        handle(&mut server, addrs[0], FromClient::Dequeue);
+        handle(&mut server, addrs[0], FromClient::Queue);
+        *server.queued_at.get_mut(&addrs[0]).unwrap() -= Duration::from_secs(OFFER_AFTER_SECS);
+        flush(&mut server);
+        assert_eq!(offers(&clients[0]).len(), 1);
+    }
+
+    #[test]
+    fn queued_clients_are_sent_their_estimated_wait() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..6)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+
+        // the last client is unrated
+        let ratings = [Some(1000), Some(1050), Some(1100), None];
+        let waited = [40, 30, 10, 20];
+        for ((&addr, &rating), &secs) in addrs.iter().zip(&ratings).zip(&waited) {
+            verify(&mut server, addr);
+            let profile = Profile {
+                rating,
+                region: None,
+            };
+            handle(&mut server, addr, FromClient::Profile(profile));
+            handle(&mut server, addr, FromClient::Queue);
+            *server.queued_at.get_mut(&addr).unwrap() -= Duration::from_secs(secs);
+        }
+        // the second client waited 30 seconds for its match
+        handle(&mut server, addrs[1], FromClient::Matched(vec![addrs[0]]));
+        assert!(!server.queue().contains(&addrs[1]));
+        server.flush().unwrap();
+        network.deliver_all();
+        for client in &clients {
+            messages(client);
+        }
+
+        server.last_status -= Duration::from_millis(STATUS_INTERVAL_MILLIS);
+        server.flush().unwrap();
+        network.deliver_all();
+        let status = |client| match messages(client)[..] {
+            [ToClient::QueueStatus(status)] => status,
+            ref msgs => panic!("expected the queue status, got {:?}", msgs),
+        };
+        let first = status(&clients[0]);
+        assert_eq!(first.position, 1);
+        assert_eq!(first.estimated_wait, Some(Duration::from_secs(0)));
+        let third = status(&clients[2]);
+        assert_eq!(third.position, 3);
+        let remaining = third.estimated_wait.unwrap().as_secs_f64();
+        assert!((19.0..=21.0).contains(&remaining), "{}", remaining);
+        let unrated = status(&clients[3]);
+        assert_eq!(unrated.position, 2);
+        assert_eq!(unrated.estimated_wait, None);
+        assert!(messages(&clients[1]).is_empty());
+    }
+
+    #[test]
+    fn prioritized_players_are_placed_ahead() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        server.prioritize("event-1=2,returning=1".parse().unwrap());
+        let addrs: Vec<SocketAddr> = (2..6)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let (player, event, returning, newcomer) = (addrs[0], addrs[1], addrs[2], addrs[3]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        let ids = ["player", "event-1", "returner"];
+        for (&addr, id) in addrs.iter().zip(&ids) {
+            verify(&mut server, addr);
+            handle(&mut server, addr, FromClient::Identify(id.to_string()));
+            handle(&mut server, addr, FromClient::Queue);
+        }
+        *server.queued_at.get_mut(&player).unwrap() -= Duration::from_secs(30);
+        // the returning player times out and comes back at another address
+        server
+            .handle_event(TransportEvent::Timeout(returning))
+            .unwrap();
+        let returned = "127.0.0.10:10".parse().unwrap();
+        let moved = network.transport(returned);
+        verify(&mut server, returned);
+        handle(
+            &mut server,
+            returned,
+            FromClient::Identify("returner".to_string()),
+        );
+        handle(&mut server, returned, FromClient::Queue);
+        assert_eq!(server.priority(returned), 1);
+
+        verify(&mut server, newcomer);
+        handle(&mut server, newcomer, FromClient::Queue);
+        let standings = messages(&clients[3])
+            .into_iter()
+            .find_map(|msg| match msg {
+                ToClient::Standings(standings) => Some(standings),
+                _ => None,
+            })
+            .unwrap();
+        let standing = |addr| standings.iter().find(|(c, _)| *c == addr).unwrap().1;
+        assert_eq!(standing(event).priority, 2);
+        assert_eq!(standing(returned).priority, 1);
+        assert_eq!(standing(player).priority, 0);
+        assert!(standing(player).waited >= Duration::from_secs(30));
+
+        server.flush().unwrap();
+        network.deliver_all();
+        for client in &clients {
+            messages(client);
+        }
+        messages(&moved);
+        server.last_status -= Duration::from_millis(STATUS_INTERVAL_MILLIS);
+        server.flush().unwrap();
+        network.deliver_all();
+        let position = |client| match messages(client)[..] {
+            [ToClient::QueueStatus(status)] => status.position,
+            ref msgs => panic!("expected the queue status, got {:?}", msgs),
+        };
+        assert_eq!(position(&clients[1]), 1);
+        assert_eq!(position(&moved), 2);
+        assert_eq!(position(&clients[0]), 3);
+        assert_eq!(position(&clients[3]), 4);
+    }
+
+    #[test]
+    fn peers_are_sent_the_handles_of_identified_players() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..5)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let peer_handles = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .filter_map(|msg| match msg {
+                    ToClient::PeerHandles(handles) => Some(handles),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        verify(&mut server, addrs[0]);
+        handle(
+            &mut server,
+            addrs[0],
+            FromClient::Identify("alice".to_string()),
+        );
+        handle(&mut server, addrs[0], FromClient::Queue);
+        server.flush().unwrap();
+        network.deliver_all();
+        assert!(peer_handles(&clients[0]).is_empty());
+        // anonymous players are sent handles but have none to send
+        verify(&mut server, addrs[1]);
+        handle(&mut server, addrs[1], FromClient::Queue);
+        assert_eq!(
+            peer_handles(&clients[1]),
+            vec![vec![(addrs[0], player_handle("alice"))]]
+        );
+        verify(&mut server, addrs[2]);
+        handle(
+            &mut server,
+            addrs[2],
+            FromClient::Identify("bob".to_string()),
+        );
+        handle(&mut server, addrs[2], FromClient::Queue);
+        assert_eq!(
+            peer_handles(&clients[2]),
+            vec![vec![(addrs[0], player_handle("alice"))]]
+        );
+
+        server.flush().unwrap();
+        network.deliver_all();
+        assert_eq!(
+            peer_handles(&clients[0]),
+            vec![vec![(addrs[2], player_handle("bob"))]]
+        );
+        assert_eq!(
+            peer_handles(&clients[1]),
+            vec![vec![(addrs[2], player_handle("bob"))]]
+        );
+        assert!(peer_handles(&clients[2]).is_empty());
+    }
+
+    #[test]
+    fn reported_matches_are_kept_in_the_history() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        server.store_history(history::MemoryHistory::new(MAX_HISTORY_MATCHES));
+        let a_addr = "127.0.0.2:2".parse().unwrap();
+        let b_addr = "127.0.0.3:3".parse().unwrap();
+        let stranger_addr = "127.0.0.4:4".parse().unwrap();
+        let a = network.transport(a_addr);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let report = |opponent, outcome| {
+            FromClient::MatchEnded(MatchReport {
+                opponents: vec![opponent],
+                outcome,
+                duration: Duration::from_secs(90),
+                relayed: true,
+                average_rtt: Some(Duration::from_millis(40)),
+                rollback_frames: None,
+            })
+        };
+
+        for &(addr, id) in &[(a_addr, "a"), (b_addr, "b")] {
+            verify(&mut server, addr);
+            handle(&mut server, addr, FromClient::Identify(id.to_string()));
+        }
+        handle(&mut server, a_addr, report(b_addr, Outcome::Win));
+        handle(&mut server, b_addr, report(a_addr, Outcome::Loss));
+        // clients that haven't identified have no history
+        handle(&mut server, stranger_addr, report(a_addr, Outcome::Win));
+        handle(
+            &mut server,
+            a_addr,
+            FromClient::FetchHistory("b".to_string()),
+        );
+        let history = a
+            .events()
+            .try_iter()
+            .filter_map(|event| match event {
+                TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                _ => None,
+            })
+            .find_map(|msg| match msg {
+                ToClient::History { player, matches } => Some((player, matches)),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(history.0, "b");
+        match &history.1[..] {
+            [record] => {
+                assert_eq!(record.opponents, vec!["a".to_string()]);
+                assert_eq!(record.outcome, Outcome::Loss);
+                assert!(record.relayed);
+            }
+            matches => panic!("expected one match, got {:?}", matches),
+        }
+    }
+
+    #[test]
+    fn leaderboard_ranks_the_reported_matches() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        server.rate_players(RatingSystem::Elo);
+        let a_addr = "127.0.0.2:2".parse().unwrap();
+        let b_addr = "127.0.0.3:3".parse().unwrap();
+        let a = network.transport(a_addr);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let report = |opponent, outcome| {
+            FromClient::MatchEnded(MatchReport {
+                opponents: vec![opponent],
+                outcome,
+                duration: Duration::from_secs(90),
+                relayed: false,
+                average_rtt: None,
+                rollback_frames: None,
+            })
+        };
+        let leaderboard = |server: &mut Server<_>, query| {
+            a.events().try_iter().for_each(drop);
+            handle(server, a_addr, FromClient::Leaderboard(query));
+            a.events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .find_map(|msg| match msg {
+                    ToClient::Leaderboard(standings) => Some(standings),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        for &(addr, id) in &[(a_addr, "a"), (b_addr, "b")] {
+            verify(&mut server, addr);
+            handle(&mut server, addr, FromClient::Identify(id.to_string()));
+            handle(&mut server, addr, FromClient::Queue);
+        }
+        for &(addr, opponent) in &[(a_addr, b_addr), (b_addr, a_addr)] {
+            handle(&mut server, addr, FromClient::Matched(vec![opponent]));
+        }
+        handle(&mut server, a_addr, report(b_addr, Outcome::Win));
+        // the match isn't rated until the opponent agrees
+        assert!(leaderboard(&mut server, LeaderboardQuery::Top { from: 1, count: 10 }).is_empty());
+        handle(&mut server, b_addr, report(a_addr, Outcome::Loss));
+        // the match was already reported
+        handle(&mut server, a_addr, report(b_addr, Outcome::Win));
+
+        let top = leaderboard(&mut server, LeaderboardQuery::Top { from: 1, count: 10 });
+        let ranked: Vec<_> = top.iter().map(|s| (s.rank, s.player.as_str())).collect();
+        assert_eq!(ranked, vec![(1, "a"), (2, "b")]);
+        assert_eq!(top[0].rating, ratings::INITIAL_RATING + 16);
+        let around = LeaderboardQuery::Around {
+            player: "b".to_string(),
+            count: 1,
+        };
+        let around = leaderboard(&mut server, around);
+        assert_eq!(around.len(), 1);
+        assert_eq!(around[0].rank, 2);
+    }
+
+    #[test]
+    fn webhooks_are_notified_once_per_match() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        server.notify_webhooks(Webhooks::new(&[url]).unwrap(), 2);
+        let a_addr: SocketAddr = "127.0.0.2:2".parse().unwrap();
+        let b_addr: SocketAddr = "127.0.0.3:3".parse().unwrap();
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+        };
+        let event = || {
+            let request = webhooks::test::receive(&listener);
+            let body = request.split("\r\n\r\n").nth(1).unwrap().to_string();
+            body
+        };
+
+        for &addr in &[a_addr, b_addr] {
+            verify(&mut server, addr);
+            handle(&mut server, addr, FromClient::Queue);
+        }
+        assert_eq!(event(), r#"{"event":"queue_spike","queued":2}"#);
+        handle(&mut server, a_addr, FromClient::Matched(vec![b_addr]));
+        handle(&mut server, b_addr, FromClient::Matched(vec![a_addr]));
+        assert!(
+            event().starts_with(r#"{"event":"match_created","players":[{"address":"127.0.0.2:2""#)
+        );
+        let report = MatchReport {
+            opponents: vec![a_addr],
+            outcome: Outcome::Win,
+            duration: Duration::from_secs(60),
+            relayed: false,
+            average_rtt: None,
+            rollback_frames: None,
+        };
+        handle(&mut server, b_addr, FromClient::MatchEnded(report));
+        // the second report of the match wasn't announced as a new match
+        assert!(event().starts_with(r#"{"event":"match_completed""#));
+    }
+
+    #[test]
+    fn queued_clients_follow_their_resume_token() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let a_addr = "127.0.0.2:2".parse().unwrap();
+        let b_addr = "127.0.0.3:3".parse().unwrap();
+        // a's NAT rebinds its port
+        let moved_addr = "127.0.0.2:20".parse().unwrap();
+        let a = network.transport(a_addr);
+        let b = network.transport(b_addr);
+        let moved = network.transport(moved_addr);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+
+        for &addr in &[a_addr, b_addr] {
+            verify(&mut server, addr);
+            handle(&mut server, addr, FromClient::Identify(addr.to_string()));
+            handle(&mut server, addr, FromClient::Queue);
+        }
+        let token = server.resume_token_of[&a_addr];
+        messages(&a);
+        messages(&b);
+
+        // resuming from the same address or with someone else's token does nothing
+        handle(&mut server, a_addr, FromClient::Resume(token));
+        handle(&mut server, moved_addr, FromClient::Resume(token ^ 1));
+        assert!(server.queue().contains(&a_addr));
+        assert!(messages(&moved).is_empty());
+
+        handle(&mut server, moved_addr, FromClient::Resume(token));
+        assert!(!server.queue().contains(&a_addr));
+        assert!(server.queue().contains(&moved_addr));
+        assert_eq!(server.identities[&moved_addr], a_addr.to_string());
+        assert_eq!(messages(&moved), vec![ToClient::YourAddr(moved_addr)]);
+        assert_eq!(
+            messages(&b),
+            vec![ToClient::PeerMoved {
+                from: a_addr,
+                to: moved_addr
+            }]
+        );
+        // the client is verified at its new address, and the old one times out harmlessly
+        server
+            .handle_event(TransportEvent::Timeout(a_addr))
+            .unwrap();
+        handle(&mut server, moved_addr, FromClient::ListRooms);
+        assert_eq!(messages(&moved), vec![ToClient::Rooms(vec![])]);
+        assert!(server.queue().contains(&moved_addr));
+        handle(&mut server, moved_addr, FromClient::Dequeue);
+        assert!(!server.resume_tokens.contains_key(&token));
+    }
+
+    #[test]
+    fn newer_sessions_supersede_stale_ones() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..5)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let (ghost, restarted, other) = (addrs[0], addrs[1], addrs[2]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        for &addr in &addrs {
+            verify(&mut server, addr);
+        }
+        handle(
+            &mut server,
+            ghost,
+            FromClient::Identify("player".to_string()),
+        );
+        handle(&mut server, ghost, FromClient::Queue);
+        handle(&mut server, other, FromClient::Queue);
+        messages(&clients[2]);
+        let ghost_token = server.resume_token_of[&ghost];
+
+        // without the ghost's resume token, the ID stays with the ghost
+        handle(
+            &mut server,
+            restarted,
+            FromClient::Identify("player".to_string()),
+        );
+        handle(
+            &mut server,
+            restarted,
+            FromClient::Supersede(ghost_token ^ 1),
+        );
+        assert!(server.queue.contains(&ghost));
+        assert!(!server.identities.contains_key(&restarted));
+        assert!(messages(&clients[2]).is_empty());
+
+        handle(&mut server, restarted, FromClient::Supersede(ghost_token));
+        handle(
+            &mut server,
+            restarted,
+            FromClient::Identify("player".to_string()),
+        );
+        handle(&mut server, restarted, FromClient::Queue);
+        assert_eq!(server.identities[&restarted], "player");
+        assert!(!server.queue.contains(&ghost));
+        assert!(!server.identities.contains_key(&ghost));
+        assert_eq!(
+            messages(&clients[2]),
+            vec![ToClient::Dequeued(seq(&server, 1), ghost)]
+        );
+        let token = resume_token(&server, restarted);
+        assert_eq!(
+            messages(&clients[1]),
+            vec![
+                ToClient::YourAddr(restarted),
+                ToClient::Peers(seq(&server, 0), vec![other].into_iter().collect()),
+                token,
+            ]
+        );
+        // requeueing doesn't supersede anyone
+        handle(&mut server, restarted, FromClient::Queue);
+        assert_eq!(server.queue.len(), 2);
+    }
+
+    #[test]
+    fn unheard_sessions_are_superseded_by_the_same_player() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..5)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let (ghost, twin, restarted) = (addrs[0], addrs[1], addrs[2]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        for &addr in &addrs {
+            verify(&mut server, addr);
+        }
+
+        // two clients that identified before queueing can't both queue with the ID
+        for &addr in &[ghost, twin] {
+            handle(
+                &mut server,
+                addr,
+                FromClient::Identify("player".to_string()),
+            );
+        }
+        handle(&mut server, ghost, FromClient::Queue);
+        handle(&mut server, twin, FromClient::Queue);
+        assert_eq!(server.identities[&ghost], "player");
+        assert!(!server.identities.contains_key(&twin));
+        assert!(server.queue.contains(&twin));
+        messages(&clients[1]);
+
+        // once the ghost has gone unheard, the same player identifying supersedes it
+        *server.last_heard.get_mut(&ghost).unwrap() -= Duration::from_secs(SUPERSEDE_AFTER_SECS);
+        handle(
+            &mut server,
+            restarted,
+            FromClient::Identify("player".to_string()),
+        );
+        assert_eq!(server.identities[&restarted], "player");
+        assert!(!server.queue.contains(&ghost));
+        assert!(!server.identities.contains_key(&ghost));
+        assert_eq!(
+            messages(&clients[1]),
+            vec![ToClient::Dequeued(seq(&server, 1), ghost)]
+        );
+    }
+
+    #[test]
+    fn the_queue_is_told_about_clients_that_time_out() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..5)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let (leaver, queued, idle) = (addrs[0], addrs[1], addrs[2]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            server.flush().unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        for &addr in &addrs {
+            verify(&mut server, addr);
+        }
+        handle(&mut server, leaver, FromClient::Queue);
+        handle(&mut server, queued, FromClient::Queue);
+        messages(&clients[1]);
+        messages(&clients[2]);
+
+        server
+            .handle_event(TransportEvent::Timeout(leaver))
+            .unwrap();
+        network.deliver_all();
+        assert!(!server.queue.contains(&leaver));
+        assert_eq!(
+            messages(&clients[1]),
+            vec![ToClient::Dequeued(seq(&server, 1), leaver)]
+        );
+        // only the queue is told
+        assert!(messages(&clients[2]).is_empty());
+        server.handle_event(TransportEvent::Timeout(idle)).unwrap();
+        network.deliver_all();
+        assert!(messages(&clients[1]).is_empty());
+    }
+
+    #[test]
+    fn friends_share_their_presence() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..5)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let (alice, bob, carol) = (addrs[0], addrs[1], addrs[2]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .filter(|msg| {
+                    matches!(
+                        msg,
+                        ToClient::Presence(_)
+                            | ToClient::FriendAddr { .. }
+                            | ToClient::FriendChallenge { .. }
+                    )
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        let flush = |server: &mut Server<_>| {
+            server.flush().unwrap();
+            network.deliver_all();
+        };
+        let presence = |id: &str, presence| ToClient::Presence(vec![(id.to_string(), presence)]);
+        let friends =
+            |ids: &[&str]| FromClient::Friends(ids.iter().map(|id| id.to_string()).collect());
+
+        for (&addr, id) in addrs.iter().zip(&["alice", "bob", "carol"]) {
+            verify(&mut server, addr);
+            handle(&mut server, addr, FromClient::Identify(id.to_string()));
+        }
+        handle(&mut server, alice, friends(&["bob"]));
+        assert_eq!(messages(&clients[0]), vec![ToClient::Presence(vec![])]);
+        flush(&mut server);
+        handle(&mut server, bob, friends(&["alice"]));
+        assert_eq!(
+            messages(&clients[1]),
+            vec![presence("alice", Presence::Online)]
+        );
+        flush(&mut server);
+        assert_eq!(
+            messages(&clients[0]),
+            vec![presence("bob", Presence::Online)]
+        );
+        assert!(messages(&clients[1]).is_empty());
+
+        // carol listed alice, who didn't list carol back
+        handle(&mut server, carol, friends(&["alice"]));
+        assert_eq!(messages(&clients[2]), vec![ToClient::Presence(vec![])]);
+        handle(&mut server, alice, FromClient::Queue);
+        flush(&mut server);
+        assert!(messages(&clients[0]).is_empty());
+        assert_eq!(
+            messages(&clients[1]),
+            vec![presence("alice", Presence::Queued)]
+        );
+        assert!(messages(&clients[2]).is_empty());
+
+        handle(
+            &mut server,
+            carol,
+            FromClient::ChallengeFriend("alice".to_string()),
+        );
+        assert!(messages(&clients[0]).is_empty());
+        handle(
+            &mut server,
+            bob,
+            FromClient::ChallengeFriend("alice".to_string()),
+        );
+        assert_eq!(
+            messages(&clients[0]),
+            vec![ToClient::FriendChallenge {
+                friend: "bob".to_string(),
+                addr: bob
+            }]
+        );
+        assert_eq!(
+            messages(&clients[1]),
+            vec![ToClient::FriendAddr {
+                friend: "alice".to_string(),
+                addr: alice
+            }]
+        );
+
+        server.handle_event(TransportEvent::Timeout(alice)).unwrap();
+        flush(&mut server);
+        assert_eq!(
+            messages(&clients[1]),
+            vec![presence("alice", Presence::Offline)]
+        );
+        handle(
+            &mut server,
+            bob,
+            FromClient::ChallengeFriend("alice".to_string()),
+        );
+        assert!(messages(&clients[1]).is_empty());
+    }
+
+    #[test]
+    fn invites_introduce_the_client_that_redeems_them() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..5)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let (host, guest, late) = (addrs[0], addrs[1], addrs[2]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+
+        for (&addr, client) in addrs.iter().zip(&clients) {
+            verify(&mut server, addr);
+            network.deliver_all();
+            messages(client);
+        }
+        handle(&mut server, host, FromClient::CreateInvite);
+        let code = match &messages(&clients[0])[..] {
+            [ToClient::Invite(code)] => code.clone(),
+            other => panic!("expected an invite, got {:?}", other),
+        };
+        handle(&mut server, guest, FromClient::RedeemInvite(code.clone()));
+        assert_eq!(messages(&clients[0]), vec![ToClient::InviteRedeemed(guest)]);
+        assert_eq!(
+            messages(&clients[1]),
+            vec![ToClient::InviteHost {
+                code: code.clone(),
+                addr: host
+            }]
+        );
+        handle(&mut server, late, FromClient::RedeemInvite(code.clone()));
+        assert_eq!(messages(&clients[2]), vec![ToClient::InviteInvalid(code)]);
+        assert!(messages(&clients[0]).is_empty());
+
+        // the invite closes when its creator times out
+        handle(&mut server, host, FromClient::CreateInvite);
+        let code = match &messages(&clients[0])[..] {
+            [ToClient::Invite(code)] => code.clone(),
+            other => panic!("expected an invite, got {:?}", other),
+        };
+        server.handle_event(TransportEvent::Timeout(host)).unwrap();
+        handle(&mut server, guest, FromClient::RedeemInvite(code.clone()));
+        assert_eq!(messages(&clients[1]), vec![ToClient::InviteInvalid(code)]);
+    }
+
+    #[test]
+    fn idle_clients_are_hidden_until_active() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..6)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let (idle, b, c, d) = (addrs[0], addrs[1], addrs[2], addrs[3]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+
+        for &addr in &addrs[..3] {
+            verify(&mut server, addr);
+            handle(&mut server, addr, FromClient::Queue);
+        }
+        server.flush().unwrap();
+        network.deliver_all();
+        for client in &clients {
+            messages(client);
+        }
+
+        // a client that isn't queued can't report anyone, and one report isn't enough
+        handle(&mut server, d, FromClient::Unresponsive(idle));
+        handle(&mut server, b, FromClient::Unresponsive(idle));
+        handle(&mut server, b, FromClient::Unresponsive(idle));
+        assert!(messages(&clients[0]).is_empty());
+        // reports expire
+        for reported in server
+            .unresponsive
+            .values_mut()
+            .flat_map(|r| r.values_mut())
+        {
+            *reported -= Duration::from_secs(IDLE_REPORT_WINDOW_SECS);
+        }
+        handle(&mut server, c, FromClient::Unresponsive(idle));
+        assert!(messages(&clients[0]).is_empty());
+        handle(&mut server, b, FromClient::Unresponsive(idle));
+        assert_eq!(
+            messages(&clients[0]),
+            vec![ToClient::Idle { dequeued: false }]
+        );
+        let dequeued = ToClient::Dequeued(seq(&server, 2), idle);
+        assert_eq!(messages(&clients[1]), vec![dequeued.clone()]);
+        assert_eq!(messages(&clients[2]), vec![dequeued]);
+
+        // the idle client stays queued but isn't proposed to those who queue after it
+        assert!(server.queue().contains(&idle));
+        verify(&mut server, d);
+        handle(&mut server, d, FromClient::Queue);
+        assert_eq!(
+            messages(&clients[3]),
+            vec![
+                ToClient::YourAddr(d),
+                ToClient::Peers(seq(&server, 0), vec![b, c].into_iter().collect()),
+                resume_token(&server, d)
+            ]
+        );
+        server.flush().unwrap();
+        network.deliver_all();
+        for client in &clients {
+            messages(client);
+        }
+
+        handle(&mut server, idle, FromClient::Active);
+        server.flush().unwrap();
+        network.deliver_all();
+        // the earlier clients were also told about d meanwhile
+        for (client, sequence) in clients[1..].iter().zip(&[4, 4, 1]) {
+            let announced =
+                ToClient::Queued(seq(&server, *sequence), vec![idle].into_iter().collect());
+            assert_eq!(messages(client), vec![announced]);
+        }
+
+        // or dequeued, if the server dequeues idle clients
+        server.dequeue_idle();
+        handle(&mut server, b, FromClient::Unresponsive(idle));
+        handle(&mut server, c, FromClient::Unresponsive(idle));
+        assert_eq!(
+            messages(&clients[0]),
+            vec![ToClient::Idle { dequeued: true }]
+        );
+        assert!(!server.queue().contains(&idle));
+    }
+
+    #[test]
+    fn idle_reports_scale_with_the_queue() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..9)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let idle = network.transport(addrs[0]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let is_idle = || {
+            idle.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => matches!(
+                    bincode::deserialize(packet.payload()),
+                    Ok(ToClient::Idle { .. })
+                ),
+                _ => false,
+            })
+        };
+
+        for &addr in &addrs {
+            verify(&mut server, addr);
+            handle(&mut server, addr, FromClient::Queue);
+        }
+        // the client is proposed to six others, so three of them have to report it
+        let reporters = &addrs[1..4];
+        for &reporter in &reporters[..2] {
+            handle(&mut server, reporter, FromClient::Unresponsive(addrs[0]));
+        }
+        assert!(!is_idle());
+        handle(
+            &mut server,
+            reporters[2],
+            FromClient::Unresponsive(addrs[0]),
+        );
+        assert!(is_idle());
+    }
+
+    #[test]
+    fn a_draining_server_only_matches_the_queued_clients() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..5)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let (a, b, late) = (addrs[0], addrs[1], addrs[2]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            server.flush().unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+
+        for &addr in &addrs {
+            verify(&mut server, addr);
+        }
+        handle(&mut server, a, FromClient::Queue);
+        handle(&mut server, b, FromClient::Queue);
+        for client in &clients {
+            messages(client);
+        }
+
+        let drain = server.drain_handle();
+        assert!(!drain.is_draining());
+        drain.drain();
+        handle(&mut server, late, FromClient::Queue);
+        assert_eq!(
+            messages(&clients[2]),
+            vec![ToClient::Rejected(RejectReason::Draining)]
+        );
+        assert!(!server.queue().contains(&late));
+        // the queued clients still refresh their peers
+        handle(&mut server, a, FromClient::Queue);
+        assert_eq!(
+            messages(&clients[0]),
+            vec![ToClient::Peers(
+                seq(&server, 0),
+                vec![b].into_iter().collect()
+            )]
+        );
+        assert!(!server.is_drained());
+
+        handle(&mut server, a, FromClient::Dequeue);
+        handle(&mut server, b, FromClient::Dequeue);
+        assert!(server.is_drained());
+    }
+
+    #[test]
+    fn configuration_changes_apply_to_the_running_queue() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..5)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let (a, b, c) = (addrs[0], addrs[1], addrs[2]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+
+        for &addr in &addrs {
+            verify(&mut server, addr);
+        }
+        handle(&mut server, a, FromClient::Queue);
+        handle(&mut server, b, FromClient::Queue);
+        for client in &clients {
+            messages(client);
+        }
+
+        // the queued clients stay queued when the cap is lowered
+        server.configure(&"max_queue = 1\nmax_refreshes = 0".parse().unwrap());
+        assert_eq!(server.queue().len(), 2);
+        handle(&mut server, c, FromClient::Queue);
+        assert_eq!(
+            messages(&clients[2]),
+            vec![ToClient::Rejected(RejectReason::QueueFull)]
+        );
+        handle(&mut server, a, FromClient::Queue);
+        assert!(messages(&clients[0]).is_empty());
+
+        // settings missing from the configuration are left as they are
+        server.configure(&"max_queue = 3".parse().unwrap());
+        handle(&mut server, a, FromClient::Queue);
+        assert!(messages(&clients[0]).is_empty());
+        handle(&mut server, c, FromClient::Queue);
+        assert!(server.queue().contains(&c));
+    }
+
+    #[test]
+    fn the_queue_is_restored_from_a_snapshot() {
+        let addrs: Vec<SocketAddr> = (2..5)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let (a, b, c) = (addrs[0], addrs[1], addrs[2]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        for &addr in &[a, b] {
+            verify(&mut server, addr);
+        }
+        handle(&mut server, a, FromClient::Identify("alice".to_string()));
+        handle(&mut server, a, FromClient::Queue);
+        handle(&mut server, b, FromClient::Queue);
+        let token = server.resume_token_of[&a];
+        let snapshot = server.snapshot();
+        let epoch = server.epoch;
+
+        // the restarted server knows the queued clients without them queueing again
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        server.restore(snapshot.clone());
+        assert_eq!(server.queue(), &[a, b].iter().copied().collect());
+        assert_eq!(server.identities[&a], "alice");
+        // the restored clients can tell they missed the updates sent while the server was down
+        assert_ne!(server.epoch, epoch);
+        verify(&mut server, c);
+        handle(&mut server, c, FromClient::Queue);
+        network.deliver_all();
+        assert!(messages(&clients[2]).contains(&ToClient::Peers(
+            seq(&server, 0),
+            [a, b].iter().copied().collect()
+        )));
+
+        // the restored clients that don't return in time are dequeued
+        handle(&mut server, a, FromClient::Resume(token));
+        server.restored_at -= Duration::from_secs(RESTORE_GRACE_SECS);
+        server.flush().unwrap();
+        network.deliver_all();
+        assert!(server.queue().contains(&a));
+        assert!(!server.queue().contains(&b));
+        assert!(messages(&clients[0])
+            .iter()
+            .any(|msg| matches!(msg, ToClient::Dequeued(_, addr) if *addr == b)));
+
+        // entries without a resume token are skipped
+        let mut tokenless = snapshot.clone();
+        tokenless.queue[0].resume_token = None;
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        server.restore(tokenless);
+        assert_eq!(server.queue().len(), 1);
+        assert!(!server.resume_tokens.contains_key(&0));
+
+        // the queue of a stale snapshot is left out
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        server.restore(Snapshot {
+            saved_at: snapshot.saved_at - Duration::from_secs(snapshot::MAX_QUEUE_AGE_SECS),
+            ..snapshot
+        });
+        assert!(server.queue().is_empty());
+    }
+
+    #[test]
+    fn repeated_queue_requests_only_refresh_the_peers() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..4)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let (a, b) = (addrs[0], addrs[1]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            server.flush().unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+
+        for &addr in &addrs {
+            verify(&mut server, addr);
+            handle(&mut server, addr, FromClient::Queue);
+        }
+        for client in &clients {
+            messages(client);
+        }
+
+        // the client keeps its resume token and the rest of the queue isn't told about it again
+        let token = resume_token(&server, b);
+        handle(&mut server, b, FromClient::Queue);
+        assert_eq!(
+            messages(&clients[1]),
+            vec![ToClient::Peers(
+                seq(&server, 0),
+                vec![a].into_iter().collect()
+            )]
+        );
+        assert!(messages(&clients[0]).is_empty());
+        assert_eq!(resume_token(&server, b), token);
+
+        // refreshing too often is ignored
+        for _ in 1..MAX_REFRESHES + 3 {
+            handle(&mut server, b, FromClient::Queue);
+        }
+        assert_eq!(messages(&clients[1]).len(), MAX_REFRESHES as usize - 1);
+        assert!(messages(&clients[0]).is_empty());
+
+        // the limit starts over when the client queues again
+        handle(&mut server, b, FromClient::Dequeue);
+        handle(&mut server, b, FromClient::Queue);
+        handle(&mut server, b, FromClient::Queue);
+        let refreshed = messages(&clients[1])
+            .into_iter()
+            .filter(|msg| matches!(msg, ToClient::Peers(_, _)))
+            .count();
+        assert_eq!(refreshed, 2);
+        assert_eq!(
+            messages(&clients[0]),
+            vec![ToClient::Queued(
+                seq(&server, 2),
+                vec![b].into_iter().collect()
+            )]
+        );
+    }
+
+    #[test]
+    fn peer_list_updates_are_numbered_until_a_resync() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let addrs: Vec<SocketAddr> = (2..5)
+            .map(|i| format!("127.0.0.{}:{}", i, i).parse().unwrap())
+            .collect();
+        let clients: Vec<_> = addrs.iter().map(|&addr| network.transport(addr)).collect();
+        let (a, b, c) = (addrs[0], addrs[1], addrs[2]);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::reliable_ordered(from, msg, 2);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            server.flush().unwrap();
+            network.deliver_all();
+        };
+        let updates = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .filter(|msg| {
+                    matches!(
+                        msg,
+                        ToClient::Peers(..) | ToClient::Queued(..) | ToClient::Dequeued(..)
+                    )
+                })
+                .collect::<Vec<ToClient>>()
+        };
+        let set = |addrs: &[SocketAddr]| addrs.iter().copied().collect::<HashSet<_>>();
+
+        for &addr in &addrs {
+            verify(&mut server, addr);
+        }
+        handle(&mut server, a, FromClient::Queue);
+        handle(&mut server, b, FromClient::Queue);
+        server.handle_event(TransportEvent::Timeout(b)).unwrap();
+        network.deliver_all();
+        assert_eq!(
+            updates(&clients[0]),
+            vec![
+                ToClient::Peers(seq(&server, 0), set(&[])),
+                ToClient::Queued(seq(&server, 1), set(&[b])),
+                ToClient::Dequeued(seq(&server, 2), b)
+            ]
+        );
+
+        // a client that isn't queued has no list to resync
+        handle(&mut server, c, FromClient::Resync);
+        assert!(updates(&clients[2]).is_empty());
+
+        // the list starts a new sequence, even for a client out of refreshes
+        server.configure(&"max_refreshes = 0".parse().unwrap());
+        handle(&mut server, a, FromClient::Resync);
+        handle(&mut server, c, FromClient::Queue);
+        assert_eq!(
+            updates(&clients[0]),
+            vec![
+                ToClient::Peers(seq(&server, 0), set(&[])),
+                ToClient::Queued(seq(&server, 1), set(&[c]))
+            ]
+        );
+    }
+
+    #[test]
+    fn unverified_sources_are_sent_a_cookie() {
+        let network = MockNetwork::new();
+        let mut server = Server::new(network.transport("127.0.0.1:1".parse().unwrap()));
+        let client_addr = "127.0.0.2:2".parse().unwrap();
+        let spoofed_addr = "127.0.0.3:3".parse().unwrap();
+        let client = network.transport(client_addr);
+        let spoofed = network.transport(spoofed_addr);
+        let handle = |server: &mut Server<_>, from: SocketAddr, msg: FromClient| {
+            let msg = bincode::serialize(&msg).unwrap();
+            let packet = mirai_core::transport::Packet::unreliable(from, msg);
+            server.handle_event(TransportEvent::Packet(packet)).unwrap();
+            network.deliver_all();
+        };
+        let messages = |client: &mirai_core::transport::MockTransport| {
+            client
+                .events()
+                .try_iter()
+                .filter_map(|event| match event {
+                    TransportEvent::Packet(packet) => bincode::deserialize(packet.payload()).ok(),
+                    _ => None,
+                })
+                .collect::<Vec<ToClient>>()
+        };
+
+        handle(&mut server, client_addr, FromClient::Queue);
+        let cookie = match messages(&client)[..] {
+            [ToClient::Cookie(cookie)] => cookie,
+            ref msgs => panic!("expected a cookie, got {:?}", msgs),
+        };
+        assert!(server.queue().is_empty());
+        // a wrong cookie doesn't verify the client
+        handle(&mut server, client_addr, FromClient::Cookie(cookie ^ 1));
+        handle(&mut server, client_addr, FromClient::Queue);
+        assert_eq!(messages(&client), vec![ToClient::Cookie(cookie)]);
+        handle(&mut server, client_addr, FromClient::Cookie(cookie));
+        handle(&mut server, client_addr, FromClient::Queue);
+        assert_eq!(
+            messages(&client),
+            vec![
+                ToClient::YourAddr(client_addr),
+                ToClient::Peers(seq(&server, 0), HashSet::new()),
+                resume_token(&server, client_addr)
+            ]
+        );
+        assert!(server.queue().contains(&client_addr));
+
+        // an unverified source is sent at most a few times what it sent
+        handle(&mut server, spoofed_addr, FromClient::StatusCheck);
+        let large = mirai_core::transport::Packet::unreliable(spoofed_addr, vec![0; 64]);
+        server.reply(large).unwrap();
+        network.deliver_all();
+        assert_eq!(messages(&spoofed), vec![ToClient::Alive]);
+    }
+
+    // blocks sending to one address until the gate is dropped
+    struct BlockingTransport {
+        inner: mirai_core::transport::MockTransport,
+        blocked: SocketAddr,
+        gate: crossbeam_channel::Receiver<()>,
+    }
+
+    impl Transport for BlockingTransport {
+        fn send(&self, packet: mirai_core::transport::Packet) -> Result<(), TransportError> {
+            if packet.addr() == self.blocked {
+                let _ = self.gate.recv();
+            }
+            self.inner.send(packet)
+        }
+
+        fn events(&self) -> &crossbeam_channel::Receiver<TransportEvent> {
+            self.inner.events()
+        }
+    }
+
+    #[test]
+    fn slow_client_does_not_stall_others() {
+        let network = MockNetwork::new();
+        let slow_addr: SocketAddr = "127.0.0.2:1".parse().unwrap();
+        // a client whose packets go through a different worker
+        let fast_addr = (2..)
+            .map(|port| SocketAddr::new(slow_addr.ip(), port))
+            .find(|&addr| worker_for(addr, SEND_WORKERS) != worker_for(slow_addr, SEND_WORKERS))
+            .unwrap();
+        let server_addr = "127.0.0.1:1".parse().unwrap();
+        let (gate_sender, gate) = crossbeam_channel::unbounded();
+        let transport = BlockingTransport {
+            inner: network.transport(server_addr),
+            blocked: slow_addr,
+            gate,
+        };
+        std::thread::spawn(move || with_transport(transport));
+        let slow = network.transport(slow_addr);
+        let fast = network.transport(fast_addr);
+
+        let queue = bincode::serialize(&FromClient::Queue).unwrap();
+        slow.send(mirai_core::transport::Packet::reliable_unordered(
+            server_addr,
+            queue,
+        ))
+        .unwrap();
+        let status_check = bincode::serialize(&FromClient::StatusCheck).unwrap();
+        fast.send(mirai_core::transport::Packet::reliable_unordered(
+            server_addr,
+            status_check,
+        ))
+        .unwrap();
+        let start = Instant::now();
+        loop {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            network.deliver_all();
+            let alive = fast.events().try_iter().any(|event| match event {
+                TransportEvent::Packet(packet) => {
+                    bincode::deserialize::<ToClient>(packet.payload()).ok() == Some(ToClient::Alive)
+                }
+                _ => false,
+            });
+            if alive {
+                break;
+            }
+            std::thread::yield_now();
+        }
+        drop(gate_sender);
+    }
+
+    #[test]
+    fn timeout_test() {
+        let server_socket = Socket::bind_any().unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        start_test_server(server_socket);
+        let mut socket_1 = Socket::bind_any().unwrap();
+        let mut socket_2 = Socket::bind_any().unwrap();
+        wait_for_server(server_addr);
+
+        queue(&mut socket_1, server_addr);
+        std::thread::sleep(std::time::Duration::from_secs(6));
+
+        queue(&mut socket_2, server_addr);
+        let peers = expect_msg(&mut socket_2, ToClient::Peers(any_seq(), HashSet::new())).unwrap();
+        if let ToClient::Peers(_, peers) = peers {
+            assert_eq!(
+                peers,
+                HashSet::new(),
+                "first client should have timed out of the queue"
+            );
+        }
+    }
+}
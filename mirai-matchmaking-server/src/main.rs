@@ -1,29 +1,89 @@
-//! The Mirai matchmaking server facilitates peer discovery for Mirai matchmaking clients.
-//! The server can receive the following messages:
-//!     StatusCheck
-//!         returns Alive to signal that it's running
-//!     Queue
-//!         if the client is not already in the queue, adds the client to the queue
-//!         selects a set of potential matches (currently the entire queue)
-//!         sends the client's info to all potential matches
-//!         returns the potential matches to the client
-//!     Dequeue
-//!         removes the client from the queue
-//!     Heartbeat
-//!         ignored
-//! Clients are dequeued when the connection times out.
+//! Runs the Mirai matchmaking server, see the library for the protocol.
+//!
+//! The server listens on the same port over both UDP and TCP, so clients on networks that block UDP
+//! can still reach it.
 //!
 //! Run using cargo run server_ip, e.g. cargo run 127.0.0.1
+//! Set MIRAI_IMPAIRMENT to simulate a bad network, e.g. MIRAI_IMPAIRMENT=latency=50,loss=0.05
+//! Set MIRAI_DEQUEUE_IDLE to dequeue the clients that leave their peers' challenges unanswered instead of
+//! only hiding them from the rest of the queue, e.g. MIRAI_DEQUEUE_IDLE=1
+//! Set MIRAI_RELAY to relay packets between matched clients that can't reach each other, e.g. MIRAI_RELAY=1,
+//! and MIRAI_RELAY_LIMITS to cap each relay's bytes per second and in total,
+//! e.g. MIRAI_RELAY_LIMITS=rate=65536,total=104857600
+//! Set MIRAI_REPLAYS to keep the replays uploaded by clients in a directory, e.g. MIRAI_REPLAYS=replays
+//! Set MIRAI_HISTORY to keep the matches reported by identified clients in a file, e.g. MIRAI_HISTORY=matches.bin
+//! Set MIRAI_RATINGS to rate players by the matches they report with elo or glicko2
+//! and answer leaderboard queries, e.g. MIRAI_RATINGS=glicko2
+//! Set MIRAI_RESERVATIONS to a file of reserved matches, one `player player start` per line with the start
+//! in seconds since the Unix epoch, e.g. MIRAI_RESERVATIONS=fixtures.txt
+//! Set MIRAI_CRITERIA to only propose players whose ratings and regions match, with criteria that relax over
+//! time, e.g. MIRAI_CRITERIA=rating=100,growth=10,max=1000,regions=30
+//! Set MIRAI_PRIORITIES to a roster of the player IDs to place ahead in the queue, one `player=tier` per line,
+//! and `returning=tier` for those returning after a disconnect, e.g. MIRAI_PRIORITIES=roster.txt
+//! With the scripting feature, set MIRAI_POLICY to a rhai script that filters the proposed players,
+//! e.g. MIRAI_POLICY=policy.rhai
+//! With the geoip feature, set MIRAI_GEOIP to a MaxMind database to place the clients that don't declare
+//! a region in the one they are located in, e.g. MIRAI_GEOIP=GeoLite2-Country.mmdb, and MIRAI_GEOIP_REGIONS
+//! to the regions of countries and continents, e.g. MIRAI_GEOIP_REGIONS=US=na-east,CA=na-east,EU=eu
+//! Set MIRAI_FEDERATION to the comma separated addresses of the servers of other regions to share queue
+//! summaries with them, e.g. MIRAI_FEDERATION=203.0.113.7:12345, and MIRAI_REGION to this server's region,
+//! e.g. MIRAI_REGION=eu. The summaries aren't encrypted, so federated servers can't set MIRAI_SECRET_KEY
+//! Set MIRAI_WEBHOOKS to POST the server's events to comma separated http URLs,
+//! e.g. MIRAI_WEBHOOKS=http://localhost:8080/mirai, and MIRAI_QUEUE_SPIKE to the queue size announced as
+//! a spike, e.g. MIRAI_QUEUE_SPIKE=500
+//! Set MIRAI_SECRET_KEY to encrypt the traffic with the clients, which are given the public key
+//! logged at startup. A new key is generated with cargo run generate-key
+//! Set MIRAI_CONFIG to a configuration file that's reloaded when it changes, to tune the rate limits, queue cap,
+//! matchmaking windows and log level without a restart, e.g. MIRAI_CONFIG=mirai.conf, see the config module.
+//! Without RUST_LOG, its log_level decides how verbose the logs are
+//! Set MIRAI_SNAPSHOT to a file the queue, rooms and ratings are saved to while the server runs and restored
+//! from when it restarts, e.g. MIRAI_SNAPSHOT=snapshot.bin, see the snapshot module
+//! Set MIRAI_DRAIN_FILE to a path whose creation drains the server before a deployment, e.g.
+//! MIRAI_DRAIN_FILE=/run/mirai/drain, after which it stops queueing new clients and logs once it can be stopped
 
-use crossbeam_channel::SendError;
-use laminar::{Packet, Socket, SocketEvent};
-use log::{debug, error, info, trace, warn};
-use mirai_core::v1::{server::*, SERVER_PORT};
+use mirai_core::crypto::{key_from_hex, key_to_hex, Keypair};
+use mirai_core::logging::{debug, error, info, set_max_level, warn, LevelFilter};
+use mirai_core::transport::impair::{impair, Impairment, ImpairmentError};
+use mirai_core::transport::{
+    LaminarTransport, MultiTransport, SecureTransport, TcpTransport, Transport,
+};
+use mirai_core::v1::SERVER_PORT;
+use mirai_matchmaking_server::bandwidth::{RelayLimits, RelayLimitsError};
+use mirai_matchmaking_server::config::{ConfigError, ConfigFile};
+use mirai_matchmaking_server::criteria::{Relaxation, RelaxationError};
+use mirai_matchmaking_server::federation::Federation;
+#[cfg(feature = "geoip")]
+use mirai_matchmaking_server::geoip::{GeoIp, GeoIpError, RegionMap};
+use mirai_matchmaking_server::history::FileHistory;
+#[cfg(feature = "scripting")]
+use mirai_matchmaking_server::policy::{PolicyError, ScriptPolicy};
+use mirai_matchmaking_server::priority::{Priorities, PrioritiesError};
+use mirai_matchmaking_server::ratings::{RatingSystem, RatingSystemError};
+use mirai_matchmaking_server::replays::DirectoryReplays;
+use mirai_matchmaking_server::snapshot::Snapshot;
+use mirai_matchmaking_server::webhooks::{WebhookError, Webhooks, DEFAULT_QUEUE_SPIKE};
+use mirai_matchmaking_server::{Reservation, Server, ServerError, MAX_HISTORY_MATCHES};
 use snafu::{ErrorCompat, ResultExt, Snafu};
-use std::{collections::HashSet, env, net::SocketAddr};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use std::{env, net::SocketAddr};
+
+// how often the drain file is checked for
+const DRAIN_CHECK_INTERVAL_MILLIS: u64 = 1000;
 
 fn main() {
-    env_logger::init();
+    // RUST_LOG's directives apply as usual, but without it the level can be raised later by the configuration
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(LevelFilter::Trace);
+    let filters = env::var("RUST_LOG");
+    if let Ok(filters) = &filters {
+        logger.parse_filters(filters);
+    }
+    logger.init();
+    if filters.is_err() {
+        set_max_level(LevelFilter::Error);
+    }
     if let Err(e) = run() {
         error!("{}", e);
         if let Some(backtrace) = ErrorCompat::backtrace(&e) {
@@ -35,303 +95,224 @@ fn main() {
 fn run() -> Result<(), StartError> {
     let args: Vec<_> = env::args().collect();
     let local_ip = args.get(1).ok_or(StartError::MissingIp)?;
+    if local_ip == "generate-key" {
+        let keypair = Keypair::generate().context(KeyGeneration)?;
+        println!("secret key: {}", key_to_hex(&keypair.secret()));
+        println!("public key: {}", key_to_hex(&keypair.public()));
+        return Ok(());
+    }
     let local_ip = local_ip.parse().context(InvalidIp { ip: local_ip })?;
     let local_addr = SocketAddr::new(local_ip, SERVER_PORT);
     debug!("binding {}", local_addr);
-    let socket = Socket::bind(local_addr).context(SocketErr)?;
-    with_socket(socket).context(InternalServerError)
-}
-
-#[derive(Debug, Snafu)]
-pub enum StartError {
-    #[snafu(display("missing IP parameter"))]
-    MissingIp,
-    #[snafu(display("invalid IP '{}': {}", ip, source))]
-    InvalidIp {
-        ip: String,
-        source: std::net::AddrParseError,
-    },
-    #[snafu(display("binding error: {}", source))]
-    SocketErr { source: laminar::ErrorKind },
-    #[snafu(display("internal server error: {}", source))]
-    InternalServerError { source: ServerError },
-}
-
-fn with_socket(mut socket: Socket) -> Result<(), ServerError> {
-    info!(
-        "starting server at {:?}",
-        socket.local_addr().context(SocketError)?
-    );
-    let packet_sender = socket.get_packet_sender();
-    let event_receiver = socket.get_event_receiver();
-    trace!("starting thread");
-    let _thread = std::thread::spawn(move || socket.start_polling());
-    trace!("started thread");
-    let mut queue = HashSet::<SocketAddr>::new();
-    info!("started server");
-
-    loop {
-        match event_receiver.recv() {
-            Ok(event) => match event {
-                SocketEvent::Packet(packet) => {
-                    let source = packet.addr();
-                    trace!("received packet from {}", source);
-                    let payload = packet.payload();
-                    // try to deserialize the payload
-                    match bincode::deserialize::<FromClient>(payload) {
-                        Ok(msg) => match msg {
-                            FromClient::StatusCheck => {
-                                debug!("received status check");
-                                let msg =
-                                    bincode::serialize(&ToClient::Alive).context(SerializeError)?;
-                                packet_sender
-                                    .send(Packet::reliable_unordered(source, msg))
-                                    .context(SenderError)?;
-                                trace!("sent response");
-                            }
-                            FromClient::Queue => {
-                                debug!("received queue request");
-                                let mut queue_clone = queue.clone();
-                                queue_clone.remove(&source);
-                                let msg = bincode::serialize(&ToClient::Peers(queue_clone.clone()))
-                                    .context(SerializeError)?;
-                                packet_sender
-                                    .send(Packet::reliable_unordered(source, msg))
-                                    .context(SenderError)?;
-                                for &client in &queue_clone {
-                                    let msg = bincode::serialize(&ToClient::Queued(source))
-                                        .context(SerializeError)?;
-                                    packet_sender
-                                        .send(Packet::reliable_unordered(client, msg))
-                                        .context(SenderError)?;
-                                }
-                                trace!("sent response");
-                                queue.insert(source);
-                                trace!("added to queue");
-                            }
-                            FromClient::Dequeue => {
-                                debug!("received dequeue request");
-                                queue.remove(&source);
-                            }
-                            FromClient::Heartbeat => { /* heartbeat, ignore */ }
-                        },
-                        Err(_) => { /* invalid message */ }
-                    }
-                }
-                SocketEvent::Connect(_connect_addr) => {}
-                SocketEvent::Timeout(timeout_addr) => {
-                    queue.remove(&timeout_addr);
-                }
-            },
-            Err(_) => { /* "something went wrong */ }
+    let udp = LaminarTransport::bind(local_addr).context(SocketErr)?;
+    let tcp = TcpTransport::listen(local_addr).context(TcpErr)?;
+    info!("starting server at {:?}", udp.local_addr());
+    let transport = MultiTransport::new(vec![Box::new(udp), Box::new(tcp)]);
+    match Impairment::from_env().context(InvalidImpairment)? {
+        Some(impairment) => {
+            info!("impairing the network: {:?}", impairment);
+            serve(impair(transport, impairment))
         }
+        None => serve(transport),
     }
 }
 
-#[derive(Debug, Snafu)]
-pub enum ServerError {
-    #[snafu(display("laminar error: {}", source))]
-    SocketError { source: laminar::ErrorKind },
-    #[snafu(display("error serializing: {}", source))]
-    SerializeError {
-        source: std::boxed::Box<bincode::ErrorKind>,
-    },
-    #[snafu(display("error sending: {}", source))]
-    SenderError { source: SendError<Packet> },
+fn serve<T: Transport>(transport: T) -> Result<(), StartError> {
+    match env::var("MIRAI_SECRET_KEY") {
+        Ok(secret) => {
+            let secret = key_from_hex(&secret).ok_or(StartError::InvalidKey)?;
+            let keypair = Keypair::from_secret(secret);
+            info!(
+                "encrypting traffic with public key {}",
+                key_to_hex(&keypair.public())
+            );
+            run_server(SecureTransport::server(transport, keypair))
+        }
+        Err(_) => run_server(transport),
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::time::{Duration, Instant};
-
-    fn start_test_server(socket: Socket) {
-        std::thread::spawn(move || with_socket(socket));
+fn run_server<T: Transport>(transport: T) -> Result<(), StartError> {
+    let mut server = Server::new(transport);
+    if env::var_os("MIRAI_RELAY").is_some() {
+        info!("relaying packets between matched clients");
+        server.enable_relay();
     }
-
-    fn wait_for_server(server_addr: SocketAddr) {
-        let mut socket = Socket::bind_any().unwrap();
-        loop {
-            let msg = bincode::serialize(&FromClient::StatusCheck).unwrap();
-            socket
-                .send(Packet::reliable_unordered(server_addr, msg))
-                .unwrap();
-            socket.manual_poll(std::time::Instant::now());
-            if let Some(event) = socket.recv() {
-                match event {
-                    SocketEvent::Packet(packet) => {
-                        let msg = bincode::deserialize::<ToClient>(packet.payload()).unwrap();
-                        assert_eq!(msg, ToClient::Alive);
-                        println!("server is alive");
-                        break;
-                    }
-                    _ => {}
-                }
-            }
-            std::thread::sleep(std::time::Duration::from_millis(100));
-        }
+    if let Ok(limits) = env::var("MIRAI_RELAY_LIMITS") {
+        let limits: RelayLimits = limits.parse().context(InvalidRelayLimits)?;
+        info!("capping relays at {:?}", limits);
+        server.limit_relays(limits);
     }
-
-    fn send(socket: &mut Socket, msg: FromClient, server_addr: SocketAddr) {
-        let ser = bincode::serialize(&msg).unwrap();
-        socket
-            .send(Packet::reliable_unordered(server_addr, ser))
-            .unwrap();
-        socket.manual_poll(std::time::Instant::now());
+    if env::var_os("MIRAI_DEQUEUE_IDLE").is_some() {
+        info!("dequeueing idle clients");
+        server.dequeue_idle();
     }
-
-    fn recv_msg(socket: &mut Socket) -> Option<ToClient> {
-        let timer = Duration::from_millis(500);
-        let now = Instant::now();
-        loop {
-            if now.elapsed() > timer {
-                return None;
-            }
-            socket.manual_poll(std::time::Instant::now());
-            match socket.recv() {
-                Some(event) => match event {
-                    SocketEvent::Packet(packet) => {
-                        let msg = bincode::deserialize::<ToClient>(packet.payload()).unwrap();
-                        return Some(msg);
-                    }
-                    _ => {}
-                },
-                _ => {}
-            }
-        }
+    if let Ok(criteria) = env::var("MIRAI_CRITERIA") {
+        let relaxation: Relaxation = criteria.parse().context(InvalidCriteria)?;
+        info!("matching players by {:?}", relaxation);
+        server.relax_criteria(relaxation);
     }
-
-    fn expect_msg(socket: &mut Socket, msg: ToClient) -> Option<ToClient> {
-        loop {
-            let recvd = recv_msg(socket)?;
-            if std::mem::discriminant(&msg) == std::mem::discriminant(&recvd) {
-                return Some(recvd);
-            }
-        }
+    if let Some(path) = env::var_os("MIRAI_PRIORITIES") {
+        let roster = std::fs::read_to_string(&path).context(PriorityFile)?;
+        let priorities: Priorities = roster.parse().context(InvalidPriorities)?;
+        info!("prioritizing the players on the roster in {:?}", path);
+        server.prioritize(priorities);
     }
-
-    #[test]
-    fn basic_queue_test() {
-        let server_socket = Socket::bind_any().unwrap();
-        let server_addr = server_socket.local_addr().unwrap();
-        start_test_server(server_socket);
-        let mut socket_1 = Socket::bind_any().unwrap();
-        let mut socket_2 = Socket::bind_any().unwrap();
-        let mut socket_3 = Socket::bind_any().unwrap();
-        let addr_1 = socket_1.local_addr().unwrap();
-        let addr_2 = socket_2.local_addr().unwrap();
-        let addr_3 = socket_3.local_addr().unwrap();
-        println!("1: {:?}", addr_1);
-        println!("2: {:?}", addr_2);
-        println!("3: {:?}", addr_3);
-        wait_for_server(server_addr);
-
-        send(&mut socket_1, FromClient::Queue, server_addr);
-        let peers = expect_msg(&mut socket_1, ToClient::Peers(HashSet::new())).unwrap();
-        if let ToClient::Peers(peer_list) = peers {
-            assert_eq!(
-                peer_list,
-                HashSet::new(),
-                "first to queue gets an empty peer set"
-            );
-        } else {
-            unreachable!("first to queue did not receive peers")
-        }
-
-        send(&mut socket_2, FromClient::Queue, server_addr);
-        let peers = expect_msg(&mut socket_2, ToClient::Peers(HashSet::new())).unwrap();
-        if let ToClient::Peers(peer_list) = peers {
-            let mut expected = HashSet::new();
-            expected.insert(addr_1);
-            assert_eq!(
-                peer_list, expected,
-                "second to queue gets the first peer in a set"
-            );
-        } else {
-            unreachable!("second to queue did not get peers")
-        }
-
-        let queued = expect_msg(&mut socket_1, ToClient::Queued(addr_2)).unwrap();
-        if let ToClient::Queued(addr) = queued {
-            assert_eq!(addr, addr_2, "first peer is notified of second peer");
-        } else {
-            unreachable!("first peer was not notified")
-        }
-
-        send(&mut socket_3, FromClient::Queue, server_addr);
-        let peers = expect_msg(&mut socket_3, ToClient::Peers(HashSet::new())).unwrap();
-        if let ToClient::Peers(peer_list) = peers {
-            let mut expected = HashSet::new();
-            expected.insert(addr_1);
-            expected.insert(addr_2);
-            assert_eq!(
-                peer_list, expected,
-                "third to queue receivers both previous peers in a set"
-            );
-        } else {
-            unreachable!("third to queue did not receive peers")
-        }
-
-        let queued = expect_msg(&mut socket_1, ToClient::Queued(addr_3)).unwrap();
-        if let ToClient::Queued(addr) = queued {
-            assert_eq!(addr, addr_3, "first peer is notified of third");
-        } else {
-            unreachable!("first peer was not notified")
-        }
-
-        let queued = expect_msg(&mut socket_2, ToClient::Queued(addr_3)).unwrap();
-        if let ToClient::Queued(addr) = queued {
-            assert_eq!(addr, addr_3, "second peer is notified of third");
-        } else {
-            unreachable!("second peer was not notified")
-        }
+    #[cfg(feature = "scripting")]
+    if let Some(path) = env::var_os("MIRAI_POLICY") {
+        let policy = ScriptPolicy::load(&path).context(InvalidPolicy)?;
+        info!("filtering the proposed players with {:?}", path);
+        server.apply_policy(policy);
     }
-
-    #[test]
-    fn basic_dequeue_test() {
-        let server_socket = Socket::bind_any().unwrap();
-        let server_addr = server_socket.local_addr().unwrap();
-        start_test_server(server_socket);
-        let mut socket_1 = Socket::bind_any().unwrap();
-        let mut socket_2 = Socket::bind_any().unwrap();
-        wait_for_server(server_addr);
-
-        send(&mut socket_1, FromClient::Queue, server_addr);
-        send(&mut socket_1, FromClient::Dequeue, server_addr);
-        send(&mut socket_2, FromClient::Queue, server_addr);
-
-        let peers = expect_msg(&mut socket_2, ToClient::Peers(HashSet::new())).unwrap();
-        if let ToClient::Peers(peers) = peers {
-            assert_eq!(
-                peers,
-                HashSet::new(),
-                "second to queue receives empty peer set"
-            );
-        } else {
-            unreachable!()
+    #[cfg(feature = "geoip")]
+    if let Some(path) = env::var_os("MIRAI_GEOIP") {
+        let regions = match env::var("MIRAI_GEOIP_REGIONS") {
+            Ok(regions) => regions.parse().context(InvalidGeoIp)?,
+            Err(_) => RegionMap::default(),
+        };
+        let geoip = GeoIp::open(&path, regions).context(InvalidGeoIp)?;
+        info!("locating clients with {:?}", path);
+        server.locate_clients(geoip);
+    }
+    if let Ok(servers) = env::var("MIRAI_FEDERATION") {
+        let servers = servers
+            .split(',')
+            .map(str::trim)
+            .map(|server| {
+                server.parse().map_err(|_| StartError::InvalidFederation {
+                    server: server.to_string(),
+                })
+            })
+            .collect::<Result<Vec<SocketAddr>, _>>()?;
+        let region = env::var("MIRAI_REGION").map_err(|_| StartError::MissingRegion)?;
+        info!("sharing the queue of {} with {:?}", region, servers);
+        server.federate(Federation::new(region, &servers));
+    }
+    if let Some(dir) = env::var_os("MIRAI_REPLAYS") {
+        let replays = DirectoryReplays::open(&dir).context(ReplayDir)?;
+        info!("storing replays in {:?}", dir);
+        server.store_replays(replays);
+    }
+    if let Some(path) = env::var_os("MIRAI_HISTORY") {
+        let history = FileHistory::open(&path, MAX_HISTORY_MATCHES).context(HistoryFile)?;
+        info!("storing match history in {:?}", path);
+        server.store_history(history);
+    }
+    if let Ok(urls) = env::var("MIRAI_WEBHOOKS") {
+        let urls: Vec<_> = urls.split(',').map(|url| url.trim().to_string()).collect();
+        let webhooks = Webhooks::new(&urls).context(InvalidWebhook)?;
+        let queue_spike = match env::var("MIRAI_QUEUE_SPIKE") {
+            Ok(spike) => spike
+                .parse()
+                .map_err(|_| StartError::InvalidQueueSpike { spike })?,
+            Err(_) => DEFAULT_QUEUE_SPIKE,
+        };
+        info!("sending events to {:?}", urls);
+        server.notify_webhooks(webhooks, queue_spike);
+    }
+    if let Ok(system) = env::var("MIRAI_RATINGS") {
+        let system: RatingSystem = system.parse().context(InvalidRatings)?;
+        info!("rating players with {:?}", system);
+        server.rate_players(system);
+    }
+    if let Some(path) = env::var_os("MIRAI_RESERVATIONS") {
+        let reservations = std::fs::read_to_string(&path).context(ReservationFile)?;
+        for line in reservations.lines().filter(|line| !line.trim().is_empty()) {
+            let reservation = Reservation::parse(line)
+                .ok_or_else(|| StartError::InvalidReservation { line: line.into() })?;
+            server.reserve(reservation);
         }
+        info!("reserved matches from {:?}", path);
     }
-
-    #[test]
-    fn timeout_test() {
-        let server_socket = Socket::bind_any().unwrap();
-        let server_addr = server_socket.local_addr().unwrap();
-        start_test_server(server_socket);
-        let mut socket_1 = Socket::bind_any().unwrap();
-        let mut socket_2 = Socket::bind_any().unwrap();
-        wait_for_server(server_addr);
-
-        send(&mut socket_1, FromClient::Queue, server_addr);
-        std::thread::sleep(std::time::Duration::from_secs(6));
-
-        send(&mut socket_2, FromClient::Queue, server_addr);
-        let peers = expect_msg(&mut socket_2, ToClient::Peers(HashSet::new())).unwrap();
-        if let ToClient::Peers(peers) = peers {
-            assert_eq!(
-                peers,
-                HashSet::new(),
-                "first client should have timed out of the queue"
-            );
+    if let Some(path) = env::var_os("MIRAI_CONFIG") {
+        let config = ConfigFile::load(&path).context(InvalidConfig)?;
+        info!(
+            "configuring the server with {:?}: {:?}",
+            path,
+            config.config()
+        );
+        server.watch_config(config);
+    }
+    if let Some(path) = env::var_os("MIRAI_SNAPSHOT") {
+        // a snapshot that can't be restored shouldn't keep the server down
+        match Snapshot::load(&path) {
+            Ok(Some(snapshot)) => {
+                info!("restoring the snapshot saved {:?} ago", snapshot.age());
+                server.restore(snapshot);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("starting without the snapshot: {}", e),
         }
+        info!("saving snapshots to {:?}", path);
+        server.save_snapshots(path);
     }
+    if let Some(path) = env::var_os("MIRAI_DRAIN_FILE") {
+        let drain = server.drain_handle();
+        info!("draining once {:?} exists", path);
+        thread::spawn(move || {
+            while !Path::new(&path).exists() {
+                thread::sleep(Duration::from_millis(DRAIN_CHECK_INTERVAL_MILLIS));
+            }
+            info!("draining");
+            drain.drain();
+        });
+    }
+    server.run().context(InternalServerError)
+}
+
+#[derive(Debug, Snafu)]
+pub enum StartError {
+    #[snafu(display("missing IP parameter"))]
+    MissingIp,
+    #[snafu(display("invalid IP '{}': {}", ip, source))]
+    InvalidIp {
+        ip: String,
+        source: std::net::AddrParseError,
+    },
+    #[snafu(display("binding error: {}", source))]
+    SocketErr { source: laminar::ErrorKind },
+    #[snafu(display("TCP binding error: {}", source))]
+    TcpErr { source: std::io::Error },
+    #[snafu(display("{}", source))]
+    InvalidImpairment { source: ImpairmentError },
+    #[snafu(display("MIRAI_SECRET_KEY is not a key of 64 hex digits"))]
+    InvalidKey,
+    #[snafu(display("could not generate a key: {}", source))]
+    KeyGeneration { source: std::io::Error },
+    #[snafu(display("could not open the replay directory: {}", source))]
+    ReplayDir { source: std::io::Error },
+    #[snafu(display("could not open the match history: {}", source))]
+    HistoryFile { source: std::io::Error },
+    #[snafu(display("could not read the reservations: {}", source))]
+    ReservationFile { source: std::io::Error },
+    #[snafu(display("invalid reservation '{}'", line))]
+    InvalidReservation { line: String },
+    #[snafu(display("{}", source))]
+    InvalidCriteria { source: RelaxationError },
+    #[snafu(display("could not read the priority roster: {}", source))]
+    PriorityFile { source: std::io::Error },
+    #[snafu(display("{}", source))]
+    InvalidPriorities { source: PrioritiesError },
+    #[snafu(display("{}", source))]
+    InvalidRelayLimits { source: RelayLimitsError },
+    #[snafu(display("{}", source))]
+    InvalidRatings { source: RatingSystemError },
+    #[cfg(feature = "scripting")]
+    #[snafu(display("{}", source))]
+    InvalidPolicy { source: PolicyError },
+    #[cfg(feature = "geoip")]
+    #[snafu(display("{}", source))]
+    InvalidGeoIp { source: GeoIpError },
+    #[snafu(display("{}", source))]
+    InvalidConfig { source: ConfigError },
+    #[snafu(display("invalid federated server address '{}'", server))]
+    InvalidFederation { server: String },
+    #[snafu(display("MIRAI_FEDERATION requires MIRAI_REGION"))]
+    MissingRegion,
+    #[snafu(display("{}", source))]
+    InvalidWebhook { source: WebhookError },
+    #[snafu(display("invalid MIRAI_QUEUE_SPIKE '{}'", spike))]
+    InvalidQueueSpike { spike: String },
+    #[snafu(display("internal server error: {}", source))]
+    InternalServerError { source: ServerError },
 }
@@ -0,0 +1,235 @@
+//! Custom matchmaking policies written as rhai scripts, enabled with the `scripting` feature.
+//!
+//! The script defines `fn allow(player, candidate)`, which returns whether the candidate may be
+//! proposed to the player. Both are maps with the keys
+//!     ip, port: the address the server sees the client at
+//!     id: the player ID it identified with, or () if it didn't
+//!     rating, region: from its profile, or () if it didn't send them
+//!     waited: the seconds it has waited in the queue
+//!     last_opponents: the IPs of the opponents of its last reported match
+//! Players are only proposed to each other if the script allows it both ways, on top of the criteria.
+//! For example, a script that never matches players from the same /16 network twice in a row:
+//!     fn network(ip) { let parts = ip.split("."); `${parts[0]}.${parts[1]}` }
+//!     fn allow(player, candidate) {
+//!         !player.last_opponents.some(|ip| network(ip) == network(candidate.ip))
+//!     }
+//!
+//! The script can't touch the file system or the network, and is stopped after `MAX_OPERATIONS`,
+//! in which case the candidate is allowed. The file is reloaded when it changes, and a script that
+//! doesn't compile is logged and ignored in favour of the one that was running.
+
+use mirai_core::logging::{info, warn};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST, INT};
+use snafu::{ResultExt, Snafu};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How many operations a single call to the script may take.
+pub const MAX_OPERATIONS: u64 = 10_000;
+
+/// How often the script file is checked for changes.
+pub const RELOAD_INTERVAL_MILLIS: u64 = 1000;
+
+/// What the script knows about a queued client.
+pub struct QueuedClient<'a> {
+    pub addr: SocketAddr,
+    pub id: Option<&'a str>,
+    pub rating: Option<u32>,
+    pub region: Option<&'a str>,
+    pub waited: Duration,
+    pub last_opponents: &'a [IpAddr],
+}
+
+impl QueuedClient<'_> {
+    fn to_map(&self) -> Map {
+        let optional = |value: Option<Dynamic>| value.unwrap_or(Dynamic::UNIT);
+        let mut map = Map::new();
+        map.insert("ip".into(), self.addr.ip().to_string().into());
+        map.insert("port".into(), Dynamic::from(INT::from(self.addr.port())));
+        map.insert(
+            "id".into(),
+            optional(self.id.map(|id| id.to_string().into())),
+        );
+        let rating = self.rating.map(|rating| Dynamic::from(INT::from(rating)));
+        map.insert("rating".into(), optional(rating));
+        map.insert(
+            "region".into(),
+            optional(self.region.map(|region| region.to_string().into())),
+        );
+        map.insert("waited".into(), self.waited.as_secs_f64().into());
+        let last_opponents: Array = self
+            .last_opponents
+            .iter()
+            .map(|ip| ip.to_string().into())
+            .collect();
+        map.insert("last_opponents".into(), last_opponents.into());
+        map
+    }
+}
+
+/// A policy script, reloaded whenever its file changes.
+pub struct ScriptPolicy {
+    engine: Engine,
+    path: PathBuf,
+    ast: AST,
+    modified: Option<SystemTime>,
+    last_checked: Instant,
+}
+
+impl ScriptPolicy {
+    /// Loads the script from the file.
+    /// # Errors
+    /// If the file can't be read, the script doesn't compile, or it doesn't define `allow`.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, PolicyError> {
+        let path = path.into();
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(1024)
+            .set_max_array_size(1024)
+            .set_max_map_size(1024)
+            .disable_symbol("eval");
+        engine.on_print(|s| info!("policy: {}", s));
+        engine.on_debug(|s, _, _| info!("policy: {}", s));
+        let modified = modified(&path);
+        let ast = compile(&engine, &path)?;
+        Ok(Self {
+            engine,
+            path,
+            ast,
+            modified,
+            last_checked: Instant::now(),
+        })
+    }
+
+    /// Reloads the script if the file has changed since it was last checked.
+    pub fn reload_if_changed(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_checked) < Duration::from_millis(RELOAD_INTERVAL_MILLIS) {
+            return;
+        }
+        self.last_checked = now;
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        match compile(&self.engine, &self.path) {
+            Ok(ast) => {
+                info!("reloaded the policy from {:?}", self.path);
+                self.ast = ast;
+            }
+            Err(e) => warn!("kept the running policy: {}", e),
+        }
+    }
+
+    /// Whether the script allows the candidate to be proposed to the player.
+    pub fn allows(&self, player: &QueuedClient, candidate: &QueuedClient) -> bool {
+        let args = (player.to_map(), candidate.to_map());
+        match self
+            .engine
+            .call_fn::<bool>(&mut Scope::new(), &self.ast, "allow", args)
+        {
+            Ok(allowed) => allowed,
+            Err(e) => {
+                warn!("the policy failed: {}", e);
+                true
+            }
+        }
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn compile(engine: &Engine, path: &PathBuf) -> Result<AST, PolicyError> {
+    let source = fs::read_to_string(path).context(ReadError { path: path.clone() })?;
+    let ast = engine
+        .compile(&source)
+        .context(CompileError { path: path.clone() })?;
+    let defines_allow = ast
+        .iter_functions()
+        .any(|f| f.name == "allow" && f.params.len() == 2);
+    if !defines_allow {
+        return Err(PolicyError::MissingAllow { path: path.clone() });
+    }
+    Ok(ast)
+}
+
+#[derive(Debug, Snafu)]
+pub enum PolicyError {
+    #[snafu(display("could not read the policy {:?}: {}", path, source))]
+    ReadError { path: PathBuf, source: io::Error },
+    #[snafu(display("the policy {:?} does not compile: {}", path, source))]
+    CompileError {
+        path: PathBuf,
+        source: rhai::ParseError,
+    },
+    #[snafu(display("the policy {:?} does not define allow(player, candidate)", path))]
+    MissingAllow { path: PathBuf },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scripts_filter_candidates_and_are_reloaded() {
+        let path = std::env::temp_dir().join(format!("mirai-policy-{}.rhai", std::process::id()));
+        let network = r#"
+            fn network(ip) { let parts = ip.split("."); `${parts[0]}.${parts[1]}` }
+            fn allow(player, candidate) {
+                !player.last_opponents.some(|ip| network(ip) == network(candidate.ip))
+            }
+        "#;
+        fs::write(&path, network).unwrap();
+        let mut policy = ScriptPolicy::load(&path).unwrap();
+        let last_opponents = ["10.0.5.5".parse().unwrap()];
+        let candidate = |ip: &str| QueuedClient {
+            addr: SocketAddr::new(ip.parse().unwrap(), 1),
+            id: None,
+            rating: Some(1000),
+            region: None,
+            waited: Duration::from_secs(5),
+            last_opponents: &[],
+        };
+        let player = QueuedClient {
+            last_opponents: &last_opponents,
+            ..candidate("1.1.1.1")
+        };
+        assert!(!policy.allows(&player, &candidate("10.0.1.1")));
+        assert!(policy.allows(&player, &candidate("10.1.1.1")));
+
+        // a broken script is ignored
+        fs::write(&path, "fn allow(player, candidate) {").unwrap();
+        policy.modified = None;
+        policy.last_checked -= Duration::from_millis(RELOAD_INTERVAL_MILLIS);
+        policy.reload_if_changed();
+        assert!(!policy.allows(&player, &candidate("10.0.1.1")));
+
+        fs::write(
+            &path,
+            "fn allow(player, candidate) { candidate.rating > 2000 }",
+        )
+        .unwrap();
+        policy.modified = None;
+        policy.last_checked -= Duration::from_millis(RELOAD_INTERVAL_MILLIS);
+        policy.reload_if_changed();
+        assert!(!policy.allows(&player, &candidate("10.1.1.1")));
+
+        // scripts that run for too long allow the candidate
+        fs::write(&path, "fn allow(player, candidate) { loop {} }").unwrap();
+        let policy = ScriptPolicy::load(&path).unwrap();
+        assert!(policy.allows(&player, &candidate("10.1.1.1")));
+
+        fs::write(&path, "fn other() {}").unwrap();
+        assert!(ScriptPolicy::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+//! Priority tiers that put some queued players ahead of others, e.g. event participants or players
+//! returning after a disconnect.
+//!
+//! The player IDs clients identify with are the tokens a league or event gives its players, so the tiers
+//! are kept in a roster of the IDs the server was given, e.g. by an event's organizers. Only the exact IDs
+//! on the roster get a tier, so a player can't make one up. Identified players that come back within
+//! `RETURNING_SECS` of timing out while queued can be given a tier of their own, since they likely dropped
+//! out of a match that never started. The roster is parsed from settings separated by commas or lines,
+//! like `abc123=2,def456=1,returning=1`.
+//!
+//! Players with higher tiers are placed ahead in the queue, and clients are sent the tiers of their peers
+//! along with how long they have waited, so that the ones with the highest tier, and then the ones that
+//! have waited longest, are considered first, see `Client::candidates` in the matchmaking client.
+
+use snafu::Snafu;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// How long after timing out while queued a player counts as returning.
+pub const RETURNING_SECS: u64 = 5 * 60;
+
+/// The tiers of player IDs, players without one have a tier of 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Priorities {
+    // the player IDs on the roster and their tiers
+    players: HashMap<String, u8>,
+    returning: Option<u8>,
+}
+
+impl Priorities {
+    /// Whether no player is given a tier.
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty() && self.returning.is_none()
+    }
+
+    /// The tier of the player ID, or of a player returning after a disconnect if that's higher.
+    pub fn tier(&self, id: &str, returning: bool) -> u8 {
+        let tier = self.players.get(id).copied().unwrap_or(0);
+        match self.returning {
+            Some(returning_tier) if returning => tier.max(returning_tier),
+            _ => tier,
+        }
+    }
+}
+
+impl FromStr for Priorities {
+    type Err = PrioritiesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut priorities = Self::default();
+        for setting in s
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            let invalid = || PrioritiesError::InvalidSetting {
+                setting: setting.to_string(),
+            };
+            let mut parts = setting.rsplitn(2, '=');
+            let tier = parts.next().ok_or_else(invalid)?.trim();
+            let key = parts.next().ok_or_else(invalid)?.trim();
+            let tier = tier.parse().map_err(|_| invalid())?;
+            match key {
+                "" => return Err(invalid()),
+                "returning" => priorities.returning = Some(tier),
+                id => {
+                    priorities.players.insert(id.to_string(), tier);
+                }
+            }
+        }
+        Ok(priorities)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum PrioritiesError {
+    #[snafu(display("invalid priority '{}', expected player=tier", setting))]
+    InvalidSetting { setting: String },
+}
+
+/// The identified players that timed out while queued, by their IDs.
+#[derive(Default)]
+pub struct Disconnected {
+    players: HashMap<String, Instant>,
+}
+
+impl Disconnected {
+    /// Remembers that the player timed out while queued.
+    pub fn insert(&mut self, id: String, now: Instant) {
+        let window = Duration::from_secs(RETURNING_SECS);
+        self.players
+            .retain(|_, &mut timed_out| now.saturating_duration_since(timed_out) < window);
+        self.players.insert(id, now);
+    }
+
+    /// Whether the player timed out recently, which it only counts as once.
+    pub fn returned(&mut self, id: &str, now: Instant) -> bool {
+        let window = Duration::from_secs(RETURNING_SECS);
+        self.players
+            .remove(id)
+            .is_some_and(|timed_out| now.saturating_duration_since(timed_out) < window)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_players_on_the_roster_have_a_tier() {
+        let priorities: Priorities = "event-1=1, finalist=3\nreturning=2\n".parse().unwrap();
+        assert_eq!(priorities.tier("finalist", false), 3);
+        assert_eq!(priorities.tier("event-1", false), 1);
+        assert_eq!(priorities.tier("event-1", true), 2);
+        // made up IDs that merely look like the roster's
+        assert_eq!(priorities.tier("event-7", false), 0);
+        assert_eq!(priorities.tier("finalist-2", false), 0);
+        assert_eq!(priorities.tier("player", false), 0);
+        assert_eq!(Priorities::default().tier("player", true), 0);
+        assert!(Priorities::default().is_empty());
+        assert!("event-1".parse::<Priorities>().is_err());
+        assert!("=1".parse::<Priorities>().is_err());
+        assert!("event-1=high".parse::<Priorities>().is_err());
+
+        let mut disconnected = Disconnected::default();
+        let now = Instant::now();
+        disconnected.insert("player".to_string(), now);
+        assert!(!disconnected.returned("other", now));
+        assert!(disconnected.returned("player", now));
+        assert!(!disconnected.returned("player", now));
+        disconnected.insert("player".to_string(), now);
+        let later = now + Duration::from_secs(RETURNING_SECS);
+        assert!(!disconnected.returned("player", later));
+    }
+}
@@ -0,0 +1,194 @@
+//! Scores how well the connection held up in the reported matches, and steers the pairings away from
+//! the combinations of networks whose matches went poorly.
+//!
+//! A match is scored from 0 to 100 from its report: the round trip beyond `GOOD_RTT_MILLIS`, the frames
+//! rolled back per second and the relay each lower the score. Reports with neither a round trip nor
+//! rollbacks aren't scored. The score is stored with the player's match record.
+//!
+//! The connection between two players depends mostly on their networks and the route between them,
+//! so the scores are averaged per pair of networks, /24 for IPv4 and /48 for IPv6, over their
+//! `RECENT_MATCHES` latest matches. Once `MIN_MATCHES` have been scored between two networks and
+//! their average is below `POOR_SCORE`, their players are only proposed to each other after both have
+//! waited `AVOID_SECS`, so they're paired with someone else if anyone is queued but still find a match.
+
+use mirai_core::v1::MatchReport;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The round trip that doesn't lower the score.
+pub const GOOD_RTT_MILLIS: u64 = 40;
+/// How many points each millisecond of round trip beyond `GOOD_RTT_MILLIS` costs.
+pub const RTT_PENALTY_PER_MILLI: f64 = 0.25;
+/// How many points each frame rolled back per second costs.
+pub const ROLLBACK_PENALTY_PER_FRAME: f64 = 5.0;
+/// How many points a relayed match loses.
+pub const RELAY_PENALTY: f64 = 10.0;
+
+/// The average score below which a pair of networks is avoided.
+pub const POOR_SCORE: u8 = 50;
+/// How many matches between two networks are scored before they may be avoided.
+pub const MIN_MATCHES: u32 = 3;
+/// How many of the latest matches between two networks the average follows.
+pub const RECENT_MATCHES: u32 = 20;
+/// How long players wait before they're proposed to players from a network they're avoided with.
+pub const AVOID_SECS: u64 = 60;
+/// The most pairs of networks kept. Once there are more, the pairs that aren't avoided are forgotten.
+pub const MAX_PAIRS: usize = 100_000;
+
+/// The quality of the match from 0, unplayable, to 100, or None if the report has nothing to score.
+pub fn score(report: &MatchReport) -> Option<u8> {
+    if report.average_rtt.is_none() && report.rollback_frames.is_none() {
+        return None;
+    }
+    let mut penalty = 0.0;
+    if let Some(rtt) = report.average_rtt {
+        let extra = (rtt.as_millis() as u64).saturating_sub(GOOD_RTT_MILLIS);
+        penalty += extra as f64 * RTT_PENALTY_PER_MILLI;
+    }
+    if let Some(frames) = report.rollback_frames {
+        // short matches are scored as if they lasted a second
+        let secs = report.duration.as_secs_f64().max(1.0);
+        penalty += f64::from(frames) / secs * ROLLBACK_PENALTY_PER_FRAME;
+    }
+    if report.relayed {
+        penalty += RELAY_PENALTY;
+    }
+    Some((100.0 - penalty).clamp(0.0, 100.0).round() as u8)
+}
+
+// the network the address is in
+fn network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & !0xff).into()),
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !((1 << 80) - 1)).into()),
+    }
+}
+
+// the networks of both addresses, in the same order either way round
+fn pair(a: IpAddr, b: IpAddr) -> (IpAddr, IpAddr) {
+    let (a, b) = (network(a), network(b));
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+#[derive(Default)]
+struct Scores {
+    total: u32,
+    count: u32,
+}
+
+impl Scores {
+    fn add(&mut self, score: u8) {
+        // the oldest matches fade out once there are enough of them
+        if self.count == RECENT_MATCHES {
+            self.total -= self.total / self.count;
+            self.count -= 1;
+        }
+        self.total += u32::from(score);
+        self.count += 1;
+    }
+
+    fn average(&self) -> u8 {
+        (self.total / self.count.max(1)) as u8
+    }
+
+    fn is_poor(&self) -> bool {
+        self.count >= MIN_MATCHES && self.average() < POOR_SCORE
+    }
+}
+
+/// The scores of the matches between each pair of networks.
+#[derive(Default)]
+pub struct MatchQuality {
+    pairs: HashMap<(IpAddr, IpAddr), Scores>,
+}
+
+impl MatchQuality {
+    /// Records the score of a match between the players at the addresses.
+    pub fn record(&mut self, a: IpAddr, b: IpAddr, score: u8) {
+        if self.pairs.len() >= MAX_PAIRS {
+            self.pairs.retain(|_, scores| scores.is_poor());
+        }
+        self.pairs.entry(pair(a, b)).or_default().add(score);
+    }
+
+    /// The average score of the recent matches between the addresses' networks, if any were scored.
+    pub fn average(&self, a: IpAddr, b: IpAddr) -> Option<u8> {
+        self.pairs.get(&pair(a, b)).map(Scores::average)
+    }
+
+    /// Whether players at the addresses are kept apart, after the one that waited less waited for the given time.
+    pub fn avoids(&self, a: IpAddr, b: IpAddr, waited: Duration) -> bool {
+        waited < Duration::from_secs(AVOID_SECS)
+            && self.pairs.get(&pair(a, b)).is_some_and(Scores::is_poor)
+    }
+
+    /// Whether any networks are kept apart.
+    pub fn avoids_any(&self) -> bool {
+        self.pairs.values().any(Scores::is_poor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mirai_core::v1::Outcome;
+
+    fn report(rtt_millis: Option<u64>, rollback_frames: Option<u32>, relayed: bool) -> MatchReport {
+        MatchReport {
+            opponents: vec![],
+            outcome: Outcome::Draw,
+            duration: Duration::from_secs(60),
+            relayed,
+            average_rtt: rtt_millis.map(Duration::from_millis),
+            rollback_frames,
+        }
+    }
+
+    #[test]
+    fn matches_are_scored_by_their_connection() {
+        assert_eq!(score(&report(None, None, true)), None);
+        assert_eq!(score(&report(Some(30), None, false)), Some(100));
+        assert_eq!(score(&report(Some(200), None, false)), Some(60));
+        // 3 frames rolled back per second
+        assert_eq!(score(&report(Some(40), Some(180), true)), Some(75));
+        assert_eq!(score(&report(Some(2000), Some(6000), true)), Some(0));
+    }
+
+    #[test]
+    fn poor_networks_are_avoided_until_the_players_have_waited() {
+        let mut quality = MatchQuality::default();
+        let a: IpAddr = "10.0.1.2".parse().unwrap();
+        let neighbour: IpAddr = "10.0.1.200".parse().unwrap();
+        let b: IpAddr = "10.0.2.3".parse().unwrap();
+        let c: IpAddr = "10.0.3.4".parse().unwrap();
+        let just_queued = Duration::from_secs(0);
+        for _ in 0..MIN_MATCHES - 1 {
+            quality.record(a, b, 20);
+        }
+        assert!(!quality.avoids(a, b, just_queued));
+        quality.record(b, a, 20);
+        quality.record(a, c, 90);
+        assert_eq!(quality.average(b, a), Some(20));
+        assert!(quality.avoids_any());
+        // the whole network is avoided
+        assert!(quality.avoids(neighbour, b, just_queued));
+        assert!(!quality.avoids(a, b, Duration::from_secs(AVOID_SECS)));
+        assert!(!quality.avoids(a, c, just_queued));
+
+        // the pair recovers once its recent matches are good
+        for _ in 0..RECENT_MATCHES {
+            quality.record(a, b, 90);
+        }
+        assert!(!quality.avoids(a, b, just_queued));
+        assert!(!quality.avoids_any());
+
+        let v6: IpAddr = "2001:db8:1:2::1".parse().unwrap();
+        let v6_neighbour: IpAddr = "2001:db8:1:ffff::1".parse().unwrap();
+        assert_eq!(pair(v6, a), pair(a, v6_neighbour));
+    }
+}
@@ -0,0 +1,539 @@
+//! The ratings of identified players, updated from the matches they report.
+//!
+//! Matches between two identified players are rated once both of them have reported the match and their
+//! reports agree on who won, so a player can't rate itself up by reporting wins it didn't have. A report
+//! whose opponent doesn't report the match within `REPORT_WINDOW_SECS` is dropped, and so are reports that
+//! contradict each other. Both ratings are then updated with the rating system the server was configured with.
+//! Players start at `INITIAL_RATING`.
+//!
+//! With Elo, the rating is updated against the average rating of the opponents, and players are ranked
+//! once they have reported a match.
+//!
+//! Glicko-2 also tracks how uncertain each rating is (its deviation) and how erratic the player's
+//! results are (its volatility). New players are provisional until they have played `PLACEMENT_MATCHES`
+//! matches, and their ratings move quickly in the meantime. The deviation grows with every
+//! `RATING_PERIOD_SECS` a player doesn't play, and players whose deviation has grown past
+//! `PROVISIONAL_DEVIATION` are provisional again until they play. Provisional players are left off
+//! the leaderboard.
+
+use mirai_core::v1::{Outcome, Standing};
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::f64::consts::PI;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The rating of a player that hasn't reported a match.
+pub const INITIAL_RATING: u32 = 1500;
+/// The most a single match changes an Elo rating by.
+pub const K_FACTOR: f64 = 32.0;
+/// The Glicko-2 deviation of a player that hasn't reported a match, which is also the most it grows to.
+pub const INITIAL_DEVIATION: f64 = 350.0;
+/// The Glicko-2 volatility of a player that hasn't reported a match.
+pub const INITIAL_VOLATILITY: f64 = 0.06;
+/// How much the Glicko-2 volatility may change, smaller values keep it steadier.
+pub const TAU: f64 = 0.5;
+/// How many matches a new player plays before it's ranked with Glicko-2.
+pub const PLACEMENT_MATCHES: u32 = 5;
+/// The Glicko-2 deviation past which an inactive player is provisional again.
+pub const PROVISIONAL_DEVIATION: f64 = 200.0;
+/// How long a Glicko-2 rating period is, the deviation of players grows for each one they don't play.
+pub const RATING_PERIOD_SECS: u64 = 24 * 60 * 60;
+/// How long a player's report of a match waits for the opponent's before it's dropped.
+pub const REPORT_WINDOW_SECS: u64 = 10 * 60;
+
+// converts between the Glicko and the Glicko-2 scale
+const GLICKO2_SCALE: f64 = 173.7178;
+// how precisely the volatility is solved for
+const CONVERGENCE: f64 = 0.000_001;
+
+/// How the players are rated, parsed from `elo` or `glicko2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RatingSystem {
+    Elo,
+    Glicko2,
+}
+
+impl FromStr for RatingSystem {
+    type Err = RatingSystemError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "elo" => Ok(Self::Elo),
+            "glicko2" => Ok(Self::Glicko2),
+            _ => Err(RatingSystemError::UnknownSystem {
+                system: s.to_string(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum RatingSystemError {
+    #[snafu(display("unknown rating system '{}', expected elo or glicko2", system))]
+    UnknownSystem { system: String },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Player {
+    rating: f64,
+    deviation: f64,
+    volatility: f64,
+    matches: u32,
+    last_played: Instant,
+}
+
+impl Player {
+    fn new(now: Instant) -> Self {
+        Self {
+            rating: f64::from(INITIAL_RATING),
+            deviation: INITIAL_DEVIATION,
+            volatility: INITIAL_VOLATILITY,
+            matches: 0,
+            last_played: now,
+        }
+    }
+
+    // the deviation after growing for each rating period the player hasn't played
+    fn deviation_at(&self, now: Instant) -> f64 {
+        let periods =
+            now.saturating_duration_since(self.last_played).as_secs() / RATING_PERIOD_SECS;
+        let phi = self.deviation / GLICKO2_SCALE;
+        let grown = (phi.powi(2) + periods as f64 * self.volatility.powi(2)).sqrt();
+        (grown * GLICKO2_SCALE).min(INITIAL_DEVIATION)
+    }
+}
+
+/// A player's rating, as saved in a snapshot, see `snapshot`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SavedRating {
+    pub player: String,
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+    pub matches: u32,
+    /// How long before the snapshot the player last played.
+    pub idle: Duration,
+}
+
+/// The ratings of the players, ordered for the leaderboard.
+pub struct Ratings {
+    system: RatingSystem,
+    players: HashMap<String, Player>,
+    // the highest rating first, ties ordered by player ID
+    ranking: BTreeSet<(Reverse<u32>, String)>,
+    // the outcomes the players reported against their opponents, waiting for the opponents' reports
+    reports: HashMap<(String, String), (Outcome, Instant)>,
+}
+
+impl Ratings {
+    pub fn new(system: RatingSystem) -> Self {
+        Self {
+            system,
+            players: HashMap::new(),
+            ranking: BTreeSet::new(),
+            reports: HashMap::new(),
+        }
+    }
+
+    /// The player's rating.
+    pub fn rating(&self, player: &str) -> u32 {
+        self.players
+            .get(player)
+            .map_or(INITIAL_RATING, |p| p.rating.round() as u32)
+    }
+
+    /// The player's Glicko-2 deviation at the given time.
+    pub fn deviation(&self, player: &str, now: Instant) -> f64 {
+        self.players
+            .get(player)
+            .map_or(INITIAL_DEVIATION, |p| p.deviation_at(now))
+    }
+
+    /// Records the player's report of a match against the opponent. Once the opponent's report of the match
+    /// agrees, the ratings of both are updated. Returns whether the match was rated.
+    pub fn report(&mut self, player: &str, opponent: &str, outcome: Outcome, now: Instant) -> bool {
+        let window = Duration::from_secs(REPORT_WINDOW_SECS);
+        self.reports
+            .retain(|_, &mut (_, reported)| now.saturating_duration_since(reported) < window);
+        let theirs = (opponent.to_string(), player.to_string());
+        match self.reports.remove(&theirs) {
+            Some((theirs, _)) if theirs == opposite(outcome) => {
+                self.rate(player, opponent, outcome, now);
+                true
+            }
+            // the reports contradict each other, so neither is trusted
+            Some(_) => false,
+            None => {
+                let ours = (player.to_string(), opponent.to_string());
+                self.reports.insert(ours, (outcome, now));
+                false
+            }
+        }
+    }
+
+    // updates the ratings of both players from the outcome of the player's match against the opponent
+    fn rate(&mut self, player: &str, opponent: &str, outcome: Outcome, now: Instant) {
+        let new = Player::new(now);
+        let current = self.players.get(player).copied().unwrap_or(new);
+        let other = self.players.get(opponent).copied().unwrap_or(new);
+        // both are rated against the rating the other had before the match
+        let updated = self.updated(current, other, outcome, now);
+        let other_updated = self.updated(other, current, opposite(outcome), now);
+        self.set(player, updated);
+        self.set(opponent, other_updated);
+    }
+
+    // the player's rating after the match against the opponent
+    fn updated(&self, player: Player, opponent: Player, outcome: Outcome, now: Instant) -> Player {
+        let score = match outcome {
+            Outcome::Win => 1.0,
+            Outcome::Loss => 0.0,
+            Outcome::Draw => 0.5,
+        };
+        let opponents = [(opponent.rating, opponent.deviation_at(now), score)];
+        let mut updated = match self.system {
+            RatingSystem::Elo => elo(player, &opponents),
+            RatingSystem::Glicko2 => glicko2(player, &opponents, now),
+        };
+        updated.rating = updated.rating.max(0.0);
+        updated.matches = player.matches + 1;
+        updated.last_played = now;
+        updated
+    }
+
+    fn set(&mut self, player: &str, updated: Player) {
+        let old = self.players.insert(player.to_string(), updated);
+        if let Some(old) = old {
+            self.ranking
+                .remove(&(Reverse(old.rating.round() as u32), player.to_string()));
+        }
+        self.ranking
+            .insert((Reverse(updated.rating.round() as u32), player.to_string()));
+    }
+
+    /// The ratings of all the players, to be restored later with `restore`.
+    pub fn save(&self, now: Instant) -> Vec<SavedRating> {
+        self.players
+            .iter()
+            .map(|(id, player)| SavedRating {
+                player: id.clone(),
+                rating: player.rating,
+                deviation: player.deviation,
+                volatility: player.volatility,
+                matches: player.matches,
+                idle: now.saturating_duration_since(player.last_played),
+            })
+            .collect()
+    }
+
+    /// Restores the saved ratings, replacing the current ratings of the same players.
+    /// The players are considered idle since they last played, including the time since the ratings were saved.
+    pub fn restore(&mut self, saved: Vec<SavedRating>, since_saved: Duration, now: Instant) {
+        for saved in saved {
+            let idle = saved.idle + since_saved;
+            let player = Player {
+                rating: saved.rating,
+                deviation: saved.deviation,
+                volatility: saved.volatility,
+                matches: saved.matches,
+                last_played: now.checked_sub(idle).unwrap_or(now),
+            };
+            let old = self.players.insert(saved.player.clone(), player);
+            if let Some(old) = old {
+                self.ranking
+                    .remove(&(Reverse(old.rating.round() as u32), saved.player.clone()));
+            }
+            self.ranking
+                .insert((Reverse(player.rating.round() as u32), saved.player));
+        }
+    }
+
+    // whether the player is on the leaderboard
+    fn placed(&self, player: &str, now: Instant) -> bool {
+        match (self.system, self.players.get(player)) {
+            (RatingSystem::Elo, Some(_)) => true,
+            (RatingSystem::Glicko2, Some(player)) => {
+                player.matches >= PLACEMENT_MATCHES
+                    && player.deviation_at(now) <= PROVISIONAL_DEVIATION
+            }
+            (_, None) => false,
+        }
+    }
+
+    /// Up to `count` players from the given rank on, where the best player is ranked 1.
+    pub fn top(&self, from: u32, count: u32, now: Instant) -> Vec<Standing> {
+        let skip = from.saturating_sub(1) as usize;
+        self.standings(now)
+            .skip(skip)
+            .take(count as usize)
+            .collect()
+    }
+
+    /// Up to `count` players ranked around the player, who is in the middle unless near either end.
+    /// Empty if the player isn't on the leaderboard.
+    pub fn around(&self, player: &str, count: u32, now: Instant) -> Vec<Standing> {
+        let position = match self.standings(now).position(|s| s.player == player) {
+            Some(position) => position,
+            None => return vec![],
+        };
+        let count = count as usize;
+        let last_start = self.standings(now).count().saturating_sub(count);
+        let skip = position.saturating_sub(count / 2).min(last_start);
+        self.standings(now).skip(skip).take(count).collect()
+    }
+
+    fn standings(&self, now: Instant) -> impl Iterator<Item = Standing> + '_ {
+        self.ranking
+            .iter()
+            .filter(move |(_, player)| self.placed(player, now))
+            .enumerate()
+            .map(|(i, (Reverse(rating), player))| Standing {
+                rank: i as u32 + 1,
+                player: player.clone(),
+                rating: *rating,
+            })
+    }
+}
+
+// the outcome the opponent reports for the same match
+fn opposite(outcome: Outcome) -> Outcome {
+    match outcome {
+        Outcome::Win => Outcome::Loss,
+        Outcome::Loss => Outcome::Win,
+        Outcome::Draw => Outcome::Draw,
+    }
+}
+
+// the Elo rating after the match against the opponents, given as (rating, deviation, score)
+fn elo(player: Player, opponents: &[(f64, f64, f64)]) -> Player {
+    let opponent =
+        opponents.iter().map(|&(rating, _, _)| rating).sum::<f64>() / opponents.len() as f64;
+    let score = opponents.iter().map(|&(_, _, score)| score).sum::<f64>() / opponents.len() as f64;
+    let expected = 1.0 / (1.0 + 10f64.powf((opponent - player.rating) / 400.0));
+    Player {
+        rating: (player.rating + K_FACTOR * (score - expected)).round(),
+        ..player
+    }
+}
+
+// the Glicko-2 rating after a rating period in which the player played the opponents,
+// given as (rating, deviation, score), see http://www.glicko.net/glicko/glicko2.pdf
+fn glicko2(player: Player, opponents: &[(f64, f64, f64)], now: Instant) -> Player {
+    let mu = (player.rating - f64::from(INITIAL_RATING)) / GLICKO2_SCALE;
+    let phi = player.deviation_at(now) / GLICKO2_SCALE;
+    let sigma = player.volatility;
+    let g = |phi: f64| 1.0 / (1.0 + 3.0 * phi.powi(2) / PI.powi(2)).sqrt();
+    let opponents: Vec<_> = opponents
+        .iter()
+        .map(|&(rating, deviation, score)| {
+            let mu_j = (rating - f64::from(INITIAL_RATING)) / GLICKO2_SCALE;
+            let g_j = g(deviation / GLICKO2_SCALE);
+            let expected = 1.0 / (1.0 + (-g_j * (mu - mu_j)).exp());
+            (g_j, expected, score)
+        })
+        .collect();
+    let v = 1.0
+        / opponents
+            .iter()
+            .map(|&(g_j, e, _)| g_j.powi(2) * e * (1.0 - e))
+            .sum::<f64>();
+    let improvement: f64 = opponents
+        .iter()
+        .map(|&(g_j, e, score)| g_j * (score - e))
+        .sum();
+    let delta = v * improvement;
+
+    // solves for the new volatility with the Illinois algorithm
+    let a = sigma.powi(2).ln();
+    let f = |x: f64| {
+        let ex = x.exp();
+        ex * (delta.powi(2) - phi.powi(2) - v - ex) / (2.0 * (phi.powi(2) + v + ex).powi(2))
+            - (x - a) / TAU.powi(2)
+    };
+    let mut big_a = a;
+    let mut big_b = if delta.powi(2) > phi.powi(2) + v {
+        (delta.powi(2) - phi.powi(2) - v).ln()
+    } else {
+        let mut k = 1.0;
+        while f(a - k * TAU) < 0.0 {
+            k += 1.0;
+        }
+        a - k * TAU
+    };
+    let mut f_a = f(big_a);
+    let mut f_b = f(big_b);
+    while (big_b - big_a).abs() > CONVERGENCE {
+        let c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+        let f_c = f(c);
+        if f_c * f_b <= 0.0 {
+            big_a = big_b;
+            f_a = f_b;
+        } else {
+            f_a /= 2.0;
+        }
+        big_b = c;
+        f_b = f_c;
+    }
+    let volatility = (big_a / 2.0).exp();
+
+    let phi_star = (phi.powi(2) + volatility.powi(2)).sqrt();
+    let new_phi = 1.0 / (1.0 / phi_star.powi(2) + 1.0 / v).sqrt();
+    let new_mu = mu + new_phi.powi(2) * improvement;
+    Player {
+        rating: new_mu * GLICKO2_SCALE + f64::from(INITIAL_RATING),
+        deviation: (new_phi * GLICKO2_SCALE).min(INITIAL_DEVIATION),
+        volatility,
+        ..player
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn players_are_ranked_by_rating() {
+        let now = Instant::now();
+        let mut ratings = Ratings::new(RatingSystem::Elo);
+        let players: Vec<String> = (0..5).map(|i| format!("player{}", i)).collect();
+        // every player beats the ones after it
+        for (i, player) in players.iter().enumerate() {
+            for (j, opponent) in players.iter().enumerate() {
+                if i != j {
+                    let outcome = if i < j { Outcome::Win } else { Outcome::Loss };
+                    ratings.report(player, opponent, outcome, now);
+                }
+            }
+        }
+        assert!(ratings.rating("player0") > INITIAL_RATING);
+        assert!(ratings.rating("player4") < INITIAL_RATING);
+
+        let ranked = |standings: Vec<Standing>| -> Vec<(u32, String)> {
+            standings.into_iter().map(|s| (s.rank, s.player)).collect()
+        };
+        assert_eq!(
+            ranked(ratings.top(1, 2, now)),
+            vec![(1, players[0].clone()), (2, players[1].clone())]
+        );
+        assert_eq!(
+            ranked(ratings.top(5, 2, now)),
+            vec![(5, players[4].clone())]
+        );
+        assert_eq!(
+            ranked(ratings.around("player2", 3, now)),
+            vec![
+                (2, players[1].clone()),
+                (3, players[2].clone()),
+                (4, players[3].clone())
+            ]
+        );
+        assert_eq!(ranked(ratings.around("player4", 2, now))[0].0, 4);
+        assert!(ratings.around("nobody", 3, now).is_empty());
+    }
+
+    #[test]
+    fn only_agreed_reports_are_rated() {
+        let now = Instant::now();
+        let mut ratings = Ratings::new(RatingSystem::Elo);
+        // a report the opponent never confirms doesn't count
+        assert!(!ratings.report("cheater", "victim", Outcome::Win, now));
+        assert_eq!(ratings.rating("cheater"), INITIAL_RATING);
+        // and neither do contradicting reports
+        assert!(!ratings.report("victim", "cheater", Outcome::Win, now));
+        assert_eq!(ratings.rating("cheater"), INITIAL_RATING);
+        assert_eq!(ratings.rating("victim"), INITIAL_RATING);
+        assert!(ratings.top(1, 10, now).is_empty());
+
+        // the opponent's report has to arrive in time
+        assert!(!ratings.report("winner", "loser", Outcome::Win, now));
+        let later = now + Duration::from_secs(REPORT_WINDOW_SECS);
+        assert!(!ratings.report("loser", "winner", Outcome::Loss, later));
+        assert!(ratings.report("winner", "loser", Outcome::Win, later));
+        assert_eq!(ratings.rating("winner"), INITIAL_RATING + 16);
+        assert_eq!(ratings.rating("loser"), INITIAL_RATING - 16);
+
+        assert!(!ratings.report("winner", "loser", Outcome::Draw, later));
+        assert!(ratings.report("loser", "winner", Outcome::Draw, later));
+        assert!(ratings.rating("loser") > INITIAL_RATING - 16);
+    }
+
+    #[test]
+    fn glicko2_matches_the_reference_example() {
+        // the example from Glickman's paper
+        let now = Instant::now();
+        let player = Player {
+            rating: 1500.0,
+            deviation: 200.0,
+            volatility: 0.06,
+            matches: 0,
+            last_played: now,
+        };
+        let opponents = [
+            (1400.0, 30.0, 1.0),
+            (1550.0, 100.0, 0.0),
+            (1700.0, 300.0, 0.0),
+        ];
+        let updated = glicko2(player, &opponents, now);
+        assert!(
+            (updated.rating - 1464.06).abs() < 0.01,
+            "{}",
+            updated.rating
+        );
+        assert!(
+            (updated.deviation - 151.52).abs() < 0.01,
+            "{}",
+            updated.deviation
+        );
+        assert!((updated.volatility - 0.05999).abs() < 0.00001);
+    }
+
+    #[test]
+    fn glicko2_players_are_placed_and_decay() {
+        let now = Instant::now();
+        let mut ratings = Ratings::new(RatingSystem::Glicko2);
+        let veteran = "veteran".to_string();
+        let rookie = "rookie".to_string();
+        for i in 0..PLACEMENT_MATCHES {
+            assert!(
+                ratings.top(1, 10, now).is_empty(),
+                "placed after {} matches",
+                i
+            );
+            ratings.report(&veteran, &rookie, Outcome::Win, now);
+            ratings.report(&rookie, &veteran, Outcome::Loss, now);
+        }
+        // the deviations are still above the provisional threshold after the placement matches
+        // against each other, so keep playing until both are ranked
+        let mut matches = PLACEMENT_MATCHES;
+        while ratings.top(1, 10, now).len() < 2 {
+            ratings.report(&veteran, &rookie, Outcome::Draw, now);
+            ratings.report(&rookie, &veteran, Outcome::Draw, now);
+            matches += 1;
+            assert!(matches < 100);
+        }
+        assert!(ratings.rating(&veteran) > ratings.rating(&rookie));
+        assert_eq!(ratings.around(&rookie, 1, now)[0].rank, 2);
+
+        // years of inactivity make the ratings provisional again
+        let later = now + Duration::from_secs(RATING_PERIOD_SECS * 365 * 5);
+        assert!(ratings.deviation(&veteran, later) > ratings.deviation(&veteran, now));
+        assert!(ratings.top(1, 10, later).is_empty());
+    }
+
+    #[test]
+    fn saved_ratings_are_restored() {
+        let now = Instant::now();
+        let mut ratings = Ratings::new(RatingSystem::Elo);
+        ratings.report("winner", "loser", Outcome::Win, now);
+        ratings.report("loser", "winner", Outcome::Loss, now);
+
+        let mut restored = Ratings::new(RatingSystem::Elo);
+        restored.restore(ratings.save(now), Duration::from_secs(60), now);
+        assert_eq!(restored.rating("winner"), ratings.rating("winner"));
+        assert_eq!(restored.top(1, 10, now), ratings.top(1, 10, now));
+    }
+}
@@ -0,0 +1,137 @@
+//! Storage for the replays uploaded by clients.
+//!
+//! The server doesn't read the replays, they are stored as the bytes written by the game client's
+//! `Replay::write` and fetched by the ID the store assigned to them.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Where the uploaded replays are kept.
+pub trait ReplayStore: Send {
+    /// Stores the replay and returns its ID.
+    /// # Errors
+    /// If the replay could not be stored.
+    fn store(&mut self, replay: &[u8]) -> io::Result<u64>;
+
+    /// Loads the replay with the given ID, if it's stored.
+    /// # Errors
+    /// If the replay could not be loaded.
+    fn load(&self, id: u64) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// Keeps the latest replays in memory, forgetting the oldest ones once it's full.
+pub struct MemoryReplays {
+    capacity: usize,
+    first_id: u64,
+    replays: VecDeque<Vec<u8>>,
+}
+
+impl MemoryReplays {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            first_id: 0,
+            replays: VecDeque::new(),
+        }
+    }
+}
+
+impl ReplayStore for MemoryReplays {
+    fn store(&mut self, replay: &[u8]) -> io::Result<u64> {
+        if self.replays.len() == self.capacity {
+            self.replays.pop_front();
+            self.first_id += 1;
+        }
+        self.replays.push_back(replay.to_vec());
+        Ok(self.first_id + self.replays.len() as u64 - 1)
+    }
+
+    fn load(&self, id: u64) -> io::Result<Option<Vec<u8>>> {
+        let replay = id
+            .checked_sub(self.first_id)
+            .and_then(|index| self.replays.get(index as usize))
+            .cloned();
+        Ok(replay)
+    }
+}
+
+/// Keeps the replays in a directory, one file named after its ID per replay.
+pub struct DirectoryReplays {
+    dir: PathBuf,
+    next_id: u64,
+}
+
+impl DirectoryReplays {
+    /// Creates the directory if needed, and continues numbering after the replays already in it.
+    /// # Errors
+    /// If the directory can't be created or read.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut next_id = 0;
+        for entry in fs::read_dir(&dir)? {
+            let id = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".replay"))
+                .and_then(|id| id.parse::<u64>().ok());
+            if let Some(id) = id {
+                next_id = next_id.max(id + 1);
+            }
+        }
+        Ok(Self { dir, next_id })
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.replay", id))
+    }
+}
+
+impl ReplayStore for DirectoryReplays {
+    fn store(&mut self, replay: &[u8]) -> io::Result<u64> {
+        let id = self.next_id;
+        fs::write(self.path(id), replay)?;
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    fn load(&self, id: u64) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(id)) {
+            Ok(replay) => Ok(Some(replay)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_forgets_the_oldest_replays() {
+        let mut replays = MemoryReplays::new(2);
+        assert_eq!(replays.store(&[1]).unwrap(), 0);
+        assert_eq!(replays.store(&[2]).unwrap(), 1);
+        assert_eq!(replays.store(&[3]).unwrap(), 2);
+        assert_eq!(replays.load(0).unwrap(), None);
+        assert_eq!(replays.load(1).unwrap(), Some(vec![2]));
+        assert_eq!(replays.load(2).unwrap(), Some(vec![3]));
+        assert_eq!(replays.load(3).unwrap(), None);
+    }
+
+    #[test]
+    fn directory_continues_numbering_after_reopening() {
+        let dir = std::env::temp_dir().join(format!("mirai-replays-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut replays = DirectoryReplays::open(&dir).unwrap();
+        assert_eq!(replays.store(&[1, 2]).unwrap(), 0);
+        let mut replays = DirectoryReplays::open(&dir).unwrap();
+        assert_eq!(replays.store(&[3]).unwrap(), 1);
+        assert_eq!(replays.load(0).unwrap(), Some(vec![1, 2]));
+        assert_eq!(replays.load(5).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+//! Snapshots of the server's state, so a quick restart doesn't empty the queue.
+//!
+//! A snapshot holds:
+//!     the queued clients, with their forwarded ports, player IDs, parties, profiles, resume tokens
+//!     and how long they have waited
+//!     the rooms hosted by queued clients
+//!     the ratings of the players
+//! The snapshot is saved every `SNAPSHOT_INTERVAL_SECS` and once the server stops, and restored on startup.
+//! The queue is only restored from a snapshot saved in the last `MAX_QUEUE_AGE_SECS`, since the clients
+//! of an older one have likely given up, while the ratings are restored from a snapshot of any age.
+//!
+//! Queued clients keep sending Resume to the server, which reconnects them once it's back. The restored
+//! clients that aren't heard from within `RESTORE_GRACE_SECS` are dequeued, as if their connection timed out.
+//! Encrypted clients can't be heard from, since their sessions didn't survive the restart.
+
+use crate::ratings::SavedRating;
+use mirai_core::v1::{Profile, Room};
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How often the snapshot is saved while the server runs.
+pub const SNAPSHOT_INTERVAL_SECS: u64 = 5;
+
+/// How old a snapshot's queue can be to be restored.
+pub const MAX_QUEUE_AGE_SECS: u64 = 60;
+
+/// How long a restored client has to contact the server before it's dequeued.
+pub const RESTORE_GRACE_SECS: u64 = 10;
+
+/// The server's state at the time it was saved.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub saved_at: SystemTime,
+    pub queue: Vec<QueuedClient>,
+    pub rooms: Vec<(SocketAddr, Room)>,
+    pub ratings: Vec<SavedRating>,
+}
+
+/// A queued client, as saved in a snapshot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QueuedClient {
+    pub addr: SocketAddr,
+    pub mapped: Option<u16>,
+    pub identity: Option<String>,
+    pub party: Option<u64>,
+    pub profile: Option<Profile>,
+    pub resume_token: Option<u64>,
+    pub waited: Duration,
+}
+
+impl Snapshot {
+    /// How long ago the snapshot was saved.
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.saved_at)
+            .unwrap_or_default()
+    }
+
+    /// Whether the snapshot's queue is recent enough to be restored.
+    pub fn is_fresh(&self) -> bool {
+        self.age() < Duration::from_secs(MAX_QUEUE_AGE_SECS)
+    }
+
+    /// Loads the snapshot saved at the path, if there is one.
+    /// # Errors
+    /// If the file can't be read or doesn't contain a snapshot.
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>, SnapshotError> {
+        let path = path.as_ref();
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => {
+                return Err(SnapshotError::ReadError {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        };
+        let snapshot = bincode::deserialize(&bytes).context(DecodeError {
+            path: path.to_path_buf(),
+        })?;
+        Ok(Some(snapshot))
+    }
+
+    /// Saves the snapshot at the path, replacing the previous one only once it's written,
+    /// so a server stopped while saving keeps its last snapshot.
+    /// # Errors
+    /// If the snapshot can't be serialized or written.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let path = path.as_ref();
+        let bytes = bincode::serialize(self).context(EncodeError)?;
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        fs::write(&partial, bytes)
+            .and_then(|_| fs::rename(&partial, path))
+            .context(WriteError {
+                path: path.to_path_buf(),
+            })
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum SnapshotError {
+    #[snafu(display("could not read the snapshot {:?}: {}", path, source))]
+    ReadError { path: PathBuf, source: io::Error },
+    #[snafu(display("invalid snapshot {:?}: {}", path, source))]
+    DecodeError {
+        path: PathBuf,
+        source: bincode::Error,
+    },
+    #[snafu(display("could not serialize the snapshot: {}", source))]
+    EncodeError { source: bincode::Error },
+    #[snafu(display("could not write the snapshot {:?}: {}", path, source))]
+    WriteError { path: PathBuf, source: io::Error },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshots_are_saved_and_loaded() {
+        let path = std::env::temp_dir().join(format!("mirai-snapshot-{}.bin", std::process::id()));
+        assert!(Snapshot::load(&path).unwrap().is_none());
+
+        let snapshot = Snapshot {
+            saved_at: SystemTime::now(),
+            queue: vec![QueuedClient {
+                addr: "127.0.0.1:2000".parse().unwrap(),
+                mapped: Some(3000),
+                identity: Some("alice".to_string()),
+                party: None,
+                profile: Some(Profile {
+                    rating: Some(1600),
+                    region: None,
+                }),
+                resume_token: Some(7),
+                waited: Duration::from_secs(20),
+            }],
+            rooms: vec![],
+            ratings: vec![],
+        };
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap().unwrap();
+        assert_eq!(loaded, snapshot);
+        assert!(loaded.is_fresh());
+
+        let stale = Snapshot {
+            saved_at: SystemTime::now() - Duration::from_secs(MAX_QUEUE_AGE_SECS),
+            ..snapshot
+        };
+        assert!(!stale.is_fresh());
+
+        fs::write(&path, b"not a snapshot").unwrap();
+        assert!(Snapshot::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
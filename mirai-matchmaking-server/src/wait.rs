@@ -0,0 +1,76 @@
+//! Estimates how much longer queued players wait for a match.
+//!
+//! Clients report when their match is confirmed, and the server records how long they waited since
+//! queueing. The waits are kept per rating bucket of `RATING_BUCKET` points, since players far from
+//! the usual ratings wait longer when the criteria are enabled, with unrated players in a bucket
+//! of their own. The estimate for a player is the average of the last `WAIT_SAMPLES` waits
+//! in its bucket, minus the time it has already waited.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// How many rating points each bucket spans.
+pub const RATING_BUCKET: u32 = 200;
+/// How many of the latest waits are averaged per bucket.
+pub const WAIT_SAMPLES: usize = 20;
+
+/// The latest waits per rating bucket.
+#[derive(Default)]
+pub struct WaitTimes {
+    buckets: HashMap<Option<u32>, VecDeque<Duration>>,
+}
+
+impl WaitTimes {
+    /// Records how long a player with the given rating waited for a match.
+    pub fn record(&mut self, rating: Option<u32>, waited: Duration) {
+        let samples = self.buckets.entry(bucket(rating)).or_default();
+        if samples.len() == WAIT_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(waited);
+    }
+
+    /// The average wait of the players in the rating's bucket, if any have been matched.
+    pub fn average(&self, rating: Option<u32>) -> Option<Duration> {
+        let samples = self.buckets.get(&bucket(rating))?;
+        let total: Duration = samples.iter().sum();
+        Some(total / samples.len() as u32)
+    }
+
+    /// How much longer a player with the given rating that has waited for the given time is
+    /// expected to wait.
+    pub fn estimate(&self, rating: Option<u32>, waited: Duration) -> Option<Duration> {
+        self.average(rating)
+            .map(|average| average.saturating_sub(waited))
+    }
+}
+
+fn bucket(rating: Option<u32>) -> Option<u32> {
+    rating.map(|rating| rating / RATING_BUCKET)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn estimates_average_the_latest_waits_of_the_bucket() {
+        let secs = Duration::from_secs;
+        let mut waits = WaitTimes::default();
+        assert_eq!(waits.estimate(Some(1000), secs(0)), None);
+
+        waits.record(Some(1000), secs(10));
+        waits.record(Some(1150), secs(20));
+        waits.record(Some(1500), secs(60));
+        waits.record(None, secs(5));
+        assert_eq!(waits.average(Some(1100)), Some(secs(15)));
+        assert_eq!(waits.estimate(Some(1100), secs(5)), Some(secs(10)));
+        assert_eq!(waits.estimate(Some(1100), secs(30)), Some(secs(0)));
+        assert_eq!(waits.estimate(None, secs(0)), Some(secs(5)));
+
+        for _ in 0..WAIT_SAMPLES {
+            waits.record(Some(1000), secs(40));
+        }
+        assert_eq!(waits.average(Some(1000)), Some(secs(40)));
+    }
+}
@@ -0,0 +1,180 @@
+//! Notifies other services of matchmaking activity, e.g. a chat bot announcing matches.
+//!
+//! Each event is POSTed to every configured URL as a JSON object, whose `event` field is one of
+//!     match_created: a client confirmed a match, sent once per match by the first player to report it
+//!     match_completed: a client reported how its match went, sent for each player
+//!     queue_spike: the queue has grown to the configured size, sent again once it has shrunk to half
+//!     queue_regions: the number of queued players per region, sent periodically while players are queued
+//!     relay_completed: the bytes relayed between two clients, sent once one of them times out
+//!     relay_usage: the bytes relayed since the server started, sent periodically while relays are active
+//!     drained: the queue of a draining server has emptied, so it can be shut down
+//! The requests are sent on a thread of their own so that a slow endpoint doesn't hold up the server.
+//! Only `http://` URLs are supported, endpoints that require TLS can be reached through a proxy,
+//! see `mirai_core::http`.
+
+use crossbeam_channel::{unbounded, Sender};
+use mirai_core::http::Endpoint;
+use mirai_core::logging::{trace, warn};
+use mirai_core::v1::Outcome;
+use serde::Serialize;
+use snafu::Snafu;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+/// How long a webhook request may take.
+pub const WEBHOOK_TIMEOUT_MILLIS: u64 = 5000;
+
+/// The queue size announced as a spike unless configured otherwise.
+pub const DEFAULT_QUEUE_SPIKE: usize = 100;
+
+/// A client taking part in an event.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Participant {
+    pub address: SocketAddr,
+    /// The player ID the client identified with.
+    pub id: Option<String>,
+}
+
+/// The events the server sends to the webhooks.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    MatchCreated {
+        players: Vec<Participant>,
+    },
+    MatchCompleted {
+        player: Participant,
+        /// The IDs of the opponents that had identified themselves.
+        opponents: Vec<String>,
+        outcome: Outcome,
+        duration_secs: f64,
+        relayed: bool,
+        average_rtt_millis: Option<f64>,
+        /// The match's score from 0 to 100, see `quality`.
+        quality: Option<u8>,
+    },
+    QueueSpike {
+        queued: usize,
+    },
+    QueueRegions {
+        regions: BTreeMap<String, usize>,
+    },
+    RelayCompleted {
+        players: Vec<Participant>,
+        relayed_bytes: u64,
+        /// The bytes dropped for going over the relay's caps.
+        dropped_bytes: u64,
+        duration_secs: f64,
+    },
+    RelayUsage {
+        /// The number of relays that are active.
+        active: usize,
+        relayed_bytes: u64,
+        dropped_bytes: u64,
+    },
+    Drained,
+}
+
+/// Sends the events to the configured URLs. The requests stop once this is dropped.
+pub struct Webhooks {
+    sender: Sender<Vec<u8>>,
+}
+
+impl Webhooks {
+    /// Starts a thread that sends the events to the URLs.
+    /// # Errors
+    /// If a URL isn't an `http://` URL.
+    pub fn new(urls: &[String]) -> Result<Self, WebhookError> {
+        let endpoints = urls
+            .iter()
+            .map(|url| {
+                Endpoint::parse(url)
+                    .ok_or_else(|| WebhookError::UnsupportedUrl { url: url.clone() })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let (sender, receiver) = unbounded::<Vec<u8>>();
+        let timeout = Duration::from_millis(WEBHOOK_TIMEOUT_MILLIS);
+        thread::spawn(move || {
+            for body in receiver {
+                for endpoint in &endpoints {
+                    let authority = endpoint.authority();
+                    match endpoint.post_json(&body, timeout) {
+                        Ok(status) if (200..300).contains(&status) => {
+                            trace!("sent webhook to {}", authority)
+                        }
+                        Ok(status) => warn!("webhook {} answered {}", authority, status),
+                        Err(e) => warn!("failed to send webhook to {}: {}", authority, e),
+                    }
+                }
+            }
+        });
+        Ok(Self { sender })
+    }
+
+    /// Queues the event to be sent to every URL.
+    pub fn notify(&self, event: &Event) {
+        match serde_json::to_vec(event) {
+            Ok(body) => {
+                // the thread only stops when the sender is dropped
+                let _ = self.sender.send(body);
+            }
+            Err(e) => warn!("failed to serialize webhook: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum WebhookError {
+    #[snafu(display(
+        "unsupported webhook URL '{}', expected http://host[:port][/path]",
+        url
+    ))]
+    UnsupportedUrl { url: String },
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // answers the next webhook request and returns it
+    pub(crate) fn receive(listener: &TcpListener) -> String {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut request = vec![];
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&request).ends_with('}') {
+            let n = stream.read(&mut buf).unwrap();
+            assert_ne!(n, 0);
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[test]
+    fn events_are_posted_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/mirai", listener.local_addr().unwrap());
+        let webhooks = Webhooks::new(&[url]).unwrap();
+        webhooks.notify(&Event::QueueSpike { queued: 100 });
+
+        let request = receive(&listener);
+        assert!(request.starts_with("POST /hooks/mirai HTTP/1.1\r\n"));
+        let body = request.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(body, r#"{"event":"queue_spike","queued":100}"#);
+    }
+
+    #[test]
+    fn only_http_urls_are_supported() {
+        assert!(Webhooks::new(&["http://example.com".to_string()]).is_ok());
+        assert!(Webhooks::new(&["https://example.com/hook".to_string()]).is_err());
+    }
+}
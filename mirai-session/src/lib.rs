@@ -0,0 +1,306 @@
+//! Mirai session ties the matchmaking client and the game client together.
+//!
+//! A session queues with the matchmaking server, challenges suitable peers and accepts their challenges,
+//! hands the socket over to the game client once a match is confirmed and waits for the match to start.
+//! The game then drives the match through the game client's session until it is finished.
+//!
+//! The session moves through the phases `Searching → Negotiating → InMatch → PostMatch`
+//! as `poll` is called.
+
+pub use mirai_game_client::{NetInput, SessionConfig};
+
+use mirai_core::logging::{debug, info};
+use mirai_core::transport::Transport;
+use mirai_game_client::{ClientError as GameError, SessionState};
+use mirai_matchmaking_client::{
+    bind_transport, secure_transport, Client, ClientError as MatchmakingError, CreateError, Peer,
+    PeerStatus,
+};
+use snafu::{ResultExt, Snafu};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// Where the session is in its lifecycle.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Phase {
+    /// Queued and looking for an opponent.
+    Searching,
+    /// Challenges are being exchanged or the confirmed match is about to start.
+    Negotiating,
+    /// The match is in progress.
+    InMatch,
+    /// The match has ended.
+    PostMatch,
+}
+
+/// How the match ended.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Outcome {
+    /// The game finished the match.
+    Finished,
+    /// The connection to the other peers was lost.
+    Disconnected,
+}
+
+/// Parameters for a session.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Config {
+    /// Parameters for the match.
+    pub session: SessionConfig,
+    /// Peers with a higher latency are not challenged and their challenges are declined.
+    pub max_latency: Option<Duration>,
+}
+
+/// The transport handed over from the matchmaking client to the game client.
+pub type Socket = Box<dyn Transport>;
+
+/// The game client's session used for the match.
+pub type MatchSession<I> = mirai_game_client::Session<I, Socket>;
+
+type SocketWrapper = Box<dyn FnOnce(Socket) -> Socket + Send>;
+
+enum Inner<I> {
+    Matchmaking(Client<Socket>),
+    Starting(MatchSession<I>),
+    InMatch(MatchSession<I>),
+    PostMatch(Outcome),
+}
+
+/// Finds and plays a single match.
+pub struct Session<I> {
+    config: Config,
+    socket_wrapper: Option<SocketWrapper>,
+    relayed: bool,
+    inner: Inner<I>,
+}
+
+impl<I: NetInput> Session<I> {
+    /// Creates a matchmaking client and queues with the server.
+    /// The network is impaired according to `MIRAI_IMPAIRMENT` if it is set,
+    /// and the traffic with the server is encrypted if `MIRAI_SERVER_KEY` is set.
+    /// # Errors
+    /// If creating the client or queueing fails.
+    pub fn new(local_ip: IpAddr, server_ip: IpAddr, config: Config) -> Result<Self, SessionError> {
+        let transport = bind_transport(local_ip).context(Create)?;
+        let transport = secure_transport(transport, server_ip).context(Create)?;
+        let mut client = Client::with_transport(server_ip, transport);
+        client.queue().context(Matchmaking)?;
+        Ok(Self {
+            config,
+            socket_wrapper: None,
+            relayed: false,
+            inner: Inner::Matchmaking(client),
+        })
+    }
+
+    /// Sets a function that wraps the transport before it is handed over to the game client,
+    /// e.g. to simulate network conditions.
+    pub fn set_socket_wrapper<F: FnOnce(Socket) -> Socket + Send + 'static>(&mut self, wrapper: F) {
+        self.socket_wrapper = Some(Box::new(wrapper));
+    }
+
+    /// Whether the match goes through the server's relay because the peers couldn't reach each other.
+    pub fn is_relayed(&self) -> bool {
+        self.relayed
+    }
+
+    /// Returns the current phase of the session.
+    pub fn phase(&self) -> Phase {
+        match &self.inner {
+            Inner::Matchmaking(client) => {
+                let negotiating = client
+                    .outgoing_challenges()
+                    .map(|challenges| !challenges.is_empty())
+                    .unwrap_or(false);
+                if negotiating {
+                    Phase::Negotiating
+                } else {
+                    Phase::Searching
+                }
+            }
+            Inner::Starting(_) => Phase::Negotiating,
+            Inner::InMatch(_) => Phase::InMatch,
+            Inner::PostMatch(_) => Phase::PostMatch,
+        }
+    }
+
+    /// Advances the session: challenges and accepts peers while searching, hands the socket
+    /// over to the game client once a match is confirmed and notices when the match is lost.
+    /// Should be called regularly.
+    /// # Errors
+    /// If the matchmaking client or the game client has failed.
+    pub fn poll(&mut self) -> Result<Phase, SessionError> {
+        match std::mem::replace(&mut self.inner, Inner::PostMatch(Outcome::Finished)) {
+            Inner::Matchmaking(client) => {
+                self.inner = match client.check_group_match().context(Matchmaking)? {
+                    Some(peers) => {
+                        info!("match confirmed with {:?}", peers);
+                        client.dequeue().context(Matchmaking)?;
+                        self.relayed = client.is_relayed().context(Matchmaking)?;
+                        if self.relayed {
+                            info!("relaying the match through the server");
+                        }
+                        let connection = client.into_peer_connection().context(Matchmaking)?;
+                        let mut socket: Socket = Box::new(connection);
+                        if let Some(wrapper) = self.socket_wrapper.take() {
+                            socket = wrapper(socket);
+                        }
+                        let session = MatchSession::new(peers, socket, self.config.session.clone());
+                        Inner::Starting(session)
+                    }
+                    None => {
+                        let peers = client.peers().context(Matchmaking)?;
+                        let outgoing = client.outgoing_challenges().context(Matchmaking)?;
+                        for (mut peer, action) in
+                            matchmaking_actions(&peers, &outgoing, self.config.max_latency)
+                        {
+                            debug!("{:?} {}", action, peer.addr());
+                            match action {
+                                Action::Challenge => client.challenge(&mut peer),
+                                Action::Accept => client.accept(&mut peer),
+                                Action::Decline => client.decline(peer.addr()),
+                            }
+                            .context(Matchmaking)?;
+                        }
+                        Inner::Matchmaking(client)
+                    }
+                };
+            }
+            Inner::Starting(session) => {
+                self.inner = match session.check_time_until_start() {
+                    Some(_) => Inner::Starting(session),
+                    None => {
+                        info!("match started");
+                        Inner::InMatch(session)
+                    }
+                };
+            }
+            Inner::InMatch(session) => {
+                self.inner = if session.state() == SessionState::Disconnected {
+                    info!("match disconnected");
+                    session.close().context(Game)?;
+                    Inner::PostMatch(Outcome::Disconnected)
+                } else {
+                    Inner::InMatch(session)
+                };
+            }
+            post_match => self.inner = post_match,
+        }
+        Ok(self.phase())
+    }
+
+    /// Returns the match in progress.
+    pub fn game(&mut self) -> Option<&mut MatchSession<I>> {
+        match &mut self.inner {
+            Inner::InMatch(session) => Some(session),
+            _ => None,
+        }
+    }
+
+    /// Returns the match in progress, consuming the session.
+    /// Useful for games that manage the match themselves from this point on.
+    pub fn into_game(self) -> Option<MatchSession<I>> {
+        match self.inner {
+            Inner::InMatch(session) => Some(session),
+            _ => None,
+        }
+    }
+
+    /// Ends the match in progress.
+    /// # Errors
+    /// If the game client encountered an error.
+    pub fn finish(&mut self) -> Result<(), SessionError> {
+        if let Inner::InMatch(_) = self.inner {
+            if let Inner::InMatch(session) =
+                std::mem::replace(&mut self.inner, Inner::PostMatch(Outcome::Finished))
+            {
+                session.close().context(Game)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how the match ended, if it has ended.
+    pub fn outcome(&self) -> Option<Outcome> {
+        match self.inner {
+            Inner::PostMatch(outcome) => Some(outcome),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Action {
+    Challenge,
+    Accept,
+    Decline,
+}
+
+// decides how to respond to the peers received from the server
+fn matchmaking_actions(
+    peers: &HashSet<Peer>,
+    outgoing: &HashSet<SocketAddr>,
+    max_latency: Option<Duration>,
+) -> Vec<(Peer, Action)> {
+    let mut actions = vec![];
+    // only one peer is challenged at a time
+    let mut challenging = !outgoing.is_empty();
+    for peer in peers.iter().filter(|peer| !peer.is_blocked()) {
+        let latency = match peer.latency() {
+            Some(latency) => latency,
+            // wait until the latency is known
+            None => continue,
+        };
+        let acceptable = max_latency.is_none_or(|max| latency <= max);
+        match peer.status() {
+            PeerStatus::IncomingChallenge if acceptable => {
+                actions.push((peer.clone(), Action::Accept))
+            }
+            PeerStatus::IncomingChallenge => actions.push((peer.clone(), Action::Decline)),
+            PeerStatus::None if acceptable && !challenging => {
+                challenging = true;
+                actions.push((peer.clone(), Action::Challenge));
+            }
+            _ => {}
+        }
+    }
+    actions
+}
+
+#[derive(Debug, Snafu)]
+pub enum SessionError {
+    Create { source: CreateError },
+    Matchmaking { source: MatchmakingError },
+    Game { source: GameError },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peer(port: u16, latency: Option<u64>) -> Peer {
+        let mut peer = Peer::new(SocketAddr::new("127.0.0.1".parse().unwrap(), port));
+        if let Some(latency) = latency {
+            peer.add_ping(Duration::from_millis(latency));
+        }
+        peer
+    }
+
+    #[test]
+    fn challenges_one_peer_with_known_latency() {
+        let peers = vec![peer(1, None), peer(2, Some(200)), peer(3, Some(20))]
+            .into_iter()
+            .collect();
+        let actions =
+            matchmaking_actions(&peers, &HashSet::new(), Some(Duration::from_millis(100)));
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].0.addr().port(), 3);
+        assert_eq!(actions[0].1, Action::Challenge);
+
+        let outgoing = vec![actions[0].0.addr()].into_iter().collect();
+        assert!(
+            matchmaking_actions(&peers, &outgoing, Some(Duration::from_millis(100))).is_empty()
+        );
+    }
+}
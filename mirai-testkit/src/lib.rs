@@ -0,0 +1,270 @@
+//! Runs a matchmaking server and clients in process for scenario tests.
+//!
+//! The server and the clients communicate over a `MockNetwork`. The server is driven by the test kit,
+//! which delivers every packet in flight and lets the server handle its events on each step.
+//! The clients handle their packets on their own threads, so the helpers step until a condition
+//! holds or a timeout is reached.
+
+use crossbeam_channel::Receiver;
+use mirai_core::transport::{MockNetwork, MockTransport, Transport, TransportEvent};
+use mirai_core::v1::{CLIENT_PORT, SERVER_PORT};
+use mirai_matchmaking_client::{Client, ClientError, Peer};
+use mirai_matchmaking_server::{Server, ServerError};
+use snafu::{ResultExt, Snafu};
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT_MILLIS: u64 = 5000;
+
+/// A server and clients on a mock network.
+pub struct TestKit {
+    network: MockNetwork,
+    server_ip: IpAddr,
+    server: Server<MockTransport>,
+    server_events: Receiver<TransportEvent>,
+    clients: Vec<Client<MockTransport>>,
+}
+
+impl TestKit {
+    /// Creates a server and the given amount of clients, which are not queued yet.
+    /// The server is at 127.0.0.1 and the clients at 127.0.0.2 onwards.
+    pub fn new(clients: usize) -> Self {
+        let network = MockNetwork::new();
+        let server_ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let (server, server_events) = Self::start_server(&network, server_ip);
+        let clients = (0..clients)
+            .map(|i| {
+                let transport = network.transport(client_addr(i));
+                Client::with_transport(server_ip, transport)
+            })
+            .collect();
+        Self {
+            network,
+            server_ip,
+            server,
+            server_events,
+            clients,
+        }
+    }
+
+    fn start_server(
+        network: &MockNetwork,
+        server_ip: IpAddr,
+    ) -> (Server<MockTransport>, Receiver<TransportEvent>) {
+        let transport = network.transport(SocketAddr::new(server_ip, SERVER_PORT));
+        let events = transport.events().clone();
+        (Server::new(transport), events)
+    }
+
+    /// The network, e.g. to change the links or deliver packets by hand.
+    pub fn network(&self) -> &MockNetwork {
+        &self.network
+    }
+
+    pub fn server(&self) -> &Server<MockTransport> {
+        &self.server
+    }
+
+    pub fn client(&self, i: usize) -> &Client<MockTransport> {
+        &self.clients[i]
+    }
+
+    pub fn client_mut(&mut self, i: usize) -> &mut Client<MockTransport> {
+        &mut self.clients[i]
+    }
+
+    /// The address of the client with the given index.
+    pub fn addr(&self, i: usize) -> SocketAddr {
+        client_addr(i)
+    }
+
+    /// Delivers every packet in flight and lets the server handle its events and send its batch.
+    /// # Errors
+    /// If the server fails to handle an event.
+    pub fn step(&mut self) -> Result<(), TestKitError> {
+        self.network.deliver_all();
+        for event in self.server_events.try_iter() {
+            self.server.handle_event(event).context(ServerErr)?;
+        }
+        self.server.flush().context(ServerErr)
+    }
+
+    /// Steps until the condition holds.
+    /// # Errors
+    /// If the condition doesn't hold within the timeout or the condition or a step fails.
+    pub fn run_until(
+        &mut self,
+        mut condition: impl FnMut(&Self) -> Result<bool, ClientError>,
+    ) -> Result<(), TestKitError> {
+        let start = Instant::now();
+        while !condition(self).context(ClientErr)? {
+            if start.elapsed() > Duration::from_millis(TIMEOUT_MILLIS) {
+                return Err(TestKitError::Timeout);
+            }
+            self.step()?;
+            thread::yield_now();
+        }
+        Ok(())
+    }
+
+    /// Queues every client and waits until each of them knows about all of the others.
+    /// # Errors
+    /// If queueing fails or takes too long.
+    pub fn queue_all(&mut self) -> Result<(), TestKitError> {
+        for client in &mut self.clients {
+            client.queue().context(ClientErr)?;
+        }
+        let others = self.clients.len().saturating_sub(1);
+        self.run_until(|kit| {
+            for client in &kit.clients {
+                if client.peers()?.len() < others {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    /// Challenges the client `to` from the client `from`.
+    /// # Errors
+    /// If sending the challenge fails.
+    pub fn challenge(&self, from: usize, to: usize) -> Result<(), TestKitError> {
+        let mut peer = Peer::new(self.addr(to));
+        self.clients[from].challenge(&mut peer).context(ClientErr)
+    }
+
+    /// Waits until `to` has received the challenge from `from` and accepts it.
+    /// # Errors
+    /// If the challenge doesn't arrive in time or accepting it fails.
+    pub fn accept(&mut self, to: usize, from: usize) -> Result<(), TestKitError> {
+        let from_addr = self.addr(from);
+        self.run_until(|kit| {
+            let incoming = kit.clients[to].incoming_challenges()?;
+            Ok(incoming
+                .iter()
+                .any(|challenge| challenge.peer() == from_addr))
+        })?;
+        let mut peer = Peer::new(from_addr);
+        self.clients[to].accept(&mut peer).context(ClientErr)
+    }
+
+    /// Waits until `to` has received the challenge from `from` and declines it.
+    /// # Errors
+    /// If the challenge doesn't arrive in time or declining it fails.
+    pub fn decline(&mut self, to: usize, from: usize) -> Result<(), TestKitError> {
+        let from_addr = self.addr(from);
+        self.run_until(|kit| {
+            let incoming = kit.clients[to].incoming_challenges()?;
+            Ok(incoming
+                .iter()
+                .any(|challenge| challenge.peer() == from_addr))
+        })?;
+        self.clients[to].decline(from_addr).context(ClientErr)
+    }
+
+    /// Waits until the two clients have confirmed a match with each other.
+    /// # Errors
+    /// If the match isn't confirmed in time.
+    pub fn wait_until_matched(&mut self, a: usize, b: usize) -> Result<(), TestKitError> {
+        let (a_addr, b_addr) = (self.addr(a), self.addr(b));
+        self.run_until(|kit| {
+            Ok(kit.clients[a].check_match()? == Some(b_addr)
+                && kit.clients[b].check_match()? == Some(a_addr))
+        })
+    }
+
+    /// Replaces the server with a new one on the same address, losing its queue.
+    pub fn restart_server(&mut self) {
+        let (server, server_events) = Self::start_server(&self.network, self.server_ip);
+        self.server = server;
+        self.server_events = server_events;
+    }
+
+    /// Replaces the client with a new one on the same transport and queues it.
+    /// # Errors
+    /// If closing the old client or queueing the new one fails.
+    pub fn requeue(&mut self, i: usize) -> Result<(), TestKitError> {
+        let transport = self.clients.remove(i).close().context(ClientErr)?;
+        let mut client = Client::with_transport(self.server_ip, transport);
+        client.queue().context(ClientErr)?;
+        self.clients.insert(i, client);
+        Ok(())
+    }
+
+    /// Closes every client.
+    /// # Errors
+    /// If a client's handler has failed.
+    pub fn close(self) -> Result<(), TestKitError> {
+        for client in self.clients {
+            client.close().context(ClientErr)?;
+        }
+        Ok(())
+    }
+}
+
+fn client_addr(i: usize) -> SocketAddr {
+    let host = u8::try_from(i + 2).expect("too many clients");
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, host)), CLIENT_PORT)
+}
+
+#[derive(Debug, Snafu)]
+pub enum TestKitError {
+    #[snafu(display("timed out"))]
+    Timeout,
+    #[snafu(display("client error: {:?}", source))]
+    ClientErr { source: ClientError },
+    #[snafu(display("server error: {}", source))]
+    ServerErr { source: ServerError },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn simultaneous_challenge() {
+        let mut kit = TestKit::new(2);
+        kit.queue_all().unwrap();
+        kit.challenge(0, 1).unwrap();
+        kit.challenge(1, 0).unwrap();
+        kit.accept(0, 1).unwrap();
+        kit.accept(1, 0).unwrap();
+        kit.wait_until_matched(0, 1).unwrap();
+        kit.close().unwrap();
+    }
+
+    #[test]
+    fn decline_race() {
+        let mut kit = TestKit::new(3);
+        kit.queue_all().unwrap();
+        kit.challenge(0, 1).unwrap();
+        kit.challenge(0, 2).unwrap();
+        kit.decline(2, 0).unwrap();
+        kit.accept(1, 0).unwrap();
+        kit.wait_until_matched(0, 1).unwrap();
+        assert_eq!(kit.client(2).check_match().unwrap(), None);
+        kit.close().unwrap();
+    }
+
+    #[test]
+    fn server_restart() {
+        let mut kit = TestKit::new(2);
+        kit.queue_all().unwrap();
+        kit.restart_server();
+        assert!(kit.server().queue().is_empty());
+
+        kit.requeue(0).unwrap();
+        kit.requeue(1).unwrap();
+        let first = kit.addr(0);
+        kit.run_until(|kit| Ok(kit.server().queue().len() == 2))
+            .unwrap();
+        kit.run_until(|kit| Ok(kit.client(1).peers()?.iter().any(|p| p.addr() == first)))
+            .unwrap();
+        kit.challenge(1, 0).unwrap();
+        kit.accept(0, 1).unwrap();
+        kit.wait_until_matched(0, 1).unwrap();
+        kit.close().unwrap();
+    }
+}